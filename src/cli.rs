@@ -0,0 +1,749 @@
+/* Command line interface (similar to wg(8) and wg-quick(8)):
+ *
+ * wireguard-rs show [<interface> | all | interfaces]
+ * wireguard-rs showconf <interface>
+ * wireguard-rs set <interface> [listen-port <port>] [fwmark <mark>] [private-key <file>]
+ *     [replay-window <packets>]
+ *     [peer <key> [remove] [preshared-key <file>] [endpoint <host>:<port>]
+ *      [persistent-keepalive <secs>] [allowed-ips <ip>/<cidr>[,<ip>/<cidr>]...]]...
+ * wireguard-rs up <config file | interface> [options]
+ * wireguard-rs down <interface>
+ * wireguard-rs genkey | genpsk | pubkey
+ * wireguard-rs vanity <prefix>
+ * wireguard-rs derive <salt> m=<KiB>,t=<passes>,p=<lanes>
+ * wireguard-rs signer <socket> <key file>
+ * wireguard-rs keyring store | remove <interface>
+ *
+ * "show", "showconf" and "set" use the UAPI protocol on the control socket of a running device,
+ * "up" starts a device from a configuration file (/etc/wireguard/<interface>.conf by default)
+ * and "down" removes the control socket, which shuts down the device.
+ * Unlike wg-quick(8), "up" ignores the Address and DNS settings and adds no routes (these are only logged).
+ * "genkey", "genpsk" and "pubkey" (reading the private key from stdin) print base64 encoded keys,
+ * "vanity" searches (on every core) for a key pair whose public key starts with the prefix.
+ * "derive" derives a private key from the passphrase read from stdin with Argon2id.
+ * "signer" runs the helper holding the private key of a device started with --signer=<socket>.
+ * "keyring" stores the private key (read from stdin) of a device started with --keyring.
+ */
+
+use super::configuration::file::{parse_prefix, ConfigFile, Interface, Peer as FilePeer};
+use super::configuration::key::{vanity_key, CancelToken};
+#[cfg(feature = "keyring")]
+use super::configuration::keyring::{self, KeyringError};
+use super::configuration::passphrase::{derive_private_key, Params};
+use super::configuration::{encode_key, parse_key};
+#[cfg(unix)]
+use super::configuration::{key, signer};
+use super::platform::plt;
+use super::platform::uapi::PlatformUAPI;
+use super::wireguard::{generate_preshared_key, generate_private_key, public_key};
+
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hex::FromHex;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+const CONFIG_DIR: &str = "/etc/wireguard/";
+
+#[derive(Debug)]
+pub enum CliError {
+    Usage(&'static str),
+    InvalidArgument(String),
+    NoDevice(String, io::Error),
+    Io(io::Error),
+    Device(i32), // errno returned by the device
+    #[cfg(feature = "keyring")]
+    Keyring(KeyringError),
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CliError::Usage(usage) => write!(f, "Usage: {}", usage),
+            CliError::InvalidArgument(arg) => write!(f, "Invalid argument: {}", arg),
+            CliError::NoDevice(name, e) => write!(f, "Unable to access interface {}: {}", name, e),
+            CliError::Io(e) => write!(f, "Control socket error: {}", e),
+            CliError::Device(errno) => write!(
+                f,
+                "Configuration rejected by the device: {}",
+                io::Error::from_raw_os_error(*errno)
+            ),
+            #[cfg(feature = "keyring")]
+            CliError::Keyring(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<io::Error> for CliError {
+    fn from(e: io::Error) -> Self {
+        CliError::Io(e)
+    }
+}
+
+// State of a device (the response of a UAPI get operation)
+#[derive(Default)]
+struct Device {
+    private_key: Option<[u8; 32]>,
+    listen_port: Option<u16>,
+    fwmark: Option<u32>,
+    peers: Vec<Peer>,
+}
+
+#[derive(Default)]
+struct Peer {
+    public_key: [u8; 32],
+    preshared_key: [u8; 32],
+    endpoint: Option<String>,
+    allowed_ips: Vec<String>,
+    last_handshake: Option<u64>,
+    handshake_initiated: bool,
+    rx_bytes: u64,
+    tx_bytes: u64,
+    persistent_keepalive: u64,
+}
+
+// Internal function
+//
+// Perform a UAPI operation on the device and return the "key=value" pairs of the response
+fn request(name: &str, request: &str) -> Result<Vec<(String, String)>, CliError> {
+    let mut stream =
+        plt::UAPI::connect(name).map_err(|e| CliError::NoDevice(name.to_owned(), e))?;
+    stream.write_all(request.as_bytes())?;
+
+    // the response is terminated by an empty line
+    let mut pairs = vec![];
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.is_empty() {
+            break;
+        }
+        let mut split = line.splitn(2, '=');
+        if let (Some(key), Some(value)) = (split.next(), split.next()) {
+            pairs.push((key.to_owned(), value.to_owned()));
+        }
+    }
+    match pairs.pop() {
+        Some((key, value)) if key == "errno" => match value.parse() {
+            Ok(0) => Ok(pairs),
+            Ok(errno) => Err(CliError::Device(errno)),
+            Err(_) => Err(CliError::Device(libc::EPROTO)),
+        },
+        _ => Err(CliError::Device(libc::EPROTO)),
+    }
+}
+
+// Internal function
+//
+// Parse the response of a UAPI get operation
+fn parse_device(pairs: &[(String, String)]) -> Device {
+    let mut device = Device::default();
+    for (key, value) in pairs {
+        if key == "public_key" {
+            device.peers.push(Peer {
+                public_key: <[u8; 32]>::from_hex(value).unwrap_or_default(),
+                ..Default::default()
+            });
+            continue;
+        }
+        match (device.peers.last_mut(), key.as_str()) {
+            (None, "private_key") => device.private_key = <[u8; 32]>::from_hex(value).ok(),
+            (None, "listen_port") => device.listen_port = value.parse().ok(),
+            (None, "fwmark") => device.fwmark = value.parse().ok(),
+            (Some(peer), "preshared_key") => {
+                peer.preshared_key = <[u8; 32]>::from_hex(value).unwrap_or_default()
+            }
+            (Some(peer), "endpoint") => peer.endpoint = Some(value.to_owned()),
+            (Some(peer), "allowed_ip") => peer.allowed_ips.push(value.to_owned()),
+            (Some(peer), "last_handshake_time_sec") => {
+                peer.last_handshake = value.parse().ok().filter(|secs| *secs != 0)
+            }
+            (Some(peer), "handshake_initiated") => peer.handshake_initiated = value == "true",
+            (Some(peer), "rx_bytes") => peer.rx_bytes = value.parse().unwrap_or(0),
+            (Some(peer), "tx_bytes") => peer.tx_bytes = value.parse().unwrap_or(0),
+            (Some(peer), "persistent_keepalive_interval") => {
+                peer.persistent_keepalive = value.parse().unwrap_or(0)
+            }
+            _ => (),
+        }
+    }
+    device
+}
+
+// Internal function
+//
+// Format a duration as "1 hour, 2 minutes, 3 seconds"
+fn duration(secs: u64) -> String {
+    let units = [
+        ("year", 365 * 24 * 3600),
+        ("day", 24 * 3600),
+        ("hour", 3600),
+        ("minute", 60),
+        ("second", 1),
+    ];
+    let mut left = secs;
+    let mut parts = vec![];
+    for (unit, size) in units.iter() {
+        let n = left / size;
+        left %= size;
+        if n > 0 {
+            parts.push(format!("{} {}{}", n, unit, if n == 1 { "" } else { "s" }));
+        }
+    }
+    parts.join(", ")
+}
+
+// Internal function
+//
+// Format a number of bytes as "1.50 KiB"
+fn bytes(n: u64) -> String {
+    let units = ["KiB", "MiB", "GiB", "TiB"];
+    if n < 1024 {
+        return format!("{} B", n);
+    }
+    let mut value = n as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < units.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.2} {}", value, units[unit])
+}
+
+// Internal function
+//
+// Format the state of a device (as "wg show"), the peers with the latest handshake first
+fn format_device(name: &str, device: &mut Device, now: u64) -> String {
+    let mut out = format!("interface: {}\n", name);
+    if let Some(sk) = device.private_key {
+        let pk = PublicKey::from(&StaticSecret::from(sk));
+        out += &format!("  public key: {}\n", encode_key(pk.as_bytes()));
+        out += "  private key: (hidden)\n";
+    }
+    if let Some(port) = device.listen_port {
+        out += &format!("  listening port: {}\n", port);
+    }
+    if let Some(fwmark) = device.fwmark {
+        out += &format!("  fwmark: 0x{:x}\n", fwmark);
+    }
+
+    device
+        .peers
+        .sort_by_key(|peer| std::cmp::Reverse(peer.last_handshake));
+    for peer in device.peers.iter() {
+        out += &format!("\npeer: {}\n", encode_key(&peer.public_key));
+        if peer.preshared_key != [0u8; 32] {
+            out += "  preshared key: (hidden)\n";
+        }
+        if let Some(endpoint) = peer.endpoint.as_ref() {
+            out += &format!("  endpoint: {}\n", endpoint);
+        }
+        if peer.allowed_ips.is_empty() {
+            out += "  allowed ips: (none)\n";
+        } else {
+            out += &format!("  allowed ips: {}\n", peer.allowed_ips.join(", "));
+        }
+        if let Some(secs) = peer.last_handshake {
+            match now.saturating_sub(secs) {
+                0 => out += "  latest handshake: Now\n",
+                ago => out += &format!("  latest handshake: {} ago\n", duration(ago)),
+            }
+        }
+        if peer.handshake_initiated {
+            out += "  handshake: initiated, awaiting response\n";
+        }
+        if peer.rx_bytes > 0 || peer.tx_bytes > 0 {
+            out += &format!(
+                "  transfer: {} received, {} sent\n",
+                bytes(peer.rx_bytes),
+                bytes(peer.tx_bytes)
+            );
+        }
+        if peer.persistent_keepalive > 0 {
+            out += &format!(
+                "  persistent keepalive: every {}\n",
+                duration(peer.persistent_keepalive)
+            );
+        }
+    }
+    out
+}
+
+// Internal function
+//
+// Convert the state of a device to a configuration file (as "wg showconf")
+fn config_file(device: Device) -> ConfigFile {
+    ConfigFile {
+        interface: Interface {
+            private_key: device.private_key.map(StaticSecret::from),
+            listen_port: device.listen_port,
+            fwmark: device.fwmark,
+            ..Default::default()
+        },
+        peers: device
+            .peers
+            .into_iter()
+            .map(|peer| FilePeer {
+                public_key: PublicKey::from(peer.public_key),
+                preshared_key: Some(peer.preshared_key).filter(|psk| *psk != [0u8; 32]),
+                allowed_ips: peer
+                    .allowed_ips
+                    .iter()
+                    .filter_map(|ip| parse_prefix(ip))
+                    .collect(),
+                endpoint: peer.endpoint,
+                persistent_keepalive: Some(peer.persistent_keepalive).filter(|secs| *secs > 0),
+            })
+            .collect(),
+    }
+}
+
+// Internal function
+//
+// Read a base64 encoded key from a file (as wg(8), "/dev/null" clears the key)
+fn read_key(path: &str) -> Result<String, CliError> {
+    let content = fs::read_to_string(path)?;
+    if content.trim().is_empty() {
+        return Ok(hex::encode([0u8; 32]));
+    }
+    parse_key(content.trim())
+        .map(hex::encode)
+        .ok_or_else(|| CliError::InvalidArgument(format!("key in {}", path)))
+}
+
+// Internal function
+//
+// Translate the arguments of "set" to a UAPI set operation
+fn set_request(args: &[&str]) -> Result<String, CliError> {
+    let invalid = |arg: &str| CliError::InvalidArgument(arg.to_owned());
+    let mut request = String::from("set=1\n");
+    let mut in_peer = false;
+    let mut args = args.iter();
+    while let Some(&arg) = args.next() {
+        // "remove" is the only argument without a value
+        if arg == "remove" && in_peer {
+            request += "remove=true\n";
+            continue;
+        }
+        let value = *args.next().ok_or_else(|| invalid(arg))?;
+        match (in_peer, arg) {
+            (false, "listen-port") => {
+                value.parse::<u16>().map_err(|_| invalid(value))?;
+                request += &format!("listen_port={}\n", value);
+            }
+            (false, "fwmark") => {
+                let mark = match value {
+                    "off" => 0,
+                    v if v.starts_with("0x") => {
+                        u32::from_str_radix(&v[2..], 16).map_err(|_| invalid(value))?
+                    }
+                    v => v.parse().map_err(|_| invalid(value))?,
+                };
+                request += &format!("fwmark={}\n", mark);
+            }
+            (false, "replay-window") => {
+                value.parse::<u64>().map_err(|_| invalid(value))?;
+                request += &format!("replay_window={}\n", value);
+            }
+            (false, "private-key") => {
+                request += &format!("private_key={}\n", read_key(value)?);
+            }
+            (_, "peer") => {
+                let pk = parse_key(value).ok_or_else(|| invalid(value))?;
+                request += &format!("public_key={}\n", hex::encode(pk));
+                in_peer = true;
+            }
+            (true, "preshared-key") => {
+                request += &format!("preshared_key={}\n", read_key(value)?);
+            }
+            (true, "endpoint") => {
+                request += &format!("endpoint={}\n", value);
+            }
+            (true, "persistent-keepalive") => {
+                let secs = match value {
+                    "off" => 0,
+                    v => v.parse::<u16>().map_err(|_| invalid(value))?,
+                };
+                request += &format!("persistent_keepalive_interval={}\n", secs);
+            }
+            (true, "allowed-ips") => {
+                request += "replace_allowed_ips=true\n";
+                for ip in value.split(',').map(str::trim).filter(|ip| !ip.is_empty()) {
+                    request += &format!("allowed_ip={}\n", ip);
+                }
+            }
+            _ => return Err(invalid(arg)),
+        }
+    }
+    request.push('\n');
+    Ok(request)
+}
+
+// Internal function
+//
+// Print the state of a device
+fn show(name: &str) -> Result<(), CliError> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let mut device = parse_device(&request(name, "get=1\n\n")?);
+    print!("{}", format_device(name, &mut device, now));
+    Ok(())
+}
+
+// Internal function
+//
+// Parse a base64 encoded private key (e.g. read from stdin)
+fn private_key(input: &str) -> Result<StaticSecret, CliError> {
+    parse_key(input.trim())
+        .map(StaticSecret::from)
+        .ok_or_else(|| CliError::InvalidArgument("private key".to_owned()))
+}
+
+// Internal function
+//
+// Derive the public key of a base64 encoded private key (as "wg pubkey")
+fn pubkey(input: &str) -> Result<String, CliError> {
+    let sk = private_key(input)?;
+    Ok(encode_key(public_key(&sk).as_bytes()))
+}
+
+// Internal function
+//
+// Derive the base64 encoded private key from a passphrase (a line, e.g. read from stdin)
+fn derive(input: &str, salt: &str, params: &Params) -> Result<String, CliError> {
+    let passphrase = input.strip_suffix('\n').unwrap_or(input);
+    let passphrase = passphrase.strip_suffix('\r').unwrap_or(passphrase);
+    let sk = derive_private_key(passphrase.as_bytes(), salt.as_bytes(), params)
+        .map_err(|e| CliError::InvalidArgument(e.to_string()))?;
+    Ok(encode_key(&sk.to_bytes()))
+}
+
+/// Run the "show", "showconf", "set", "down", "genkey", "genpsk", "pubkey", "vanity", "derive",
+/// "signer" or "keyring" subcommand (args[0])
+pub fn run(args: &[String]) -> Result<(), CliError> {
+    let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+    match args.as_slice() {
+        ["show"] | ["show", "all"] => {
+            // a device which cannot be accessed does not hide the others
+            for (i, name) in plt::UAPI::list()?.iter().enumerate() {
+                if i > 0 {
+                    println!();
+                }
+                if let Err(e) = show(name) {
+                    eprintln!("{}", e);
+                }
+            }
+            Ok(())
+        }
+        ["show", "interfaces"] => {
+            println!("{}", plt::UAPI::list()?.join(" "));
+            Ok(())
+        }
+        ["show", name] => show(name),
+        ["show", ..] => Err(CliError::Usage("show [<interface> | all | interfaces]")),
+        ["showconf", name] => {
+            print!(
+                "{}",
+                config_file(parse_device(&request(name, "get=1\n\n")?))
+            );
+            Ok(())
+        }
+        ["showconf", ..] => Err(CliError::Usage("showconf <interface>")),
+        ["set", name, ..] if args.len() > 2 => {
+            request(name, &set_request(&args[2..])?)?;
+            Ok(())
+        }
+        ["set", ..] => Err(CliError::Usage("set <interface> <key> <value> ...")),
+        ["down", name] => {
+            plt::UAPI::remove(name).map_err(|e| CliError::NoDevice(name.to_string(), e))
+        }
+        ["genkey"] => {
+            println!("{}", encode_key(&generate_private_key().to_bytes()));
+            Ok(())
+        }
+        ["genpsk"] => {
+            println!("{}", encode_key(&generate_preshared_key()));
+            Ok(())
+        }
+        ["pubkey"] => {
+            let mut input = String::new();
+            io::stdin().read_to_string(&mut input)?;
+            println!("{}", pubkey(&input)?);
+            Ok(())
+        }
+        ["vanity", prefix] => {
+            // the expected number of attempts (6 bits per character)
+            eprintln!(
+                "Searching for a public key starting with {} (1 in {:.0} keys)",
+                prefix,
+                64f64.powi(prefix.len() as i32)
+            );
+            let sk = vanity_key(prefix, num_cpus::get(), &CancelToken::new())
+                .map_err(|e| CliError::InvalidArgument(format!("{} ({})", prefix, e)))?;
+            if let Some(sk) = sk {
+                println!("private key: {}", encode_key(&sk.to_bytes()));
+                println!("public key: {}", encode_key(public_key(&sk).as_bytes()));
+            }
+            Ok(())
+        }
+        ["vanity", ..] => Err(CliError::Usage("vanity <prefix>")),
+        ["derive", salt, params] => {
+            let params: Params = params
+                .parse()
+                .map_err(|e| CliError::InvalidArgument(format!("{} ({})", params, e)))?;
+            let mut input = String::new();
+            io::stdin().read_to_string(&mut input)?;
+            println!("{}", derive(&input, salt, &params)?);
+            Ok(())
+        }
+        ["derive", ..] => Err(CliError::Usage(
+            "derive <salt> m=<KiB>,t=<passes>,p=<lanes> < passphrase",
+        )),
+        #[cfg(unix)]
+        ["signer", socket, path] => {
+            let sk = key::load_private_key(path)
+                .map_err(|e| CliError::InvalidArgument(format!("{} ({})", path, e)))?;
+            signer::serve(signer::bind(socket)?, sk)?;
+            Ok(())
+        }
+        ["signer", ..] => Err(CliError::Usage("signer <socket> <key file>")),
+        #[cfg(feature = "keyring")]
+        ["keyring", "store", name] => {
+            let mut input = String::new();
+            io::stdin().read_to_string(&mut input)?;
+            keyring::store(name, &private_key(&input)?).map_err(CliError::Keyring)
+        }
+        #[cfg(feature = "keyring")]
+        ["keyring", "remove", name] => keyring::remove(name).map_err(CliError::Keyring),
+        ["keyring", ..] => Err(CliError::Usage("keyring store | remove <interface>")),
+        ["genkey", ..] | ["genpsk", ..] | ["pubkey", ..] => {
+            Err(CliError::Usage("genkey | genpsk | pubkey < private.key"))
+        }
+        _ => Err(CliError::Usage("down <interface>")),
+    }
+}
+
+/// Translate the arguments of "up" (following the subcommand) to the arguments of the daemon
+///
+/// The configuration is either a path or the name of the interface
+/// (configured in /etc/wireguard/<interface>.conf), the interface is named after the file.
+pub fn up(args: &[String]) -> Result<Vec<String>, CliError> {
+    let usage = CliError::Usage("up <config file | interface> [options]");
+    let config = args.first().ok_or(usage)?;
+    let path = if config.contains('/') || config.contains('.') {
+        config.to_owned()
+    } else {
+        format!("{}{}.conf", CONFIG_DIR, config)
+    };
+    let name = Path::new(&path)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .filter(|stem| !stem.is_empty())
+        .ok_or_else(|| CliError::InvalidArgument(config.to_owned()))?;
+
+    let mut daemon = vec![name.to_owned(), format!("--config={}", path)];
+    daemon.extend(args[1..].iter().cloned());
+    Ok(daemon)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RESPONSE: &str = "\
+private_key=c809f3e5317e9575c9b5ed78b638b7ce530dabe85ddab614220241801ddf0669
+listen_port=51820
+fwmark=4660
+public_key=c53201039adba14be71f886da1d8dbe9eebded08cb111b75340078999aa9f038
+preshared_key=0000000000000000000000000000000000000000000000000000000000000000
+protocol_version=1
+rx_bytes=0
+tx_bytes=0
+persistent_keepalive_interval=0
+handshake_initiated=true
+allowed_ip=10.0.0.3/32
+public_key=4eb32f4a83f88d842563a448cc181bb2c42a637bf12363e2fb2ef594e5965d7d
+preshared_key=1690b2870b3d731c16a15e3110bb5f26f8c937ecd04553c84a59515a07a8a551
+protocol_version=1
+rx_bytes=1536
+tx_bytes=3145728
+persistent_keepalive_interval=25
+last_handshake_time_sec=1000
+last_handshake_time_nsec=0
+endpoint=192.95.5.67:1234
+allowed_ip=10.0.0.2/32
+allowed_ip=fd00::/64";
+
+    fn pairs(response: &str) -> Vec<(String, String)> {
+        response
+            .lines()
+            .map(|line| {
+                let mut split = line.splitn(2, '=');
+                (
+                    split.next().unwrap().to_owned(),
+                    split.next().unwrap().to_owned(),
+                )
+            })
+            .collect()
+    }
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_format_device() {
+        let mut device = parse_device(&pairs(RESPONSE));
+        assert_eq!(
+            format_device("wg0", &mut device, 1000 + 3723),
+            "\
+interface: wg0
+  public key: HIgo9xNzJMWLKASShiTqIybxZ0U3wGLiUeJ1PKf8ykw=
+  private key: (hidden)
+  listening port: 51820
+  fwmark: 0x1234
+
+peer: TrMvSoP4jYQlY6RIzBgbssQqY3vxI2Pi+y71lOWWXX0=
+  preshared key: (hidden)
+  endpoint: 192.95.5.67:1234
+  allowed ips: 10.0.0.2/32, fd00::/64
+  latest handshake: 1 hour, 2 minutes, 3 seconds ago
+  transfer: 1.50 KiB received, 3.00 MiB sent
+  persistent keepalive: every 25 seconds
+
+peer: xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=
+  allowed ips: 10.0.0.3/32
+  handshake: initiated, awaiting response
+"
+        );
+    }
+
+    #[test]
+    fn test_showconf() {
+        assert_eq!(
+            config_file(parse_device(&pairs(RESPONSE))).to_string(),
+            "\
+[Interface]
+ListenPort = 51820
+FwMark = 0x1234
+PrivateKey = yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=
+
+[Peer]
+PublicKey = xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=
+AllowedIPs = 10.0.0.3/32
+
+[Peer]
+PublicKey = TrMvSoP4jYQlY6RIzBgbssQqY3vxI2Pi+y71lOWWXX0=
+PresharedKey = FpCyhws9cxwWoV4xELtfJvjJN+zQRVPISllRWgeopVE=
+AllowedIPs = 10.0.0.2/32, fd00::/64
+Endpoint = 192.95.5.67:1234
+PersistentKeepalive = 25
+"
+        );
+    }
+
+    #[test]
+    fn test_set_request() {
+        let request = set_request(&[
+            "listen-port",
+            "51820",
+            "fwmark",
+            "0x10",
+            "replay-window",
+            "4096",
+            "peer",
+            "xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=",
+            "endpoint",
+            "vpn.example.com:51820",
+            "persistent-keepalive",
+            "off",
+            "allowed-ips",
+            "10.0.0.0/8, fd00::/64",
+            "peer",
+            "TrMvSoP4jYQlY6RIzBgbssQqY3vxI2Pi+y71lOWWXX0=",
+            "remove",
+        ])
+        .unwrap();
+        assert_eq!(
+            request,
+            "set=1\n\
+             listen_port=51820\n\
+             fwmark=16\n\
+             replay_window=4096\n\
+             public_key=c53201039adba14be71f886da1d8dbe9eebded08cb111b75340078999aa9f038\n\
+             endpoint=vpn.example.com:51820\n\
+             persistent_keepalive_interval=0\n\
+             replace_allowed_ips=true\n\
+             allowed_ip=10.0.0.0/8\n\
+             allowed_ip=fd00::/64\n\
+             public_key=4eb32f4a83f88d842563a448cc181bb2c42a637bf12363e2fb2ef594e5965d7d\n\
+             remove=true\n\n"
+        );
+
+        // interface settings after a peer, missing values and invalid keys are rejected
+        assert!(set_request(&[
+            "peer",
+            "xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=",
+            "listen-port",
+            "1"
+        ])
+        .is_err());
+        assert!(set_request(&["listen-port"]).is_err());
+        assert!(set_request(&["peer", "invalid"]).is_err());
+    }
+
+    #[test]
+    fn test_up() {
+        assert_eq!(
+            up(&strings(&["wg0"])).unwrap(),
+            strings(&["wg0", "--config=/etc/wireguard/wg0.conf"])
+        );
+        assert_eq!(
+            up(&strings(&["./vpn.conf", "-f"])).unwrap(),
+            strings(&["vpn", "--config=./vpn.conf", "-f"])
+        );
+        assert!(up(&[]).is_err());
+    }
+
+    #[test]
+    fn test_format_units() {
+        assert_eq!(duration(1), "1 second");
+        assert_eq!(duration(90061), "1 day, 1 hour, 1 minute, 1 second");
+        assert_eq!(bytes(1023), "1023 B");
+        assert_eq!(bytes(1024 * 1024 * 1024), "1.00 GiB");
+    }
+
+    #[test]
+    fn test_keys() {
+        assert_eq!(
+            pubkey("yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=\n").unwrap(),
+            "HIgo9xNzJMWLKASShiTqIybxZ0U3wGLiUeJ1PKf8ykw="
+        );
+        assert!(pubkey("invalid").is_err());
+
+        // generated keys are random and round-trip through base64
+        let sk = encode_key(&generate_private_key().to_bytes());
+        assert_ne!(sk, encode_key(&generate_private_key().to_bytes()));
+        assert!(pubkey(&sk).is_ok());
+        // derived keys only depend on the passphrase (without the line ending)
+        let params = "m=64,t=1,p=1".parse().unwrap();
+        let derived = derive("passphrase\n", "test-network", &params).unwrap();
+        assert_eq!(
+            derive("passphrase\r\n", "test-network", &params).unwrap(),
+            derived
+        );
+        assert_ne!(
+            derive("passphrase", "test-networks", &params).unwrap(),
+            derived
+        );
+        assert!(derive("\n", "test-network", &params).is_err());
+
+        let psk = generate_preshared_key();
+        assert_ne!(psk, generate_preshared_key());
+        assert_eq!(parse_key(&encode_key(&psk)), Some(psk));
+    }
+}
@@ -1,11 +1,14 @@
+use std::collections::HashMap;
 use std::mem;
-use std::net::{IpAddr, SocketAddr};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
 use std::sync::atomic::Ordering;
 use std::sync::{Arc, Mutex, MutexGuard};
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 
 use x25519_dalek::{PublicKey, StaticSecret};
 
+use super::diff::{ChangeSet, InterfaceChange, PeerChange};
+use super::file::{self, Interface};
 use super::udp::Owner;
 use super::*;
 
@@ -23,8 +26,15 @@ pub struct PeerState {
     pub last_handshake_time: Option<(u64, u64)>,
     pub public_key: PublicKey,
     pub allowed_ips: Vec<(IpAddr, u32)>,
-    pub endpoint: Option<SocketAddr>,
-    pub persistent_keepalive_interval: u64,
+    pub endpoint: Option<SocketAddr>,  // active endpoint
+    pub endpoint_name: Option<String>, // hostname of the endpoint (resolved again periodically)
+    pub endpoints: Vec<SocketAddr>,    // candidate endpoints (failover), in order
+    pub tcp_fallback: bool,
+    pub persistent_keepalive_interval: u64, // current interval (also when adaptive)
+    pub adaptive_keepalive: Option<(u64, u64)>, // range of the adaptive keepalive
+    pub expiry: Option<u64>, // seconds until the peer is removed (time-limited peers)
+    pub handshake: HandshakeState, // state of the handshake in progress (if any)
+    pub staged_packets: (usize, DropPolicy), // depth and drop policy of the queue awaiting a key-pair
     pub preshared_key: [u8; 32], // 0^32 is the "default value" (though treated like any other psk)
 }
 
@@ -35,6 +45,12 @@ struct Inner<T: tun::Tun, B: udp::PlatformUDP> {
     port: u16,
     bind: Option<B::Owner>,
     fwmark: Option<u32>,
+    interface: Option<String>,
+    ttl: Option<u8>,
+    workers: usize,
+    steer: bool,
+    tcp_listen: bool,
+    tcp: Option<TcpListener>,
 }
 
 impl<T: tun::Tun, B: udp::PlatformUDP> WireGuardConfig<T, B> {
@@ -50,8 +66,86 @@ impl<T: tun::Tun, B: udp::PlatformUDP> WireGuardConfig<T, B> {
             port: 0,
             bind: None,
             fwmark: None,
+            interface: None,
+            ttl: None,
+            workers: 1,
+            steer: false,
+            tcp_listen: false,
+            tcp: None,
         })))
     }
+
+    /// Set the number of UDP sockets (for each IP version) opened by the listener,
+    /// each served by a separate reader thread. Takes effect on the next bind.
+    ///
+    /// # Arguments
+    ///
+    /// - `workers`: Number of sockets bound to the listen port (SO_REUSEPORT)
+    /// - `steer`: Steer transport messages to a socket by receiver index
+    pub fn set_udp_workers(&self, workers: usize, steer: bool) {
+        let mut cfg = self.lock();
+        cfg.workers = workers.max(1);
+        cfg.steer = steer;
+    }
+
+    /// Accept connections from peers using the TCP fallback transport,
+    /// on the TCP port matching the listen port. Takes effect on the next bind.
+    ///
+    /// # Arguments
+    ///
+    /// - `enabled`: Listen for TCP connections
+    pub fn set_tcp_listen(&self, enabled: bool) {
+        self.lock().tcp_listen = enabled;
+    }
+
+    /// Open the TCP connections of the fallback transport through a proxy
+    ///
+    /// # Arguments
+    ///
+    /// - `proxy`: A SOCKS5 or HTTP CONNECT proxy, or None to connect directly
+    pub fn set_tcp_proxy(&self, proxy: Option<TcpProxy>) {
+        self.lock().wireguard.set_tcp_proxy(proxy);
+    }
+
+    /// Install an obfuscator applied to every datagram sent and received
+    /// (e.g. padding or a pluggable transport of the host application)
+    ///
+    /// # Arguments
+    ///
+    /// - `obfuscator`: The obfuscator, or None to send the messages unmodified
+    pub fn set_obfuscator(&self, obfuscator: Option<Arc<dyn Obfuscator>>) {
+        self.lock().wireguard.set_obfuscator(obfuscator);
+    }
+
+    /// Set (or unset) the callback invoked with inbound messages of unknown type
+    /// (e.g. to monitor scanning activity or handle protocol extensions)
+    ///
+    /// # Arguments
+    ///
+    /// - `handler`: The callback, invoked with the message and its source
+    pub fn set_unknown_message_handler(&self, handler: Option<UnknownMessageHandler<B::Endpoint>>) {
+        self.lock().wireguard.set_unknown_message_handler(handler);
+    }
+
+    /// Set (or unset) the callback invoked when a time-limited peer
+    /// (see the "expiry" key of the UAPI) has expired and been removed
+    ///
+    /// # Arguments
+    ///
+    /// - `handler`: The callback, invoked with the public key of the peer
+    pub fn set_expiry_handler(&self, handler: Option<PeerExpiryHandler>) {
+        self.lock().wireguard.set_expiry_handler(handler);
+    }
+
+    /// Set (or unset) the callback invoked when a new key-pair with a peer is confirmed
+    /// (e.g. to report that the tunnel is connected)
+    ///
+    /// # Arguments
+    ///
+    /// - `handler`: The callback, invoked with the public key of the peer
+    pub fn set_key_confirmed_handler(&self, handler: Option<KeyConfirmedHandler>) {
+        self.lock().wireguard.set_key_confirmed_handler(handler);
+    }
 }
 
 impl<T: tun::Tun, B: udp::PlatformUDP> Clone for WireGuardConfig<T, B> {
@@ -87,6 +181,17 @@ pub trait Configuration {
     /// An integer indicating the protocol version
     fn get_protocol_version(&self) -> usize;
 
+    /// Updates the listen port of the device,
+    /// rebinding the sockets (if bound) without affecting the peers or their sessions
+    ///
+    /// # Arguments
+    ///
+    /// - `port`: The new listen port (0 to select a port at random)
+    ///
+    /// # Returns
+    ///
+    /// An error if the new port could not be bound,
+    /// in which case the device keeps listening on the old port.
     fn set_listen_port(&self, port: u16) -> Result<(), ConfigError>;
 
     /// Set the firewall mark (or similar, depending on platform)
@@ -101,6 +206,93 @@ pub trait Configuration {
     /// "bind" implementation.
     fn set_fwmark(&self, mark: Option<u32>) -> Result<(), ConfigError>;
 
+    /// Bind the UDP sockets to a network interface,
+    /// pinning the outer tunnel traffic to the chosen uplink (retained across rebinds)
+    ///
+    /// # Arguments
+    ///
+    /// - `name`: The name of the interface (or None to use any interface)
+    ///
+    /// # Returns
+    ///
+    /// An error if the sockets could not be bound to the interface
+    /// (e.g. missing privileges or no such interface).
+    fn set_interface(&self, name: Option<&str>) -> Result<(), ConfigError>;
+
+    fn get_interface(&self) -> Option<String>;
+
+    /// Close and reopen the UDP sockets after a change of network connectivity
+    /// (e.g. roaming between Wi-Fi and cellular on a mobile device):
+    /// the sockets are rebound on the current listen port,
+    /// the source addresses of all endpoints are cleared
+    /// and a handshake is immediately initiated with every peer.
+    ///
+    /// # Arguments
+    ///
+    /// - `interface`: The interface to bind the new sockets to (or None to retain the current setting)
+    ///
+    /// # Returns
+    ///
+    /// An error if the sockets could not be rebound, in which case the old sockets are retained.
+    fn rebind(&self, interface: Option<&str>) -> Result<(), ConfigError>;
+
+    /// Set the TTL (IPv4) / hop limit (IPv6) of the outer packets,
+    /// for both handshake and transport messages (retained across rebinds)
+    ///
+    /// # Arguments
+    ///
+    /// - `ttl`: The TTL / hop limit (or None to use the system default)
+    fn set_ttl(&self, ttl: Option<u8>) -> Result<(), ConfigError>;
+
+    fn get_ttl(&self) -> Option<u8>;
+
+    /// Set a fixed DSCP value for the outer packets
+    /// (by default the DSCP of the inner packet is copied to the outer packet)
+    ///
+    /// # Arguments
+    ///
+    /// - `dscp`: The DSCP value (6 bits), or None to copy the DSCP of the inner packets
+    fn set_dscp(&self, dscp: Option<u8>);
+
+    fn get_dscp(&self) -> Option<u8>;
+
+    /// Set the size of the anti-replay window (in packets) of new key-pairs,
+    /// tolerating more reordering of the transport messages (e.g. on multi-path links)
+    ///
+    /// # Arguments
+    ///
+    /// - `size`: The window size (clamped to the supported range)
+    fn set_replay_window(&self, size: u64);
+
+    fn get_replay_window(&self) -> u64;
+
+    /// Set the replay and flood thresholds of the handshake
+    /// (e.g. a timestamp tolerance for high-latency links)
+    ///
+    /// # Arguments
+    ///
+    /// - `config`: The new handshake configuration
+    fn set_handshake_config(&self, config: HandshakeConfig);
+
+    fn get_handshake_config(&self) -> HandshakeConfig;
+
+    /// Set the policy for inbound packets with an inner source address
+    /// outside the allowed IPs of the sending peer
+    ///
+    /// # Arguments
+    ///
+    /// - `policy`: Drop (strict) or deliver (permissive) the packets
+    fn set_source_policy(&self, policy: SourcePolicy);
+
+    fn get_source_policy(&self) -> SourcePolicy;
+
+    /// Returns the number of inbound packets violating the cryptokey routing
+    fn get_source_violations(&self) -> u64;
+
+    /// Returns the number of inbound messages dropped by the type de-multiplexer
+    /// (unknown type, too short or oversized)
+    fn get_message_stats(&self) -> MessageStats;
+
     /// Removes all peers from the device
     fn replace_peers(&self);
 
@@ -128,6 +320,43 @@ pub trait Configuration {
     /// If the peer already exists this operation is a noop
     fn add_peer(&self, peer: &PublicKey) -> bool;
 
+    /// Replace the public key of a peer (key rotation),
+    /// retaining its allowed IPs, preshared key, endpoint and sessions
+    ///
+    /// # Arguments
+    ///
+    /// - `old`: The current public key of the peer
+    /// - `new`: The new public key of the peer
+    ///
+    /// # Returns
+    ///
+    /// A bool indicating if the key was replaced
+    /// (false if no such peer exists or another peer has the new key).
+    fn replace_peer_key(&self, old: &PublicKey, new: &PublicKey) -> bool;
+
+    /// Immediately initiate a handshake with the peer
+    ///
+    /// # Arguments
+    ///
+    /// - `peer`: The public key of the peer
+    ///
+    /// # Returns
+    ///
+    /// A bool indicating if the peer exists.
+    fn begin_handshake(&self, peer: &PublicKey) -> bool;
+
+    /// Invalidate the sessions with the peer,
+    /// forcing a new handshake before the next transport message
+    ///
+    /// # Arguments
+    ///
+    /// - `peer`: The public key of the peer
+    ///
+    /// # Returns
+    ///
+    /// A bool indicating if the peer exists.
+    fn clear_session(&self, peer: &PublicKey) -> bool;
+
     /// Update the psk of a peer
     ///
     /// # Arguments
@@ -138,7 +367,20 @@ pub trait Configuration {
     /// # Returns
     ///
     /// An error if no such peer exists
-    fn set_preshared_key(&self, peer: &PublicKey, psk: [u8; 32]);
+    fn set_preshared_key(&self, peer: &PublicKey, psk: Option<[u8; 32]>);
+
+    /// Update the hybrid (post-quantum) secret of a peer, combined with the psk,
+    /// e.g. from a key encapsulation run out-of-band (write-only, not reported by get)
+    ///
+    /// # Arguments
+    ///
+    /// - `peer`: The public key of the peer
+    /// - `secret`: The new secret or None if the secret should be unset
+    ///
+    /// # Returns
+    ///
+    /// A bool indicating if the peer exists.
+    fn set_hybrid_secret(&self, peer: &PublicKey, secret: Option<[u8; 32]>) -> bool;
 
     /// Update the endpoint of the
     ///
@@ -148,6 +390,38 @@ pub trait Configuration {
     /// - `psk`
     fn set_endpoint(&self, peer: &PublicKey, addr: SocketAddr);
 
+    /// Set the endpoint of the peer to a hostname,
+    /// re-resolved periodically and after handshakes fail to complete
+    ///
+    /// # Arguments
+    ///
+    /// - `peer`: The public key of the peer
+    /// - `name`: The endpoint as "host:port"
+    ///
+    /// # Returns
+    ///
+    /// An error if the name could not be resolved
+    fn set_endpoint_name(&self, peer: &PublicKey, name: &str) -> Result<(), ConfigError>;
+
+    /// Set an ordered list of candidate endpoints for the peer,
+    /// the peer fails over to the next candidate when handshakes fail to complete
+    ///
+    /// # Arguments
+    ///
+    /// - `peer`: The public key of the peer
+    /// - `addrs`: The candidate endpoints, in order of preference
+    fn set_endpoints(&self, peer: &PublicKey, addrs: Vec<SocketAddr>);
+
+    /// Enable falling back to TCP (length-prefixed messages) for the peer,
+    /// when handshakes over UDP fail to complete (e.g. on networks blocking UDP).
+    /// The peer returns to UDP when handshakes over TCP fail to complete.
+    ///
+    /// # Arguments
+    ///
+    /// - `peer`: The public key of the peer
+    /// - `enabled`: Fall back to TCP
+    fn set_tcp_fallback(&self, peer: &PublicKey, enabled: bool);
+
     /// Update the endpoint of the
     ///
     /// # Arguments
@@ -156,6 +430,35 @@ pub trait Configuration {
     /// - `psk`
     fn set_persistent_keepalive_interval(&self, peer: &PublicKey, secs: u64);
 
+    /// Enable (or disable) the adaptive persistent keepalive of the peer:
+    /// the interval is increased from min to max until the NAT binding times out,
+    /// settling on the longest interval which keeps it.
+    ///
+    /// # Arguments
+    ///
+    /// - `peer`: The public key of the peer
+    /// - `range`: The shortest and longest interval (in seconds),
+    ///   or None to return to the fixed persistent keepalive interval
+    fn set_adaptive_keepalive(&self, peer: &PublicKey, range: Option<(u64, u64)>);
+
+    /// Limit the lifetime of the peer: once expired the peer is removed
+    /// and its sessions wiped (e.g. for guest access).
+    ///
+    /// # Arguments
+    ///
+    /// - `peer`: The public key of the peer
+    /// - `secs`: Seconds (from now) until the peer expires, or None to never expire
+    fn set_peer_expiry(&self, peer: &PublicKey, secs: Option<u64>);
+
+    /// Configure the queue of outbound packets awaiting a key-pair (e.g. during the handshake)
+    ///
+    /// # Arguments
+    ///
+    /// - `peer`: The public key of the peer
+    /// - `depth`: Maximum number of staged packets
+    /// - `policy`: Drop the oldest (Head) or the new (Tail) packet when the queue is full
+    fn set_staged_packets(&self, peer: &PublicKey, depth: usize, policy: DropPolicy);
+
     /// Remove all allowed IPs from the peer
     ///
     /// # Arguments
@@ -190,23 +493,76 @@ pub trait Configuration {
     fn get_peers(&self) -> Vec<PeerState>;
 
     fn get_fwmark(&self) -> Option<u32>;
+
+    /// Apply a configuration as the delta against the running state (as "wg syncconf"):
+    /// peers missing from the configuration are removed, while the other peers are added
+    /// or updated in place, retaining the sessions of the peers which remain.
+    ///
+    /// The endpoints are resolved and the listen port bound before any peer is modified,
+    /// the peers are then updated while holding the configuration lock
+    /// (hence other configuration clients never observe a partially applied configuration).
+    ///
+    /// # Arguments
+    ///
+    /// - `config`: The new configuration
+    ///
+    /// # Returns
+    ///
+    /// An error if an endpoint could not be resolved or the listen port could not be bound,
+    /// in which case the peers are left unchanged.
+    fn apply_config(&self, config: &ConfigFile) -> Result<(), ConfigError>;
 }
 
+/* Bind the listen port and hand the sockets to the WireGuard device.
+ *
+ * The previous bind (if any) is only released once the new writer is installed,
+ * hence outbound traffic is never without a socket and a failed bind leaves the old listener running.
+ * The exception is the TCP listener (if enabled), which is released before binding the new one.
+ */
 fn start_listener<T: tun::Tun, B: udp::PlatformUDP>(
     mut cfg: MutexGuard<Inner<T, B>>,
 ) -> Result<(), ConfigError> {
-    cfg.bind = None;
-
     // create new listener
-    let (mut readers, writer, mut owner) = match B::bind(cfg.port) {
+    let (mut readers, writer, mut owner) = match B::bind_reuseport(cfg.port, cfg.workers, cfg.steer)
+    {
         Ok(r) => r,
         Err(_) => {
             return Err(ConfigError::FailedToBind);
         }
     };
 
-    // set fwmark
-    let _ = owner.set_fwmark(cfg.fwmark); // TODO: handle
+    // set fwmark (retained across rebinds)
+    if let Err(e) = owner.set_fwmark(cfg.fwmark) {
+        log::warn!("failed to set fwmark {:?} on new bind: {}", cfg.fwmark, e);
+    }
+
+    // set TTL / hop limit of outer packets
+    if let Err(e) = owner.set_ttl(cfg.ttl) {
+        log::warn!("failed to set TTL {:?} on new bind: {}", cfg.ttl, e);
+    }
+
+    // bind to interface (never send over another uplink)
+    if let Some(name) = cfg.interface.as_ref() {
+        if let Err(e) = owner.set_interface(Some(name.as_str())) {
+            log::warn!("failed to bind to interface {}: {}", name, e);
+            return Err(ConfigError::FailedToBind);
+        }
+    }
+
+    // accept TCP connections on the same port (the UDP sockets remain the preferred path),
+    // the old listener is released first: it may hold the same port (rebind)
+    let tcp = if cfg.tcp_listen {
+        cfg.tcp = None;
+        match cfg.wireguard.listen_tcp(owner.get_port()) {
+            Ok(listener) => Some(listener),
+            Err(e) => {
+                log::warn!("failed to listen on TCP port {}: {}", owner.get_port(), e);
+                return Err(ConfigError::FailedToBind);
+            }
+        }
+    } else {
+        None
+    };
 
     // set writer on WireGuard
     cfg.wireguard.set_writer(writer);
@@ -216,16 +572,19 @@ fn start_listener<T: tun::Tun, B: udp::PlatformUDP>(
         cfg.wireguard.add_udp_reader(reader);
     }
 
-    // create new UDP state
+    // replace UDP state (closing the old sockets terminates their readers)
     cfg.bind = Some(owner);
+    cfg.tcp = tcp;
     Ok(())
 }
 
 impl<T: tun::Tun, B: udp::PlatformUDP> Configuration for WireGuardConfig<T, B> {
     fn up(&self, mtu: usize) -> Result<(), ConfigError> {
         log::info!("configuration, set device up");
-        let cfg = self.lock();
+        let mut cfg = self.lock();
         cfg.wireguard.up(mtu);
+        cfg.bind = None;
+        cfg.tcp = None;
         start_listener(cfg)
     }
 
@@ -234,6 +593,7 @@ impl<T: tun::Tun, B: udp::PlatformUDP> Configuration for WireGuardConfig<T, B> {
         let mut cfg = self.lock();
         cfg.wireguard.down();
         cfg.bind = None;
+        cfg.tcp = None;
     }
 
     fn get_fwmark(&self) -> Option<u32> {
@@ -262,25 +622,31 @@ impl<T: tun::Tun, B: udp::PlatformUDP> Configuration for WireGuardConfig<T, B> {
     fn set_listen_port(&self, port: u16) -> Result<(), ConfigError> {
         log::trace!("Config, Set listen port: {:?}", port);
 
-        // update port and take old bind
+        // update port
         let mut cfg = self.lock();
-        let bound: bool = {
-            let old = mem::replace(&mut cfg.bind, None);
-            cfg.port = port;
-            old.is_some()
-        };
+        let old = mem::replace(&mut cfg.port, port);
 
-        // restart listener if bound
-        if bound {
-            start_listener(cfg)
-        } else {
-            Ok(())
+        // rebind if listening on another port
+        let bound = cfg.bind.as_ref().map(|bind| bind.get_port());
+        match bound {
+            None => Ok(()),
+            Some(bound) if port != 0 && bound == port => Ok(()),
+            Some(_) => {
+                let res = start_listener(cfg);
+                if res.is_err() {
+                    log::warn!("failed to bind listen port {}, keeping the old bind", port);
+                    self.lock().port = old;
+                }
+                res
+            }
         }
     }
 
     fn set_fwmark(&self, mark: Option<u32>) -> Result<(), ConfigError> {
         log::trace!("Config, Set fwmark: {:?}", mark);
-        match self.lock().bind.as_mut() {
+        let mut cfg = self.lock();
+        cfg.fwmark = mark;
+        match cfg.bind.as_mut() {
             Some(bind) => {
                 if bind.set_fwmark(mark).is_err() {
                     Err(ConfigError::IOError)
@@ -292,6 +658,117 @@ impl<T: tun::Tun, B: udp::PlatformUDP> Configuration for WireGuardConfig<T, B> {
         }
     }
 
+    fn set_interface(&self, name: Option<&str>) -> Result<(), ConfigError> {
+        log::trace!("Config, Set interface: {:?}", name);
+        let mut cfg = self.lock();
+        let old = cfg.interface.clone();
+        if let Some(bind) = cfg.bind.as_mut() {
+            if let Err(e) = bind.set_interface(name) {
+                log::warn!("failed to bind to interface {:?}: {}", name, e);
+
+                // undo the binding of the sockets updated before the failure
+                if let Err(e) = bind.set_interface(old.as_deref()) {
+                    log::warn!("failed to restore interface {:?}: {}", old, e);
+                }
+                return Err(ConfigError::IOError);
+            }
+        }
+        cfg.interface = name.map(|name| name.to_owned());
+        Ok(())
+    }
+
+    fn get_interface(&self) -> Option<String> {
+        self.lock().interface.clone()
+    }
+
+    fn rebind(&self, interface: Option<&str>) -> Result<(), ConfigError> {
+        log::info!("configuration, rebind sockets (network change)");
+        let mut cfg = self.lock();
+        let old = cfg.interface.clone();
+        if let Some(name) = interface {
+            cfg.interface = Some(name.to_owned());
+        }
+
+        // reopen on the bound port (also when it was selected at random)
+        let bound = match cfg.bind.as_ref() {
+            Some(bind) => bind.get_port(),
+            None => return Ok(()),
+        };
+        let port = mem::replace(&mut cfg.port, bound);
+        let wg = cfg.wireguard.clone();
+        let res = start_listener(cfg);
+
+        let mut cfg = self.lock();
+        cfg.port = port;
+        if res.is_err() {
+            log::warn!("failed to rebind sockets, keeping the old bind");
+            cfg.interface = old;
+            return res;
+        }
+        drop(cfg);
+
+        wg.network_changed();
+        Ok(())
+    }
+
+    fn set_ttl(&self, ttl: Option<u8>) -> Result<(), ConfigError> {
+        log::trace!("Config, Set TTL: {:?}", ttl);
+        let mut cfg = self.lock();
+        cfg.ttl = ttl;
+        match cfg.bind.as_mut() {
+            Some(bind) => bind.set_ttl(ttl).map_err(|_| ConfigError::IOError),
+            None => Ok(()),
+        }
+    }
+
+    fn get_ttl(&self) -> Option<u8> {
+        self.lock().ttl
+    }
+
+    fn set_dscp(&self, dscp: Option<u8>) {
+        log::trace!("Config, Set DSCP: {:?}", dscp);
+        self.lock().wireguard.router.set_dscp(dscp);
+    }
+
+    fn get_dscp(&self) -> Option<u8> {
+        self.lock().wireguard.router.get_dscp()
+    }
+
+    fn set_replay_window(&self, size: u64) {
+        log::trace!("Config, Set replay window: {}", size);
+        self.lock().wireguard.set_replay_window(size);
+    }
+
+    fn get_replay_window(&self) -> u64 {
+        self.lock().wireguard.get_replay_window()
+    }
+
+    fn set_handshake_config(&self, config: HandshakeConfig) {
+        log::trace!("Config, Set handshake config: {:?}", config);
+        self.lock().wireguard.set_handshake_config(config);
+    }
+
+    fn get_handshake_config(&self) -> HandshakeConfig {
+        self.lock().wireguard.get_handshake_config()
+    }
+
+    fn set_source_policy(&self, policy: SourcePolicy) {
+        log::trace!("Config, Set source policy: {:?}", policy);
+        self.lock().wireguard.set_source_policy(policy);
+    }
+
+    fn get_source_policy(&self) -> SourcePolicy {
+        self.lock().wireguard.get_source_policy()
+    }
+
+    fn get_source_violations(&self) -> u64 {
+        self.lock().wireguard.source_violations()
+    }
+
+    fn get_message_stats(&self) -> MessageStats {
+        self.lock().wireguard.message_stats()
+    }
+
     fn replace_peers(&self) {
         self.lock().wireguard.clear_peers();
     }
@@ -304,13 +781,66 @@ impl<T: tun::Tun, B: udp::PlatformUDP> Configuration for WireGuardConfig<T, B> {
         self.lock().wireguard.add_peer(*peer)
     }
 
-    fn set_preshared_key(&self, peer: &PublicKey, psk: [u8; 32]) {
+    fn replace_peer_key(&self, old: &PublicKey, new: &PublicKey) -> bool {
+        self.lock().wireguard.replace_peer_key(old, *new)
+    }
+
+    fn begin_handshake(&self, peer: &PublicKey) -> bool {
+        self.lock().wireguard.begin_handshake(peer)
+    }
+
+    fn clear_session(&self, peer: &PublicKey) -> bool {
+        self.lock().wireguard.clear_session(peer)
+    }
+
+    fn set_preshared_key(&self, peer: &PublicKey, psk: Option<[u8; 32]>) {
         self.lock().wireguard.set_psk(*peer, psk);
     }
 
+    fn set_hybrid_secret(&self, peer: &PublicKey, secret: Option<[u8; 32]>) -> bool {
+        log::trace!("Config, Set hybrid secret: {}", secret.is_some());
+        self.lock().wireguard.set_hybrid_secret(*peer, secret)
+    }
+
     fn set_endpoint(&self, peer: &PublicKey, addr: SocketAddr) {
         if let Some(peer) = self.lock().wireguard.peers.read().get(peer) {
             peer.set_endpoint(B::Endpoint::from_address(addr));
+            *peer.endpoint_name.lock() = None;
+            peer.endpoints.lock().clear();
+        }
+    }
+
+    fn set_endpoints(&self, peer: &PublicKey, addrs: Vec<SocketAddr>) {
+        self.lock().wireguard.set_endpoints(peer, addrs);
+    }
+
+    fn set_endpoint_name(&self, peer: &PublicKey, name: &str) -> Result<(), ConfigError> {
+        // resolve without holding the configuration lock
+        let wg = self.lock().wireguard.clone();
+        match wg.set_endpoint_name(peer, name) {
+            Ok(addr) => {
+                log::debug!("Config, endpoint {} resolved to {}", name, addr);
+                Ok(())
+            }
+            Err(e) => {
+                log::info!("Config, failed to resolve endpoint {}: {}", name, e);
+                Err(ConfigError::InvalidSocketAddr)
+            }
+        }
+    }
+
+    fn set_tcp_fallback(&self, peer: &PublicKey, enabled: bool) {
+        let cfg = self.lock();
+        let peers = cfg.wireguard.peers.read();
+        if let Some(peer) = peers.get(peer) {
+            peer.opaque().tcp_fallback.store(enabled, Ordering::SeqCst);
+
+            // return to UDP
+            if !enabled {
+                if let Some(addr) = peer.get_endpoint() {
+                    cfg.wireguard.tcp.remove(&addr, None);
+                }
+            }
         }
     }
 
@@ -320,6 +850,24 @@ impl<T: tun::Tun, B: udp::PlatformUDP> Configuration for WireGuardConfig<T, B> {
         }
     }
 
+    fn set_adaptive_keepalive(&self, peer: &PublicKey, range: Option<(u64, u64)>) {
+        if let Some(peer) = self.lock().wireguard.peers.read().get(peer) {
+            peer.opaque().set_adaptive_keepalive(range);
+        }
+    }
+
+    fn set_peer_expiry(&self, peer: &PublicKey, secs: Option<u64>) {
+        self.lock()
+            .wireguard
+            .set_peer_expiry(peer, secs.map(Duration::from_secs));
+    }
+
+    fn set_staged_packets(&self, peer: &PublicKey, depth: usize, policy: DropPolicy) {
+        if let Some(peer) = self.lock().wireguard.peers.read().get(peer) {
+            peer.set_staged_packets(depth, policy);
+        }
+    }
+
     fn replace_allowed_ips(&self, peer: &PublicKey) {
         if let Some(peer) = self.lock().wireguard.peers.read().get(peer) {
             peer.remove_allowed_ips();
@@ -352,33 +900,387 @@ impl<T: tun::Tun, B: udp::PlatformUDP> Configuration for WireGuardConfig<T, B> {
     */
 
     fn get_peers(&self) -> Vec<PeerState> {
+        peer_states(&self.lock())
+    }
+
+    fn apply_config(&self, config: &ConfigFile) -> Result<(), ConfigError> {
+        log::info!("configuration, apply configuration");
+
+        // resolve the endpoints (without holding the configuration lock)
+        let mut resolved = HashMap::new();
+        for endpoint in config
+            .peers
+            .iter()
+            .filter_map(|peer| peer.endpoint.as_ref())
+        {
+            let addr = match endpoint.parse::<SocketAddr>() {
+                Ok(addr) => addr,
+                Err(_) => match endpoint.to_socket_addrs().ok().and_then(|mut a| a.next()) {
+                    Some(addr) => addr,
+                    None => {
+                        log::info!("Config, failed to resolve endpoint {}", endpoint);
+                        return Err(ConfigError::InvalidSocketAddr);
+                    }
+                },
+            };
+            resolved.insert(endpoint.as_str(), addr);
+        }
+
+        // bind the listen port (the old bind is retained on failure)
+        if let Some(port) = config.interface.listen_port {
+            if self.lock().port != port {
+                self.set_listen_port(port)?;
+            }
+        }
+        if self.get_fwmark() != config.interface.fwmark {
+            self.set_fwmark(config.interface.fwmark)?;
+        }
+
+        // compare with the running state (in which the listen port and fwmark are applied)
         let cfg = self.lock();
-        let peers = cfg.wireguard.peers.read();
-        let mut state = Vec::with_capacity(peers.len());
-
-        for (pk, p) in peers.iter() {
-            // convert the system time to (secs, nano) since epoch
-            let last_handshake_time = (*p.walltime_last_handshake.lock()).map(|t| {
-                let duration = t
-                    .duration_since(SystemTime::UNIX_EPOCH)
-                    .unwrap_or_else(|_| Duration::from_secs(0));
-                (duration.as_secs(), duration.subsec_nanos() as u64)
-            });
-
-            if let Some(psk) = cfg.wireguard.get_psk(&pk) {
-                // extract state into PeerState
-                state.push(PeerState {
-                    preshared_key: psk,
-                    endpoint: p.get_endpoint(),
-                    rx_bytes: p.rx_bytes.load(Ordering::Relaxed),
-                    tx_bytes: p.tx_bytes.load(Ordering::Relaxed),
-                    persistent_keepalive_interval: p.get_keepalive_interval(),
-                    allowed_ips: p.list_allowed_ips(),
-                    last_handshake_time,
-                    public_key: pk,
-                })
+        let wg = &cfg.wireguard;
+        let current = ConfigFile {
+            interface: Interface {
+                private_key: wg.get_sk(),
+                listen_port: config.interface.listen_port,
+                fwmark: config.interface.fwmark,
+                ..Default::default()
+            },
+            peers: peer_states(&cfg)
+                .into_iter()
+                .map(file::Peer::from_state)
+                .collect(),
+        };
+        let changes = ChangeSet::between(&current, config);
+        if changes.is_empty() {
+            log::info!("Config, no changes");
+        }
+        for line in changes.to_string().lines() {
+            log::info!("Config, {}", line);
+        }
+
+        // a new private key invalidates every session
+        if changes.interface.contains(&InterfaceChange::PrivateKey) {
+            wg.set_key(config.interface.private_key.clone());
+        }
+
+        for pk in changes.removed.iter() {
+            wg.remove_peer(pk);
+        }
+
+        for (pk, peer_changes) in changes.added.iter().chain(changes.modified.iter()) {
+            let peer = match config
+                .peers
+                .iter()
+                .find(|peer| peer.public_key.as_bytes() == pk.as_bytes())
+            {
+                Some(peer) => peer,
+                None => continue,
+            };
+            wg.add_peer(*pk);
+            if peer_changes.contains(&PeerChange::PresharedKey) {
+                wg.set_psk(*pk, peer.preshared_key);
+            }
+
+            let peers = wg.peers.read();
+            let handle = match peers.get(pk) {
+                Some(handle) => handle,
+                None => continue,
+            };
+            for change in peer_changes.iter() {
+                match change {
+                    PeerChange::AllowedIps { .. } => handle.set_allowed_ips(&peer.allowed_ips),
+                    PeerChange::PersistentKeepalive(secs) => {
+                        handle.opaque().set_persistent_keepalive_interval(*secs)
+                    }
+                    PeerChange::Endpoint(endpoint) => {
+                        if let Some(addr) = resolved.get(endpoint.as_str()) {
+                            let name = Some((endpoint.clone(), Instant::now()))
+                                .filter(|_| endpoint.parse::<SocketAddr>().is_err());
+                            handle.set_endpoint(B::Endpoint::from_address(*addr));
+                            *handle.endpoint_name.lock() = name;
+                            handle.endpoints.lock().clear();
+                        }
+                    }
+                    // applied above / not part of configuration files
+                    PeerChange::PresharedKey
+                    | PeerChange::Endpoints(_)
+                    | PeerChange::TcpFallback(_)
+                    | PeerChange::AdaptiveKeepalive(_) => (),
+                }
             }
         }
-        state
+        Ok(())
+    }
+}
+
+// Internal function
+//
+// Snapshot of the state of every peer
+fn peer_states<T: tun::Tun, B: udp::PlatformUDP>(cfg: &Inner<T, B>) -> Vec<PeerState> {
+    let peers = cfg.wireguard.peers.read();
+    let mut state = Vec::with_capacity(peers.len());
+
+    for (pk, info) in peers.peers() {
+        let p = match peers.get(&pk) {
+            Some(p) => p,
+            None => continue,
+        };
+
+        // convert the system time to (secs, nano) since epoch
+        let last_handshake_time = (*p.walltime_last_handshake.lock()).map(|t| {
+            let duration = t
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_else(|_| Duration::from_secs(0));
+            (duration.as_secs(), duration.subsec_nanos() as u64)
+        });
+
+        if let Some(psk) = cfg.wireguard.get_psk(&pk) {
+            // extract state into PeerState
+            state.push(PeerState {
+                preshared_key: psk,
+                endpoint: p.get_endpoint(),
+                endpoint_name: p
+                    .endpoint_name
+                    .lock()
+                    .as_ref()
+                    .map(|(name, _)| name.clone()),
+                endpoints: p.endpoints.lock().clone(),
+                tcp_fallback: p.tcp_fallback.load(Ordering::Relaxed),
+                rx_bytes: p.rx_bytes.load(Ordering::Relaxed),
+                tx_bytes: p.tx_bytes.load(Ordering::Relaxed),
+                persistent_keepalive_interval: p.get_keepalive_interval(),
+                adaptive_keepalive: p.get_adaptive_keepalive().map(|(min, max, _)| (min, max)),
+                expiry: cfg.wireguard.get_peer_expiry(&pk).map(|ttl| ttl.as_secs()),
+                staged_packets: p.get_staged_packets(),
+                allowed_ips: p.list_allowed_ips(),
+                handshake: info.state,
+                last_handshake_time,
+                public_key: pk,
+            })
+        }
+    }
+    state
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::super::super::platform::dummy;
+    use super::super::super::platform::linux::UDP as LinuxUDP;
+    use super::*;
+
+    use rand::rngs::OsRng;
+    use std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+    use std::thread;
+
+    #[test]
+    fn test_set_listen_port() {
+        let (_fake, _reader, tun_writer, _mtu) = dummy::TunTest::create(false);
+        let cfg: WireGuardConfig<dummy::TunTest, LinuxUDP> =
+            WireGuardConfig::new(WireGuard::new(tun_writer));
+
+        let pk = PublicKey::from(&StaticSecret::new(&mut OsRng));
+        cfg.set_private_key(Some(StaticSecret::new(&mut OsRng)));
+        cfg.add_peer(&pk);
+
+        // not bound: only the port is updated
+        cfg.set_listen_port(0).unwrap();
+        assert_eq!(cfg.get_listen_port(), None);
+        cfg.up(1420).unwrap();
+        let port = cfg.get_listen_port().unwrap();
+
+        // rebind to another port, the peers are retained
+        cfg.set_listen_port(0).unwrap();
+        let new = cfg.get_listen_port().unwrap();
+        assert_ne!(port, new);
+        assert_eq!(cfg.get_peers().len(), 1);
+
+        // the old port is released (once the old readers have terminated)
+        let released = (0..100).any(|_| {
+            thread::sleep(Duration::from_millis(10));
+            UdpSocket::bind(("127.0.0.1", port)).is_ok()
+        });
+        assert!(released, "old port should be released");
+
+        // binding an occupied port fails, but the device keeps listening
+        // (dual-stack socket, occupying the port for both IP versions)
+        let taken = UdpSocket::bind("[::]:0").unwrap();
+        let occupied = taken.local_addr().unwrap().port();
+        assert!(cfg.set_listen_port(occupied).is_err());
+        assert_eq!(cfg.get_listen_port(), Some(new));
+
+        // setting the current port is a noop
+        cfg.set_listen_port(new).unwrap();
+        assert_eq!(cfg.get_listen_port(), Some(new));
+        cfg.down();
+    }
+
+    #[test]
+    fn test_rebind() {
+        let (_fake, _reader, tun_writer, _mtu) = dummy::TunTest::create(false);
+        let cfg: WireGuardConfig<dummy::TunTest, LinuxUDP> =
+            WireGuardConfig::new(WireGuard::new(tun_writer));
+
+        // the peer endpoint is a plain socket
+        let remote = UdpSocket::bind("127.0.0.1:0").unwrap();
+        remote
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let pk = PublicKey::from(&StaticSecret::new(&mut OsRng));
+        cfg.set_private_key(Some(StaticSecret::new(&mut OsRng)));
+        cfg.add_peer(&pk);
+        cfg.set_endpoint(&pk, remote.local_addr().unwrap());
+        cfg.set_tcp_listen(true);
+
+        // not bound: nothing to rebind
+        cfg.rebind(None).unwrap();
+        assert_eq!(cfg.get_listen_port(), None);
+        cfg.up(1420).unwrap();
+        let port = cfg.get_listen_port().unwrap();
+
+        // the port is retained and a handshake initiated immediately, every time
+        let mut buf = [0u8; 1500];
+        for _ in 0..2 {
+            cfg.rebind(None).unwrap();
+            assert_eq!(cfg.get_listen_port(), Some(port));
+            assert_eq!(cfg.lock().tcp.as_ref().map(|l| l.get_port()), Some(port));
+            assert_eq!(cfg.get_peers().len(), 1);
+            let (len, src) = remote.recv_from(&mut buf).unwrap();
+            assert_eq!(len, 148, "expected a handshake initiation");
+            assert_eq!(buf[0], 1);
+            assert_eq!(src.port(), port);
+        }
+
+        // the random port is selected again once the device is restarted
+        assert_eq!(cfg.lock().port, 0);
+        cfg.down();
+    }
+
+    #[test]
+    fn test_tcp_fallback() {
+        let create = || {
+            let (fake, _reader, tun_writer, _mtu) = dummy::TunTest::create(false);
+            let cfg: WireGuardConfig<dummy::TunTest, LinuxUDP> =
+                WireGuardConfig::new(WireGuard::new(tun_writer));
+            let sk = StaticSecret::new(&mut OsRng);
+            let pk = PublicKey::from(&sk);
+            cfg.set_private_key(Some(sk));
+            (fake, cfg, pk)
+        };
+        let (_fake1, cfg1, pk1) = create();
+        let (_fake2, cfg2, pk2) = create();
+        cfg1.add_peer(&pk2);
+        cfg2.add_peer(&pk1);
+
+        // the TCP listener follows the listen port
+        cfg1.set_tcp_listen(true);
+        cfg1.up(1420).unwrap();
+        cfg2.up(1420).unwrap();
+        let port = cfg1.get_listen_port().unwrap();
+        assert!(TcpStream::connect(("127.0.0.1", port)).is_ok());
+
+        // the endpoint is only reachable over TCP (no UDP socket on the port)
+        let wg1 = cfg1.lock().wireguard.clone();
+        let wg2 = cfg2.lock().wireguard.clone();
+        let listener = wg1.listen_tcp(0).unwrap();
+        let addr: SocketAddr = ("127.0.0.1", listener.get_port())
+            .to_socket_addrs()
+            .unwrap()
+            .next()
+            .unwrap();
+        cfg2.set_endpoint(&pk1, addr);
+        cfg2.set_tcp_fallback(&pk1, true);
+        assert!(cfg2.get_peers()[0].tcp_fallback);
+        wg2.connect_tcp(addr).unwrap();
+        assert!(wg2.tcp.contains(&addr));
+
+        // the handshake completes over TCP (the response is sent to the TCP connection)
+        wg2.peers
+            .read()
+            .get(&pk1)
+            .unwrap()
+            .opaque()
+            .packet_send_handshake_initiation();
+        let complete = (0..200).any(|_| {
+            thread::sleep(Duration::from_millis(10));
+            let responder = &cfg1.get_peers()[0];
+            cfg2.get_peers()[0].last_handshake_time.is_some()
+                && responder.rx_bytes > 0
+                && responder.tx_bytes > 0
+        });
+        assert!(complete, "handshake over TCP should complete");
+
+        // disabling the fallback returns to UDP
+        cfg2.set_tcp_fallback(&pk1, false);
+        assert!(!wg2.tcp.contains(&addr));
+
+        cfg1.down();
+        cfg2.down();
+    }
+
+    #[test]
+    fn test_apply_config() {
+        let (_fake, _reader, tun_writer, _mtu) = dummy::TunTest::create(false);
+        let cfg: WireGuardConfig<dummy::TunTest, dummy::PairBind> =
+            WireGuardConfig::new(WireGuard::new(tun_writer));
+        let keys: Vec<String> = (0..3)
+            .map(|_| {
+                let pk = PublicKey::from(&StaticSecret::new(&mut OsRng));
+                encode_key(pk.as_bytes())
+            })
+            .collect();
+        let id = |key: &str| {
+            let pk = PublicKey::from(parse_key(key).unwrap());
+            let peers = cfg.lock().wireguard.peers.read().get(&pk).map(|p| p.id);
+            peers
+        };
+        let peer = |key: &str, allowed: &str, extra: &str| {
+            format!(
+                "[Peer]\nPublicKey = {}\nAllowedIPs = {}\n{}\n",
+                key, allowed, extra
+            )
+        };
+
+        let first = ConfigFile::parse(&format!(
+            "[Interface]\nFwMark = 1\n{}{}",
+            peer(&keys[0], "10.0.0.1/32", "Endpoint = 127.0.0.1:51820"),
+            peer(&keys[1], "10.0.0.2/32", "")
+        ))
+        .unwrap();
+        cfg.apply_config(&first).unwrap();
+        assert_eq!(cfg.get_peers().len(), 2);
+        let retained = id(&keys[0]).unwrap();
+
+        // the first peer is updated in place, the second removed and the third added
+        let second = ConfigFile::parse(&format!(
+            "[Interface]\n{}{}",
+            peer(
+                &keys[0],
+                "10.0.0.1/32, 10.1.0.0/16",
+                "PersistentKeepalive = 25"
+            ),
+            peer(&keys[2], "10.0.0.2/32", "")
+        ))
+        .unwrap();
+        cfg.apply_config(&second).unwrap();
+        assert_eq!(id(&keys[0]), Some(retained));
+        assert_eq!(id(&keys[1]), None);
+        assert!(id(&keys[2]).is_some());
+        assert_eq!(cfg.get_fwmark(), None);
+        let state = cfg
+            .get_peers()
+            .into_iter()
+            .find(|p| encode_key(p.public_key.as_bytes()) == keys[0])
+            .unwrap();
+        assert_eq!(state.allowed_ips.len(), 2);
+        assert_eq!(state.persistent_keepalive_interval, 25);
+        assert!(state.endpoint.is_some());
+
+        // an endpoint which cannot be resolved leaves the peers unchanged
+        let invalid =
+            ConfigFile::parse(&peer(&keys[1], "10.0.0.3/32", "Endpoint = invalid.:1")).unwrap();
+        assert!(cfg.apply_config(&invalid).is_err());
+        assert_eq!(id(&keys[0]), Some(retained));
+        assert_eq!(id(&keys[1]), None);
     }
 }
@@ -0,0 +1,333 @@
+/* Differences between two configurations of a device:
+ * applied by the hot reload (against the running state)
+ * and for external controllers to preview the effect of a new configuration.
+ *
+ * Keys which are omitted from the new configuration are handled as when applying it:
+ * the private key and listen port are retained, as is the endpoint of a peer,
+ * while the fwmark, preshared key and persistent keepalive are cleared.
+ */
+
+use super::file::{network, ConfigFile, Peer};
+use super::key::Key;
+
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+
+use x25519_dalek::PublicKey;
+
+/// A change to the interface
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InterfaceChange {
+    PrivateKey, // the key itself is not included
+    ListenPort(u16),
+    FwMark(Option<u32>),
+}
+
+/// A change to a peer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerChange {
+    PresharedKey, // the key itself is not included
+    Endpoint(String),
+    AllowedIps {
+        added: Vec<(IpAddr, u32)>,
+        removed: Vec<(IpAddr, u32)>,
+    },
+    PersistentKeepalive(u64), // 0: off
+    // only in structured configurations
+    #[cfg_attr(not(feature = "structured-config"), allow(dead_code))]
+    Endpoints(Vec<SocketAddr>),
+    #[cfg_attr(not(feature = "structured-config"), allow(dead_code))]
+    TcpFallback(bool),
+    #[cfg_attr(not(feature = "structured-config"), allow(dead_code))]
+    AdaptiveKeepalive(Option<(u64, u64)>),
+}
+
+/// The changes from one configuration to another
+///
+/// Added peers are described by the changes from a peer with only a public key.
+#[derive(Default)]
+pub struct ChangeSet {
+    pub interface: Vec<InterfaceChange>,
+    pub added: Vec<(PublicKey, Vec<PeerChange>)>,
+    pub removed: Vec<PublicKey>,
+    pub modified: Vec<(PublicKey, Vec<PeerChange>)>,
+}
+
+// Internal function
+//
+// The allowed IPs of a peer, without host bits and duplicates
+fn prefixes(peer: &Peer) -> Vec<(IpAddr, u32)> {
+    let mut prefixes: Vec<(IpAddr, u32)> = peer
+        .allowed_ips
+        .iter()
+        .map(|(ip, masklen)| (network(*ip, *masklen), *masklen))
+        .collect();
+    prefixes.sort();
+    prefixes.dedup();
+    prefixes
+}
+
+// Internal function
+//
+// The changes of a peer (from a peer with only a public key if `old` is None)
+fn peer_changes(old: Option<&Peer>, new: &Peer) -> Vec<PeerChange> {
+    let mut changes = vec![];
+    if old.and_then(|peer| peer.preshared_key) != new.preshared_key {
+        changes.push(PeerChange::PresharedKey);
+    }
+    if let Some(endpoint) = new.endpoint.as_ref() {
+        if old.and_then(|peer| peer.endpoint.as_ref()) != Some(endpoint) {
+            changes.push(PeerChange::Endpoint(endpoint.clone()));
+        }
+    }
+
+    let before = old.map(prefixes).unwrap_or_default();
+    let after = prefixes(new);
+    let added: Vec<(IpAddr, u32)> = after
+        .iter()
+        .filter(|prefix| !before.contains(prefix))
+        .cloned()
+        .collect();
+    let removed: Vec<(IpAddr, u32)> = before
+        .iter()
+        .filter(|prefix| !after.contains(prefix))
+        .cloned()
+        .collect();
+    if !added.is_empty() || !removed.is_empty() {
+        changes.push(PeerChange::AllowedIps { added, removed });
+    }
+
+    let keepalive = |peer: &Peer| peer.persistent_keepalive.unwrap_or(0);
+    if old.map_or(0, keepalive) != keepalive(new) {
+        changes.push(PeerChange::PersistentKeepalive(keepalive(new)));
+    }
+    changes
+}
+
+// Internal function
+//
+// Find a peer by public key
+fn find<'a>(peers: &'a [Peer], pk: &PublicKey) -> Option<&'a Peer> {
+    peers
+        .iter()
+        .find(|peer| peer.public_key.as_bytes() == pk.as_bytes())
+}
+
+impl ChangeSet {
+    /// Compute the changes from the `old` to the `new` configuration
+    pub fn between(old: &ConfigFile, new: &ConfigFile) -> ChangeSet {
+        let mut changes = ChangeSet::default();
+
+        // interface
+        if let Some(sk) = new.interface.private_key.as_ref() {
+            let old = old.interface.private_key.as_ref().map(|sk| sk.to_bytes());
+            if old != Some(sk.to_bytes()) {
+                changes.interface.push(InterfaceChange::PrivateKey);
+            }
+        }
+        if let Some(port) = new.interface.listen_port {
+            if old.interface.listen_port != Some(port) {
+                changes.interface.push(InterfaceChange::ListenPort(port));
+            }
+        }
+        if old.interface.fwmark != new.interface.fwmark {
+            changes
+                .interface
+                .push(InterfaceChange::FwMark(new.interface.fwmark));
+        }
+
+        // peers
+        for peer in old.peers.iter() {
+            if find(&new.peers, &peer.public_key).is_none() {
+                changes.removed.push(peer.public_key);
+            }
+        }
+        for peer in new.peers.iter() {
+            match find(&old.peers, &peer.public_key) {
+                None => changes
+                    .added
+                    .push((peer.public_key, peer_changes(None, peer))),
+                Some(old) => {
+                    let peer_changes = peer_changes(Some(old), peer);
+                    if !peer_changes.is_empty() {
+                        changes.modified.push((peer.public_key, peer_changes));
+                    }
+                }
+            }
+        }
+        changes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.interface.is_empty()
+            && self.added.is_empty()
+            && self.removed.is_empty()
+            && self.modified.is_empty()
+    }
+
+    /// Add a change to a peer, which is modified unless it was added
+    #[cfg_attr(not(feature = "structured-config"), allow(dead_code))]
+    pub fn push(&mut self, pk: &PublicKey, change: PeerChange) {
+        let entry = self
+            .added
+            .iter_mut()
+            .chain(self.modified.iter_mut())
+            .find(|(key, _)| key.as_bytes() == pk.as_bytes());
+        match entry {
+            Some((_, changes)) => changes.push(change),
+            None => self.modified.push((*pk, vec![change])),
+        }
+    }
+}
+
+impl fmt::Display for PeerChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let list = |prefixes: &[(IpAddr, u32)]| {
+            prefixes
+                .iter()
+                .map(|(ip, masklen)| format!("{}/{}", ip, masklen))
+                .collect::<Vec<String>>()
+                .join(", ")
+        };
+        match self {
+            PeerChange::PresharedKey => write!(f, "preshared key changed"),
+            PeerChange::Endpoint(endpoint) => write!(f, "endpoint = {}", endpoint),
+            PeerChange::AllowedIps { added, removed } => {
+                let mut parts = vec![];
+                if !added.is_empty() {
+                    parts.push(format!("+{}", list(added)));
+                }
+                if !removed.is_empty() {
+                    parts.push(format!("-{}", list(removed)));
+                }
+                write!(f, "allowed ips {}", parts.join(" "))
+            }
+            PeerChange::PersistentKeepalive(0) => write!(f, "persistent keepalive = off"),
+            PeerChange::PersistentKeepalive(secs) => write!(f, "persistent keepalive = {}", secs),
+            PeerChange::Endpoints(addrs) => {
+                let addrs: Vec<String> = addrs.iter().map(|addr| addr.to_string()).collect();
+                write!(f, "endpoints = {}", addrs.join(", "))
+            }
+            PeerChange::TcpFallback(enabled) => write!(f, "tcp fallback = {}", enabled),
+            PeerChange::AdaptiveKeepalive(Some((min, max))) => {
+                write!(f, "adaptive keepalive = {}-{}s", min, max)
+            }
+            PeerChange::AdaptiveKeepalive(None) => write!(f, "adaptive keepalive = off"),
+        }
+    }
+}
+
+/// One change per line, e.g. "+ peer <key>: endpoint = 192.0.2.1:51820"
+impl fmt::Display for ChangeSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for change in self.interface.iter() {
+            match change {
+                InterfaceChange::PrivateKey => writeln!(f, "~ interface: private key changed")?,
+                InterfaceChange::ListenPort(port) => {
+                    writeln!(f, "~ interface: listen port = {}", port)?
+                }
+                InterfaceChange::FwMark(None) => writeln!(f, "~ interface: fwmark = off")?,
+                InterfaceChange::FwMark(Some(mark)) => {
+                    writeln!(f, "~ interface: fwmark = 0x{:x}", mark)?
+                }
+            }
+        }
+        for pk in self.removed.iter() {
+            writeln!(f, "- peer {}", Key::from(pk))?;
+        }
+        for (prefix, peers) in [("+", &self.added), ("~", &self.modified)].iter() {
+            for (pk, changes) in peers.iter() {
+                let changes: Vec<String> = changes.iter().map(|c| c.to_string()).collect();
+                write!(f, "{} peer {}", prefix, Key::from(pk))?;
+                if !changes.is_empty() {
+                    write!(f, ": {}", changes.join("; "))?;
+                }
+                writeln!(f)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::file::encode_key;
+
+    const OLD: &str = "\
+[Interface]
+PrivateKey = yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=
+ListenPort = 51820
+FwMark = 0x10
+
+[Peer]
+PublicKey = xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=
+AllowedIPs = 10.0.0.2/32, 10.1.0.0/16
+Endpoint = vpn.example.com:51820
+PersistentKeepalive = 25
+
+[Peer]
+PublicKey = TrMvSoP4jYQlY6RIzBgbssQqY3vxI2Pi+y71lOWWXX0=
+AllowedIPs = 10.0.0.3/32
+";
+
+    const NEW: &str = "\
+[Interface]
+PrivateKey = yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=
+
+[Peer]
+PublicKey = xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=
+AllowedIPs = 10.1.2.3/16, 10.0.0.4/32
+Endpoint = vpn.example.com:51820
+
+[Peer]
+PublicKey = HIgo9xNzJMWLKASShiTqIybxZ0U3wGLiUeJ1PKf8ykw=
+PresharedKey = FpCyhws9cxwWoV4xELtfJvjJN+zQRVPISllRWgeopVE=
+AllowedIPs = 10.0.0.3/32
+Endpoint = 192.0.2.1:51820
+";
+
+    #[test]
+    fn test_between() {
+        let old = ConfigFile::parse(OLD).unwrap();
+        let new = ConfigFile::parse(NEW).unwrap();
+        let changes = ChangeSet::between(&old, &new);
+
+        // the listen port is retained, the fwmark cleared
+        assert_eq!(changes.interface, vec![InterfaceChange::FwMark(None)]);
+        assert_eq!(changes.removed.len(), 1);
+        assert_eq!(
+            encode_key(changes.removed[0].as_bytes()),
+            "TrMvSoP4jYQlY6RIzBgbssQqY3vxI2Pi+y71lOWWXX0="
+        );
+        assert_eq!(changes.added.len(), 1);
+        assert_eq!(changes.added[0].1.len(), 3);
+        assert_eq!(changes.modified.len(), 1);
+        assert_eq!(
+            changes.modified[0].1,
+            vec![
+                PeerChange::AllowedIps {
+                    added: vec![("10.0.0.4".parse().unwrap(), 32)],
+                    removed: vec![("10.0.0.2".parse().unwrap(), 32)],
+                },
+                PeerChange::PersistentKeepalive(0),
+            ]
+        );
+
+        assert_eq!(
+            changes.to_string(),
+            "\
+~ interface: fwmark = off
+- peer TrMvSoP4jYQlY6RIzBgbssQqY3vxI2Pi+y71lOWWXX0=
++ peer HIgo9xNzJMWLKASShiTqIybxZ0U3wGLiUeJ1PKf8ykw=: preshared key changed; \
+endpoint = 192.0.2.1:51820; allowed ips +10.0.0.3/32
+~ peer xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=: allowed ips +10.0.0.4/32 -10.0.0.2/32; \
+persistent keepalive = off
+"
+        );
+
+        // no changes
+        assert!(ChangeSet::between(&new, &ConfigFile::parse(NEW).unwrap()).is_empty());
+    }
+}
@@ -0,0 +1,298 @@
+/* Overrides of the configuration from environment variables (e.g. secrets of a container):
+ *
+ * WG_RS_PRIVATE_KEY=<base64 key>
+ * WG_RS_PRIVATE_KEY_FILE=/run/secrets/wg0.key
+ * WG_RS_LISTEN_PORT=51820
+ * WG_RS_PEER_1_ENDPOINT=vpn.example.com:51820  (endpoint of the first peer)
+ *
+ * The overrides are layered over the configuration file (or an empty configuration).
+ * Other variables starting with WG_RS_ are rejected, to catch misspelled names.
+ */
+
+use super::file::{parse_key, ConfigFile};
+use super::key::load_private_key;
+#[cfg(feature = "structured-config")]
+use super::key::Key;
+#[cfg(feature = "structured-config")]
+use super::structured::DeviceConfig;
+
+use std::ffi::OsString;
+use std::fmt;
+
+use x25519_dalek::StaticSecret;
+
+pub const PREFIX: &str = "WG_RS_";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum EnvError {
+    UnknownVariable(String),
+    InvalidVariable(String),
+    InvalidKeyFile(String, String), // (variable, reason)
+}
+
+impl fmt::Display for EnvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EnvError::UnknownVariable(name) => write!(f, "Unknown environment variable {}", name),
+            EnvError::InvalidVariable(name) => {
+                write!(f, "Invalid value of environment variable {}", name)
+            }
+            EnvError::InvalidKeyFile(name, reason) => write!(f, "{}: {}", name, reason),
+        }
+    }
+}
+
+/// The overrides read from the environment
+#[derive(Default)]
+pub struct Overrides {
+    private_key: Option<StaticSecret>,
+    listen_port: Option<u16>,
+    endpoints: Vec<(String, usize, String)>, // (variable, index of the peer, endpoint)
+}
+
+impl Overrides {
+    /// Read the overrides from the variables of the environment
+    ///
+    /// # Arguments
+    ///
+    /// - `vars`: The variables (`std::env::vars_os()`), only those starting with WG_RS_ are considered
+    ///   (the values of which must be valid UTF-8)
+    pub fn from_vars<I: IntoIterator<Item = (OsString, OsString)>>(
+        vars: I,
+    ) -> Result<Overrides, EnvError> {
+        let mut overrides = Overrides::default();
+        let mut key_file = None;
+        for (name, value) in vars {
+            let name = match name.into_string() {
+                Ok(name) => name,
+                Err(name) => match name.to_string_lossy() {
+                    lossy if lossy.starts_with(PREFIX) => {
+                        return Err(EnvError::UnknownVariable(lossy.into_owned()))
+                    }
+                    _ => continue,
+                },
+            };
+            let key = match name.strip_prefix(PREFIX) {
+                Some(key) => key,
+                None => continue,
+            };
+            let invalid = || EnvError::InvalidVariable(name.clone());
+            let value = value.into_string().map_err(|_| invalid())?;
+            let value = value.trim();
+            match key {
+                "PRIVATE_KEY" => {
+                    let sk = parse_key(value).ok_or_else(invalid)?;
+                    overrides.private_key = Some(StaticSecret::from(sk));
+                }
+                "PRIVATE_KEY_FILE" => {
+                    let sk = load_private_key(value)
+                        .map_err(|err| EnvError::InvalidKeyFile(name.clone(), err.to_string()))?;
+                    key_file = Some(sk);
+                }
+                "LISTEN_PORT" => {
+                    overrides.listen_port = Some(value.parse().map_err(|_| invalid())?)
+                }
+                key if key.starts_with("PEER_") && key.ends_with("_ENDPOINT") => {
+                    let index = key["PEER_".len()..key.len() - "_ENDPOINT".len()]
+                        .parse::<usize>()
+                        .ok()
+                        .filter(|index| *index > 0)
+                        .ok_or_else(|| EnvError::UnknownVariable(name.clone()))?;
+                    if value.is_empty() {
+                        return Err(invalid());
+                    }
+                    overrides
+                        .endpoints
+                        .push((name.clone(), index - 1, value.to_owned()));
+                }
+                _ => return Err(EnvError::UnknownVariable(name.clone())),
+            }
+        }
+
+        // the key is either given or read from a file
+        if let Some(key) = key_file {
+            if overrides.private_key.replace(key).is_some() {
+                return Err(EnvError::InvalidVariable(format!(
+                    "{}PRIVATE_KEY_FILE",
+                    PREFIX
+                )));
+            }
+        }
+        overrides.endpoints.sort();
+        Ok(overrides)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.private_key.is_none() && self.listen_port.is_none() && self.endpoints.is_empty()
+    }
+
+    /// Apply the overrides to a configuration file
+    ///
+    /// # Returns
+    ///
+    /// An error if the endpoint of a peer which is not in the file is overridden.
+    pub fn apply(&self, file: &mut ConfigFile) -> Result<(), EnvError> {
+        if let Some(sk) = self.private_key.as_ref() {
+            file.interface.private_key = Some(sk.clone());
+        }
+        if let Some(port) = self.listen_port {
+            file.interface.listen_port = Some(port);
+        }
+        for (name, index, endpoint) in self.endpoints.iter() {
+            let peer = file
+                .peers
+                .get_mut(*index)
+                .ok_or_else(|| EnvError::InvalidVariable(name.clone()))?;
+            peer.endpoint = Some(endpoint.clone());
+        }
+        Ok(())
+    }
+
+    /// Apply the overrides to a structured configuration
+    #[cfg(feature = "structured-config")]
+    pub fn apply_structured(&self, config: &mut DeviceConfig) -> Result<(), EnvError> {
+        if let Some(sk) = self.private_key.as_ref() {
+            config.private_key = Some(Key(sk.to_bytes()));
+        }
+        if let Some(port) = self.listen_port {
+            config.listen_port = Some(port);
+        }
+        for (name, index, endpoint) in self.endpoints.iter() {
+            let peer = config
+                .peers
+                .get_mut(*index)
+                .ok_or_else(|| EnvError::InvalidVariable(name.clone()))?;
+            peer.endpoint = Some(endpoint.clone());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    const SK: &str = "yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=";
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(OsString, OsString)> {
+        vars.iter()
+            .map(|(name, value)| (name.into(), value.into()))
+            .collect()
+    }
+
+    #[test]
+    fn test_overrides() {
+        let mut file = ConfigFile::parse(
+            "[Interface]\nListenPort = 51820\n\
+             [Peer]\nPublicKey = xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=\n\
+             [Peer]\nPublicKey = TrMvSoP4jYQlY6RIzBgbssQqY3vxI2Pi+y71lOWWXX0=\nEndpoint = 192.0.2.1:1\n",
+        )
+        .unwrap();
+
+        // variables without the prefix are ignored
+        let overrides = Overrides::from_vars(vars(&[
+            ("HOME", "/root"),
+            ("WG_RS_PRIVATE_KEY", SK),
+            ("WG_RS_LISTEN_PORT", "51821"),
+            ("WG_RS_PEER_2_ENDPOINT", "vpn.example.com:51820"),
+        ]))
+        .unwrap();
+        overrides.apply(&mut file).unwrap();
+        assert_eq!(
+            file.interface.private_key.as_ref().map(|sk| sk.to_bytes()),
+            parse_key(SK)
+        );
+        assert_eq!(file.interface.listen_port, Some(51821));
+        assert_eq!(file.peers[0].endpoint, None);
+        assert_eq!(
+            file.peers[1].endpoint.as_deref(),
+            Some("vpn.example.com:51820")
+        );
+
+        // the endpoint of a missing peer
+        let overrides =
+            Overrides::from_vars(vars(&[("WG_RS_PEER_3_ENDPOINT", "192.0.2.1:1")])).unwrap();
+        assert_eq!(
+            overrides.apply(&mut file),
+            Err(EnvError::InvalidVariable("WG_RS_PEER_3_ENDPOINT".into()))
+        );
+        assert!(Overrides::from_vars(vars(&[("PATH", "/bin")]))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_non_utf8() {
+        use std::os::unix::ffi::OsStringExt;
+        let invalid = || OsString::from_vec(vec![0x66, 0x6f, 0xff]);
+
+        // other variables are ignored
+        assert!(Overrides::from_vars(vec![
+            ("LANG_DATA".into(), invalid()),
+            (invalid(), "value".into()),
+        ])
+        .unwrap()
+        .is_empty());
+
+        // the value of a variable of wireguard-rs is rejected
+        match Overrides::from_vars(vec![("WG_RS_LISTEN_PORT".into(), invalid())]) {
+            Err(EnvError::InvalidVariable(name)) => assert_eq!(name, "WG_RS_LISTEN_PORT"),
+            _ => panic!("invalid value accepted"),
+        }
+        let mut name = PREFIX.as_bytes().to_vec();
+        name.push(0xff);
+        assert!(Overrides::from_vars(vec![(OsString::from_vec(name), "1".into())]).is_err());
+    }
+
+    #[test]
+    fn test_key_file() {
+        let path = env::temp_dir().join(format!("wg-rs-env-{}.key", std::process::id()));
+        fs::write(&path, format!("{}\n", SK)).unwrap();
+        let name = "WG_RS_PRIVATE_KEY_FILE";
+
+        // readable by others
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        match Overrides::from_vars(vars(&[(name, path.to_str().unwrap())])) {
+            Err(EnvError::InvalidKeyFile(variable, _)) => assert_eq!(variable, name),
+            _ => panic!("key file readable by others accepted"),
+        }
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        let overrides = Overrides::from_vars(vars(&[(name, path.to_str().unwrap())])).unwrap();
+        assert_eq!(overrides.private_key.map(|sk| sk.to_bytes()), parse_key(SK));
+
+        // the key is given twice
+        let both = Overrides::from_vars(vars(&[
+            (name, path.to_str().unwrap()),
+            ("WG_RS_PRIVATE_KEY", SK),
+        ]));
+        fs::remove_file(&path).unwrap();
+        assert!(both.is_err());
+        assert!(Overrides::from_vars(vars(&[(name, path.to_str().unwrap())])).is_err());
+    }
+
+    #[test]
+    fn test_invalid() {
+        let invalid = |name: &str, value: &str| Overrides::from_vars(vars(&[(name, value)])).err();
+        assert_eq!(
+            invalid("WG_RS_LISTEN_PORT", "70000"),
+            Some(EnvError::InvalidVariable("WG_RS_LISTEN_PORT".into()))
+        );
+        assert_eq!(
+            invalid("WG_RS_PRIVATE_KEY", "invalid"),
+            Some(EnvError::InvalidVariable("WG_RS_PRIVATE_KEY".into()))
+        );
+        assert_eq!(
+            invalid("WG_RS_PEER_0_ENDPOINT", "192.0.2.1:1"),
+            Some(EnvError::UnknownVariable("WG_RS_PEER_0_ENDPOINT".into()))
+        );
+        assert_eq!(
+            invalid("WG_RS_LISTENPORT", "1"),
+            Some(EnvError::UnknownVariable("WG_RS_LISTENPORT".into()))
+        );
+    }
+}
@@ -4,6 +4,18 @@ use std::fmt;
 #[cfg(unix)]
 use libc::*;
 
+// errno values reported over the UAPI (as on Linux)
+#[cfg(not(unix))]
+mod errno {
+    pub const EPERM: i32 = 1;
+    pub const EIO: i32 = 5;
+    pub const EINVAL: i32 = 22;
+    pub const EPROTO: i32 = 71;
+}
+
+#[cfg(not(unix))]
+use errno::*;
+
 #[derive(Debug)]
 pub enum ConfigError {
     FailedToBind,
@@ -11,6 +23,7 @@ pub enum ConfigError {
     InvalidPortNumber,
     InvalidFwmark,
     InvalidKey,
+    LowOrderPublicKey,
     InvalidSocketAddr,
     InvalidKeepaliveInterval,
     InvalidAllowedIp,
@@ -37,7 +50,6 @@ impl Error for ConfigError {
     }
 }
 
-#[cfg(unix)]
 impl ConfigError {
     pub fn errno(&self) -> i32 {
         // TODO: obtain the correct errorno values
@@ -47,6 +59,7 @@ impl ConfigError {
 
             // parsing of value failed
             ConfigError::InvalidHexValue => EINVAL,
+            ConfigError::LowOrderPublicKey => EINVAL,
             ConfigError::InvalidPortNumber => EINVAL,
             ConfigError::InvalidFwmark => EINVAL,
             ConfigError::InvalidSocketAddr => EINVAL,
@@ -0,0 +1,672 @@
+/* Configuration files in the format of wg-quick(8):
+ *
+ * [Interface]
+ * PrivateKey = yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=
+ * Address = 10.0.0.1/24, fd00::1/64
+ * ListenPort = 51820
+ *
+ * [Peer]
+ * PublicKey = xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=
+ * AllowedIPs = 10.0.0.2/32
+ * Endpoint = vpn.example.com:51820
+ *
+ * The keys of wg(8) are applied to the device, while the keys of wg-quick
+ * (Address, DNS, MTU, Table and the hooks) are returned to the caller,
+ * which assigns the addresses and routes (e.g. the VpnService on Android).
+ */
+
+use super::super::wireguard::base64;
+use super::config::PeerState;
+use super::key::validate_public_key;
+use super::{ConfigError, Configuration};
+
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// The [Interface] section
+#[derive(Default)]
+pub struct Interface {
+    pub private_key: Option<StaticSecret>,
+    pub listen_port: Option<u16>,
+    pub fwmark: Option<u32>,
+    pub addresses: Vec<(IpAddr, u32)>,
+    pub dns: Vec<String>,
+    pub mtu: Option<usize>,
+    pub table: Option<String>,
+    pub hooks: Vec<(String, String)>, // (PreUp / PostUp / PreDown / PostDown, command), not executed
+}
+
+/// A [Peer] section
+pub struct Peer {
+    pub public_key: PublicKey,
+    pub preshared_key: Option<[u8; 32]>,
+    pub allowed_ips: Vec<(IpAddr, u32)>,
+    pub endpoint: Option<String>, // address or "host:port"
+    pub persistent_keepalive: Option<u64>,
+}
+
+/// A parsed configuration file
+#[derive(Default)]
+pub struct ConfigFile {
+    pub interface: Interface,
+    pub peers: Vec<Peer>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum FileError {
+    ReadFailed,
+    InvalidSection(usize),     // line
+    InvalidLine(usize),        // line
+    UnknownKey(usize),         // line
+    InvalidValue(usize),       // line
+    MissingPublicKey(usize),   // line of the [Peer] section
+    LowOrderKey(usize),        // line
+    DuplicateInterface(usize), // line of the [Interface] section
+}
+
+impl fmt::Display for FileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileError::ReadFailed => write!(f, "Failed to read configuration file"),
+            FileError::InvalidSection(line) => write!(f, "Line {}: unknown section", line),
+            FileError::InvalidLine(line) => write!(f, "Line {}: expected \"Key = Value\"", line),
+            FileError::UnknownKey(line) => write!(f, "Line {}: unknown key", line),
+            FileError::InvalidValue(line) => write!(f, "Line {}: invalid value", line),
+            FileError::MissingPublicKey(line) => {
+                write!(f, "Line {}: peer without a public key", line)
+            }
+            FileError::LowOrderKey(line) => {
+                write!(f, "Line {}: public key is a low-order point", line)
+            }
+            FileError::DuplicateInterface(line) => {
+                write!(f, "Line {}: more than one [Interface] section", line)
+            }
+        }
+    }
+}
+
+/// Encode a key in base64 (as in configuration files and the output of wg(8))
+pub fn encode_key(key: &[u8; 32]) -> String {
+    base64(key)
+}
+
+/// Decode a base64 encoded key (44 characters, with padding)
+pub fn parse_key(value: &str) -> Option<[u8; 32]> {
+    fn sextet(c: u8) -> Option<u32> {
+        match c {
+            b'A'..=b'Z' => Some(u32::from(c - b'A')),
+            b'a'..=b'z' => Some(u32::from(c - b'a') + 26),
+            b'0'..=b'9' => Some(u32::from(c - b'0') + 52),
+            b'+' => Some(62),
+            b'/' => Some(63),
+            _ => None,
+        }
+    }
+
+    let value = value.as_bytes();
+    if value.len() != 44 || value[43] != b'=' {
+        return None;
+    }
+
+    // 10 groups of 4 characters (30 bytes) followed by 3 characters (2 bytes)
+    let mut key = [0u8; 32];
+    for (i, chunk) in value[..40].chunks(4).enumerate() {
+        let mut n = 0;
+        for c in chunk {
+            n = (n << 6) | sextet(*c)?;
+        }
+        key[3 * i..3 * i + 3].copy_from_slice(&n.to_be_bytes()[1..]);
+    }
+    let n = (sextet(value[40])? << 12) | (sextet(value[41])? << 6) | sextet(value[42])?;
+    if n & 0x3 != 0 {
+        return None; // non-canonical encoding
+    }
+    key[30] = (n >> 10) as u8;
+    key[31] = (n >> 2) as u8;
+    Some(key)
+}
+
+/// Parse an address with an optional prefix length (e.g. "10.0.0.1/24" or "fd00::1")
+pub fn parse_prefix(value: &str) -> Option<(IpAddr, u32)> {
+    let mut split = value.splitn(2, '/');
+    let ip: IpAddr = split.next()?.parse().ok()?;
+    let max = if ip.is_ipv4() { 32 } else { 128 };
+    let masklen = match split.next() {
+        Some(masklen) => masklen.parse().ok().filter(|masklen| *masklen <= max)?,
+        None => max,
+    };
+    Some((ip, masklen))
+}
+
+/// Clear the host bits of a prefix
+pub fn network(ip: IpAddr, masklen: u32) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let mask = u32::MAX.checked_shl(32 - masklen).unwrap_or(0);
+            IpAddr::V4((u32::from(ip) & mask).into())
+        }
+        IpAddr::V6(ip) => {
+            let mask = u128::MAX.checked_shl(128 - masklen).unwrap_or(0);
+            IpAddr::V6((u128::from(ip) & mask).into())
+        }
+    }
+}
+
+// Internal function
+//
+// Split a comma separated list (empty elements are skipped)
+fn list(value: &str) -> impl Iterator<Item = &str> {
+    value.split(',').map(str::trim).filter(|v| !v.is_empty())
+}
+
+// Peer section being parsed
+struct PartialPeer {
+    line: usize,
+    public_key: Option<PublicKey>,
+    preshared_key: Option<[u8; 32]>,
+    allowed_ips: Vec<(IpAddr, u32)>,
+    endpoint: Option<String>,
+    persistent_keepalive: Option<u64>,
+}
+
+impl PartialPeer {
+    fn finish(self) -> Result<Peer, FileError> {
+        Ok(Peer {
+            public_key: self
+                .public_key
+                .ok_or(FileError::MissingPublicKey(self.line))?,
+            preshared_key: self.preshared_key,
+            allowed_ips: self.allowed_ips,
+            endpoint: self.endpoint,
+            persistent_keepalive: self.persistent_keepalive,
+        })
+    }
+}
+
+enum Section {
+    None,
+    Interface,
+    Peer(PartialPeer),
+}
+
+impl Peer {
+    /// The configuration of a peer of a running device
+    /// (the endpoint is the configured hostname, if any, otherwise the active endpoint)
+    pub fn from_state(peer: PeerState) -> Peer {
+        Peer {
+            public_key: peer.public_key,
+            preshared_key: Some(peer.preshared_key).filter(|psk| *psk != [0u8; 32]),
+            allowed_ips: peer.allowed_ips,
+            endpoint: match peer.endpoint_name {
+                Some(name) => Some(name),
+                None => peer.endpoint.map(|addr| addr.to_string()),
+            },
+            persistent_keepalive: Some(peer.persistent_keepalive_interval).filter(|secs| *secs > 0),
+        }
+    }
+}
+
+impl ConfigFile {
+    /// Read and parse a configuration file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<ConfigFile, FileError> {
+        let content = fs::read_to_string(path).map_err(|_| FileError::ReadFailed)?;
+        Self::parse(&content)
+    }
+
+    /// Parse the content of a configuration file
+    ///
+    /// Sections and keys are case-insensitive, comments start with '#'.
+    pub fn parse(content: &str) -> Result<ConfigFile, FileError> {
+        let mut file = ConfigFile::default();
+        let mut section = Section::None;
+        let mut interface = None;
+
+        for (i, line) in content.lines().enumerate() {
+            let nr = i + 1;
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            // start of a section (completes the previous peer)
+            if line.starts_with('[') && line.ends_with(']') {
+                if let Section::Peer(peer) = section {
+                    file.peers.push(peer.finish()?);
+                }
+                section = match line[1..line.len() - 1].trim().to_ascii_lowercase().as_str() {
+                    "interface" => {
+                        if interface.replace(nr).is_some() {
+                            return Err(FileError::DuplicateInterface(nr));
+                        }
+                        Section::Interface
+                    }
+                    "peer" => Section::Peer(PartialPeer {
+                        line: nr,
+                        public_key: None,
+                        preshared_key: None,
+                        allowed_ips: vec![],
+                        endpoint: None,
+                        persistent_keepalive: None,
+                    }),
+                    _ => return Err(FileError::InvalidSection(nr)),
+                };
+                continue;
+            }
+
+            // key = value
+            let mut split = line.splitn(2, '=');
+            let (key, value) = match (split.next(), split.next()) {
+                (Some(key), Some(value)) => (key.trim().to_ascii_lowercase(), value.trim()),
+                _ => return Err(FileError::InvalidLine(nr)),
+            };
+            let invalid = || FileError::InvalidValue(nr);
+            match (&mut section, key.as_str()) {
+                (Section::None, _) => return Err(FileError::InvalidLine(nr)),
+
+                // keys of wg(8)
+                (Section::Interface, "privatekey") => {
+                    let sk = parse_key(value).ok_or_else(invalid)?;
+                    file.interface.private_key = Some(StaticSecret::from(sk));
+                }
+                (Section::Interface, "listenport") => {
+                    file.interface.listen_port = Some(value.parse().map_err(|_| invalid())?);
+                }
+                (Section::Interface, "fwmark") => {
+                    file.interface.fwmark = match value {
+                        "off" => None,
+                        v if v.starts_with("0x") => {
+                            Some(u32::from_str_radix(&v[2..], 16).map_err(|_| invalid())?)
+                        }
+                        v => Some(v.parse().map_err(|_| invalid())?),
+                    }
+                    .filter(|mark| *mark != 0);
+                }
+
+                // keys of wg-quick(8)
+                (Section::Interface, "address") => {
+                    for v in list(value) {
+                        let prefix = parse_prefix(v).ok_or_else(invalid)?;
+                        file.interface.addresses.push(prefix);
+                    }
+                }
+                (Section::Interface, "dns") => {
+                    file.interface.dns.extend(list(value).map(str::to_owned));
+                }
+                (Section::Interface, "mtu") => {
+                    file.interface.mtu = Some(value.parse().map_err(|_| invalid())?);
+                }
+                (Section::Interface, "table") => {
+                    file.interface.table = Some(value.to_owned());
+                }
+                (Section::Interface, "preup")
+                | (Section::Interface, "postup")
+                | (Section::Interface, "predown")
+                | (Section::Interface, "postdown") => {
+                    file.interface.hooks.push((key.clone(), value.to_owned()));
+                }
+                (Section::Interface, "saveconfig") => match value {
+                    "true" | "false" => (),
+                    _ => return Err(invalid()),
+                },
+
+                // peer keys
+                (Section::Peer(peer), "publickey") => {
+                    let pk = PublicKey::from(parse_key(value).ok_or_else(invalid)?);
+                    validate_public_key(&pk).map_err(|_| FileError::LowOrderKey(nr))?;
+                    peer.public_key = Some(pk);
+                }
+                (Section::Peer(peer), "presharedkey") => {
+                    peer.preshared_key = Some(parse_key(value).ok_or_else(invalid)?);
+                }
+                (Section::Peer(peer), "allowedips") => {
+                    for v in list(value) {
+                        let prefix = parse_prefix(v).ok_or_else(invalid)?;
+                        peer.allowed_ips.push(prefix);
+                    }
+                }
+                (Section::Peer(peer), "endpoint") => {
+                    peer.endpoint = Some(value.to_owned());
+                }
+                (Section::Peer(peer), "persistentkeepalive") => {
+                    peer.persistent_keepalive = match value {
+                        "off" => Some(0),
+                        v => Some(v.parse().map_err(|_| invalid())?),
+                    };
+                }
+
+                _ => return Err(FileError::UnknownKey(nr)),
+            }
+        }
+
+        if let Section::Peer(peer) = section {
+            file.peers.push(peer.finish()?);
+        }
+        Ok(file)
+    }
+
+    /// Apply the configuration to the device (as "wg setconf": every peer is replaced)
+    ///
+    /// # Returns
+    ///
+    /// An error if the listen port / fwmark could not be applied
+    /// or the endpoint of a peer could not be resolved.
+    pub fn apply<C: Configuration>(&self, config: &C) -> Result<(), ConfigError> {
+        if let Some(sk) = self.interface.private_key.as_ref() {
+            config.set_private_key(Some(sk.clone()));
+        }
+        if let Some(port) = self.interface.listen_port {
+            config.set_listen_port(port)?;
+        }
+        config.set_fwmark(self.interface.fwmark)?;
+
+        config.replace_peers();
+        for peer in self.peers.iter() {
+            let pk = &peer.public_key;
+            config.add_peer(pk);
+            config.set_preshared_key(pk, peer.preshared_key);
+            for (ip, masklen) in peer.allowed_ips.iter() {
+                config.add_allowed_ip(pk, *ip, *masklen);
+            }
+            if let Some(interval) = peer.persistent_keepalive {
+                config.set_persistent_keepalive_interval(pk, interval);
+            }
+            if let Some(endpoint) = peer.endpoint.as_ref() {
+                match endpoint.parse::<SocketAddr>() {
+                    Ok(addr) => config.set_endpoint(pk, addr),
+                    Err(_) => config.set_endpoint_name(pk, endpoint)?,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Capture the configuration of a device (as "wg showconf"), including the endpoints
+    /// learned at runtime. The keys of wg-quick are left unset.
+    #[allow(dead_code)] // used by embedding applications (the CLI goes through the UAPI)
+    pub fn from_device<C: Configuration>(config: &C) -> ConfigFile {
+        ConfigFile {
+            interface: Interface {
+                private_key: config.get_private_key(),
+                listen_port: config.get_listen_port(),
+                fwmark: config.get_fwmark(),
+                ..Default::default()
+            },
+            peers: config
+                .get_peers()
+                .into_iter()
+                .map(Peer::from_state)
+                .collect(),
+        }
+    }
+
+    /// Returns the routes for the allowed IPs of the peers (as added by wg-quick),
+    /// the most specific first. No routes are returned when Table is "off".
+    pub fn routes(&self) -> Vec<(IpAddr, u32)> {
+        if self.interface.table.as_deref() == Some("off") {
+            return vec![];
+        }
+        let mut routes: Vec<(IpAddr, u32)> = self
+            .peers
+            .iter()
+            .flat_map(|peer| peer.allowed_ips.iter())
+            .map(|(ip, masklen)| (network(*ip, *masklen), *masklen))
+            .collect();
+        routes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        routes.dedup();
+        routes
+    }
+}
+
+// Internal function
+//
+// Format a list of prefixes (as in AllowedIPs / Address)
+fn prefixes(list: &[(IpAddr, u32)]) -> String {
+    let list: Vec<String> = list
+        .iter()
+        .map(|(ip, masklen)| format!("{}/{}", ip, masklen))
+        .collect();
+    list.join(", ")
+}
+
+/// Serializes the configuration in the format of configuration files
+impl fmt::Display for ConfigFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let interface = &self.interface;
+        writeln!(f, "[Interface]")?;
+        if let Some(port) = interface.listen_port {
+            writeln!(f, "ListenPort = {}", port)?;
+        }
+        if let Some(fwmark) = interface.fwmark {
+            writeln!(f, "FwMark = 0x{:x}", fwmark)?;
+        }
+        if let Some(sk) = interface.private_key.as_ref() {
+            writeln!(f, "PrivateKey = {}", encode_key(&sk.to_bytes()))?;
+        }
+        if !interface.addresses.is_empty() {
+            writeln!(f, "Address = {}", prefixes(&interface.addresses))?;
+        }
+        if !interface.dns.is_empty() {
+            writeln!(f, "DNS = {}", interface.dns.join(", "))?;
+        }
+        if let Some(mtu) = interface.mtu {
+            writeln!(f, "MTU = {}", mtu)?;
+        }
+        if let Some(table) = interface.table.as_ref() {
+            writeln!(f, "Table = {}", table)?;
+        }
+        for (hook, command) in interface.hooks.iter() {
+            let hook = match hook.as_str() {
+                "preup" => "PreUp",
+                "postup" => "PostUp",
+                "predown" => "PreDown",
+                _ => "PostDown",
+            };
+            writeln!(f, "{} = {}", hook, command)?;
+        }
+
+        for peer in self.peers.iter() {
+            writeln!(f, "\n[Peer]")?;
+            writeln!(f, "PublicKey = {}", encode_key(peer.public_key.as_bytes()))?;
+            if let Some(psk) = peer.preshared_key.as_ref() {
+                writeln!(f, "PresharedKey = {}", encode_key(psk))?;
+            }
+            if !peer.allowed_ips.is_empty() {
+                writeln!(f, "AllowedIPs = {}", prefixes(&peer.allowed_ips))?;
+            }
+            if let Some(endpoint) = peer.endpoint.as_ref() {
+                writeln!(f, "Endpoint = {}", endpoint)?;
+            }
+            if let Some(secs) = peer.persistent_keepalive {
+                writeln!(f, "PersistentKeepalive = {}", secs)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::platform::dummy;
+    use super::super::super::wireguard::WireGuard;
+    use super::super::WireGuardConfig;
+    use super::*;
+
+    const CONFIG: &str = "
+        # wg-quick configuration
+        [Interface]
+        PrivateKey = yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=
+        Address = 10.192.122.1/24, fd00::1/64
+        ListenPort = 51820
+        FwMark = 0x1234
+        DNS = 10.192.122.53, example.com
+        MTU = 1380
+        PostUp = echo up
+
+        [Peer] # a peer
+        PublicKey = xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=
+        PresharedKey = FpCyhws9cxwWoV4xELtfJvjJN+zQVRPISllRWgeopVE=
+        AllowedIPs = 10.192.122.3/32, 10.192.124.1/24
+        Endpoint = 192.95.5.67:1234
+        PersistentKeepalive = 25
+
+        [peer]
+        publickey = TrMvSoP4jYQlY6RIzBgbssQqY3vxI2Pi+y71lOWWXX0=
+        allowedips = 10.192.122.4, 10.192.124.0/24
+    ";
+
+    #[test]
+    fn test_parse_key() {
+        let key = parse_key("xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=").unwrap();
+        assert_eq!(
+            hex::encode(key),
+            "c53201039adba14be71f886da1d8dbe9eebded08cb111b75340078999aa9f038"
+        );
+        assert_eq!(
+            encode_key(&key),
+            "xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg="
+        );
+        assert!(parse_key("xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg").is_none());
+        assert!(parse_key("xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dh=").is_none());
+        assert!(parse_key("xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8D!=").is_none());
+    }
+
+    #[test]
+    fn test_parse() {
+        let file = ConfigFile::parse(CONFIG).unwrap();
+        let interface = &file.interface;
+        assert!(interface.private_key.is_some());
+        assert_eq!(interface.listen_port, Some(51820));
+        assert_eq!(interface.fwmark, Some(0x1234));
+        assert_eq!(
+            interface.addresses,
+            vec![
+                ("10.192.122.1".parse().unwrap(), 24),
+                ("fd00::1".parse().unwrap(), 64)
+            ]
+        );
+        assert_eq!(interface.dns, vec!["10.192.122.53", "example.com"]);
+        assert_eq!(interface.mtu, Some(1380));
+        assert_eq!(interface.hooks.len(), 1);
+
+        assert_eq!(file.peers.len(), 2);
+        assert!(file.peers[0].preshared_key.is_some());
+        assert_eq!(file.peers[0].endpoint.as_deref(), Some("192.95.5.67:1234"));
+        assert_eq!(file.peers[0].persistent_keepalive, Some(25));
+        assert_eq!(
+            file.peers[1].allowed_ips[0],
+            ("10.192.122.4".parse().unwrap(), 32)
+        );
+
+        // route hints (deduplicated, most specific first)
+        let routes: Vec<String> = file
+            .routes()
+            .iter()
+            .map(|(ip, masklen)| format!("{}/{}", ip, masklen))
+            .collect();
+        assert_eq!(
+            routes,
+            vec!["10.192.122.3/32", "10.192.122.4/32", "10.192.124.0/24"]
+        );
+    }
+
+    #[test]
+    fn test_display() {
+        let file = ConfigFile::parse(CONFIG).unwrap();
+        let again = ConfigFile::parse(&file.to_string()).unwrap();
+        assert_eq!(again.to_string(), file.to_string());
+        assert_eq!(again.interface.addresses, file.interface.addresses);
+        assert_eq!(again.interface.hooks, file.interface.hooks);
+        assert_eq!(again.peers.len(), 2);
+        assert_eq!(again.peers[0].preshared_key, file.peers[0].preshared_key);
+        assert_eq!(again.peers[1].allowed_ips, file.peers[1].allowed_ips);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let parse = |content: &str| ConfigFile::parse(content).err();
+        assert_eq!(
+            parse("[Interface]\n[Foo]"),
+            Some(FileError::InvalidSection(2))
+        );
+        assert_eq!(parse("ListenPort = 1"), Some(FileError::InvalidLine(1)));
+        assert_eq!(
+            parse("[Interface]\nListenPort"),
+            Some(FileError::InvalidLine(2))
+        );
+        assert_eq!(
+            parse("[Interface]\nFoo = 1"),
+            Some(FileError::UnknownKey(2))
+        );
+        assert_eq!(
+            parse("[Peer]\nListenPort = 1"),
+            Some(FileError::UnknownKey(2))
+        );
+        assert_eq!(
+            parse("[Interface]\nListenPort = 65536"),
+            Some(FileError::InvalidValue(2))
+        );
+        assert_eq!(
+            parse("[Interface]\n\n[Peer]\nAllowedIPs = 10.0.0.0/33"),
+            Some(FileError::InvalidValue(4))
+        );
+        assert_eq!(
+            parse("[Interface]\n[Peer]\nAllowedIPs = 10.0.0.0/8\n[Peer]"),
+            Some(FileError::MissingPublicKey(2))
+        );
+        assert_eq!(
+            parse("[Interface]\n[Interface]"),
+            Some(FileError::DuplicateInterface(2))
+        );
+        assert_eq!(
+            parse("[Peer]\nPublicKey = AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA="),
+            Some(FileError::LowOrderKey(2))
+        );
+    }
+
+    #[test]
+    fn test_apply() {
+        let (_fake, _reader, tun_writer, _mtu) = dummy::TunTest::create(false);
+        let cfg: WireGuardConfig<dummy::TunTest, dummy::PairBind> =
+            WireGuardConfig::new(WireGuard::new(tun_writer));
+
+        let file = ConfigFile::parse(CONFIG).unwrap();
+        file.apply(&cfg).unwrap();
+        assert!(cfg.get_private_key().is_some());
+        assert_eq!(cfg.get_fwmark(), Some(0x1234));
+
+        let peers = cfg.get_peers();
+        assert_eq!(peers.len(), 2);
+        let peer = peers
+            .iter()
+            .find(|peer| peer.public_key.as_bytes() == file.peers[0].public_key.as_bytes())
+            .unwrap();
+        assert!(peer.endpoint.is_some());
+        assert_eq!(peer.persistent_keepalive_interval, 25);
+        assert_eq!(peer.allowed_ips.len(), 1); // 10.192.124.0/24 is taken by the second peer
+
+        // export (as "wg showconf") and import the state of the device
+        let exported = ConfigFile::from_device(&cfg).to_string();
+        assert!(exported.contains("PersistentKeepalive = 25"));
+        let imported = ConfigFile::parse(&exported).unwrap();
+        assert_eq!(
+            imported.interface.private_key.map(|sk| sk.to_bytes()),
+            file.interface.private_key.as_ref().map(|sk| sk.to_bytes())
+        );
+        assert_eq!(imported.peers.len(), 2);
+        assert!(imported
+            .peers
+            .iter()
+            .any(|peer| peer.persistent_keepalive == Some(25) && peer.endpoint.is_some()));
+
+        // applying again replaces the peers
+        ConfigFile::parse(&CONFIG[..CONFIG.find("[peer]").unwrap()])
+            .unwrap()
+            .apply(&cfg)
+            .unwrap();
+        assert_eq!(cfg.get_peers().len(), 1);
+    }
+}
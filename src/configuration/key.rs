@@ -0,0 +1,411 @@
+/* Keys in their canonical representation (44 characters of base64, as with wg(8)),
+ * loading of the private key from a file (e.g. a secret of a container)
+ * and the search for key pairs with a recognizable public key (a "vanity" prefix):
+ *
+ * The file must not be readable by the group or others (as enforced by wg-quick(8)),
+ * and holds either the key encoded in base64 (the output of "wg genkey")
+ * or the 32 raw bytes of the key. The content of the file is cleared after parsing.
+ */
+
+use super::super::wireguard::is_low_order;
+use super::file::{encode_key, parse_key};
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+
+use clear_on_drop::clear::Clear;
+use clear_on_drop::ClearOnDrop;
+use rand::rngs::OsRng;
+#[cfg(feature = "structured-config")]
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+// longest accepted file: a base64 key with surrounding whitespace
+const MAX_SIZE: usize = 128;
+
+#[derive(Debug)]
+pub enum KeyError {
+    ReadFailed(io::Error),
+    InsecurePermissions(u32), // mode of the file
+    InvalidFormat,
+    InvalidPrefix, // of a vanity key
+    LowOrderPoint, // public key with which every shared secret is all-zero
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyError::ReadFailed(err) => write!(f, "Failed to read key file: {}", err),
+            KeyError::InsecurePermissions(mode) => write!(
+                f,
+                "Key file is readable by the group or others (mode {:o})",
+                mode & 0o7777
+            ),
+            KeyError::InvalidFormat => write!(
+                f,
+                "Invalid key (expected 44 characters of base64 or 32 raw bytes)"
+            ),
+            KeyError::InvalidPrefix => write!(f, "No base64 encoded key starts with the prefix"),
+            KeyError::LowOrderPoint => write!(f, "Public key is a low-order point"),
+        }
+    }
+}
+
+impl From<io::Error> for KeyError {
+    fn from(err: io::Error) -> Self {
+        KeyError::ReadFailed(err)
+    }
+}
+
+/// A public, private or preshared key, formatted and parsed in base64
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Key(pub [u8; 32]);
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&encode_key(&self.0))
+    }
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Key({})", self)
+    }
+}
+
+impl FromStr for Key {
+    type Err = KeyError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        parse_key(value).map(Key).ok_or(KeyError::InvalidFormat)
+    }
+}
+
+impl From<&PublicKey> for Key {
+    fn from(pk: &PublicKey) -> Self {
+        Key(*pk.as_bytes())
+    }
+}
+
+impl From<Key> for PublicKey {
+    fn from(key: Key) -> Self {
+        PublicKey::from(key.0)
+    }
+}
+
+#[cfg(feature = "structured-config")]
+impl Serialize for Key {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "structured-config")]
+impl<'de> Deserialize<'de> for Key {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        value
+            .parse()
+            .map_err(|_| de::Error::custom(format!("invalid key: {}", value)))
+    }
+}
+
+/// Validate the public key of a peer
+///
+/// # Returns
+///
+/// An error if the key is all-zero or another low-order point:
+/// every handshake with the peer would fail (on an all-zero shared secret).
+pub fn validate_public_key(pk: &PublicKey) -> Result<(), KeyError> {
+    if is_low_order(pk) {
+        Err(KeyError::LowOrderPoint)
+    } else {
+        Ok(())
+    }
+}
+
+// Internal function
+//
+// Parse the content of a key file: 32 raw bytes or a base64 key
+fn parse_content(content: &[u8]) -> Option<[u8; 32]> {
+    if content.len() == 32 {
+        let mut key = [0u8; 32];
+        key.copy_from_slice(content);
+        return Some(key);
+    }
+    let content = std::str::from_utf8(content).ok()?;
+    parse_key(content.trim())
+}
+
+/// Load the private key from a file
+///
+/// # Arguments
+///
+/// - `path`: The key file, which must not be readable by the group or others
+///
+/// # Returns
+///
+/// The key, which is cleared from memory when dropped (as are the intermediate buffers).
+pub fn load_private_key<P: AsRef<Path>>(path: P) -> Result<StaticSecret, KeyError> {
+    let mut file = File::open(path)?;
+
+    #[cfg(unix)]
+    {
+        let mode = file.metadata()?.permissions().mode();
+        if mode & 0o044 != 0 {
+            return Err(KeyError::InsecurePermissions(mode));
+        }
+    }
+
+    // a fixed buffer: the content is never reallocated (leaving copies behind)
+    let mut buf = [0u8; MAX_SIZE + 1];
+    let mut buf = ClearOnDrop::new(&mut buf[..]);
+    let mut len = 0;
+    loop {
+        match file.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into()),
+        }
+        if len > MAX_SIZE {
+            return Err(KeyError::InvalidFormat);
+        }
+    }
+
+    let mut key = parse_content(&buf[..len]).ok_or(KeyError::InvalidFormat)?;
+    let sk = StaticSecret::from(key);
+    key.clear();
+    Ok(sk)
+}
+
+/// Cancels a search for a vanity key (e.g. from another thread)
+#[derive(Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+// Internal function
+//
+// Check that the base64 encoding of some key can start with the prefix
+fn valid_prefix(prefix: &str) -> bool {
+    const ALPHABET: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    // the 43rd character only encodes 4 bits (followed by padding)
+    prefix
+        .chars()
+        .enumerate()
+        .all(|(i, c)| match ALPHABET.find(c) {
+            Some(_) if i < 42 => true,
+            Some(n) if i == 42 => n % 4 == 0,
+            _ => false,
+        })
+}
+
+/// Search for a key pair whose base64 encoded public key starts with a prefix
+///
+/// # Arguments
+///
+/// - `prefix`: The prefix of the public key (every character adds 6 bits: 64 times the attempts)
+/// - `threads`: The number of threads searching in parallel (e.g. the number of cores)
+/// - `cancel`: Token to stop the search
+///
+/// # Returns
+///
+/// The private key, None if the search was cancelled.
+pub fn vanity_key(
+    prefix: &str,
+    threads: usize,
+    cancel: &CancelToken,
+) -> Result<Option<StaticSecret>, KeyError> {
+    if !valid_prefix(prefix) {
+        return Err(KeyError::InvalidPrefix);
+    }
+
+    // the first key found stops the other threads
+    let done = CancelToken::new();
+    let (tx, rx) = mpsc::channel();
+    let workers: Vec<thread::JoinHandle<()>> = (0..threads.max(1))
+        .map(|_| {
+            let prefix = prefix.to_owned();
+            let (cancel, done, tx) = (cancel.clone(), done.clone(), tx.clone());
+            thread::spawn(move || {
+                while !cancel.is_cancelled() && !done.is_cancelled() {
+                    let sk = StaticSecret::new(&mut OsRng);
+                    if encode_key(PublicKey::from(&sk).as_bytes()).starts_with(&prefix) {
+                        let _ = tx.send(sk);
+                        return;
+                    }
+                }
+            })
+        })
+        .collect();
+    drop(tx);
+
+    // all senders are dropped when the search is cancelled
+    let sk = rx.recv().ok();
+    done.cancel();
+    for worker in workers {
+        let _ = worker.join();
+    }
+    Ok(sk)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+
+    const SK: &str = "yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=";
+
+    fn key_file(name: &str, content: &[u8], mode: u32) -> PathBuf {
+        let path = env::temp_dir().join(format!("wg-rs-key-{}-{}", std::process::id(), name));
+        fs::write(&path, content).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_key_base64() {
+        let key: Key = SK.parse().unwrap();
+        assert_eq!(Some(key.0), parse_key(SK));
+        assert_eq!(key.to_string(), SK);
+        assert_eq!(format!("{:?}", key), format!("Key({})", SK));
+
+        // public keys
+        let pk = PublicKey::from(&StaticSecret::from(key.0));
+        assert_eq!(
+            Key::from(&pk).to_string(),
+            "HIgo9xNzJMWLKASShiTqIybxZ0U3wGLiUeJ1PKf8ykw="
+        );
+        assert_eq!(PublicKey::from(Key::from(&pk)).as_bytes(), pk.as_bytes());
+
+        // hex, truncated and unpadded keys
+        for invalid in [
+            "c809f3e5317e9575c9b5ed78b638b7ce530dabe85ddab614220241801ddf0669",
+            "yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBm=",
+            "yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk",
+        ]
+        .iter()
+        {
+            assert!(invalid.parse::<Key>().is_err(), "accepted {}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_validate_public_key() {
+        let pk = PublicKey::from(&StaticSecret::new(&mut OsRng));
+        assert!(validate_public_key(&pk).is_ok());
+
+        // all-zero, 1 and p - 1 (with and without the ignored most significant bit)
+        for low in [
+            "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=",
+            "AQAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=",
+            "AQAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAIA=",
+            "7P///////////////////////////////////////38=",
+            "7P////////////////////////////////////////8=",
+        ]
+        .iter()
+        {
+            let pk = PublicKey::from(parse_key(low).unwrap());
+            match validate_public_key(&pk) {
+                Err(KeyError::LowOrderPoint) => (),
+                _ => panic!("accepted low-order point {}", low),
+            }
+        }
+    }
+
+    #[test]
+    fn test_load_private_key() {
+        let expected = parse_key(SK).unwrap();
+
+        // base64 (with a trailing newline) and raw bytes
+        let base64 = key_file("base64", format!("{}\n", SK).as_bytes(), 0o600);
+        let raw = key_file("raw", &expected, 0o400);
+        for path in [&base64, &raw].iter() {
+            let sk = load_private_key(path).unwrap();
+            assert_eq!(sk.to_bytes(), expected);
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_rejected() {
+        // readable by the group or others
+        for mode in [0o640, 0o604].iter() {
+            let path = key_file("mode", SK.as_bytes(), *mode);
+            let result = load_private_key(&path);
+            fs::remove_file(&path).unwrap();
+            match result {
+                Err(KeyError::InsecurePermissions(m)) => assert_eq!(m & 0o777, *mode),
+                _ => panic!("key file with mode {:o} accepted", mode),
+            }
+        }
+
+        // neither base64 nor 32 raw bytes
+        for content in [&b"invalid"[..], &[0u8; 31][..], &[b' '; 200][..]].iter() {
+            let path = key_file("format", content, 0o600);
+            let result = load_private_key(&path);
+            fs::remove_file(&path).unwrap();
+            match result {
+                Err(KeyError::InvalidFormat) => (),
+                _ => panic!("key file {:?} accepted", content),
+            }
+        }
+
+        // missing
+        let path = env::temp_dir().join(format!("wg-rs-key-{}-missing", std::process::id()));
+        match load_private_key(&path) {
+            Err(KeyError::ReadFailed(_)) => (),
+            _ => panic!("missing key file accepted"),
+        }
+    }
+
+    #[test]
+    fn test_vanity_key() {
+        let cancel = CancelToken::new();
+        for prefix in ["", "A", "wg"].iter() {
+            let sk = vanity_key(prefix, 2, &cancel).unwrap().unwrap();
+            assert!(encode_key(PublicKey::from(&sk).as_bytes()).starts_with(prefix));
+        }
+
+        // a cancelled search
+        let cancelled = cancel.clone();
+        let search = thread::spawn(move || vanity_key("wireguard", 2, &cancelled));
+        cancel.cancel();
+        assert!(search.join().unwrap().unwrap().is_none());
+
+        // no key starts with these
+        let longest = "A".repeat(42) + "B";
+        for prefix in ["wg-", "wg=", &longest, &"A".repeat(44)].iter() {
+            match vanity_key(prefix, 1, &CancelToken::new()) {
+                Err(KeyError::InvalidPrefix) => (),
+                _ => panic!("accepted prefix {}", prefix),
+            }
+        }
+    }
+}
@@ -0,0 +1,460 @@
+/* Storage of the private key in the keyring of the operating system (instead of a file):
+ *
+ * - linux: the kernel keyring of the user (a "user" key described as "wireguard-rs:<interface>"),
+ *   held in memory by the kernel: the key is lost on reboot and must be stored again
+ * - macos: the Keychain (a generic password of the service "wireguard-rs" for the interface)
+ * - windows: the Credential Manager (a generic credential named "wireguard-rs:<interface>")
+ *
+ * The key is stored as its 32 raw bytes.
+ */
+
+use std::fmt;
+
+use clear_on_drop::clear::Clear;
+use x25519_dalek::StaticSecret;
+
+pub const SERVICE: &str = "wireguard-rs";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum KeyringError {
+    NotFound,
+    InvalidKey,
+    Unsupported,
+    Failed(String),
+}
+
+impl fmt::Display for KeyringError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyringError::NotFound => write!(f, "No key in the keyring"),
+            KeyringError::InvalidKey => write!(f, "Invalid key in the keyring"),
+            KeyringError::Unsupported => {
+                write!(
+                    f,
+                    "Keyring not supported on this platform (or denied by the sandbox)"
+                )
+            }
+            KeyringError::Failed(e) => write!(f, "Keyring error: {}", e),
+        }
+    }
+}
+
+/// Load the private key of an interface from the keyring
+pub fn load(interface: &str) -> Result<StaticSecret, KeyringError> {
+    // a larger buffer detects payloads other than a key
+    let mut buf = [0u8; 33];
+    let result = imp::read(interface, &mut buf);
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&buf[..32]);
+    buf.clear();
+
+    let sk = match result {
+        Ok(32) => Ok(StaticSecret::from(key)),
+        Ok(_) => Err(KeyringError::InvalidKey),
+        Err(e) => Err(e),
+    };
+    key.clear();
+    sk
+}
+
+/// Store the private key of an interface in the keyring (replacing a stored key)
+pub fn store(interface: &str, sk: &StaticSecret) -> Result<(), KeyringError> {
+    let mut key = sk.to_bytes();
+    let result = imp::write(interface, &key);
+    key.clear();
+    result
+}
+
+/// Remove the private key of an interface from the keyring
+pub fn remove(interface: &str) -> Result<(), KeyringError> {
+    imp::remove(interface)
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use super::{KeyringError, SERVICE};
+
+    use std::ffi::CString;
+    use std::io;
+
+    use libc::{c_long, syscall, SYS_add_key, SYS_keyctl};
+
+    const KEY_SPEC_USER_KEYRING: c_long = -4;
+    const KEYCTL_SETPERM: c_long = 5;
+    const KEYCTL_UNLINK: c_long = 9;
+    const KEYCTL_SEARCH: c_long = 10;
+    const KEYCTL_READ: c_long = 11;
+
+    // possessor: all, user (the owner): view, read and search
+    const KEY_PERM: c_long = 0x3f0b_0000;
+
+    const KEY_TYPE: &[u8] = b"user\0";
+
+    fn description(interface: &str) -> Result<CString, KeyringError> {
+        CString::new(format!("{}:{}", SERVICE, interface))
+            .map_err(|_| KeyringError::Failed(format!("invalid interface name {}", interface)))
+    }
+
+    fn error() -> KeyringError {
+        let e = io::Error::last_os_error();
+        match e.raw_os_error() {
+            Some(libc::ENOKEY) | Some(libc::EKEYEXPIRED) | Some(libc::EKEYREVOKED) => {
+                KeyringError::NotFound
+            }
+            // keyctl is commonly denied by the seccomp profile of containers
+            Some(libc::ENOSYS) | Some(libc::EPERM) | Some(libc::EACCES) => {
+                KeyringError::Unsupported
+            }
+            _ => KeyringError::Failed(e.to_string()),
+        }
+    }
+
+    fn search(interface: &str) -> Result<c_long, KeyringError> {
+        let desc = description(interface)?;
+        let serial = unsafe {
+            syscall(
+                SYS_keyctl,
+                KEYCTL_SEARCH,
+                KEY_SPEC_USER_KEYRING,
+                KEY_TYPE.as_ptr(),
+                desc.as_ptr(),
+                0 as c_long,
+            )
+        };
+        if serial < 0 {
+            return Err(error());
+        }
+        Ok(serial)
+    }
+
+    pub fn read(interface: &str, buf: &mut [u8]) -> Result<usize, KeyringError> {
+        let serial = search(interface)?;
+
+        // the size of the payload is returned (even if larger than the buffer)
+        let len = unsafe { syscall(SYS_keyctl, KEYCTL_READ, serial, buf.as_mut_ptr(), buf.len()) };
+        if len < 0 {
+            return Err(error());
+        }
+        Ok(len as usize)
+    }
+
+    pub fn write(interface: &str, payload: &[u8]) -> Result<(), KeyringError> {
+        let desc = description(interface)?;
+        let serial = unsafe {
+            syscall(
+                SYS_add_key,
+                KEY_TYPE.as_ptr(),
+                desc.as_ptr(),
+                payload.as_ptr(),
+                payload.len(),
+                KEY_SPEC_USER_KEYRING,
+            )
+        };
+        if serial < 0 {
+            return Err(error());
+        }
+
+        // readable by the owner without possessing the key (e.g. from another session)
+        if unsafe { syscall(SYS_keyctl, KEYCTL_SETPERM, serial, KEY_PERM) } < 0 {
+            return Err(error());
+        }
+        Ok(())
+    }
+
+    pub fn remove(interface: &str) -> Result<(), KeyringError> {
+        let serial = search(interface)?;
+        if unsafe { syscall(SYS_keyctl, KEYCTL_UNLINK, serial, KEY_SPEC_USER_KEYRING) } < 0 {
+            return Err(error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod imp {
+    use super::{KeyringError, SERVICE};
+
+    use std::os::raw::{c_char, c_void};
+    use std::ptr;
+    use std::slice;
+
+    type OSStatus = i32;
+
+    const ERR_SEC_DUPLICATE_ITEM: OSStatus = -25299;
+    const ERR_SEC_ITEM_NOT_FOUND: OSStatus = -25300;
+
+    #[link(name = "Security", kind = "framework")]
+    extern "C" {
+        fn SecKeychainFindGenericPassword(
+            keychain: *const c_void,
+            service_len: u32,
+            service: *const c_char,
+            account_len: u32,
+            account: *const c_char,
+            password_len: *mut u32,
+            password: *mut *mut c_void,
+            item: *mut *mut c_void,
+        ) -> OSStatus;
+        fn SecKeychainAddGenericPassword(
+            keychain: *mut c_void,
+            service_len: u32,
+            service: *const c_char,
+            account_len: u32,
+            account: *const c_char,
+            password_len: u32,
+            password: *const c_void,
+            item: *mut *mut c_void,
+        ) -> OSStatus;
+        fn SecKeychainItemModifyAttributesAndData(
+            item: *mut c_void,
+            attrs: *const c_void,
+            length: u32,
+            data: *const c_void,
+        ) -> OSStatus;
+        fn SecKeychainItemFreeContent(attrs: *mut c_void, data: *mut c_void) -> OSStatus;
+        fn SecKeychainItemDelete(item: *mut c_void) -> OSStatus;
+    }
+
+    #[link(name = "CoreFoundation", kind = "framework")]
+    extern "C" {
+        fn CFRelease(cf: *const c_void);
+    }
+
+    fn error(status: OSStatus) -> KeyringError {
+        match status {
+            ERR_SEC_ITEM_NOT_FOUND => KeyringError::NotFound,
+            status => KeyringError::Failed(format!("OSStatus {}", status)),
+        }
+    }
+
+    // the item of the interface (to be released with CFRelease)
+    fn find(interface: &str) -> Result<*mut c_void, KeyringError> {
+        let mut item = ptr::null_mut();
+        let status = unsafe {
+            SecKeychainFindGenericPassword(
+                ptr::null(),
+                SERVICE.len() as u32,
+                SERVICE.as_ptr() as *const c_char,
+                interface.len() as u32,
+                interface.as_ptr() as *const c_char,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                &mut item,
+            )
+        };
+        if status != 0 {
+            return Err(error(status));
+        }
+        Ok(item)
+    }
+
+    pub fn read(interface: &str, buf: &mut [u8]) -> Result<usize, KeyringError> {
+        let mut len = 0u32;
+        let mut data = ptr::null_mut();
+        let status = unsafe {
+            SecKeychainFindGenericPassword(
+                ptr::null(),
+                SERVICE.len() as u32,
+                SERVICE.as_ptr() as *const c_char,
+                interface.len() as u32,
+                interface.as_ptr() as *const c_char,
+                &mut len,
+                &mut data,
+                ptr::null_mut(),
+            )
+        };
+        if status != 0 {
+            return Err(error(status));
+        }
+
+        // copy and clear the content allocated by the keychain
+        unsafe {
+            let content = slice::from_raw_parts_mut(data as *mut u8, len as usize);
+            let n = content.len().min(buf.len());
+            buf[..n].copy_from_slice(&content[..n]);
+            for byte in content.iter_mut() {
+                ptr::write_volatile(byte, 0);
+            }
+            SecKeychainItemFreeContent(ptr::null_mut(), data);
+        }
+        Ok(len as usize)
+    }
+
+    pub fn write(interface: &str, payload: &[u8]) -> Result<(), KeyringError> {
+        let status = unsafe {
+            SecKeychainAddGenericPassword(
+                ptr::null_mut(),
+                SERVICE.len() as u32,
+                SERVICE.as_ptr() as *const c_char,
+                interface.len() as u32,
+                interface.as_ptr() as *const c_char,
+                payload.len() as u32,
+                payload.as_ptr() as *const c_void,
+                ptr::null_mut(),
+            )
+        };
+        if status != ERR_SEC_DUPLICATE_ITEM {
+            return if status == 0 {
+                Ok(())
+            } else {
+                Err(error(status))
+            };
+        }
+
+        // replace the stored key
+        let item = find(interface)?;
+        let status = unsafe {
+            let status = SecKeychainItemModifyAttributesAndData(
+                item,
+                ptr::null(),
+                payload.len() as u32,
+                payload.as_ptr() as *const c_void,
+            );
+            CFRelease(item);
+            status
+        };
+        if status != 0 {
+            return Err(error(status));
+        }
+        Ok(())
+    }
+
+    pub fn remove(interface: &str) -> Result<(), KeyringError> {
+        let item = find(interface)?;
+        let status = unsafe {
+            let status = SecKeychainItemDelete(item);
+            CFRelease(item);
+            status
+        };
+        if status != 0 {
+            return Err(error(status));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+mod imp {
+    use super::{KeyringError, SERVICE};
+
+    use std::ffi::OsStr;
+    use std::io;
+    use std::mem;
+    use std::os::windows::ffi::OsStrExt;
+    use std::ptr;
+    use std::slice;
+
+    use winapi::shared::winerror::ERROR_NOT_FOUND;
+    use winapi::um::wincred::{
+        CredDeleteW, CredFree, CredReadW, CredWriteW, CREDENTIALW, CRED_PERSIST_LOCAL_MACHINE,
+        CRED_TYPE_GENERIC,
+    };
+
+    fn wide(value: &str) -> Vec<u16> {
+        OsStr::new(value).encode_wide().chain(Some(0)).collect()
+    }
+
+    fn error() -> KeyringError {
+        let e = io::Error::last_os_error();
+        match e.raw_os_error() {
+            Some(code) if code as u32 == ERROR_NOT_FOUND => KeyringError::NotFound,
+            _ => KeyringError::Failed(e.to_string()),
+        }
+    }
+
+    pub fn read(interface: &str, buf: &mut [u8]) -> Result<usize, KeyringError> {
+        let target = wide(&format!("{}:{}", SERVICE, interface));
+        let mut cred = ptr::null_mut();
+        if unsafe { CredReadW(target.as_ptr(), CRED_TYPE_GENERIC, 0, &mut cred) } == 0 {
+            return Err(error());
+        }
+
+        // copy and clear the blob allocated by the Credential Manager
+        unsafe {
+            let len = (*cred).CredentialBlobSize as usize;
+            let blob = slice::from_raw_parts_mut((*cred).CredentialBlob, len);
+            let n = len.min(buf.len());
+            buf[..n].copy_from_slice(&blob[..n]);
+            for byte in blob.iter_mut() {
+                ptr::write_volatile(byte, 0);
+            }
+            CredFree(cred as *mut _);
+            Ok(len)
+        }
+    }
+
+    pub fn write(interface: &str, payload: &[u8]) -> Result<(), KeyringError> {
+        let mut target = wide(&format!("{}:{}", SERVICE, interface));
+        let mut user = wide(SERVICE);
+        let mut cred: CREDENTIALW = unsafe { mem::zeroed() };
+        cred.Type = CRED_TYPE_GENERIC;
+        cred.TargetName = target.as_mut_ptr();
+        cred.UserName = user.as_mut_ptr();
+        cred.CredentialBlobSize = payload.len() as u32;
+        cred.CredentialBlob = payload.as_ptr() as *mut u8;
+        cred.Persist = CRED_PERSIST_LOCAL_MACHINE;
+        if unsafe { CredWriteW(&mut cred, 0) } == 0 {
+            return Err(error());
+        }
+        Ok(())
+    }
+
+    pub fn remove(interface: &str) -> Result<(), KeyringError> {
+        let target = wide(&format!("{}:{}", SERVICE, interface));
+        if unsafe { CredDeleteW(target.as_ptr(), CRED_TYPE_GENERIC, 0) } == 0 {
+            return Err(error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+mod imp {
+    use super::KeyringError;
+
+    pub fn read(_interface: &str, _buf: &mut [u8]) -> Result<usize, KeyringError> {
+        Err(KeyringError::Unsupported)
+    }
+
+    pub fn write(_interface: &str, _payload: &[u8]) -> Result<(), KeyringError> {
+        Err(KeyringError::Unsupported)
+    }
+
+    pub fn remove(_interface: &str) -> Result<(), KeyringError> {
+        Err(KeyringError::Unsupported)
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    use rand::rngs::OsRng;
+
+    #[test]
+    #[ignore = "keyctl may be denied in a container"]
+    fn test_keyring() {
+        let interface = format!("test{}", std::process::id());
+        match load(&interface) {
+            Err(KeyringError::NotFound) => (),
+            other => panic!("unexpected key: {:?}", other.map(|sk| sk.to_bytes())),
+        }
+
+        // stored keys are replaced
+        for _ in 0..2 {
+            let sk = StaticSecret::new(&mut OsRng);
+            store(&interface, &sk).unwrap();
+            assert_eq!(load(&interface).unwrap().to_bytes(), sk.to_bytes());
+        }
+
+        // other payloads are rejected
+        imp::write(&interface, b"not a key").unwrap();
+        assert_eq!(
+            load(&interface).map(|sk| sk.to_bytes()),
+            Err(KeyringError::InvalidKey)
+        );
+
+        remove(&interface).unwrap();
+        assert_eq!(remove(&interface), Err(KeyringError::NotFound));
+    }
+}
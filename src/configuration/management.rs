@@ -0,0 +1,540 @@
+/* Management API (HTTP + JSON), for managing fleets of devices remotely:
+ *
+ * GET    /interface                    public key, listen port, fwmark and totals
+ * GET    /peers                        every peer (configuration and statistics)
+ * POST   /peers                        add a peer (PeerConfig), 409 if it exists
+ * GET    /peers/<key>                  a single peer
+ * PUT    /peers/<key>                  add or update a peer (the allowed IPs are replaced)
+ * DELETE /peers/<key>                  remove a peer
+ * POST   /peers/<key>/handshake        initiate a handshake immediately
+ * DELETE /peers/<key>/session          invalidate the sessions with the peer
+ *
+ * Keys in paths are either hex or base64 (url-safe or percent-encoded).
+ * Requests are authenticated with a bearer token ("Authorization: Bearer <token>").
+ * Preshared keys can be set, but are never returned (only whether a peer has one).
+ * The server does not terminate TLS: expose it through a TLS proxy (or a tunnel).
+ */
+
+use super::file::parse_key;
+use super::key::Key;
+use super::structured::PeerConfig;
+use super::Configuration;
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use hex::FromHex;
+use serde::Serialize;
+use x25519_dalek::PublicKey;
+
+const MAX_REQUEST_SIZE: u64 = 1 << 20;
+const MAX_HEADERS: usize = 64;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Semantics:
+// Bound on the number of connections handled concurrently (further connections are closed)
+const MAX_CONNECTIONS: usize = 16;
+
+#[derive(Serialize)]
+struct InterfaceStatus {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    public_key: Option<Key>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    listen_port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fwmark: Option<u32>,
+    peers: usize,
+    rx_bytes: u64,
+    tx_bytes: u64,
+    source_violations: u64, // inbound packets violating the cryptokey routing
+    unknown_messages: u64,  // inbound messages dropped by type (unknown, short, oversized)
+    short_messages: u64,
+    oversized_messages: u64,
+}
+
+#[derive(Serialize)]
+struct PeerStatus {
+    #[serde(flatten)]
+    config: PeerConfig, // without the preshared key
+    has_preshared_key: bool,
+    rx_bytes: u64,
+    tx_bytes: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_handshake: Option<u64>, // UNIX time (seconds)
+}
+
+#[derive(Serialize)]
+struct Error<'a> {
+    error: &'a str,
+}
+
+// Internal function
+//
+// Decode the key of a peer in a path (hex, base64url or percent-encoded base64)
+fn path_key(value: &str) -> Option<PublicKey> {
+    if let Ok(key) = <[u8; 32]>::from_hex(value) {
+        return Some(PublicKey::from(key));
+    }
+    let value = value
+        .replace("%2B", "+")
+        .replace("%2b", "+")
+        .replace("%2F", "/")
+        .replace("%2f", "/")
+        .replace("%3D", "=")
+        .replace("%3d", "=")
+        .replace('-', "+")
+        .replace('_', "/");
+    parse_key(&value).map(PublicKey::from)
+}
+
+fn json<T: Serialize>(status: u16, value: &T) -> (u16, String) {
+    match serde_json::to_string(value) {
+        Ok(body) => (status, body),
+        Err(_) => error(500, "failed to serialize response"),
+    }
+}
+
+fn error(status: u16, msg: &str) -> (u16, String) {
+    let body = serde_json::to_string(&Error { error: msg }).unwrap_or_default();
+    (status, body)
+}
+
+// Internal function
+//
+// Look up a peer of the device
+fn peer_status<C: Configuration>(config: &C, pk: &PublicKey) -> Option<PeerStatus> {
+    config
+        .get_peers()
+        .into_iter()
+        .find(|peer| peer.public_key.as_bytes() == pk.as_bytes())
+        .map(status)
+}
+
+fn status(peer: super::config::PeerState) -> PeerStatus {
+    let rx_bytes = peer.rx_bytes;
+    let tx_bytes = peer.tx_bytes;
+    let last_handshake = peer.last_handshake_time.map(|(secs, _)| secs);
+    let mut config = PeerConfig::from_state(peer);
+    PeerStatus {
+        has_preshared_key: config.preshared_key.take().is_some(),
+        config,
+        rx_bytes,
+        tx_bytes,
+        last_handshake,
+    }
+}
+
+/// Process a request
+///
+/// # Returns
+///
+/// The status code and the JSON body of the response.
+pub fn route<C: Configuration>(config: &C, method: &str, path: &str, body: &[u8]) -> (u16, String) {
+    let segments: Vec<&str> = path
+        .split('?')
+        .next()
+        .unwrap_or("")
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect();
+
+    // every route on a peer requires a valid key
+    let pk = match segments.as_slice() {
+        ["peers", key] | ["peers", key, "handshake"] | ["peers", key, "session"] => {
+            match path_key(key) {
+                Some(pk) => Some(pk),
+                None => return error(400, "invalid key"),
+            }
+        }
+        _ => None,
+    };
+    let peer_config = || -> Result<PeerConfig, (u16, String)> {
+        serde_json::from_slice(body).map_err(|e| error(400, &e.to_string()))
+    };
+
+    match (method, segments.as_slice(), pk) {
+        ("GET", ["interface"], _) => {
+            let peers = config.get_peers();
+            let stats = config.get_message_stats();
+            json(
+                200,
+                &InterfaceStatus {
+                    public_key: config
+                        .get_private_key()
+                        .map(|sk| Key(*PublicKey::from(&sk).as_bytes())),
+                    listen_port: config.get_listen_port(),
+                    fwmark: config.get_fwmark(),
+                    peers: peers.len(),
+                    rx_bytes: peers.iter().map(|peer| peer.rx_bytes).sum(),
+                    tx_bytes: peers.iter().map(|peer| peer.tx_bytes).sum(),
+                    source_violations: config.get_source_violations(),
+                    unknown_messages: stats.unknown,
+                    short_messages: stats.short,
+                    oversized_messages: stats.oversized,
+                },
+            )
+        }
+        ("GET", ["peers"], _) => {
+            let peers: Vec<PeerStatus> = config.get_peers().into_iter().map(status).collect();
+            json(200, &peers)
+        }
+        ("POST", ["peers"], _) => {
+            let peer = match peer_config() {
+                Ok(peer) => peer,
+                Err(response) => return response,
+            };
+            let pk = PublicKey::from(peer.public_key.0);
+            if peer_status(config, &pk).is_some() {
+                return error(409, "peer exists");
+            }
+            match peer.apply(config) {
+                Ok(()) => json(201, &peer_status(config, &pk)),
+                Err(e) => {
+                    config.remove_peer(&pk);
+                    error(400, &e.to_string())
+                }
+            }
+        }
+        ("GET", ["peers", _], Some(pk)) => match peer_status(config, &pk) {
+            Some(peer) => json(200, &peer),
+            None => error(404, "no such peer"),
+        },
+        ("PUT", ["peers", _], Some(pk)) => {
+            let peer = match peer_config() {
+                Ok(peer) => peer,
+                Err(response) => return response,
+            };
+            if peer.public_key.0 != *pk.as_bytes() {
+                return error(400, "public key does not match the path");
+            }
+            match peer.apply(config) {
+                Ok(()) => json(200, &peer_status(config, &pk)),
+                Err(e) => error(400, &e.to_string()),
+            }
+        }
+        ("DELETE", ["peers", _], Some(pk)) => match peer_status(config, &pk) {
+            Some(_) => {
+                config.remove_peer(&pk);
+                (204, String::new())
+            }
+            None => error(404, "no such peer"),
+        },
+        ("POST", ["peers", _, "handshake"], Some(pk)) => {
+            if config.begin_handshake(&pk) {
+                (202, String::new())
+            } else {
+                error(404, "no such peer")
+            }
+        }
+        ("DELETE", ["peers", _, "session"], Some(pk)) => {
+            if config.clear_session(&pk) {
+                (204, String::new())
+            } else {
+                error(404, "no such peer")
+            }
+        }
+        (_, ["interface"], _) | (_, ["peers"], _) | (_, ["peers", ..], _) => {
+            error(405, "method not allowed")
+        }
+        _ => error(404, "not found"),
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        _ => "Internal Server Error",
+    }
+}
+
+// Internal function
+//
+// Compare the tokens in constant time
+fn authorized(expected: &str, header: Option<&str>) -> bool {
+    let token = match header.and_then(|h| h.strip_prefix("Bearer ")) {
+        Some(token) => token.trim().as_bytes(),
+        None => return false,
+    };
+    let expected = expected.as_bytes();
+    token.len() == expected.len()
+        && token
+            .iter()
+            .zip(expected.iter())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/// Handle a connection (a single request, HTTP/1.1)
+///
+/// # Arguments
+///
+/// - `stream`: The connection
+/// - `config`: The configuration interface of the device
+/// - `token`: The bearer token required to authorize requests (None to allow all)
+pub fn handle<S: Read + Write, C: Configuration>(
+    stream: &mut S,
+    config: &C,
+    token: Option<&str>,
+) -> io::Result<()> {
+    let (status, body) = {
+        let mut reader = BufReader::new((&mut *stream).take(MAX_REQUEST_SIZE));
+
+        // request line
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let mut parts = line.split_whitespace();
+        let (method, path) = match (parts.next(), parts.next()) {
+            (Some(method), Some(path)) => (method.to_owned(), path.to_owned()),
+            _ => return Ok(()),
+        };
+
+        // headers
+        let mut length = 0;
+        let mut authorization = None;
+        for _ in 0..MAX_HEADERS {
+            let mut header = String::new();
+            if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+                break;
+            }
+            let mut split = header.splitn(2, ':');
+            if let (Some(name), Some(value)) = (split.next(), split.next()) {
+                match name.trim().to_ascii_lowercase().as_str() {
+                    "content-length" => length = value.trim().parse().unwrap_or(u64::MAX),
+                    "authorization" => authorization = Some(value.trim().to_owned()),
+                    _ => (),
+                }
+            }
+        }
+
+        let allowed = match token {
+            Some(token) => authorized(token, authorization.as_deref()),
+            None => true,
+        };
+        if !allowed {
+            error(401, "unauthorized")
+        } else if length > MAX_REQUEST_SIZE {
+            error(413, "request too large")
+        } else {
+            let mut body = vec![0u8; length as usize];
+            reader.read_exact(&mut body)?;
+            route(config, &method, &path, &body)
+        }
+    };
+
+    log::debug!("management API: response {}", status);
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason(status),
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+/// Serve the management API (a thread per connection, at most `MAX_CONNECTIONS`)
+pub fn serve<C: Configuration + Clone + Send + 'static>(
+    listener: TcpListener,
+    config: C,
+    token: Option<String>,
+) {
+    let active = Arc::new(AtomicUsize::new(0));
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    log::debug!("management API: failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+            if active.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                active.fetch_sub(1, Ordering::SeqCst);
+                log::debug!("management API: too many connections, closing");
+                continue;
+            }
+            let active = active.clone();
+            let config = config.clone();
+            let token = token.clone();
+            thread::spawn(move || {
+                let _ = stream.set_read_timeout(Some(REQUEST_TIMEOUT));
+                let _ = stream.set_write_timeout(Some(REQUEST_TIMEOUT));
+                if let Err(e) = handle(&mut stream, &config, token.as_deref()) {
+                    log::debug!("management API: connection error: {}", e);
+                }
+                active.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::platform::dummy;
+    use super::super::super::wireguard::WireGuard;
+    use super::super::WireGuardConfig;
+    use super::*;
+
+    use std::io::Cursor;
+
+    const PK: &str = "xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=";
+    const PK_URL: &str = "xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg%3D";
+
+    struct Connection {
+        request: Cursor<Vec<u8>>,
+        response: Vec<u8>,
+    }
+
+    impl Read for Connection {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.request.read(buf)
+        }
+    }
+
+    impl Write for Connection {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.response.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn device() -> WireGuardConfig<dummy::TunTest, dummy::PairBind> {
+        let (_fake, _reader, tun_writer, _mtu) = dummy::TunTest::create(false);
+        WireGuardConfig::new(WireGuard::new(tun_writer))
+    }
+
+    #[test]
+    fn test_peer_crud() {
+        let cfg = device();
+        let body = format!(
+            r#"{{"public_key": "{}", "endpoint": "127.0.0.1:51820", "allowed_ips": ["10.0.0.2/32"]}}"#,
+            PK
+        );
+
+        // create
+        assert_eq!(route(&cfg, "POST", "/peers", body.as_bytes()).0, 201);
+        assert_eq!(route(&cfg, "POST", "/peers", body.as_bytes()).0, 409);
+        assert_eq!(route(&cfg, "POST", "/peers", b"{}").0, 400);
+        let low = body.replace(PK, "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=");
+        assert_eq!(route(&cfg, "POST", "/peers", low.as_bytes()).0, 400);
+
+        // read (by base64url, percent-encoded base64 and hex)
+        let (status, peer) = route(&cfg, "GET", &format!("/peers/{}", PK_URL), b"");
+        assert_eq!(status, 200);
+        assert!(peer.contains(r#""allowed_ips":["10.0.0.2/32"]"#));
+        assert!(peer.contains(r#""has_preshared_key":false"#));
+        assert!(peer.contains(r#""rx_bytes":0"#));
+        let url = PK.replace('/', "_").replace('+', "-");
+        assert_eq!(route(&cfg, "GET", &format!("/peers/{}", url), b"").0, 200);
+        let hex = hex::encode(parse_key(PK).unwrap());
+        assert_eq!(route(&cfg, "GET", &format!("/peers/{}", hex), b"").0, 200);
+        assert_eq!(route(&cfg, "GET", "/peers/invalid", b"").0, 400);
+        let (status, peers) = route(&cfg, "GET", "/peers", b"");
+        assert_eq!(status, 200);
+        assert!(peers.starts_with('[') && peers.contains(PK));
+        let (status, interface) = route(&cfg, "GET", "/interface", b"");
+        assert_eq!(status, 200);
+        assert!(interface.contains(r#""peers":1"#));
+        assert!(interface.contains(r#""source_violations":0"#));
+
+        // update (the allowed IPs are replaced)
+        let update = body.replace("10.0.0.2/32", "10.0.1.0/24");
+        let path = format!("/peers/{}", PK_URL);
+        let (status, peer) = route(&cfg, "PUT", &path, update.as_bytes());
+        assert_eq!(status, 200);
+        assert!(peer.contains(r#""allowed_ips":["10.0.1.0/24"]"#));
+
+        // the preshared key is never returned
+        let psk = "FpCyhws9cxwWoV4xELtfJvjJN+zQVRPISllRWgeopVE=";
+        let update = update.replacen("{", &format!(r#"{{"preshared_key": "{}", "#, psk), 1);
+        let (status, peer) = route(&cfg, "PUT", &path, update.as_bytes());
+        assert_eq!(status, 200);
+        assert!(peer.contains(r#""has_preshared_key":true"#));
+        assert!(!peer.contains(r#""preshared_key":"#));
+        assert!(!peer.contains(psk));
+        assert!(!route(&cfg, "GET", "/peers", b"").1.contains(psk));
+        assert_eq!(
+            route(
+                &cfg,
+                "PUT",
+                "/peers/TrMvSoP4jYQlY6RIzBgbssQqY3vxI2Pi-y71lOWWXX0=",
+                update.as_bytes()
+            )
+            .0,
+            400
+        );
+
+        // handshake, clear session, delete
+        let handshake = format!("/peers/{}/handshake", PK_URL);
+        let session = format!("/peers/{}/session", PK_URL);
+        assert_eq!(route(&cfg, "POST", &handshake, b"").0, 202);
+        assert_eq!(route(&cfg, "DELETE", &session, b"").0, 204);
+        assert_eq!(route(&cfg, "DELETE", &path, b"").0, 204);
+        assert_eq!(route(&cfg, "DELETE", &path, b"").0, 404);
+        assert_eq!(route(&cfg, "POST", &handshake, b"").0, 404);
+        assert_eq!(route(&cfg, "DELETE", &session, b"").0, 404);
+        assert_eq!(route(&cfg, "PATCH", &path, b"").0, 405);
+        assert_eq!(route(&cfg, "GET", "/", b"").0, 404);
+    }
+
+    #[test]
+    fn test_handle() {
+        let cfg = device();
+        let request = |token: &str| {
+            let mut conn = Connection {
+                request: Cursor::new(
+                    format!(
+                        "GET /interface HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer {}\r\n\r\n",
+                        token
+                    )
+                    .into_bytes(),
+                ),
+                response: vec![],
+            };
+            handle(&mut conn, &cfg, Some("secret")).unwrap();
+            String::from_utf8(conn.response).unwrap()
+        };
+
+        let response = request("secret");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(
+            r#""peers":0,"rx_bytes":0,"tx_bytes":0,"source_violations":0,"unknown_messages":0,"short_messages":0,"oversized_messages":0}"#
+        ));
+        assert!(request("wrong!").starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+
+        // request with a body
+        let body = format!(r#"{{"public_key": "{}"}}"#, PK);
+        let mut conn = Connection {
+            request: Cursor::new(
+                format!(
+                    "POST /peers HTTP/1.1\r\ncontent-length: {}\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+                .into_bytes(),
+            ),
+            response: vec![],
+        };
+        handle(&mut conn, &cfg, None).unwrap();
+        assert!(String::from_utf8(conn.response)
+            .unwrap()
+            .starts_with("HTTP/1.1 201 Created\r\n"));
+    }
+}
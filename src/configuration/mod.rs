@@ -1,12 +1,32 @@
 mod config;
+pub mod diff;
+pub mod env;
 mod error;
+pub mod file;
+pub mod key;
+#[cfg(feature = "keyring")]
+pub mod keyring;
+#[cfg(feature = "management-api")]
+pub mod management;
+pub mod passphrase;
+#[cfg(unix)]
+pub mod signer;
+#[cfg(feature = "structured-config")]
+mod structured;
 pub mod uapi;
 
 use super::platform::Endpoint;
 use super::platform::{tun, udp};
-use super::wireguard::WireGuard;
+use super::wireguard::{
+    DropPolicy, EvictionPolicy, HandshakeConfig, HandshakeState, KeyConfirmedHandler, MessageStats,
+    Obfuscator, PeerExpiryHandler, ReservedBytes, SourcePolicy, TcpListener, TcpProxy,
+    UnknownMessageHandler, WireGuard,
+};
 
 pub use error::ConfigError;
+pub use file::{encode_key, parse_key, ConfigFile};
+#[cfg(feature = "structured-config")]
+pub use structured::{DeviceConfig, PeerConfig, StructuredError};
 
 pub use config::Configuration;
 pub use config::WireGuardConfig;
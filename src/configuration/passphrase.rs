@@ -0,0 +1,262 @@
+/* Derivation of a (deterministic) private key from a passphrase with Argon2id (RFC 9106, the argon2 crate),
+ * for ephemeral test networks and the recovery of a key which was not backed up:
+ *
+ * The parameters are explicit (as in the encoding of password hashes: "m=65536,t=3,p=4"),
+ * the same passphrase, salt and parameters always yield the same key.
+ * A key derived from a weak passphrase is a weak key: prefer random keys ("genkey").
+ */
+
+use std::fmt;
+use std::str::FromStr;
+
+use argon2::{Algorithm, Argon2, Version};
+use clear_on_drop::clear::Clear;
+use x25519_dalek::StaticSecret;
+
+// lanes are split into 4 slices, synchronized between the lanes
+const SYNC_POINTS: u32 = 4;
+
+// shortest salt (as required by the specification)
+pub const MIN_SALT_LEN: usize = 8;
+
+// largest memory (KiB, 4 GiB): the memory is allocated at once
+pub const MAX_MEMORY: u32 = 1 << 22;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PassphraseError {
+    EmptyPassphrase,
+    SaltTooShort,
+    InvalidParameters,
+}
+
+impl fmt::Display for PassphraseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PassphraseError::EmptyPassphrase => write!(f, "Empty passphrase"),
+            PassphraseError::SaltTooShort => {
+                write!(f, "Salt is shorter than {} bytes", MIN_SALT_LEN)
+            }
+            PassphraseError::InvalidParameters => write!(
+                f,
+                "Invalid Argon2id parameters (expected m=<KiB>,t=<passes>,p=<lanes> with 8p <= m <= {})",
+                MAX_MEMORY
+            ),
+        }
+    }
+}
+
+/// The parameters of Argon2id
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Params {
+    pub memory: u32,      // KiB
+    pub iterations: u32,  // passes over the memory
+    pub parallelism: u32, // lanes
+}
+
+impl Params {
+    // Internal function
+    //
+    // Check the parameters against the bounds of the specification
+    fn valid(&self) -> bool {
+        self.iterations >= 1
+            && self.parallelism >= 1
+            && self.parallelism < (1 << 24)
+            && self.memory >= 2 * SYNC_POINTS * self.parallelism
+            && self.memory <= MAX_MEMORY
+    }
+
+    // Internal function
+    //
+    // The parameters of the argon2 crate (with an output of `len` bytes)
+    fn argon2(&self, len: usize) -> Result<argon2::Params, PassphraseError> {
+        argon2::Params::new(self.memory, self.iterations, self.parallelism, Some(len))
+            .map_err(|_| PassphraseError::InvalidParameters)
+    }
+}
+
+impl fmt::Display for Params {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "m={},t={},p={}",
+            self.memory, self.iterations, self.parallelism
+        )
+    }
+}
+
+impl FromStr for Params {
+    type Err = PassphraseError;
+
+    // every parameter must be given (in any order)
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (mut memory, mut iterations, mut parallelism) = (None, None, None);
+        for param in value.split(',') {
+            let mut kv = param.trim().splitn(2, '=');
+            let slot = match kv.next() {
+                Some("m") => &mut memory,
+                Some("t") => &mut iterations,
+                Some("p") => &mut parallelism,
+                _ => return Err(PassphraseError::InvalidParameters),
+            };
+            let n = kv.next().and_then(|n| n.parse::<u32>().ok());
+            if slot.is_some() || n.is_none() {
+                return Err(PassphraseError::InvalidParameters);
+            }
+            *slot = n;
+        }
+        match (memory, iterations, parallelism) {
+            (Some(memory), Some(iterations), Some(parallelism)) => {
+                let params = Params {
+                    memory,
+                    iterations,
+                    parallelism,
+                };
+                if params.valid() {
+                    Ok(params)
+                } else {
+                    Err(PassphraseError::InvalidParameters)
+                }
+            }
+            _ => Err(PassphraseError::InvalidParameters),
+        }
+    }
+}
+
+/// Derive a private key from a passphrase with Argon2id
+///
+/// # Arguments
+///
+/// - `passphrase`: The passphrase
+/// - `salt`: The salt (at least 8 bytes), e.g. the name of the network
+/// - `params`: The memory (KiB), iterations and parallelism of Argon2id
+///
+/// # Returns
+///
+/// The private key, the same for the same passphrase, salt and parameters.
+pub fn derive_private_key(
+    passphrase: &[u8],
+    salt: &[u8],
+    params: &Params,
+) -> Result<StaticSecret, PassphraseError> {
+    if passphrase.is_empty() {
+        return Err(PassphraseError::EmptyPassphrase);
+    }
+    if salt.len() < MIN_SALT_LEN {
+        return Err(PassphraseError::SaltTooShort);
+    }
+    if !params.valid() {
+        return Err(PassphraseError::InvalidParameters);
+    }
+
+    // the memory of Argon2id is cleared by the crate (zeroize feature)
+    let mut key = [0u8; 32];
+    Argon2::new(
+        Algorithm::Argon2id,
+        Version::V0x13,
+        params.argon2(key.len())?,
+    )
+    .hash_password_into(passphrase, salt, &mut key)
+    .map_err(|_| PassphraseError::InvalidParameters)?;
+    let sk = StaticSecret::from(key);
+    key.clear();
+    Ok(sk)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_argon2id() {
+        // test vector of RFC 9106 (section 5.3)
+        let params = Params {
+            memory: 32,
+            iterations: 3,
+            parallelism: 4,
+        };
+        let mut builder = argon2::ParamsBuilder::new();
+        builder
+            .m_cost(params.memory)
+            .t_cost(params.iterations)
+            .p_cost(params.parallelism)
+            .output_len(32)
+            .data(argon2::AssociatedData::new(&[0x04; 12]).unwrap());
+        let argon2 = Argon2::new_with_secret(
+            &[0x03; 8],
+            Algorithm::Argon2id,
+            Version::V0x13,
+            builder.build().unwrap(),
+        )
+        .unwrap();
+        let mut tag = [0u8; 32];
+        argon2
+            .hash_password_into(&[0x01; 32], &[0x02; 16], &mut tag)
+            .unwrap();
+        assert_eq!(
+            hex::encode(tag),
+            "0d640df58d78766c08c037a34a8b53c9d01ef0452d75b65eb52520e96b01e659"
+        );
+    }
+
+    #[test]
+    fn test_derive_private_key() {
+        let params: Params = "m=64,t=2,p=2".parse().unwrap();
+        assert_eq!(params.to_string(), "m=64,t=2,p=2");
+        let derive = |passphrase: &[u8], salt: &[u8], params: &Params| {
+            derive_private_key(passphrase, salt, params).map(|sk| sk.to_bytes())
+        };
+
+        // deterministic, and dependent on every input
+        let key = derive(b"correct horse", b"test-network", &params).unwrap();
+        assert_eq!(derive(b"correct horse", b"test-network", &params), Ok(key));
+        assert_ne!(derive(b"correct horsf", b"test-network", &params), Ok(key));
+        assert_ne!(derive(b"correct horse", b"test-networl", &params), Ok(key));
+        let more = Params {
+            iterations: 3,
+            ..params
+        };
+        assert_ne!(derive(b"correct horse", b"test-network", &more), Ok(key));
+
+        assert_eq!(
+            derive(b"", b"test-network", &params),
+            Err(PassphraseError::EmptyPassphrase)
+        );
+        assert_eq!(
+            derive(b"correct horse", b"short", &params),
+            Err(PassphraseError::SaltTooShort)
+        );
+    }
+
+    #[test]
+    fn test_params() {
+        let params: Params = "t=3, p=4, m=65536".parse().unwrap();
+        assert_eq!(
+            params,
+            Params {
+                memory: 65536,
+                iterations: 3,
+                parallelism: 4
+            }
+        );
+
+        // missing, repeated, unknown and out of bounds parameters
+        for invalid in [
+            "m=65536,t=3",
+            "m=65536,t=3,p=4,p=4",
+            "m=65536,t=3,p=4,x=1",
+            "m=65536,t=0,p=4",
+            "m=16,t=3,p=4",
+            "m=4294967295,t=3,p=4",
+            "m=65536,t=3,p=-1",
+        ]
+        .iter()
+        {
+            assert_eq!(
+                invalid.parse::<Params>(),
+                Err(PassphraseError::InvalidParameters),
+                "{}",
+                invalid
+            );
+        }
+    }
+}
@@ -0,0 +1,205 @@
+/* Split-key deployments: the static private key is held by a privileged helper process
+ * ("wireguard-rs signer <socket> <key file>"), which performs the Diffie-Hellman operations
+ * of the handshake for the network-facing device over a UNIX socket:
+ *
+ * - on connection the helper sends its static public key (32 bytes).
+ * - every request is a public key (32 bytes), answered with the shared secret (32 bytes).
+ *
+ * The socket is only accessible by its owner: the device connects before dropping privileges
+ * and keeps the connection, reconnecting (if the socket is reachable) when it is lost.
+ * Requests time out (SIGNER_TIMEOUT), such that a stuck helper does not block the handshakes.
+ */
+
+use super::super::wireguard::StaticDh;
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use x25519_dalek::{PublicKey, StaticSecret};
+
+// time after which a request to the helper is abandoned
+const SIGNER_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The static key held by a helper process
+pub struct Signer {
+    path: PathBuf,
+    pk: PublicKey,
+    stream: Mutex<Option<UnixStream>>,
+}
+
+// Internal function
+//
+// Connect to the helper and read its public key
+fn connect(path: &Path) -> io::Result<(UnixStream, PublicKey)> {
+    let mut stream = UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(SIGNER_TIMEOUT))?;
+    stream.set_write_timeout(Some(SIGNER_TIMEOUT))?;
+    let mut pk = [0u8; 32];
+    stream.read_exact(&mut pk)?;
+    Ok((stream, PublicKey::from(pk)))
+}
+
+// Internal function
+//
+// Perform a single request on the connection
+fn request(stream: &mut UnixStream, pk: &PublicKey) -> io::Result<[u8; 32]> {
+    stream.write_all(pk.as_bytes())?;
+    let mut ss = [0u8; 32];
+    stream.read_exact(&mut ss)?;
+    Ok(ss)
+}
+
+impl Signer {
+    /// Connect to the helper process
+    ///
+    /// # Arguments
+    ///
+    /// - `path`: The UNIX socket of the helper
+    pub fn connect<P: AsRef<Path>>(path: P) -> io::Result<Signer> {
+        let path = path.as_ref().to_owned();
+        let (stream, pk) = connect(&path)?;
+        Ok(Signer {
+            path,
+            pk,
+            stream: Mutex::new(Some(stream)),
+        })
+    }
+}
+
+impl StaticDh for Signer {
+    fn pubkey(&self) -> PublicKey {
+        self.pk
+    }
+
+    fn dh(&self, pk: &PublicKey) -> Option<[u8; 32]> {
+        let mut stream = self.stream.lock().unwrap();
+
+        // a lost connection is re-established once (to a helper with the same key)
+        for _ in 0..2 {
+            if stream.is_none() {
+                match connect(&self.path) {
+                    Ok((new, key)) if key.as_bytes() == self.pk.as_bytes() => *stream = Some(new),
+                    Ok(_) => log::warn!("signer {}: public key changed", self.path.display()),
+                    Err(e) => log::warn!("signer {}: {}", self.path.display(), e),
+                }
+            }
+            if let Some(conn) = stream.as_mut() {
+                match request(conn, pk) {
+                    Ok(ss) => return Some(ss),
+                    Err(e) => {
+                        log::debug!("signer {}: connection lost: {}", self.path.display(), e);
+                        *stream = None;
+                    }
+                }
+            }
+        }
+
+        // attempted again by the next handshake
+        None
+    }
+}
+
+/// Create the socket of the helper process (accessible only by its owner)
+pub fn bind<P: AsRef<Path>>(path: P) -> io::Result<UnixListener> {
+    let _ = fs::remove_file(path.as_ref());
+    let umask = unsafe { libc::umask(0o077) };
+    let listener = UnixListener::bind(path);
+    unsafe { libc::umask(umask) };
+    listener
+}
+
+/// Serve the Diffie-Hellman operations with the private key (a thread per connection)
+///
+/// # Arguments
+///
+/// - `listener`: The socket created by `bind`
+/// - `sk`: The static private key, which never leaves the helper process
+pub fn serve(listener: UnixListener, sk: StaticSecret) -> io::Result<()> {
+    let pk = PublicKey::from(&sk);
+    loop {
+        let (mut stream, _) = listener.accept()?;
+        let sk = sk.clone();
+        thread::spawn(move || {
+            if stream.write_all(pk.as_bytes()).is_err() {
+                return;
+            }
+            let mut peer = [0u8; 32];
+            while stream.read_exact(&mut peer).is_ok() {
+                let ss = sk.diffie_hellman(&PublicKey::from(peer));
+                if stream.write_all(ss.as_bytes()).is_err() {
+                    return;
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+
+    use rand::rngs::OsRng;
+
+    #[test]
+    fn test_signer() {
+        let path = env::temp_dir().join(format!("wg-rs-signer-{}.sock", std::process::id()));
+        let listener = bind(&path).unwrap();
+        let sk = StaticSecret::new(&mut OsRng);
+        let pk = PublicKey::from(&sk);
+        let helper = sk.clone();
+        thread::spawn(move || serve(listener, helper));
+
+        // the same shared secrets as with the key itself
+        let signer = Signer::connect(&path).unwrap();
+        assert_eq!(signer.pubkey().as_bytes(), pk.as_bytes());
+        let peer = PublicKey::from(&StaticSecret::new(&mut OsRng));
+        assert_eq!(signer.dh(&peer), Some(*sk.diffie_hellman(&peer).as_bytes()));
+
+        // a lost connection is re-established
+        signer
+            .stream
+            .lock()
+            .unwrap()
+            .as_ref()
+            .unwrap()
+            .shutdown(std::net::Shutdown::Both)
+            .unwrap();
+        assert_eq!(signer.dh(&peer), Some(*sk.diffie_hellman(&peer).as_bytes()));
+
+        // the helper is unreachable
+        fs::remove_file(&path).unwrap();
+        *signer.stream.lock().unwrap() = None;
+        assert_eq!(signer.dh(&peer), None);
+    }
+
+    #[test]
+    fn test_signer_timeout() {
+        let path = env::temp_dir().join(format!("wg-rs-stuck-{}.sock", std::process::id()));
+        let listener = bind(&path).unwrap();
+        let pk = PublicKey::from(&StaticSecret::new(&mut OsRng));
+
+        // a helper which never answers requests
+        thread::spawn(move || {
+            let mut streams = vec![];
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                stream.write_all(pk.as_bytes()).unwrap();
+                streams.push(stream);
+            }
+        });
+
+        let signer = Signer::connect(&path).unwrap();
+        let peer = PublicKey::from(&StaticSecret::new(&mut OsRng));
+        let start = std::time::Instant::now();
+        assert_eq!(signer.dh(&peer), None);
+        assert!(start.elapsed() < SIGNER_TIMEOUT * 4);
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,446 @@
+/* Structured configuration (TOML / JSON), for configurations managed by orchestration tools:
+ *
+ * private_key = "yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk="
+ * listen_port = 51820
+ *
+ * [[peers]]
+ * public_key = "xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg="
+ * endpoint = "vpn.example.com:51820"
+ * allowed_ips = ["10.0.0.2/32"]
+ *
+ * Keys are base64 encoded (as with wg(8)), allowed IPs are in CIDR notation.
+ * The configuration of a running device can be captured and applied again.
+ */
+
+// serialization / capture is used by orchestration tools (not by the daemon itself)
+#![allow(dead_code)]
+
+use super::config::PeerState;
+use super::diff::{ChangeSet, PeerChange};
+use super::file::{parse_prefix, ConfigFile, Interface, Peer};
+use super::key::{validate_public_key, Key};
+use super::{ConfigError, Configuration};
+
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+
+use serde::de::{self, Deserializer};
+use serde::ser::Serializer;
+use serde::{Deserialize, Serialize};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// An allowed IP range in CIDR notation (e.g. "10.0.0.0/8")
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Prefix(pub IpAddr, pub u32);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private_key: Option<Key>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub listen_port: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fwmark: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtu: Option<usize>,
+    #[serde(default)]
+    pub peers: Vec<PeerConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct PeerConfig {
+    pub public_key: Key,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preshared_key: Option<Key>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub endpoint: Option<String>, // address or "host:port"
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub endpoints: Vec<SocketAddr>, // candidate endpoints (failover), in order
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub tcp_fallback: bool,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub persistent_keepalive_interval: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub adaptive_keepalive: Option<(u64, u64)>, // shortest and longest interval (probing)
+    #[serde(default)]
+    pub allowed_ips: Vec<Prefix>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum StructuredError {
+    ReadFailed,
+    UnknownFormat, // extension is neither ".toml" nor ".json"
+    Toml(String),
+    Json(String),
+    LowOrderKey(Key), // public key of a peer
+}
+
+impl fmt::Display for StructuredError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StructuredError::ReadFailed => write!(f, "Failed to read configuration file"),
+            StructuredError::UnknownFormat => write!(f, "Unknown format (expected .toml or .json)"),
+            StructuredError::Toml(msg) => write!(f, "Invalid TOML: {}", msg),
+            StructuredError::Json(msg) => write!(f, "Invalid JSON: {}", msg),
+            StructuredError::LowOrderKey(key) => {
+                write!(f, "Public key {} is a low-order point", key)
+            }
+        }
+    }
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+fn is_zero(value: &u64) -> bool {
+    *value == 0
+}
+
+impl fmt::Display for Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.0, self.1)
+    }
+}
+
+impl Serialize for Prefix {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Prefix {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        parse_prefix(&value)
+            .map(|(ip, masklen)| Prefix(ip, masklen))
+            .ok_or_else(|| de::Error::custom(format!("invalid allowed IP: {}", value)))
+    }
+}
+
+impl DeviceConfig {
+    /// Read a configuration file (the format is determined by the extension)
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<DeviceConfig, StructuredError> {
+        let path = path.as_ref();
+        let extension = path.extension().and_then(|ext| ext.to_str());
+        let parse = match extension {
+            Some("toml") => Self::from_toml,
+            Some("json") => Self::from_json,
+            _ => return Err(StructuredError::UnknownFormat),
+        };
+        let content = fs::read_to_string(path).map_err(|_| StructuredError::ReadFailed)?;
+        parse(&content)
+    }
+
+    pub fn from_toml(content: &str) -> Result<DeviceConfig, StructuredError> {
+        let config: DeviceConfig =
+            toml::from_str(content).map_err(|e| StructuredError::Toml(e.to_string()))?;
+        config.validate()
+    }
+
+    pub fn to_toml(&self) -> Result<String, StructuredError> {
+        toml::to_string(self).map_err(|e| StructuredError::Toml(e.to_string()))
+    }
+
+    pub fn from_json(content: &str) -> Result<DeviceConfig, StructuredError> {
+        let config: DeviceConfig =
+            serde_json::from_str(content).map_err(|e| StructuredError::Json(e.to_string()))?;
+        config.validate()
+    }
+
+    // Internal function
+    //
+    // Reject the public keys of peers which are low-order points
+    fn validate(self) -> Result<DeviceConfig, StructuredError> {
+        for peer in self.peers.iter() {
+            validate_public_key(&PublicKey::from(peer.public_key.0))
+                .map_err(|_| StructuredError::LowOrderKey(peer.public_key))?;
+        }
+        Ok(self)
+    }
+
+    pub fn to_json(&self) -> Result<String, StructuredError> {
+        serde_json::to_string_pretty(self).map_err(|e| StructuredError::Json(e.to_string()))
+    }
+
+    /// Capture the configuration of a device
+    ///
+    /// The MTU is not known to the configuration interface and is left unset.
+    pub fn from_device<C: Configuration>(config: &C) -> DeviceConfig {
+        DeviceConfig {
+            private_key: config.get_private_key().map(|sk| Key(sk.to_bytes())),
+            listen_port: config.get_listen_port(),
+            fwmark: config.get_fwmark(),
+            mtu: None,
+            peers: config
+                .get_peers()
+                .into_iter()
+                .map(PeerConfig::from_state)
+                .collect(),
+        }
+    }
+
+    /// Compute the changes from this configuration to the `new` configuration
+    /// (e.g. to preview the effect of applying it)
+    pub fn diff(&self, new: &DeviceConfig) -> ChangeSet {
+        let mut changes = ChangeSet::between(&self.to_file(), &new.to_file());
+
+        // keys without an equivalent in configuration files
+        for peer in new.peers.iter() {
+            let pk = PublicKey::from(peer.public_key.0);
+            let old = self
+                .peers
+                .iter()
+                .find(|old| old.public_key == peer.public_key);
+            let endpoints = old.map_or(&[][..], |old| &old.endpoints[..]);
+            if !peer.endpoints.is_empty() && peer.endpoints[..] != *endpoints {
+                changes.push(&pk, PeerChange::Endpoints(peer.endpoints.clone()));
+            }
+            if peer.tcp_fallback != old.map(|old| old.tcp_fallback).unwrap_or(false) {
+                changes.push(&pk, PeerChange::TcpFallback(peer.tcp_fallback));
+            }
+            if peer.adaptive_keepalive != old.and_then(|old| old.adaptive_keepalive) {
+                changes.push(&pk, PeerChange::AdaptiveKeepalive(peer.adaptive_keepalive));
+            }
+        }
+        changes
+    }
+
+    // Internal function
+    //
+    // The configuration in the representation of configuration files
+    fn to_file(&self) -> ConfigFile {
+        ConfigFile {
+            interface: Interface {
+                private_key: self.private_key.map(|sk| StaticSecret::from(sk.0)),
+                listen_port: self.listen_port,
+                fwmark: self.fwmark,
+                ..Default::default()
+            },
+            peers: self
+                .peers
+                .iter()
+                .map(|peer| Peer {
+                    public_key: PublicKey::from(peer.public_key.0),
+                    preshared_key: peer.preshared_key.map(|psk| psk.0),
+                    allowed_ips: peer
+                        .allowed_ips
+                        .iter()
+                        .map(|Prefix(ip, masklen)| (*ip, *masklen))
+                        .collect(),
+                    endpoint: peer.endpoint.clone(),
+                    persistent_keepalive: Some(peer.persistent_keepalive_interval)
+                        .filter(|secs| *secs > 0),
+                })
+                .collect(),
+        }
+    }
+
+    /// Apply the configuration to the device (every peer is replaced)
+    ///
+    /// # Returns
+    ///
+    /// An error if the listen port / fwmark could not be applied
+    /// or the endpoint of a peer could not be resolved.
+    pub fn apply<C: Configuration>(&self, config: &C) -> Result<(), ConfigError> {
+        if let Some(sk) = self.private_key {
+            config.set_private_key(Some(StaticSecret::from(sk.0)));
+        }
+        if let Some(port) = self.listen_port {
+            config.set_listen_port(port)?;
+        }
+        config.set_fwmark(self.fwmark)?;
+
+        config.replace_peers();
+        for peer in self.peers.iter() {
+            peer.apply(config)?;
+        }
+        Ok(())
+    }
+}
+
+impl PeerConfig {
+    /// The configuration of a peer of a running device
+    pub fn from_state(peer: PeerState) -> PeerConfig {
+        PeerConfig {
+            public_key: Key(*peer.public_key.as_bytes()),
+            preshared_key: Some(Key(peer.preshared_key)).filter(|psk| psk.0 != [0u8; 32]),
+            endpoint: peer.endpoint.map(|addr| addr.to_string()),
+            endpoints: peer.endpoints,
+            tcp_fallback: peer.tcp_fallback,
+            persistent_keepalive_interval: peer.persistent_keepalive_interval,
+            adaptive_keepalive: peer.adaptive_keepalive,
+            allowed_ips: peer
+                .allowed_ips
+                .into_iter()
+                .map(|(ip, masklen)| Prefix(ip, masklen))
+                .collect(),
+        }
+    }
+
+    /// Add the peer to the device, or update it (replacing the allowed IPs)
+    ///
+    /// # Returns
+    ///
+    /// An error if the public key is a low-order point or the endpoint could not be resolved.
+    pub fn apply<C: Configuration>(&self, config: &C) -> Result<(), ConfigError> {
+        let pk = PublicKey::from(self.public_key.0);
+        validate_public_key(&pk).map_err(|_| ConfigError::LowOrderPublicKey)?;
+        config.add_peer(&pk);
+        config.set_preshared_key(&pk, self.preshared_key.map(|psk| psk.0));
+        config.replace_allowed_ips(&pk);
+        for Prefix(ip, masklen) in self.allowed_ips.iter() {
+            config.add_allowed_ip(&pk, *ip, *masklen);
+        }
+        config.set_persistent_keepalive_interval(&pk, self.persistent_keepalive_interval);
+        config.set_adaptive_keepalive(&pk, self.adaptive_keepalive);
+        config.set_tcp_fallback(&pk, self.tcp_fallback);
+        if !self.endpoints.is_empty() {
+            config.set_endpoints(&pk, self.endpoints.clone());
+        }
+        if let Some(endpoint) = self.endpoint.as_ref() {
+            match endpoint.parse::<SocketAddr>() {
+                Ok(addr) => config.set_endpoint(&pk, addr),
+                Err(_) => config.set_endpoint_name(&pk, endpoint)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::platform::dummy;
+    use super::super::super::wireguard::WireGuard;
+    use super::super::diff::InterfaceChange;
+    use super::super::file::encode_key;
+    use super::super::WireGuardConfig;
+    use super::*;
+
+    const CONFIG: &str = r#"
+        private_key = "yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk="
+        listen_port = 51820
+        mtu = 1380
+
+        [[peers]]
+        public_key = "xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg="
+        preshared_key = "FpCyhws9cxwWoV4xELtfJvjJN+zQVRPISllRWgeopVE="
+        endpoint = "192.95.5.67:1234"
+        persistent_keepalive_interval = 25
+        allowed_ips = ["10.192.122.3/32", "fd00::/64"]
+
+        [[peers]]
+        public_key = "TrMvSoP4jYQlY6RIzBgbssQqY3vxI2Pi+y71lOWWXX0="
+        allowed_ips = ["10.192.122.4"]
+    "#;
+
+    #[test]
+    fn test_round_trip() {
+        let config = DeviceConfig::from_toml(CONFIG).unwrap();
+        assert_eq!(config.listen_port, Some(51820));
+        assert_eq!(config.peers.len(), 2);
+        assert_eq!(
+            config.peers[1].allowed_ips,
+            vec![Prefix("10.192.122.4".parse().unwrap(), 32)]
+        );
+        assert_eq!(
+            encode_key(&config.peers[0].public_key.0),
+            "xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg="
+        );
+
+        // TOML and JSON round trips
+        let toml = config.to_toml().unwrap();
+        assert_eq!(DeviceConfig::from_toml(&toml).unwrap(), config);
+        let json = config.to_json().unwrap();
+        assert_eq!(DeviceConfig::from_json(&json).unwrap(), config);
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(DeviceConfig::from_toml("listen_port = 70000").is_err());
+        assert!(DeviceConfig::from_toml("port = 1").is_err());
+        assert!(DeviceConfig::from_json(r#"{"private_key": "invalid"}"#).is_err());
+        assert!(DeviceConfig::from_json(
+            r#"{"peers": [{"public_key": "xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=",
+                           "allowed_ips": ["10.0.0.0/33"]}]}"#
+        )
+        .is_err());
+        assert_eq!(
+            DeviceConfig::from_toml(
+                "[[peers]]\npublic_key = \"AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=\""
+            ),
+            Err(StructuredError::LowOrderKey(Key([0u8; 32])))
+        );
+        assert_eq!(
+            DeviceConfig::load("wg0.conf"),
+            Err(StructuredError::UnknownFormat)
+        );
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = DeviceConfig::from_toml(CONFIG).unwrap();
+        let mut new = old.clone();
+        assert!(old.diff(&new).is_empty());
+
+        new.listen_port = Some(51821);
+        new.peers.remove(0);
+        new.peers[0].tcp_fallback = true;
+        new.peers[0].endpoints = vec!["192.0.2.1:51820".parse().unwrap()];
+        new.peers[0]
+            .allowed_ips
+            .push(Prefix("10.1.0.0".parse().unwrap(), 16));
+        let changes = old.diff(&new);
+        assert_eq!(changes.interface, vec![InterfaceChange::ListenPort(51821)]);
+        assert_eq!(
+            encode_key(changes.removed[0].as_bytes()),
+            "xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg="
+        );
+        assert!(changes.added.is_empty());
+        assert_eq!(changes.modified.len(), 1);
+        assert_eq!(
+            changes.modified[0].1,
+            vec![
+                PeerChange::AllowedIps {
+                    added: vec![("10.1.0.0".parse().unwrap(), 16)],
+                    removed: vec![],
+                },
+                PeerChange::Endpoints(new.peers[0].endpoints.clone()),
+                PeerChange::TcpFallback(true),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_apply_and_capture() {
+        let (_fake, _reader, tun_writer, _mtu) = dummy::TunTest::create(false);
+        let cfg: WireGuardConfig<dummy::TunTest, dummy::PairBind> =
+            WireGuardConfig::new(WireGuard::new(tun_writer));
+
+        let config = DeviceConfig::from_toml(CONFIG).unwrap();
+        config.apply(&cfg).unwrap();
+
+        // the captured configuration matches (up to the order of the peers and the endpoint)
+        let mut captured = DeviceConfig::from_device(&cfg);
+        assert_eq!(captured.private_key, config.private_key);
+        assert_eq!(captured.peers.len(), 2);
+        captured.peers.sort_by_key(|peer| {
+            config
+                .peers
+                .iter()
+                .position(|p| p.public_key == peer.public_key)
+        });
+        for (captured, peer) in captured.peers.iter_mut().zip(config.peers.iter()) {
+            assert!(captured.endpoint.is_some() == peer.endpoint.is_some());
+            captured.endpoint = peer.endpoint.clone();
+            captured
+                .allowed_ips
+                .sort_by_key(|prefix| prefix.to_string());
+            assert_eq!(captured, peer);
+        }
+    }
+}
@@ -1,6 +1,6 @@
 use std::io;
 
-use super::Configuration;
+use super::{Configuration, DropPolicy, EvictionPolicy, HandshakeState, SourcePolicy};
 
 pub fn serialize<C: Configuration, W: io::Write>(writer: &mut W, config: &C) -> io::Result<()> {
     let mut write = |key: &'static str, value: String| {
@@ -14,23 +14,71 @@ pub fn serialize<C: Configuration, W: io::Write>(writer: &mut W, config: &C) ->
     };
 
     // serialize interface
-    config
-        .get_private_key()
-        .map(|sk| write("private_key", hex::encode(sk.to_bytes())));
+    if let Some(sk) = config.get_private_key() {
+        write("private_key", hex::encode(sk.to_bytes()))?;
+    }
+
+    if let Some(port) = config.get_listen_port() {
+        write("listen_port", port.to_string())?;
+    }
+
+    if let Some(fwmark) = config.get_fwmark() {
+        write("fwmark", fwmark.to_string())?;
+    }
+
+    if let Some(dscp) = config.get_dscp() {
+        write("dscp", dscp.to_string())?;
+    }
+
+    write("replay_window", config.get_replay_window().to_string())?;
+
+    write(
+        "source_policy",
+        match config.get_source_policy() {
+            SourcePolicy::Strict => "strict",
+            SourcePolicy::Permissive => "permissive",
+        }
+        .to_string(),
+    )?;
+    write(
+        "source_violations",
+        config.get_source_violations().to_string(),
+    )?;
 
-    config
-        .get_listen_port()
-        .map(|port| write("listen_port", port.to_string()));
+    let stats = config.get_message_stats();
+    write("unknown_messages", stats.unknown.to_string())?;
+    write("short_messages", stats.short.to_string())?;
+    write("oversized_messages", stats.oversized.to_string())?;
 
-    config
-        .get_fwmark()
-        .map(|fwmark| write("fwmark", fwmark.to_string()));
+    let handshake = config.get_handshake_config();
+    write(
+        "initiation_flood_window",
+        handshake.initiation_flood_window.as_millis().to_string(),
+    )?;
+    write(
+        "timestamp_tolerance",
+        handshake.timestamp_tolerance.as_millis().to_string(),
+    )?;
+    write("reserved_bytes", handshake.reserved.name())?;
+    write("max_peers", handshake.max_peers.to_string())?;
+    write(
+        "eviction",
+        match handshake.eviction {
+            EvictionPolicy::Reject => "reject",
+            EvictionPolicy::OldestHandshake => "oldest_handshake",
+        }
+        .to_string(),
+    )?;
 
     // serialize all peers
     let mut peers = config.get_peers();
     while let Some(p) = peers.pop() {
         write("public_key", hex::encode(p.public_key.as_bytes()))?;
         write("preshared_key", hex::encode(p.preshared_key))?;
+        write(
+            "protocol_version",
+            config.get_protocol_version().to_string(),
+        )?;
         write("rx_bytes", p.rx_bytes.to_string())?;
         write("tx_bytes", p.tx_bytes.to_string())?;
         write(
@@ -38,6 +86,31 @@ pub fn serialize<C: Configuration, W: io::Write>(writer: &mut W, config: &C) ->
             p.persistent_keepalive_interval.to_string(),
         )?;
 
+        if let Some((min, max)) = p.adaptive_keepalive {
+            write("adaptive_keepalive", format!("{},{}", min, max))?;
+        }
+
+        if let Some(secs) = p.expiry {
+            write("expiry", secs.to_string())?;
+        }
+
+        if p.handshake == HandshakeState::InitiationSent {
+            write("handshake_initiated", "true".to_string())?;
+        }
+
+        let (depth, policy) = p.staged_packets;
+        write(
+            "staged_packets",
+            format!(
+                "{},{}",
+                depth,
+                match policy {
+                    DropPolicy::Head => "head",
+                    DropPolicy::Tail => "tail",
+                }
+            ),
+        )?;
+
         if let Some((secs, nsecs)) = p.last_handshake_time {
             write("last_handshake_time_sec", secs.to_string())?;
             write("last_handshake_time_nsec", nsecs.to_string())?;
@@ -47,6 +120,10 @@ pub fn serialize<C: Configuration, W: io::Write>(writer: &mut W, config: &C) ->
             write("endpoint", endpoint.to_string())?;
         }
 
+        for candidate in p.endpoints {
+            write("endpoint_candidate", candidate.to_string())?;
+        }
+
         for (ip, cidr) in p.allowed_ips {
             write("allowed_ip", ip.to_string() + "/" + &cidr.to_string())?;
         }
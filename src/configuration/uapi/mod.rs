@@ -3,50 +3,75 @@ mod set;
 
 use std::io::{Read, Write};
 
-use super::{ConfigError, Configuration};
+use super::{
+    ConfigError, Configuration, DropPolicy, EvictionPolicy, HandshakeState, ReservedBytes,
+    SourcePolicy,
+};
 
 use get::serialize;
 use set::LineParser;
 
 const MAX_LINE_LENGTH: usize = 256;
 
+/* Serve requests of the cross-platform userspace API on a connection:
+ *
+ * - "get=1" followed by an empty line: the configuration is returned as key=value lines.
+ * - "set=1" followed by key=value lines and an empty line: the configuration is updated.
+ *
+ * Every response is terminated by "errno=N" and an empty line.
+ * Requests are served until the connection is closed by the client,
+ * or the request stream cannot be followed (e.g. an unknown operation).
+ */
 pub fn handle<S: Read + Write, C: Configuration>(stream: &mut S, config: &C) {
-    fn operation<S: Read + Write, C: Configuration>(
-        stream: &mut S,
-        config: &C,
-    ) -> Result<(), ConfigError> {
-        // read string up to maximum length (why is this not in std?)
-        fn readline<R: Read>(reader: &mut R) -> Result<String, ConfigError> {
-            let mut m: [u8; 1] = [0u8];
-            let mut l: String = String::with_capacity(MAX_LINE_LENGTH);
-            while reader.read_exact(&mut m).is_ok() {
-                let c = m[0] as char;
-                if c == '\n' {
-                    log::trace!("UAPI, line: {}", l);
-                    return Ok(l);
-                };
-                l.push(c);
-                if l.len() > MAX_LINE_LENGTH {
-                    return Err(ConfigError::LineTooLong);
-                }
+    // read string up to maximum length (why is this not in std?)
+    fn readline<R: Read>(reader: &mut R) -> Result<String, ConfigError> {
+        let mut m: [u8; 1] = [0u8];
+        let mut l: String = String::with_capacity(MAX_LINE_LENGTH);
+        while reader.read_exact(&mut m).is_ok() {
+            let c = m[0] as char;
+            if c == '\n' {
+                log::trace!("UAPI, line: {}", l);
+                return Ok(l);
+            };
+            l.push(c);
+            if l.len() > MAX_LINE_LENGTH {
+                return Err(ConfigError::LineTooLong);
             }
-            Err(ConfigError::IOError)
         }
+        Err(ConfigError::IOError)
+    }
 
-        // split into (key, value) pair
-        fn keypair(ln: &str) -> Result<(&str, &str), ConfigError> {
-            let mut split = ln.splitn(2, '=');
-            match (split.next(), split.next()) {
-                (Some(key), Some(value)) => Ok((key, value)),
-                _ => Err(ConfigError::LineTooLong),
-            }
-        };
+    // split into (key, value) pair
+    fn keypair(ln: &str) -> Result<(&str, &str), ConfigError> {
+        let mut split = ln.splitn(2, '=');
+        match (split.next(), split.next()) {
+            (Some(key), Some(value)) => Ok((key, value)),
+            _ => Err(ConfigError::LineTooLong),
+        }
+    }
+
+    // skip the remaining lines of the request
+    fn skip<R: Read>(reader: &mut R) -> Result<(), ConfigError> {
+        while !readline(reader)?.is_empty() {}
+        Ok(())
+    }
 
-        // read operation line
-        match readline(stream)?.as_str() {
+    // Returns the result of the operation,
+    // or an error if the end of the request could not be found.
+    fn operation<S: Read + Write, C: Configuration>(
+        stream: &mut S,
+        config: &C,
+        op: &str,
+    ) -> Result<Result<(), ConfigError>, ConfigError> {
+        match op {
             "get=1" => {
                 log::debug!("UAPI, Get operation");
-                serialize(stream, config).map_err(|_| ConfigError::IOError)
+                if !readline(stream)?.is_empty() {
+                    skip(stream)?;
+                    return Ok(Err(ConfigError::InvalidOperation));
+                }
+                serialize(stream, config).map_err(|_| ConfigError::IOError)?;
+                Ok(Ok(()))
             }
             "set=1" => {
                 log::debug!("UAPI, Set operation");
@@ -56,27 +81,511 @@ pub fn handle<S: Read + Write, C: Configuration>(stream: &mut S, config: &C) {
                     if ln == "" {
                         break;
                     }
-                    let (k, v) = keypair(ln.as_str())?;
-                    parser.parse_line(k, v)?;
+                    if let Err(e) = keypair(ln.as_str()).and_then(|(k, v)| parser.parse_line(k, v))
+                    {
+                        skip(stream)?;
+                        return Ok(Err(e));
+                    }
                 }
-                parser.parse_line("", "")
+                Ok(parser.parse_line("", ""))
             }
             _ => Err(ConfigError::InvalidOperation),
         }
     }
 
-    // process operation
-    let res = operation(stream, config);
-    log::debug!("UAPI, Result of operation: {:?}", res);
+    loop {
+        // read operation line (the client closes the connection when done)
+        let op = match readline(stream) {
+            Ok(op) => op,
+            Err(_) => return,
+        };
+
+        // process operation
+        let (res, close) = match operation(stream, config, op.as_str()) {
+            Ok(res) => (res, false),
+            Err(e) => (Err(e), true),
+        };
+        log::debug!("UAPI, Result of operation: {:?}", res);
 
-    // return errno
-    let _ = stream.write("errno=".as_ref());
-    let _ = stream.write(
-        match res {
-            Err(e) => e.errno().to_string(),
-            Ok(()) => "0".to_owned(),
+        // return errno
+        let _ = stream.write("errno=".as_ref());
+        let _ = stream.write(
+            match res {
+                Err(e) => e.errno().to_string(),
+                Ok(()) => "0".to_owned(),
+            }
+            .as_ref(),
+        );
+        let _ = stream.write("\n\n".as_ref());
+        if close {
+            return;
         }
-        .as_ref(),
-    );
-    let _ = stream.write("\n\n".as_ref());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::platform::dummy;
+    use super::super::super::wireguard::WireGuard;
+    use super::super::WireGuardConfig;
+    use super::*;
+
+    use std::io::{self, Cursor};
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use rand::rngs::OsRng;
+    use x25519_dalek::{PublicKey, StaticSecret};
+
+    const PRIVATE_KEY: &str = "e84b5a6d2717c1003a13b431570353dbaca9146cf150c5f8575680feba52027a";
+    const PUBLIC_KEY: &str = "b85996fecc9c7f1fc6d2572a76eda11d59bcd20be8e543b15ce4bd85a8e75a33";
+
+    struct Connection {
+        request: Cursor<Vec<u8>>,
+        response: Vec<u8>,
+    }
+
+    impl Read for Connection {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.request.read(buf)
+        }
+    }
+
+    impl Write for Connection {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.response.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn request<C: Configuration>(config: &C, request: &str) -> String {
+        let mut conn = Connection {
+            request: Cursor::new(request.as_bytes().to_owned()),
+            response: vec![],
+        };
+        handle(&mut conn, config);
+        String::from_utf8(conn.response).unwrap()
+    }
+
+    #[test]
+    fn test_handle() {
+        let (_fake, _reader, tun_writer, _mtu) = dummy::TunTest::create(false);
+        let cfg: WireGuardConfig<dummy::TunTest, dummy::PairBind> =
+            WireGuardConfig::new(WireGuard::new(tun_writer));
+
+        // several requests on one connection
+        let response = request(
+            &cfg,
+            &format!(
+                "set=1\nprivate_key={}\npublic_key={}\nallowed_ip=10.0.0.0/24\n\nget=1\n\n",
+                PRIVATE_KEY, PUBLIC_KEY
+            ),
+        );
+        assert!(response.starts_with("errno=0\n\n"));
+        assert!(response.contains(&format!("\nprivate_key={}\n", PRIVATE_KEY)));
+        assert!(response.contains(&format!("\npublic_key={}\n", PUBLIC_KEY)));
+        assert!(response.contains("\nprotocol_version=1\n"));
+        assert!(response.contains("\nallowed_ip=10.0.0.0/24\n"));
+        assert!(response.ends_with("\nerrno=0\n\n"));
+
+        // the remainder of a failed request is skipped
+        let response = request(&cfg, "set=1\nno_such_key=1\nfwmark=1\n\nget=1\n\n");
+        let errno = ConfigError::InvalidKey.errno();
+        assert!(response.starts_with(&format!("errno={}\n\n", errno)));
+        assert!(!response.contains("fwmark="));
+        assert!(response.ends_with("\nerrno=0\n\n"));
+
+        // low-order public keys are rejected
+        let response = request(&cfg, &format!("set=1\npublic_key={}\n\n", "00".repeat(32)));
+        let errno = ConfigError::LowOrderPublicKey.errno();
+        assert_eq!(response, format!("errno={}\n\n", errno));
+        assert_eq!(cfg.get_peers().len(), 1);
+
+        // get requests are terminated by an empty line
+        let response = request(&cfg, "get=1\nfwmark=1\n\nget=1\n\n");
+        let errno = ConfigError::InvalidOperation.errno();
+        assert!(response.starts_with(&format!("errno={}\n\n", errno)));
+        assert!(response.ends_with("\nerrno=0\n\n"));
+
+        // the connection is closed after an unknown operation
+        let response = request(&cfg, "put=1\n\nget=1\n\n");
+        assert_eq!(response, format!("errno={}\n\n", errno));
+    }
+
+    #[test]
+    fn test_endpoints() {
+        let (_fake, _reader, tun_writer, _mtu) = dummy::TunTest::create(false);
+        let cfg: WireGuardConfig<dummy::TunTest, dummy::PairBind> =
+            WireGuardConfig::new(WireGuard::new(tun_writer));
+        let set = |lines: &str| {
+            request(
+                &cfg,
+                &format!("set=1\npublic_key={}\n{}\n", PUBLIC_KEY, lines),
+            )
+        };
+
+        // the last endpoint wins
+        let response = set("endpoint=10.0.0.1:51820\nendpoint=10.0.0.2:51820\n");
+        assert_eq!(response, "errno=0\n\n");
+        let peer = &cfg.get_peers()[0];
+        assert!(peer.endpoint.is_some());
+        assert!(peer.endpoints.is_empty());
+
+        // candidates follow the endpoint (in order)
+        let response = set(
+            "endpoint_candidate=10.0.0.3:51820\nendpoint=10.0.0.1:51820\n\
+             endpoint_candidate=10.0.0.1:51820\nendpoint_candidate=[::1]:51820\n",
+        );
+        assert_eq!(response, "errno=0\n\n");
+        let peer = &cfg.get_peers()[0];
+        let expected: Vec<SocketAddr> = vec![
+            "10.0.0.1:51820".parse().unwrap(),
+            "10.0.0.3:51820".parse().unwrap(),
+            "[::1]:51820".parse().unwrap(),
+        ];
+        assert_eq!(peer.endpoints, expected);
+        let response = request(&cfg, "get=1\n\n");
+        assert!(response.contains("\nendpoint_candidate=10.0.0.3:51820\n"));
+
+        // candidates are addresses (not hostnames)
+        let errno = ConfigError::InvalidSocketAddr.errno();
+        let response = set("endpoint_candidate=example.com:51820\n");
+        assert_eq!(response, format!("errno={}\n\n", errno));
+
+        // a single endpoint replaces the candidates
+        let response = set("endpoint=10.0.0.4:51820\n");
+        assert_eq!(response, "errno=0\n\n");
+        assert!(cfg.get_peers()[0].endpoints.is_empty());
+    }
+
+    #[test]
+    fn test_peer_expiry() {
+        let (_fake, _reader, tun_writer, _mtu) = dummy::TunTest::create(false);
+        let cfg: WireGuardConfig<dummy::TunTest, dummy::PairBind> =
+            WireGuardConfig::new(WireGuard::new(tun_writer));
+        let set = |value: &str| {
+            request(
+                &cfg,
+                &format!("set=1\npublic_key={}\nexpiry={}\n\n", PUBLIC_KEY, value),
+            )
+        };
+
+        assert_eq!(set("3600"), "errno=0\n\n");
+        let expiry = cfg.get_peers()[0].expiry.unwrap();
+        assert!(expiry > 3590 && expiry <= 3600);
+        assert!(request(&cfg, "get=1\n\n").contains("\nexpiry="));
+
+        // a deadline too far in the future never expires
+        assert_eq!(set(&u64::MAX.to_string()), "errno=0\n\n");
+        assert_eq!(cfg.get_peers()[0].expiry, None);
+
+        let errno = ConfigError::UnsupportedValue.errno();
+        assert_eq!(set("-1"), format!("errno={}\n\n", errno));
+
+        // remove the deadline
+        assert_eq!(set("3600"), "errno=0\n\n");
+        assert_eq!(set("0"), "errno=0\n\n");
+        assert_eq!(cfg.get_peers()[0].expiry, None);
+        assert!(!request(&cfg, "get=1\n\n").contains("\nexpiry="));
+    }
+
+    #[test]
+    fn test_replay_window() {
+        let (_fake, _reader, tun_writer, _mtu) = dummy::TunTest::create(false);
+        let cfg: WireGuardConfig<dummy::TunTest, dummy::PairBind> =
+            WireGuardConfig::new(WireGuard::new(tun_writer));
+        assert!(request(&cfg, "get=1\n\n").starts_with("replay_window=1984\n"));
+
+        // the window is rounded up to fill the bitmap
+        assert_eq!(
+            request(&cfg, "set=1\nreplay_window=4000\n\n"),
+            "errno=0\n\n"
+        );
+        assert_eq!(cfg.get_replay_window(), 4032);
+        assert!(request(&cfg, "get=1\n\n").starts_with("replay_window=4032\n"));
+
+        let errno = ConfigError::UnsupportedValue.errno();
+        let response = request(&cfg, "set=1\nreplay_window=-1\n\n");
+        assert_eq!(response, format!("errno={}\n\n", errno));
+    }
+
+    #[test]
+    fn test_staged_packets() {
+        let (_fake, _reader, tun_writer, _mtu) = dummy::TunTest::create(false);
+        let cfg: WireGuardConfig<dummy::TunTest, dummy::PairBind> =
+            WireGuardConfig::new(WireGuard::new(tun_writer));
+        let set = |value: &str| {
+            request(
+                &cfg,
+                &format!(
+                    "set=1\npublic_key={}\nstaged_packets={}\n\n",
+                    PUBLIC_KEY, value
+                ),
+            )
+        };
+
+        assert_eq!(set("16,tail"), "errno=0\n\n");
+        assert_eq!(cfg.get_peers()[0].staged_packets, (16, DropPolicy::Tail));
+        let response = request(&cfg, "get=1\n\n");
+        assert!(response.contains("\nstaged_packets=16,tail\n"));
+
+        // invalid depths and policies are rejected
+        let errno = ConfigError::UnsupportedValue.errno();
+        for value in &["0,head", "16", "16,drop", "x,tail"] {
+            assert_eq!(set(value), format!("errno={}\n\n", errno));
+        }
+        assert_eq!(set("32,head"), "errno=0\n\n");
+        assert_eq!(cfg.get_peers()[0].staged_packets, (32, DropPolicy::Head));
+    }
+
+    #[test]
+    fn test_new_public_key() {
+        let (_fake, _reader, tun_writer, _mtu) = dummy::TunTest::create(false);
+        let cfg: WireGuardConfig<dummy::TunTest, dummy::PairBind> =
+            WireGuardConfig::new(WireGuard::new(tun_writer));
+        let new = hex::encode(PublicKey::from(&StaticSecret::new(&mut OsRng)).as_bytes());
+        let psk = "01".repeat(32);
+        let response = request(
+            &cfg,
+            &format!(
+                "set=1\npublic_key={}\npreshared_key={}\nendpoint=10.0.0.1:51820\n\
+                 allowed_ip=10.0.0.0/24\n\n",
+                PUBLIC_KEY, psk
+            ),
+        );
+        assert_eq!(response, "errno=0\n\n");
+
+        // the settings of the peer are retained (and updated) under the new key
+        let response = request(
+            &cfg,
+            &format!(
+                "set=1\npublic_key={}\nupdate_only=true\nnew_public_key={}\n\
+                 persistent_keepalive_interval=25\n\n",
+                PUBLIC_KEY, new
+            ),
+        );
+        assert_eq!(response, "errno=0\n\n");
+        let response = request(&cfg, "get=1\n\n");
+        assert!(!response.contains(PUBLIC_KEY));
+        assert!(response.contains(&format!("\npublic_key={}\n", new)));
+        assert!(response.contains(&format!("\npreshared_key={}\n", psk)));
+        assert!(response.contains("\nendpoint="));
+        assert!(response.contains("\nallowed_ip=10.0.0.0/24\n"));
+        assert!(response.contains("\npersistent_keepalive_interval=25\n"));
+
+        // the key of another peer cannot be taken
+        let response = request(
+            &cfg,
+            &format!(
+                "set=1\npublic_key={}\nnew_public_key={}\n\n",
+                PUBLIC_KEY, new
+            ),
+        );
+        let errno = ConfigError::UnsupportedValue.errno();
+        assert_eq!(response, format!("errno={}\n\n", errno));
+        assert_eq!(cfg.get_peers().len(), 2);
+    }
+
+    #[test]
+    fn test_clear_session() {
+        let (_fake, _reader, tun_writer, _mtu) = dummy::TunTest::create(false);
+        let cfg: WireGuardConfig<dummy::TunTest, dummy::PairBind> =
+            WireGuardConfig::new(WireGuard::new(tun_writer));
+        let set = |lines: &str| request(&cfg, &format!("set=1\n{}\n", lines));
+
+        let response = set(&format!("public_key={}\nclear_session=true\n", PUBLIC_KEY));
+        assert_eq!(response, "errno=0\n\n");
+        let response = set(&format!("public_key={}\nclear_session=false\n", PUBLIC_KEY));
+        let errno = ConfigError::UnsupportedValue.errno();
+        assert_eq!(response, format!("errno={}\n\n", errno));
+        assert_eq!(cfg.get_peers().len(), 1);
+    }
+
+    #[test]
+    fn test_hybrid_secret() {
+        let (_fake, _reader, tun_writer, _mtu) = dummy::TunTest::create(false);
+        let cfg: WireGuardConfig<dummy::TunTest, dummy::PairBind> =
+            WireGuardConfig::new(WireGuard::new(tun_writer));
+        let set = |secret: &str| {
+            request(
+                &cfg,
+                &format!(
+                    "set=1\npublic_key={}\nhybrid_secret={}\n\n",
+                    PUBLIC_KEY, secret
+                ),
+            )
+        };
+
+        assert_eq!(set(&"42".repeat(32)), "errno=0\n\n");
+        assert!(!request(&cfg, "get=1\n\n").contains("hybrid_secret"));
+        assert_eq!(set(&"00".repeat(32)), "errno=0\n\n");
+
+        let errno = ConfigError::InvalidHexValue.errno();
+        assert_eq!(set("42"), format!("errno={}\n\n", errno));
+    }
+
+    #[test]
+    fn test_handshake_config() {
+        let (_fake, _reader, tun_writer, _mtu) = dummy::TunTest::create(false);
+        let cfg: WireGuardConfig<dummy::TunTest, dummy::PairBind> =
+            WireGuardConfig::new(WireGuard::new(tun_writer));
+        let response = request(&cfg, "get=1\n\n");
+        assert!(response.contains("\ninitiation_flood_window=20\ntimestamp_tolerance=0\n"));
+
+        let response = request(
+            &cfg,
+            "set=1\ninitiation_flood_window=5\ntimestamp_tolerance=1500\n\n",
+        );
+        assert_eq!(response, "errno=0\n\n");
+        let config = cfg.get_handshake_config();
+        assert_eq!(config.initiation_flood_window, Duration::from_millis(5));
+        assert_eq!(config.timestamp_tolerance, Duration::from_millis(1500));
+        let response = request(&cfg, "get=1\n\n");
+        assert!(response.contains("\ninitiation_flood_window=5\ntimestamp_tolerance=1500\n"));
+
+        let errno = ConfigError::UnsupportedValue.errno();
+        let response = request(&cfg, "set=1\ntimestamp_tolerance=1s\n\n");
+        assert_eq!(response, format!("errno={}\n\n", errno));
+    }
+
+    #[test]
+    fn test_reserved_bytes() {
+        let (_fake, _reader, tun_writer, _mtu) = dummy::TunTest::create(false);
+        let cfg: WireGuardConfig<dummy::TunTest, dummy::PairBind> =
+            WireGuardConfig::new(WireGuard::new(tun_writer));
+        assert!(request(&cfg, "get=1\n\n").contains("\nreserved_bytes=strict\n"));
+
+        for (value, reserved) in &[
+            ("ignore", ReservedBytes::Ignore),
+            ("a1b2c3", ReservedBytes::Custom([0xa1, 0xb2, 0xc3])),
+            ("strict", ReservedBytes::Strict),
+        ] {
+            let response = request(&cfg, &format!("set=1\nreserved_bytes={}\n\n", value));
+            assert_eq!(response, "errno=0\n\n");
+            assert_eq!(cfg.get_handshake_config().reserved, *reserved);
+            let response = request(&cfg, "get=1\n\n");
+            assert!(response.contains(&format!("\nreserved_bytes={}\n", value)));
+        }
+
+        let errno = ConfigError::UnsupportedValue.errno();
+        let response = request(&cfg, "set=1\nreserved_bytes=a1b2\n\n");
+        assert_eq!(response, format!("errno={}\n\n", errno));
+    }
+
+    #[test]
+    fn test_dscp() {
+        let (_fake, _reader, tun_writer, _mtu) = dummy::TunTest::create(false);
+        let cfg: WireGuardConfig<dummy::TunTest, dummy::PairBind> =
+            WireGuardConfig::new(WireGuard::new(tun_writer));
+        assert!(!request(&cfg, "get=1\n\n").contains("dscp="));
+
+        assert_eq!(request(&cfg, "set=1\ndscp=46\n\n"), "errno=0\n\n");
+        assert_eq!(cfg.get_dscp(), Some(46));
+        assert!(request(&cfg, "get=1\n\n").starts_with("dscp=46\n"));
+
+        assert_eq!(request(&cfg, "set=1\ndscp=inherit\n\n"), "errno=0\n\n");
+        assert_eq!(cfg.get_dscp(), None);
+
+        let errno = ConfigError::UnsupportedValue.errno();
+        let response = request(&cfg, "set=1\ndscp=64\n\n");
+        assert_eq!(response, format!("errno={}\n\n", errno));
+    }
+
+    #[test]
+    fn test_max_peers() {
+        let (_fake, _reader, tun_writer, _mtu) = dummy::TunTest::create(false);
+        let cfg: WireGuardConfig<dummy::TunTest, dummy::PairBind> =
+            WireGuardConfig::new(WireGuard::new(tun_writer));
+        let response = request(&cfg, "get=1\n\n");
+        assert!(response.contains("\nmax_peers=1048576\neviction=reject\n"));
+        let peer = || hex::encode(PublicKey::from(&StaticSecret::new(&mut OsRng)).as_bytes());
+
+        // peers beyond the limit are rejected
+        let response = request(&cfg, "set=1\nmax_peers=1\n\n");
+        assert_eq!(response, "errno=0\n\n");
+        let first = peer();
+        let response = request(&cfg, &format!("set=1\npublic_key={}\n\n", first));
+        assert_eq!(response, "errno=0\n\n");
+        request(&cfg, &format!("set=1\npublic_key={}\n\n", peer()));
+        assert_eq!(cfg.get_peers().len(), 1);
+        assert!(request(&cfg, "get=1\n\n").contains(&first));
+
+        // or evict the peer with the oldest handshake
+        let response = request(&cfg, "set=1\neviction=oldest_handshake\n\n");
+        assert_eq!(response, "errno=0\n\n");
+        assert_eq!(
+            cfg.get_handshake_config().eviction,
+            EvictionPolicy::OldestHandshake
+        );
+        let second = peer();
+        request(&cfg, &format!("set=1\npublic_key={}\n\n", second));
+        let response = request(&cfg, "get=1\n\n");
+        assert!(response.contains("\nmax_peers=1\neviction=oldest_handshake\n"));
+        assert!(response.contains(&second));
+        assert!(!response.contains(&first));
+
+        let errno = ConfigError::UnsupportedValue.errno();
+        for line in &["max_peers=0", "eviction=lru"] {
+            let response = request(&cfg, &format!("set=1\n{}\n\n", line));
+            assert_eq!(response, format!("errno={}\n\n", errno));
+        }
+    }
+
+    #[test]
+    fn test_source_policy() {
+        let (_fake, _reader, tun_writer, _mtu) = dummy::TunTest::create(false);
+        let cfg: WireGuardConfig<dummy::TunTest, dummy::PairBind> =
+            WireGuardConfig::new(WireGuard::new(tun_writer));
+        let response = request(&cfg, "get=1\n\n");
+        assert!(response.contains("\nsource_policy=strict\nsource_violations=0\n"));
+        assert!(response.contains("\nunknown_messages=0\nshort_messages=0\noversized_messages=0\n"));
+
+        let response = request(&cfg, "set=1\nsource_policy=permissive\n\n");
+        assert_eq!(response, "errno=0\n\n");
+        assert_eq!(cfg.get_source_policy(), SourcePolicy::Permissive);
+        assert!(request(&cfg, "get=1\n\n").contains("\nsource_policy=permissive\n"));
+
+        let errno = ConfigError::UnsupportedValue.errno();
+        let response = request(&cfg, "set=1\nsource_policy=loose\n\n");
+        assert_eq!(response, format!("errno={}\n\n", errno));
+    }
+
+    #[test]
+    fn test_adaptive_keepalive() {
+        let (_fake, _reader, tun_writer, _mtu) = dummy::TunTest::create(false);
+        let cfg: WireGuardConfig<dummy::TunTest, dummy::PairBind> =
+            WireGuardConfig::new(WireGuard::new(tun_writer));
+        let set = |value: &str| {
+            request(
+                &cfg,
+                &format!(
+                    "set=1\npublic_key={}\nadaptive_keepalive={}\n\n",
+                    PUBLIC_KEY, value
+                ),
+            )
+        };
+
+        // probing starts from the shortest interval
+        assert_eq!(set("25,600"), "errno=0\n\n");
+        let peer = &cfg.get_peers()[0];
+        assert_eq!(peer.adaptive_keepalive, Some((25, 600)));
+        assert_eq!(peer.persistent_keepalive_interval, 25);
+        let response = request(&cfg, "get=1\n\n");
+        assert!(response.contains("\nadaptive_keepalive=25,600\n"));
+
+        // invalid ranges are rejected
+        let errno = ConfigError::InvalidKeepaliveInterval.errno();
+        for value in &["600,25", "25", "25,x", ""] {
+            assert_eq!(set(value), format!("errno={}\n\n", errno));
+        }
+
+        // disable
+        assert_eq!(set("0"), "errno=0\n\n");
+        let peer = &cfg.get_peers()[0];
+        assert_eq!(peer.adaptive_keepalive, None);
+        assert_eq!(peer.persistent_keepalive_interval, 0);
+    }
 }
@@ -1,25 +1,38 @@
 use hex::FromHex;
-use std::net::{IpAddr, SocketAddr};
+#[cfg(unix)]
+use std::ffi::CString;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::time::Duration;
 use subtle::ConstantTimeEq;
 use x25519_dalek::{PublicKey, StaticSecret};
 
-use super::{ConfigError, Configuration};
+use super::super::key::{validate_public_key, Key};
+use super::{ConfigError, Configuration, DropPolicy, EvictionPolicy, ReservedBytes, SourcePolicy};
 
 enum ParserState {
-    Peer(ParsedPeer),
+    Peer(Box<ParsedPeer>),
     Interface,
 }
 
 struct ParsedPeer {
     public_key: PublicKey,
+    new_public_key: Option<PublicKey>,
     update_only: bool,
+    clear_session: bool,
+    tcp_fallback: Option<bool>,
     allowed_ips: Vec<(IpAddr, u32)>,
     remove: bool,
     preshared_key: Option<[u8; 32]>,
+    hybrid_secret: Option<[u8; 32]>,
     replace_allowed_ips: bool,
     persistent_keepalive_interval: Option<u64>,
+    adaptive_keepalive: Option<Option<(u64, u64)>>,
+    expiry: Option<Option<u64>>,
+    staged_packets: Option<(usize, DropPolicy)>,
     protocol_version: Option<usize>,
     endpoint: Option<SocketAddr>,
+    endpoint_candidates: Vec<SocketAddr>,
+    endpoint_name: Option<String>,
 }
 
 pub struct LineParser<'a, C: Configuration> {
@@ -27,6 +40,62 @@ pub struct LineParser<'a, C: Configuration> {
     state: ParserState,
 }
 
+// Internal function
+//
+// Resolve the zone of a scoped IPv6 address: an interface index or name
+fn parse_zone(zone: &str) -> Option<u32> {
+    match zone.parse() {
+        Ok(index) => Some(index),
+        Err(_) => interface_index(zone),
+    }
+}
+
+#[cfg(unix)]
+fn interface_index(name: &str) -> Option<u32> {
+    let name = CString::new(name).ok()?;
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => None,
+        index => Some(index),
+    }
+}
+
+#[cfg(not(unix))]
+fn interface_index(_name: &str) -> Option<u32> {
+    None
+}
+
+// Internal function
+//
+// Check if an endpoint is a hostname and port ("host:port"), resolved by the device
+fn is_hostname(value: &str) -> bool {
+    match value.rfind(':') {
+        Some(i) => {
+            let (host, port) = (&value[..i], &value[i + 1..]);
+            port.parse::<u16>().is_ok()
+                && !host.is_empty()
+                && host
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+        }
+        None => false,
+    }
+}
+
+// Internal function
+//
+// Parse an endpoint, IPv6 addresses may carry a zone (e.g. "[fe80::1%eth0]:51820")
+fn parse_endpoint(value: &str) -> Option<SocketAddr> {
+    if let Ok(endpoint) = value.parse() {
+        return Some(endpoint);
+    }
+    let (host, port) = value.strip_prefix('[')?.split_at(value.rfind("]:")? - 1);
+    let (addr, zone) = host.split_at(host.find('%')?);
+    let addr: Ipv6Addr = addr.parse().ok()?;
+    let port: u16 = port[2..].parse().ok()?;
+    let scope = parse_zone(&zone[1..])?;
+    Some(SocketAddr::V6(SocketAddrV6::new(addr, port, 0, scope)))
+}
+
 impl<'a, C: Configuration> LineParser<'a, C> {
     pub fn new(config: &'a C) -> LineParser<'a, C> {
         LineParser {
@@ -37,17 +106,29 @@ impl<'a, C: Configuration> LineParser<'a, C> {
 
     fn new_peer(value: &str) -> Result<ParserState, ConfigError> {
         match <[u8; 32]>::from_hex(value) {
-            Ok(pk) => Ok(ParserState::Peer(ParsedPeer {
+            Ok(pk) if validate_public_key(&PublicKey::from(pk)).is_err() => {
+                Err(ConfigError::LowOrderPublicKey)
+            }
+            Ok(pk) => Ok(ParserState::Peer(Box::new(ParsedPeer {
                 public_key: PublicKey::from(pk),
+                new_public_key: None,
                 remove: false,
                 update_only: false,
+                clear_session: false,
+                tcp_fallback: None,
                 allowed_ips: vec![],
                 preshared_key: None,
+                hybrid_secret: None,
                 replace_allowed_ips: false,
                 persistent_keepalive_interval: None,
+                adaptive_keepalive: None,
+                expiry: None,
+                staged_packets: None,
                 protocol_version: None,
                 endpoint: None,
-            })),
+                endpoint_candidates: vec![],
+                endpoint_name: None,
+            }))),
             Err(_) => Err(ConfigError::InvalidHexValue),
         }
     }
@@ -63,29 +144,85 @@ impl<'a, C: Configuration> LineParser<'a, C> {
         // flush peer updates to configuration
         fn flush_peer<C: Configuration>(config: &C, peer: &ParsedPeer) -> Option<ConfigError> {
             if peer.remove {
-                log::trace!("flush peer, remove peer");
+                log::trace!("flush peer, remove peer {}", Key::from(&peer.public_key));
                 config.remove_peer(&peer.public_key);
                 return None;
             }
 
             if !peer.update_only {
-                log::trace!("flush peer, add peer");
+                log::trace!("flush peer, add peer {}", Key::from(&peer.public_key));
                 config.add_peer(&peer.public_key);
             }
 
+            // the remaining settings apply to the peer under its new key
+            let pk = match peer.new_public_key {
+                Some(new) => {
+                    log::trace!("flush peer, replace public key by {}", Key::from(&new));
+                    if !config.replace_peer_key(&peer.public_key, &new) {
+                        return Some(ConfigError::UnsupportedValue);
+                    }
+                    new
+                }
+                None => peer.public_key,
+            };
+
+            if peer.clear_session {
+                log::trace!("flush peer, clear session");
+                config.clear_session(&pk);
+            }
+
             for (ip, cidr) in &peer.allowed_ips {
                 log::trace!("flush peer, add allowed_ips : {}/{}", ip.to_string(), cidr);
-                config.add_allowed_ip(&peer.public_key, *ip, *cidr);
+                config.add_allowed_ip(&pk, *ip, *cidr);
             }
 
             if let Some(psk) = peer.preshared_key {
-                log::trace!("flush peer, set preshared_key {}", hex::encode(psk));
-                config.set_preshared_key(&peer.public_key, psk);
+                log::trace!("flush peer, set preshared_key {}", Key(psk));
+                config.set_preshared_key(
+                    &pk,
+                    if psk.ct_eq(&[0u8; 32]).into() {
+                        None
+                    } else {
+                        Some(psk)
+                    },
+                );
+            }
+
+            if let Some(secret) = peer.hybrid_secret {
+                log::trace!("flush peer, set hybrid_secret");
+                config.set_hybrid_secret(
+                    &pk,
+                    if secret.ct_eq(&[0u8; 32]).into() {
+                        None
+                    } else {
+                        Some(secret)
+                    },
+                );
             }
 
             if let Some(secs) = peer.persistent_keepalive_interval {
                 log::trace!("flush peer, set persistent_keepalive_interval {}", secs);
-                config.set_persistent_keepalive_interval(&peer.public_key, secs);
+                config.set_persistent_keepalive_interval(&pk, secs);
+            }
+
+            if let Some(range) = peer.adaptive_keepalive {
+                log::trace!("flush peer, set adaptive_keepalive {:?}", range);
+                config.set_adaptive_keepalive(&pk, range);
+            }
+
+            if let Some(secs) = peer.expiry {
+                log::trace!("flush peer, set expiry {:?}", secs);
+                config.set_peer_expiry(&pk, secs);
+            }
+
+            if let Some((depth, policy)) = peer.staged_packets {
+                log::trace!("flush peer, set staged_packets {} {:?}", depth, policy);
+                config.set_staged_packets(&pk, depth, policy);
+            }
+
+            if let Some(enabled) = peer.tcp_fallback {
+                log::trace!("flush peer, set tcp_fallback {}", enabled);
+                config.set_tcp_fallback(&pk, enabled);
             }
 
             if let Some(version) = peer.protocol_version {
@@ -95,9 +232,28 @@ impl<'a, C: Configuration> LineParser<'a, C> {
                 }
             }
 
-            if let Some(endpoint) = peer.endpoint {
-                log::trace!("flush peer, set endpoint {}", endpoint.to_string());
-                config.set_endpoint(&peer.public_key, endpoint);
+            // the endpoint is preferred over the candidates for failover
+            if peer.endpoint_candidates.is_empty() {
+                if let Some(endpoint) = peer.endpoint {
+                    log::trace!("flush peer, set endpoint {}", endpoint.to_string());
+                    config.set_endpoint(&pk, endpoint);
+                }
+            } else {
+                let mut endpoints: Vec<SocketAddr> = peer.endpoint.into_iter().collect();
+                for candidate in &peer.endpoint_candidates {
+                    if !endpoints.contains(candidate) {
+                        endpoints.push(*candidate);
+                    }
+                }
+                log::trace!("flush peer, set endpoints {:?}", endpoints);
+                config.set_endpoints(&pk, endpoints);
+            }
+
+            if let Some(name) = peer.endpoint_name.as_ref() {
+                log::trace!("flush peer, set endpoint name {}", name);
+                if let Err(e) = config.set_endpoint_name(&pk, name) {
+                    return Some(e);
+                }
             };
 
             None
@@ -139,6 +295,100 @@ impl<'a, C: Configuration> LineParser<'a, C> {
                     Err(_) => Err(ConfigError::InvalidFwmark),
                 },
 
+                // opt: set a fixed DSCP for the outer packets ("inherit" copies the inner DSCP)
+                "dscp" => match value {
+                    "inherit" => {
+                        self.config.set_dscp(None);
+                        Ok(())
+                    }
+                    _ => match value.parse() {
+                        Ok(dscp) if dscp < 64 => {
+                            self.config.set_dscp(Some(dscp));
+                            Ok(())
+                        }
+                        _ => Err(ConfigError::UnsupportedValue),
+                    },
+                },
+
+                // opt: set the anti-replay window (in packets)
+                "replay_window" => match value.parse() {
+                    Ok(size) => {
+                        self.config.set_replay_window(size);
+                        Ok(())
+                    }
+                    Err(_) => Err(ConfigError::UnsupportedValue),
+                },
+
+                // opt: set the minimum time between consumed initiations (in milliseconds)
+                "initiation_flood_window" => match value.parse() {
+                    Ok(ms) => {
+                        let mut config = self.config.get_handshake_config();
+                        config.initiation_flood_window = Duration::from_millis(ms);
+                        self.config.set_handshake_config(config);
+                        Ok(())
+                    }
+                    Err(_) => Err(ConfigError::UnsupportedValue),
+                },
+
+                // opt: set the tolerance for older initiation timestamps (in milliseconds)
+                "timestamp_tolerance" => match value.parse() {
+                    Ok(ms) => {
+                        let mut config = self.config.get_handshake_config();
+                        config.timestamp_tolerance = Duration::from_millis(ms);
+                        self.config.set_handshake_config(config);
+                        Ok(())
+                    }
+                    Err(_) => Err(ConfigError::UnsupportedValue),
+                },
+
+                // opt: set the policy for packets violating the cryptokey routing
+                "source_policy" => match value {
+                    "strict" => {
+                        self.config.set_source_policy(SourcePolicy::Strict);
+                        Ok(())
+                    }
+                    "permissive" => {
+                        self.config.set_source_policy(SourcePolicy::Permissive);
+                        Ok(())
+                    }
+                    _ => Err(ConfigError::UnsupportedValue),
+                },
+
+                // opt: set the policy for the reserved bytes of all messages
+                "reserved_bytes" => match ReservedBytes::from_name(value) {
+                    Some(reserved) => {
+                        let mut config = self.config.get_handshake_config();
+                        config.reserved = reserved;
+                        self.config.set_handshake_config(config);
+                        Ok(())
+                    }
+                    None => Err(ConfigError::UnsupportedValue),
+                },
+
+                // opt: set the maximum number of peers
+                "max_peers" => match value.parse() {
+                    Ok(max_peers) if max_peers > 0 => {
+                        let mut config = self.config.get_handshake_config();
+                        config.max_peers = max_peers;
+                        self.config.set_handshake_config(config);
+                        Ok(())
+                    }
+                    _ => Err(ConfigError::UnsupportedValue),
+                },
+
+                // opt: set what to do when adding a peer beyond max_peers
+                "eviction" => {
+                    let eviction = match value {
+                        "reject" => EvictionPolicy::Reject,
+                        "oldest_handshake" => EvictionPolicy::OldestHandshake,
+                        _ => return Err(ConfigError::UnsupportedValue),
+                    };
+                    let mut config = self.config.get_handshake_config();
+                    config.eviction = eviction;
+                    self.config.set_handshake_config(config);
+                    Ok(())
+                }
+
                 // opt: remove all peers
                 "replace_peers" => match value {
                     "true" => {
@@ -167,7 +417,9 @@ impl<'a, C: Configuration> LineParser<'a, C> {
             ParserState::Peer(ref mut peer) => match key {
                 // opt: new peer
                 "public_key" => {
-                    flush_peer(self.config, &peer);
+                    if let Some(e) = flush_peer(self.config, peer) {
+                        return Err(e);
+                    }
                     self.state = Self::new_peer(value)?;
                     Ok(())
                 }
@@ -178,12 +430,34 @@ impl<'a, C: Configuration> LineParser<'a, C> {
                     Ok(())
                 }
 
+                // opt: replace the public key of the peer (key rotation),
+                // retaining its allowed IPs, preshared key, endpoint and sessions
+                "new_public_key" => match <[u8; 32]>::from_hex(value) {
+                    Ok(pk) if validate_public_key(&PublicKey::from(pk)).is_err() => {
+                        Err(ConfigError::LowOrderPublicKey)
+                    }
+                    Ok(pk) => {
+                        peer.new_public_key = Some(PublicKey::from(pk));
+                        Ok(())
+                    }
+                    Err(_) => Err(ConfigError::InvalidHexValue),
+                },
+
                 // opt: update only
                 "update_only" => {
                     peer.update_only = true;
                     Ok(())
                 }
 
+                // opt: invalidate the sessions with the peer
+                "clear_session" => match value {
+                    "true" => {
+                        peer.clear_session = true;
+                        Ok(())
+                    }
+                    _ => Err(ConfigError::UnsupportedValue),
+                },
+
                 // opt: set preshared key
                 "preshared_key" => match <[u8; 32]>::from_hex(value) {
                     Ok(psk) => {
@@ -193,13 +467,50 @@ impl<'a, C: Configuration> LineParser<'a, C> {
                     Err(_) => Err(ConfigError::InvalidHexValue),
                 },
 
+                // opt: set hybrid (post-quantum) secret, all zeros to unset
+                "hybrid_secret" => match <[u8; 32]>::from_hex(value) {
+                    Ok(secret) => {
+                        peer.hybrid_secret = Some(secret);
+                        Ok(())
+                    }
+                    Err(_) => Err(ConfigError::InvalidHexValue),
+                },
+
                 // opt: set endpoint
-                "endpoint" => match value.parse() {
-                    Ok(endpoint) => {
+                "endpoint" => match parse_endpoint(value) {
+                    Some(endpoint) => {
                         peer.endpoint = Some(endpoint);
+                        peer.endpoint_name = None;
                         Ok(())
                     }
-                    Err(_) => Err(ConfigError::InvalidSocketAddr),
+                    None if is_hostname(value) => {
+                        peer.endpoint = None;
+                        peer.endpoint_name = Some(value.to_owned());
+                        Ok(())
+                    }
+                    None => Err(ConfigError::InvalidSocketAddr),
+                },
+
+                // opt: add a candidate endpoint for failover (in order of preference)
+                "endpoint_candidate" => match parse_endpoint(value) {
+                    Some(endpoint) => {
+                        peer.endpoint_candidates.push(endpoint);
+                        Ok(())
+                    }
+                    None => Err(ConfigError::InvalidSocketAddr),
+                },
+
+                // opt: fall back to TCP when handshakes over UDP fail
+                "tcp_fallback" => match value {
+                    "true" => {
+                        peer.tcp_fallback = Some(true);
+                        Ok(())
+                    }
+                    "false" => {
+                        peer.tcp_fallback = Some(false);
+                        Ok(())
+                    }
+                    _ => Err(ConfigError::UnsupportedValue),
                 },
 
                 // opt: set persistent keepalive interval
@@ -211,6 +522,55 @@ impl<'a, C: Configuration> LineParser<'a, C> {
                     Err(_) => Err(ConfigError::InvalidKeepaliveInterval),
                 },
 
+                // opt: probe the NAT binding timeout ("<min>,<max>" in seconds, 0 disables it)
+                "adaptive_keepalive" => {
+                    let mut split = value.splitn(2, ',');
+                    let min = split.next().and_then(|x| x.parse().ok());
+                    let max = split.next().and_then(|x| x.parse().ok());
+                    match (min, max) {
+                        (Some(min), Some(max)) if min <= max => {
+                            peer.adaptive_keepalive = Some(Some((min, max)));
+                            Ok(())
+                        }
+                        _ if value == "0" => {
+                            peer.adaptive_keepalive = Some(None);
+                            Ok(())
+                        }
+                        _ => Err(ConfigError::InvalidKeepaliveInterval),
+                    }
+                }
+
+                // opt: remove the peer after the given number of seconds (0 never expires)
+                "expiry" => match value.parse() {
+                    Ok(0) => {
+                        peer.expiry = Some(None);
+                        Ok(())
+                    }
+                    Ok(secs) => {
+                        peer.expiry = Some(Some(secs));
+                        Ok(())
+                    }
+                    Err(_) => Err(ConfigError::UnsupportedValue),
+                },
+
+                // opt: queue of packets awaiting a key-pair ("<depth>,<head|tail>")
+                "staged_packets" => {
+                    let mut split = value.splitn(2, ',');
+                    let depth = split.next().and_then(|x| x.parse().ok());
+                    let policy = match split.next() {
+                        Some("head") => Some(DropPolicy::Head),
+                        Some("tail") => Some(DropPolicy::Tail),
+                        _ => None,
+                    };
+                    match (depth, policy) {
+                        (Some(depth), Some(policy)) if depth > 0 => {
+                            peer.staged_packets = Some((depth, policy));
+                            Ok(())
+                        }
+                        _ => Err(ConfigError::UnsupportedValue),
+                    }
+                }
+
                 // opt replace allowed ips
                 "replace_allowed_ips" => {
                     peer.replace_allowed_ips = true;
@@ -247,8 +607,10 @@ impl<'a, C: Configuration> LineParser<'a, C> {
                 // flush (used at end of transcipt)
                 "" => {
                     log::trace!("UAPI, Set, processes end of transaction");
-                    flush_peer(self.config, &peer);
-                    Ok(())
+                    match flush_peer(self.config, peer) {
+                        Some(e) => Err(e),
+                        None => Ok(()),
+                    }
                 }
 
                 // unknown key
@@ -257,3 +619,45 @@ impl<'a, C: Configuration> LineParser<'a, C> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_endpoint() {
+        let parse = |value: &str| parse_endpoint(value).map(|addr| addr.to_string());
+
+        assert_eq!(parse("192.0.2.1:51820").unwrap(), "192.0.2.1:51820");
+        assert_eq!(parse("[2001:db8::1]:51820").unwrap(), "[2001:db8::1]:51820");
+
+        // link-local addresses with a numeric zone or an interface name
+        let endpoint = parse_endpoint("[fe80::1%2]:51820").unwrap();
+        match endpoint {
+            SocketAddr::V6(addr) => assert_eq!(addr.scope_id(), 2),
+            _ => panic!("expected IPv6 endpoint"),
+        }
+        if let Some(index) = interface_index("lo") {
+            let endpoint = parse_endpoint("[fe80::1%lo]:51820").unwrap();
+            assert_eq!(endpoint.to_string(), format!("[fe80::1%{}]:51820", index));
+        }
+
+        // invalid endpoints
+        assert!(parse("[fe80::1%no-such-if0]:51820").is_none());
+        assert!(parse("[fe80::1%]:51820").is_none());
+        assert!(parse("[fe80::1%2]").is_none());
+        assert!(parse("fe80::1%2:51820").is_none());
+        assert!(parse("[192.0.2.1%2]:51820").is_none());
+    }
+
+    #[test]
+    fn test_is_hostname() {
+        assert!(is_hostname("vpn.example.com:51820"));
+        assert!(is_hostname("localhost:1"));
+        assert!(!is_hostname("vpn.example.com"));
+        assert!(!is_hostname("vpn.example.com:65536"));
+        assert!(!is_hostname(":51820"));
+        assert!(!is_hostname("[fe80::1%eth0]:51820"));
+        assert!(!is_hostname("vpn example:51820"));
+    }
+}
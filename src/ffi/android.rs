@@ -0,0 +1,244 @@
+/* C-ABI for embedding the device in an Android application (through JNI)
+ *
+ * The VpnService establishes the TUN device and hands over its file descriptor:
+ *
+ * - wg_turn_on / wg_turn_off: start / stop a device, identified by a handle.
+ * - wg_set_config / wg_get_config: the UAPI "set" / "get" operations.
+ * - wg_export_config: the configuration as a configuration file (for backup / migration).
+ * - wg_rebind: reopen the sockets after a change of network.
+ * - wg_set_protect: exempt the sockets from the tunnel (VpnService.protect).
+ * - wg_set_obfuscator: obfuscate the datagrams with callbacks of the application.
+ * - wg_set_expiry_callback: notify the application of the removal of time-limited peers.
+ * - wg_set_key_confirmed_callback: notify the application of confirmed sessions with peers.
+ */
+
+use super::super::configuration::{Configuration, WireGuardConfig};
+use super::super::platform::linux::udp::{set_protect, Protect};
+use super::super::platform::plt;
+use super::super::wireguard::WireGuard;
+use super::{
+    device_config, export_config, get_config, set_config, set_expiry_callback,
+    set_key_confirmed_callback, set_obfuscator, string, Context, Handles, ObfuscateCallback,
+    PeerCallback,
+};
+
+use std::os::raw::{c_char, c_int, c_void};
+use std::os::unix::io::RawFd;
+use std::thread;
+
+struct Tunnel {
+    cfg: WireGuardConfig<plt::Tun, plt::UDP>,
+}
+
+static TUNNELS: Handles<Tunnel> = Handles::new();
+
+/// Start a device on a TUN device established by the VpnService
+///
+/// # Arguments
+///
+/// - `tun_fd`: The file descriptor of the TUN device (detached from the ParcelFileDescriptor)
+/// - `mtu`: The MTU of the TUN device
+/// - `settings`: The configuration ("key=value" lines of a UAPI set operation)
+///
+/// # Returns
+///
+/// A handle (non-negative) identifying the device or a negative errno.
+/// The TUN device is closed once the system removes the interface.
+#[no_mangle]
+pub unsafe extern "C" fn wg_turn_on(tun_fd: c_int, mtu: c_int, settings: *const c_char) -> c_int {
+    let settings = match string(settings) {
+        Some(settings) => settings,
+        None => return -libc::EINVAL,
+    };
+    if mtu <= 0 {
+        return -libc::EINVAL;
+    }
+
+    // create WireGuard device
+    let (mut readers, writer, _status) = match plt::Tun::from_fd(tun_fd as RawFd) {
+        Ok(tun) => tun,
+        Err(e) => {
+            log::info!("Failed to use TUN device: {}", e);
+            return -libc::EBADF;
+        }
+    };
+    let wg: WireGuard<plt::Tun, plt::UDP> = WireGuard::with_config(writer, device_config());
+    while let Some(reader) = readers.pop() {
+        wg.add_tun_reader(reader);
+    }
+
+    // configure and bring up (no events from the TUN device)
+    let cfg = WireGuardConfig::new(wg.clone());
+    let res = set_config(&cfg, settings);
+    if res != 0 {
+        cfg.down();
+        return res;
+    }
+    if let Err(e) = cfg.up(mtu as usize) {
+        log::info!("Failed to bring up device: {}", e);
+        cfg.down();
+        return -e.errno();
+    }
+
+    // release the TUN device once all readers have exited
+    thread::spawn(move || {
+        wg.wait();
+        unsafe { libc::close(tun_fd) };
+    });
+    TUNNELS.insert(Tunnel { cfg })
+}
+
+/// Stop the device identified by the handle
+#[no_mangle]
+pub extern "C" fn wg_turn_off(handle: c_int) {
+    if let Some(tunnel) = TUNNELS.remove(handle) {
+        tunnel.cfg.down();
+        tunnel.cfg.replace_peers();
+    }
+}
+
+/// Apply settings ("key=value" lines of a UAPI set operation) to a running device
+///
+/// # Returns
+///
+/// Zero on success or a negative errno.
+#[no_mangle]
+pub unsafe extern "C" fn wg_set_config(handle: c_int, settings: *const c_char) -> c_int {
+    match string(settings) {
+        Some(settings) => TUNNELS.with(handle, |tunnel| set_config(&tunnel.cfg, settings)),
+        None => -libc::EINVAL,
+    }
+}
+
+/// Obtain the configuration (the response of a UAPI get operation)
+///
+/// # Returns
+///
+/// A string to be released with wg_free_string, or null if the handle is invalid.
+#[no_mangle]
+pub extern "C" fn wg_get_config(handle: c_int) -> *mut c_char {
+    let mut config = std::ptr::null_mut();
+    TUNNELS.with(handle, |tunnel| {
+        config = get_config(&tunnel.cfg);
+        0
+    });
+    config
+}
+
+/// Export the configuration, including the endpoints learned at runtime,
+/// in the [Interface] / [Peer] format of configuration files
+///
+/// # Returns
+///
+/// A string to be released with wg_free_string, or null if the handle is invalid.
+#[no_mangle]
+pub extern "C" fn wg_export_config(handle: c_int) -> *mut c_char {
+    let mut config = std::ptr::null_mut();
+    TUNNELS.with(handle, |tunnel| {
+        config = export_config(&tunnel.cfg);
+        0
+    });
+    config
+}
+
+/// Reopen the sockets of the device after a change of network
+///
+/// # Returns
+///
+/// Zero on success or a negative errno.
+#[no_mangle]
+pub extern "C" fn wg_rebind(handle: c_int) -> c_int {
+    TUNNELS.with(handle, |tunnel| match tunnel.cfg.rebind(None) {
+        Ok(()) => 0,
+        Err(e) => -e.errno(),
+    })
+}
+
+/// Install the callbacks obfuscating the datagrams of the device (see ObfuscateCallback)
+///
+/// # Arguments
+///
+/// - `wrap`: Callback transforming every outbound message (or null to remove the obfuscator)
+/// - `unwrap`: Callback reversing the transformation of every received datagram (or null)
+/// - `ctx`: An opaque pointer passed to the callbacks
+///
+/// # Returns
+///
+/// Zero on success or a negative errno.
+#[no_mangle]
+pub extern "C" fn wg_set_obfuscator(
+    handle: c_int,
+    wrap: Option<ObfuscateCallback>,
+    unwrap: Option<ObfuscateCallback>,
+    ctx: *mut c_void,
+) -> c_int {
+    TUNNELS.with(handle, |tunnel| {
+        set_obfuscator(&tunnel.cfg, wrap, unwrap, ctx)
+    })
+}
+
+/// Install the callback invoked when a time-limited peer (see the "expiry" key)
+/// has expired and been removed (see PeerCallback)
+///
+/// # Arguments
+///
+/// - `callback`: The callback (or null to remove it)
+/// - `ctx`: An opaque pointer passed to the callback
+///
+/// # Returns
+///
+/// Zero on success or a negative errno.
+#[no_mangle]
+pub extern "C" fn wg_set_expiry_callback(
+    handle: c_int,
+    callback: Option<PeerCallback>,
+    ctx: *mut c_void,
+) -> c_int {
+    TUNNELS.with(handle, |tunnel| {
+        set_expiry_callback(&tunnel.cfg, callback, ctx);
+        0
+    })
+}
+
+/// Install the callback invoked when a new key-pair with a peer is confirmed,
+/// e.g. to report the tunnel as connected (see PeerCallback)
+///
+/// # Arguments
+///
+/// - `callback`: The callback (or null to remove it)
+/// - `ctx`: An opaque pointer passed to the callback
+///
+/// # Returns
+///
+/// Zero on success or a negative errno.
+#[no_mangle]
+pub extern "C" fn wg_set_key_confirmed_callback(
+    handle: c_int,
+    callback: Option<PeerCallback>,
+    ctx: *mut c_void,
+) -> c_int {
+    TUNNELS.with(handle, |tunnel| {
+        set_key_confirmed_callback(&tunnel.cfg, callback, ctx);
+        0
+    })
+}
+
+/// Set the callback protecting every new socket from the tunnel
+///
+/// The callback should invoke VpnService.protect on the file descriptor
+/// and return non-zero on success; sockets which cannot be protected are not used.
+///
+/// # Arguments
+///
+/// - `callback`: The callback (or null to remove it)
+/// - `ctx`: An opaque pointer passed to the callback (e.g. a global JNI reference)
+#[no_mangle]
+pub extern "C" fn wg_set_protect(
+    callback: Option<extern "C" fn(ctx: *mut c_void, fd: c_int) -> c_int>,
+    ctx: *mut c_void,
+) {
+    let ctx = Context(ctx);
+    set_protect(
+        callback.map(|callback| Box::new(move |fd: RawFd| callback(ctx.0, fd) != 0) as Protect),
+    );
+}
@@ -0,0 +1,489 @@
+/* C-ABI for embedding the device in an application
+ *
+ * - android: on a TUN device established by the VpnService (through JNI).
+ * - network_extension: on the packet flow of a packet tunnel provider (iOS / macOS).
+ *
+ * Devices are identified by handles and configured using the UAPI protocol
+ * (as with the control socket): errors are returned as negative errno values.
+ *
+ * The datagrams of a device can be obfuscated by callbacks of the application
+ * (e.g. a pluggable transport), installed with wg_set_obfuscator.
+ * The application is notified of the removal of time-limited peers
+ * by the callback installed with wg_set_expiry_callback,
+ * and of confirmed sessions with peers by the callback of wg_set_key_confirmed_callback.
+ *
+ * Devices of private networks can use a custom handshake protocol (wg_set_protocol),
+ * deliberately incompatible with standard WireGuard.
+ */
+
+#[cfg(target_os = "android")]
+pub mod android;
+
+#[cfg(any(target_os = "ios", target_os = "macos"))]
+pub mod network_extension;
+
+use super::configuration::{self, ConfigFile, Configuration, WireGuardConfig};
+use super::platform::{tun, udp};
+use super::wireguard::{
+    DeviceConfig, KeyConfirmedHandler, Obfuscator, PeerExpiryHandler, Protocol,
+};
+
+use std::ffi::{CStr, CString};
+use std::io::{self, Cursor, Read, Write};
+use std::os::raw::{c_char, c_int, c_void};
+use std::sync::Arc;
+
+use spin::Mutex;
+use x25519_dalek::PublicKey;
+
+// Handshake protocol of the devices started subsequently (None for standard WireGuard)
+static PROTOCOL: Mutex<Option<Protocol>> = Mutex::new(None);
+
+/// Opaque pointer passed back to the callbacks of the application
+pub struct Context(pub *mut c_void);
+
+// the context is never dereferenced (only passed back to the application)
+unsafe impl Send for Context {}
+unsafe impl Sync for Context {}
+
+/// The running devices, indexed by handle
+pub struct Handles<T>(Mutex<Vec<Option<T>>>);
+
+impl<T> Default for Handles<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Handles<T> {
+    pub const fn new() -> Self {
+        Handles(Mutex::new(Vec::new()))
+    }
+
+    /// Allocate the lowest free handle
+    pub fn insert(&self, value: T) -> c_int {
+        let mut handles = self.0.lock();
+        match handles.iter().position(|value| value.is_none()) {
+            Some(handle) => {
+                handles[handle] = Some(value);
+                handle as c_int
+            }
+            None => {
+                handles.push(Some(value));
+                (handles.len() - 1) as c_int
+            }
+        }
+    }
+
+    /// Release the handle
+    pub fn remove(&self, handle: c_int) -> Option<T> {
+        let mut handles = self.0.lock();
+        match handles.get_mut(handle as usize) {
+            Some(value) if handle >= 0 => value.take(),
+            _ => None,
+        }
+    }
+
+    /// Apply a function to the value identified by the handle (-EBADF if invalid)
+    pub fn with<F: FnOnce(&T) -> c_int>(&self, handle: c_int, f: F) -> c_int {
+        let handles = self.0.lock();
+        match handles
+            .get(handle as usize)
+            .and_then(|value| value.as_ref())
+        {
+            Some(value) if handle >= 0 => f(value),
+            _ => -libc::EBADF,
+        }
+    }
+}
+
+/// Transforms a datagram (wrap: before sending, unwrap: after receiving)
+/// into the output buffer of the given capacity
+///
+/// # Returns
+///
+/// The length of the output, or a negative value if the datagram should be dropped
+pub type ObfuscateCallback = extern "C" fn(
+    ctx: *mut c_void,
+    input: *const u8,
+    len: usize,
+    output: *mut u8,
+    capacity: usize,
+) -> isize;
+
+/// Number of bytes an obfuscator may add to a datagram
+/// (the capacity of the output buffer exceeds the length of the input by this amount)
+pub const MAX_OBFUSCATION_OVERHEAD: usize = 256;
+
+// Obfuscator calling back into the application
+struct CallbackObfuscator {
+    wrap: ObfuscateCallback,
+    unwrap: ObfuscateCallback,
+    ctx: Context,
+}
+
+impl CallbackObfuscator {
+    fn call(&self, callback: ObfuscateCallback, input: &[u8]) -> Option<Vec<u8>> {
+        let mut output = vec![0u8; input.len() + MAX_OBFUSCATION_OVERHEAD];
+        let len = callback(
+            self.ctx.0,
+            input.as_ptr(),
+            input.len(),
+            output.as_mut_ptr(),
+            output.len(),
+        );
+        if len < 0 || len as usize > output.len() {
+            return None;
+        }
+        output.truncate(len as usize);
+        Some(output)
+    }
+}
+
+impl Obfuscator for CallbackObfuscator {
+    fn wrap(&self, msg: &[u8]) -> Vec<u8> {
+        // a message which cannot be wrapped is never sent in the clear:
+        // an empty datagram is sent instead (and dropped by the peer)
+        self.call(self.wrap, msg).unwrap_or_default()
+    }
+
+    fn unwrap(&self, datagram: Vec<u8>) -> Option<Vec<u8>> {
+        self.call(self.unwrap, &datagram)
+    }
+}
+
+/// Install (or remove) the callbacks obfuscating the datagrams of the device
+///
+/// # Arguments
+///
+/// - `wrap`: Callback transforming every outbound message (or null to remove the obfuscator)
+/// - `unwrap`: Callback reversing the transformation of every received datagram
+///   (or null to remove the obfuscator)
+/// - `ctx`: An opaque pointer passed to the callbacks
+///
+/// # Returns
+///
+/// Zero on success or a negative errno (if only one of the callbacks is null).
+pub fn set_obfuscator<T: tun::Tun, B: udp::PlatformUDP>(
+    cfg: &WireGuardConfig<T, B>,
+    wrap: Option<ObfuscateCallback>,
+    unwrap: Option<ObfuscateCallback>,
+    ctx: *mut c_void,
+) -> c_int {
+    match (wrap, unwrap) {
+        (Some(wrap), Some(unwrap)) => {
+            let ctx = Context(ctx);
+            cfg.set_obfuscator(Some(Arc::new(CallbackObfuscator { wrap, unwrap, ctx })));
+            0
+        }
+        (None, None) => {
+            cfg.set_obfuscator(None);
+            0
+        }
+        _ => -libc::EINVAL,
+    }
+}
+
+/// Notifies the application of an event concerning a peer,
+/// with the public key of the peer (32 bytes, valid for the duration of the call)
+pub type PeerCallback = extern "C" fn(ctx: *mut c_void, public_key: *const u8);
+
+// Internal function
+//
+// Wrap a callback of the application as a handler of peer events
+fn peer_handler(callback: PeerCallback, ctx: *mut c_void) -> impl Fn(&PublicKey) + Send + Sync {
+    let ctx = Context(ctx);
+    move |pk: &PublicKey| callback(ctx.0, pk.as_bytes().as_ptr())
+}
+
+/// Install (or remove) the callback invoked when a time-limited peer has expired
+///
+/// # Arguments
+///
+/// - `callback`: Callback invoked with the public key of the removed peer (or null)
+/// - `ctx`: An opaque pointer passed to the callback
+pub fn set_expiry_callback<T: tun::Tun, B: udp::PlatformUDP>(
+    cfg: &WireGuardConfig<T, B>,
+    callback: Option<PeerCallback>,
+    ctx: *mut c_void,
+) {
+    cfg.set_expiry_handler(
+        callback.map(|callback| Box::new(peer_handler(callback, ctx)) as PeerExpiryHandler),
+    );
+}
+
+/// Install (or remove) the callback invoked when a new key-pair with a peer is confirmed
+/// (by the handshake response as initiator, or the first transport message as responder)
+///
+/// # Arguments
+///
+/// - `callback`: Callback invoked with the public key of the peer (or null)
+/// - `ctx`: An opaque pointer passed to the callback
+pub fn set_key_confirmed_callback<T: tun::Tun, B: udp::PlatformUDP>(
+    cfg: &WireGuardConfig<T, B>,
+    callback: Option<PeerCallback>,
+    ctx: *mut c_void,
+) {
+    cfg.set_key_confirmed_handler(
+        callback.map(|callback| Arc::new(peer_handler(callback, ctx)) as KeyConfirmedHandler),
+    );
+}
+
+// UAPI request / response held in memory
+struct Buffer {
+    request: Cursor<Vec<u8>>,
+    response: Vec<u8>,
+}
+
+impl Read for Buffer {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.request.read(buf)
+    }
+}
+
+impl Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.response.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Internal function
+//
+// Process a UAPI operation and return the response
+fn uapi<C: Configuration>(cfg: &C, request: String) -> String {
+    let mut buffer = Buffer {
+        request: Cursor::new(request.into_bytes()),
+        response: vec![],
+    };
+    configuration::uapi::handle(&mut buffer, cfg);
+    String::from_utf8_lossy(&buffer.response).into_owned()
+}
+
+/// Apply the settings ("key=value" lines of a UAPI set operation)
+///
+/// # Returns
+///
+/// Zero on success or a negative errno.
+pub fn set_config<C: Configuration>(cfg: &C, settings: &str) -> c_int {
+    let mut request = String::from("set=1\n");
+    for line in settings.lines().filter(|line| !line.is_empty()) {
+        request.push_str(line);
+        request.push('\n');
+    }
+    request.push('\n');
+    let errno: c_int = uapi(cfg, request)
+        .lines()
+        .find(|line| line.starts_with("errno="))
+        .and_then(|line| line["errno=".len()..].parse().ok())
+        .unwrap_or(libc::EIO);
+    -errno
+}
+
+/// Obtain the configuration (the response of a UAPI get operation)
+///
+/// # Returns
+///
+/// A string to be released with wg_free_string.
+pub fn get_config<C: Configuration>(cfg: &C) -> *mut c_char {
+    CString::new(uapi(cfg, "get=1\n\n".to_owned()))
+        .map(CString::into_raw)
+        .unwrap_or(std::ptr::null_mut())
+}
+
+/// Export the configuration in the format of configuration files (as "wg showconf")
+///
+/// # Returns
+///
+/// A string to be released with wg_free_string.
+pub fn export_config<C: Configuration>(cfg: &C) -> *mut c_char {
+    CString::new(ConfigFile::from_device(cfg).to_string())
+        .map(CString::into_raw)
+        .unwrap_or(std::ptr::null_mut())
+}
+
+/// Configuration of a device being started (with the protocol of wg_set_protocol)
+pub fn device_config() -> DeviceConfig {
+    let mut config = DeviceConfig::default();
+    if let Some(protocol) = *PROTOCOL.lock() {
+        config.protocol = protocol;
+    }
+    config
+}
+
+/// Read a string argument (None if null or not UTF-8)
+///
+/// # Safety
+///
+/// The pointer must be null or point to a NUL-terminated string outliving the result.
+pub unsafe fn string<'a>(ptr: *const c_char) -> Option<&'a str> {
+    if ptr.is_null() {
+        None
+    } else {
+        CStr::from_ptr(ptr).to_str().ok()
+    }
+}
+
+/// Release a string returned by wg_get_config / wg_export_config
+///
+/// # Safety
+///
+/// The pointer must be null or a string returned by the library, which is not used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn wg_free_string(ptr: *mut c_char) {
+    if !ptr.is_null() {
+        drop(CString::from_raw(ptr));
+    }
+}
+
+/// Select the handshake protocol of the devices started subsequently
+///
+/// # Arguments
+///
+/// - `construction`: The Noise construction string (null for standard WireGuard)
+/// - `identifier`: The protocol identifier string
+///
+/// # Returns
+///
+/// Zero on success or a negative errno.
+///
+/// # Safety
+///
+/// The strings must be null or NUL-terminated.
+#[no_mangle]
+pub unsafe extern "C" fn wg_set_protocol(
+    construction: *const c_char,
+    identifier: *const c_char,
+) -> c_int {
+    if construction.is_null() {
+        *PROTOCOL.lock() = None;
+        return 0;
+    }
+    match (string(construction), string(identifier)) {
+        (Some(construction), Some(identifier)) => {
+            let protocol = Protocol::new(construction.as_bytes(), identifier.as_bytes());
+            *PROTOCOL.lock() = Some(protocol);
+            0
+        }
+        _ => -libc::EINVAL,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // XORs the datagram with a key byte (read from the context) and appends a marker byte
+    extern "C" fn xor_wrap(
+        ctx: *mut c_void,
+        input: *const u8,
+        len: usize,
+        output: *mut u8,
+        capacity: usize,
+    ) -> isize {
+        let key = unsafe { *(ctx as *const u8) };
+        let input = unsafe { std::slice::from_raw_parts(input, len) };
+        let output = unsafe { std::slice::from_raw_parts_mut(output, capacity) };
+        for (o, i) in output.iter_mut().zip(input) {
+            *o = i ^ key;
+        }
+        output[len] = 0xaa;
+        (len + 1) as isize
+    }
+
+    extern "C" fn xor_unwrap(
+        ctx: *mut c_void,
+        input: *const u8,
+        len: usize,
+        output: *mut u8,
+        capacity: usize,
+    ) -> isize {
+        let key = unsafe { *(ctx as *const u8) };
+        let input = unsafe { std::slice::from_raw_parts(input, len) };
+        let output = unsafe { std::slice::from_raw_parts_mut(output, capacity) };
+        match input.split_last() {
+            Some((&0xaa, payload)) => {
+                for (o, i) in output.iter_mut().zip(payload) {
+                    *o = i ^ key;
+                }
+                payload.len() as isize
+            }
+            _ => -1,
+        }
+    }
+
+    extern "C" fn fail(
+        _ctx: *mut c_void,
+        _input: *const u8,
+        _len: usize,
+        _output: *mut u8,
+        _capacity: usize,
+    ) -> isize {
+        -1
+    }
+
+    // copies the public key into the buffer passed as context
+    extern "C" fn copy_key(ctx: *mut c_void, public_key: *const u8) {
+        unsafe { std::ptr::copy_nonoverlapping(public_key, ctx as *mut u8, 32) };
+    }
+
+    #[test]
+    fn test_peer_handler() {
+        let mut key = [0u8; 32];
+        let pk = PublicKey::from([7u8; 32]);
+        let handler = peer_handler(copy_key, key.as_mut_ptr() as *mut c_void);
+        handler(&pk);
+        assert_eq!(key, [7u8; 32]);
+    }
+
+    #[test]
+    fn test_callback_obfuscator() {
+        static KEY: u8 = 0x5a;
+        let obfuscator = CallbackObfuscator {
+            wrap: xor_wrap,
+            unwrap: xor_unwrap,
+            ctx: Context(&KEY as *const u8 as *mut c_void),
+        };
+        let wrapped = obfuscator.wrap(&[1, 2, 3]);
+        assert_eq!(wrapped, vec![0x5b, 0x58, 0x59, 0xaa]);
+        assert_eq!(obfuscator.unwrap(wrapped), Some(vec![1, 2, 3]));
+        assert_eq!(obfuscator.unwrap(vec![1, 2, 3]), None);
+
+        // failures drop the datagram (outbound: never sent in the clear)
+        let failing = CallbackObfuscator {
+            wrap: fail,
+            unwrap: fail,
+            ctx: Context(std::ptr::null_mut()),
+        };
+        assert!(failing.wrap(&[1, 2, 3]).is_empty());
+        assert_eq!(failing.unwrap(vec![1, 2, 3]), None);
+    }
+
+    #[test]
+    fn test_set_protocol() {
+        let construction = CString::new("Noise_IKpsk2_25519_ChaChaPoly_BLAKE2s").unwrap();
+        let identifier = CString::new("Private network v1").unwrap();
+        assert_eq!(
+            unsafe { wg_set_protocol(construction.as_ptr(), identifier.as_ptr()) },
+            0
+        );
+        assert_eq!(
+            device_config().protocol,
+            Protocol::new(construction.as_bytes(), identifier.as_bytes())
+        );
+
+        // a null construction restores standard WireGuard
+        assert_eq!(
+            unsafe { wg_set_protocol(std::ptr::null(), std::ptr::null()) },
+            0
+        );
+        assert_eq!(device_config().protocol, Protocol::default());
+        assert_eq!(
+            unsafe { wg_set_protocol(construction.as_ptr(), std::ptr::null()) },
+            -libc::EINVAL
+        );
+    }
+}
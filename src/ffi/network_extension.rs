@@ -0,0 +1,336 @@
+/* C-ABI for embedding the device in a packet tunnel provider (NetworkExtension on iOS / macOS)
+ *
+ * The packets are exchanged with the NEPacketTunnelFlow through the application:
+ *
+ * - wg_turn_on / wg_turn_off: start / stop a device, identified by a handle.
+ * - wg_send_packets: pass the packets obtained from readPackets to the device.
+ * - the write_packets callback (of wg_turn_on): deliver packets with writePackets.
+ * - wg_set_config / wg_get_config: the UAPI "set" / "get" operations.
+ * - wg_export_config: the configuration as a configuration file (for backup / migration).
+ * - wg_sleep / wg_wake: the sleep / wake handlers of the provider.
+ * - wg_rebind: reopen the sockets after a change of network path.
+ * - wg_set_obfuscator: obfuscate the datagrams with callbacks of the application.
+ * - wg_set_expiry_callback: notify the application of the removal of time-limited peers.
+ * - wg_set_key_confirmed_callback: notify the application of confirmed sessions with peers.
+ */
+
+use super::super::configuration::{Configuration, WireGuardConfig};
+use super::super::platform::packet_flow::{PacketFlow, PacketFlowTun};
+use super::super::platform::plt;
+use super::super::platform::tun::{Status, TunEvent};
+use super::super::wireguard::WireGuard;
+use super::{
+    device_config, export_config, get_config, set_config, set_expiry_callback,
+    set_key_confirmed_callback, set_obfuscator, string, Context, Handles, ObfuscateCallback,
+    PeerCallback,
+};
+
+use std::os::raw::{c_char, c_int, c_void};
+use std::slice;
+use std::sync::Arc;
+use std::thread;
+
+/// Delivers a batch of packets (and their protocol families: AF_INET / AF_INET6)
+pub type WritePacketsCallback = extern "C" fn(
+    ctx: *mut c_void,
+    packets: *const *const u8,
+    lengths: *const usize,
+    protocols: *const c_int,
+    count: usize,
+);
+
+struct Tunnel {
+    cfg: WireGuardConfig<PacketFlowTun, plt::UDP>,
+    flow: Arc<PacketFlow>,
+}
+
+static TUNNELS: Handles<Tunnel> = Handles::new();
+
+// Internal function
+//
+// Protocol family of an IP packet (as expected by writePackets)
+fn protocol(packet: &[u8]) -> c_int {
+    match packet.first().map(|b| b >> 4) {
+        Some(6) => libc::AF_INET6,
+        _ => libc::AF_INET,
+    }
+}
+
+// Internal function
+//
+// Obtain the packet flow of the device identified by the handle
+fn flow(handle: c_int) -> Option<Arc<PacketFlow>> {
+    let mut flow = None;
+    TUNNELS.with(handle, |tunnel| {
+        flow = Some(tunnel.flow.clone());
+        0
+    });
+    flow
+}
+
+/// Start a device exchanging packets with the packet flow
+///
+/// # Arguments
+///
+/// - `settings`: The configuration ("key=value" lines of a UAPI set operation)
+/// - `mtu`: The MTU of the tunnel (as set in the NEPacketTunnelNetworkSettings)
+/// - `write_packets`: Callback delivering batches of packets to the flow
+/// - `ctx`: An opaque pointer passed to the callback
+///
+/// # Returns
+///
+/// A handle (non-negative) identifying the device or a negative errno.
+#[no_mangle]
+pub unsafe extern "C" fn wg_turn_on(
+    settings: *const c_char,
+    mtu: c_int,
+    write_packets: WritePacketsCallback,
+    ctx: *mut c_void,
+) -> c_int {
+    let settings = match string(settings) {
+        Some(settings) => settings,
+        None => return -libc::EINVAL,
+    };
+    if mtu <= 0 {
+        return -libc::EINVAL;
+    }
+
+    // create WireGuard device
+    let ctx = Context(ctx);
+    let (mut readers, writer, mut status, flow) = PacketFlowTun::create(
+        mtu as usize,
+        Box::new(move |batch: &[Vec<u8>]| {
+            let packets: Vec<*const u8> = batch.iter().map(|packet| packet.as_ptr()).collect();
+            let lengths: Vec<usize> = batch.iter().map(|packet| packet.len()).collect();
+            let protocols: Vec<c_int> = batch.iter().map(|packet| protocol(packet)).collect();
+            write_packets(
+                ctx.0,
+                packets.as_ptr(),
+                lengths.as_ptr(),
+                protocols.as_ptr(),
+                batch.len(),
+            );
+        }),
+    );
+    let wg: WireGuard<PacketFlowTun, plt::UDP> = WireGuard::with_config(writer, device_config());
+    while let Some(reader) = readers.pop() {
+        wg.add_tun_reader(reader);
+    }
+
+    // configure
+    let cfg = WireGuardConfig::new(wg);
+    let res = set_config(&cfg, settings);
+    if res != 0 {
+        return res;
+    }
+
+    // start event thread (bring up and sleep / wake)
+    {
+        let cfg = cfg.clone();
+        thread::spawn(move || loop {
+            match status.event() {
+                Err(_) => {
+                    log::debug!("packet flow closed");
+                    break;
+                }
+                Ok(TunEvent::Up(mtu)) => {
+                    log::info!("packet flow up (mtu = {})", mtu);
+                    let _ = cfg.up(mtu);
+                }
+                Ok(TunEvent::Down) => {
+                    log::info!("packet flow down");
+                    cfg.down();
+                }
+            }
+        });
+    }
+    TUNNELS.insert(Tunnel {
+        cfg,
+        flow: Arc::new(flow),
+    })
+}
+
+/// Stop the device identified by the handle
+#[no_mangle]
+pub extern "C" fn wg_turn_off(handle: c_int) {
+    if let Some(tunnel) = TUNNELS.remove(handle) {
+        tunnel.cfg.down();
+        tunnel.cfg.replace_peers();
+    }
+}
+
+/// Pass a batch of packets obtained from the flow (readPackets) to the device
+///
+/// # Arguments
+///
+/// - `packets`: The packets (pointers to their first byte)
+/// - `lengths`: The length of each packet
+/// - `count`: The number of packets
+///
+/// # Returns
+///
+/// Zero on success or a negative errno.
+#[no_mangle]
+pub unsafe extern "C" fn wg_send_packets(
+    handle: c_int,
+    packets: *const *const u8,
+    lengths: *const usize,
+    count: usize,
+) -> c_int {
+    if count > 0 && (packets.is_null() || lengths.is_null()) {
+        return -libc::EINVAL;
+    }
+    let flow = match flow(handle) {
+        Some(flow) => flow,
+        None => return -libc::EBADF,
+    };
+    let packets = (0..count)
+        .map(|i| slice::from_raw_parts(*packets.add(i), *lengths.add(i)).to_owned())
+        .collect::<Vec<_>>();
+    match flow.send_packets(packets) {
+        Ok(()) => 0,
+        Err(_) => -libc::EPIPE,
+    }
+}
+
+/// Apply settings ("key=value" lines of a UAPI set operation) to a running device
+///
+/// # Returns
+///
+/// Zero on success or a negative errno.
+#[no_mangle]
+pub unsafe extern "C" fn wg_set_config(handle: c_int, settings: *const c_char) -> c_int {
+    match string(settings) {
+        Some(settings) => TUNNELS.with(handle, |tunnel| set_config(&tunnel.cfg, settings)),
+        None => -libc::EINVAL,
+    }
+}
+
+/// Obtain the configuration (the response of a UAPI get operation)
+///
+/// # Returns
+///
+/// A string to be released with wg_free_string, or null if the handle is invalid.
+#[no_mangle]
+pub extern "C" fn wg_get_config(handle: c_int) -> *mut c_char {
+    let mut config = std::ptr::null_mut();
+    TUNNELS.with(handle, |tunnel| {
+        config = get_config(&tunnel.cfg);
+        0
+    });
+    config
+}
+
+/// Export the configuration, including the endpoints learned at runtime,
+/// in the [Interface] / [Peer] format of configuration files
+///
+/// # Returns
+///
+/// A string to be released with wg_free_string, or null if the handle is invalid.
+#[no_mangle]
+pub extern "C" fn wg_export_config(handle: c_int) -> *mut c_char {
+    let mut config = std::ptr::null_mut();
+    TUNNELS.with(handle, |tunnel| {
+        config = export_config(&tunnel.cfg);
+        0
+    });
+    config
+}
+
+/// The system is going to sleep (sleepWithCompletionHandler): close the sockets
+#[no_mangle]
+pub extern "C" fn wg_sleep(handle: c_int) {
+    if let Some(flow) = flow(handle) {
+        flow.sleep();
+    }
+}
+
+/// The system has woken (wake): reopen the sockets
+#[no_mangle]
+pub extern "C" fn wg_wake(handle: c_int) {
+    if let Some(flow) = flow(handle) {
+        flow.wake();
+    }
+}
+
+/// Reopen the sockets of the device after a change of network path
+///
+/// # Returns
+///
+/// Zero on success or a negative errno.
+#[no_mangle]
+pub extern "C" fn wg_rebind(handle: c_int) -> c_int {
+    TUNNELS.with(handle, |tunnel| match tunnel.cfg.rebind(None) {
+        Ok(()) => 0,
+        Err(e) => -e.errno(),
+    })
+}
+
+/// Install the callbacks obfuscating the datagrams of the device (see ObfuscateCallback)
+///
+/// # Arguments
+///
+/// - `wrap`: Callback transforming every outbound message (or null to remove the obfuscator)
+/// - `unwrap`: Callback reversing the transformation of every received datagram (or null)
+/// - `ctx`: An opaque pointer passed to the callbacks
+///
+/// # Returns
+///
+/// Zero on success or a negative errno.
+#[no_mangle]
+pub extern "C" fn wg_set_obfuscator(
+    handle: c_int,
+    wrap: Option<ObfuscateCallback>,
+    unwrap: Option<ObfuscateCallback>,
+    ctx: *mut c_void,
+) -> c_int {
+    TUNNELS.with(handle, |tunnel| {
+        set_obfuscator(&tunnel.cfg, wrap, unwrap, ctx)
+    })
+}
+
+/// Install the callback invoked when a time-limited peer (see the "expiry" key)
+/// has expired and been removed (see PeerCallback)
+///
+/// # Arguments
+///
+/// - `callback`: The callback (or null to remove it)
+/// - `ctx`: An opaque pointer passed to the callback
+///
+/// # Returns
+///
+/// Zero on success or a negative errno.
+#[no_mangle]
+pub extern "C" fn wg_set_expiry_callback(
+    handle: c_int,
+    callback: Option<PeerCallback>,
+    ctx: *mut c_void,
+) -> c_int {
+    TUNNELS.with(handle, |tunnel| {
+        set_expiry_callback(&tunnel.cfg, callback, ctx);
+        0
+    })
+}
+
+/// Install the callback invoked when a new key-pair with a peer is confirmed,
+/// e.g. to report the tunnel as connected (see PeerCallback)
+///
+/// # Arguments
+///
+/// - `callback`: The callback (or null to remove it)
+/// - `ctx`: An opaque pointer passed to the callback
+///
+/// # Returns
+///
+/// Zero on success or a negative errno.
+#[no_mangle]
+pub extern "C" fn wg_set_key_confirmed_callback(
+    handle: c_int,
+    callback: Option<PeerCallback>,
+    ctx: *mut c_void,
+) -> c_int {
+    TUNNELS.with(handle, |tunnel| {
+        set_key_confirmed_callback(&tunnel.cfg, callback, ctx);
+        0
+    })
+}
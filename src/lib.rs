@@ -0,0 +1,16 @@
+/* Library for embedding the device in an application,
+ * the daemon (main.rs) is built on top of the library.
+ *
+ * The library is built on every platform,
+ * the C-ABI (ffi) is exported on Android, iOS and macOS (on top of the UNIX helpers in ffi/mod.rs).
+ */
+#![cfg_attr(feature = "unstable", feature(test))]
+
+extern crate alloc;
+
+pub mod configuration;
+pub mod platform;
+pub mod wireguard;
+
+#[cfg(unix)]
+pub mod ffi;
@@ -1,30 +1,32 @@
-#![cfg_attr(feature = "unstable", feature(test))]
-
-extern crate alloc;
-
 #[cfg(feature = "profiler")]
 extern crate cpuprofiler;
 
 #[cfg(feature = "profiler")]
 use cpuprofiler::PROFILER;
 
-mod configuration;
-mod platform;
-mod wireguard;
-
+mod cli;
 mod util;
 
+use wireguard_rs::{configuration, platform, wireguard};
+
 use std::env;
+use std::fs;
+use std::io;
+#[cfg(feature = "management-api")]
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::thread;
+use std::time::Duration;
 
 use configuration::Configuration;
 
 use platform::tun::{PlatformTun, Status};
 use platform::uapi::{BindUAPI, PlatformUAPI};
+use platform::udp::{Owner, PlatformUDP};
 use platform::*;
 
-use wireguard::WireGuard;
+use wireguard::{TcpProxy, WireGuard, MAX_MTU};
 
 #[cfg(feature = "profiler")]
 fn profiler_stop() {
@@ -35,6 +37,39 @@ fn profiler_stop() {
 #[cfg(not(feature = "profiler"))]
 fn profiler_stop() {}
 
+// Internal function
+//
+// Restore the handshake timestamp counter persisted by a previous run (see --timestamp-file),
+// such that the protection against a clock stepped backwards survives a restart
+fn restore_timestamp(path: &Path) {
+    match fs::read(path) {
+        Ok(bytes) if bytes.len() == 12 => {
+            let mut ts: wireguard::handshake::TAI64N = [0u8; 12];
+            ts.copy_from_slice(&bytes);
+            wireguard::handshake::restore_counter(&ts);
+        }
+        Ok(_) => log::warn!("Invalid timestamp counter file {}", path.display()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => log::warn!("Failed to read {}: {}", path.display(), e),
+    }
+}
+
+// Internal function
+//
+// Stop the profiler and persist the handshake timestamp counter before exiting
+fn shutdown(timestamp_file: Option<&Path>) {
+    if let Some(path) = timestamp_file {
+        if let Err(e) = fs::write(path, wireguard::handshake::save_counter()) {
+            log::warn!(
+                "Failed to save the timestamp counter to {}: {}",
+                path.display(),
+                e
+            );
+        }
+    }
+    profiler_stop();
+}
+
 #[cfg(feature = "profiler")]
 fn profiler_start(name: &str) {
     use std::path::Path;
@@ -53,14 +88,56 @@ fn profiler_start(name: &str) {
 }
 
 fn main() {
+    // skip path (argv[0])
+    let mut args: Vec<String> = env::args().skip(1).collect();
+
+    // subcommands (see cli.rs)
+    match args.first().map(|arg| arg.as_str()) {
+        Some("show") | Some("showconf") | Some("set") | Some("down") | Some("genkey")
+        | Some("genpsk") | Some("pubkey") | Some("vanity") | Some("derive") | Some("signer")
+        | Some("keyring") => {
+            if let Err(e) = cli::run(&args) {
+                eprintln!("{}", e);
+                exit(1);
+            }
+            exit(0);
+        }
+        Some("up") => {
+            args = cli::up(&args[1..]).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                exit(1);
+            });
+        }
+        _ => (),
+    }
+
     // parse command line arguments
     let mut name = None;
-    let mut drop_privileges = true;
-    let mut foreground = false;
-    let mut args = env::args();
-
-    // skip path (argv[0])
-    args.next();
+    let mut drop_privileges = cfg!(unix);
+    let mut foreground = !cfg!(unix);
+    let mut udp_workers = 1;
+    let mut interface = None;
+    let mut ttl = None;
+    let mut dscp = None;
+    let mut tuning = wireguard::DeviceConfig::default();
+    let mut tcp_listen = false;
+    let mut tcp_proxy = None;
+    let mut mtu = None;
+    let mut tun_queues = 1;
+    let mut tun_offload = false;
+    let mut lock_secrets = false;
+    let mut owner = (None, None);
+    let mut config = None;
+    let mut config_path = None;
+    let mut timestamp_file: Option<PathBuf> = None;
+    #[cfg(feature = "structured-config")]
+    let mut device_config = None;
+    #[cfg(feature = "management-api")]
+    let mut management = (None, None);
+    #[cfg(unix)]
+    let mut signer = None;
+    #[cfg(feature = "keyring")]
+    let mut keyring = false;
     for arg in args {
         match arg.as_str() {
             "--foreground" | "-f" => {
@@ -69,6 +146,199 @@ fn main() {
             "--disable-drop-privileges" => {
                 drop_privileges = false;
             }
+            "--tun-offload" => {
+                tun_offload = true;
+            }
+            "--tcp-listen" => {
+                tcp_listen = true;
+            }
+            "--lock-secrets" => {
+                lock_secrets = true;
+            }
+            #[cfg(feature = "keyring")]
+            "--keyring" => {
+                keyring = true;
+            }
+            arg if arg.starts_with("--udp-workers=") => {
+                udp_workers = arg["--udp-workers=".len()..].parse().unwrap_or_else(|_| {
+                    eprintln!("Invalid number of UDP workers: {}", arg);
+                    exit(-1);
+                });
+            }
+            arg if arg.starts_with("--bind-interface=") => {
+                interface = Some(arg["--bind-interface=".len()..].to_owned());
+            }
+            arg if arg.starts_with("--tcp-proxy=") => {
+                let value = arg["--tcp-proxy=".len()..].parse::<TcpProxy>();
+                tcp_proxy = Some(value.unwrap_or_else(|e| {
+                    eprintln!("Invalid TCP proxy ({}): {}", e, arg);
+                    exit(-1);
+                }));
+            }
+            arg if arg.starts_with("--ttl=") => {
+                let value = arg["--ttl=".len()..].parse().ok().filter(|ttl| *ttl > 0);
+                ttl = Some(value.unwrap_or_else(|| {
+                    eprintln!("Invalid TTL (1-255): {}", arg);
+                    exit(-1);
+                }));
+            }
+            arg if arg.starts_with("--mtu=") => {
+                let value = arg["--mtu=".len()..]
+                    .parse()
+                    .ok()
+                    .filter(|mtu| *mtu >= 576 && *mtu <= MAX_MTU);
+                mtu = Some(value.unwrap_or_else(|| {
+                    eprintln!("Invalid MTU (576-{}): {}", MAX_MTU, arg);
+                    exit(-1);
+                }));
+            }
+            arg if arg.starts_with("--tun-queues=") => {
+                let value = arg["--tun-queues=".len()..].parse().ok().filter(|n| *n > 0);
+                tun_queues = value.unwrap_or_else(|| {
+                    eprintln!("Invalid number of TUN queues: {}", arg);
+                    exit(-1);
+                });
+            }
+            arg if arg.starts_with("--tun-owner=") => {
+                // "uid" or "uid:gid"
+                let mut ids = arg["--tun-owner=".len()..].splitn(2, ':');
+                let mut parse = || ids.next().map(|id| id.parse::<u32>());
+                owner = match (parse(), parse()) {
+                    (Some(Ok(uid)), None) => (Some(uid), None),
+                    (Some(Ok(uid)), Some(Ok(gid))) => (Some(uid), Some(gid)),
+                    _ => {
+                        eprintln!("Invalid TUN owner (uid[:gid]): {}", arg);
+                        exit(-1);
+                    }
+                };
+            }
+            arg if arg.starts_with("--dscp=") => {
+                let value = arg["--dscp=".len()..]
+                    .parse()
+                    .ok()
+                    .filter(|dscp| *dscp < 64);
+                dscp = Some(value.unwrap_or_else(|| {
+                    eprintln!("Invalid DSCP (0-63): {}", arg);
+                    exit(-1);
+                }));
+            }
+            arg if arg.starts_with("--replay-window=") => {
+                let value = arg["--replay-window=".len()..].parse();
+                tuning.replay_window = value.unwrap_or_else(|_| {
+                    eprintln!("Invalid replay window (packets): {}", arg);
+                    exit(-1);
+                });
+            }
+            arg if arg.starts_with("--initiation-flood-window=") => {
+                let value = arg["--initiation-flood-window=".len()..].parse();
+                tuning.handshake.initiation_flood_window =
+                    Duration::from_millis(value.unwrap_or_else(|_| {
+                        eprintln!("Invalid initiation flood window (milliseconds): {}", arg);
+                        exit(-1);
+                    }));
+            }
+            arg if arg.starts_with("--timestamp-tolerance=") => {
+                let value = arg["--timestamp-tolerance=".len()..].parse();
+                tuning.handshake.timestamp_tolerance =
+                    Duration::from_millis(value.unwrap_or_else(|_| {
+                        eprintln!("Invalid timestamp tolerance (milliseconds): {}", arg);
+                        exit(-1);
+                    }));
+            }
+            arg if arg.starts_with("--reserved-bytes=") => {
+                let value = wireguard::ReservedBytes::from_name(&arg["--reserved-bytes=".len()..]);
+                tuning.handshake.reserved = value.unwrap_or_else(|| {
+                    eprintln!("Invalid reserved bytes (strict|ignore|<hex>): {}", arg);
+                    exit(-1);
+                });
+            }
+            arg if arg.starts_with("--max-peers=") => {
+                let value = arg["--max-peers=".len()..].parse().ok().filter(|n| *n > 0);
+                tuning.handshake.max_peers = value.unwrap_or_else(|| {
+                    eprintln!("Invalid maximum number of peers: {}", arg);
+                    exit(-1);
+                });
+            }
+            arg if arg.starts_with("--eviction=") => {
+                tuning.handshake.eviction = match &arg["--eviction=".len()..] {
+                    "reject" => wireguard::EvictionPolicy::Reject,
+                    "oldest-handshake" => wireguard::EvictionPolicy::OldestHandshake,
+                    _ => {
+                        eprintln!("Invalid eviction policy (reject|oldest-handshake): {}", arg);
+                        exit(-1);
+                    }
+                };
+            }
+            arg if arg.starts_with("--timestamp-file=") => {
+                // must remain writable (e.g. owned by the user privileges are dropped to)
+                timestamp_file = Some(arg["--timestamp-file=".len()..].into());
+            }
+            arg if arg.starts_with("--protocol=") => {
+                // "construction:identifier" (incompatible with standard WireGuard)
+                let mut strings = arg["--protocol=".len()..].splitn(2, ':');
+                tuning.protocol = match (strings.next(), strings.next()) {
+                    (Some(construction), Some(identifier)) if !construction.is_empty() => {
+                        wireguard::Protocol::new(construction.as_bytes(), identifier.as_bytes())
+                    }
+                    _ => {
+                        eprintln!("Invalid protocol (construction:identifier): {}", arg);
+                        exit(-1);
+                    }
+                };
+            }
+            #[cfg(feature = "structured-config")]
+            arg if arg.starts_with("--config=")
+                && (arg.ends_with(".toml") || arg.ends_with(".json")) =>
+            {
+                // structured configuration file
+                let path = &arg["--config=".len()..];
+                device_config = Some(configuration::DeviceConfig::load(path).unwrap_or_else(|e| {
+                    eprintln!("Invalid configuration file ({}): {}", e, path);
+                    exit(-1);
+                }));
+            }
+            #[cfg(feature = "management-api")]
+            arg if arg.starts_with("--management-listen=") => {
+                let value = arg["--management-listen=".len()..].parse::<SocketAddr>();
+                management.0 = Some(value.unwrap_or_else(|_| {
+                    eprintln!("Invalid management API address: {}", arg);
+                    exit(-1);
+                }));
+            }
+            #[cfg(feature = "management-api")]
+            arg if arg.starts_with("--management-token-file=") => {
+                let path = &arg["--management-token-file=".len()..];
+                let token = fs::read_to_string(path).unwrap_or_else(|e| {
+                    eprintln!("Failed to read management API token ({}): {}", e, path);
+                    exit(-1);
+                });
+                management.1 = Some(token.trim().to_owned()).filter(|token| !token.is_empty());
+            }
+            #[cfg(unix)]
+            arg if arg.starts_with("--signer=") => {
+                // the private key is held by a helper ("wireguard-rs signer"),
+                // connected before dropping privileges
+                let path = &arg["--signer=".len()..];
+                signer = Some(
+                    configuration::signer::Signer::connect(path).unwrap_or_else(|e| {
+                        eprintln!("Failed to connect to signer ({}): {}", e, path);
+                        exit(-1);
+                    }),
+                );
+            }
+            arg if arg.starts_with("--config=") => {
+                // wg-quick style configuration file
+                let path = &arg["--config=".len()..];
+                config = Some(configuration::ConfigFile::load(path).unwrap_or_else(|e| {
+                    eprintln!("Invalid configuration file ({}): {}", e, path);
+                    exit(-1);
+                }));
+                config_path = Some(
+                    Path::new(path)
+                        .canonicalize()
+                        .unwrap_or_else(|_| path.into()),
+                );
+            }
             dev => name = Some(dev.to_owned()),
         }
     }
@@ -82,18 +352,155 @@ fn main() {
         Some(name) => name,
     };
 
+    // lock the memory of the keys (before any key is loaded and privileges are dropped)
+    if lock_secrets {
+        if let Err(e) = wireguard::lock_secrets() {
+            eprintln!("Failed to lock memory of secrets: {}", e);
+        }
+    }
+
+    // layer the overrides from the environment (WG_RS_*) over the configuration file
+    let overrides = configuration::env::Overrides::from_vars(env::vars_os()).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(-1);
+    });
+    #[cfg(feature = "structured-config")]
+    let overlay_file = match device_config.as_mut() {
+        Some(device_config) => {
+            if let Err(e) = overrides.apply_structured(device_config) {
+                eprintln!("{}", e);
+                exit(-1);
+            }
+            false
+        }
+        None => true,
+    };
+    #[cfg(not(feature = "structured-config"))]
+    let overlay_file = true;
+    if overlay_file && !overrides.is_empty() {
+        if let Err(e) = overrides.apply(config.get_or_insert_with(Default::default)) {
+            eprintln!("{}", e);
+            exit(-1);
+        }
+    }
+
+    // the private key stored in the keyring of the operating system (for the interface)
+    #[cfg(feature = "keyring")]
+    {
+        if keyring {
+            let sk = configuration::keyring::load(&name).unwrap_or_else(|e| {
+                eprintln!("Failed to load private key of {}: {}", name, e);
+                exit(-1);
+            });
+            #[cfg(feature = "structured-config")]
+            {
+                if let Some(device_config) = device_config.as_mut() {
+                    device_config.private_key = Some(configuration::key::Key(sk.to_bytes()));
+                }
+            }
+            if overlay_file {
+                config
+                    .get_or_insert_with(Default::default)
+                    .interface
+                    .private_key = Some(sk);
+            }
+        }
+    }
+
+    // the private key is either held by the signer or configured
+    #[cfg(unix)]
+    {
+        let configured = config
+            .as_ref()
+            .map(|file| file.interface.private_key.is_some())
+            .unwrap_or(false);
+        #[cfg(feature = "structured-config")]
+        let configured = configured
+            || device_config
+                .as_ref()
+                .map(|config| config.private_key.is_some())
+                .unwrap_or(false);
+        if signer.is_some() && configured {
+            eprintln!("A private key is configured along with a signer");
+            exit(-1);
+        }
+    }
+
+    // remote access to the management API requires a token
+    #[cfg(feature = "management-api")]
+    {
+        if let (Some(addr), None) = (management.0, management.1.as_ref()) {
+            if !addr.ip().is_loopback() {
+                eprintln!("The management API requires a token on a non-loopback address");
+                exit(-1);
+            }
+        }
+    }
+
+    // reload the configuration file on SIGHUP (received by a dedicated thread)
+    #[cfg(unix)]
+    {
+        if config_path.is_some() {
+            util::block_hangup();
+        }
+    }
+
+    // create TUN device
+    // (a reader thread per queue and segmentation offloading, if supported by the platform)
+    let tun = if tun_offload {
+        plt::Tun::create_offload(name.as_str(), tun_queues)
+    } else {
+        plt::Tun::create_multiqueue(name.as_str(), tun_queues)
+    };
+    let (mut readers, writer, status) = tun.unwrap_or_else(|e| {
+        eprintln!("Failed to create TUN device: {}", e);
+        exit(-3);
+    });
+
+    // configure TUN device (requires privileges)
+    let mtu = mtu.or_else(|| config.as_ref().and_then(|file| file.interface.mtu));
+    #[cfg(feature = "structured-config")]
+    let mtu = mtu.or_else(|| device_config.as_ref().and_then(|config| config.mtu));
+    if let Some(mtu) = mtu {
+        if let Err(e) = status.set_mtu(mtu) {
+            eprintln!("Failed to set MTU of TUN device: {}", e);
+            exit(-3);
+        }
+    }
+    if let Err(e) = status.set_owner(owner.0, owner.1) {
+        eprintln!("Failed to set owner of TUN device: {}", e);
+        exit(-3);
+    }
+
+    // the kernel may assign the name (e.g. "utun" on macOS)
+    let name = status.name();
+
     // create UAPI socket
     let uapi = plt::UAPI::bind(name.as_str()).unwrap_or_else(|e| {
         eprintln!("Failed to create UAPI listener: {}", e);
         exit(-2);
     });
 
-    // create TUN device
-    let (mut readers, writer, status) = plt::Tun::create(name.as_str()).unwrap_or_else(|e| {
-        eprintln!("Failed to create TUN device: {}", e);
-        exit(-3);
+    // create management API listener
+    #[cfg(feature = "management-api")]
+    let management = management.0.map(|addr| {
+        let listener = std::net::TcpListener::bind(addr).unwrap_or_else(|e| {
+            eprintln!("Failed to create management API listener: {}", e);
+            exit(-2);
+        });
+        (listener, management.1)
     });
 
+    // validate the interface while privileged: binding a socket to a device may require CAP_NET_RAW
+    // (the sockets are bound again by the device, unprivileged binding requires Linux 5.7 or later)
+    if let Some(name) = interface.as_ref() {
+        let probe = plt::UDP::bind(0).and_then(|(_, _, mut owner)| owner.set_interface(Some(name)));
+        if let Err(e) = probe {
+            eprintln!("Failed to bind to interface {}: {}", name, e);
+            exit(-6);
+        }
+    }
+
     // drop privileges
     if drop_privileges {
         match util::drop_privileges() {
@@ -127,31 +534,141 @@ fn main() {
     #[cfg(feature = "profiler")]
     profiler_start(name.as_str());
 
+    // restore the timestamp counter before any initiation is created
+    if let Some(path) = timestamp_file.as_ref() {
+        restore_timestamp(path);
+    }
+
     // create WireGuard device
-    let wg: WireGuard<plt::Tun, plt::UDP> = WireGuard::new(writer);
+    let wg: WireGuard<plt::Tun, plt::UDP> = WireGuard::with_config(writer, tuning);
 
     // add all Tun readers
     while let Some(reader) = readers.pop() {
         wg.add_tun_reader(reader);
     }
 
+    // time-limited peers are removed by the device
+    wg.set_expiry_handler(Some(Box::new(|pk| {
+        log::info!(
+            "Peer {} expired and was removed",
+            wireguard::base64(pk.as_bytes())
+        );
+    })));
+
     // wrap in configuration interface
     let cfg = configuration::WireGuardConfig::new(wg.clone());
+    cfg.set_udp_workers(udp_workers, udp_workers > 1);
+    cfg.set_dscp(dscp);
+    cfg.set_tcp_listen(tcp_listen);
+    cfg.set_tcp_proxy(tcp_proxy);
+    if let Err(e) = cfg.set_interface(interface.as_deref()) {
+        eprintln!("Failed to bind to interface: {}", e);
+        exit(-6);
+    }
+    if let Err(e) = cfg.set_ttl(ttl) {
+        eprintln!("Failed to set TTL: {}", e);
+        exit(-6);
+    }
+
+    // apply configuration file
+    #[cfg(feature = "structured-config")]
+    {
+        if let Some(config) = device_config {
+            if let Err(e) = config.apply(&cfg) {
+                eprintln!("Failed to apply configuration file: {}", e);
+                exit(-6);
+            }
+        }
+    }
+    // (addresses, DNS servers and routes are ignored: left to the network configuration of the host)
+    if let Some(file) = config {
+        if let Err(e) = file.apply(&cfg) {
+            eprintln!("Failed to apply configuration file: {}", e);
+            exit(-6);
+        }
+        for (ip, masklen) in file.interface.addresses.iter() {
+            log::info!(
+                "Address {}/{} ignored (to be assigned to {})",
+                ip,
+                masklen,
+                name
+            );
+        }
+        for dns in file.interface.dns.iter() {
+            log::info!("DNS {} ignored", dns);
+        }
+        for (ip, masklen) in file.routes() {
+            log::info!(
+                "Route {}/{} ignored (to be added through {})",
+                ip,
+                masklen,
+                name
+            );
+        }
+        if !file.interface.hooks.is_empty() {
+            log::warn!("PreUp / PostUp / PreDown / PostDown commands are not executed");
+        }
+    }
+
+    // the static key is held by the signer
+    #[cfg(unix)]
+    {
+        if let Some(signer) = signer {
+            wg.set_static_key(Some(Box::new(signer)));
+        }
+    }
+
+    // start management API server
+    #[cfg(feature = "management-api")]
+    {
+        if let Some((listener, token)) = management {
+            if let Ok(addr) = listener.local_addr() {
+                log::info!("Management API listening on {}", addr);
+            }
+            configuration::management::serve(listener, cfg.clone(), token);
+        }
+    }
+
+    // apply the changes to the configuration file on SIGHUP, retaining the unchanged peers
+    // (the file must remain readable, e.g. with --disable-drop-privileges)
+    #[cfg(unix)]
+    {
+        if let Some(path) = config_path {
+            let cfg = cfg.clone();
+            thread::spawn(move || loop {
+                util::wait_hangup();
+                log::info!("Reloading configuration file {}", path.display());
+                match configuration::ConfigFile::load(&path) {
+                    Ok(mut file) => {
+                        if let Err(e) = overrides.apply(&mut file) {
+                            log::warn!("Failed to reload configuration file: {}", e);
+                        } else if let Err(e) = cfg.apply_config(&file) {
+                            log::warn!("Failed to apply configuration file: {}", e);
+                        }
+                    }
+                    Err(e) => log::warn!("Failed to reload configuration file: {}", e),
+                }
+            });
+        }
+    }
 
     // start Tun event thread
     {
         let cfg = cfg.clone();
+        let timestamp_file = timestamp_file.clone();
         let mut status = status;
         thread::spawn(move || loop {
             match status.event() {
                 Err(e) => {
                     log::info!("Tun device error {}", e);
-                    profiler_stop();
+                    shutdown(timestamp_file.as_deref());
                     exit(0);
                 }
                 Ok(tun::TunEvent::Up(mtu)) => {
                     log::info!("Tun up (mtu = {})", mtu);
-                    let _ = cfg.up(mtu); // TODO: handle
+                    if let Err(e) = cfg.up(mtu) {
+                        log::error!("Failed to bring up the device: {}", e);
+                    }
                 }
                 Ok(tun::TunEvent::Down) => {
                     log::info!("Tun down");
@@ -161,25 +678,45 @@ fn main() {
         });
     }
 
-    // start UAPI server
-    thread::spawn(move || loop {
-        // accept and handle UAPI config connections
-        match uapi.connect() {
-            Ok(mut stream) => {
-                let cfg = cfg.clone();
-                thread::spawn(move || {
-                    configuration::uapi::handle(&mut stream, &cfg);
-                });
+    // shut down when the control socket is removed (e.g. by "wireguard-rs down")
+    #[cfg(unix)]
+    {
+        let name = name.clone();
+        let timestamp_file = timestamp_file.clone();
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(1));
+            if let Ok(names) = plt::UAPI::list() {
+                if !names.contains(&name) {
+                    log::info!("Control socket removed");
+                    shutdown(timestamp_file.as_deref());
+                    exit(0);
+                }
             }
-            Err(err) => {
-                log::info!("UAPI connection error: {}", err);
-                profiler_stop();
-                exit(-1);
+        });
+    }
+
+    // start UAPI server
+    {
+        let timestamp_file = timestamp_file.clone();
+        thread::spawn(move || loop {
+            // accept and handle UAPI config connections
+            match uapi.connect() {
+                Ok(mut stream) => {
+                    let cfg = cfg.clone();
+                    thread::spawn(move || {
+                        configuration::uapi::handle(&mut stream, &cfg);
+                    });
+                }
+                Err(err) => {
+                    log::info!("UAPI connection error: {}", err);
+                    shutdown(timestamp_file.as_deref());
+                    exit(-1);
+                }
             }
-        }
-    });
+        });
+    }
 
     // block until all tun readers closed
     wg.wait();
-    profiler_stop();
+    shutdown(timestamp_file.as_deref());
 }
@@ -0,0 +1,7 @@
+mod tun;
+mod uapi;
+mod udp;
+
+pub use tun::BsdTun as Tun;
+pub use uapi::BsdUAPI as UAPI;
+pub use udp::BsdUDP as UDP;
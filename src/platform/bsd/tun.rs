@@ -0,0 +1,459 @@
+use super::super::tun::*;
+
+use std::error::Error;
+use std::ffi::CString;
+use std::fmt;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::ptr;
+
+// every packet read from / written to a tun(4) device is prefixed by its address family
+// (always on OpenBSD, after TUNSIFHEAD on FreeBSD)
+const SIZE_AF_HEADER: usize = 4;
+
+// ioctls on an ifreq (identical layout on FreeBSD and OpenBSD)
+const SIOCGIFMTU: libc::c_ulong = 0xc020_6933;
+const SIOCSIFMTU: libc::c_ulong = 0x8020_6934;
+
+// tun(4) ioctls (FreeBSD only)
+#[cfg(target_os = "freebsd")]
+const TUNSIFHEAD: libc::c_ulong = 0x8004_7460;
+#[cfg(target_os = "freebsd")]
+const TUNGIFNAME: libc::c_ulong = 0x4020_745d;
+
+// routing message type announcing a change of interface state
+const RTM_IFINFO: u8 = 0xe;
+
+// OpenBSD has no cloning device, hence units up to MAX_UNIT are probed in order
+#[cfg(target_os = "openbsd")]
+const MAX_UNIT: u32 = 255;
+
+#[repr(C)]
+struct IfreqMTU {
+    name: [u8; libc::IFNAMSIZ],
+    mtu: libc::c_int,
+    _pad: [u8; 12],
+}
+
+// Prefix of struct if_msghdr
+// Layout from: https://cgit.freebsd.org/src/tree/sys/net/if.h
+#[cfg(target_os = "freebsd")]
+#[allow(dead_code)]
+#[repr(C)]
+struct IfMsghdr {
+    ifm_msglen: libc::c_ushort,
+    ifm_version: libc::c_uchar,
+    ifm_type: libc::c_uchar,
+    ifm_addrs: libc::c_int,
+    ifm_flags: libc::c_int,
+    ifm_index: libc::c_ushort,
+}
+
+// Prefix of struct if_msghdr
+// Layout from: https://cvsweb.openbsd.org/src/sys/net/if.h
+#[cfg(target_os = "openbsd")]
+#[allow(dead_code)]
+#[repr(C)]
+struct IfMsghdr {
+    ifm_msglen: libc::c_ushort,
+    ifm_version: libc::c_uchar,
+    ifm_type: libc::c_uchar,
+    ifm_hdrlen: libc::c_ushort,
+    ifm_index: libc::c_ushort,
+    ifm_tableid: libc::c_ushort,
+    ifm_pad1: libc::c_uchar,
+    ifm_pad2: libc::c_uchar,
+    ifm_addrs: libc::c_int,
+    ifm_flags: libc::c_int,
+}
+
+pub struct BsdTun {}
+
+pub struct BsdTunReader {
+    fd: RawFd,
+}
+
+pub struct BsdTunWriter {
+    fd: RawFd,
+}
+
+pub struct BsdTunStatus {
+    events: Vec<TunEvent>,
+    mtu: Option<usize>, // MTU of the last reported up event (None if down)
+    index: libc::c_ushort,
+    name: [u8; libc::IFNAMSIZ],
+    fd: RawFd,  // routing socket
+    tun: RawFd, // fd of the tun device (for configuration)
+}
+
+#[derive(Debug)]
+pub enum BsdTunError {
+    InvalidTunDeviceName,
+    FailedToOpenTunDevice,
+    SetHeadIoctlFailed,
+    GetNameIoctlFailed,
+    GetMTUIoctlFailed,
+    SetMTUIoctlFailed,
+    SetOwnerFailed,
+    RoutingSocketFailure,
+    Closed,
+}
+
+impl fmt::Display for BsdTunError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BsdTunError::InvalidTunDeviceName => {
+                write!(f, "Invalid name (must be \"tun\" or \"tun[0-9]+\")")
+            }
+            BsdTunError::FailedToOpenTunDevice => {
+                write!(f, "Failed to open tun device (device in use?)")
+            }
+            BsdTunError::SetHeadIoctlFailed => write!(f, "tunsifhead ioctl failed"),
+            BsdTunError::GetNameIoctlFailed => write!(f, "tungifname ioctl failed"),
+            BsdTunError::GetMTUIoctlFailed => write!(f, "ifmtu ioctl failed"),
+            BsdTunError::SetMTUIoctlFailed => {
+                write!(f, "set ifmtu ioctl failed (insufficient permissions?)")
+            }
+            BsdTunError::SetOwnerFailed => {
+                write!(
+                    f,
+                    "failed to change owner of tun device (insufficient permissions?)"
+                )
+            }
+            BsdTunError::RoutingSocketFailure => write!(f, "Routing socket listener error"),
+            BsdTunError::Closed => write!(f, "The tunnel has been closed"),
+        }
+    }
+}
+
+impl Error for BsdTunError {}
+
+impl Reader for BsdTunReader {
+    type Error = BsdTunError;
+
+    fn read(&self, buf: &mut [u8], offset: usize) -> Result<usize, Self::Error> {
+        // the address family header is read into the space reserved for the prefix
+        debug_assert!(
+            offset >= SIZE_AF_HEADER,
+            "There is no space for the address family header"
+        );
+        let start = offset - SIZE_AF_HEADER;
+        let n: isize =
+            unsafe { libc::read(self.fd, buf[start..].as_mut_ptr() as _, buf.len() - start) };
+        if n < SIZE_AF_HEADER as isize {
+            Err(BsdTunError::Closed)
+        } else {
+            // conversion is safe
+            Ok(n as usize - SIZE_AF_HEADER)
+        }
+    }
+}
+
+impl Writer for BsdTunWriter {
+    type Error = BsdTunError;
+
+    fn write(&self, src: &[u8]) -> Result<(), Self::Error> {
+        // prefix the address family (network byte order), based on the IP version
+        // (AF_INET6 differs between the BSDs, hence the value from libc)
+        let af = match src.first().map(|v| v >> 4) {
+            Some(6) => libc::AF_INET6,
+            _ => libc::AF_INET,
+        };
+        let header = (af as u32).to_be_bytes();
+        let iov = [
+            libc::iovec {
+                iov_base: header.as_ptr() as *mut libc::c_void,
+                iov_len: header.len(),
+            },
+            libc::iovec {
+                iov_base: src.as_ptr() as *mut libc::c_void,
+                iov_len: src.len(),
+            },
+        ];
+        match unsafe { libc::writev(self.fd, iov.as_ptr(), iov.len() as libc::c_int) } {
+            -1 => Err(BsdTunError::Closed),
+            _ => Ok(()),
+        }
+    }
+}
+
+// Internal function
+//
+// Issue an MTU ioctl (get or set) on the named interface
+fn ioctl_mtu(name: &[u8; libc::IFNAMSIZ], request: libc::c_ulong, mtu: usize) -> Result<usize, ()> {
+    debug_assert_eq!(
+        name[libc::IFNAMSIZ - 1],
+        0,
+        "name buffer not null-terminated"
+    );
+
+    // create socket
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+    if fd < 0 {
+        return Err(());
+    }
+
+    // do ioctl
+    let mut req = IfreqMTU {
+        name: *name,
+        mtu: mtu as libc::c_int,
+        _pad: [0u8; 12],
+    };
+    let err = unsafe { libc::ioctl(fd, request, &mut req as *mut IfreqMTU) };
+
+    // close socket
+    unsafe { libc::close(fd) };
+
+    if err != 0 {
+        Err(())
+    } else {
+        Ok(req.mtu as usize)
+    }
+}
+
+fn get_mtu(name: &[u8; libc::IFNAMSIZ]) -> Result<usize, BsdTunError> {
+    ioctl_mtu(name, SIOCGIFMTU, 0).map_err(|_| BsdTunError::GetMTUIoctlFailed)
+}
+
+fn set_mtu(name: &[u8; libc::IFNAMSIZ], mtu: usize) -> Result<(), BsdTunError> {
+    ioctl_mtu(name, SIOCSIFMTU, mtu)
+        .map(|_| ())
+        .map_err(|_| BsdTunError::SetMTUIoctlFailed)
+}
+
+// Internal function
+//
+// Parse the unit number of the requested device name:
+// "tun" selects a free unit, "tunN" requests unit N.
+fn parse_unit(name: &str) -> Option<Option<u32>> {
+    if !name.starts_with("tun") {
+        return None;
+    }
+    let unit = &name["tun".len()..];
+    if unit.is_empty() {
+        return Some(None);
+    }
+    if !unit.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    unit.parse::<u32>().ok().map(Some)
+}
+
+// Internal function
+//
+// Open the device node of a tun interface (read/write, close-on-exec)
+fn open_device(path: &str) -> Option<RawFd> {
+    let path = CString::new(path).ok()?;
+    match unsafe { libc::open(path.as_ptr(), libc::O_RDWR | libc::O_CLOEXEC) } {
+        -1 => None,
+        fd => Some(fd),
+    }
+}
+
+// Internal function
+//
+// Open the requested tun device (cloning a new unit if none was requested)
+// and return the fd together with the name of the interface.
+#[cfg(target_os = "freebsd")]
+fn open_tun(unit: Option<u32>) -> Result<(RawFd, [u8; libc::IFNAMSIZ]), BsdTunError> {
+    let path = match unit {
+        Some(unit) => format!("/dev/tun{}", unit),
+        None => "/dev/tun".to_owned(),
+    };
+    let fd = open_device(&path).ok_or(BsdTunError::FailedToOpenTunDevice)?;
+
+    // enable the address family header (multi-af mode)
+    let on: libc::c_int = 1;
+    if unsafe { libc::ioctl(fd, TUNSIFHEAD, &on as *const libc::c_int) } < 0 {
+        unsafe { libc::close(fd) };
+        return Err(BsdTunError::SetHeadIoctlFailed);
+    }
+
+    // discover the name of the interface (e.g. tun3)
+    let mut req = IfreqMTU {
+        name: [0u8; libc::IFNAMSIZ],
+        mtu: 0,
+        _pad: [0u8; 12],
+    };
+    if unsafe { libc::ioctl(fd, TUNGIFNAME, &mut req as *mut IfreqMTU) } < 0 {
+        unsafe { libc::close(fd) };
+        return Err(BsdTunError::GetNameIoctlFailed);
+    }
+    req.name[libc::IFNAMSIZ - 1] = 0;
+    Ok((fd, req.name))
+}
+
+// Internal function
+//
+// Open the requested tun device (probing for a free unit if none was requested)
+// and return the fd together with the name of the interface.
+#[cfg(target_os = "openbsd")]
+fn open_tun(unit: Option<u32>) -> Result<(RawFd, [u8; libc::IFNAMSIZ]), BsdTunError> {
+    let units = match unit {
+        Some(unit) => unit..=unit,
+        None => 0..=MAX_UNIT,
+    };
+    for unit in units {
+        // busy units (opened by another process) fail with EBUSY
+        if let Some(fd) = open_device(&format!("/dev/tun{}", unit)) {
+            let mut name = [0u8; libc::IFNAMSIZ];
+            let ifname = format!("tun{}", unit);
+            if ifname.len() >= libc::IFNAMSIZ {
+                unsafe { libc::close(fd) };
+                return Err(BsdTunError::InvalidTunDeviceName);
+            }
+            name[..ifname.len()].copy_from_slice(ifname.as_bytes());
+            return Ok((fd, name));
+        }
+    }
+    Err(BsdTunError::FailedToOpenTunDevice)
+}
+
+impl Status for BsdTunStatus {
+    type Error = BsdTunError;
+
+    fn event(&mut self) -> Result<TunEvent, Self::Error> {
+        const HDR_SIZE: usize = mem::size_of::<IfMsghdr>();
+
+        let mut buf = [0u8; 1 << 12];
+        log::debug!("routing socket, fetch event (fd = {})", self.fd);
+        loop {
+            // attempt to return a buffered event
+            if let Some(event) = self.events.pop() {
+                return Ok(event);
+            }
+
+            // read messages
+            let size: libc::ssize_t =
+                unsafe { libc::read(self.fd, buf.as_mut_ptr() as _, buf.len()) };
+            if size < 0 {
+                break Err(BsdTunError::RoutingSocketFailure);
+            }
+
+            // cut buffer to size
+            let size: usize = size as usize;
+            let mut remain = &buf[..size];
+            log::debug!("routing socket, received message ({} bytes)", size);
+
+            while remain.len() >= HDR_SIZE {
+                let hdr: IfMsghdr =
+                    unsafe { ptr::read_unaligned(remain.as_ptr() as *const IfMsghdr) };
+                let len = hdr.ifm_msglen as usize;
+                if len == 0 || len > remain.len() {
+                    break;
+                }
+                remain = &remain[len..];
+
+                // only link updates for this interface
+                if hdr.ifm_type != RTM_IFINFO || hdr.ifm_index != self.index {
+                    continue;
+                }
+
+                // report a change in state (or MTU)
+                let mtu = if hdr.ifm_flags & libc::IFF_UP != 0 {
+                    Some(get_mtu(&self.name)?)
+                } else {
+                    None
+                };
+                if mtu != self.mtu {
+                    log::trace!("routing socket, link event, mtu = {:?}", mtu);
+                    self.mtu = mtu;
+                    self.events.push(match mtu {
+                        Some(mtu) => TunEvent::Up(mtu),
+                        None => TunEvent::Down,
+                    });
+                }
+            }
+        }
+    }
+}
+
+impl BsdTunStatus {
+    fn new(name: [u8; libc::IFNAMSIZ], tun: RawFd) -> Result<BsdTunStatus, BsdTunError> {
+        // create routing socket (link updates for all interfaces)
+        let fd = unsafe { libc::socket(libc::PF_ROUTE, libc::SOCK_RAW, libc::AF_UNSPEC) };
+        if fd < 0 {
+            return Err(BsdTunError::RoutingSocketFailure);
+        }
+
+        let index = unsafe { libc::if_nametoindex(name.as_ptr() as *const libc::c_char) };
+        Ok(BsdTunStatus {
+            events: vec![
+                #[cfg(feature = "start_up")]
+                TunEvent::Up(1500),
+            ],
+            mtu: None,
+            index: index as libc::c_ushort,
+            name,
+            fd,
+            tun,
+        })
+    }
+
+    /// Returns the name of the interface (assigned by the kernel)
+    pub fn name(&self) -> String {
+        let len = self.name.iter().position(|b| *b == 0).unwrap_or(0);
+        String::from_utf8_lossy(&self.name[..len]).into_owned()
+    }
+
+    /// Set the MTU of the interface
+    /// (reported by a subsequent up event)
+    pub fn set_mtu(&self, mtu: usize) -> Result<(), BsdTunError> {
+        set_mtu(&self.name, mtu)
+    }
+
+    /// Returns the current MTU of the interface
+    pub fn get_mtu(&self) -> Result<usize, BsdTunError> {
+        get_mtu(&self.name)
+    }
+
+    /// Change the owner and group of the tun device node,
+    /// the current value is kept for None
+    pub fn set_owner(&self, uid: Option<u32>, gid: Option<u32>) -> Result<(), BsdTunError> {
+        if uid.is_none() && gid.is_none() {
+            return Ok(());
+        }
+        let uid = uid.map(|uid| uid as libc::uid_t).unwrap_or(!0);
+        let gid = gid.map(|gid| gid as libc::gid_t).unwrap_or(!0);
+        if unsafe { libc::fchown(self.tun, uid, gid) } < 0 {
+            return Err(BsdTunError::SetOwnerFailed);
+        }
+        Ok(())
+    }
+}
+
+impl Tun for BsdTun {
+    type Writer = BsdTunWriter;
+    type Reader = BsdTunReader;
+    type Error = BsdTunError;
+}
+
+impl PlatformTun for BsdTun {
+    type Status = BsdTunStatus;
+
+    fn create(name: &str) -> Result<(Vec<Self::Reader>, Self::Writer, Self::Status), Self::Error> {
+        let unit = parse_unit(name).ok_or(BsdTunError::InvalidTunDeviceName)?;
+        let (fd, ifname) = open_tun(unit)?;
+        Ok((
+            vec![BsdTunReader { fd }],
+            BsdTunWriter { fd },
+            BsdTunStatus::new(ifname, fd)?,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_unit() {
+        assert_eq!(parse_unit("tun"), Some(None));
+        assert_eq!(parse_unit("tun0"), Some(Some(0)));
+        assert_eq!(parse_unit("tun12"), Some(Some(12)));
+        assert_eq!(parse_unit("wg0"), None);
+        assert_eq!(parse_unit("tun-1"), None);
+        assert_eq!(parse_unit("tun+1"), None);
+        assert_eq!(parse_unit("tun99999999999"), None);
+    }
+}
@@ -0,0 +1,344 @@
+use super::super::udp::*;
+use super::super::Endpoint;
+
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Semantics:
+// Interval at which blocked readers check whether the bind has been closed
+// (shutdown does not reliably unblock a receive on an unconnected datagram socket)
+const READ_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Semantics:
+// TTL restored when the configured TTL is cleared
+const DEFAULT_TTL: u32 = 64;
+
+// Semantics:
+// Socket option used to implement the fwmark
+// (FreeBSD: cookie matched by the firewall, OpenBSD: routing table)
+#[cfg(target_os = "freebsd")]
+const SO_FWMARK: libc::c_int = 0x1015; // SO_USER_COOKIE
+#[cfg(target_os = "openbsd")]
+const SO_FWMARK: libc::c_int = 0x1021; // SO_RTABLE
+
+pub struct BsdUDP();
+
+pub struct BsdOwner {
+    port: u16,
+    sock4: Option<Arc<UdpSocket>>,
+    sock6: Option<Arc<UdpSocket>>,
+    closed: Arc<AtomicBool>,
+}
+
+pub struct BsdUDPReader {
+    sock: Arc<UdpSocket>,
+    closed: Arc<AtomicBool>,
+}
+
+#[derive(Clone)]
+pub struct BsdUDPWriter {
+    sock4: Option<Arc<UdpSocket>>,
+    sock6: Option<Arc<UdpSocket>>,
+}
+
+/// Endpoint of a peer (the source address is selected by the routing table)
+pub struct BsdEndpoint(SocketAddr);
+
+// Internal function
+//
+// Convert IPv4-mapped IPv6 addresses (from the dual-stack socket) to IPv4
+fn unmap(addr: SocketAddr) -> SocketAddr {
+    if let SocketAddr::V6(addr6) = addr {
+        if let Some(ip) = addr6.ip().to_ipv4() {
+            if addr6.ip().segments()[..6] == [0, 0, 0, 0, 0, 0xffff] {
+                return SocketAddr::V4(SocketAddrV4::new(ip, addr6.port()));
+            }
+        }
+    }
+    addr
+}
+
+impl Endpoint for BsdEndpoint {
+    fn from_address(addr: SocketAddr) -> Self {
+        BsdEndpoint(unmap(addr))
+    }
+
+    fn into_address(&self) -> SocketAddr {
+        self.0
+    }
+
+    fn clear_src(&mut self) {}
+}
+
+impl Reader<BsdEndpoint> for BsdUDPReader {
+    type Error = io::Error;
+
+    fn read(&self, buf: &mut [u8]) -> Result<(usize, BsdEndpoint), Self::Error> {
+        loop {
+            match self.sock.recv_from(buf) {
+                Ok((len, src)) => return Ok((len, BsdEndpoint::from_address(src))),
+                Err(e) => match e.kind() {
+                    io::ErrorKind::WouldBlock
+                    | io::ErrorKind::TimedOut
+                    | io::ErrorKind::Interrupted
+                        if !self.closed.load(Ordering::Acquire) =>
+                    {
+                        continue
+                    }
+                    _ => return Err(e),
+                },
+            }
+        }
+    }
+}
+
+impl Writer<BsdEndpoint> for BsdUDPWriter {
+    type Error = io::Error;
+
+    fn write(&self, buf: &[u8], dst: &mut BsdEndpoint) -> Result<(), Self::Error> {
+        let sock = match dst.0 {
+            SocketAddr::V4(_) => self.sock4.as_ref(),
+            SocketAddr::V6(_) => self.sock6.as_ref(),
+        };
+        match sock {
+            Some(sock) => sock.send_to(buf, dst.0).map(|_| ()),
+            None => Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                "no socket bound for the address family",
+            )),
+        }
+    }
+}
+
+impl Owner for BsdOwner {
+    type Error = io::Error;
+
+    fn get_port(&self) -> u16 {
+        self.port
+    }
+
+    fn set_fwmark(&mut self, value: Option<u32>) -> Result<(), Self::Error> {
+        let value = value.unwrap_or(0) as libc::c_int;
+        for sock in self.sock4.iter().chain(self.sock6.iter()) {
+            setsockopt_int(sock, libc::SOL_SOCKET, SO_FWMARK, value)?;
+        }
+        Ok(())
+    }
+
+    fn set_interface(&mut self, name: Option<&str>) -> Result<(), Self::Error> {
+        match name {
+            None => Ok(()),
+            Some(_) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "binding to an interface is not supported on this platform",
+            )),
+        }
+    }
+
+    fn set_ttl(&mut self, ttl: Option<u8>) -> Result<(), Self::Error> {
+        if let Some(sock) = self.sock4.as_ref() {
+            sock.set_ttl(ttl.map(u32::from).unwrap_or(DEFAULT_TTL))?;
+        }
+        if let Some(sock) = self.sock6.as_ref() {
+            // -1 selects the system default
+            let value = ttl.map(libc::c_int::from).unwrap_or(-1);
+            setsockopt_int(sock, libc::IPPROTO_IPV6, libc::IPV6_UNICAST_HOPS, value)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for BsdOwner {
+    fn drop(&mut self) {
+        log::debug!("closing the bind (port = {})", self.port);
+        self.closed.store(true, Ordering::Release);
+        for sock in self.sock4.iter().chain(self.sock6.iter()) {
+            unsafe {
+                libc::shutdown(sock.as_raw_fd(), libc::SHUT_RDWR);
+            }
+        }
+    }
+}
+
+fn setsockopt_int(
+    sock: &UdpSocket,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    let res = unsafe {
+        libc::setsockopt(
+            sock.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+impl UDP for BsdUDP {
+    type Error = io::Error;
+    type Endpoint = BsdEndpoint;
+    type Writer = BsdUDPWriter;
+    type Reader = BsdUDPReader;
+}
+
+impl BsdUDP {
+    /* Bind a datagram socket to the wildcard address
+     *
+     * SO_REUSEPORT allows the sockets to be rebound on the same port
+     * before the previous sockets are closed (see the UAPI configuration).
+     */
+    fn bind_socket(addr: SocketAddr) -> io::Result<UdpSocket> {
+        let domain = match addr {
+            SocketAddr::V4(_) => libc::AF_INET,
+            SocketAddr::V6(_) => libc::AF_INET6,
+        };
+        let fd = unsafe { libc::socket(domain, libc::SOCK_DGRAM, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let sock = unsafe { UdpSocket::from_raw_fd(fd) };
+
+        setsockopt_int(&sock, libc::SOL_SOCKET, libc::SO_REUSEADDR, 1)?;
+        setsockopt_int(&sock, libc::SOL_SOCKET, libc::SO_REUSEPORT, 1)?;
+        if domain == libc::AF_INET6 {
+            setsockopt_int(&sock, libc::IPPROTO_IPV6, libc::IPV6_V6ONLY, 1)?;
+        }
+
+        let res = match addr {
+            SocketAddr::V4(addr) => {
+                let sockaddr = libc::sockaddr_in {
+                    sin_len: mem::size_of::<libc::sockaddr_in>() as u8,
+                    sin_family: libc::AF_INET as libc::sa_family_t,
+                    sin_port: addr.port().to_be(),
+                    sin_addr: libc::in_addr {
+                        s_addr: u32::from(*addr.ip()).to_be(),
+                    },
+                    sin_zero: [0; 8],
+                };
+                unsafe {
+                    libc::bind(
+                        fd,
+                        &sockaddr as *const libc::sockaddr_in as *const libc::sockaddr,
+                        mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+                    )
+                }
+            }
+            SocketAddr::V6(addr) => {
+                let mut sockaddr: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+                sockaddr.sin6_len = mem::size_of::<libc::sockaddr_in6>() as u8;
+                sockaddr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sockaddr.sin6_port = addr.port().to_be();
+                sockaddr.sin6_addr.s6_addr = addr.ip().octets();
+                unsafe {
+                    libc::bind(
+                        fd,
+                        &sockaddr as *const libc::sockaddr_in6 as *const libc::sockaddr,
+                        mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+                    )
+                }
+            }
+        };
+        if res != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        sock.set_read_timeout(Some(READ_POLL_INTERVAL))?;
+        Ok(sock)
+    }
+}
+
+impl PlatformUDP for BsdUDP {
+    type Owner = BsdOwner;
+
+    fn bind(port: u16) -> Result<(Vec<Self::Reader>, Self::Writer, Self::Owner), Self::Error> {
+        log::debug!("bind to port {}", port);
+
+        // bind IPv6 first (if available), then IPv4 on the same port
+        let sock6 = Self::bind_socket(SocketAddr::V6(SocketAddrV6::new(
+            Ipv6Addr::UNSPECIFIED,
+            port,
+            0,
+            0,
+        )))
+        .map(Arc::new);
+        let port = match sock6.as_ref() {
+            Ok(sock) => sock.local_addr()?.port(),
+            Err(e) => {
+                log::debug!("failed to bind IPv6 socket: {}", e);
+                port
+            }
+        };
+        let sock4 = Self::bind_socket(SocketAddr::V4(SocketAddrV4::new(
+            Ipv4Addr::UNSPECIFIED,
+            port,
+        )))
+        .map(Arc::new);
+        let port = match (sock4.as_ref(), sock6.as_ref()) {
+            (Ok(sock), _) => sock.local_addr()?.port(),
+            (Err(_), Ok(_)) => port,
+            (Err(e), Err(_)) => return Err(io::Error::new(e.kind(), e.to_string())),
+        };
+
+        let sock4 = sock4.ok();
+        let sock6 = sock6.ok();
+        let closed = Arc::new(AtomicBool::new(false));
+        let readers = sock4
+            .iter()
+            .chain(sock6.iter())
+            .map(|sock| BsdUDPReader {
+                sock: sock.clone(),
+                closed: closed.clone(),
+            })
+            .collect();
+
+        Ok((
+            readers,
+            BsdUDPWriter {
+                sock4: sock4.clone(),
+                sock6: sock6.clone(),
+            },
+            BsdOwner {
+                port,
+                sock4,
+                sock6,
+                closed,
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bind_loopback() {
+        let (readers, writer, owner) = BsdUDP::bind(0).unwrap();
+        let port = owner.get_port();
+        assert_ne!(port, 0);
+
+        // send to ourselves over IPv4
+        let mut dst = BsdEndpoint::from_address(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port));
+        writer.write(&[1, 2, 3], &mut dst).unwrap();
+        let mut buf = [0u8; 16];
+        let (len, src) = readers[0].read(&mut buf).unwrap();
+        assert_eq!(&buf[..len], &[1, 2, 3]);
+        assert_eq!(src.into_address().port(), port);
+
+        // readers terminate once the owner is dropped
+        drop(owner);
+        assert!(readers[0].read(&mut buf).is_err());
+    }
+}
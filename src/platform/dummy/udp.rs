@@ -186,6 +186,14 @@ impl Owner for VoidOwner {
     fn get_port(&self) -> u16 {
         0
     }
+
+    fn set_interface(&mut self, _name: Option<&str>) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn set_ttl(&mut self, _ttl: Option<u8>) -> Result<(), Self::Error> {
+        Ok(())
+    }
 }
 
 impl PlatformUDP for PairBind {
@@ -4,4 +4,9 @@ pub trait Endpoint: Send + 'static {
     fn from_address(addr: SocketAddr) -> Self;
     fn into_address(&self) -> SocketAddr;
     fn clear_src(&mut self);
+
+    /// Traffic class (DSCP and ECN bits) of the packet received from the endpoint
+    fn traffic_class(&self) -> u8 {
+        0
+    }
 }
@@ -1,6 +1,6 @@
 mod tun;
 mod uapi;
-mod udp;
+pub mod udp;
 
 pub use tun::LinuxTun as Tun;
 pub use uapi::LinuxUAPI as UAPI;
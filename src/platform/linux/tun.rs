@@ -7,7 +7,25 @@ use std::os::raw::c_short;
 use std::os::unix::io::RawFd;
 
 const TUNSETIFF: u64 = 0x4004_54ca;
+const TUNGETIFF: u64 = 0x8004_54d2;
+const TUNSETOWNER: u64 = 0x4004_54cc;
+const TUNSETGROUP: u64 = 0x4004_54ce;
 const CLONE_DEVICE_PATH: &[u8] = b"/dev/net/tun\0";
+const IFF_MULTI_QUEUE: c_short = 0x0100;
+const IFF_VNET_HDR: c_short = 0x4000;
+
+// segmentation / checksum offloading (include/uapi/linux/if_tun.h)
+const TUNSETOFFLOAD: u64 = 0x4004_54d0;
+const TUN_F_CSUM: libc::c_uint = 0x01;
+const TUN_F_TSO4: libc::c_uint = 0x02;
+const TUN_F_TSO6: libc::c_uint = 0x04;
+
+// struct virtio_net_hdr (include/uapi/linux/virtio_net.h), prefixes every packet with IFF_VNET_HDR
+const SIZE_VNET_HDR: usize = 10;
+const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
+const VIRTIO_NET_HDR_GSO_NONE: u8 = 0;
+const VIRTIO_NET_HDR_GSO_TCPV4: u8 = 1;
+const VIRTIO_NET_HDR_GSO_TCPV6: u8 = 4;
 
 #[repr(C)]
 struct Ifreq {
@@ -32,10 +50,12 @@ pub struct LinuxTun {}
 
 pub struct LinuxTunReader {
     fd: RawFd,
+    vnet_hdr: bool, // packets are prefixed by a virtio-net header (offloading enabled)
 }
 
 pub struct LinuxTunWriter {
     fd: RawFd,
+    vnet_hdr: bool,
 }
 
 pub struct LinuxTunStatus {
@@ -43,6 +63,7 @@ pub struct LinuxTunStatus {
     index: i32,
     name: [u8; libc::IFNAMSIZ],
     fd: RawFd,
+    tun: RawFd, // fd of the TUN device (for configuration)
 }
 
 #[derive(Debug)]
@@ -51,6 +72,10 @@ pub enum LinuxTunError {
     FailedToOpenCloneDevice,
     SetIFFIoctlFailed,
     GetMTUIoctlFailed,
+    SetMTUIoctlFailed,
+    SetOwnerIoctlFailed,
+    SetOffloadIoctlFailed,
+    MalformedOffload,
     NetlinkFailure,
     Closed, // TODO
 }
@@ -67,6 +92,16 @@ impl fmt::Display for LinuxTunError {
             }
             LinuxTunError::Closed => write!(f, "The tunnel has been closed"),
             LinuxTunError::GetMTUIoctlFailed => write!(f, "ifmtu ioctl failed"),
+            LinuxTunError::SetMTUIoctlFailed => {
+                write!(f, "set ifmtu ioctl failed (insufficient permissions?)")
+            }
+            LinuxTunError::SetOwnerIoctlFailed => {
+                write!(f, "set owner ioctl failed (insufficient permissions?)")
+            }
+            LinuxTunError::SetOffloadIoctlFailed => {
+                write!(f, "set offload ioctl failed (unsupported by the kernel?)")
+            }
+            LinuxTunError::MalformedOffload => write!(f, "Unsupported virtio-net header"),
             LinuxTunError::NetlinkFailure => write!(f, "Netlink listener error"),
         }
     }
@@ -82,10 +117,55 @@ impl Error for LinuxTunError {
     }
 }
 
+// Internal function
+//
+// Decode a virtio-net header (in native byte order)
+fn decode_vnet_hdr(hdr: &[u8; SIZE_VNET_HDR]) -> Result<Offload, LinuxTunError> {
+    let field = |i: usize| u16::from_ne_bytes([hdr[i], hdr[i + 1]]);
+    let gso = match hdr[1] {
+        VIRTIO_NET_HDR_GSO_NONE => Gso::None,
+        VIRTIO_NET_HDR_GSO_TCPV4 => Gso::TcpV4,
+        VIRTIO_NET_HDR_GSO_TCPV6 => Gso::TcpV6,
+        _ => return Err(LinuxTunError::MalformedOffload),
+    };
+    Ok(Offload {
+        gso,
+        hdr_len: field(2),
+        gso_size: field(4),
+        csum_start: field(6),
+        csum_offset: field(8),
+        needs_csum: hdr[0] & VIRTIO_NET_HDR_F_NEEDS_CSUM != 0,
+    })
+}
+
+// Internal function
+//
+// Encode a virtio-net header (in native byte order)
+fn encode_vnet_hdr(offload: &Offload) -> [u8; SIZE_VNET_HDR] {
+    let mut hdr = [0u8; SIZE_VNET_HDR];
+    if offload.needs_csum {
+        hdr[0] = VIRTIO_NET_HDR_F_NEEDS_CSUM;
+    }
+    hdr[1] = match offload.gso {
+        Gso::None => VIRTIO_NET_HDR_GSO_NONE,
+        Gso::TcpV4 => VIRTIO_NET_HDR_GSO_TCPV4,
+        Gso::TcpV6 => VIRTIO_NET_HDR_GSO_TCPV6,
+    };
+    hdr[2..4].copy_from_slice(&offload.hdr_len.to_ne_bytes());
+    hdr[4..6].copy_from_slice(&offload.gso_size.to_ne_bytes());
+    hdr[6..8].copy_from_slice(&offload.csum_start.to_ne_bytes());
+    hdr[8..10].copy_from_slice(&offload.csum_offset.to_ne_bytes());
+    hdr
+}
+
 impl Reader for LinuxTunReader {
     type Error = LinuxTunError;
 
     fn read(&self, buf: &mut [u8], offset: usize) -> Result<usize, Self::Error> {
+        if self.vnet_hdr {
+            return self.read_offload(buf, offset).map(|(len, _)| len);
+        }
+
         /*
         debug_assert!(
             offset < buf.len(),
@@ -101,17 +181,73 @@ impl Reader for LinuxTunReader {
             Ok(n as usize)
         }
     }
+
+    fn offload(&self) -> bool {
+        self.vnet_hdr
+    }
+
+    fn read_offload(&self, buf: &mut [u8], offset: usize) -> Result<(usize, Offload), Self::Error> {
+        if !self.vnet_hdr {
+            return self.read(buf, offset).map(|len| (len, Offload::default()));
+        }
+
+        // read the virtio-net header separately from the packet
+        let mut hdr = [0u8; SIZE_VNET_HDR];
+        let iov = [
+            libc::iovec {
+                iov_base: hdr.as_mut_ptr() as *mut libc::c_void,
+                iov_len: hdr.len(),
+            },
+            libc::iovec {
+                iov_base: buf[offset..].as_mut_ptr() as *mut libc::c_void,
+                iov_len: buf.len() - offset,
+            },
+        ];
+        let n: isize = unsafe { libc::readv(self.fd, iov.as_ptr(), iov.len() as libc::c_int) };
+        if n < SIZE_VNET_HDR as isize {
+            return Err(LinuxTunError::Closed);
+        }
+
+        // conversion is safe
+        Ok((n as usize - SIZE_VNET_HDR, decode_vnet_hdr(&hdr)?))
+    }
 }
 
 impl Writer for LinuxTunWriter {
     type Error = LinuxTunError;
 
     fn write(&self, src: &[u8]) -> Result<(), Self::Error> {
+        if self.vnet_hdr {
+            return self.write_offload(src, &Offload::default());
+        }
         match unsafe { libc::write(self.fd, src.as_ptr() as _, src.len() as _) } {
             -1 => Err(LinuxTunError::Closed),
             _ => Ok(()),
         }
     }
+
+    fn offload(&self) -> bool {
+        self.vnet_hdr
+    }
+
+    fn write_offload(&self, src: &[u8], offload: &Offload) -> Result<(), Self::Error> {
+        debug_assert!(self.vnet_hdr, "offloading not enabled");
+        let hdr = encode_vnet_hdr(offload);
+        let iov = [
+            libc::iovec {
+                iov_base: hdr.as_ptr() as *mut libc::c_void,
+                iov_len: hdr.len(),
+            },
+            libc::iovec {
+                iov_base: src.as_ptr() as *mut libc::c_void,
+                iov_len: src.len(),
+            },
+        ];
+        match unsafe { libc::writev(self.fd, iov.as_ptr(), iov.len() as libc::c_int) } {
+            -1 => Err(LinuxTunError::Closed),
+            _ => Ok(()),
+        }
+    }
 }
 
 fn get_ifindex(name: &[u8; libc::IFNAMSIZ]) -> i32 {
@@ -170,6 +306,45 @@ fn get_mtu(name: &[u8; libc::IFNAMSIZ]) -> Result<usize, LinuxTunError> {
     Ok(buf.mtu as usize)
 }
 
+fn set_mtu(name: &[u8; libc::IFNAMSIZ], mtu: usize) -> Result<(), LinuxTunError> {
+    #[repr(C)]
+    struct arg {
+        name: [u8; libc::IFNAMSIZ],
+        mtu: u32,
+    }
+
+    debug_assert_eq!(
+        name[libc::IFNAMSIZ - 1],
+        0,
+        "name buffer not null-terminated"
+    );
+
+    // create socket
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+    if fd < 0 {
+        return Err(LinuxTunError::SetMTUIoctlFailed);
+    }
+
+    // do SIOCSIFMTU ioctl
+    let buf = arg {
+        name: *name,
+        mtu: mtu as u32,
+    };
+    let err = unsafe {
+        let ptr: &libc::c_void = &*(&buf as *const _ as *const libc::c_void);
+        libc::ioctl(fd, libc::SIOCSIFMTU, ptr)
+    };
+
+    // close socket
+    unsafe { libc::close(fd) };
+
+    // handle error from ioctl
+    if err != 0 {
+        return Err(LinuxTunError::SetMTUIoctlFailed);
+    }
+    Ok(())
+}
+
 impl Status for LinuxTunStatus {
     type Error = LinuxTunError;
 
@@ -267,7 +442,41 @@ impl LinuxTunStatus {
     const RTNLGRP_IPV4_IFADDR: libc::c_uint = 5;
     const RTNLGRP_IPV6_IFADDR: libc::c_uint = 9;
 
-    fn new(name: [u8; libc::IFNAMSIZ]) -> Result<LinuxTunStatus, LinuxTunError> {
+    /// Returns the name of the interface
+    pub fn name(&self) -> String {
+        let len = self.name.iter().position(|b| *b == 0).unwrap_or(0);
+        String::from_utf8_lossy(&self.name[..len]).into_owned()
+    }
+
+    /// Set the MTU of the interface
+    /// (reported by a subsequent up event)
+    pub fn set_mtu(&self, mtu: usize) -> Result<(), LinuxTunError> {
+        set_mtu(&self.name, mtu)
+    }
+
+    /// Returns the current MTU of the interface
+    pub fn get_mtu(&self) -> Result<usize, LinuxTunError> {
+        get_mtu(&self.name)
+    }
+
+    /// Allow an unprivileged user / group to open the TUN device
+    ///
+    /// # Arguments
+    ///
+    /// - `uid`: The owning user (or None to leave unchanged)
+    /// - `gid`: The owning group (or None to leave unchanged)
+    pub fn set_owner(&self, uid: Option<u32>, gid: Option<u32>) -> Result<(), LinuxTunError> {
+        for (req, id) in [(TUNSETOWNER, uid), (TUNSETGROUP, gid)].iter() {
+            if let Some(id) = id {
+                if unsafe { libc::ioctl(self.tun, *req as _, *id as libc::c_ulong) } < 0 {
+                    return Err(LinuxTunError::SetOwnerIoctlFailed);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn new(name: [u8; libc::IFNAMSIZ], tun: RawFd) -> Result<LinuxTunStatus, LinuxTunError> {
         // create netlink socket
         let fd = unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_RAW, libc::NETLINK_ROUTE) };
         if fd < 0 {
@@ -303,12 +512,31 @@ impl LinuxTunStatus {
                 ],
                 index: get_ifindex(&name),
                 fd,
+                tun,
                 name,
             })
         }
     }
 }
 
+// Internal function
+//
+// Open the clone device and attach it to the TUN device described by the request
+fn attach_queue(req: &Ifreq) -> Result<RawFd, LinuxTunError> {
+    // open clone device
+    let fd: RawFd = match unsafe { libc::open(CLONE_DEVICE_PATH.as_ptr() as _, libc::O_RDWR) } {
+        -1 => return Err(LinuxTunError::FailedToOpenCloneDevice),
+        fd => fd,
+    };
+
+    // create TUN device (or attach queue)
+    if unsafe { libc::ioctl(fd, TUNSETIFF as _, req) } < 0 {
+        unsafe { libc::close(fd) };
+        return Err(LinuxTunError::SetIFFIoctlFailed);
+    }
+    Ok(fd)
+}
+
 impl Tun for LinuxTun {
     type Writer = LinuxTunWriter;
     type Reader = LinuxTunReader;
@@ -319,6 +547,78 @@ impl PlatformTun for LinuxTun {
     type Status = LinuxTunStatus;
 
     fn create(name: &str) -> Result<(Vec<Self::Reader>, Self::Writer, Self::Status), Self::Error> {
+        Self::create_multiqueue(name, 1)
+    }
+
+    /// Attach a file descriptor for each queue to the device (IFF_MULTI_QUEUE),
+    /// every queue is served by a separate reader.
+    fn create_multiqueue(name: &str, queues: usize) -> Result<Created<Self>, Self::Error> {
+        Self::open(name, queues, false)
+    }
+
+    /// Enable TSO and checksum offloading (IFF_VNET_HDR and TUNSETOFFLOAD):
+    /// the kernel hands over TCP super-packets and accepts coalesced segments.
+    fn create_offload(name: &str, queues: usize) -> Result<Created<Self>, Self::Error> {
+        Self::open(name, queues, true)
+    }
+}
+
+impl LinuxTun {
+    /// Use a TUN device opened by another party
+    /// (e.g. the file descriptor established by the VpnService on Android)
+    ///
+    /// # Arguments
+    ///
+    /// - `fd`: The file descriptor of the TUN device (ownership is transferred)
+    ///
+    /// # Returns
+    ///
+    /// A single reader, the writer and the status of the device.
+    /// When the netlink socket cannot be opened (e.g. in an unprivileged application),
+    /// the status reports no events and the MTU must be supplied by the caller.
+    #[cfg_attr(not(target_os = "android"), allow(dead_code))]
+    pub fn from_fd(
+        fd: RawFd,
+    ) -> Result<(Vec<LinuxTunReader>, LinuxTunWriter, LinuxTunStatus), LinuxTunError> {
+        // obtain the name and flags of the device
+        let mut req = Ifreq {
+            name: [0u8; libc::IFNAMSIZ],
+            flags: 0,
+            _pad: [0u8; 64],
+        };
+        if unsafe { libc::ioctl(fd, TUNGETIFF as _, &mut req) } < 0 {
+            return Err(LinuxTunError::SetIFFIoctlFailed);
+        }
+        if req.flags & (libc::IFF_TUN | libc::IFF_NO_PI) as c_short
+            != (libc::IFF_TUN | libc::IFF_NO_PI) as c_short
+        {
+            return Err(LinuxTunError::SetIFFIoctlFailed);
+        }
+        let vnet_hdr = req.flags & IFF_VNET_HDR != 0;
+
+        // fallback: no events (rtnetlink is restricted on Android)
+        let status = LinuxTunStatus::new(req.name, fd).unwrap_or_else(|_| {
+            log::debug!("netlink unavailable, no events for the TUN device");
+            LinuxTunStatus {
+                events: vec![],
+                index: get_ifindex(&req.name),
+                name: req.name,
+                fd: -1,
+                tun: fd,
+            }
+        });
+        Ok((
+            vec![LinuxTunReader { fd, vnet_hdr }],
+            LinuxTunWriter { fd, vnet_hdr },
+            status,
+        ))
+    }
+
+    fn open(
+        name: &str,
+        queues: usize,
+        vnet_hdr: bool,
+    ) -> Result<(Vec<LinuxTunReader>, LinuxTunWriter, LinuxTunStatus), LinuxTunError> {
         // construct request struct
         let mut req = Ifreq {
             name: [0u8; libc::IFNAMSIZ],
@@ -326,6 +626,14 @@ impl PlatformTun for LinuxTun {
             _pad: [0u8; 64],
         };
 
+        // a single queue retains the flags of a regular device
+        if queues > 1 {
+            req.flags |= IFF_MULTI_QUEUE;
+        }
+        if vnet_hdr {
+            req.flags |= IFF_VNET_HDR;
+        }
+
         // sanity check length of device name
         let bs = name.as_bytes();
         if bs.len() > libc::IFNAMSIZ - 1 {
@@ -333,23 +641,122 @@ impl PlatformTun for LinuxTun {
         }
         req.name[..bs.len()].copy_from_slice(bs);
 
-        // open clone device
-        let fd: RawFd = match unsafe { libc::open(CLONE_DEVICE_PATH.as_ptr() as _, libc::O_RDWR) } {
-            -1 => return Err(LinuxTunError::FailedToOpenCloneDevice),
-            fd => fd,
+        // attach every queue to the device (created by the first)
+        let queues = queues.max(1);
+        let mut fds: Vec<RawFd> = Vec::with_capacity(queues);
+        let close = |fds: Vec<RawFd>| {
+            for fd in fds {
+                unsafe { libc::close(fd) };
+            }
         };
-        assert!(fd >= 0);
+        for _ in 0..queues {
+            match attach_queue(&req) {
+                Ok(fd) => fds.push(fd),
+                Err(e) => {
+                    close(fds);
+                    return Err(e);
+                }
+            }
+        }
 
-        // create TUN device
-        if unsafe { libc::ioctl(fd, TUNSETIFF as _, &req) } < 0 {
-            return Err(LinuxTunError::SetIFFIoctlFailed);
+        // announce the supported offloads (applies to the device)
+        if vnet_hdr {
+            let offloads = TUN_F_CSUM | TUN_F_TSO4 | TUN_F_TSO6;
+            if unsafe { libc::ioctl(fds[0], TUNSETOFFLOAD as _, offloads as libc::c_ulong) } < 0 {
+                close(fds);
+                return Err(LinuxTunError::SetOffloadIoctlFailed);
+            }
         }
 
         // create PlatformTunMTU instance
+        let fd = fds[0];
         Ok((
-            vec![LinuxTunReader { fd }], // TODO: use multi-queue for Linux
-            LinuxTunWriter { fd },
-            LinuxTunStatus::new(req.name)?,
+            fds.into_iter()
+                .map(|fd| LinuxTunReader { fd, vnet_hdr })
+                .collect(),
+            LinuxTunWriter { fd, vnet_hdr },
+            LinuxTunStatus::new(req.name, fd)?,
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::rngs::OsRng;
+    use rand::Rng;
+
+    #[test]
+    #[ignore = "creating a TUN device requires CAP_NET_ADMIN"]
+    fn test_tun_configure() {
+        let name = format!("wgtest{}", OsRng.gen::<u16>());
+        let (readers, _writer, status) = LinuxTun::create(&name).unwrap();
+        assert_eq!(readers.len(), 1);
+        assert_eq!(status.name(), name);
+
+        status.set_mtu(1280).unwrap();
+        assert_eq!(status.get_mtu().unwrap(), 1280);
+        status.set_mtu(1420).unwrap();
+        assert_eq!(status.get_mtu().unwrap(), 1420);
+        status.set_owner(Some(0), Some(0)).unwrap();
+        status.set_owner(None, None).unwrap();
+    }
+
+    #[test]
+    #[ignore = "creating a TUN device requires CAP_NET_ADMIN"]
+    fn test_tun_multiqueue() {
+        let name = format!("wgtest{}", OsRng.gen::<u16>());
+        let (readers, _writer, status) = LinuxTun::create_multiqueue(&name, 4).unwrap();
+        assert_eq!(readers.len(), 4);
+        assert_eq!(status.name(), name);
+
+        // every queue has a distinct file descriptor
+        let mut fds: Vec<RawFd> = readers.iter().map(|reader| reader.fd).collect();
+        fds.dedup();
+        assert_eq!(fds.len(), 4);
+    }
+
+    #[test]
+    #[ignore = "creating a TUN device requires CAP_NET_ADMIN"]
+    fn test_tun_from_fd() {
+        let name = format!("wgtest{}", OsRng.gen::<u16>());
+        let (readers, _writer, _status) = LinuxTun::create(&name).unwrap();
+
+        // the name is obtained from the device
+        let fd = unsafe { libc::dup(readers[0].fd) };
+        let (readers, _writer, status) = LinuxTun::from_fd(fd).unwrap();
+        assert_eq!(readers.len(), 1);
+        assert!(!readers[0].offload());
+        assert_eq!(status.name(), name);
+
+        // not a TUN device
+        assert!(LinuxTun::from_fd(libc::STDIN_FILENO).is_err());
+    }
+
+    #[test]
+    fn test_vnet_hdr() {
+        let offload = Offload {
+            gso: Gso::TcpV6,
+            gso_size: 1380,
+            hdr_len: 60,
+            csum_start: 40,
+            csum_offset: 16,
+            needs_csum: true,
+        };
+        assert_eq!(
+            decode_vnet_hdr(&encode_vnet_hdr(&offload)).unwrap(),
+            offload
+        );
+    }
+
+    #[test]
+    #[ignore = "creating a TUN device requires CAP_NET_ADMIN"]
+    fn test_tun_offload() {
+        let name = format!("wgtest{}", OsRng.gen::<u16>());
+        let (readers, writer, _status) = LinuxTun::create_offload(&name, 2).unwrap();
+        assert_eq!(readers.len(), 2);
+        assert!(readers.iter().all(|reader| reader.offload()));
+        assert!(writer.offload());
+    }
+}
@@ -1,15 +1,91 @@
 use super::super::udp::*;
 use super::super::Endpoint;
 
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::VecDeque;
 use std::convert::TryInto;
 use std::io;
 use std::mem;
-use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::os::unix::io::RawFd;
 use std::ptr;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 
-pub struct FD(RawFd);
+use spin::RwLock;
+
+pub struct FD(RawFd, Offload, Mutex<Vec<(SocketAddr, usize)>>); // fd, offloads, path MTU updates
+
+impl FD {
+    fn new(fd: RawFd) -> FD {
+        FD(fd, Offload::enable(fd), Mutex::new(vec![]))
+    }
+}
+
+/* Segmentation offloads enabled on a socket:
+ *
+ * - gso: runs of datagrams to the same endpoint are sent as a single message (UDP_SEGMENT),
+ *   disabled again if the egress device cannot checksum the segments.
+ * - gro: the kernel delivers coalesced datagrams (UDP_GRO), which are split by the reader,
+ *   the segments which do not fit in the batch are returned by the next reads
+ *   (UDP_GRO is not disabled for single datagram reads:
+ *   the segment size of datagrams coalesced before would no longer be reported).
+ */
+#[derive(Default)]
+pub struct Offload {
+    gso: AtomicBool,
+    gro: bool,
+    pending: Mutex<VecDeque<(Vec<u8>, LinuxEndpoint)>>,
+}
+
+impl Offload {
+    // Internal function
+    //
+    // Enable the offloads supported by the kernel on the socket
+    fn enable(fd: RawFd) -> Offload {
+        let gro = setsockopt_int(fd, libc::SOL_UDP, UDP_GRO, 1).is_ok();
+        let gso = {
+            let mut value: libc::c_int = 0;
+            let mut len = mem::size_of_val(&value) as libc::socklen_t;
+            let res = unsafe {
+                libc::getsockopt(
+                    fd,
+                    libc::SOL_UDP,
+                    UDP_SEGMENT,
+                    &mut value as *mut libc::c_int as *mut libc::c_void,
+                    &mut len,
+                )
+            };
+            res == 0
+        };
+        log::debug!("udp offloads (fd = {}, gso = {}, gro = {})", fd, gso, gro);
+        Offload {
+            gso: AtomicBool::new(gso),
+            gro,
+            pending: Mutex::default(),
+        }
+    }
+
+    // Internal function
+    //
+    // Move the segments left over from a previous read into the batch
+    fn take_pending(&self, bufs: &mut [&mut [u8]]) -> Vec<(usize, LinuxEndpoint)> {
+        let mut pending = self.pending.lock().unwrap();
+        let mut batch = Vec::with_capacity(cmp::min(bufs.len(), pending.len()));
+        for buf in bufs.iter_mut() {
+            match pending.pop_front() {
+                Some((seg, src)) => {
+                    let size = cmp::min(seg.len(), buf.len());
+                    buf[..size].copy_from_slice(&seg[..size]);
+                    batch.push((size, src));
+                }
+                None => break,
+            }
+        }
+        batch
+    }
+}
 
 impl Drop for FD {
     fn drop(&mut self) {
@@ -37,19 +113,21 @@ struct ControlHeaderV6 {
 pub struct EndpointV4 {
     dst: libc::sockaddr_in, // destination IP
     info: libc::in_pktinfo, // src & ifindex
+    tos: u8,                // traffic class of received packet
 }
 
 pub struct EndpointV6 {
     dst: libc::sockaddr_in6, // destination IP
     info: libc::in6_pktinfo, // src & zone id
+    tos: u8,                 // traffic class of received packet
 }
 
 pub struct LinuxUDP();
 
 pub struct LinuxOwner {
     port: u16,
-    sock4: Option<Arc<FD>>,
-    sock6: Option<Arc<FD>>,
+    sock4: Vec<Arc<FD>>,
+    sock6: Vec<Arc<FD>>,
 }
 
 pub enum LinuxUDPReader {
@@ -68,8 +146,27 @@ pub enum LinuxEndpoint {
     V6(EndpointV6),
 }
 
+const UDP_SEGMENT: libc::c_int = 103;
+
+const UDP_GRO: libc::c_int = 104;
+
+// maximum number of segments in a coalesced datagram (UDP_MAX_SEGMENTS)
+const MAX_SEGMENTS: usize = 64;
+
+// maximum payload of a coalesced datagram sent (IPv6 and UDP headers included)
+const MAX_COALESCED_SIZE: usize = 0xffff - 40 - 8;
+
+// size of the scratch space for a coalesced datagram received
+const GRO_BUFFER_SIZE: usize = 1 << 16;
+
+// size of the control buffer of a message (packet info, segment size and traffic class)
+const CONTROL_WORDS: usize = 14;
+
 fn errno() -> libc::c_int {
     unsafe {
+        #[cfg(target_os = "android")]
+        let ptr = libc::__errno();
+        #[cfg(not(target_os = "android"))]
         let ptr = libc::__errno_location();
         if ptr.is_null() {
             0
@@ -79,6 +176,35 @@ fn errno() -> libc::c_int {
     }
 }
 
+/* Socket protection:
+ *
+ * When the device is embedded in an Android application (VpnService),
+ * the sockets must be exempted from the routes of the tunnel itself,
+ * the callback is invoked for every socket before it is bound.
+ */
+pub type Protect = Box<dyn Fn(RawFd) -> bool + Send + Sync>;
+
+static PROTECT: RwLock<Option<Protect>> = RwLock::new(None);
+
+/// Set the callback protecting every new socket (or None to remove it)
+#[cfg_attr(not(target_os = "android"), allow(dead_code))]
+pub fn set_protect(callback: Option<Protect>) {
+    *PROTECT.write() = callback;
+}
+
+// Internal function
+//
+// Invoke the protection callback (if any) on a new socket
+fn protect(fd: RawFd) -> Result<(), io::Error> {
+    match PROTECT.read().as_ref() {
+        Some(callback) if !callback(fd) => {
+            log::debug!("failed to protect socket (fd = {})", fd);
+            Err(io::Error::other("failed to protect socket"))
+        }
+        _ => Ok(()),
+    }
+}
+
 fn setsockopt<V: Sized>(
     fd: RawFd,
     level: libc::c_int,
@@ -129,9 +255,112 @@ fn safe_cast<T, D>(v: &mut T) -> *mut D {
     (v as *mut T) as *mut D
 }
 
+// Internal function
+//
+// Append a control message to the (zeroed, aligned) buffer
+// and return the length of the control data.
+unsafe fn put_control<T>(
+    control: &mut [u64],
+    offset: usize,
+    level: libc::c_int,
+    ty: libc::c_int,
+    value: &T,
+) -> usize {
+    debug_assert!(
+        offset + libc::CMSG_SPACE(mem::size_of::<T>() as u32) as usize <= mem::size_of_val(control)
+    );
+    let hdr = (control.as_mut_ptr() as *mut u8).add(offset) as *mut libc::cmsghdr;
+    (*hdr).cmsg_len = libc::CMSG_LEN(mem::size_of::<T>() as u32) as _;
+    (*hdr).cmsg_level = level;
+    (*hdr).cmsg_type = ty;
+    ptr::copy_nonoverlapping(
+        value as *const T as *const u8,
+        libc::CMSG_DATA(hdr),
+        mem::size_of::<T>(),
+    );
+    offset + libc::CMSG_SPACE(mem::size_of::<T>() as u32) as usize
+}
+
+// Internal function
+//
+// Extract the packet info (sticky source), the segment size of a coalesced datagram
+// and the traffic class from the control messages of a received message.
+unsafe fn parse_control<P: Copy>(
+    hdr: &libc::msghdr,
+    pktinfo: (libc::c_int, libc::c_int),
+) -> (P, Option<usize>, u8) {
+    let mut info: P = mem::zeroed();
+    let mut segment = None;
+    let mut tos = 0;
+    let mut cmsg = libc::CMSG_FIRSTHDR(hdr);
+    while !cmsg.is_null() {
+        let data = libc::CMSG_DATA(cmsg);
+        match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+            (libc::SOL_UDP, UDP_GRO) => {
+                segment = Some(ptr::read_unaligned(data as *const libc::c_int) as usize)
+            }
+            (libc::IPPROTO_IP, libc::IP_TOS) => tos = *data,
+            (libc::IPPROTO_IPV6, libc::IPV6_TCLASS) => {
+                tos = ptr::read_unaligned(data as *const libc::c_int) as u8
+            }
+            kind if kind == pktinfo => info = ptr::read_unaligned(data as *const P),
+            _ => (),
+        }
+        cmsg = libc::CMSG_NXTHDR(hdr, cmsg);
+    }
+    (info, segment, tos)
+}
+
+// struct sock_extended_err (linux/errqueue.h)
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SockExtendedErr {
+    ee_errno: u32,
+    ee_origin: u8,
+    ee_type: u8,
+    ee_code: u8,
+    ee_pad: u8,
+    ee_info: u32,
+    ee_data: u32,
+}
+
+// Internal function
+//
+// Extract the MTU reported by an EMSGSIZE error from the control messages
+// of a message read from the error queue.
+unsafe fn parse_error(hdr: &libc::msghdr, recverr: (libc::c_int, libc::c_int)) -> Option<usize> {
+    const SO_EE_ORIGIN_NONE: u8 = 0;
+    let mut cmsg = libc::CMSG_FIRSTHDR(hdr);
+    while !cmsg.is_null() {
+        if ((*cmsg).cmsg_level, (*cmsg).cmsg_type) == recverr {
+            let err = ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const SockExtendedErr);
+            if err.ee_errno == libc::EMSGSIZE as u32 && err.ee_origin != SO_EE_ORIGIN_NONE {
+                return Some(err.ee_info as usize);
+            }
+        }
+        cmsg = libc::CMSG_NXTHDR(hdr, cmsg);
+    }
+    None
+}
+
+// Internal function
+//
+// Convert an IPv4-mapped IPv6 address (::ffff:a.b.c.d) into the IPv4 address,
+// the IPv6 socket is bound with IPV6_V6ONLY, hence the packet must be sent over the IPv4 socket.
+fn unmap(addr: SocketAddr) -> SocketAddr {
+    if let SocketAddr::V6(addr6) = addr {
+        let octets = addr6.ip().octets();
+        if octets[..10] == [0u8; 10] && octets[10..12] == [0xff, 0xff] {
+            let ip = Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15]);
+            return SocketAddr::V4(SocketAddrV4::new(ip, addr6.port()));
+        }
+    }
+    addr
+}
+
 impl Endpoint for LinuxEndpoint {
     fn from_address(addr: SocketAddr) -> Self {
-        match addr {
+        match unmap(addr) {
             SocketAddr::V4(addr) => LinuxEndpoint::V4(EndpointV4 {
                 dst: libc::sockaddr_in {
                     sin_family: libc::AF_INET as libc::sa_family_t,
@@ -146,6 +375,7 @@ impl Endpoint for LinuxEndpoint {
                     ipi_spec_dst: libc::in_addr { s_addr: 0 }, // src IP (dst of incoming packet)
                     ipi_addr: libc::in_addr { s_addr: 0 },
                 },
+                tos: 0,
             }),
             SocketAddr::V6(addr) => LinuxEndpoint::V6(EndpointV6 {
                 dst: libc::sockaddr_in6 {
@@ -161,6 +391,7 @@ impl Endpoint for LinuxEndpoint {
                     ipi6_addr: libc::in6_addr { s6_addr: [0; 16] }, // src IP
                     ipi6_ifindex: 0,                                // zone id
                 },
+                tos: 0,
             }),
         }
     }
@@ -174,8 +405,8 @@ impl Endpoint for LinuxEndpoint {
                 ))
             }
             LinuxEndpoint::V6(EndpointV6 { ref dst, .. }) => SocketAddr::V6(SocketAddrV6::new(
-                u128::from_ne_bytes(dst.sin6_addr.s6_addr).into(), // IPv6 addr
-                u16::from_be(dst.sin6_port), // convert back to native byte-order
+                Ipv6Addr::from(dst.sin6_addr.s6_addr), // IPv6 addr
+                u16::from_be(dst.sin6_port),           // convert back to native byte-order
                 dst.sin6_flowinfo,
                 dst.sin6_scope_id,
             )),
@@ -194,126 +425,266 @@ impl Endpoint for LinuxEndpoint {
             }
         };
     }
+
+    fn traffic_class(&self) -> u8 {
+        match self {
+            LinuxEndpoint::V4(EndpointV4 { tos, .. }) => *tos,
+            LinuxEndpoint::V6(EndpointV6 { tos, .. }) => *tos,
+        }
+    }
+}
+
+// length, source address, packet info, segment size (if coalesced) and traffic class
+// of a received datagram
+type Received<A, P> = (usize, A, P, Option<usize>, u8);
+
+thread_local! {
+    // scratch space for coalesced (GRO) datagrams, reused by the reader thread
+    static GRO_SCRATCH: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
 }
 
 impl LinuxUDPReader {
-    fn read6(fd: RawFd, buf: &mut [u8]) -> Result<(usize, LinuxEndpoint), io::Error> {
+    /* Receive a batch of datagrams using recvmmsg,
+     * generic over the socket address and packet info of the IP version.
+     *
+     * Returns the length, source address, packet info and segment size (if coalesced)
+     * of every datagram received. Blocks until the first datagram arrives (MSG_WAITFORONE).
+     */
+    fn recv_mmsg<A: Copy, P: Copy>(
+        sock: &FD,
+        bufs: &mut [&mut [u8]],
+        pktinfo: (libc::c_int, libc::c_int),
+        address: &dyn Fn(A) -> SocketAddr,
+    ) -> Result<Vec<Received<A, P>>, io::Error> {
+        let fd = sock.0;
         log::trace!(
-            "receive IPv6 packet (block), (fd {}, max-len {})",
+            "receive batch (block), (fd {}, max-msgs {})",
             fd,
-            buf.len()
+            bufs.len()
         );
 
-        debug_assert!(!buf.is_empty(), "reading into empty buffer (will fail)");
+        debug_assert!(!bufs.is_empty(), "reading into empty batch (will fail)");
+
+        let mut iovs: Vec<libc::iovec> = bufs
+            .iter_mut()
+            .map(|buf| libc::iovec {
+                iov_base: buf.as_mut_ptr() as *mut core::ffi::c_void,
+                iov_len: buf.len(),
+            })
+            .collect();
+        let mut srcs: Vec<A> = (0..bufs.len()).map(|_| unsafe { mem::zeroed() }).collect();
+        let mut controls: Vec<[u64; CONTROL_WORDS]> = vec![[0; CONTROL_WORDS]; bufs.len()];
+        let mut msgs: Vec<libc::mmsghdr> = iovs
+            .iter_mut()
+            .zip(srcs.iter_mut().zip(controls.iter_mut()))
+            .map(|(iov, (src, control))| libc::mmsghdr {
+                msg_hdr: libc::msghdr {
+                    msg_name: safe_cast(src),
+                    msg_namelen: mem::size_of::<A>() as u32,
+                    msg_iov: iov as *mut libc::iovec,
+                    msg_iovlen: 1,
+                    msg_control: safe_cast(control),
+                    msg_controllen: mem::size_of::<[u64; CONTROL_WORDS]>(),
+                    msg_flags: 0,
+                },
+                msg_len: 0,
+            })
+            .collect();
 
-        let mut iovs: [libc::iovec; 1] = [libc::iovec {
-            iov_base: buf.as_mut_ptr() as *mut core::ffi::c_void,
-            iov_len: buf.len(),
-        }];
-        let mut src: libc::sockaddr_in6 = unsafe { mem::MaybeUninit::uninit().assume_init() };
-        let mut control: ControlHeaderV6 = unsafe { mem::MaybeUninit::uninit().assume_init() };
-        let mut hdr = libc::msghdr {
-            msg_name: safe_cast(&mut src),
-            msg_namelen: mem::size_of_val(&src) as u32,
-            msg_iov: iovs.as_mut_ptr(),
-            msg_iovlen: iovs.len(),
-            msg_control: safe_cast(&mut control),
-            msg_controllen: mem::size_of_val(&control),
-            msg_flags: 0,
+        let ret = loop {
+            let ret = unsafe {
+                libc::recvmmsg(
+                    fd,
+                    msgs.as_mut_ptr(),
+                    msgs.len() as libc::c_uint,
+                    libc::MSG_WAITFORONE,
+                    ptr::null_mut(),
+                )
+            };
+            if ret < 0 && Self::recv_errors(sock, pktinfo.0, address) {
+                continue;
+            }
+            break ret;
         };
 
-        debug_assert!(
-            hdr.msg_controllen
-                >= mem::size_of::<libc::cmsghdr>() + mem::size_of::<libc::in6_pktinfo>(),
-        );
-
-        let len = unsafe { libc::recvmsg(fd, &mut hdr as *mut libc::msghdr, 0) };
+        // the socket was shut down (empty message without source address)
+        let ret = msgs[..ret.max(0) as usize]
+            .iter()
+            .take_while(|msg| msg.msg_hdr.msg_namelen != 0)
+            .count() as libc::c_int;
 
-        if len <= 0 {
-            // TODO: FIX!
+        if ret <= 0 {
             return Err(io::Error::new(
                 io::ErrorKind::NotConnected,
                 format!(
-                    "failed to receive (len = {}, fd = {}, errno = {})",
-                    len,
+                    "failed to receive batch (ret = {}, fd = {}, errno = {})",
+                    ret,
                     fd,
                     errno()
                 ),
             ));
         }
 
-        Ok((
-            len.try_into().unwrap(),
-            LinuxEndpoint::V6(EndpointV6 {
-                info: control.info, // save pktinfo (sticky source)
-                dst: src,           // our future destination is the source address
-            }),
-        ))
+        // our future destination is the source address, save pktinfo (sticky source)
+        Ok(msgs[..ret as usize]
+            .iter()
+            .zip(srcs.iter())
+            .map(|(msg, src)| {
+                let (info, segment, tos) = unsafe { parse_control::<P>(&msg.msg_hdr, pktinfo) };
+                (msg.msg_len as usize, *src, info, segment, tos)
+            })
+            .collect())
     }
 
-    fn read4(fd: RawFd, buf: &mut [u8]) -> Result<(usize, LinuxEndpoint), io::Error> {
-        log::trace!(
-            "receive IPv4 packet (block), (fd {}, max-len {})",
-            fd,
-            buf.len()
+    /* Drain the error queue of the socket (IP_RECVERR / IPV6_RECVERR),
+     * recording the path MTU reported by "fragmentation needed" / "packet too big" errors.
+     *
+     * Returns true if the failed receive was caused by an ICMP error,
+     * in which case the receive should be retried.
+     */
+    fn recv_errors<A>(sock: &FD, level: libc::c_int, address: &dyn Fn(A) -> SocketAddr) -> bool {
+        let icmp = matches!(
+            errno(),
+            libc::ECONNREFUSED
+                | libc::EHOSTUNREACH
+                | libc::ENETUNREACH
+                | libc::EHOSTDOWN
+                | libc::EMSGSIZE
+                | libc::EPROTO
         );
-
-        debug_assert!(!buf.is_empty(), "reading into empty buffer (will fail)");
-
-        let mut iovs: [libc::iovec; 1] = [libc::iovec {
-            iov_base: buf.as_mut_ptr() as *mut core::ffi::c_void,
-            iov_len: buf.len(),
-        }];
-        let mut src: libc::sockaddr_in = unsafe { mem::MaybeUninit::uninit().assume_init() };
-        let mut control: ControlHeaderV4 = unsafe { mem::MaybeUninit::uninit().assume_init() };
-        let mut hdr = libc::msghdr {
-            msg_name: safe_cast(&mut src),
-            msg_namelen: mem::size_of_val(&src) as u32,
-            msg_iov: iovs.as_mut_ptr(),
-            msg_iovlen: iovs.len(),
-            msg_control: safe_cast(&mut control),
-            msg_controllen: mem::size_of_val(&control),
-            msg_flags: 0,
+        let ty = if level == libc::IPPROTO_IP {
+            libc::IP_RECVERR
+        } else {
+            libc::IPV6_RECVERR
         };
+        loop {
+            let mut dst: A = unsafe { mem::zeroed() };
+            let mut control = [0u64; CONTROL_WORDS];
+            let mut hdr = libc::msghdr {
+                msg_name: safe_cast(&mut dst),
+                msg_namelen: mem::size_of::<A>() as u32,
+                msg_iov: ptr::null_mut(),
+                msg_iovlen: 0,
+                msg_control: safe_cast(&mut control),
+                msg_controllen: mem::size_of_val(&control),
+                msg_flags: 0,
+            };
+            let ret = unsafe {
+                libc::recvmsg(
+                    sock.0,
+                    &mut hdr as *mut libc::msghdr,
+                    libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT,
+                )
+            };
+            if ret < 0 {
+                return icmp;
+            }
+            if let Some(mtu) = unsafe { parse_error(&hdr, (level, ty)) } {
+                let dst = address(dst);
+                log::debug!("path MTU to {} reported as {}", dst, mtu);
+                sock.2.lock().unwrap().push((dst, mtu));
+            }
+        }
+    }
 
-        debug_assert!(
-            hdr.msg_controllen
-                >= mem::size_of::<libc::cmsghdr>() + mem::size_of::<libc::in_pktinfo>(),
-        );
-
-        let len = unsafe { libc::recvmsg(fd, &mut hdr as *mut libc::msghdr, 0) };
+    /* Receive a batch of datagrams, one per buffer.
+     *
+     * If UDP_GRO is enabled on the socket, coalesced datagrams are received into
+     * scratch space (for every buffer) and split into their segments:
+     * the segments which do not fit in the batch are kept for the next read.
+     */
+    fn read_batch_raw<A: Copy, P: Copy, F: Fn(A, P, u8) -> LinuxEndpoint>(
+        fd: &FD,
+        bufs: &mut [&mut [u8]],
+        pktinfo: (libc::c_int, libc::c_int),
+        endpoint: F,
+    ) -> Result<Vec<(usize, LinuxEndpoint)>, io::Error> {
+        let address = |dst| endpoint(dst, unsafe { mem::zeroed() }, 0).into_address();
+
+        // segments left over from the previous read
+        let batch = fd.1.take_pending(bufs);
+        if !batch.is_empty() {
+            return Ok(batch);
+        }
 
-        if len <= 0 {
-            return Err(io::Error::new(
-                io::ErrorKind::NotConnected,
-                format!(
-                    "failed to receive (len = {}, fd = {}, errno = {})",
-                    len,
-                    fd,
-                    errno()
-                ),
-            ));
+        if !fd.1.gro {
+            return Ok(Self::recv_mmsg(fd, bufs, pktinfo, &address)?
+                .into_iter()
+                .map(|(len, src, info, _, tos)| (len, endpoint(src, info, tos)))
+                .collect());
         }
 
-        Ok((
-            len.try_into().unwrap(),
-            LinuxEndpoint::V4(EndpointV4 {
-                info: control.info, // save pktinfo (sticky source)
-                dst: src,           // our future destination is the source address
-            }),
-        ))
+        GRO_SCRATCH.with(|scratch| {
+            // every datagram may be coalesced (holding up to MAX_SEGMENTS segments)
+            let mut scratch = scratch.borrow_mut();
+            scratch.resize(bufs.len() * GRO_BUFFER_SIZE, 0);
+            let mut slices: Vec<&mut [u8]> = scratch.chunks_mut(GRO_BUFFER_SIZE).collect();
+            let received = Self::recv_mmsg::<A, P>(fd, &mut slices[..], pktinfo, &address)?;
+
+            // split into segments
+            let mut batch: Vec<(usize, LinuxEndpoint)> = Vec::with_capacity(bufs.len());
+            let mut pending = fd.1.pending.lock().unwrap();
+            for (slice, (len, src, info, segment, tos)) in slices.iter().zip(received) {
+                let segment = segment.unwrap_or(len).max(1);
+                let mut offset = 0;
+                loop {
+                    let seg = &slice[offset..cmp::min(len, offset + segment)];
+                    if batch.len() == bufs.len() {
+                        // batch full: return on the next read
+                        pending.push_back((seg.to_vec(), endpoint(src, info, tos)));
+                    } else {
+                        let buf = &mut bufs[batch.len()];
+                        let size = cmp::min(seg.len(), buf.len());
+                        buf[..size].copy_from_slice(&seg[..size]);
+                        batch.push((size, endpoint(src, info, tos)));
+                    }
+                    offset += segment;
+                    if offset >= len {
+                        break;
+                    }
+                }
+            }
+            Ok(batch)
+        })
     }
 }
 
 impl Reader<LinuxEndpoint> for LinuxUDPReader {
     type Error = io::Error;
 
-    fn read(&self, buf: &mut [u8]) -> Result<(usize, LinuxEndpoint), Self::Error> {
+    fn read_batch(
+        &self,
+        bufs: &mut [&mut [u8]],
+    ) -> Result<Vec<(usize, LinuxEndpoint)>, Self::Error> {
         match self {
-            Self::V4(fd) => Self::read4(fd.0, buf),
-            Self::V6(fd) => Self::read6(fd.0, buf),
+            Self::V4(fd) => Self::read_batch_raw(
+                fd,
+                bufs,
+                (libc::IPPROTO_IP, libc::IP_PKTINFO),
+                |dst, info, tos| LinuxEndpoint::V4(EndpointV4 { info, dst, tos }),
+            ),
+            Self::V6(fd) => Self::read_batch_raw(
+                fd,
+                bufs,
+                (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO),
+                |dst, info, tos| LinuxEndpoint::V6(EndpointV6 { info, dst, tos }),
+            ),
         }
     }
+
+    fn read(&self, buf: &mut [u8]) -> Result<(usize, LinuxEndpoint), Self::Error> {
+        // the other segments of a coalesced datagram are returned by the next reads
+        let mut bufs = [buf];
+        Ok(self.read_batch(&mut bufs[..])?.remove(0))
+    }
+
+    fn path_mtu_updates(&self) -> Vec<(SocketAddr, usize)> {
+        let fd = match self {
+            Self::V4(fd) => fd,
+            Self::V6(fd) => fd,
+        };
+        mem::take(&mut *fd.2.lock().unwrap())
+    }
 }
 
 impl LinuxUDPWriter {
@@ -448,9 +819,189 @@ impl LinuxUDPWriter {
     }
 }
 
+impl LinuxUDPWriter {
+    /* Send a batch of datagrams to the same destination using sendmmsg,
+     * generic over the socket address and packet info of the IP version.
+     *
+     * With UDP_SEGMENT (gso), runs of equally sized datagrams (the last may be shorter)
+     * are sent as a single message, which is segmented by the kernel (or the NIC).
+     *
+     * Returns the number of datagrams sent and the errno on failure.
+     */
+    fn send_mmsg<A, P>(
+        fd: RawFd,
+        bufs: &[&[u8]],
+        gso: bool,
+        dst: &mut A,
+        pktinfo: Option<(libc::c_int, libc::c_int, P)>,
+        tos: (libc::c_int, libc::c_int, libc::c_int),
+    ) -> Result<(), (usize, libc::c_int)> {
+        // group the datagrams into messages
+        let mut groups: Vec<(usize, usize)> = vec![];
+        let mut start = 0;
+        while start < bufs.len() {
+            let size = bufs[start].len();
+            let mut end = start + 1;
+            let mut total = size;
+            while gso
+                && size > 0
+                && end < bufs.len()
+                && end - start < MAX_SEGMENTS
+                && bufs[end - 1].len() == size
+                && bufs[end].len() <= size
+                && total + bufs[end].len() <= MAX_COALESCED_SIZE
+            {
+                total += bufs[end].len();
+                end += 1;
+            }
+            groups.push((start, end));
+            start = end;
+        }
+
+        let mut iovs: Vec<libc::iovec> = bufs
+            .iter()
+            .map(|buf| libc::iovec {
+                iov_base: buf.as_ptr() as *mut core::ffi::c_void,
+                iov_len: buf.len(),
+            })
+            .collect();
+
+        // control messages: sticky source, segment size and traffic class
+        let mut controls: Vec<[u64; CONTROL_WORDS]> = vec![[0; CONTROL_WORDS]; groups.len()];
+        let lens: Vec<usize> = groups
+            .iter()
+            .zip(controls.iter_mut())
+            .map(|(&(start, end), control)| unsafe {
+                let mut len = 0;
+                if let Some((level, ty, info)) = pktinfo.as_ref() {
+                    len = put_control(control, len, *level, *ty, info);
+                }
+                if end - start > 1 {
+                    let segment = bufs[start].len() as u16;
+                    len = put_control(control, len, libc::SOL_UDP, UDP_SEGMENT, &segment);
+                }
+                if tos.2 != 0 {
+                    len = put_control(control, len, tos.0, tos.1, &tos.2);
+                }
+                len
+            })
+            .collect();
+
+        let mut sent = 0;
+        while sent < groups.len() {
+            let mut msgs: Vec<libc::mmsghdr> = groups[sent..]
+                .iter()
+                .zip(controls[sent..].iter_mut().zip(lens[sent..].iter()))
+                .map(|(&(start, end), (control, &len))| libc::mmsghdr {
+                    msg_hdr: libc::msghdr {
+                        msg_name: safe_cast(dst),
+                        msg_namelen: mem::size_of::<A>() as u32,
+                        msg_iov: &mut iovs[start] as *mut libc::iovec,
+                        msg_iovlen: end - start,
+                        msg_control: if len > 0 {
+                            safe_cast(control)
+                        } else {
+                            ptr::null_mut()
+                        },
+                        msg_controllen: len,
+                        msg_flags: 0,
+                    },
+                    msg_len: 0,
+                })
+                .collect();
+
+            let ret =
+                unsafe { libc::sendmmsg(fd, msgs.as_mut_ptr(), msgs.len() as libc::c_uint, 0) };
+            if ret <= 0 {
+                return Err((groups[sent].0, errno()));
+            }
+            sent += ret as usize;
+        }
+        Ok(())
+    }
+
+    fn write_batch_to<A, P: Copy>(
+        sock: &FD,
+        bufs: &[&[u8]],
+        dst: &mut A,
+        info: &mut P,
+        pktinfo: (libc::c_int, libc::c_int),
+        tos: (libc::c_int, libc::c_int, libc::c_int),
+    ) -> Result<(), io::Error> {
+        log::debug!("sending batch ({} fd, {} msgs)", sock.0, bufs.len());
+
+        let mut bufs = bufs;
+        let mut sticky = true;
+        loop {
+            let gso = sock.1.gso.load(Ordering::Relaxed);
+            let control = if sticky {
+                Some((pktinfo.0, pktinfo.1, *info))
+            } else {
+                None
+            };
+            match Self::send_mmsg(sock.0, bufs, gso, dst, control, tos) {
+                Ok(()) => return Ok(()),
+                Err((sent, libc::EINVAL)) if sticky => {
+                    log::trace!("clear source and retry");
+                    *info = unsafe { mem::zeroed() };
+                    sticky = false;
+                    bufs = &bufs[sent..];
+                }
+                Err((sent, libc::EIO)) if gso => {
+                    // the egress device does not support checksum offloading
+                    log::debug!("disable UDP GSO and retry (fd = {})", sock.0);
+                    sock.1.gso.store(false, Ordering::Relaxed);
+                    bufs = &bufs[sent..];
+                }
+                Err(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotConnected,
+                        "failed to send batch",
+                    ))
+                }
+            }
+        }
+    }
+}
+
 impl Writer<LinuxEndpoint> for LinuxUDPWriter {
     type Error = io::Error;
 
+    fn write_batch(
+        &self,
+        bufs: &[&[u8]],
+        dst: &mut LinuxEndpoint,
+        tos: u8,
+    ) -> Result<(), Self::Error> {
+        let tos = libc::c_int::from(tos);
+        match dst {
+            LinuxEndpoint::V4(EndpointV4 {
+                ref mut dst,
+                ref mut info,
+                ..
+            }) => Self::write_batch_to(
+                &self.sock4,
+                bufs,
+                dst,
+                info,
+                (libc::IPPROTO_IP, libc::IP_PKTINFO),
+                (libc::IPPROTO_IP, libc::IP_TOS, tos),
+            ),
+            LinuxEndpoint::V6(EndpointV6 {
+                ref mut dst,
+                ref mut info,
+                ..
+            }) => Self::write_batch_to(
+                &self.sock6,
+                bufs,
+                dst,
+                info,
+                (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO),
+                (libc::IPPROTO_IPV6, libc::IPV6_TCLASS, tos),
+            ),
+        }
+    }
+
     fn write(&self, buf: &[u8], dst: &mut LinuxEndpoint) -> Result<(), Self::Error> {
         match dst {
             LinuxEndpoint::V4(ref mut end) => Self::write4(self.sock4.0, buf, end),
@@ -467,34 +1018,71 @@ impl Owner for LinuxOwner {
     }
 
     fn set_fwmark(&mut self, value: Option<u32>) -> Result<(), Self::Error> {
-        fn set_mark(fd: Option<RawFd>, value: u32) -> Result<(), io::Error> {
-            if let Some(fd) = fd {
-                setsockopt(fd, libc::SOL_SOCKET, libc::SO_MARK, &value)
-            } else {
-                Ok(())
+        let value = value.unwrap_or(0);
+        for fd in self.sock6.iter().chain(self.sock4.iter()) {
+            setsockopt(fd.0, libc::SOL_SOCKET, libc::SO_MARK, &value)?;
+        }
+        Ok(())
+    }
+
+    fn set_interface(&mut self, name: Option<&str>) -> Result<(), Self::Error> {
+        // an empty name removes the binding
+        let name = name.unwrap_or("").as_bytes();
+        if name.len() >= libc::IFNAMSIZ {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "interface name too long",
+            ));
+        }
+        for fd in self.sock6.iter().chain(self.sock4.iter()) {
+            let res = unsafe {
+                libc::setsockopt(
+                    fd.0,
+                    libc::SOL_SOCKET,
+                    libc::SO_BINDTODEVICE,
+                    name.as_ptr() as *const libc::c_void,
+                    name.len() as libc::socklen_t,
+                )
+            };
+            if res != 0 {
+                return Err(io::Error::other(format!(
+                    "Failed to bind to device (res = {}, errno = {})",
+                    res,
+                    errno()
+                )));
             }
         }
-        let value = value.unwrap_or(0);
-        set_mark(self.sock6.as_ref().map(|fd| fd.0), value)?;
-        set_mark(self.sock4.as_ref().map(|fd| fd.0), value)
+        Ok(())
+    }
+
+    fn set_ttl(&mut self, ttl: Option<u8>) -> Result<(), Self::Error> {
+        // -1 selects the system default
+        let value = ttl.map(libc::c_int::from).unwrap_or(-1);
+        for fd in &self.sock4 {
+            setsockopt_int(fd.0, libc::IPPROTO_IP, libc::IP_TTL, value)?;
+        }
+        for fd in &self.sock6 {
+            setsockopt_int(fd.0, libc::IPPROTO_IPV6, libc::IPV6_UNICAST_HOPS, value)?;
+        }
+        Ok(())
     }
 }
 
 impl Drop for LinuxOwner {
     fn drop(&mut self) {
         log::debug!("closing the bind (port = {})", self.port);
-        if let Some(fd) = &self.sock4 {
+        for fd in &self.sock4 {
             log::debug!("shutdown IPv4 (fd = {})", fd.0);
             unsafe {
                 libc::shutdown(fd.0, libc::SHUT_RDWR);
             }
-        };
-        if let Some(fd) = &self.sock6 {
+        }
+        for fd in &self.sock6 {
             log::debug!("shutdown IPv6 (fd = {})", fd.0);
             unsafe {
                 libc::shutdown(fd.0, libc::SHUT_RDWR);
             }
-        };
+        }
     }
 }
 
@@ -511,12 +1099,13 @@ impl LinuxUDP {
      * Arguments:
      *
      * - 'port', port to bind to (0 = any)
+     * - 'reuseport', enable SO_REUSEPORT (multiple sockets on the same port)
      *
      * Returns:
      *
      * Returns a tuple of the resulting port and socket.
      */
-    fn bind6(port: u16) -> Result<(u16, RawFd), io::Error> {
+    fn bind6(port: u16, reuseport: bool) -> Result<(u16, RawFd), io::Error> {
         log::trace!("attempting to bind on IPv6 (port {})", port);
 
         // create socket fd
@@ -529,9 +1118,19 @@ impl LinuxUDP {
             ));
         }
 
+        if let Err(e) = protect(fd) {
+            unsafe { libc::close(fd) };
+            return Err(e);
+        }
+
         setsockopt_int(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR, 1)?;
         setsockopt_int(fd, libc::IPPROTO_IPV6, libc::IPV6_RECVPKTINFO, 1)?;
         setsockopt_int(fd, libc::IPPROTO_IPV6, libc::IPV6_V6ONLY, 1)?;
+        setsockopt_int(fd, libc::IPPROTO_IPV6, libc::IPV6_RECVERR, 1)?;
+        setsockopt_int(fd, libc::IPPROTO_IPV6, libc::IPV6_RECVTCLASS, 1)?;
+        if reuseport {
+            setsockopt_int(fd, libc::SOL_SOCKET, libc::SO_REUSEPORT, 1)?;
+        }
 
         const INADDR_ANY: libc::in6_addr = libc::in6_addr { s6_addr: [0; 16] };
 
@@ -590,12 +1189,13 @@ impl LinuxUDP {
      * Arguments:
      *
      * - 'port', port to bind to (0 = any)
+     * - 'reuseport', enable SO_REUSEPORT (multiple sockets on the same port)
      *
      * Returns:
      *
      * Returns a tuple of the resulting port and socket.
      */
-    fn bind4(port: u16) -> Result<(u16, RawFd), io::Error> {
+    fn bind4(port: u16, reuseport: bool) -> Result<(u16, RawFd), io::Error> {
         log::trace!("attempting to bind on IPv4 (port {})", port);
 
         // create socket fd
@@ -608,8 +1208,18 @@ impl LinuxUDP {
             ));
         }
 
+        if let Err(e) = protect(fd) {
+            unsafe { libc::close(fd) };
+            return Err(e);
+        }
+
         setsockopt_int(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR, 1)?;
         setsockopt_int(fd, libc::IPPROTO_IP, libc::IP_PKTINFO, 1)?;
+        setsockopt_int(fd, libc::IPPROTO_IP, libc::IP_RECVERR, 1)?;
+        setsockopt_int(fd, libc::IPPROTO_IP, libc::IP_RECVTOS, 1)?;
+        if reuseport {
+            setsockopt_int(fd, libc::SOL_SOCKET, libc::SO_REUSEPORT, 1)?;
+        }
 
         const INADDR_ANY: libc::in_addr = libc::in_addr { s_addr: 0 };
 
@@ -661,22 +1271,58 @@ impl LinuxUDP {
         log::trace!("bound IPv4 socket (port {}, fd {})", new_port, fd);
         Ok((new_port, fd))
     }
-}
 
-impl PlatformUDP for LinuxUDP {
-    type Owner = LinuxOwner;
+    /* Attach a classic BPF program to the reuseport group of the socket,
+     * which steers transport messages to socket (receiver % workers).
+     * Other messages return an out-of-range index and are distributed by flow hash.
+     *
+     * Note that the receiver index is loaded in network byte-order,
+     * which does not matter since the index is random.
+     */
+    fn attach_steering(fd: RawFd, workers: usize) -> Result<(), io::Error> {
+        const SO_ATTACH_REUSEPORT_CBPF: libc::c_int = 51;
+        const LD_B_ABS: u16 = 0x30; // BPF_LD | BPF_B | BPF_ABS
+        const LD_W_ABS: u16 = 0x20; // BPF_LD | BPF_W | BPF_ABS
+        const JEQ_K: u16 = 0x15; // BPF_JMP | BPF_JEQ | BPF_K
+        const MOD_K: u16 = 0x94; // BPF_ALU | BPF_MOD | BPF_K
+        const RET_A: u16 = 0x16; // BPF_RET | BPF_A
+        const RET_K: u16 = 0x06; // BPF_RET | BPF_K
+
+        let insn = |code, jt, jf, k| libc::sock_filter { code, jt, jf, k };
+        let mut filter = [
+            insn(LD_B_ABS, 0, 0, 0), // message type (UDP payload offset 0)
+            insn(JEQ_K, 0, 3, 4),    // transport message?
+            insn(LD_W_ABS, 0, 0, 4), // receiver index
+            insn(MOD_K, 0, 0, workers as u32),
+            insn(RET_A, 0, 0, 0),
+            insn(RET_K, 0, 0, u32::MAX),
+        ];
+        let prog = libc::sock_fprog {
+            len: filter.len() as u16,
+            filter: filter.as_mut_ptr(),
+        };
+        setsockopt(fd, libc::SOL_SOCKET, SO_ATTACH_REUSEPORT_CBPF, &prog)
+    }
 
-    fn bind(mut port: u16) -> Result<(Vec<Self::Reader>, Self::Writer, Self::Owner), Self::Error> {
-        log::debug!("bind to port {}", port);
+    /// Bind multiple sockets (for each IP version) to the same port using SO_REUSEPORT,
+    /// see `PlatformUDP::bind_reuseport`.
+    fn bind_group(
+        mut port: u16,
+        workers: usize,
+        steer: bool,
+    ) -> Result<(Vec<LinuxUDPReader>, LinuxUDPWriter, LinuxOwner), io::Error> {
+        let workers = workers.max(1);
+        let reuseport = workers > 1;
+        log::debug!("bind to port {} (workers = {})", port, workers);
 
         // attempt to bind on ipv6
-        let bind6 = Self::bind6(port);
+        let bind6 = Self::bind6(port, reuseport);
         if let Ok((new_port, _)) = bind6 {
             port = new_port;
         }
 
         // attempt to bind on ipv4 on the same port
-        let bind4 = Self::bind4(port);
+        let bind4 = Self::bind4(port, reuseport);
         if let Ok((new_port, _)) = bind4 {
             port = new_port;
         }
@@ -687,32 +1333,531 @@ impl PlatformUDP for LinuxUDP {
             return Err(bind6.unwrap_err());
         }
 
-        let sock6 = bind6.ok().map(|(_, fd)| Arc::new(FD(fd)));
-        let sock4 = bind4.ok().map(|(_, fd)| Arc::new(FD(fd)));
+        let mut sock6: Vec<Arc<FD>> = bind6
+            .ok()
+            .map(|(_, fd)| Arc::new(FD::new(fd)))
+            .into_iter()
+            .collect();
+        let mut sock4: Vec<Arc<FD>> = bind4
+            .ok()
+            .map(|(_, fd)| Arc::new(FD::new(fd)))
+            .into_iter()
+            .collect();
+
+        // join the remaining sockets to the reuseport groups
+        for _ in 1..workers {
+            if !sock6.is_empty() {
+                let (_, fd) = Self::bind6(port, true)?;
+                sock6.push(Arc::new(FD::new(fd)));
+            }
+            if !sock4.is_empty() {
+                let (_, fd) = Self::bind4(port, true)?;
+                sock4.push(Arc::new(FD::new(fd)));
+            }
+        }
 
-        // create owner
-        let owner = LinuxOwner {
-            port,
-            sock6: sock6.clone(),
-            sock4: sock4.clone(),
-        };
+        // steer transport messages by receiver index
+        if steer && reuseport {
+            for group in [&sock6, &sock4].iter() {
+                if let Some(fd) = group.first() {
+                    Self::attach_steering(fd.0, workers)?;
+                }
+            }
+        }
 
         // create readers
-        let mut readers: Vec<Self::Reader> = Vec::with_capacity(2);
-        if let Some(sock) = sock6.clone() {
-            readers.push(LinuxUDPReader::V6(sock))
+        let mut readers: Vec<LinuxUDPReader> = Vec::with_capacity(2 * workers);
+        for sock in &sock6 {
+            readers.push(LinuxUDPReader::V6(sock.clone()))
         }
-        if let Some(sock) = sock4.clone() {
-            readers.push(LinuxUDPReader::V4(sock))
+        for sock in &sock4 {
+            readers.push(LinuxUDPReader::V4(sock.clone()))
         }
         debug_assert!(!readers.is_empty());
 
         // create writer
         let writer = LinuxUDPWriter {
-            sock4: sock4.unwrap_or_else(|| Arc::new(FD(-1))),
-            sock6: sock6.unwrap_or_else(|| Arc::new(FD(-1))),
+            sock4: sock4
+                .first()
+                .cloned()
+                .unwrap_or_else(|| Arc::new(FD(-1, Offload::default(), Mutex::default()))),
+            sock6: sock6
+                .first()
+                .cloned()
+                .unwrap_or_else(|| Arc::new(FD(-1, Offload::default(), Mutex::default()))),
         };
 
+        // create owner
+        let owner = LinuxOwner { port, sock6, sock4 };
+
         Ok((readers, writer, owner))
     }
 }
+
+impl PlatformUDP for LinuxUDP {
+    type Owner = LinuxOwner;
+
+    fn bind(port: u16) -> Result<(Vec<Self::Reader>, Self::Writer, Self::Owner), Self::Error> {
+        Self::bind_group(port, 1, false)
+    }
+
+    fn bind_reuseport(port: u16, workers: usize, steer: bool) -> Result<Bound<Self>, Self::Error> {
+        Self::bind_group(port, workers, steer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::UdpSocket;
+    use std::os::unix::io::AsRawFd;
+
+    /* Check sticky sockets:
+     * replies are sent from the local address the request was received on,
+     * even if the routing table would select another source address.
+     */
+    #[test]
+    fn test_sticky_source() {
+        let (readers, writer, owner) = LinuxUDP::bind(0).unwrap();
+        let reader = readers
+            .into_iter()
+            .find(|r| match r {
+                LinuxUDPReader::V4(_) => true,
+                LinuxUDPReader::V6(_) => false,
+            })
+            .unwrap();
+
+        // any address in 127.0.0.0/8 is local
+        let local: SocketAddr = format!("127.0.0.2:{}", owner.get_port()).parse().unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(b"request", local).unwrap();
+
+        let mut buf = [0u8; 64];
+        let (len, mut src) = reader.read(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"request");
+        assert_eq!(src.into_address(), client.local_addr().unwrap());
+
+        writer.write(b"reply", &mut src).unwrap();
+        let (len, from) = client.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"reply");
+        assert_eq!(
+            from, local,
+            "reply should be sent from the address of the request"
+        );
+
+        // after clearing the source, the routing table selects the address
+        src.clear_src();
+        writer.write(b"reply", &mut src).unwrap();
+        let (_, from) = client.recv_from(&mut buf).unwrap();
+        assert_eq!(from.port(), local.port());
+    }
+
+    #[test]
+    fn test_endpoint_address() {
+        for addr in &[
+            "192.0.2.1:51820",
+            "[2001:db8::1]:51820",
+            "[fe80::1%2]:51820",
+            "[::1]:1",
+        ] {
+            let addr: SocketAddr = addr.parse().unwrap();
+            assert_eq!(LinuxEndpoint::from_address(addr).into_address(), addr);
+        }
+
+        // IPv4-mapped addresses are sent over the IPv4 socket
+        let addr: SocketAddr = "[::ffff:192.0.2.1]:51820".parse().unwrap();
+        let endpoint = LinuxEndpoint::from_address(addr);
+        assert!(match endpoint {
+            LinuxEndpoint::V4(_) => true,
+            LinuxEndpoint::V6(_) => false,
+        });
+        assert_eq!(
+            endpoint.into_address(),
+            "192.0.2.1:51820".parse::<SocketAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_batch_io() {
+        let (readers, writer, owner) = LinuxUDP::bind(0).unwrap();
+        let reader = readers
+            .into_iter()
+            .find(|r| match r {
+                LinuxUDPReader::V4(_) => true,
+                LinuxUDPReader::V6(_) => false,
+            })
+            .unwrap();
+
+        let local: SocketAddr = format!("127.0.0.1:{}", owner.get_port()).parse().unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        for i in 0..8u8 {
+            client.send_to(&[i; 16][..(i as usize + 1)], local).unwrap();
+        }
+
+        // receive all datagrams (in order), possibly over several batches
+        let mut received = vec![];
+        let mut src = None;
+        while received.len() < 8 {
+            let mut bufs = vec![vec![0u8; 64]; 4];
+            let mut slices: Vec<&mut [u8]> = bufs.iter_mut().map(|buf| &mut buf[..]).collect();
+            let batch = reader.read_batch(&mut slices[..]).unwrap();
+            assert!(!batch.is_empty() && batch.len() <= 4);
+            for (buf, (len, from)) in bufs.iter().zip(batch) {
+                assert_eq!(from.into_address(), client.local_addr().unwrap());
+                received.push(buf[..len].to_vec());
+                src = Some(from);
+            }
+        }
+        for (i, msg) in received.iter().enumerate() {
+            assert_eq!(msg, &vec![i as u8; i + 1]);
+        }
+
+        // send the datagrams back in a single batch
+        let msgs: Vec<&[u8]> = received.iter().map(|msg| &msg[..]).collect();
+        writer
+            .write_batch(&msgs[..], src.as_mut().unwrap(), 0)
+            .unwrap();
+        let mut buf = [0u8; 64];
+        for msg in &received {
+            let (len, from) = client.recv_from(&mut buf).unwrap();
+            assert_eq!(from, local);
+            assert_eq!(&buf[..len], &msg[..]);
+        }
+    }
+
+    /* Check segmentation offloads:
+     * runs of equally sized datagrams are coalesced on send (GSO)
+     * and possibly delivered coalesced to the receiver (GRO), which must split them.
+     */
+    #[test]
+    fn test_offload() {
+        let bind = || {
+            let (readers, writer, owner) = LinuxUDP::bind(0).unwrap();
+            let reader = readers
+                .into_iter()
+                .find(|r| match r {
+                    LinuxUDPReader::V4(_) => true,
+                    LinuxUDPReader::V6(_) => false,
+                })
+                .unwrap();
+            (reader, writer, owner)
+        };
+        let ((_, writer, _owner1), (reader, _, owner2)) = (bind(), bind());
+
+        // 10 segments and a shorter trailing segment
+        let mut msgs: Vec<Vec<u8>> = (0..10u8).map(|i| vec![i; 200]).collect();
+        msgs.push(vec![10; 50]);
+
+        let dst: SocketAddr = format!("127.0.0.1:{}", owner2.get_port()).parse().unwrap();
+        let mut dst = LinuxEndpoint::from_address(dst);
+        let bufs: Vec<&[u8]> = msgs.iter().map(|msg| &msg[..]).collect();
+        writer.write_batch(&bufs[..], &mut dst, 0).unwrap();
+
+        // batches smaller than a coalesced datagram: the remaining segments are not lost
+        let mut received = vec![];
+        while received.len() < msgs.len() {
+            let mut bufs = vec![vec![0u8; 256]; 4];
+            let mut slices: Vec<&mut [u8]> = bufs.iter_mut().map(|buf| &mut buf[..]).collect();
+            let batch = reader.read_batch(&mut slices[..]).unwrap();
+            assert!(batch.len() <= 4);
+            for (buf, (len, _)) in bufs.iter().zip(batch) {
+                received.push(buf[..len].to_vec());
+            }
+        }
+        assert_eq!(received, msgs);
+
+        // single datagram reads return every segment
+        writer.write_batch(&bufs[..], &mut dst, 0).unwrap();
+        let mut received = vec![];
+        while received.len() < msgs.len() {
+            let mut buf = [0u8; 256];
+            let (len, _) = reader.read(&mut buf).unwrap();
+            received.push(buf[..len].to_vec());
+        }
+        assert_eq!(received, msgs);
+    }
+
+    #[test]
+    fn test_reuseport_steering() {
+        const WORKERS: usize = 4;
+        let (readers, _writer, owner) = LinuxUDP::bind_reuseport(0, WORKERS, true).unwrap();
+        let readers: Vec<_> = readers
+            .into_iter()
+            .filter(|r| match r {
+                LinuxUDPReader::V4(_) => true,
+                LinuxUDPReader::V6(_) => false,
+            })
+            .collect();
+        assert_eq!(readers.len(), WORKERS);
+        assert_eq!(owner.sock4.len(), WORKERS);
+
+        // fail rather than block if a message is steered to another socket
+        let timeout = libc::timeval {
+            tv_sec: 1,
+            tv_usec: 0,
+        };
+        for fd in &owner.sock4 {
+            setsockopt(fd.0, libc::SOL_SOCKET, libc::SO_RCVTIMEO, &timeout).unwrap();
+        }
+
+        let local: SocketAddr = format!("127.0.0.1:{}", owner.get_port()).parse().unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut buf = [0u8; 64];
+        for receiver in 0..(2 * WORKERS as u32) {
+            // transport message header: type, reserved, receiver (loaded big-endian)
+            let mut msg = [0u8; 32];
+            msg[0] = 4;
+            msg[4..8].copy_from_slice(&receiver.to_be_bytes());
+            client.send_to(&msg, local).unwrap();
+
+            let reader = &readers[receiver as usize % WORKERS];
+            let (len, _) = reader.read(&mut buf).unwrap();
+            assert_eq!(&buf[..len], &msg[..]);
+        }
+    }
+
+    #[test]
+    fn test_recv_error() {
+        let (readers, writer, owner) = LinuxUDP::bind(0).unwrap();
+        let reader = readers
+            .into_iter()
+            .find(|r| match r {
+                LinuxUDPReader::V4(_) => true,
+                LinuxUDPReader::V6(_) => false,
+            })
+            .unwrap();
+
+        // send to a closed port: the ICMP port unreachable is queued on the socket
+        let closed = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut dst = LinuxEndpoint::from_address(closed.local_addr().unwrap());
+        drop(closed);
+        writer.write(b"probe", &mut dst).unwrap();
+
+        // the error does not interrupt the reader
+        let local: SocketAddr = format!("127.0.0.1:{}", owner.get_port()).parse().unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(b"request", local).unwrap();
+        let mut buf = [0u8; 64];
+        let (len, src) = reader.read(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"request");
+        assert_eq!(src.into_address(), client.local_addr().unwrap());
+
+        // port unreachable carries no path MTU
+        assert!(reader.path_mtu_updates().is_empty());
+    }
+
+    #[test]
+    fn test_protect() {
+        let protected = Arc::new(Mutex::new(vec![]));
+        {
+            let protected = protected.clone();
+            set_protect(Some(Box::new(move |fd| {
+                protected.lock().unwrap().push(fd);
+                true
+            })));
+        }
+        let bind = LinuxUDP::bind(0);
+        set_protect(None);
+        let (_readers, _writer, owner) = bind.unwrap();
+
+        // every socket is protected before use
+        let protected = protected.lock().unwrap();
+        for fd in owner.sock4.iter().chain(owner.sock6.iter()) {
+            assert!(protected.contains(&fd.0));
+        }
+    }
+
+    fn get_mark(fd: RawFd) -> u32 {
+        let mut value: u32 = 0;
+        let mut len = mem::size_of_val(&value) as libc::socklen_t;
+        let res = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_MARK,
+                &mut value as *mut u32 as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(res, 0);
+        value
+    }
+
+    #[test]
+    #[ignore = "setting the mark requires CAP_NET_ADMIN"]
+    fn test_fwmark() {
+        let (_readers, _writer, mut owner) = LinuxUDP::bind(0).unwrap();
+        owner.set_fwmark(Some(0x51820)).unwrap();
+        for fd in owner.sock4.iter().chain(owner.sock6.iter()) {
+            assert_eq!(get_mark(fd.0), 0x51820);
+        }
+
+        // clearing the mark resets it to zero
+        owner.set_fwmark(None).unwrap();
+        for fd in owner.sock4.iter().chain(owner.sock6.iter()) {
+            assert_eq!(get_mark(fd.0), 0);
+        }
+    }
+
+    fn get_int(fd: RawFd, level: libc::c_int, name: libc::c_int) -> libc::c_int {
+        let mut value: libc::c_int = 0;
+        let mut len = mem::size_of_val(&value) as libc::socklen_t;
+        let res = unsafe {
+            libc::getsockopt(
+                fd,
+                level,
+                name,
+                &mut value as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(res, 0);
+        value
+    }
+
+    #[test]
+    fn test_ttl() {
+        let (readers, writer, mut owner) = LinuxUDP::bind(0).unwrap();
+
+        owner.set_ttl(Some(7)).unwrap();
+        for fd in &owner.sock4 {
+            assert_eq!(get_int(fd.0, libc::IPPROTO_IP, libc::IP_TTL), 7);
+        }
+        for fd in &owner.sock6 {
+            assert_eq!(
+                get_int(fd.0, libc::IPPROTO_IPV6, libc::IPV6_UNICAST_HOPS),
+                7
+            );
+        }
+
+        // the TTL is carried by the outer packets
+        let reader = readers
+            .iter()
+            .find(|r| match r {
+                LinuxUDPReader::V4(_) => true,
+                LinuxUDPReader::V6(_) => false,
+            })
+            .unwrap();
+        let local: SocketAddr = format!("127.0.0.1:{}", owner.get_port()).parse().unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        setsockopt_int(client.as_raw_fd(), libc::IPPROTO_IP, libc::IP_RECVTTL, 1).unwrap();
+        client.send_to(b"request", local).unwrap();
+        let mut buf = [0u8; 64];
+        let (_, mut src) = reader.read(&mut buf).unwrap();
+        writer.write(b"reply", &mut src).unwrap();
+        assert_eq!(recv_control(client.as_raw_fd(), libc::IP_TTL), 7);
+
+        // reset to the system default
+        owner.set_ttl(None).unwrap();
+        for fd in &owner.sock4 {
+            assert_ne!(get_int(fd.0, libc::IPPROTO_IP, libc::IP_TTL), 7);
+        }
+    }
+
+    // receive a datagram and return an IPv4 header field from the control message
+    // (requires IP_RECVTTL / IP_RECVTOS)
+    fn recv_control(fd: RawFd, ty: libc::c_int) -> u8 {
+        let mut buf = [0u8; 64];
+        let mut iovs = [libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        }];
+        let mut control = [0u64; CONTROL_WORDS];
+        let mut hdr = libc::msghdr {
+            msg_name: ptr::null_mut(),
+            msg_namelen: 0,
+            msg_iov: iovs.as_mut_ptr(),
+            msg_iovlen: iovs.len(),
+            msg_control: safe_cast(&mut control),
+            msg_controllen: mem::size_of_val(&control),
+            msg_flags: 0,
+        };
+        assert!(unsafe { libc::recvmsg(fd, &mut hdr, 0) } > 0);
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&hdr);
+            assert!(!cmsg.is_null());
+            assert_eq!((*cmsg).cmsg_type, ty);
+            *libc::CMSG_DATA(cmsg)
+        }
+    }
+
+    #[test]
+    fn test_traffic_class() {
+        let (readers, writer, owner) = LinuxUDP::bind(0).unwrap();
+        let reader = readers
+            .into_iter()
+            .find(|r| match r {
+                LinuxUDPReader::V4(_) => true,
+                LinuxUDPReader::V6(_) => false,
+            })
+            .unwrap();
+
+        // the traffic class of a received packet is stored in the endpoint
+        let local: SocketAddr = format!("127.0.0.1:{}", owner.get_port()).parse().unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        setsockopt_int(
+            client.as_raw_fd(),
+            libc::IPPROTO_IP,
+            libc::IP_TOS,
+            46 << 2 | 1,
+        )
+        .unwrap();
+        setsockopt_int(client.as_raw_fd(), libc::IPPROTO_IP, libc::IP_RECVTOS, 1).unwrap();
+        client.send_to(b"request", local).unwrap();
+        let mut buf = [0u8; 64];
+        let (_, mut src) = reader.read(&mut buf).unwrap();
+        assert_eq!(src.traffic_class(), 46 << 2 | 1);
+
+        // the traffic class of sent packets is set per batch
+        writer
+            .write_batch(&[b"reply"], &mut src, 10 << 2 | 2)
+            .unwrap();
+        assert_eq!(recv_control(client.as_raw_fd(), libc::IP_TOS), 10 << 2 | 2);
+        writer.write_batch(&[b"reply"], &mut src, 0).unwrap();
+        assert_eq!(recv_control(client.as_raw_fd(), libc::IP_TOS), 0);
+    }
+
+    fn get_interface(fd: RawFd) -> String {
+        let mut name = [0u8; libc::IFNAMSIZ];
+        let mut len = name.len() as libc::socklen_t;
+        let res = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_BINDTODEVICE,
+                name.as_mut_ptr() as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(res, 0);
+        let len = name.iter().position(|c| *c == 0).unwrap_or(name.len());
+        String::from_utf8_lossy(&name[..len]).into_owned()
+    }
+
+    #[test]
+    fn test_interface_name() {
+        let (_readers, _writer, mut owner) = LinuxUDP::bind(0).unwrap();
+
+        // names must fit in IFNAMSIZ (including the terminator)
+        assert!(owner.set_interface(Some("interface-name-16")).is_err());
+
+        // no such device
+        assert!(owner.set_interface(Some("wg-no-such-if")).is_err());
+    }
+
+    #[test]
+    #[ignore = "binding to a device requires CAP_NET_RAW"]
+    fn test_bind_interface() {
+        let (_readers, _writer, mut owner) = LinuxUDP::bind(0).unwrap();
+        owner.set_interface(Some("lo")).unwrap();
+        for fd in owner.sock4.iter().chain(owner.sock6.iter()) {
+            assert_eq!(get_interface(fd.0), "lo");
+        }
+
+        // clearing the interface removes the binding
+        owner.set_interface(None).unwrap();
+        for fd in owner.sock4.iter().chain(owner.sock6.iter()) {
+            assert_eq!(get_interface(fd.0), "");
+        }
+    }
+}
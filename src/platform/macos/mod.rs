@@ -0,0 +1,7 @@
+mod tun;
+mod uapi;
+mod udp;
+
+pub use tun::MacosTun as Tun;
+pub use uapi::MacosUAPI as UAPI;
+pub use udp::MacosUDP as UDP;
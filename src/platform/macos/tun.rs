@@ -0,0 +1,395 @@
+use super::super::tun::*;
+
+use std::error::Error;
+use std::fmt;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::ptr;
+
+const UTUN_CONTROL_NAME: &[u8] = b"com.apple.net.utun_control\0";
+
+// every packet read from / written to a utun device is prefixed by its address family
+const SIZE_AF_HEADER: usize = 4;
+
+// ioctls on an ifreq (not exported by libc for this platform)
+const SIOCGIFMTU: libc::c_ulong = 0xc020_6933;
+const SIOCSIFMTU: libc::c_ulong = 0x8020_6934;
+
+#[repr(C)]
+struct IfreqMTU {
+    name: [u8; libc::IFNAMSIZ],
+    mtu: libc::c_int,
+    _pad: [u8; 12],
+}
+
+pub struct MacosTun {}
+
+pub struct MacosTunReader {
+    fd: RawFd,
+}
+
+pub struct MacosTunWriter {
+    fd: RawFd,
+}
+
+pub struct MacosTunStatus {
+    events: Vec<TunEvent>,
+    mtu: Option<usize>, // MTU of the last reported up event (None if down)
+    index: libc::c_ushort,
+    name: [u8; libc::IFNAMSIZ],
+    fd: RawFd, // routing socket
+}
+
+#[derive(Debug)]
+pub enum MacosTunError {
+    InvalidTunDeviceName,
+    FailedToOpenControlSocket,
+    ControlInfoIoctlFailed,
+    ConnectFailed,
+    GetNameFailed,
+    GetMTUIoctlFailed,
+    SetMTUIoctlFailed,
+    SetOwnerUnsupported,
+    RoutingSocketFailure,
+    Closed,
+}
+
+impl fmt::Display for MacosTunError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MacosTunError::InvalidTunDeviceName => {
+                write!(f, "Invalid name (must be \"utun\" or \"utun[0-9]+\")")
+            }
+            MacosTunError::FailedToOpenControlSocket => {
+                write!(f, "Failed to open kernel control socket")
+            }
+            MacosTunError::ControlInfoIoctlFailed => write!(f, "ctliocginfo ioctl failed"),
+            MacosTunError::ConnectFailed => {
+                write!(f, "Failed to connect utun control (device in use?)")
+            }
+            MacosTunError::GetNameFailed => write!(f, "Failed to obtain utun interface name"),
+            MacosTunError::GetMTUIoctlFailed => write!(f, "ifmtu ioctl failed"),
+            MacosTunError::SetMTUIoctlFailed => {
+                write!(f, "set ifmtu ioctl failed (insufficient permissions?)")
+            }
+            MacosTunError::SetOwnerUnsupported => {
+                write!(f, "utun devices can not be assigned an owner")
+            }
+            MacosTunError::RoutingSocketFailure => write!(f, "Routing socket listener error"),
+            MacosTunError::Closed => write!(f, "The tunnel has been closed"),
+        }
+    }
+}
+
+impl Error for MacosTunError {}
+
+impl Reader for MacosTunReader {
+    type Error = MacosTunError;
+
+    fn read(&self, buf: &mut [u8], offset: usize) -> Result<usize, Self::Error> {
+        // the address family header is read into the space reserved for the prefix
+        debug_assert!(
+            offset >= SIZE_AF_HEADER,
+            "There is no space for the address family header"
+        );
+        let start = offset - SIZE_AF_HEADER;
+        let n: isize =
+            unsafe { libc::read(self.fd, buf[start..].as_mut_ptr() as _, buf.len() - start) };
+        if n < SIZE_AF_HEADER as isize {
+            Err(MacosTunError::Closed)
+        } else {
+            // conversion is safe
+            Ok(n as usize - SIZE_AF_HEADER)
+        }
+    }
+}
+
+impl Writer for MacosTunWriter {
+    type Error = MacosTunError;
+
+    fn write(&self, src: &[u8]) -> Result<(), Self::Error> {
+        // prefix the address family (network byte order), based on the IP version
+        let af = match src.first().map(|v| v >> 4) {
+            Some(6) => libc::AF_INET6,
+            _ => libc::AF_INET,
+        };
+        let header = (af as u32).to_be_bytes();
+        let iov = [
+            libc::iovec {
+                iov_base: header.as_ptr() as *mut libc::c_void,
+                iov_len: header.len(),
+            },
+            libc::iovec {
+                iov_base: src.as_ptr() as *mut libc::c_void,
+                iov_len: src.len(),
+            },
+        ];
+        match unsafe { libc::writev(self.fd, iov.as_ptr(), iov.len() as libc::c_int) } {
+            -1 => Err(MacosTunError::Closed),
+            _ => Ok(()),
+        }
+    }
+}
+
+// Internal function
+//
+// Issue an MTU ioctl (get or set) on the named interface
+fn ioctl_mtu(name: &[u8; libc::IFNAMSIZ], request: libc::c_ulong, mtu: usize) -> Result<usize, ()> {
+    debug_assert_eq!(
+        name[libc::IFNAMSIZ - 1],
+        0,
+        "name buffer not null-terminated"
+    );
+
+    // create socket
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+    if fd < 0 {
+        return Err(());
+    }
+
+    // do ioctl
+    let mut req = IfreqMTU {
+        name: *name,
+        mtu: mtu as libc::c_int,
+        _pad: [0u8; 12],
+    };
+    let err = unsafe { libc::ioctl(fd, request, &mut req as *mut IfreqMTU) };
+
+    // close socket
+    unsafe { libc::close(fd) };
+
+    if err != 0 {
+        Err(())
+    } else {
+        Ok(req.mtu as usize)
+    }
+}
+
+fn get_mtu(name: &[u8; libc::IFNAMSIZ]) -> Result<usize, MacosTunError> {
+    ioctl_mtu(name, SIOCGIFMTU, 0).map_err(|_| MacosTunError::GetMTUIoctlFailed)
+}
+
+fn set_mtu(name: &[u8; libc::IFNAMSIZ], mtu: usize) -> Result<(), MacosTunError> {
+    ioctl_mtu(name, SIOCSIFMTU, mtu)
+        .map(|_| ())
+        .map_err(|_| MacosTunError::SetMTUIoctlFailed)
+}
+
+// Internal function
+//
+// Parse the unit number of the requested device name:
+// "utun" lets the kernel select a free unit, "utunN" requests unit N
+// (sc_unit is the unit number plus one, zero selects any unit).
+fn parse_unit(name: &str) -> Option<u32> {
+    if !name.starts_with("utun") {
+        return None;
+    }
+    let unit = &name["utun".len()..];
+    if unit.is_empty() {
+        return Some(0);
+    }
+    if !unit.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    unit.parse::<u32>().ok()?.checked_add(1)
+}
+
+impl Status for MacosTunStatus {
+    type Error = MacosTunError;
+
+    fn event(&mut self) -> Result<TunEvent, Self::Error> {
+        const HDR_SIZE: usize = mem::size_of::<libc::if_msghdr>();
+
+        let mut buf = [0u8; 1 << 12];
+        log::debug!("routing socket, fetch event (fd = {})", self.fd);
+        loop {
+            // attempt to return a buffered event
+            if let Some(event) = self.events.pop() {
+                return Ok(event);
+            }
+
+            // read messages
+            let size: libc::ssize_t =
+                unsafe { libc::read(self.fd, buf.as_mut_ptr() as _, buf.len()) };
+            if size < 0 {
+                break Err(MacosTunError::RoutingSocketFailure);
+            }
+
+            // cut buffer to size
+            let size: usize = size as usize;
+            let mut remain = &buf[..size];
+            log::debug!("routing socket, received message ({} bytes)", size);
+
+            while remain.len() >= HDR_SIZE {
+                let hdr: libc::if_msghdr =
+                    unsafe { ptr::read_unaligned(remain.as_ptr() as *const libc::if_msghdr) };
+                let len = hdr.ifm_msglen as usize;
+                if len == 0 || len > remain.len() {
+                    break;
+                }
+                remain = &remain[len..];
+
+                // only link updates for this interface
+                if hdr.ifm_type as libc::c_int != libc::RTM_IFINFO || hdr.ifm_index != self.index {
+                    continue;
+                }
+
+                // report a change in state (or MTU)
+                let mtu = if hdr.ifm_flags & libc::IFF_UP != 0 {
+                    Some(get_mtu(&self.name)?)
+                } else {
+                    None
+                };
+                if mtu != self.mtu {
+                    log::trace!("routing socket, link event, mtu = {:?}", mtu);
+                    self.mtu = mtu;
+                    self.events.push(match mtu {
+                        Some(mtu) => TunEvent::Up(mtu),
+                        None => TunEvent::Down,
+                    });
+                }
+            }
+        }
+    }
+}
+
+impl MacosTunStatus {
+    fn new(name: [u8; libc::IFNAMSIZ]) -> Result<MacosTunStatus, MacosTunError> {
+        // create routing socket (link updates for all interfaces)
+        let fd = unsafe { libc::socket(libc::PF_ROUTE, libc::SOCK_RAW, libc::AF_UNSPEC) };
+        if fd < 0 {
+            return Err(MacosTunError::RoutingSocketFailure);
+        }
+
+        let index = unsafe { libc::if_nametoindex(name.as_ptr() as *const libc::c_char) };
+        Ok(MacosTunStatus {
+            events: vec![
+                #[cfg(feature = "start_up")]
+                TunEvent::Up(1500),
+            ],
+            mtu: None,
+            index: index as libc::c_ushort,
+            name,
+            fd,
+        })
+    }
+
+    /// Returns the name of the interface (assigned by the kernel)
+    pub fn name(&self) -> String {
+        let len = self.name.iter().position(|b| *b == 0).unwrap_or(0);
+        String::from_utf8_lossy(&self.name[..len]).into_owned()
+    }
+
+    /// Set the MTU of the interface
+    /// (reported by a subsequent up event)
+    pub fn set_mtu(&self, mtu: usize) -> Result<(), MacosTunError> {
+        set_mtu(&self.name, mtu)
+    }
+
+    /// Returns the current MTU of the interface
+    pub fn get_mtu(&self) -> Result<usize, MacosTunError> {
+        get_mtu(&self.name)
+    }
+
+    /// utun devices are owned by the process which created them,
+    /// hence only clearing the owner is supported
+    pub fn set_owner(&self, uid: Option<u32>, gid: Option<u32>) -> Result<(), MacosTunError> {
+        if uid.is_some() || gid.is_some() {
+            return Err(MacosTunError::SetOwnerUnsupported);
+        }
+        Ok(())
+    }
+}
+
+impl Tun for MacosTun {
+    type Writer = MacosTunWriter;
+    type Reader = MacosTunReader;
+    type Error = MacosTunError;
+}
+
+impl PlatformTun for MacosTun {
+    type Status = MacosTunStatus;
+
+    fn create(name: &str) -> Result<(Vec<Self::Reader>, Self::Writer, Self::Status), Self::Error> {
+        let unit = parse_unit(name).ok_or(MacosTunError::InvalidTunDeviceName)?;
+
+        // open kernel control socket
+        let fd: RawFd = match unsafe {
+            libc::socket(libc::PF_SYSTEM, libc::SOCK_DGRAM, libc::SYSPROTO_CONTROL)
+        } {
+            -1 => return Err(MacosTunError::FailedToOpenControlSocket),
+            fd => fd,
+        };
+
+        // resolve the id of the utun control
+        let mut info: libc::ctl_info = unsafe { mem::zeroed() };
+        for (dst, src) in info.ctl_name.iter_mut().zip(UTUN_CONTROL_NAME.iter()) {
+            *dst = *src as libc::c_char;
+        }
+        if unsafe { libc::ioctl(fd, libc::CTLIOCGINFO, &mut info as *mut libc::ctl_info) } < 0 {
+            unsafe { libc::close(fd) };
+            return Err(MacosTunError::ControlInfoIoctlFailed);
+        }
+
+        // create the utun device by connecting to the control
+        let addr = libc::sockaddr_ctl {
+            sc_len: mem::size_of::<libc::sockaddr_ctl>() as libc::c_uchar,
+            sc_family: libc::AF_SYSTEM as libc::c_uchar,
+            ss_sysaddr: libc::AF_SYS_CONTROL as u16,
+            sc_id: info.ctl_id,
+            sc_unit: unit,
+            sc_reserved: [0; 5],
+        };
+        let res = unsafe {
+            libc::connect(
+                fd,
+                &addr as *const libc::sockaddr_ctl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_ctl>() as libc::socklen_t,
+            )
+        };
+        if res < 0 {
+            unsafe { libc::close(fd) };
+            return Err(MacosTunError::ConnectFailed);
+        }
+
+        // discover the name of the interface (e.g. utun3)
+        let mut ifname = [0u8; libc::IFNAMSIZ];
+        let mut len = (libc::IFNAMSIZ - 1) as libc::socklen_t;
+        let res = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SYSPROTO_CONTROL,
+                libc::UTUN_OPT_IFNAME,
+                ifname.as_mut_ptr() as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if res < 0 {
+            unsafe { libc::close(fd) };
+            return Err(MacosTunError::GetNameFailed);
+        }
+        ifname[libc::IFNAMSIZ - 1] = 0;
+
+        Ok((
+            vec![MacosTunReader { fd }],
+            MacosTunWriter { fd },
+            MacosTunStatus::new(ifname)?,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_unit() {
+        assert_eq!(parse_unit("utun"), Some(0));
+        assert_eq!(parse_unit("utun0"), Some(1));
+        assert_eq!(parse_unit("utun12"), Some(13));
+        assert_eq!(parse_unit("wg0"), None);
+        assert_eq!(parse_unit("utun-1"), None);
+        assert_eq!(parse_unit("utun+1"), None);
+        assert_eq!(parse_unit("utun99999999999"), None);
+    }
+}
@@ -0,0 +1,63 @@
+use super::super::uapi::*;
+
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+
+const SOCK_DIR: &str = "/var/run/wireguard/";
+
+pub struct MacosUAPI {}
+
+impl PlatformUAPI for MacosUAPI {
+    type Error = io::Error;
+    type Bind = UnixListener;
+
+    fn bind(name: &str) -> Result<UnixListener, io::Error> {
+        let socket_path = format!("{}{}.sock", SOCK_DIR, name);
+        let _ = fs::create_dir_all(SOCK_DIR);
+        let _ = fs::set_permissions(SOCK_DIR, fs::Permissions::from_mode(0o755));
+        let _ = fs::remove_file(&socket_path);
+
+        // only the owner (root) may configure the device
+        let umask = unsafe { libc::umask(0o077) };
+        let listener = UnixListener::bind(socket_path);
+        unsafe { libc::umask(umask) };
+        listener
+    }
+
+    fn connect(name: &str) -> Result<UnixStream, io::Error> {
+        UnixStream::connect(format!("{}{}.sock", SOCK_DIR, name))
+    }
+
+    fn list() -> Result<Vec<String>, io::Error> {
+        let entries = match fs::read_dir(SOCK_DIR) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+        let mut names = vec![];
+        for entry in entries {
+            let file_name = entry?.file_name();
+            if let Some(name) = file_name.to_str().and_then(|n| n.strip_suffix(".sock")) {
+                names.push(name.to_owned());
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    fn remove(name: &str) -> Result<(), io::Error> {
+        fs::remove_file(format!("{}{}.sock", SOCK_DIR, name))
+    }
+}
+
+impl BindUAPI for UnixListener {
+    type Stream = UnixStream;
+    type Error = io::Error;
+
+    fn connect(&self) -> Result<UnixStream, io::Error> {
+        let (stream, _) = self.accept()?;
+        Ok(stream)
+    }
+}
@@ -0,0 +1,212 @@
+/* An in-memory TUN device backed by channels
+ *
+ * The device is driven by a MemoryTunHost (the "kernel end" of the device):
+ * packets sent by the host are read by WireGuard and routed to the peers,
+ * while packets written by WireGuard are received by the host.
+ *
+ * This allows embedding WireGuard with a user-space network stack
+ * and exercising the full device in tests (without a TUN device or privileges).
+ */
+
+// used by embedding applications and tests (not by the daemon itself)
+#![allow(dead_code)]
+
+use super::tun::*;
+
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+
+use crossbeam_channel::{bounded, Receiver, RecvTimeoutError, Sender, TrySendError};
+
+// Semantics:
+// Packets buffered in either direction, packets written by WireGuard are dropped when full
+const QUEUE_CAPACITY: usize = 1024;
+
+pub struct MemoryTun {}
+
+pub struct MemoryTunReader {
+    rx: Receiver<Vec<u8>>,
+}
+
+pub struct MemoryTunWriter {
+    tx: Sender<Vec<u8>>,
+}
+
+pub struct MemoryTunStatus {
+    events: Receiver<TunEvent>,
+}
+
+/// The host end of an in-memory TUN device
+pub struct MemoryTunHost {
+    tx: Sender<Vec<u8>>,
+    rx: Receiver<Vec<u8>>,
+    events: Sender<TunEvent>,
+}
+
+#[derive(Debug)]
+pub enum MemoryTunError {
+    QueueFull,
+    Closed,
+}
+
+impl fmt::Display for MemoryTunError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MemoryTunError::QueueFull => write!(f, "The queue of the host is full"),
+            MemoryTunError::Closed => write!(f, "The tunnel has been closed"),
+        }
+    }
+}
+
+impl Error for MemoryTunError {}
+
+impl Reader for MemoryTunReader {
+    type Error = MemoryTunError;
+
+    fn read(&self, buf: &mut [u8], offset: usize) -> Result<usize, Self::Error> {
+        let msg = self.rx.recv().map_err(|_| MemoryTunError::Closed)?;
+
+        // oversized packets are truncated (as by a TUN device)
+        let n = msg.len().min(buf.len() - offset);
+        buf[offset..offset + n].copy_from_slice(&msg[..n]);
+        Ok(n)
+    }
+}
+
+impl Writer for MemoryTunWriter {
+    type Error = MemoryTunError;
+
+    fn write(&self, src: &[u8]) -> Result<(), Self::Error> {
+        match self.tx.try_send(src.to_owned()) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(MemoryTunError::QueueFull),
+            Err(TrySendError::Disconnected(_)) => Err(MemoryTunError::Closed),
+        }
+    }
+}
+
+impl Status for MemoryTunStatus {
+    type Error = MemoryTunError;
+
+    fn event(&mut self) -> Result<TunEvent, Self::Error> {
+        self.events.recv().map_err(|_| MemoryTunError::Closed)
+    }
+}
+
+impl Tun for MemoryTun {
+    type Writer = MemoryTunWriter;
+    type Reader = MemoryTunReader;
+    type Error = MemoryTunError;
+}
+
+impl MemoryTun {
+    /// Create an in-memory TUN device
+    ///
+    /// # Arguments
+    ///
+    /// - `readers`: The number of readers (sharing the packets sent by the host)
+    /// - `mtu`: The MTU reported by the initial up event
+    ///
+    /// # Returns
+    ///
+    /// The readers, writer and status of the device (for WireGuard)
+    /// along with the host end of the device.
+    /// Dropping the host closes the device.
+    pub fn create(
+        readers: usize,
+        mtu: usize,
+    ) -> (
+        Vec<MemoryTunReader>,
+        MemoryTunWriter,
+        MemoryTunStatus,
+        MemoryTunHost,
+    ) {
+        let (host_tx, rx) = bounded(QUEUE_CAPACITY);
+        let (tx, host_rx) = bounded(QUEUE_CAPACITY);
+        let (events_tx, events) = crossbeam_channel::unbounded();
+        let _ = events_tx.send(TunEvent::Up(mtu));
+        (
+            (0..readers.max(1))
+                .map(|_| MemoryTunReader { rx: rx.clone() })
+                .collect(),
+            MemoryTunWriter { tx },
+            MemoryTunStatus { events },
+            MemoryTunHost {
+                tx: host_tx,
+                rx: host_rx,
+                events: events_tx,
+            },
+        )
+    }
+}
+
+impl MemoryTunHost {
+    /// Send a packet to WireGuard (blocks while the queue is full)
+    pub fn send(&self, packet: Vec<u8>) -> Result<(), MemoryTunError> {
+        self.tx.send(packet).map_err(|_| MemoryTunError::Closed)
+    }
+
+    /// Receive a packet written by WireGuard (blocks until a packet is available)
+    pub fn recv(&self) -> Result<Vec<u8>, MemoryTunError> {
+        self.rx.recv().map_err(|_| MemoryTunError::Closed)
+    }
+
+    /// Receive a packet written by WireGuard, waiting at most the given duration
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Option<Vec<u8>>, MemoryTunError> {
+        match self.rx.recv_timeout(timeout) {
+            Ok(packet) => Ok(Some(packet)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(MemoryTunError::Closed),
+        }
+    }
+
+    /// Report a change of the MTU (or bring the device up)
+    pub fn up(&self, mtu: usize) {
+        let _ = self.events.send(TunEvent::Up(mtu));
+    }
+
+    /// Bring the device down
+    pub fn down(&self) {
+        let _ = self.events.send(TunEvent::Down);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_tun() {
+        let (readers, writer, mut status, host) = MemoryTun::create(2, 1420);
+        assert_eq!(readers.len(), 2);
+
+        // initial up event and later changes
+        host.down();
+        match status.event().unwrap() {
+            TunEvent::Up(mtu) => assert_eq!(mtu, 1420),
+            TunEvent::Down => panic!("expected up event"),
+        }
+        match status.event().unwrap() {
+            TunEvent::Up(_) => panic!("expected down event"),
+            TunEvent::Down => (),
+        }
+
+        // host to WireGuard (read after the prefix)
+        host.send(vec![1, 2, 3]).unwrap();
+        let mut buf = [0u8; 8];
+        assert_eq!(readers[1].read(&mut buf, 4).unwrap(), 3);
+        assert_eq!(&buf[4..7], &[1, 2, 3]);
+
+        // WireGuard to host
+        writer.write(&[4, 5]).unwrap();
+        assert_eq!(host.recv().unwrap(), vec![4, 5]);
+        assert_eq!(host.recv_timeout(Duration::from_millis(10)).unwrap(), None);
+
+        // dropping the host closes the device
+        drop(host);
+        assert!(readers[0].read(&mut buf, 0).is_err());
+        assert!(writer.write(&[6]).is_err());
+        assert!(status.event().is_err());
+    }
+}
@@ -1,16 +1,36 @@
 mod endpoint;
 
+pub mod memory;
+pub mod packet_flow;
 pub mod tun;
 pub mod uapi;
 pub mod udp;
 
 pub use endpoint::Endpoint;
 
-#[cfg(target_os = "linux")]
+#[cfg(any(target_os = "linux", target_os = "android"))]
 pub mod linux;
 
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+pub mod macos;
+
+#[cfg(windows)]
+pub mod windows;
+
+#[cfg(any(target_os = "freebsd", target_os = "openbsd"))]
+pub mod bsd;
+
 #[cfg(test)]
 pub mod dummy;
 
-#[cfg(target_os = "linux")]
+#[cfg(any(target_os = "linux", target_os = "android"))]
 pub use linux as plt;
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+pub use macos as plt;
+
+#[cfg(windows)]
+pub use windows as plt;
+
+#[cfg(any(target_os = "freebsd", target_os = "openbsd"))]
+pub use bsd as plt;
@@ -0,0 +1,260 @@
+/* A TUN device exchanging packets through callbacks (without owning a file descriptor),
+ * following the semantics of NEPacketTunnelFlow (the NetworkExtension on iOS / macOS):
+ *
+ * - The packets read from the flow (readPackets) are passed to PacketFlow::send_packets,
+ *   in batches, and read by WireGuard.
+ * - The packets written by WireGuard are delivered to the callback in batches (writePackets),
+ *   a batch consists of every packet written while the previous batch was delivered.
+ * - Sleep / wake of the system is reported as a down / up event of the device.
+ */
+
+// used by embedding applications (not by the daemon itself)
+#![allow(dead_code)]
+
+use super::tun::*;
+
+use std::error::Error;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use crossbeam_channel::{bounded, Receiver, Sender, TrySendError};
+
+// Semantics:
+// Packets buffered in either direction, packets written by WireGuard are dropped when full
+const QUEUE_CAPACITY: usize = 1024;
+
+// Semantics:
+// Maximum number of packets delivered to the callback at once
+const MAX_BATCH: usize = 128;
+
+/// Delivers a batch of packets to the flow (writePackets)
+pub type WritePackets = Box<dyn Fn(&[Vec<u8>]) + Send>;
+
+pub struct PacketFlowTun {}
+
+pub struct PacketFlowReader {
+    rx: Receiver<Vec<u8>>,
+}
+
+pub struct PacketFlowWriter {
+    tx: Sender<Vec<u8>>,
+}
+
+pub struct PacketFlowStatus {
+    events: Receiver<TunEvent>,
+}
+
+/// The application end of the device
+pub struct PacketFlow {
+    tx: Sender<Vec<u8>>,
+    events: Sender<TunEvent>,
+    mtu: AtomicUsize,
+}
+
+#[derive(Debug)]
+pub enum PacketFlowError {
+    QueueFull,
+    Closed,
+}
+
+impl fmt::Display for PacketFlowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PacketFlowError::QueueFull => write!(f, "The queue of the packet flow is full"),
+            PacketFlowError::Closed => write!(f, "The tunnel has been closed"),
+        }
+    }
+}
+
+impl Error for PacketFlowError {}
+
+impl Reader for PacketFlowReader {
+    type Error = PacketFlowError;
+
+    fn read(&self, buf: &mut [u8], offset: usize) -> Result<usize, Self::Error> {
+        let msg = self.rx.recv().map_err(|_| PacketFlowError::Closed)?;
+
+        // oversized packets are truncated (as by a TUN device)
+        let n = msg.len().min(buf.len() - offset);
+        buf[offset..offset + n].copy_from_slice(&msg[..n]);
+        Ok(n)
+    }
+}
+
+impl Writer for PacketFlowWriter {
+    type Error = PacketFlowError;
+
+    fn write(&self, src: &[u8]) -> Result<(), Self::Error> {
+        match self.tx.try_send(src.to_owned()) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(PacketFlowError::QueueFull),
+            Err(TrySendError::Disconnected(_)) => Err(PacketFlowError::Closed),
+        }
+    }
+}
+
+impl Status for PacketFlowStatus {
+    type Error = PacketFlowError;
+
+    fn event(&mut self) -> Result<TunEvent, Self::Error> {
+        self.events.recv().map_err(|_| PacketFlowError::Closed)
+    }
+}
+
+impl Tun for PacketFlowTun {
+    type Writer = PacketFlowWriter;
+    type Reader = PacketFlowReader;
+    type Error = PacketFlowError;
+}
+
+impl PacketFlowTun {
+    /// Create a device exchanging packets with a packet flow
+    ///
+    /// # Arguments
+    ///
+    /// - `mtu`: The MTU of the tunnel (reported by the initial up event)
+    /// - `write_packets`: Callback delivering the packets written by WireGuard to the flow
+    ///
+    /// # Returns
+    ///
+    /// The reader, writer and status of the device (for WireGuard)
+    /// along with the application end of the device.
+    /// Dropping the application end closes the device,
+    /// the callback is released once the writer is dropped.
+    pub fn create(
+        mtu: usize,
+        write_packets: WritePackets,
+    ) -> (
+        Vec<PacketFlowReader>,
+        PacketFlowWriter,
+        PacketFlowStatus,
+        PacketFlow,
+    ) {
+        let (flow_tx, rx) = bounded(QUEUE_CAPACITY);
+        let (tx, flow_rx) = bounded(QUEUE_CAPACITY);
+        let (events_tx, events) = crossbeam_channel::unbounded();
+        let _ = events_tx.send(TunEvent::Up(mtu));
+
+        // deliver written packets in batches
+        thread::spawn(move || {
+            let flow_rx: Receiver<Vec<u8>> = flow_rx;
+            let mut batch = Vec::with_capacity(MAX_BATCH);
+            while let Ok(packet) = flow_rx.recv() {
+                batch.push(packet);
+                while batch.len() < MAX_BATCH {
+                    match flow_rx.try_recv() {
+                        Ok(packet) => batch.push(packet),
+                        Err(_) => break,
+                    }
+                }
+                write_packets(&batch[..]);
+                batch.clear();
+            }
+            log::debug!("packet flow, writer closed");
+        });
+
+        (
+            vec![PacketFlowReader { rx }],
+            PacketFlowWriter { tx },
+            PacketFlowStatus { events },
+            PacketFlow {
+                tx: flow_tx,
+                events: events_tx,
+                mtu: AtomicUsize::new(mtu),
+            },
+        )
+    }
+}
+
+impl PacketFlow {
+    /// Pass a batch of packets read from the flow (readPackets) to WireGuard
+    ///
+    /// Blocks while the queue is full.
+    pub fn send_packets<I: IntoIterator<Item = Vec<u8>>>(
+        &self,
+        packets: I,
+    ) -> Result<(), PacketFlowError> {
+        for packet in packets {
+            self.tx.send(packet).map_err(|_| PacketFlowError::Closed)?;
+        }
+        Ok(())
+    }
+
+    /// Report a change of the MTU of the tunnel
+    pub fn set_mtu(&self, mtu: usize) {
+        self.mtu.store(mtu, Ordering::Relaxed);
+        let _ = self.events.send(TunEvent::Up(mtu));
+    }
+
+    /// The system is going to sleep: the device is brought down (closing the sockets)
+    pub fn sleep(&self) {
+        let _ = self.events.send(TunEvent::Down);
+    }
+
+    /// The system has woken: the device is brought up again (on new sockets)
+    pub fn wake(&self) {
+        let _ = self
+            .events
+            .send(TunEvent::Up(self.mtu.load(Ordering::Relaxed)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+
+    #[test]
+    fn test_packet_flow() {
+        let (batches_tx, batches) = channel();
+        let (readers, writer, mut status, flow) = PacketFlowTun::create(
+            1280,
+            Box::new(move |batch: &[Vec<u8>]| {
+                let _ = batches_tx.send(batch.to_owned());
+            }),
+        );
+
+        // sleep and wake
+        let mut expect = |mtu: Option<usize>| match (status.event().unwrap(), mtu) {
+            (TunEvent::Up(mtu), Some(expected)) => assert_eq!(mtu, expected),
+            (TunEvent::Down, None) => (),
+            _ => panic!("unexpected event"),
+        };
+        flow.sleep();
+        flow.set_mtu(1420);
+        flow.sleep();
+        flow.wake();
+        expect(Some(1280));
+        expect(None);
+        expect(Some(1420));
+        expect(None);
+        expect(Some(1420));
+
+        // packets read from the flow
+        flow.send_packets(vec![vec![1], vec![2, 3]]).unwrap();
+        let mut buf = [0u8; 8];
+        assert_eq!(readers[0].read(&mut buf, 4).unwrap(), 1);
+        assert_eq!(readers[0].read(&mut buf, 4).unwrap(), 2);
+        assert_eq!(&buf[4..6], &[2, 3]);
+
+        // packets written to the flow are delivered in order (in batches)
+        for i in 0..200u8 {
+            writer.write(&[i]).unwrap();
+        }
+        let mut written = vec![];
+        while written.len() < 200 {
+            let batch = batches.recv_timeout(Duration::from_secs(10)).unwrap();
+            assert!(!batch.is_empty() && batch.len() <= MAX_BATCH);
+            written.extend(batch);
+        }
+        assert_eq!(written, (0..200u8).map(|i| vec![i]).collect::<Vec<_>>());
+
+        // dropping the application end closes the device
+        drop(flow);
+        assert!(readers[0].read(&mut buf, 0).is_err());
+        assert!(status.event().is_err());
+    }
+}
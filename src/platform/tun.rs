@@ -5,6 +5,29 @@ pub enum TunEvent {
     Down,      // interface is down
 }
 
+/// Segmentation offload of a TCP super-packet
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Gso {
+    #[default]
+    None,
+    TcpV4,
+    TcpV6,
+}
+
+/// Offload metadata accompanying a packet (mirrors the virtio-net header)
+///
+/// A super-packet consists of the IP and TCP headers followed by the payload of several segments,
+/// each (except the last) carrying gso_size bytes of payload.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Offload {
+    pub gso: Gso,
+    pub gso_size: u16,    // payload of each segment
+    pub hdr_len: u16,     // length of the IP and TCP headers
+    pub csum_start: u16,  // start of the checksummed region (the transport header)
+    pub csum_offset: u16, // offset of the checksum field from csum_start
+    pub needs_csum: bool, // the checksum field holds only the pseudo-header sum
+}
+
 pub trait Status: Send + 'static {
     type Error: Error;
 
@@ -26,6 +49,18 @@ pub trait Writer: Send + Sync + 'static {
     ///
     /// Unit type or an error
     fn write(&self, src: &[u8]) -> Result<(), Self::Error>;
+
+    /// Returns true if the writer accepts super-packets (see write_offload)
+    fn offload(&self) -> bool {
+        false
+    }
+
+    /// Write a packet accompanied by offload metadata (e.g. a TCP super-packet),
+    /// only called when the writer supports offloading.
+    fn write_offload(&self, src: &[u8], offload: &Offload) -> Result<(), Self::Error> {
+        debug_assert_eq!(offload.gso, Gso::None, "writer does not support offloading");
+        self.write(src)
+    }
 }
 
 pub trait Reader: Send + 'static {
@@ -46,6 +81,19 @@ pub trait Reader: Send + 'static {
     ///
     /// The size of the IP packet (ignoring the header) or an std::error::Error instance:
     fn read(&self, buf: &mut [u8], offset: usize) -> Result<usize, Self::Error>;
+
+    /// Returns true if the reader may return super-packets (see read_offload)
+    fn offload(&self) -> bool {
+        false
+    }
+
+    /// Reads a packet and the accompanying offload metadata into dst[offset:]
+    ///
+    /// When offloading is enabled the packet may be a TCP super-packet (up to 64 KiB),
+    /// which must be split into segments, or lack a complete transport checksum.
+    fn read_offload(&self, buf: &mut [u8], offset: usize) -> Result<(usize, Offload), Self::Error> {
+        self.read(buf, offset).map(|len| (len, Offload::default()))
+    }
 }
 
 pub trait Tun: Send + Sync + 'static {
@@ -54,9 +102,30 @@ pub trait Tun: Send + Sync + 'static {
     type Error: Error;
 }
 
+/// The readers, writer and status returned when creating the device of a `PlatformTun`.
+pub type Created<T> = (
+    Vec<<T as Tun>::Reader>,
+    <T as Tun>::Writer,
+    <T as PlatformTun>::Status,
+);
+
 /// On some platforms the application can create the TUN device itself.
 pub trait PlatformTun: Tun {
     type Status: Status;
 
     fn create(name: &str) -> Result<(Vec<Self::Reader>, Self::Writer, Self::Status), Self::Error>;
+
+    /// Create the TUN device with a reader for each of the requested queues
+    ///
+    /// Platforms without multi-queue support return the readers of create.
+    fn create_multiqueue(name: &str, _queues: usize) -> Result<Created<Self>, Self::Error> {
+        Self::create(name)
+    }
+
+    /// Create the TUN device with segmentation and checksum offloading enabled
+    ///
+    /// Platforms without offload support return the readers and writer of create_multiqueue.
+    fn create_offload(name: &str, queues: usize) -> Result<Created<Self>, Self::Error> {
+        Self::create_multiqueue(name, queues)
+    }
 }
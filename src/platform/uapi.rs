@@ -13,4 +13,13 @@ pub trait PlatformUAPI {
     type Bind: BindUAPI;
 
     fn bind(name: &str) -> Result<Self::Bind, Self::Error>;
+
+    /// Connect to the control socket of a running device (as a client)
+    fn connect(name: &str) -> Result<<Self::Bind as BindUAPI>::Stream, Self::Error>;
+
+    /// Names of the running devices (with a control socket)
+    fn list() -> Result<Vec<String>, Self::Error>;
+
+    /// Remove the control socket of a running device, which then shuts down
+    fn remove(name: &str) -> Result<(), Self::Error>;
 }
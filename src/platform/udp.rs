@@ -1,16 +1,72 @@
+/* The UDP bind abstraction, decoupling the handshake and router layers from raw sockets:
+ *
+ * - Reader::read   receives a datagram with its source endpoint (recv_from)
+ * - Writer::write  sends a datagram to an endpoint (send_to)
+ * - Reader::read_batch / Writer::write_batch move several datagrams per call (recvmmsg/sendmmsg)
+ * - Reader::path_mtu_updates reports the path MTU from ICMP errors (error queue)
+ * - PlatformUDP::bind opens the sockets on a port, rebinding is done by dropping the Owner
+ *   and binding again (see the UAPI configuration)
+ * - PlatformUDP::bind_reuseport opens a group of sockets on the same port (one per worker)
+ * - Owner::set_fwmark sets the mark of the sockets (set_mark)
+ *
+ * Implementations: platform::linux, platform::macos, platform::windows and platform::bsd (sockets)
+ * and platform::dummy (mock binds for tests).
+ */
+
 use super::Endpoint;
 use std::error::Error;
+use std::net::SocketAddr;
 
 pub trait Reader<E: Endpoint>: Send + Sync {
     type Error: Error;
 
     fn read(&self, buf: &mut [u8]) -> Result<(usize, E), Self::Error>;
+
+    /// Receive multiple datagrams (recvmmsg on Linux),
+    /// blocking until at least one datagram is available.
+    ///
+    /// # Arguments
+    ///
+    /// - `bufs`: Non-empty list of buffers, the i'th datagram is written to the i'th buffer
+    ///
+    /// # Returns
+    ///
+    /// The length and source of every datagram received (at most bufs.len())
+    fn read_batch(&self, bufs: &mut [&mut [u8]]) -> Result<Vec<(usize, E)>, Self::Error> {
+        let (len, src) = self.read(bufs[0])?;
+        Ok(vec![(len, src)])
+    }
+
+    /// Path MTU updates learned since the last call, from ICMP errors received on the socket
+    /// ("fragmentation needed" / "packet too big")
+    ///
+    /// # Returns
+    ///
+    /// A list of (destination, path MTU) pairs
+    fn path_mtu_updates(&self) -> Vec<(SocketAddr, usize)> {
+        vec![]
+    }
 }
 
 pub trait Writer<E: Endpoint>: Send + Sync + 'static {
     type Error: Error;
 
     fn write(&self, buf: &[u8], dst: &mut E) -> Result<(), Self::Error>;
+
+    /// Send multiple datagrams to the same endpoint (sendmmsg on Linux)
+    ///
+    /// # Arguments
+    ///
+    /// - `bufs`: The datagrams, sent in order
+    /// - `dst`: The destination endpoint
+    /// - `tos`: The traffic class (DSCP and ECN bits) of the packets
+    ///   (may be ignored by the implementation)
+    fn write_batch(&self, bufs: &[&[u8]], dst: &mut E, _tos: u8) -> Result<(), Self::Error> {
+        for buf in bufs {
+            self.write(buf, dst)?;
+        }
+        Ok(())
+    }
 }
 
 pub trait UDP: Send + Sync + 'static {
@@ -31,8 +87,30 @@ pub trait Owner: Send {
     fn get_port(&self) -> u16;
 
     fn set_fwmark(&mut self, value: Option<u32>) -> Result<(), Self::Error>;
+
+    /// Restrict the sockets to a single network interface (SO_BINDTODEVICE on Linux),
+    /// such that the outer (encrypted) traffic only traverses the chosen uplink.
+    ///
+    /// # Arguments
+    ///
+    /// - `name`: The name of the interface, or None to remove the restriction
+    fn set_interface(&mut self, name: Option<&str>) -> Result<(), Self::Error>;
+
+    /// Set the TTL (IPv4) and hop limit (IPv6) of all packets sent from the sockets
+    ///
+    /// # Arguments
+    ///
+    /// - `ttl`: The TTL / hop limit, or None to use the system default
+    fn set_ttl(&mut self, ttl: Option<u8>) -> Result<(), Self::Error>;
 }
 
+/// The readers, writer and owner returned when binding the sockets of a `PlatformUDP`.
+pub type Bound<B> = (
+    Vec<<B as UDP>::Reader>,
+    <B as UDP>::Writer,
+    <B as PlatformUDP>::Owner,
+);
+
 /// On some platforms the application can itself bind to a socket.
 /// This enables configuration using the UAPI interface.
 pub trait PlatformUDP: UDP {
@@ -42,4 +120,20 @@ pub trait PlatformUDP: UDP {
     /// an associated instance of the owner type, which closes the UDP socket upon "drop"
     /// and enables configuration of the fwmark value.
     fn bind(port: u16) -> Result<(Vec<Self::Reader>, Self::Writer, Self::Owner), Self::Error>;
+
+    /// Bind multiple sockets for each IP version to the same port (SO_REUSEPORT on Linux),
+    /// returning a reader for every socket, such that each reader can be served by its own worker.
+    ///
+    /// # Arguments
+    ///
+    /// - `port`: The port to bind to (0 = any)
+    /// - `workers`: The number of sockets for each IP version
+    /// - `steer`: Steer transport messages to a socket by their receiver index,
+    ///   such that every key-pair is consistently handled by the same worker.
+    ///
+    /// Platforms without support for multiple sockets bind a single socket.
+    fn bind_reuseport(port: u16, workers: usize, steer: bool) -> Result<Bound<Self>, Self::Error> {
+        let _ = (workers, steer);
+        Self::bind(port)
+    }
 }
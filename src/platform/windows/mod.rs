@@ -0,0 +1,7 @@
+mod tun;
+mod uapi;
+mod udp;
+
+pub use tun::WindowsTun as Tun;
+pub use uapi::WindowsUAPI as UAPI;
+pub use udp::WindowsUDP as UDP;
@@ -0,0 +1,423 @@
+/* TUN device on Windows using the Wintun driver (https://www.wintun.net):
+ *
+ * wintun.dll is loaded at runtime (from the application directory or System32),
+ * packets are exchanged through the ring buffers of a session,
+ * without any address family prefix.
+ *
+ * The adapter has no notion of link state, hence the status reports the interface
+ * as up as soon as the session has started and reports every subsequent change of the MTU.
+ */
+
+use super::super::tun::*;
+
+use std::error::Error;
+use std::ffi::OsStr;
+use std::fmt;
+use std::mem;
+use std::os::windows::ffi::OsStrExt;
+use std::ptr;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use winapi::shared::guiddef::GUID;
+use winapi::shared::ifdef::NET_LUID;
+use winapi::shared::minwindef::{BYTE, DWORD, HMODULE};
+use winapi::shared::netioapi::{
+    GetIpInterfaceEntry, InitializeIpInterfaceEntry, SetIpInterfaceEntry, MIB_IPINTERFACE_ROW,
+};
+use winapi::shared::winerror::{ERROR_BUFFER_OVERFLOW, ERROR_NO_MORE_ITEMS, NO_ERROR};
+use winapi::shared::ws2def::{AF_INET, AF_INET6};
+use winapi::um::errhandlingapi::GetLastError;
+use winapi::um::libloaderapi::{
+    GetProcAddress, LoadLibraryExW, LOAD_LIBRARY_SEARCH_APPLICATION_DIR,
+    LOAD_LIBRARY_SEARCH_SYSTEM32,
+};
+use winapi::um::synchapi::WaitForSingleObject;
+use winapi::um::winbase::{INFINITE, WAIT_OBJECT_0};
+use winapi::um::winnt::{HANDLE, LPCWSTR};
+
+// Semantics:
+// Size of the send and receive rings of a session (power of two, 128 KiB to 64 MiB)
+const RING_CAPACITY: DWORD = 0x80_0000;
+
+// Semantics:
+// Interval at which the status polls the MTU of the interface
+const MTU_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// maximum length of an adapter name (including the terminating null)
+const MAX_ADAPTER_NAME: usize = 128;
+
+// tunnel type shown for the adapter
+const TUNNEL_TYPE: &str = "WireGuard";
+
+type CreateAdapterFn = unsafe extern "system" fn(LPCWSTR, LPCWSTR, *const GUID) -> HANDLE;
+type CloseAdapterFn = unsafe extern "system" fn(HANDLE);
+type GetAdapterLuidFn = unsafe extern "system" fn(HANDLE, *mut NET_LUID);
+type StartSessionFn = unsafe extern "system" fn(HANDLE, DWORD) -> HANDLE;
+type EndSessionFn = unsafe extern "system" fn(HANDLE);
+type GetReadWaitEventFn = unsafe extern "system" fn(HANDLE) -> HANDLE;
+type ReceivePacketFn = unsafe extern "system" fn(HANDLE, *mut DWORD) -> *mut BYTE;
+type ReleaseReceivePacketFn = unsafe extern "system" fn(HANDLE, *const BYTE);
+type AllocateSendPacketFn = unsafe extern "system" fn(HANDLE, DWORD) -> *mut BYTE;
+type SendPacketFn = unsafe extern "system" fn(HANDLE, *const BYTE);
+
+// functions of wintun.dll
+struct Api {
+    create_adapter: CreateAdapterFn,
+    close_adapter: CloseAdapterFn,
+    get_adapter_luid: GetAdapterLuidFn,
+    start_session: StartSessionFn,
+    end_session: EndSessionFn,
+    get_read_wait_event: GetReadWaitEventFn,
+    receive_packet: ReceivePacketFn,
+    release_receive_packet: ReleaseReceivePacketFn,
+    allocate_send_packet: AllocateSendPacketFn,
+    send_packet: SendPacketFn,
+}
+
+// an adapter with a running session, shared by the reader, writer and status
+struct Session {
+    api: Api,
+    adapter: HANDLE,
+    session: HANDLE,
+    read_event: HANDLE, // owned by the session
+    luid: NET_LUID,
+}
+
+// the handles may be used concurrently (the rings are thread-safe)
+unsafe impl Send for Session {}
+unsafe impl Sync for Session {}
+
+pub struct WindowsTun {}
+
+pub struct WindowsTunReader {
+    session: Arc<Session>,
+}
+
+pub struct WindowsTunWriter {
+    session: Arc<Session>,
+}
+
+pub struct WindowsTunStatus {
+    events: Vec<TunEvent>,
+    mtu: Option<usize>, // MTU of the last reported up event
+    name: String,
+    session: Arc<Session>,
+}
+
+#[derive(Debug)]
+pub enum WindowsTunError {
+    InvalidTunDeviceName,
+    LibraryNotFound,
+    MissingSymbol(&'static str),
+    CreateAdapterFailed(DWORD),
+    StartSessionFailed(DWORD),
+    GetMTUFailed(DWORD),
+    SetMTUFailed(DWORD),
+    SetOwnerUnsupported,
+    RingFull,
+    Closed,
+}
+
+impl fmt::Display for WindowsTunError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WindowsTunError::InvalidTunDeviceName => write!(f, "Invalid name (empty or too long)"),
+            WindowsTunError::LibraryNotFound => {
+                write!(f, "Failed to load wintun.dll (not installed?)")
+            }
+            WindowsTunError::MissingSymbol(name) => write!(f, "wintun.dll lacks {}", name),
+            WindowsTunError::CreateAdapterFailed(err) => write!(
+                f,
+                "Failed to create adapter (error = {}, insufficient permissions?)",
+                err
+            ),
+            WindowsTunError::StartSessionFailed(err) => {
+                write!(f, "Failed to start session (error = {})", err)
+            }
+            WindowsTunError::GetMTUFailed(err) => {
+                write!(f, "Failed to get interface MTU (error = {})", err)
+            }
+            WindowsTunError::SetMTUFailed(err) => {
+                write!(f, "Failed to set interface MTU (error = {})", err)
+            }
+            WindowsTunError::SetOwnerUnsupported => {
+                write!(f, "Wintun adapters can not be assigned an owner")
+            }
+            WindowsTunError::RingFull => write!(f, "The send ring is full"),
+            WindowsTunError::Closed => write!(f, "The tunnel has been closed"),
+        }
+    }
+}
+
+impl Error for WindowsTunError {}
+
+// Internal function
+//
+// Encode a string as a null-terminated wide string
+fn wide(s: &str) -> Vec<u16> {
+    OsStr::new(s).encode_wide().chain(Some(0)).collect()
+}
+
+// Internal function
+//
+// Look up a function in the loaded library
+fn symbol(lib: HMODULE, name: &'static str) -> Result<usize, WindowsTunError> {
+    let cname: Vec<u8> = name.bytes().chain(Some(0)).collect();
+    let addr = unsafe { GetProcAddress(lib, cname.as_ptr() as *const i8) };
+    if addr.is_null() {
+        return Err(WindowsTunError::MissingSymbol(name));
+    }
+    Ok(addr as usize)
+}
+
+impl Api {
+    fn load() -> Result<Api, WindowsTunError> {
+        let path = wide("wintun.dll");
+        let lib = unsafe {
+            LoadLibraryExW(
+                path.as_ptr(),
+                ptr::null_mut(),
+                LOAD_LIBRARY_SEARCH_APPLICATION_DIR | LOAD_LIBRARY_SEARCH_SYSTEM32,
+            )
+        };
+        if lib.is_null() {
+            return Err(WindowsTunError::LibraryNotFound);
+        }
+
+        // the library remains loaded for the lifetime of the process
+        unsafe {
+            Ok(Api {
+                create_adapter: mem::transmute(symbol(lib, "WintunCreateAdapter")?),
+                close_adapter: mem::transmute(symbol(lib, "WintunCloseAdapter")?),
+                get_adapter_luid: mem::transmute(symbol(lib, "WintunGetAdapterLUID")?),
+                start_session: mem::transmute(symbol(lib, "WintunStartSession")?),
+                end_session: mem::transmute(symbol(lib, "WintunEndSession")?),
+                get_read_wait_event: mem::transmute(symbol(lib, "WintunGetReadWaitEvent")?),
+                receive_packet: mem::transmute(symbol(lib, "WintunReceivePacket")?),
+                release_receive_packet: mem::transmute(symbol(lib, "WintunReleaseReceivePacket")?),
+                allocate_send_packet: mem::transmute(symbol(lib, "WintunAllocateSendPacket")?),
+                send_packet: mem::transmute(symbol(lib, "WintunSendPacket")?),
+            })
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        unsafe {
+            (self.api.end_session)(self.session);
+            (self.api.close_adapter)(self.adapter);
+        }
+    }
+}
+
+// Internal function
+//
+// Fetch the IP interface of the adapter for an address family
+fn ip_interface(luid: &NET_LUID, family: i32) -> Result<MIB_IPINTERFACE_ROW, DWORD> {
+    let mut row: MIB_IPINTERFACE_ROW = unsafe { mem::zeroed() };
+    unsafe { InitializeIpInterfaceEntry(&mut row) };
+    row.Family = family as u16;
+    row.InterfaceLuid = *luid;
+    match unsafe { GetIpInterfaceEntry(&mut row) } {
+        NO_ERROR => Ok(row),
+        err => Err(err),
+    }
+}
+
+fn get_mtu(luid: &NET_LUID) -> Result<usize, WindowsTunError> {
+    ip_interface(luid, AF_INET)
+        .map(|row| row.NlMtu as usize)
+        .map_err(WindowsTunError::GetMTUFailed)
+}
+
+fn set_mtu(luid: &NET_LUID, mtu: usize) -> Result<(), WindowsTunError> {
+    for &family in &[AF_INET, AF_INET6] {
+        let mut row = match ip_interface(luid, family) {
+            Ok(row) => row,
+            Err(_) if family == AF_INET6 => continue, // IPv6 disabled on the adapter
+            Err(err) => return Err(WindowsTunError::SetMTUFailed(err)),
+        };
+        row.NlMtu = mtu as u32;
+        if family == AF_INET {
+            // must be zero when updating an IPv4 interface
+            row.SitePrefixLength = 0;
+        }
+        match unsafe { SetIpInterfaceEntry(&mut row) } {
+            NO_ERROR => (),
+            err => return Err(WindowsTunError::SetMTUFailed(err)),
+        }
+    }
+    Ok(())
+}
+
+impl Reader for WindowsTunReader {
+    type Error = WindowsTunError;
+
+    fn read(&self, buf: &mut [u8], offset: usize) -> Result<usize, Self::Error> {
+        let s = &*self.session;
+        loop {
+            let mut size: DWORD = 0;
+            let packet = unsafe { (s.api.receive_packet)(s.session, &mut size) };
+            if !packet.is_null() {
+                let size = size as usize;
+                let fits = size <= buf.len() - offset;
+                if fits {
+                    let src = unsafe { std::slice::from_raw_parts(packet, size) };
+                    buf[offset..offset + size].copy_from_slice(src);
+                }
+                unsafe { (s.api.release_receive_packet)(s.session, packet) };
+                if fits {
+                    return Ok(size);
+                }
+                log::debug!("wintun, dropped oversized packet ({} bytes)", size);
+                continue;
+            }
+
+            // wait for packets (ERROR_HANDLE_EOF once the session has ended)
+            match unsafe { GetLastError() } {
+                ERROR_NO_MORE_ITEMS => {
+                    if unsafe { WaitForSingleObject(s.read_event, INFINITE) } != WAIT_OBJECT_0 {
+                        return Err(WindowsTunError::Closed);
+                    }
+                }
+                _ => return Err(WindowsTunError::Closed),
+            }
+        }
+    }
+}
+
+impl Writer for WindowsTunWriter {
+    type Error = WindowsTunError;
+
+    fn write(&self, src: &[u8]) -> Result<(), Self::Error> {
+        let s = &*self.session;
+        let packet = unsafe { (s.api.allocate_send_packet)(s.session, src.len() as DWORD) };
+        if packet.is_null() {
+            return match unsafe { GetLastError() } {
+                ERROR_BUFFER_OVERFLOW => Err(WindowsTunError::RingFull),
+                _ => Err(WindowsTunError::Closed),
+            };
+        }
+        unsafe {
+            ptr::copy_nonoverlapping(src.as_ptr(), packet, src.len());
+            (s.api.send_packet)(s.session, packet);
+        }
+        Ok(())
+    }
+}
+
+impl Status for WindowsTunStatus {
+    type Error = WindowsTunError;
+
+    fn event(&mut self) -> Result<TunEvent, Self::Error> {
+        loop {
+            // attempt to return a buffered event
+            if let Some(event) = self.events.pop() {
+                return Ok(event);
+            }
+
+            // report the MTU when changed (fails once the adapter is removed)
+            let mtu = get_mtu(&self.session.luid)?;
+            if self.mtu != Some(mtu) {
+                log::trace!("wintun, up event, mtu = {}", mtu);
+                self.mtu = Some(mtu);
+                return Ok(TunEvent::Up(mtu));
+            }
+            thread::sleep(MTU_POLL_INTERVAL);
+        }
+    }
+}
+
+impl WindowsTunStatus {
+    /// Returns the name of the adapter
+    pub fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    /// Set the MTU of the interface (IPv4 and IPv6),
+    /// reported by a subsequent up event
+    pub fn set_mtu(&self, mtu: usize) -> Result<(), WindowsTunError> {
+        set_mtu(&self.session.luid, mtu)
+    }
+
+    /// Returns the current MTU of the interface
+    pub fn get_mtu(&self) -> Result<usize, WindowsTunError> {
+        get_mtu(&self.session.luid)
+    }
+
+    /// Wintun adapters are only accessible to the process which created them,
+    /// hence only clearing the owner is supported
+    pub fn set_owner(&self, uid: Option<u32>, gid: Option<u32>) -> Result<(), WindowsTunError> {
+        if uid.is_some() || gid.is_some() {
+            return Err(WindowsTunError::SetOwnerUnsupported);
+        }
+        Ok(())
+    }
+}
+
+impl Tun for WindowsTun {
+    type Writer = WindowsTunWriter;
+    type Reader = WindowsTunReader;
+    type Error = WindowsTunError;
+}
+
+impl PlatformTun for WindowsTun {
+    type Status = WindowsTunStatus;
+
+    fn create(name: &str) -> Result<(Vec<Self::Reader>, Self::Writer, Self::Status), Self::Error> {
+        // sanity check the adapter name
+        if name.is_empty() || name.encode_utf16().count() >= MAX_ADAPTER_NAME {
+            return Err(WindowsTunError::InvalidTunDeviceName);
+        }
+
+        // create adapter
+        let api = Api::load()?;
+        let adapter = unsafe {
+            (api.create_adapter)(wide(name).as_ptr(), wide(TUNNEL_TYPE).as_ptr(), ptr::null())
+        };
+        if adapter.is_null() {
+            return Err(WindowsTunError::CreateAdapterFailed(unsafe {
+                GetLastError()
+            }));
+        }
+        let mut luid: NET_LUID = unsafe { mem::zeroed() };
+        unsafe { (api.get_adapter_luid)(adapter, &mut luid) };
+
+        // start session
+        let session = unsafe { (api.start_session)(adapter, RING_CAPACITY) };
+        if session.is_null() {
+            let err = unsafe { GetLastError() };
+            unsafe { (api.close_adapter)(adapter) };
+            return Err(WindowsTunError::StartSessionFailed(err));
+        }
+        let read_event = unsafe { (api.get_read_wait_event)(session) };
+
+        let session = Arc::new(Session {
+            api,
+            adapter,
+            session,
+            read_event,
+            luid,
+        });
+        Ok((
+            vec![WindowsTunReader {
+                session: session.clone(),
+            }],
+            WindowsTunWriter {
+                session: session.clone(),
+            },
+            WindowsTunStatus {
+                events: vec![
+                    #[cfg(feature = "start_up")]
+                    TunEvent::Up(1500),
+                ],
+                mtu: None,
+                name: name.to_owned(),
+                session,
+            },
+        ))
+    }
+}
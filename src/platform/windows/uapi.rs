@@ -0,0 +1,151 @@
+use super::super::uapi::*;
+
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::windows::ffi::OsStrExt;
+use std::os::windows::io::{FromRawHandle, RawHandle};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use winapi::shared::minwindef::{DWORD, FALSE};
+use winapi::shared::sddl::{ConvertStringSecurityDescriptorToSecurityDescriptorW, SDDL_REVISION_1};
+use winapi::shared::winerror::ERROR_PIPE_CONNECTED;
+use winapi::um::handleapi::{CloseHandle, INVALID_HANDLE_VALUE};
+use winapi::um::minwinbase::SECURITY_ATTRIBUTES;
+use winapi::um::namedpipeapi::{ConnectNamedPipe, CreateNamedPipeW};
+use winapi::um::winbase::{
+    LocalFree, FILE_FLAG_FIRST_PIPE_INSTANCE, PIPE_ACCESS_DUPLEX, PIPE_READMODE_BYTE,
+    PIPE_TYPE_BYTE, PIPE_UNLIMITED_INSTANCES, PIPE_WAIT,
+};
+use winapi::um::winnt::PSECURITY_DESCRIPTOR;
+
+const PIPE_ROOT: &str = r"\\.\pipe\";
+const PIPE_PREFIX: &str = r"\\.\pipe\ProtectedPrefix\Administrators\WireGuard\";
+
+// only the local system and administrators may connect (the UAPI exposes the private key)
+const PIPE_SECURITY: &str = "O:SYD:P(A;;GA;;;SY)(A;;GA;;;BA)";
+
+const PIPE_BUFFER_SIZE: DWORD = 4096;
+
+pub struct WindowsUAPI {}
+
+/// Named pipe on which UAPI connections are accepted
+pub struct NamedPipe {
+    path: Vec<u16>,
+    security: PSECURITY_DESCRIPTOR,
+    first: AtomicBool,
+}
+
+// the security descriptor is immutable once created
+unsafe impl Send for NamedPipe {}
+unsafe impl Sync for NamedPipe {}
+
+fn wide(s: &str) -> Vec<u16> {
+    OsStr::new(s).encode_wide().chain(Some(0)).collect()
+}
+
+impl PlatformUAPI for WindowsUAPI {
+    type Error = io::Error;
+    type Bind = NamedPipe;
+
+    fn bind(name: &str) -> Result<NamedPipe, io::Error> {
+        let mut security: PSECURITY_DESCRIPTOR = ptr::null_mut();
+        let ok = unsafe {
+            ConvertStringSecurityDescriptorToSecurityDescriptorW(
+                wide(PIPE_SECURITY).as_ptr(),
+                SDDL_REVISION_1 as DWORD,
+                &mut security,
+                ptr::null_mut(),
+            )
+        };
+        if ok == FALSE {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(NamedPipe {
+            path: wide(&format!("{}{}", PIPE_PREFIX, name)),
+            security,
+            first: AtomicBool::new(true),
+        })
+    }
+
+    fn connect(name: &str) -> Result<File, io::Error> {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(format!("{}{}", PIPE_PREFIX, name))
+    }
+
+    fn list() -> Result<Vec<String>, io::Error> {
+        // the pipe file system lists every pipe by its path relative to \\.\pipe\
+        let prefix = &PIPE_PREFIX[PIPE_ROOT.len()..];
+        let mut names = vec![];
+        for entry in fs::read_dir(PIPE_ROOT)? {
+            let file_name = entry?.file_name();
+            if let Some(name) = file_name.to_str().and_then(|n| n.strip_prefix(prefix)) {
+                names.push(name.to_owned());
+            }
+        }
+        names.sort();
+        names.dedup(); // every instance of a pipe is listed
+        Ok(names)
+    }
+
+    fn remove(_name: &str) -> Result<(), io::Error> {
+        // named pipes disappear with the process owning them
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "removing the named pipe is not supported (stop the service instead)",
+        ))
+    }
+}
+
+impl BindUAPI for NamedPipe {
+    type Stream = File;
+    type Error = io::Error;
+
+    fn connect(&self) -> Result<File, io::Error> {
+        let mut attributes = SECURITY_ATTRIBUTES {
+            nLength: std::mem::size_of::<SECURITY_ATTRIBUTES>() as DWORD,
+            lpSecurityDescriptor: self.security,
+            bInheritHandle: FALSE,
+        };
+
+        // the first instance fails if another process already owns the pipe
+        let mut mode = PIPE_ACCESS_DUPLEX;
+        if self.first.swap(false, Ordering::SeqCst) {
+            mode |= FILE_FLAG_FIRST_PIPE_INSTANCE;
+        }
+
+        // create a new instance of the pipe and wait for a client
+        let handle = unsafe {
+            CreateNamedPipeW(
+                self.path.as_ptr(),
+                mode,
+                PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT,
+                PIPE_UNLIMITED_INSTANCES,
+                PIPE_BUFFER_SIZE,
+                PIPE_BUFFER_SIZE,
+                0,
+                &mut attributes,
+            )
+        };
+        if handle == INVALID_HANDLE_VALUE {
+            return Err(io::Error::last_os_error());
+        }
+        if unsafe { ConnectNamedPipe(handle, ptr::null_mut()) } == FALSE {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(ERROR_PIPE_CONNECTED as i32) {
+                unsafe { CloseHandle(handle) };
+                return Err(err);
+            }
+        }
+        Ok(unsafe { File::from_raw_handle(handle as RawHandle) })
+    }
+}
+
+impl Drop for NamedPipe {
+    fn drop(&mut self) {
+        unsafe { LocalFree(self.security) };
+    }
+}
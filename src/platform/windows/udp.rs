@@ -0,0 +1,290 @@
+use super::super::udp::*;
+use super::super::Endpoint;
+
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::os::windows::io::AsRawSocket;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use winapi::ctypes::{c_char, c_int};
+use winapi::shared::minwindef::DWORD;
+use winapi::shared::netioapi::if_nametoindex;
+use winapi::shared::ws2def::{IPPROTO_IP, IPPROTO_IPV6};
+use winapi::shared::ws2ipdef::{IPV6_UNICAST_HOPS, IPV6_UNICAST_IF};
+use winapi::um::winsock2::{setsockopt, SOCKET};
+
+// not exported by winapi
+const IP_UNICAST_IF: c_int = 31;
+
+// Semantics:
+// Interval at which blocked readers check whether the bind has been closed
+const READ_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Semantics:
+// TTL / hop limit restored when the configured TTL is cleared (the system default)
+const DEFAULT_TTL: u32 = 128;
+
+pub struct WindowsUDP();
+
+pub struct WindowsOwner {
+    port: u16,
+    sock4: Option<Arc<UdpSocket>>,
+    sock6: Option<Arc<UdpSocket>>,
+    closed: Arc<AtomicBool>,
+}
+
+pub struct WindowsUDPReader {
+    sock: Arc<UdpSocket>,
+    closed: Arc<AtomicBool>,
+}
+
+#[derive(Clone)]
+pub struct WindowsUDPWriter {
+    sock4: Option<Arc<UdpSocket>>,
+    sock6: Option<Arc<UdpSocket>>,
+}
+
+/// Endpoint of a peer (the source address is selected by the routing table)
+pub struct WindowsEndpoint(SocketAddr);
+
+// Internal function
+//
+// Convert IPv4-mapped IPv6 addresses to IPv4
+fn unmap(addr: SocketAddr) -> SocketAddr {
+    if let SocketAddr::V6(addr6) = addr {
+        if let Some(ip) = addr6.ip().to_ipv4() {
+            if addr6.ip().segments()[..6] == [0, 0, 0, 0, 0, 0xffff] {
+                return SocketAddr::V4(SocketAddrV4::new(ip, addr6.port()));
+            }
+        }
+    }
+    addr
+}
+
+impl Endpoint for WindowsEndpoint {
+    fn from_address(addr: SocketAddr) -> Self {
+        WindowsEndpoint(unmap(addr))
+    }
+
+    fn into_address(&self) -> SocketAddr {
+        self.0
+    }
+
+    fn clear_src(&mut self) {}
+}
+
+impl Reader<WindowsEndpoint> for WindowsUDPReader {
+    type Error = io::Error;
+
+    fn read(&self, buf: &mut [u8]) -> Result<(usize, WindowsEndpoint), Self::Error> {
+        loop {
+            match self.sock.recv_from(buf) {
+                Ok((len, src)) => return Ok((len, WindowsEndpoint::from_address(src))),
+                Err(e) => match e.kind() {
+                    // ICMP port unreachable (from an earlier send) is reported as a reset
+                    io::ErrorKind::WouldBlock
+                    | io::ErrorKind::TimedOut
+                    | io::ErrorKind::Interrupted
+                    | io::ErrorKind::ConnectionReset
+                        if !self.closed.load(Ordering::Acquire) =>
+                    {
+                        continue
+                    }
+                    _ => return Err(e),
+                },
+            }
+        }
+    }
+}
+
+impl Writer<WindowsEndpoint> for WindowsUDPWriter {
+    type Error = io::Error;
+
+    fn write(&self, buf: &[u8], dst: &mut WindowsEndpoint) -> Result<(), Self::Error> {
+        let sock = match dst.0 {
+            SocketAddr::V4(_) => self.sock4.as_ref(),
+            SocketAddr::V6(_) => self.sock6.as_ref(),
+        };
+        match sock {
+            Some(sock) => sock.send_to(buf, dst.0).map(|_| ()),
+            None => Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                "no socket bound for the address family",
+            )),
+        }
+    }
+}
+
+fn setsockopt_int(sock: &UdpSocket, level: c_int, name: c_int, value: DWORD) -> io::Result<()> {
+    let res = unsafe {
+        setsockopt(
+            sock.as_raw_socket() as SOCKET,
+            level,
+            name,
+            &value as *const DWORD as *const c_char,
+            mem::size_of::<DWORD>() as c_int,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+impl Owner for WindowsOwner {
+    type Error = io::Error;
+
+    fn get_port(&self) -> u16 {
+        self.port
+    }
+
+    fn set_fwmark(&mut self, value: Option<u32>) -> Result<(), Self::Error> {
+        match value {
+            None => Ok(()),
+            Some(_) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "fwmark is not supported on this platform",
+            )),
+        }
+    }
+
+    fn set_interface(&mut self, name: Option<&str>) -> Result<(), Self::Error> {
+        // index 0 removes the binding
+        let index = match name {
+            None => 0,
+            Some(name) => {
+                let name = CString::new(name)
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid name"))?;
+                match unsafe { if_nametoindex(name.as_ptr()) } {
+                    0 => return Err(io::Error::new(io::ErrorKind::NotFound, "no such interface")),
+                    index => index,
+                }
+            }
+        };
+
+        // the IPv4 option expects the index in network byte order
+        if let Some(sock) = self.sock4.as_ref() {
+            setsockopt_int(sock, IPPROTO_IP as c_int, IP_UNICAST_IF, index.to_be())?;
+        }
+        if let Some(sock) = self.sock6.as_ref() {
+            setsockopt_int(sock, IPPROTO_IPV6 as c_int, IPV6_UNICAST_IF, index)?;
+        }
+        Ok(())
+    }
+
+    fn set_ttl(&mut self, ttl: Option<u8>) -> Result<(), Self::Error> {
+        let value = ttl.map(u32::from).unwrap_or(DEFAULT_TTL);
+        if let Some(sock) = self.sock4.as_ref() {
+            sock.set_ttl(value)?;
+        }
+        if let Some(sock) = self.sock6.as_ref() {
+            setsockopt_int(sock, IPPROTO_IPV6 as c_int, IPV6_UNICAST_HOPS, value)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for WindowsOwner {
+    fn drop(&mut self) {
+        // the readers terminate on their next poll
+        log::debug!("closing the bind (port = {})", self.port);
+        self.closed.store(true, Ordering::Release);
+    }
+}
+
+impl UDP for WindowsUDP {
+    type Error = io::Error;
+    type Endpoint = WindowsEndpoint;
+    type Writer = WindowsUDPWriter;
+    type Reader = WindowsUDPReader;
+}
+
+impl PlatformUDP for WindowsUDP {
+    type Owner = WindowsOwner;
+
+    /// Bind an IPv6 (v6 only, the default on this platform) and an IPv4 socket on the same port
+    ///
+    /// Note: the port is held until the readers have noticed that the previous bind was closed,
+    /// hence rebinding to the same port may fail for up to READ_POLL_INTERVAL.
+    fn bind(port: u16) -> Result<(Vec<Self::Reader>, Self::Writer, Self::Owner), Self::Error> {
+        log::debug!("bind to port {}", port);
+
+        // bind IPv6 first (if available), then IPv4 on the same port
+        let sock6 = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, port)).map(Arc::new);
+        let port = match sock6.as_ref() {
+            Ok(sock) => sock.local_addr()?.port(),
+            Err(e) => {
+                log::debug!("failed to bind IPv6 socket: {}", e);
+                port
+            }
+        };
+        let sock4 = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port)).map(Arc::new);
+        let port = match (sock4.as_ref(), sock6.as_ref()) {
+            (Ok(sock), _) => sock.local_addr()?.port(),
+            (Err(_), Ok(_)) => port,
+            (Err(e), Err(_)) => return Err(io::Error::new(e.kind(), e.to_string())),
+        };
+
+        let sock4 = sock4.ok();
+        let sock6 = sock6.ok();
+        for sock in sock4.iter().chain(sock6.iter()) {
+            sock.set_read_timeout(Some(READ_POLL_INTERVAL))?;
+        }
+        let closed = Arc::new(AtomicBool::new(false));
+        let readers = sock4
+            .iter()
+            .chain(sock6.iter())
+            .map(|sock| WindowsUDPReader {
+                sock: sock.clone(),
+                closed: closed.clone(),
+            })
+            .collect();
+
+        Ok((
+            readers,
+            WindowsUDPWriter {
+                sock4: sock4.clone(),
+                sock6: sock6.clone(),
+            },
+            WindowsOwner {
+                port,
+                sock4,
+                sock6,
+                closed,
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bind_loopback() {
+        let (readers, writer, owner) = WindowsUDP::bind(0).unwrap();
+        let port = owner.get_port();
+        assert_ne!(port, 0);
+
+        // send to ourselves over IPv4
+        let mut dst =
+            WindowsEndpoint::from_address(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port));
+        writer.write(&[1, 2, 3], &mut dst).unwrap();
+        let mut buf = [0u8; 16];
+        let reader = readers
+            .iter()
+            .find(|reader| reader.sock.local_addr().unwrap().is_ipv4())
+            .unwrap();
+        let (len, src) = reader.read(&mut buf).unwrap();
+        assert_eq!(&buf[..len], &[1, 2, 3]);
+        assert_eq!(src.into_address().port(), port);
+
+        // readers terminate once the owner is dropped
+        drop(owner);
+        assert!(reader.read(&mut buf).is_err());
+    }
+}
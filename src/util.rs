@@ -1,8 +1,19 @@
-use std::cmp::Ordering;
 use std::fmt;
+
+#[cfg(unix)]
+use std::cmp::Ordering;
+#[cfg(unix)]
 use std::process::exit;
 
+#[cfg(unix)]
+use std::mem;
+#[cfg(unix)]
+use std::ptr;
+
+#[cfg(unix)]
 use libc::{c_char, chdir, chroot, fork, getpwnam, getuid, setgid, setsid, setuid, umask};
+#[cfg(unix)]
+use libc::{c_int, pthread_sigmask, sigaddset, sigemptyset, sigset_t, sigwait, SIGHUP, SIG_BLOCK};
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
 pub enum DaemonizeError {
@@ -12,6 +23,8 @@ pub enum DaemonizeError {
     SetUser,
     Chroot,
     Chdir,
+    #[cfg(not(unix))]
+    Unsupported,
 }
 
 impl fmt::Display for DaemonizeError {
@@ -23,11 +36,14 @@ impl fmt::Display for DaemonizeError {
             DaemonizeError::SetUser => "unable to set user (drop privileges)",
             DaemonizeError::Chroot => "unable to enter chroot jail",
             DaemonizeError::Chdir => "failed to change directory",
+            #[cfg(not(unix))]
+            DaemonizeError::Unsupported => "not supported on this platform",
         }
         .fmt(f)
     }
 }
 
+#[cfg(unix)]
 fn fork_and_exit() -> Result<(), DaemonizeError> {
     let pid = unsafe { fork() };
     match pid.cmp(&0) {
@@ -37,6 +53,7 @@ fn fork_and_exit() -> Result<(), DaemonizeError> {
     }
 }
 
+#[cfg(unix)]
 pub fn daemonize() -> Result<(), DaemonizeError> {
     // fork from the original parent
     fork_and_exit()?;
@@ -50,6 +67,7 @@ pub fn daemonize() -> Result<(), DaemonizeError> {
     fork_and_exit()
 }
 
+#[cfg(unix)]
 pub fn drop_privileges() -> Result<(), DaemonizeError> {
     // retrieve nobody's uid & gid
     let usr = unsafe { getpwnam("nobody\x00".as_ptr() as *const c_char) };
@@ -83,3 +101,40 @@ pub fn drop_privileges() -> Result<(), DaemonizeError> {
         Ok(())
     }
 }
+
+#[cfg(unix)]
+fn hangup_set() -> sigset_t {
+    unsafe {
+        let mut set: sigset_t = mem::zeroed();
+        sigemptyset(&mut set);
+        sigaddset(&mut set, SIGHUP);
+        set
+    }
+}
+
+/// Block SIGHUP, to be received by `wait_hangup`:
+/// must be called before any thread is spawned (the threads inherit the signal mask).
+#[cfg(unix)]
+pub fn block_hangup() {
+    let set = hangup_set();
+    unsafe { pthread_sigmask(SIG_BLOCK, &set, ptr::null_mut()) };
+}
+
+/// Wait for the next SIGHUP (blocked by `block_hangup`)
+#[cfg(unix)]
+pub fn wait_hangup() {
+    let set = hangup_set();
+    let mut signal: c_int = 0;
+    while unsafe { sigwait(&set, &mut signal) } != 0 {}
+}
+
+// services are run in the background (and as a restricted account) by the service manager
+#[cfg(not(unix))]
+pub fn daemonize() -> Result<(), DaemonizeError> {
+    Err(DaemonizeError::Unsupported)
+}
+
+#[cfg(not(unix))]
+pub fn drop_privileges() -> Result<(), DaemonizeError> {
+    Err(DaemonizeError::Unsupported)
+}
@@ -10,8 +10,14 @@ pub const REKEY_ATTEMPT_TIME: Duration = Duration::from_secs(90);
 pub const REKEY_TIMEOUT: Duration = Duration::from_secs(5);
 pub const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(10);
 
-pub const MAX_TIMER_HANDSHAKES: usize =
-    (REKEY_ATTEMPT_TIME.as_secs() / REKEY_TIMEOUT.as_secs()) as usize;
+// Semantics:
+// Upper bound of the random jitter added to REKEY_TIMEOUT when retransmitting initiations
+pub const REKEY_TIMEOUT_JITTER_MAX: Duration = Duration::from_millis(333);
+
+// Semantics:
+// The retransmission timeout is doubled after every unanswered initiation (exponential backoff),
+// up to the following duration (or the configured rekey timeout if longer)
+pub const REKEY_TIMEOUT_MAX: Duration = Duration::from_secs(20);
 
 // Semantics:
 // Maximum number of buffered handshake requests
@@ -28,10 +34,50 @@ pub const THRESHOLD_UNDER_LOAD: usize = MAX_QUEUED_INCOMING_HANDSHAKES / 8;
 // it will remain under load for at least the following duration.
 pub const DURATION_UNDER_LOAD: Duration = Duration::from_secs(1);
 
+// Semantics:
+// Largest (super-)packet read from a TUN device with offloading enabled
+pub const MAX_OFFLOAD_PACKET: usize = 1 << 16;
+
+// Semantics:
+// Largest MTU of the device (jumbo frames): the transport message
+// (the padded packet, 16-byte header and 16-byte tag) must fit in a UDP datagram over IPv4
+pub const MAX_MTU: usize = 0xffff - 20 - 8 - 32;
+
+// Semantics:
+// Largest MTU for which packets are read from the TUN device directly into a new message,
+// for larger (jumbo) MTUs packets are read into a reusable buffer and copied into a message of their size
+pub const MAX_INPLACE_MTU: usize = 1 << 14;
+
 // Semantics:
 // The payload of transport messages are padded to this multiple
 pub const MESSAGE_PADDING_MULTIPLE: usize = 16;
 
+// Semantics:
+// Interval at which sessions and handshakes older than REJECT_AFTER_TIME * 3 are erased
+pub const KEY_SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+
+// Semantics:
+// Interval at which the hostnames of peer endpoints are re-resolved
+pub const ENDPOINT_REFRESH_INTERVAL: Duration = Duration::from_secs(300);
+
+// Semantics:
+// Age of the latest handshake after which the endpoint is stale (may be re-resolved),
+// as in reresolve-dns of wireguard-tools
+pub const ENDPOINT_STALE_AFTER: Duration = Duration::from_secs(135);
+
+// Semantics:
+// Interval at which the resolver checks for endpoints due for re-resolution
+pub const ENDPOINT_RESOLVE_TICK: Duration = Duration::from_secs(1);
+
+// Semantics:
+// Time after which the adaptive persistent keepalive probes longer intervals again,
+// having settled on the longest interval keeping the NAT binding
+pub const ADAPTIVE_KEEPALIVE_REPROBE: Duration = Duration::from_secs(3600);
+
+// Semantics:
+// Time after which connecting to a peer over TCP (fallback transport) is abandoned
+pub const TCP_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
 // Semantics:
 // Longest possible duration of any WireGuard timer
 pub const TIMER_MAX_DURATION: Duration = Duration::from_secs(200);
@@ -48,6 +94,11 @@ pub const TIMERS_SLOTS: usize = (TIMER_MAX_DURATION.as_micros() / TIMERS_TICK.as
 // Initial capacity of timer-wheel (grows to accommodate more timers).
 pub const TIMERS_CAPACITY: usize = 16;
 
+// Performance:
+// Maximum number of datagrams received by a UDP reader in a single system call
+// (large enough to hold the segments of a coalesced datagram, see UDP GRO)
+pub const UDP_READ_BATCH: usize = 64;
+
 /* A long duration (compared to the WireGuard time constants),
  * used in places to avoid Option<Instant> by instead using a long "expired" Instant:
  * (Instant::now() - TIME_HORIZON)
@@ -0,0 +1,144 @@
+/* Standalone verification of the mac fields
+ *
+ * Allows a UDP front-end (e.g. a load balancer) to drop garbage
+ * before it reaches the tunnel process, without a full handshake device:
+ * only the public key of the server is required.
+ */
+
+use std::net::SocketAddr;
+
+use byteorder::{ByteOrder, LittleEndian};
+use rand::prelude::{CryptoRng, RngCore};
+use zerocopy::AsBytes;
+
+use x25519_dalek::PublicKey;
+
+use super::macs;
+use super::messages::{CookieReply, Initiation, MacsFooter, ReservedBytes, Response};
+use super::messages::{TYPE_INITIATION, TYPE_RESPONSE};
+use super::types::HandshakeError;
+
+pub struct MacVerifier {
+    validator: macs::Validator,
+}
+
+// Internal function
+//
+// Split a raw initiation or response into the inner message, the mac footer and the sender id
+// (the reserved bytes are covered by mac1, hence any policy is accepted)
+fn split(msg: &[u8]) -> Result<(&[u8], &MacsFooter, u32), HandshakeError> {
+    if msg.len() < 4 {
+        return Err(HandshakeError::InvalidMessageFormat);
+    }
+    match LittleEndian::read_u32(msg) & 0xff {
+        TYPE_INITIATION => {
+            let msg = Initiation::parse_with(msg, ReservedBytes::Ignore)?.into_ref();
+            Ok((msg.noise.as_bytes(), &msg.macs, msg.noise.f_sender.get()))
+        }
+        TYPE_RESPONSE => {
+            let msg = Response::parse_with(msg, ReservedBytes::Ignore)?.into_ref();
+            Ok((msg.noise.as_bytes(), &msg.macs, msg.noise.f_sender.get()))
+        }
+        _ => Err(HandshakeError::InvalidMessageFormat),
+    }
+}
+
+impl MacVerifier {
+    /// Create a new verifier
+    ///
+    /// # Arguments
+    ///
+    /// - `pk`: The static public key of the server (tunnel process)
+    pub fn new(pk: PublicKey) -> MacVerifier {
+        MacVerifier {
+            validator: macs::Validator::new(pk),
+        }
+    }
+
+    /// Check the mac1 field of a raw initiation or response
+    ///
+    /// # Arguments
+    ///
+    /// - `msg`: The received message (untrusted input)
+    pub fn verify_mac1(&self, msg: &[u8]) -> Result<(), HandshakeError> {
+        let (inner, macs, _) = split(msg)?;
+        self.validator.check_mac1(inner, macs)
+    }
+
+    /// Check the mac2 field of a raw initiation or response,
+    /// against the cookies issued by this verifier (see `cookie_reply`)
+    ///
+    /// # Arguments
+    ///
+    /// - `msg`: The received message (untrusted input)
+    /// - `src`: The source address of the message
+    pub fn verify_mac2(&self, msg: &[u8], src: &SocketAddr) -> Result<bool, HandshakeError> {
+        let (inner, macs, _) = split(msg)?;
+        Ok(self.validator.check_mac2(inner, src, macs))
+    }
+
+    /// Create a cookie reply to a raw initiation or response,
+    /// the initiator will include a valid mac2 field in subsequent messages.
+    ///
+    /// # Arguments
+    ///
+    /// - `msg`: The received message (untrusted input)
+    /// - `src`: The source address of the message
+    pub fn cookie_reply<R: RngCore + CryptoRng>(
+        &self,
+        rng: &mut R,
+        msg: &[u8],
+        src: &SocketAddr,
+    ) -> Result<Vec<u8>, HandshakeError> {
+        let (_, macs, sender) = split(msg)?;
+        let mut reply = CookieReply::default();
+        self.validator
+            .create_cookie_reply(rng, sender, src, macs, &mut reply);
+        Ok(reply.as_bytes().to_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::device::Device;
+    use super::*;
+    use rand::rngs::OsRng;
+    use x25519_dalek::StaticSecret;
+
+    #[test]
+    fn test_mac_verifier() {
+        let sk1 = StaticSecret::new(&mut OsRng);
+        let sk2 = StaticSecret::new(&mut OsRng);
+        let pk1 = PublicKey::from(&sk1);
+        let pk2 = PublicKey::from(&sk2);
+        let src: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+
+        let mut dev: Device<()> = Device::new();
+        dev.set_sk(Some(sk1));
+        dev.add(pk2, ()).unwrap();
+
+        let verifier = MacVerifier::new(pk2);
+
+        // valid initiation to the server
+        let mut msg = dev.begin(&mut OsRng, &pk2).unwrap();
+        verifier.verify_mac1(&msg).unwrap();
+        assert!(!verifier.verify_mac2(&msg, &src).unwrap());
+
+        // initiation to another server
+        assert!(MacVerifier::new(pk1).verify_mac1(&msg).is_err());
+
+        // garbage
+        assert!(verifier.verify_mac1(&msg[..100]).is_err());
+        assert!(verifier.verify_mac1(&[4, 0, 0, 0]).is_err());
+        msg[20] ^= 1;
+        assert!(verifier.verify_mac1(&msg).is_err());
+
+        // the cookie reply is accepted by the initiator, which then sets mac2
+        let msg = dev.begin(&mut OsRng, &pk2).unwrap();
+        let reply = verifier.cookie_reply(&mut OsRng, &msg, &src).unwrap();
+        dev.process(&mut OsRng, &reply, None).unwrap();
+        let msg = dev.begin(&mut OsRng, &pk2).unwrap();
+        verifier.verify_mac1(&msg).unwrap();
+        assert!(verifier.verify_mac2(&msg, &src).unwrap());
+    }
+}
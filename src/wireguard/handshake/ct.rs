@@ -0,0 +1,60 @@
+/* Constant-time comparison helpers
+ *
+ * Every comparison of MACs, tags, shared secrets or keys in the handshake
+ * should go through these functions, rather than using the (short-circuiting)
+ * equality operators on byte slices.
+ */
+
+use subtle::ConstantTimeEq;
+
+/// Compare two byte slices in constant time
+///
+/// # Arguments
+///
+/// - `a`: The first byte slice
+/// - `b`: The second byte slice
+///
+/// # Returns
+///
+/// True iff the slices are equal.
+/// The running time only depends on the length of the slices.
+#[inline(always)]
+pub fn eq(a: &[u8], b: &[u8]) -> bool {
+    a.ct_eq(b).into()
+}
+
+/// Check if a byte slice is all zero in constant time
+///
+/// # Arguments
+///
+/// - `a`: The byte slice to check
+///
+/// # Returns
+///
+/// True iff every byte of the slice is zero.
+#[inline(always)]
+pub fn is_zero(a: &[u8]) -> bool {
+    a.iter().fold(0u8, |acc, b| acc | b).ct_eq(&0u8).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ct_eq() {
+        assert!(eq(&[1, 2, 3], &[1, 2, 3]));
+        assert!(!eq(&[1, 2, 3], &[1, 2, 4]));
+        assert!(!eq(&[1, 2, 3], &[1, 2]));
+        assert!(eq(&[], &[]));
+    }
+
+    #[test]
+    fn test_ct_is_zero() {
+        assert!(is_zero(&[0u8; 32]));
+        assert!(is_zero(&[]));
+        let mut v = [0u8; 32];
+        v[31] = 1;
+        assert!(!is_zero(&v));
+    }
+}
@@ -1,7 +1,8 @@
 use std::collections::hash_map;
 use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use byteorder::{ByteOrder, LittleEndian};
 use dashmap::mapref::entry::Entry;
@@ -16,20 +17,21 @@ use clear_on_drop::clear::Clear;
 use x25519_dalek::PublicKey;
 use x25519_dalek::StaticSecret;
 
+use super::super::secret::Secret;
+use super::ct;
 use super::macs;
-use super::messages::{CookieReply, Initiation, Response};
+use super::messages::{CookieReply, Initiation, MacsFooter, Response};
 use super::messages::{TYPE_COOKIE_REPLY, TYPE_INITIATION, TYPE_RESPONSE};
 use super::noise;
-use super::peer::Peer;
+use super::peer::{Peer, State};
 use super::ratelimiter::RateLimiter;
+use super::timestamp::{self, TAI64N};
 use super::types::*;
 
-const MAX_PEER_PER_DEVICE: usize = 1 << 20;
-
 pub struct KeyState {
-    pub(super) sk: StaticSecret, // static secret key
-    pub(super) pk: PublicKey,    // static public key
-    macs: macs::Validator,       // validator for the mac fields
+    pub(super) sk: Box<dyn StaticDh>, // static secret key (performs the DH operations)
+    pub(super) pk: PublicKey,         // static public key
+    macs: macs::Validator,            // validator for the mac fields
 }
 
 /// The device is generic over an "opaque" type
@@ -40,19 +42,38 @@ pub struct Device<O> {
     id_map: DashMap<u32, [u8; 32]>, // concurrent map
     pk_map: HashMap<[u8; 32], Peer<O>>,
     limiter: Mutex<RateLimiter>,
+    config: HandshakeConfig,
+    provisioner: Option<Provisioner<O>>,
+    provisioned: Mutex<Vec<(PublicKey, PeerConfig<O>)>>,
+    clock: Arc<dyn Clock>,
+    pub(super) protocol: noise::Protocol,
 }
 
 pub struct Iter<'a, O> {
     iter: hash_map::Iter<'a, [u8; 32], Peer<O>>,
 }
 
+pub struct PeerInfoIter<'a, O> {
+    iter: hash_map::Iter<'a, [u8; 32], Peer<O>>,
+}
+
+impl<'a, O> Iterator for PeerInfoIter<'a, O> {
+    type Item = (PublicKey, PeerInfo);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter
+            .next()
+            .map(|(pk, peer)| (PublicKey::from(*pk), peer.info()))
+    }
+}
+
 impl<'a, O> Iterator for Iter<'a, O> {
     type Item = (PublicKey, &'a O);
 
     fn next(&mut self) -> Option<Self::Item> {
         self.iter
             .next()
-            .map(|(pk, peer)| (PublicKey::from(*pk), &peer.opaque))
+            .map(|(pk, peer)| (PublicKey::from(*pk), peer.opaque()))
     }
 }
 
@@ -79,9 +100,17 @@ impl<O> Device<O> {
         }
     }
 
+    /// Enables enumeration of (public key, handshake state) pairs,
+    /// e.g. for status reporting.
+    pub fn peers(&self) -> PeerInfoIter<'_, O> {
+        PeerInfoIter {
+            iter: self.pk_map.iter(),
+        }
+    }
+
     /// Enables lookup by public key without exposing internal peer type.
     pub fn get(&self, pk: &PublicKey) -> Option<&O> {
-        self.pk_map.get(pk.as_bytes()).map(|peer| &peer.opaque)
+        self.pk_map.get(pk.as_bytes()).map(|peer| peer.opaque())
     }
 
     pub fn contains_key(&self, pk: &PublicKey) -> bool {
@@ -100,23 +129,126 @@ impl<O> Device<O> {
             id_map: DashMap::new(),
             pk_map: HashMap::new(),
             limiter: Mutex::new(RateLimiter::new()),
+            config: HandshakeConfig::default(),
+            provisioner: None,
+            provisioned: Mutex::new(vec![]),
+            clock: Arc::new(SystemClock),
+            protocol: noise::Protocol::default(),
+        }
+    }
+
+    /// Initialize a new handshake state machine using a custom protocol
+    /// (see `Protocol::new`), for private networks deliberately incompatible with standard WireGuard.
+    ///
+    /// # Arguments
+    ///
+    /// - `protocol`: The initial chaining key and hash transcript
+    pub fn with_protocol(protocol: noise::Protocol) -> Device<O> {
+        let mut device = Device::new();
+        device.protocol = protocol;
+        device
+    }
+
+    /// Set the callback used to admit unknown initiators
+    ///
+    /// When an initiation from an unknown (but authenticated) static public key is received,
+    /// the callback decides whether to admit the peer.
+    /// Admitted peers are queued and processing fails with `HandshakeError::PeerProvisioned`:
+    /// the caller should then call `admit_provisioned` and process the message again.
+    ///
+    /// # Arguments
+    ///
+    /// * `provisioner` - The callback, or None to reject every unknown initiator
+    pub fn set_provisioner(&mut self, provisioner: Option<Provisioner<O>>) {
+        self.provisioner = provisioner;
+    }
+
+    /// Add every peer admitted by the provisioning callback to the device
+    ///
+    /// # Returns
+    ///
+    /// The public keys of the added peers,
+    /// and the peers evicted to make room for them (see `add`):
+    /// the caller must tear down the state associated with the evicted peers.
+    pub fn admit_provisioned(&mut self) -> (Vec<PublicKey>, Vec<(PublicKey, O)>) {
+        let provisioned: Vec<_> = self.provisioned.lock().unwrap().drain(..).collect();
+        let mut added = Vec::with_capacity(provisioned.len());
+        let mut evicted = vec![];
+        for (pk, config) in provisioned {
+            // the same initiator might have been admitted several times
+            if self.pk_map.contains_key(pk.as_bytes()) {
+                continue;
+            }
+            if let Ok(old) = self.add(pk, config.opaque) {
+                let _ = self.set_psk(pk, config.psk);
+                added.push(pk);
+                evicted.extend(old);
+            }
+        }
+        (added, evicted)
+    }
+
+    /// Update the replay and flood protection thresholds
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The new handshake configuration
+    pub fn set_config(&mut self, config: HandshakeConfig) {
+        self.config = config;
+
+        // update the lifetime of cookies
+        if let Some(keyst) = self.keyst.as_mut() {
+            keyst.macs.set_lifetime(config.cookie_lifetime);
+        }
+        for peer in self.pk_map.values() {
+            peer.macs.lock().set_lifetime(config.cookie_lifetime);
         }
     }
 
+    /// Return the replay and flood protection thresholds
+    pub fn get_config(&self) -> HandshakeConfig {
+        self.config
+    }
+
+    /// Replace the source of time (the system clock by default)
+    /// with a `ManualClock` to advance virtual time in tests.
+    ///
+    /// # Arguments
+    ///
+    /// * `clock` - The new clock
+    #[cfg(test)]
+    pub fn allocated_ids(&self, pk: &PublicKey) -> usize {
+        self.id_map
+            .iter()
+            .filter(|entry| entry.value() == pk.as_bytes())
+            .count()
+    }
+
+    #[cfg(test)]
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// Return the current time according to the clock of the device
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
     fn update_ss(&mut self) -> (Vec<u32>, Option<PublicKey>) {
         let mut same = None;
         let mut ids = Vec::with_capacity(self.pk_map.len());
         for (pk, peer) in self.pk_map.iter_mut() {
             if let Some(key) = self.keyst.as_ref() {
-                if key.pk.as_bytes() == pk {
+                if ct::eq(key.pk.as_bytes(), pk) {
                     same = Some(PublicKey::from(*pk));
-                    peer.ss.clear()
+                    peer.ss.lock().clear()
                 } else {
+                    // computed again by the next handshake if unavailable
                     let pk = PublicKey::from(*pk);
-                    peer.ss = *key.sk.diffie_hellman(&pk).as_bytes();
+                    **peer.ss.lock() = key.sk.dh(&pk).unwrap_or([0u8; 32]);
                 }
             } else {
-                peer.ss.clear();
+                peer.ss.lock().clear();
             }
             if let Some(id) = peer.reset_state() {
                 ids.push(id)
@@ -132,10 +264,25 @@ impl<O> Device<O> {
     ///
     /// * `sk` - x25519 scalar representing the local private key
     pub fn set_sk(&mut self, sk: Option<StaticSecret>) -> Option<PublicKey> {
+        self.set_static_key(sk.map(|sk| Box::new(SecretKey::from(sk)) as Box<dyn StaticDh>))
+    }
+
+    /// Update the static key of the device, which may be held outside of the process
+    ///
+    /// # Arguments
+    ///
+    /// * `sk` - The backend performing the Diffie-Hellman operations with the private key
+    ///
+    /// # Returns
+    ///
+    /// The public key of a peer matching the new public key of the device (the peer is removed)
+    pub fn set_static_key(&mut self, sk: Option<Box<dyn StaticDh>>) -> Option<PublicKey> {
         // update secret and public key
+        let lifetime = self.config.cookie_lifetime;
         self.keyst = sk.map(|sk| {
-            let pk = PublicKey::from(&sk);
-            let macs = macs::Validator::new(pk);
+            let pk = sk.pubkey();
+            let mut macs = macs::Validator::new(pk);
+            macs.set_lifetime(lifetime);
             KeyState { pk, sk, macs }
         });
 
@@ -159,49 +306,77 @@ impl<O> Device<O> {
     ///
     /// # Returns
     ///
-    /// A secret key (x25519 scalar)
-    pub fn get_sk(&self) -> Option<&StaticSecret> {
-        self.keyst.as_ref().map(|key| &key.sk)
+    /// A secret key (x25519 scalar), None if the key cannot be exported from its backend
+    pub fn get_sk(&self) -> Option<StaticSecret> {
+        self.keyst.as_ref().and_then(|key| key.sk.secret())
     }
 
     /// Add a new public key to the state machine
-    /// To remove public keys, you must create a new machine instance
     ///
     /// # Arguments
     ///
     /// * `pk` - The public key to add
     /// * `identifier` - Associated identifier which can be used to distinguish the peers
-    pub fn add(&mut self, pk: PublicKey, opaque: O) -> Result<(), ConfigError> {
-        // ensure less than 2^20 peers
-        if self.pk_map.len() > MAX_PEER_PER_DEVICE {
-            return Err(ConfigError::new("Too many peers for device"));
+    ///
+    /// # Returns
+    ///
+    /// The public key and opaque value of a peer evicted to make room (if any),
+    /// see `HandshakeConfig::eviction`.
+    ///
+    /// The call fails if the device is at capacity (and eviction is disabled)
+    /// or the public key matches the device (or is a low-order point).
+    pub fn add(&mut self, pk: PublicKey, opaque: O) -> Result<Option<(PublicKey, O)>, ConfigError> {
+        // error if the public key is a low-order point (all-zero shared secrets)
+        if noise::is_low_order(&pk) {
+            return Err(ConfigError::new("Public key of peer is a low-order point"));
         }
 
         // error if public key matches device
         if let Some(key) = self.keyst.as_ref() {
-            if pk.as_bytes() == key.pk.as_bytes() {
+            if ct::eq(pk.as_bytes(), key.pk.as_bytes()) {
                 return Err(ConfigError::new("Public key of peer matches the device"));
             }
         }
 
+        // ensure less than max_peers (at most 2^20) peers
+        let mut evicted = None;
+        let max_peers = self.config.max_peers.min(MAX_PEER_PER_DEVICE);
+        if !self.pk_map.contains_key(pk.as_bytes()) && self.pk_map.len() >= max_peers {
+            match self.config.eviction {
+                EvictionPolicy::Reject => {
+                    return Err(ConfigError::new("Too many peers for device"));
+                }
+                EvictionPolicy::OldestHandshake => {
+                    let oldest = self
+                        .pk_map
+                        .iter()
+                        .min_by_key(|(_, peer)| peer.last_activity())
+                        .map(|(pk, _)| PublicKey::from(*pk))
+                        .ok_or_else(|| ConfigError::new("Too many peers for device"))?;
+                    evicted = Some((oldest, self.remove_peer(&oldest)?));
+                }
+            }
+        }
+
         // pre-compute shared secret and add to pk_map
-        self.pk_map.insert(
-            *pk.as_bytes(),
-            Peer::new(
-                pk,
-                self.keyst
-                    .as_ref()
-                    .map(|key| *key.sk.diffie_hellman(&pk).as_bytes())
-                    .unwrap_or([0u8; 32]),
-                opaque,
-            ),
+        let peer = Peer::new(
+            pk,
+            self.keyst
+                .as_ref()
+                .and_then(|key| key.sk.dh(&pk))
+                .unwrap_or([0u8; 32]),
+            opaque,
+            self.now(),
         );
+        peer.macs.lock().set_lifetime(self.config.cookie_lifetime);
+        self.pk_map.insert(*pk.as_bytes(), peer);
 
-        Ok(())
+        Ok(evicted)
     }
 
     /// Remove a peer by public key
-    /// To remove public keys, you must create a new machine instance
+    ///
+    /// Any in-flight handshake is aborted and the secrets of the peer are cleared.
     ///
     /// # Arguments
     ///
@@ -209,33 +384,103 @@ impl<O> Device<O> {
     ///
     /// # Returns
     ///
+    /// The opaque value associated with the peer,
+    /// enabling the caller to tear down any state (e.g. routing entries and keypairs).
+    ///
     /// The call might fail if the public key is not found
-    pub fn remove(&mut self, pk: &PublicKey) -> Result<(), ConfigError> {
+    pub fn remove_peer(&mut self, pk: &PublicKey) -> Result<O, ConfigError> {
         // remove the peer
-        self.pk_map
+        let peer = self
+            .pk_map
             .remove(pk.as_bytes())
             .ok_or_else(|| ConfigError::new("Public key not in device"))?;
 
         // remove every id entry for the peer in the public key map
         // O(n) operations, however it is rare: only when removing peers.
         self.id_map.retain(|_, v| v != pk.as_bytes());
+
+        // clear the handshake state and secrets
+        Ok(peer.into_opaque())
+    }
+
+    /// Replace the public key of a peer in place
+    ///
+    /// The opaque value and psk of the peer are retained,
+    /// while the static-static shared secret and mac keys are recomputed
+    /// and any in-flight handshake is aborted.
+    ///
+    /// # Arguments
+    ///
+    /// * `old` - The current public key of the peer
+    /// * `new` - The new public key of the peer
+    ///
+    /// # Returns
+    ///
+    /// The call might fail if the old public key is not found,
+    /// the new public key is already in use, matches the device or is a low-order point.
+    pub fn replace_pk(&mut self, old: &PublicKey, new: PublicKey) -> Result<(), ConfigError> {
+        if self.pk_map.contains_key(new.as_bytes()) {
+            return Err(ConfigError::new("Public key already in device"));
+        }
+
+        if noise::is_low_order(&new) {
+            return Err(ConfigError::new("Public key of peer is a low-order point"));
+        }
+
+        if let Some(key) = self.keyst.as_ref() {
+            if ct::eq(new.as_bytes(), key.pk.as_bytes()) {
+                return Err(ConfigError::new("Public key of peer matches the device"));
+            }
+        }
+
+        let peer = self
+            .pk_map
+            .remove(old.as_bytes())
+            .ok_or_else(|| ConfigError::new("Public key not in device"))?;
+
+        // abort in-flight handshake
+        if let Some(id) = peer.reset_state() {
+            self.release(id);
+        }
+
+        // recompute state derived from the public key
+        peer.ss.lock().clear();
+        if let Some(ss) = self.keyst.as_ref().and_then(|key| key.sk.dh(&new)) {
+            **peer.ss.lock() = ss;
+        }
+        peer.macs.lock().set_pk(new);
+        *peer.timestamp.lock() = None;
+        *peer.last_initiation_consumption.lock() = None;
+
+        // re-associate remaining ids (of established sessions) with the new key
+        for mut entry in self.id_map.iter_mut() {
+            if *entry.value() == *old.as_bytes() {
+                *entry.value_mut() = *new.as_bytes();
+            }
+        }
+
+        self.pk_map.insert(*new.as_bytes(), peer);
         Ok(())
     }
 
-    /// Add a psk to the peer
+    /// Add, rotate or remove the psk of the peer
     ///
     /// # Arguments
     ///
     /// * `pk` - The public key of the peer
-    /// * `psk` - The psk to set / unset
+    /// * `psk` - The psk to set, or None to remove the psk
     ///
     /// # Returns
     ///
     /// The call might fail if the public key is not found
-    pub fn set_psk(&mut self, pk: PublicKey, psk: Psk) -> Result<(), ConfigError> {
+    pub fn set_psk(&mut self, pk: PublicKey, psk: Option<Psk>) -> Result<(), ConfigError> {
         match self.pk_map.get_mut(pk.as_bytes()) {
-            Some(mut peer) => {
-                peer.psk = psk;
+            Some(peer) => {
+                // an all zero psk is equivalent to no psk
+                peer.psk.clear();
+                if let Some(psk) = psk {
+                    *peer.psk = psk;
+                }
                 Ok(())
             }
             _ => Err(ConfigError::new("No such public key")),
@@ -255,11 +500,83 @@ impl<O> Device<O> {
     /// The call might fail if the public key is not found
     pub fn get_psk(&self, pk: &PublicKey) -> Result<Psk, ConfigError> {
         match self.pk_map.get(pk.as_bytes()) {
-            Some(peer) => Ok(peer.psk),
+            Some(peer) => Ok(*peer.psk),
             _ => Err(ConfigError::new("No such public key")),
         }
     }
 
+    /// Set the hybrid (post-quantum) secret of the peer
+    ///
+    /// The secret is mixed into the psk slot of the handshake,
+    /// both sides must configure the same value for the handshake to succeed.
+    ///
+    /// # Arguments
+    ///
+    /// * `pk` - The public key of the peer
+    /// * `secret` - The hybrid secret to set / unset
+    ///
+    /// # Returns
+    ///
+    /// The call might fail if the public key is not found
+    pub fn set_hybrid_secret(
+        &mut self,
+        pk: PublicKey,
+        secret: Option<[u8; 32]>,
+    ) -> Result<(), ConfigError> {
+        match self.pk_map.get_mut(pk.as_bytes()) {
+            Some(peer) => {
+                if let Some(old) = peer.hybrid.as_mut() {
+                    old.clear();
+                }
+                peer.hybrid = secret.map(Secret::new);
+                Ok(())
+            }
+            _ => Err(ConfigError::new("No such public key")),
+        }
+    }
+
+    /// Abort any in-flight handshake with the peer
+    ///
+    /// # Arguments
+    ///
+    /// * `pk` - The public key of the peer
+    ///
+    /// # Returns
+    ///
+    /// The call might fail if the public key is not found
+    pub fn reset_handshake(&self, pk: &PublicKey) -> Result<(), ConfigError> {
+        let peer = self
+            .pk_map
+            .get(pk.as_bytes())
+            .ok_or_else(|| ConfigError::new("No such public key"))?;
+        if let Some(id) = peer.reset_state() {
+            self.release(id);
+        }
+        Ok(())
+    }
+
+    /// Abort in-flight handshakes older than the given age,
+    /// erasing the ephemeral secret and chaining state of the initiation
+    ///
+    /// # Arguments
+    ///
+    /// * `max_age` - The age (according to the clock of the device) of handshakes to abort
+    ///
+    /// # Returns
+    ///
+    /// The number of aborted handshakes
+    pub fn sweep(&self, max_age: Duration) -> usize {
+        let now = self.now();
+        let mut swept = 0;
+        for peer in self.pk_map.values() {
+            if let Some(id) = peer.reset_stale_state(now, max_age) {
+                self.release(id);
+                swept += 1;
+            }
+        }
+        swept
+    }
+
     /// Release an id back to the pool
     ///
     /// # Arguments
@@ -279,6 +596,18 @@ impl<O> Device<O> {
         &self,
         rng: &mut R,
         pk: &PublicKey,
+    ) -> Result<Vec<u8>, HandshakeError> {
+        self.begin_at(rng, pk, &timestamp::now())
+    }
+
+    // Internal function
+    //
+    // Begin a new handshake using the given timestamp
+    pub(super) fn begin_at<R: RngCore + CryptoRng>(
+        &self,
+        rng: &mut R,
+        pk: &PublicKey,
+        ts: &TAI64N,
     ) -> Result<Vec<u8>, HandshakeError> {
         match (self.keyst.as_ref(), self.pk_map.get(pk.as_bytes())) {
             (_, None) => Err(HandshakeError::UnknownPublicKey),
@@ -286,9 +615,22 @@ impl<O> Device<O> {
             (Some(keyst), Some(peer)) => {
                 let local = self.allocate(rng, pk);
                 let mut msg = Initiation::default();
+                msg.noise
+                    .f_type
+                    .set(self.config.reserved.encode(TYPE_INITIATION));
 
                 // create noise part of initation
-                noise::create_initiation(rng, keyst, peer, pk, local, &mut msg.noise)?;
+                noise::create_initiation(
+                    rng,
+                    keyst,
+                    peer,
+                    pk,
+                    local,
+                    ts,
+                    self.now(),
+                    &self.protocol,
+                    &mut msg.noise,
+                )?;
 
                 // add macs to initation
                 peer.macs
@@ -326,10 +668,12 @@ impl<O> Device<O> {
         };
 
         // de-multiplex the message type field
-        match LittleEndian::read_u32(msg) {
+        // (the reserved bytes are validated when parsing)
+        let reserved = self.config.reserved;
+        match LittleEndian::read_u32(msg) & 0xff {
             TYPE_INITIATION => {
                 // parse message
-                let msg = Initiation::parse(msg)?;
+                let msg = Initiation::parse_with(msg, reserved)?;
 
                 // check mac1 field
                 keyst.macs.check_mac1(msg.noise.as_bytes(), &msg.macs)?;
@@ -338,7 +682,7 @@ impl<O> Device<O> {
                 if let Some(src) = src {
                     // check mac2 field
                     if !keyst.macs.check_mac2(msg.noise.as_bytes(), &src, &msg.macs) {
-                        let mut reply = Default::default();
+                        let mut reply = CookieReply::default();
                         keyst.macs.create_cookie_reply(
                             rng,
                             msg.noise.f_sender.get(),
@@ -346,6 +690,7 @@ impl<O> Device<O> {
                             &msg.macs,
                             &mut reply,
                         );
+                        reply.f_type.set(reserved.encode(TYPE_COOKIE_REPLY));
                         return Ok((None, Some(reply.as_bytes().to_owned()), None));
                     }
 
@@ -365,11 +710,12 @@ impl<O> Device<O> {
                 let mut resp = Response::default();
 
                 // create response (release id on error)
-                let keys = noise::create_response(rng, peer, &pk, local, st, &mut resp.noise)
+                let keys = noise::create_response(rng, self, peer, &pk, local, st, &mut resp.noise)
                     .map_err(|e| {
                         self.release(local);
                         e
                     })?;
+                resp.noise.f_type.set(reserved.encode(TYPE_RESPONSE));
 
                 // add macs to response
                 peer.macs
@@ -378,13 +724,13 @@ impl<O> Device<O> {
 
                 // return unconfirmed keypair and the response as vector
                 Ok((
-                    Some(&peer.opaque),
+                    Some(peer.opaque()),
                     Some(resp.as_bytes().to_owned()),
                     Some(keys),
                 ))
             }
             TYPE_RESPONSE => {
-                let msg = Response::parse(msg)?;
+                let msg = Response::parse_with(msg, reserved)?;
 
                 // check mac1 field
                 keyst.macs.check_mac1(msg.noise.as_bytes(), &msg.macs)?;
@@ -393,7 +739,7 @@ impl<O> Device<O> {
                 if let Some(src) = src {
                     // check mac2 field
                     if !keyst.macs.check_mac2(msg.noise.as_bytes(), &src, &msg.macs) {
-                        let mut reply = Default::default();
+                        let mut reply = CookieReply::default();
                         keyst.macs.create_cookie_reply(
                             rng,
                             msg.noise.f_sender.get(),
@@ -401,6 +747,7 @@ impl<O> Device<O> {
                             &msg.macs,
                             &mut reply,
                         );
+                        reply.f_type.set(reserved.encode(TYPE_COOKIE_REPLY));
                         return Ok((None, Some(reply.as_bytes().to_owned()), None));
                     }
 
@@ -414,7 +761,7 @@ impl<O> Device<O> {
                 noise::consume_response(self, keyst, &msg.noise)
             }
             TYPE_COOKIE_REPLY => {
-                let msg = CookieReply::parse(msg)?;
+                let msg = CookieReply::parse_with(msg, reserved)?;
 
                 // lookup peer
                 let (peer, _) = self.lookup_id(msg.f_receiver.get())?;
@@ -422,8 +769,28 @@ impl<O> Device<O> {
                 // validate cookie reply
                 peer.macs.lock().process(&msg)?;
 
-                // this prompts no new message and
-                // DOES NOT cryptographically verify the peer
+                // retransmit the initiation (at most once) with the mac2 field set
+                if let State::InitiationSent {
+                    local,
+                    ref mut initiation,
+                    ..
+                } = *peer.state.lock()
+                {
+                    if local == msg.f_receiver.get() {
+                        if let Some(noise) = initiation.take() {
+                            let mut retransmit = Initiation {
+                                noise,
+                                macs: MacsFooter::default(),
+                            };
+                            peer.macs
+                                .lock()
+                                .generate(retransmit.noise.as_bytes(), &mut retransmit.macs);
+                            return Ok((None, Some(retransmit.as_bytes().to_owned()), None));
+                        }
+                    }
+                }
+
+                // this DOES NOT cryptographically verify the peer
                 Ok((None, None, None))
             }
             _ => Err(HandshakeError::InvalidMessageFormat),
@@ -434,9 +801,31 @@ impl<O> Device<O> {
     //
     // Return the peer associated with the public key
     pub(super) fn lookup_pk(&self, pk: &PublicKey) -> Result<&Peer<O>, HandshakeError> {
-        self.pk_map
-            .get(pk.as_bytes())
-            .ok_or(HandshakeError::UnknownPublicKey)
+        // refuse to establish a session with ourselves
+        if let Some(key) = self.keyst.as_ref() {
+            if ct::eq(pk.as_bytes(), key.pk.as_bytes()) {
+                return Err(HandshakeError::SelfConnection);
+            }
+        }
+
+        match self.pk_map.get(pk.as_bytes()) {
+            Some(peer) => Ok(peer),
+            None => self.provision(pk),
+        }
+    }
+
+    // Internal function
+    //
+    // Consult the provisioning callback for an unknown public key,
+    // queuing the peer for admission if accepted.
+    fn provision(&self, pk: &PublicKey) -> Result<&Peer<O>, HandshakeError> {
+        let config = self
+            .provisioner
+            .as_ref()
+            .and_then(|provisioner| provisioner(pk))
+            .ok_or(HandshakeError::UnknownPublicKey)?;
+        self.provisioned.lock().unwrap().push((*pk, config));
+        Err(HandshakeError::PeerProvisioned)
     }
 
     // Internal function
@@ -507,7 +896,7 @@ mod tests {
             // every shared secret is unique
             let mut ss: HashSet<[u8; 32]> = HashSet::new();
             for peer in dev.pk_map.values() {
-                ss.insert(peer.ss);
+                ss.insert(**peer.ss.lock());
             }
             assert_eq!(ss.len(), dev.len());
         }
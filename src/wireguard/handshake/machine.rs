@@ -0,0 +1,177 @@
+/* Sans-IO interface to the Noise_IKpsk2 handshake
+ *
+ * The machine consumes handshake messages as byte slices and produces events:
+ * messages to transmit and key-pairs resulting from completed handshakes.
+ * It performs no IO and has no timers; retransmission and rekeying is left to the caller.
+ *
+ * The machine is exported by the library when built with the sans-io feature.
+ */
+
+use std::net::SocketAddr;
+
+use rand::prelude::{CryptoRng, RngCore};
+
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use super::super::types::KeyPair;
+use super::device::Device;
+use super::types::*;
+
+/// Event produced by the handshake machine
+pub enum Event<'a, O> {
+    /// Message to transmit to the source of the processed message
+    Send(Vec<u8>),
+
+    /// Key-pair established with the peer
+    KeyPair(&'a O, KeyPair),
+}
+
+pub struct HandshakeMachine<O> {
+    device: Device<O>,
+}
+
+impl<O> HandshakeMachine<O> {
+    /// Create a new handshake machine
+    ///
+    /// # Arguments
+    ///
+    /// - `sk`: The static private key of the local party
+    pub fn new(sk: StaticSecret) -> HandshakeMachine<O> {
+        let mut device = Device::new();
+        device.set_sk(Some(sk));
+        HandshakeMachine { device }
+    }
+
+    /// Add a peer
+    ///
+    /// # Arguments
+    ///
+    /// - `pk`: The static public key of the peer
+    /// - `opaque`: Value associated with the peer, returned with established key-pairs
+    /// - `psk`: Optional pre-shared key
+    pub fn add_peer(
+        &mut self,
+        pk: PublicKey,
+        opaque: O,
+        psk: Option<Psk>,
+    ) -> Result<(), ConfigError> {
+        self.device.add(pk, opaque)?;
+        self.device.set_psk(pk, psk)
+    }
+
+    /// Remove a peer
+    ///
+    /// # Returns
+    ///
+    /// The value associated with the peer
+    pub fn remove_peer(&mut self, pk: &PublicKey) -> Result<O, ConfigError> {
+        self.device.remove_peer(pk)
+    }
+
+    /// Create a handshake initiation to the peer
+    ///
+    /// # Returns
+    ///
+    /// The initiation message to transmit
+    pub fn initiate<R: RngCore + CryptoRng>(
+        &self,
+        rng: &mut R,
+        pk: &PublicKey,
+    ) -> Result<Vec<u8>, HandshakeError> {
+        self.device.begin(rng, pk)
+    }
+
+    /// Process a received handshake message
+    ///
+    /// # Arguments
+    ///
+    /// - `msg`: The received message (untrusted input)
+    /// - `src`: The source address of the message, if the caller is under load
+    ///
+    /// # Returns
+    ///
+    /// The events resulting from the message
+    pub fn receive<'a, R: RngCore + CryptoRng>(
+        &'a self,
+        rng: &mut R,
+        msg: &[u8],
+        src: Option<SocketAddr>,
+    ) -> Result<Vec<Event<'a, O>>, HandshakeError> {
+        let (peer, resp, keypair) = self.device.process(rng, msg, src)?;
+        let mut events = Vec::with_capacity(2);
+        if let Some(resp) = resp {
+            events.push(Event::Send(resp));
+        }
+        if let (Some(peer), Some(keypair)) = (peer, keypair) {
+            events.push(Event::KeyPair(peer, keypair));
+        }
+        Ok(events)
+    }
+
+    /// Release the receiver id of a key-pair which is no longer used
+    ///
+    /// # Arguments
+    ///
+    /// - `id`: The local id of the key-pair (`KeyPair::local_id`)
+    pub fn release(&self, id: u32) {
+        self.device.release(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::OsRng;
+
+    #[test]
+    fn test_handshake_machine() {
+        let sk1 = StaticSecret::new(&mut OsRng);
+        let sk2 = StaticSecret::new(&mut OsRng);
+        let pk1 = PublicKey::from(&sk1);
+        let pk2 = PublicKey::from(&sk2);
+
+        let mut m1 = HandshakeMachine::new(sk1);
+        let mut m2 = HandshakeMachine::new(sk2);
+        m1.add_peer(pk2, 2, None).unwrap();
+        m2.add_peer(pk1, 1, None).unwrap();
+
+        // initiator -> responder
+
+        let init = m1.initiate(&mut OsRng, &pk2).unwrap();
+        let mut events = m2.receive(&mut OsRng, &init, None).unwrap().into_iter();
+        let resp = match events.next() {
+            Some(Event::Send(msg)) => msg,
+            _ => panic!("expected response"),
+        };
+        let kp_r = match events.next() {
+            Some(Event::KeyPair(peer, kp)) => {
+                assert_eq!(*peer, 1);
+                kp
+            }
+            _ => panic!("expected key-pair"),
+        };
+
+        // responder -> initiator
+
+        let mut events = m1.receive(&mut OsRng, &resp, None).unwrap().into_iter();
+        let kp_i = match events.next() {
+            Some(Event::KeyPair(peer, kp)) => {
+                assert_eq!(*peer, 2);
+                kp
+            }
+            _ => panic!("expected key-pair"),
+        };
+        assert!(events.next().is_none());
+
+        assert_eq!(kp_i.send, kp_r.recv);
+        assert_eq!(kp_i.recv, kp_r.send);
+
+        // release the key-pairs and remove the peers
+
+        m1.release(kp_i.local_id());
+        m2.release(kp_r.local_id());
+        assert_eq!(m1.remove_peer(&pk2).unwrap(), 2);
+        assert!(m1.remove_peer(&pk2).is_err());
+        assert!(m1.initiate(&mut OsRng, &pk2).is_err());
+    }
+}
@@ -13,8 +13,10 @@ use chacha20poly1305::XChaCha20Poly1305;
 
 // MAC
 use blake2::Blake2s;
-use subtle::ConstantTimeEq;
 
+use clear_on_drop::clear::Clear;
+
+use super::ct;
 use super::messages::{CookieReply, MacsFooter, TYPE_COOKIE_REPLY};
 use super::types::HandshakeError;
 
@@ -26,7 +28,7 @@ const SIZE_SECRET: usize = 32;
 const SIZE_MAC: usize = 16; // blake2s-mac128
 const SIZE_TAG: usize = 16; // xchacha20poly1305 tag
 
-const COOKIE_UPDATE_INTERVAL: Duration = Duration::from_secs(120);
+pub(super) const COOKIE_UPDATE_INTERVAL: Duration = Duration::from_secs(120);
 
 macro_rules! HASH {
     ( $($input:expr),* ) => {{
@@ -85,11 +87,18 @@ struct Cookie {
     birth: Instant,
 }
 
+impl Drop for Cookie {
+    fn drop(&mut self) {
+        self.value.clear();
+    }
+}
+
 pub struct Generator {
     mac1_key: [u8; 32],
     cookie_key: [u8; 32], // xchacha20poly key for opening cookie response
     last_mac1: Option<[u8; 16]>,
     cookie: Option<Cookie>,
+    lifetime: Duration, // lifetime of obtained cookies
 }
 
 fn addr_to_mac_bytes(addr: &SocketAddr) -> Vec<u8> {
@@ -125,9 +134,34 @@ impl Generator {
             cookie_key: HASH!(LABEL_COOKIE, pk.as_bytes()).into(),
             last_mac1: None,
             cookie: None,
+            lifetime: COOKIE_UPDATE_INTERVAL,
         }
     }
 
+    /// Update the lifetime of cookies obtained from the peer
+    ///
+    /// # Arguments
+    ///
+    /// - lifetime: Duration after which a cookie is discarded
+    pub fn set_lifetime(&mut self, lifetime: Duration) {
+        self.lifetime = lifetime;
+    }
+
+    /// Update the public key associated with the generator
+    ///
+    /// Recomputes the cached mac1 and cookie keys,
+    /// any cookie obtained for the previous key is discarded.
+    ///
+    /// # Arguments
+    ///
+    /// - pk: The new public key of the peer
+    pub fn set_pk(&mut self, pk: PublicKey) {
+        self.mac1_key = HASH!(LABEL_MAC1, pk.as_bytes()).into();
+        self.cookie_key = HASH!(LABEL_COOKIE, pk.as_bytes()).into();
+        self.last_mac1 = None;
+        self.cookie = None;
+    }
+
     /// Process a CookieReply message
     ///
     /// # Arguments
@@ -165,7 +199,7 @@ impl Generator {
         macs.f_mac1 = MAC!(&self.mac1_key, inner);
         macs.f_mac2 = match &self.cookie {
             Some(cookie) => {
-                if cookie.birth.elapsed() > COOKIE_UPDATE_INTERVAL {
+                if cookie.birth.elapsed() > self.lifetime {
                     self.cookie = None;
                     [0u8; SIZE_MAC]
                 } else {
@@ -183,10 +217,17 @@ struct Secret {
     birth: Instant,
 }
 
+impl Drop for Secret {
+    fn drop(&mut self) {
+        self.value.clear();
+    }
+}
+
 pub struct Validator {
     mac1_key: [u8; 32],   // mac1 key, derived from device public key
     cookie_key: [u8; 32], // xchacha20poly key for sealing cookie response
     secret: RwLock<Secret>,
+    lifetime: Duration, // rotation interval of the cookie secret
 }
 
 impl Validator {
@@ -198,12 +239,22 @@ impl Validator {
                 value: [0u8; SIZE_SECRET],
                 birth: Instant::now() - Duration::new(86400, 0),
             }),
+            lifetime: COOKIE_UPDATE_INTERVAL,
         }
     }
 
+    /// Update the lifetime of issued cookies (the rotation interval of the secret)
+    ///
+    /// # Arguments
+    ///
+    /// - lifetime: Duration after which the secret is replaced
+    pub fn set_lifetime(&mut self, lifetime: Duration) {
+        self.lifetime = lifetime;
+    }
+
     fn get_tau(&self, src: &[u8]) -> Option<[u8; SIZE_COOKIE]> {
         let secret = self.secret.read();
-        if secret.birth.elapsed() < COOKIE_UPDATE_INTERVAL {
+        if secret.birth.elapsed() < self.lifetime {
             Some(MAC!(&secret.value, src))
         } else {
             None
@@ -214,7 +265,7 @@ impl Validator {
         // check if current value is still valid
         {
             let secret = self.secret.read();
-            if secret.birth.elapsed() < COOKIE_UPDATE_INTERVAL {
+            if secret.birth.elapsed() < self.lifetime {
                 return MAC!(&secret.value, src);
             };
         }
@@ -222,7 +273,7 @@ impl Validator {
         // take write lock, check again
         {
             let mut secret = self.secret.write();
-            if secret.birth.elapsed() < COOKIE_UPDATE_INTERVAL {
+            if secret.birth.elapsed() < self.lifetime {
                 return MAC!(&secret.value, src);
             };
 
@@ -261,7 +312,7 @@ impl Validator {
     /// - inner: The inner message covered by the mac1 field
     /// - macs: The mac footer
     pub fn check_mac1(&self, inner: &[u8], macs: &MacsFooter) -> Result<(), HandshakeError> {
-        let valid_mac1: bool = MAC!(&self.mac1_key, inner).ct_eq(&macs.f_mac1).into();
+        let valid_mac1 = ct::eq(&MAC!(&self.mac1_key, inner), &macs.f_mac1);
         if !valid_mac1 {
             Err(HandshakeError::InvalidMac1)
         } else {
@@ -272,7 +323,7 @@ impl Validator {
     pub fn check_mac2(&self, inner: &[u8], src: &SocketAddr, macs: &MacsFooter) -> bool {
         let src = addr_to_mac_bytes(src);
         match self.get_tau(&src) {
-            Some(tau) => MAC!(&tau, inner, macs.f_mac1).ct_eq(&macs.f_mac2).into(),
+            Some(tau) => ct::eq(&MAC!(&tau, inner, macs.f_mac1), &macs.f_mac2),
             None => false,
         }
     }
@@ -322,4 +373,94 @@ mod tests {
             assert!(validator.check_mac2(&inner2[..], &src, &macs), "mac2 of inner2 did not validate");
         }
     }
+
+    #[test]
+    fn test_generator_set_pk() {
+        let mut macs = MacsFooter::default();
+        let inner = b"inner message";
+        let (validator1, mut generator) = new_validator_generator();
+        let (validator2, _) = new_validator_generator();
+
+        // mac1 is bound to the original public key
+        generator.generate(&inner[..], &mut macs);
+        assert!(validator1.check_mac1(&inner[..], &macs).is_ok());
+        assert!(validator2.check_mac1(&inner[..], &macs).is_err());
+
+        // switch the generator to a new public key
+        let sk = StaticSecret::new(&mut OsRng);
+        let pk = PublicKey::from(&sk);
+        let validator3 = Validator::new(pk);
+        generator.set_pk(pk);
+
+        generator.generate(&inner[..], &mut macs);
+        assert!(validator1.check_mac1(&inner[..], &macs).is_err());
+        assert!(validator3.check_mac1(&inner[..], &macs).is_ok());
+    }
+
+    #[test]
+    fn test_cookie_secret_rotation() {
+        let mut msg = CookieReply::default();
+        let mut macs = MacsFooter::default();
+        let src = "192.0.2.16:8080".parse().unwrap();
+        let inner = b"inner message";
+        let (validator, mut generator) = new_validator_generator();
+
+        // obtain a cookie and generate a valid mac2
+        generator.generate(&inner[..], &mut macs);
+        validator.create_cookie_reply(&mut OsRng, 0, &src, &macs, &mut msg);
+        generator
+            .process(&msg)
+            .expect("failed to process CookieReply");
+        generator.generate(&inner[..], &mut macs);
+        assert!(validator.check_mac2(&inner[..], &src, &macs));
+
+        // age the secret past the update interval (not possible within the uptime of the host)
+        validator.secret.write().birth = match Instant::now().checked_sub(COOKIE_UPDATE_INTERVAL) {
+            Some(birth) => birth,
+            None => return,
+        };
+        assert!(
+            !validator.check_mac2(&inner[..], &src, &macs),
+            "mac2 should not validate with an expired secret"
+        );
+
+        // a new cookie reply must rotate the secret
+        let old = validator.secret.read().value;
+        validator.create_cookie_reply(&mut OsRng, 0, &src, &macs, &mut msg);
+        assert_ne!(old, validator.secret.read().value, "secret was not rotated");
+        assert!(
+            !validator.check_mac2(&inner[..], &src, &macs),
+            "mac2 under the old secret should not validate"
+        );
+    }
+
+    #[test]
+    fn test_cookie_lifetime() {
+        let mut msg = CookieReply::default();
+        let mut macs = MacsFooter::default();
+        let src = "192.0.2.16:8080".parse().unwrap();
+        let inner = b"inner message";
+        let (mut validator, mut generator) = new_validator_generator();
+
+        // obtain a cookie
+        generator.generate(&inner[..], &mut macs);
+        validator.create_cookie_reply(&mut OsRng, 0, &src, &macs, &mut msg);
+        generator
+            .process(&msg)
+            .expect("failed to process CookieReply");
+
+        // the cookie is discarded once older than the lifetime
+        generator.cookie.as_mut().unwrap().birth = Instant::now() - Duration::from_secs(2);
+        generator.set_lifetime(Duration::from_secs(1));
+        generator.generate(&inner[..], &mut macs);
+        assert_eq!(macs.f_mac2, [0u8; SIZE_MAC], "mac2 should not be set");
+
+        // the secret is considered expired once older than the lifetime
+        validator.secret.write().birth = Instant::now() - Duration::from_secs(2);
+        assert!(validator.get_tau(&[]).is_some());
+        validator.set_lifetime(Duration::from_secs(1));
+        assert!(validator.get_tau(&[]).is_none());
+        validator.set_lifetime(Duration::from_secs(3));
+        assert!(validator.get_tau(&[]).is_some());
+    }
 }
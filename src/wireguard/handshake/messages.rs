@@ -4,9 +4,12 @@ use hex;
 #[cfg(test)]
 use std::fmt;
 
+use std::convert::TryFrom;
+
 use std::mem;
 
 use byteorder::LittleEndian;
+use hex::FromHex;
 use zerocopy::byteorder::U32;
 use zerocopy::{AsBytes, ByteSlice, FromBytes, LayoutVerified};
 
@@ -33,6 +36,60 @@ pub const MAX_HANDSHAKE_MSG_SIZE: usize = max(
     mem::size_of::<CookieReply>(),
 );
 
+/* Reserved bytes following the message type */
+
+/// Policy governing how the three reserved bytes following the message type
+/// are written and validated (some obfuscated deployments use non-zero values)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReservedBytes {
+    /// Write zeros, reject messages with non-zero reserved bytes
+    #[default]
+    Strict,
+
+    /// Write zeros, accept messages with any reserved bytes
+    Ignore,
+
+    /// Write the given bytes, reject messages with different reserved bytes
+    Custom([u8; 3]),
+}
+
+impl ReservedBytes {
+    /// Parse a policy: "strict", "ignore" or the custom bytes in hex (e.g. "a1b2c3")
+    pub fn from_name(value: &str) -> Option<ReservedBytes> {
+        match value {
+            "strict" => Some(ReservedBytes::Strict),
+            "ignore" => Some(ReservedBytes::Ignore),
+            _ => <[u8; 3]>::from_hex(value).ok().map(ReservedBytes::Custom),
+        }
+    }
+
+    /// The name of the policy (see `from_name`)
+    pub fn name(self) -> String {
+        match self {
+            ReservedBytes::Strict => "strict".to_owned(),
+            ReservedBytes::Ignore => "ignore".to_owned(),
+            ReservedBytes::Custom(r) => hex::encode(r),
+        }
+    }
+
+    /// Encode the type field of an outbound message
+    pub fn encode(self, ty: u32) -> u32 {
+        match self {
+            ReservedBytes::Strict | ReservedBytes::Ignore => ty,
+            ReservedBytes::Custom(r) => u32::from_le_bytes([ty as u8, r[0], r[1], r[2]]),
+        }
+    }
+
+    /// Check the type field of an inbound message against the expected type
+    pub fn check(self, field: u32, ty: u32) -> bool {
+        match self {
+            ReservedBytes::Strict => field == ty,
+            ReservedBytes::Ignore => field & 0xff == ty,
+            ReservedBytes::Custom(_) => field == self.encode(ty),
+        }
+    }
+}
+
 /* Handshake messsages */
 
 #[repr(packed)]
@@ -91,10 +148,17 @@ pub struct NoiseResponse {
 
 impl Initiation {
     pub fn parse<B: ByteSlice>(bytes: B) -> Result<LayoutVerified<B, Self>, HandshakeError> {
+        Self::parse_with(bytes, ReservedBytes::Strict)
+    }
+
+    pub fn parse_with<B: ByteSlice>(
+        bytes: B,
+        reserved: ReservedBytes,
+    ) -> Result<LayoutVerified<B, Self>, HandshakeError> {
         let msg: LayoutVerified<B, Self> =
             LayoutVerified::new(bytes).ok_or(HandshakeError::InvalidMessageFormat)?;
 
-        if msg.noise.f_type.get() != (TYPE_INITIATION as u32) {
+        if !reserved.check(msg.noise.f_type.get(), TYPE_INITIATION) {
             return Err(HandshakeError::InvalidMessageFormat);
         }
 
@@ -104,10 +168,17 @@ impl Initiation {
 
 impl Response {
     pub fn parse<B: ByteSlice>(bytes: B) -> Result<LayoutVerified<B, Self>, HandshakeError> {
+        Self::parse_with(bytes, ReservedBytes::Strict)
+    }
+
+    pub fn parse_with<B: ByteSlice>(
+        bytes: B,
+        reserved: ReservedBytes,
+    ) -> Result<LayoutVerified<B, Self>, HandshakeError> {
         let msg: LayoutVerified<B, Self> =
             LayoutVerified::new(bytes).ok_or(HandshakeError::InvalidMessageFormat)?;
 
-        if msg.noise.f_type.get() != (TYPE_RESPONSE as u32) {
+        if !reserved.check(msg.noise.f_type.get(), TYPE_RESPONSE) {
             return Err(HandshakeError::InvalidMessageFormat);
         }
 
@@ -117,10 +188,17 @@ impl Response {
 
 impl CookieReply {
     pub fn parse<B: ByteSlice>(bytes: B) -> Result<LayoutVerified<B, Self>, HandshakeError> {
+        Self::parse_with(bytes, ReservedBytes::Strict)
+    }
+
+    pub fn parse_with<B: ByteSlice>(
+        bytes: B,
+        reserved: ReservedBytes,
+    ) -> Result<LayoutVerified<B, Self>, HandshakeError> {
         let msg: LayoutVerified<B, Self> =
             LayoutVerified::new(bytes).ok_or(HandshakeError::InvalidMessageFormat)?;
 
-        if msg.f_type.get() != (TYPE_COOKIE_REPLY as u32) {
+        if !reserved.check(msg.f_type.get(), TYPE_COOKIE_REPLY) {
             return Err(HandshakeError::InvalidMessageFormat);
         }
 
@@ -128,6 +206,46 @@ impl CookieReply {
     }
 }
 
+/* Serialization of handshake messages (for external tools and the packet crafter) */
+
+macro_rules! wire_format {
+    ($type:ident, $($field:ident).+, $expected:expr) => {
+        impl $type {
+            /// Serialize the message to its wire format
+            pub fn to_bytes(self) -> Vec<u8> {
+                self.as_bytes().to_vec()
+            }
+
+            /// Deserialize a message from its wire format,
+            /// validating the length and message type (but not the macs)
+            pub fn from_bytes(bytes: &[u8]) -> Result<Self, HandshakeError> {
+                let msg: LayoutVerified<&[u8], Self> =
+                    LayoutVerified::new(bytes).ok_or(HandshakeError::InvalidMessageFormat)?;
+
+                if msg.$($field).+.get() != $expected {
+                    return Err(HandshakeError::InvalidMessageFormat);
+                }
+
+                Ok(*msg)
+            }
+        }
+
+        impl TryFrom<&[u8]> for $type {
+            type Error = HandshakeError;
+
+            fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+                Self::from_bytes(bytes)
+            }
+        }
+    };
+}
+
+wire_format!(Initiation, noise.f_type, TYPE_INITIATION);
+wire_format!(Response, noise.f_type, TYPE_RESPONSE);
+wire_format!(CookieReply, f_type, TYPE_COOKIE_REPLY);
+wire_format!(NoiseInitiation, f_type, TYPE_INITIATION);
+wire_format!(NoiseResponse, f_type, TYPE_RESPONSE);
+
 /* Default values */
 
 impl Default for Response {
@@ -360,4 +478,82 @@ mod tests {
         let msg_p = Initiation::parse(&buf[..]).unwrap();
         assert_eq!(msg, *msg_p.into_ref());
     }
+
+    #[test]
+    fn message_cookie_reply_identity() {
+        let mut msg: CookieReply = Default::default();
+
+        msg.f_receiver.set(146252);
+        msg.f_nonce = [
+            0x4f, 0x1c, 0x60, 0xec, 0x0e, 0xf6, 0x36, 0xf0, 0x78, 0x28, 0x57, 0x42, 0x60, 0x0e,
+            0x1e, 0x95, 0x41, 0x6b, 0x52, 0x05, 0xa2, 0x09, 0xe1, 0xbf,
+        ];
+        msg.f_cookie = [
+            0xdc, 0x33, 0x90, 0x15, 0x8f, 0x82, 0x3e, 0x06, 0x44, 0xa0, 0xde, 0x4c, 0x15, 0x6c,
+            0x5d, 0xa4, 0x65, 0x99, 0xf6, 0x6c, 0xa1, 0x14, 0x77, 0xf9, 0xeb, 0x6a, 0xec, 0xc3,
+            0x3c, 0xda, 0x47, 0xe1,
+        ];
+
+        let buf: Vec<u8> = msg.as_bytes().to_vec();
+        let msg_p = CookieReply::parse(&buf[..]).unwrap();
+        assert_eq!(msg, *msg_p.into_ref());
+    }
+
+    #[test]
+    fn message_wire_sizes() {
+        // sizes must match the kernel implementation
+        assert_eq!(mem::size_of::<Initiation>(), 148);
+        assert_eq!(mem::size_of::<Response>(), 92);
+        assert_eq!(mem::size_of::<CookieReply>(), 64);
+        assert_eq!(MAX_HANDSHAKE_MSG_SIZE, 148);
+    }
+
+    #[test]
+    fn message_reserved_bytes() {
+        let mut msg: CookieReply = Default::default();
+
+        // strict (default)
+        let buf = msg.to_bytes();
+        assert!(CookieReply::parse_with(&buf[..], ReservedBytes::Strict).is_ok());
+        assert!(CookieReply::parse_with(&buf[..], ReservedBytes::Custom([1, 2, 3])).is_err());
+
+        // custom
+        let custom = ReservedBytes::Custom([1, 2, 3]);
+        msg.f_type.set(custom.encode(TYPE_COOKIE_REPLY));
+        let buf = msg.to_bytes();
+        assert_eq!(&buf[..4], &[TYPE_COOKIE_REPLY as u8, 1, 2, 3]);
+        assert!(CookieReply::parse_with(&buf[..], custom).is_ok());
+        assert!(CookieReply::parse_with(&buf[..], ReservedBytes::Ignore).is_ok());
+        assert!(CookieReply::parse_with(&buf[..], ReservedBytes::Strict).is_err());
+        assert!(CookieReply::parse_with(&buf[..], ReservedBytes::Custom([1, 2, 4])).is_err());
+    }
+
+    #[test]
+    fn message_serialization() {
+        let mut msg: Response = Default::default();
+        msg.noise.f_sender.set(1234);
+        msg.noise.f_receiver.set(5678);
+        msg.macs.f_mac1 = [0x42; SIZE_MAC];
+
+        // round trip
+        let buf = msg.to_bytes();
+        assert_eq!(Response::from_bytes(&buf[..]).unwrap(), msg);
+        assert_eq!(Response::try_from(&buf[..]).unwrap(), msg);
+
+        // inner message
+        let noise = NoiseResponse::from_bytes(&buf[..mem::size_of::<NoiseResponse>()]).unwrap();
+        assert_eq!(noise, msg.noise);
+
+        // invalid length
+        assert!(Response::from_bytes(&buf[1..]).is_err());
+        assert!(NoiseResponse::from_bytes(&buf[..]).is_err());
+
+        // invalid type
+        let mut buf = Initiation::default().to_bytes();
+        assert!(Initiation::try_from(&buf[..]).is_ok());
+        assert!(CookieReply::from_bytes(&buf[..mem::size_of::<CookieReply>()]).is_err());
+        buf[0] = TYPE_RESPONSE as u8;
+        assert!(Initiation::from_bytes(&buf[..]).is_err());
+        assert!(NoiseInitiation::from_bytes(&buf[..mem::size_of::<NoiseInitiation>()]).is_err());
+    }
 }
@@ -6,6 +6,7 @@
  * For documentation.
  */
 
+mod ct;
 mod device;
 mod macs;
 mod messages;
@@ -15,10 +16,34 @@ mod ratelimiter;
 mod timestamp;
 mod types;
 
+pub mod cookie;
+
+#[cfg(any(test, feature = "sans-io"))]
+mod machine;
+
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+
 #[cfg(test)]
 mod tests;
 
 // publicly exposed interface
 
 pub use device::Device;
-pub use messages::{MAX_HANDSHAKE_MSG_SIZE, TYPE_COOKIE_REPLY, TYPE_INITIATION, TYPE_RESPONSE};
+
+#[cfg(feature = "sans-io")]
+pub use machine::{Event, HandshakeMachine};
+
+pub use messages::{
+    CookieReply, Initiation, MacsFooter, NoiseInitiation, NoiseResponse, ReservedBytes, Response,
+    MAX_HANDSHAKE_MSG_SIZE, TYPE_COOKIE_REPLY, TYPE_INITIATION, TYPE_RESPONSE,
+};
+pub use noise::{is_low_order, Protocol};
+pub use timestamp::{restore_counter, save_counter, TAI64N};
+pub use types::{
+    ConfigError, EvictionPolicy, HandshakeConfig, HandshakeError, HandshakeState, PeerConfig,
+    PeerInfo, Provisioner, Psk, SecretKey, StaticDh,
+};
+
+#[cfg(test)]
+pub use types::{Clock, ManualClock};
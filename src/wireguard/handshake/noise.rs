@@ -1,7 +1,7 @@
 use std::time::Instant;
 
 // DH
-use x25519_dalek::{PublicKey, SharedSecret, StaticSecret};
+use x25519_dalek::{PublicKey, StaticSecret};
 
 // HASH & MAC
 use blake2::Blake2s;
@@ -19,15 +19,15 @@ use generic_array::*;
 use clear_on_drop::clear::Clear;
 use clear_on_drop::clear_stack_on_return;
 
-use subtle::ConstantTimeEq;
-
+use super::ct;
 use super::device::{Device, KeyState};
+use super::messages::TYPE_RESPONSE;
 use super::messages::{NoiseInitiation, NoiseResponse};
-use super::messages::{TYPE_INITIATION, TYPE_RESPONSE};
 use super::peer::{Peer, State};
 use super::timestamp;
 use super::types::*;
 
+use super::super::secret::Secret;
 use super::super::types::{Key, KeyPair};
 
 // HMAC hasher (generic construction)
@@ -128,6 +128,57 @@ macro_rules! OPEN {
     };
 }
 
+/// Combine the psk with a hybrid (post-quantum) secret:
+/// HKDF with the psk as salt and the hybrid secret as input key material,
+/// such that the result remains secret as long as either input does.
+///
+/// # Arguments
+///
+/// - `psk`: The pre-shared key of the peer
+/// - `hybrid`: The hybrid secret of the peer
+pub(super) fn hybrid_psk(psk: &Psk, hybrid: &[u8; 32]) -> Psk {
+    let mut okm = KDF1!(&psk[..], &hybrid[..]);
+    let mut out: Psk = [0u8; 32];
+    out.copy_from_slice(&okm);
+    okm.clear();
+    out
+}
+
+/// The initial chaining key and hash transcript,
+/// derived from the construction and identifier strings of the protocol
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Protocol {
+    ck: [u8; SIZE_CK],
+    hs: [u8; SIZE_HS],
+}
+
+impl Default for Protocol {
+    fn default() -> Self {
+        Protocol {
+            ck: INITIAL_CK,
+            hs: INITIAL_HS,
+        }
+    }
+}
+
+impl Protocol {
+    /// Derive the initial state from custom strings,
+    /// resulting in a handshake deliberately incompatible with standard WireGuard
+    ///
+    /// # Arguments
+    ///
+    /// - `construction`: The construction string (C := Hash(Construction))
+    /// - `identifier`: The identifier string (H := Hash(C || Identifier))
+    pub fn new(construction: &[u8], identifier: &[u8]) -> Protocol {
+        let ck = HASH!(construction);
+        let hs = HASH!(&ck, identifier);
+        Protocol {
+            ck: ck.into(),
+            hs: hs.into(),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -149,6 +200,15 @@ mod tests {
         assert_eq!(INITIAL_HS[..], HASH!(INITIAL_CK, IDENTIFIER)[..]);
     }
 
+    /* The standard strings yield the precomputed values
+     */
+    #[test]
+    fn protocol_default() {
+        let protocol = Protocol::new(CONSTRUCTION, IDENTIFIER);
+        assert_eq!(protocol.ck, Protocol::default().ck);
+        assert_eq!(protocol.hs, Protocol::default().hs);
+    }
+
     /* Sanity check the HKDF macro
      *
      * Test vectors generated using WireGuard-Go
@@ -210,46 +270,138 @@ mod tests {
             debug_assert_eq!(tt2[..], t2[..]);
         }
     }
+
+    #[test]
+    fn low_order_points() {
+        let sk = StaticSecret::new(&mut rand::rngs::OsRng);
+        for point in LOW_ORDER_POINTS.iter() {
+            // every shared secret is all-zero (also with the most significant bit set)
+            let mut high = *point;
+            high[31] |= 0x80;
+            for pk in [PublicKey::from(*point), PublicKey::from(high)].iter() {
+                assert!(is_low_order(pk));
+                assert!(ct::is_zero(sk.diffie_hellman(pk).as_bytes()));
+            }
+        }
+        assert!(!is_low_order(&PublicKey::from(&sk)));
+    }
 }
 
 // Computes an X25519 shared secret.
 //
-// This function wraps dalek to add a zero-check.
+// This function wraps the key (dalek or the backend of the static key) to add a zero-check.
 // This is not recommended by the Noise specification,
 // but implemented in the kernel with which we strive for absolute equivalent behavior.
 #[inline(always)]
-fn shared_secret(sk: &StaticSecret, pk: &PublicKey) -> Result<SharedSecret, HandshakeError> {
-    let ss = sk.diffie_hellman(pk);
-    if ss.as_bytes().ct_eq(&[0u8; 32]).into() {
+fn shared_secret<K: StaticDh + ?Sized>(sk: &K, pk: &PublicKey) -> Result<[u8; 32], HandshakeError> {
+    let ss = sk.dh(pk).ok_or(HandshakeError::StaticKeyUnavailable)?;
+    if ct::is_zero(&ss) {
         Err(HandshakeError::InvalidSharedSecret)
     } else {
         Ok(ss)
     }
 }
 
+// Internal function
+//
+// The precomputed DH(S_priv, S_pub) with the peer,
+// computed again if it was unavailable (the backend of the static key failed).
+fn static_shared_secret<O>(
+    keyst: &KeyState,
+    peer: &Peer<O>,
+    pk: &PublicKey,
+) -> Result<[u8; 32], HandshakeError> {
+    let mut ss = peer.ss.lock();
+    if ct::is_zero(&ss[..]) {
+        **ss = shared_secret(keyst.sk.as_ref(), pk)?;
+    }
+    Ok(**ss)
+}
+
+// The u-coordinates of the points of small order (and of their non-canonical encodings modulo p),
+// for which the shared secret is all-zero whatever the private key.
+const LOW_ORDER_POINTS: [[u8; 32]; 7] = [
+    // 0 (order 2)
+    [0u8; 32],
+    // 1 (order 1)
+    [
+        0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00,
+    ],
+    // order 8
+    [
+        0xe0, 0xeb, 0x7a, 0x7c, 0x3b, 0x41, 0xb8, 0xae, 0x16, 0x56, 0xe3, 0xfa, 0xf1, 0x9f, 0xc4,
+        0x6a, 0xda, 0x09, 0x8d, 0xeb, 0x9c, 0x32, 0xb1, 0xfd, 0x86, 0x62, 0x05, 0x16, 0x5f, 0x49,
+        0xb8, 0x00,
+    ],
+    // order 8
+    [
+        0x5f, 0x9c, 0x95, 0xbc, 0xa3, 0x50, 0x8c, 0x24, 0xb1, 0xd0, 0xb1, 0x55, 0x9c, 0x83, 0xef,
+        0x5b, 0x04, 0x44, 0x5c, 0xc4, 0x58, 0x1c, 0x8e, 0x86, 0xd8, 0x22, 0x4e, 0xdd, 0xd0, 0x9f,
+        0x11, 0x57,
+    ],
+    // p - 1 (order 4)
+    [
+        0xec, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0x7f,
+    ],
+    // p (= 0)
+    [
+        0xed, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0x7f,
+    ],
+    // p + 1 (= 1)
+    [
+        0xee, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0x7f,
+    ],
+];
+
+/// Check if a public key is a point of small order (e.g. all-zero),
+/// with which every shared secret is all-zero (and every handshake fails).
+///
+/// # Arguments
+///
+/// - `pk`: The public key (the most significant bit is ignored, as by X25519)
+///
+/// # Returns
+///
+/// True iff the public key is one of the known low-order points.
+pub fn is_low_order(pk: &PublicKey) -> bool {
+    let mut u = *pk.as_bytes();
+    u[31] &= 0x7f;
+    LOW_ORDER_POINTS.iter().any(|point| ct::eq(&u, point))
+}
+
 pub(super) fn create_initiation<R: RngCore + CryptoRng, O>(
     rng: &mut R,
     keyst: &KeyState,
     peer: &Peer<O>,
     pk: &PublicKey,
     local: u32,
+    ts: &timestamp::TAI64N,
+    birth: Instant,
+    protocol: &Protocol,
     msg: &mut NoiseInitiation,
 ) -> Result<(), HandshakeError> {
     log::debug!("create initiation");
 
-    // check for zero shared-secret (see "shared_secret" note).
-    if peer.ss.ct_eq(&[0u8; 32]).into() {
-        return Err(HandshakeError::InvalidSharedSecret);
-    }
-
     clear_stack_on_return(CLEAR_PAGES, || {
+        // check for zero shared-secret (see "shared_secret" note).
+
+        let ss = static_shared_secret(keyst, peer, pk)?;
+
         // initialize state
 
-        let ck = INITIAL_CK;
-        let hs = INITIAL_HS;
+        let ck = protocol.ck;
+        let hs = protocol.hs;
         let hs = HASH!(&hs, pk.as_bytes());
 
-        msg.f_type.set(TYPE_INITIATION as u32);
+        // the type field is set by the caller (see the reserved bytes policy)
         msg.f_sender.set(local); // from us
 
         // (E_priv, E_pub) := DH-Generate()
@@ -271,7 +423,7 @@ pub(super) fn create_initiation<R: RngCore + CryptoRng, O>(
 
         // (C, k) := Kdf2(C, DH(E_priv, S_pub))
 
-        let (ck, key) = KDF2!(&ck, shared_secret(&eph_sk, &pk)?.as_bytes());
+        let (ck, key) = KDF2!(&ck, &shared_secret(&eph_sk, pk)?);
 
         // msg.static := Aead(k, 0, S_pub, H)
 
@@ -288,14 +440,14 @@ pub(super) fn create_initiation<R: RngCore + CryptoRng, O>(
 
         // (C, k) := Kdf2(C, DH(S_priv, S_pub))
 
-        let (ck, key) = KDF2!(&ck, &peer.ss);
+        let (ck, key) = KDF2!(&ck, &ss);
 
         // msg.timestamp := Aead(k, 0, Timestamp(), H)
 
         SEAL!(
             &key,
             &hs,                  // ad
-            &timestamp::now(),    // pt
+            ts,                   // pt
             &mut msg.f_timestamp  // ct || tag
         );
 
@@ -310,6 +462,8 @@ pub(super) fn create_initiation<R: RngCore + CryptoRng, O>(
             ck,
             eph_sk,
             local,
+            birth,
+            initiation: Some(*msg),
         };
 
         Ok(())
@@ -326,8 +480,8 @@ pub(super) fn consume_initiation<'a, O>(
     clear_stack_on_return(CLEAR_PAGES, || {
         // initialize new state
 
-        let ck = INITIAL_CK;
-        let hs = INITIAL_HS;
+        let ck = device.protocol.ck;
+        let hs = device.protocol.hs;
         let hs = HASH!(&hs, keyst.pk.as_bytes());
 
         // C := Kdf(C, E_pub)
@@ -341,7 +495,7 @@ pub(super) fn consume_initiation<'a, O>(
         // (C, k) := Kdf2(C, DH(E_priv, S_pub))
 
         let eph_r_pk = PublicKey::from(msg.f_ephemeral);
-        let (ck, key) = KDF2!(&ck, shared_secret(&keyst.sk, &eph_r_pk)?.as_bytes());
+        let (ck, key) = KDF2!(&ck, &shared_secret(keyst.sk.as_ref(), &eph_r_pk)?);
 
         // msg.static := Aead(k, 0, S_pub, H)
 
@@ -358,9 +512,7 @@ pub(super) fn consume_initiation<'a, O>(
 
         // check for zero shared-secret (see "shared_secret" note).
 
-        if peer.ss.ct_eq(&[0u8; 32]).into() {
-            return Err(HandshakeError::InvalidSharedSecret);
-        }
+        let ss = static_shared_secret(keyst, peer, &PublicKey::from(pk))?;
 
         // reset initiation state
 
@@ -372,7 +524,7 @@ pub(super) fn consume_initiation<'a, O>(
 
         // (C, k) := Kdf2(C, DH(S_priv, S_pub))
 
-        let (ck, key) = KDF2!(&ck, &peer.ss);
+        let (ck, key) = KDF2!(&ck, &ss);
 
         // msg.timestamp := Aead(k, 0, Timestamp(), H)
 
@@ -387,7 +539,7 @@ pub(super) fn consume_initiation<'a, O>(
 
         // check and update timestamp
 
-        peer.check_replay_flood(device, &ts)?;
+        peer.check_replay_flood(device, &ts, &msg.f_ephemeral)?;
 
         // H := Hash(H || msg.timestamp)
 
@@ -405,6 +557,7 @@ pub(super) fn consume_initiation<'a, O>(
 
 pub(super) fn create_response<R: RngCore + CryptoRng, O>(
     rng: &mut R,
+    device: &Device<O>,
     peer: &Peer<O>,
     pk: &PublicKey,
     local: u32,              // sending identifier
@@ -440,15 +593,17 @@ pub(super) fn create_response<R: RngCore + CryptoRng, O>(
 
         // C := Kdf1(C, DH(E_priv, E_pub))
 
-        let ck = KDF1!(&ck, shared_secret(&eph_sk, &eph_r_pk)?.as_bytes());
+        let ck = KDF1!(&ck, &shared_secret(&eph_sk, &eph_r_pk)?);
 
         // C := Kdf1(C, DH(E_priv, S_pub))
 
-        let ck = KDF1!(&ck, shared_secret(&eph_sk, &pk)?.as_bytes());
+        let ck = KDF1!(&ck, &shared_secret(&eph_sk, pk)?);
 
         // (C, tau, k) := Kdf3(C, Q)
 
-        let (ck, tau, key) = KDF3!(&ck, &peer.psk);
+        let mut psk = peer.effective_psk();
+        let (ck, tau, key) = KDF3!(&ck, &psk);
+        psk.clear();
 
         // H := Hash(H || tau)
 
@@ -472,16 +627,18 @@ pub(super) fn create_response<R: RngCore + CryptoRng, O>(
 
         // return unconfirmed key-pair
 
+        let birth = device.now();
+        *peer.last_handshake.lock() = Some(birth);
         Ok(KeyPair {
-            birth: Instant::now(),
+            birth,
             initiator: false,
             send: Key {
                 id: receiver,
-                key: key_send.into(),
+                key: Secret::new(key_send.into()),
             },
             recv: Key {
                 id: local,
-                key: key_recv.into(),
+                key: Secret::new(key_recv.into()),
             },
         })
     })
@@ -507,6 +664,7 @@ pub(super) fn consume_response<'a, O>(
                 ck,
                 local,
                 ref eph_sk,
+                ..
             } => Ok((hs, ck, local, StaticSecret::from(eph_sk.to_bytes()))),
             _ => Err(HandshakeError::InvalidState),
         }?;
@@ -522,15 +680,17 @@ pub(super) fn consume_response<'a, O>(
         // C := Kdf1(C, DH(E_priv, E_pub))
 
         let eph_r_pk = PublicKey::from(msg.f_ephemeral);
-        let ck = KDF1!(&ck, shared_secret(&eph_sk, &eph_r_pk)?.as_bytes());
+        let ck = KDF1!(&ck, &shared_secret(&eph_sk, &eph_r_pk)?);
 
         // C := Kdf1(C, DH(E_priv, S_pub))
 
-        let ck = KDF1!(&ck, shared_secret(&keyst.sk, &eph_r_pk)?.as_bytes());
+        let ck = KDF1!(&ck, &shared_secret(keyst.sk.as_ref(), &eph_r_pk)?);
 
         // (C, tau, k) := Kdf3(C, Q)
 
-        let (ck, tau, key) = KDF3!(&ck, &peer.psk);
+        let mut psk = peer.effective_psk();
+        let (ck, tau, key) = KDF3!(&ck, &psk);
+        psk.clear();
 
         // H := Hash(H || tau)
 
@@ -547,7 +707,7 @@ pub(super) fn consume_response<'a, O>(
 
         // derive key-pair
 
-        let birth = Instant::now();
+        let birth = device.now();
         let (key_send, key_recv) = KDF2!(&ck, &[]);
 
         // check for new initiation sent while lock released
@@ -556,7 +716,7 @@ pub(super) fn consume_response<'a, O>(
         let update = match *state {
             State::InitiationSent {
                 eph_sk: ref old, ..
-            } => old.to_bytes().ct_eq(&eph_sk.to_bytes()).into(),
+            } => ct::eq(&old.to_bytes(), &eph_sk.to_bytes()),
             _ => false,
         };
 
@@ -564,22 +724,23 @@ pub(super) fn consume_response<'a, O>(
             // null the initiation state
             // (to avoid replay of this response message)
             *state = State::Reset;
+            *peer.last_handshake.lock() = Some(birth);
             let remote = msg.f_sender.get();
 
             // return confirmed key-pair
             Ok((
-                Some(&peer.opaque),
+                Some(peer.opaque()),
                 None,
                 Some(KeyPair {
                     birth,
                     initiator: true,
                     send: Key {
                         id: remote,
-                        key: key_send.into(),
+                        key: Secret::new(key_send.into()),
                     },
                     recv: Key {
                         id: local,
-                        key: key_recv.into(),
+                        key: Secret::new(key_recv.into()),
                     },
                 }),
             ))
@@ -11,40 +11,51 @@ use x25519_dalek::StaticSecret;
 
 use clear_on_drop::clear::Clear;
 
+use super::super::secret::Secret;
+use super::ct;
 use super::device::Device;
 use super::macs;
+use super::messages::NoiseInitiation;
+use super::noise;
 use super::timestamp;
 use super::types::*;
 
-const TIME_BETWEEN_INITIATIONS: Duration = Duration::from_millis(20);
-
 // Represents the state of a peer.
 //
 // This type is only for internal use and not exposed.
 pub(super) struct Peer<O> {
-    // opaque type which identifies a peer
-    pub opaque: O,
+    // opaque type which identifies a peer (only taken by `into_opaque`)
+    opaque: Option<O>,
 
     // mutable state
     pub state: Mutex<State>,
     pub timestamp: Mutex<Option<timestamp::TAI64N>>,
+    pub last_ephemeral: Mutex<Option<[u8; 32]>>, // ephemeral of the last consumed initiation
     pub last_initiation_consumption: Mutex<Option<Instant>>,
+    pub last_handshake: Mutex<Option<Instant>>,
+    pub created: Instant,
 
     // state related to DoS mitigation fields
     pub macs: Mutex<macs::Generator>,
 
     // constant state
-    pub ss: [u8; 32], // precomputed DH(static, static)
-    pub psk: Psk,     // psk of peer
+    pub ss: Mutex<Secret>, // precomputed DH(static, static), zero if unavailable
+    pub psk: Secret,       // psk of peer
+
+    // optional post-quantum shared secret (e.g. from a KEM run out-of-band),
+    // mixed into the psk slot of the handshake
+    pub hybrid: Option<Secret>,
 }
 
 pub enum State {
     Reset,
     InitiationSent {
-        local: u32, // local id assigned
+        local: u32,     // local id assigned
+        birth: Instant, // creation of the initiation
         eph_sk: StaticSecret,
         hs: GenericArray<u8, U32>,
         ck: GenericArray<u8, U32>,
+        initiation: Option<NoiseInitiation>, // sent initiation, until retransmitted with a cookie
     },
 }
 
@@ -58,19 +69,92 @@ impl Drop for State {
     }
 }
 
+impl<O> Drop for Peer<O> {
+    fn drop(&mut self) {
+        // clear static-static shared secret, psk and handshake state
+        self.clear_secrets();
+    }
+}
+
 impl<O> Peer<O> {
-    pub fn new(pk: PublicKey, ss: [u8; 32], opaque: O) -> Self {
+    pub fn new(pk: PublicKey, ss: [u8; 32], opaque: O, created: Instant) -> Self {
         Self {
-            opaque,
+            opaque: Some(opaque),
             macs: Mutex::new(macs::Generator::new(pk)),
             state: Mutex::new(State::Reset),
             timestamp: Mutex::new(None),
+            last_ephemeral: Mutex::new(None),
             last_initiation_consumption: Mutex::new(None),
-            ss,
-            psk: [0u8; 32],
+            last_handshake: Mutex::new(None),
+            created,
+            ss: Mutex::new(Secret::new(ss)),
+            psk: Secret::new([0u8; 32]),
+            hybrid: None,
+        }
+    }
+
+    pub fn opaque(&self) -> &O {
+        self.opaque.as_ref().unwrap()
+    }
+
+    /// Clear the secrets of the peer and release the opaque value
+    pub fn into_opaque(mut self) -> O {
+        self.clear_secrets();
+        self.opaque.take().unwrap()
+    }
+
+    /// Return the value used in the psk slot of the handshake
+    ///
+    /// If a hybrid secret is configured, it is combined with the psk (see `noise::hybrid_psk`):
+    /// the resulting session keys are then secure as long as
+    /// either the psk or the hybrid secret remains secret.
+    ///
+    /// # Returns
+    ///
+    /// The effective psk for the peer (a copy, to be cleared by the caller)
+    pub fn effective_psk(&self) -> Psk {
+        match self.hybrid.as_ref() {
+            None => *self.psk,
+            Some(hybrid) => noise::hybrid_psk(&self.psk, hybrid),
+        }
+    }
+
+    /// Return a snapshot of the handshake state of the peer
+    pub fn info(&self) -> PeerInfo {
+        PeerInfo {
+            state: match *self.state.lock() {
+                State::Reset => HandshakeState::Reset,
+                State::InitiationSent { .. } => HandshakeState::InitiationSent,
+            },
+            last_handshake: *self.last_handshake.lock(),
+            has_psk: !ct::is_zero(&self.psk[..]),
         }
     }
 
+    /// Return the time of the last completed handshake,
+    /// or the creation of the peer if no handshake has completed.
+    pub fn last_activity(&self) -> Instant {
+        self.last_handshake.lock().unwrap_or(self.created)
+    }
+
+    /// Clear the static-static shared secret, psk and hybrid secret,
+    /// as well as any in-flight handshake state.
+    ///
+    /// Invoked when the peer is removed from the device or dropped,
+    /// the peer must not be used for handshakes afterwards.
+    ///
+    /// # Returns
+    ///
+    /// The id allocated to an in-flight initiation (if any)
+    pub fn clear_secrets(&mut self) -> Option<u32> {
+        self.ss.lock().clear();
+        self.psk.clear();
+        if let Some(hybrid) = self.hybrid.as_mut() {
+            hybrid.clear();
+        }
+        self.reset_state()
+    }
+
     pub fn reset_state(&self) -> Option<u32> {
         match mem::replace(&mut *self.state.lock(), State::Reset) {
             State::InitiationSent { local, .. } => Some(local),
@@ -78,31 +162,62 @@ impl<O> Peer<O> {
         }
     }
 
+    /// Reset the state of the peer if the initiation in-flight is older than `max_age`
+    ///
+    /// # Returns
+    ///
+    /// The local id of the aborted initiation (to be released)
+    pub fn reset_stale_state(&self, now: Instant, max_age: Duration) -> Option<u32> {
+        let mut state = self.state.lock();
+        match *state {
+            State::InitiationSent { birth, .. }
+                if now.saturating_duration_since(birth) >= max_age =>
+            {
+                match mem::replace(&mut *state, State::Reset) {
+                    State::InitiationSent { local, .. } => Some(local),
+                    State::Reset => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
     /// Set the mutable state of the peer conditioned on the timestamp being newer
     ///
     /// # Arguments
     ///
     /// * st_new - The updated state of the peer
     /// * ts_new - The associated timestamp
+    /// * ephemeral - The ephemeral public key of the initiation
     pub fn check_replay_flood(
         &self,
         device: &Device<O>,
         timestamp_new: &timestamp::TAI64N,
+        ephemeral: &[u8; 32],
     ) -> Result<(), HandshakeError> {
         let mut state = self.state.lock();
         let mut timestamp = self.timestamp.lock();
+        let mut last_ephemeral = self.last_ephemeral.lock();
         let mut last_initiation_consumption = self.last_initiation_consumption.lock();
 
+        // check duplicate: the same initiation may be sent over multiple paths
+        // (e.g. to both an IPv4 and IPv6 endpoint), only the first copy is answered
+        if *last_ephemeral == Some(*ephemeral) {
+            return Err(HandshakeError::DuplicateInitiation);
+        }
+
         // check replay attack
         if let Some(timestamp_old) = *timestamp {
-            if !timestamp::compare(&timestamp_old, &timestamp_new) {
+            let tolerance = device.get_config().timestamp_tolerance;
+            if !timestamp::compare_with_tolerance(&timestamp_old, timestamp_new, tolerance) {
                 return Err(HandshakeError::OldTimestamp);
             }
         };
 
         // check flood attack
         if let Some(last) = *last_initiation_consumption {
-            if last.elapsed() < TIME_BETWEEN_INITIATIONS {
+            let elapsed = device.now().saturating_duration_since(last);
+            if elapsed < device.get_config().initiation_flood_window {
                 return Err(HandshakeError::InitiationFlood);
             }
         }
@@ -113,9 +228,14 @@ impl<O> Peer<O> {
         }
 
         // update replay & flood protection
+        // (retain the newest timestamp, older ones may be accepted within the tolerance)
         *state = State::Reset;
-        *timestamp = Some(*timestamp_new);
-        *last_initiation_consumption = Some(Instant::now());
+        match *timestamp {
+            Some(timestamp_old) if !timestamp::compare(&timestamp_old, timestamp_new) => (),
+            _ => *timestamp = Some(*timestamp_new),
+        }
+        *last_ephemeral = Some(*ephemeral);
+        *last_initiation_consumption = Some(device.now());
         Ok(())
     }
 }
@@ -52,9 +52,9 @@ impl RateLimiter {
                 // update existing entry
                 let mut entry = entry.lock();
 
-                // add tokens earned since last time
-                entry.tokens = MAX_TOKENS
-                    .min(entry.tokens + u64::from(entry.last_time.elapsed().subsec_nanos()));
+                // add tokens earned since last time (including whole seconds)
+                let earned = entry.last_time.elapsed().as_nanos().min(MAX_TOKENS as u128) as u64;
+                entry.tokens = MAX_TOKENS.min(entry.tokens + earned);
                 entry.last_time = Instant::now();
 
                 // subtract cost of packet
@@ -195,4 +195,29 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_ratelimiter_refill_whole_seconds() {
+        let ratelimiter = RateLimiter::new();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+
+        // exhaust the burst
+        for _ in 0..PACKETS_BURSTABLE {
+            assert!(ratelimiter.allow(&ip));
+        }
+        assert!(!ratelimiter.allow(&ip));
+
+        // pretend that more than a second has elapsed
+        if let Some(entry) = ratelimiter.0.table.read().get(&ip) {
+            entry.lock().last_time = Instant::now() - Duration::from_millis(1100);
+        }
+
+        // the full burst should be available again
+        for _ in 0..PACKETS_BURSTABLE {
+            assert!(
+                ratelimiter.allow(&ip),
+                "tokens for whole seconds not earned"
+            );
+        }
+    }
 }
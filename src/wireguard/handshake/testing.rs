@@ -0,0 +1,299 @@
+/* Packet-crafting API for conformance testing of other implementations
+ *
+ * The crafter constructs valid handshake messages from a local identity,
+ * which can then be mutated into malformed or edge-case packets:
+ * bad macs, truncated fields, replayed timestamps, non-zero reserved bytes, etc.
+ *
+ * Crafted packets are intended for test tooling only:
+ * the crafter is exported by the library when built with the testing feature.
+ */
+
+use std::mem;
+use std::net::SocketAddr;
+
+use byteorder::{ByteOrder, LittleEndian};
+use rand::prelude::{CryptoRng, RngCore};
+use zerocopy::{AsBytes, LayoutVerified};
+
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use super::device::Device;
+use super::macs;
+use super::messages::{CookieReply, MacsFooter, NoiseInitiation, NoiseResponse};
+use super::messages::{TYPE_INITIATION, TYPE_RESPONSE};
+use super::timestamp::{self, TAI64N};
+use super::types::*;
+
+/// A crafted handshake packet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet(Vec<u8>);
+
+/// Constructs handshake packets between a local identity and a single remote peer
+pub struct Crafter {
+    device: Device<()>,
+    peer: PublicKey,
+    validator: macs::Validator,
+}
+
+impl Packet {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0[..]
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.0
+    }
+
+    // Internal function
+    //
+    // Return the offset of the mac footer (initiations and responses only)
+    fn macs_offset(&self) -> Option<usize> {
+        match self.0.first().map(|ty| u32::from(*ty)) {
+            Some(TYPE_INITIATION) => Some(mem::size_of::<NoiseInitiation>()),
+            Some(TYPE_RESPONSE) => Some(mem::size_of::<NoiseResponse>()),
+            _ => None,
+        }
+        .filter(|offset| offset + mem::size_of::<MacsFooter>() <= self.0.len())
+    }
+
+    /// Flip a bit of the mac1 field
+    ///
+    /// Panics if the packet is not an (untruncated) initiation or response
+    pub fn corrupt_mac1(self) -> Self {
+        let offset = self.macs_offset().expect("packet does not carry macs");
+        self.flip(offset)
+    }
+
+    /// Flip a bit of the mac2 field
+    ///
+    /// Panics if the packet is not an (untruncated) initiation or response
+    pub fn corrupt_mac2(self) -> Self {
+        let offset = self.macs_offset().expect("packet does not carry macs");
+        self.flip(offset + mem::size_of::<MacsFooter>() / 2)
+    }
+
+    /// Flip the least significant bit of the byte at the offset
+    pub fn flip(mut self, offset: usize) -> Self {
+        self.0[offset] ^= 1;
+        self
+    }
+
+    /// Overwrite the three reserved bytes following the message type
+    pub fn reserved(mut self, reserved: [u8; 3]) -> Self {
+        self.0[1..4].copy_from_slice(&reserved[..]);
+        self
+    }
+
+    /// Truncate the packet to the given length
+    pub fn truncate(mut self, len: usize) -> Self {
+        self.0.truncate(len);
+        self
+    }
+
+    /// Append trailing bytes to the packet
+    pub fn extend(mut self, bytes: &[u8]) -> Self {
+        self.0.extend_from_slice(bytes);
+        self
+    }
+}
+
+impl Crafter {
+    /// Create a new crafter
+    ///
+    /// # Arguments
+    ///
+    /// - `sk`: The static private key of the local identity
+    /// - `peer`: The static public key of the remote peer
+    /// - `psk`: Optional pre-shared key
+    pub fn new(sk: StaticSecret, peer: PublicKey, psk: Option<Psk>) -> Result<Self, ConfigError> {
+        let validator = macs::Validator::new(PublicKey::from(&sk));
+        let mut device = Device::new();
+        device.set_sk(Some(sk));
+        device.add(peer, ())?;
+        device.set_psk(peer, psk)?;
+        Ok(Crafter {
+            device,
+            peer,
+            validator,
+        })
+    }
+
+    /// Craft a valid initiation with the current time as timestamp
+    pub fn initiation<R: RngCore + CryptoRng>(
+        &self,
+        rng: &mut R,
+    ) -> Result<Packet, HandshakeError> {
+        self.initiation_at(rng, &timestamp::now())
+    }
+
+    /// Craft an initiation carrying the given timestamp,
+    /// e.g. to replay an old timestamp or to issue one from the future
+    pub fn initiation_at<R: RngCore + CryptoRng>(
+        &self,
+        rng: &mut R,
+        ts: &TAI64N,
+    ) -> Result<Packet, HandshakeError> {
+        self.device.begin_at(rng, &self.peer, ts).map(Packet)
+    }
+
+    /// Craft a valid response to an initiation from the peer
+    pub fn response<R: RngCore + CryptoRng>(
+        &self,
+        rng: &mut R,
+        initiation: &[u8],
+    ) -> Result<Packet, HandshakeError> {
+        match self.device.process(rng, initiation, None)? {
+            (_, Some(msg), _) => Ok(Packet(msg)),
+            _ => Err(HandshakeError::InvalidMessageFormat),
+        }
+    }
+
+    /// Craft a cookie reply to an initiation or response from the peer
+    ///
+    /// # Arguments
+    ///
+    /// - `msg`: The message to reply to
+    /// - `src`: The source address of the message (the cookie is bound to it)
+    pub fn cookie_reply<R: RngCore + CryptoRng>(
+        &self,
+        rng: &mut R,
+        msg: &[u8],
+        src: &SocketAddr,
+    ) -> Result<Packet, HandshakeError> {
+        let offset = Packet(msg.to_vec())
+            .macs_offset()
+            .ok_or(HandshakeError::InvalidMessageFormat)?;
+        let macs: LayoutVerified<&[u8], MacsFooter> =
+            LayoutVerified::new(&msg[offset..offset + mem::size_of::<MacsFooter>()])
+                .ok_or(HandshakeError::InvalidMessageFormat)?;
+
+        let mut reply = CookieReply::default();
+        self.validator.create_cookie_reply(
+            rng,
+            LittleEndian::read_u32(&msg[4..]),
+            src,
+            &macs,
+            &mut reply,
+        );
+        Ok(Packet(reply.as_bytes().to_owned()))
+    }
+
+    /// Recompute the mac1 field of a modified packet (mac2 is zeroed),
+    /// so that the packet passes mac1 validation at the peer
+    ///
+    /// Panics if the packet is not an (untruncated) initiation or response
+    pub fn remac(&self, mut packet: Packet) -> Packet {
+        let offset = packet.macs_offset().expect("packet does not carry macs");
+        let (inner, footer) = packet.0.split_at_mut(offset);
+        let mut macs = MacsFooter::default();
+        macs::Generator::new(self.peer).generate(inner, &mut macs);
+        macs.f_mac2 = [0u8; 16];
+        footer[..mem::size_of::<MacsFooter>()].copy_from_slice(macs.as_bytes());
+        packet
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::OsRng;
+
+    fn setup() -> (Crafter, Device<usize>) {
+        let sk1 = StaticSecret::new(&mut OsRng);
+        let sk2 = StaticSecret::new(&mut OsRng);
+        let pk1 = PublicKey::from(&sk1);
+        let pk2 = PublicKey::from(&sk2);
+
+        let crafter = Crafter::new(sk1, pk2, None).unwrap();
+        let mut dev = Device::new();
+        dev.set_sk(Some(sk2));
+        dev.add(pk1, 1).unwrap();
+        (crafter, dev)
+    }
+
+    #[test]
+    fn test_crafter_valid() {
+        let (crafter, dev) = setup();
+        let init = crafter.initiation(&mut OsRng).unwrap();
+        let (_, resp, _) = dev.process(&mut OsRng, init.as_bytes(), None).unwrap();
+        crafter
+            .device
+            .process(&mut OsRng, &resp.unwrap(), None)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_crafter_malformed() {
+        let (crafter, dev) = setup();
+        let init = crafter.initiation(&mut OsRng).unwrap();
+
+        match dev.process(&mut OsRng, init.clone().corrupt_mac1().as_bytes(), None) {
+            Err(HandshakeError::InvalidMac1) => (),
+            _ => panic!("expected invalid mac1"),
+        }
+
+        match dev.process(&mut OsRng, init.clone().truncate(100).as_bytes(), None) {
+            Err(HandshakeError::InvalidMessageFormat) => (),
+            _ => panic!("expected invalid format"),
+        }
+
+        // valid macs over non-zero reserved bytes
+        let packet = crafter.remac(init.reserved([1, 2, 3]));
+        match dev.process(&mut OsRng, packet.as_bytes(), None) {
+            Err(HandshakeError::InvalidMessageFormat) => (),
+            _ => panic!("expected invalid format"),
+        }
+    }
+
+    #[test]
+    fn test_crafter_response() {
+        let (crafter, dev) = setup();
+        let pk1 = PublicKey::from(&crafter.device.get_sk().unwrap());
+
+        // mac2 is not covered by mac1 (and only checked under load)
+        let init = dev.begin(&mut OsRng, &pk1).unwrap();
+        let resp = crafter.response(&mut OsRng, &init).unwrap();
+        match dev.process(&mut OsRng, resp.clone().extend(&[0]).as_bytes(), None) {
+            Err(HandshakeError::InvalidMessageFormat) => (),
+            _ => panic!("expected invalid format"),
+        }
+        let (_, _, keypair) = dev
+            .process(&mut OsRng, &resp.corrupt_mac2().into_bytes(), None)
+            .unwrap();
+        assert!(keypair.unwrap().initiator);
+    }
+
+    #[test]
+    fn test_crafter_replay() {
+        let (crafter, dev) = setup();
+        let old = timestamp::now();
+        let new = timestamp::now();
+
+        let init = crafter.initiation_at(&mut OsRng, &new).unwrap();
+        dev.process(&mut OsRng, init.as_bytes(), None).unwrap();
+
+        let init = crafter.initiation_at(&mut OsRng, &old).unwrap();
+        match dev.process(&mut OsRng, init.as_bytes(), None) {
+            Err(HandshakeError::OldTimestamp) => (),
+            _ => panic!("expected old timestamp"),
+        }
+    }
+
+    #[test]
+    fn test_crafter_cookie_reply() {
+        let (crafter, dev) = setup();
+        let src: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        let pk1 = PublicKey::from(&crafter.device.get_sk().unwrap());
+
+        // reply to an initiation from the device
+        let init = dev.begin(&mut OsRng, &pk1).unwrap();
+        let reply = crafter.cookie_reply(&mut OsRng, &init, &src).unwrap();
+        assert_eq!(reply.as_bytes().len(), mem::size_of::<CookieReply>());
+        dev.process(&mut OsRng, reply.as_bytes(), None).unwrap();
+
+        // cookie replies carry no macs
+        assert!(crafter
+            .cookie_reply(&mut OsRng, reply.as_bytes(), &src)
+            .is_err());
+    }
+}
@@ -1,6 +1,7 @@
 use super::*;
 
 use std::net::SocketAddr;
+use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 
@@ -12,7 +13,8 @@ use rand::rngs::OsRng;
 use x25519_dalek::PublicKey;
 use x25519_dalek::StaticSecret;
 
-use super::messages::{Initiation, Response};
+use super::messages::{Initiation, ReservedBytes, Response};
+use super::types::{EvictionPolicy, HandshakeConfig, HandshakeError, HandshakeState, PeerConfig};
 
 fn setup_devices<R: RngCore + CryptoRng, O: Default>(
     rng: &mut R,
@@ -41,8 +43,8 @@ fn setup_devices<R: RngCore + CryptoRng, O: Default>(
     dev1.add(pk2, O::default()).unwrap();
     dev2.add(pk1, O::default()).unwrap();
 
-    dev1.set_psk(pk2, psk).unwrap();
-    dev2.set_psk(pk1, psk).unwrap();
+    dev1.set_psk(pk2, Some(psk)).unwrap();
+    dev2.set_psk(pk1, Some(psk)).unwrap();
 
     (pk1, dev1, pk2, dev2)
 }
@@ -77,18 +79,18 @@ fn handshake_under_load() {
         _ => panic!("unexpected response"),
     };
 
-    // device-1 : processes CookieReply (no response)
+    // 3. device-1 : processes CookieReply, retransmits the initiation with mac2
+    let msg_init = match dev1.process(&mut OsRng, &msg_cookie, Some(src2)).unwrap() {
+        (None, Some(msg), None) => msg,
+        _ => panic!("unexpected response"),
+    };
+
+    // device-1 : retransmits at most once
     match dev1.process(&mut OsRng, &msg_cookie, Some(src2)).unwrap() {
         (None, None, None) => (),
         _ => panic!("unexpected response"),
     }
 
-    // avoid initiation flood detection
-    wait();
-
-    // 3. device-1 : create second initiation
-    let msg_init = dev1.begin(&mut OsRng, &pk2).unwrap();
-
     // 4. device-2 : responds with noise response
     let msg_response = match dev2.process(&mut OsRng, &msg_init, Some(src1)).unwrap() {
         (Some(_), Some(msg), Some(kp)) => {
@@ -194,6 +196,580 @@ fn handshake_no_load() {
         wait();
     }
 
-    dev1.remove(&pk2).unwrap();
-    dev2.remove(&pk1).unwrap();
+    dev1.remove_peer(&pk2).unwrap();
+    dev2.remove_peer(&pk1).unwrap();
+}
+
+#[test]
+fn handshake_hybrid_secret() {
+    let (pk1, mut dev1, pk2, mut dev2): (_, Device<usize>, _, _) = setup_devices(&mut OsRng);
+
+    let mut secret = [0u8; 32];
+    OsRng.fill_bytes(&mut secret[..]);
+
+    // mismatched hybrid secrets: initiator fails to authenticate the response
+
+    dev1.set_hybrid_secret(pk2, Some(secret)).unwrap();
+
+    let msg1 = dev1.begin(&mut OsRng, &pk2).unwrap();
+    let (_, msg2, _) = dev2
+        .process(&mut OsRng, &msg1, None)
+        .expect("failed to process initiation");
+    assert!(
+        dev1.process(&mut OsRng, &msg2.unwrap(), None).is_err(),
+        "response with mismatched hybrid secret should fail"
+    );
+
+    // avoid initiation flood detection
+    wait();
+
+    // matching hybrid secrets: handshake completes
+
+    dev2.set_hybrid_secret(pk1, Some(secret)).unwrap();
+
+    let msg1 = dev1.begin(&mut OsRng, &pk2).unwrap();
+    let (_, msg2, ks_r) = dev2
+        .process(&mut OsRng, &msg1, None)
+        .expect("failed to process initiation");
+    let (_, _, ks_i) = dev1
+        .process(&mut OsRng, &msg2.unwrap(), None)
+        .expect("failed to process response");
+
+    let ks_r = ks_r.unwrap();
+    let ks_i = ks_i.unwrap();
+    assert_eq!(ks_i.send, ks_r.recv, "KeyI.send != KeyR.recv");
+    assert_eq!(ks_i.recv, ks_r.send, "KeyI.recv != KeyR.send");
+}
+
+#[test]
+fn handshake_self_connection() {
+    let (pk1, dev1, pk2, _dev2): (_, Device<usize>, _, _) = setup_devices(&mut OsRng);
+
+    assert!(dev1.lookup_pk(&pk2).is_ok());
+    match dev1.lookup_pk(&pk1) {
+        Err(HandshakeError::SelfConnection) => (),
+        _ => panic!("lookup of device public key should fail"),
+    }
+}
+
+#[test]
+fn handshake_config_flood_window() {
+    let (_pk1, dev1, pk2, mut dev2): (_, Device<usize>, _, _) = setup_devices(&mut OsRng);
+
+    // default configuration: back-to-back initiations are rejected
+
+    let msg1 = dev1.begin(&mut OsRng, &pk2).unwrap();
+    dev2.process(&mut OsRng, &msg1, None).unwrap();
+    let msg1 = dev1.begin(&mut OsRng, &pk2).unwrap();
+    match dev2.process(&mut OsRng, &msg1, None) {
+        Err(HandshakeError::InitiationFlood) => (),
+        _ => panic!("expected initiation flood to be detected"),
+    }
+
+    // disable the flood window
+
+    dev2.set_config(HandshakeConfig {
+        initiation_flood_window: Duration::from_secs(0),
+        ..Default::default()
+    });
+
+    let msg1 = dev1.begin(&mut OsRng, &pk2).unwrap();
+    dev2.process(&mut OsRng, &msg1, None)
+        .expect("initiation should be accepted without a flood window");
+}
+
+#[test]
+fn handshake_duplicate_initiation() {
+    let (_pk1, dev1, pk2, mut dev2): (_, Device<usize>, _, _) = setup_devices(&mut OsRng);
+
+    // accept any initiation which is not an exact duplicate
+
+    dev2.set_config(HandshakeConfig {
+        initiation_flood_window: Duration::from_secs(0),
+        timestamp_tolerance: Duration::from_secs(10),
+        ..Default::default()
+    });
+
+    // the same initiation received over two paths is only answered once
+
+    let msg1 = dev1.begin(&mut OsRng, &pk2).unwrap();
+    let (_, msg2, _) = dev2.process(&mut OsRng, &msg1, None).unwrap();
+    match dev2.process(&mut OsRng, &msg1, None) {
+        Err(HandshakeError::DuplicateInitiation) => (),
+        _ => panic!("expected duplicate initiation to be detected"),
+    }
+
+    // the handshake completes with the keys of the first response
+
+    let (_, _, ks_i) = dev1.process(&mut OsRng, &msg2.unwrap(), None).unwrap();
+    assert!(ks_i.is_some());
+
+    // a new initiation is accepted
+
+    let msg1 = dev1.begin(&mut OsRng, &pk2).unwrap();
+    dev2.process(&mut OsRng, &msg1, None)
+        .expect("new initiation should be accepted");
+}
+
+#[test]
+fn handshake_manual_clock() {
+    let (_pk1, mut dev1, pk2, mut dev2): (_, Device<usize>, _, _) = setup_devices(&mut OsRng);
+
+    let clock = Arc::new(ManualClock::new());
+    dev1.set_clock(clock.clone());
+    dev2.set_clock(clock.clone());
+
+    // the key-pairs are born at the time of the clock
+
+    let msg1 = dev1.begin(&mut OsRng, &pk2).unwrap();
+    let (_, msg2, ks_r) = dev2.process(&mut OsRng, &msg1, None).unwrap();
+    let (_, _, ks_i) = dev1.process(&mut OsRng, &msg2.unwrap(), None).unwrap();
+    assert_eq!(ks_r.unwrap().birth, clock.now());
+    assert_eq!(ks_i.unwrap().birth, clock.now());
+
+    // the flood window only elapses with the clock (regardless of wall time)
+
+    wait();
+    let msg1 = dev1.begin(&mut OsRng, &pk2).unwrap();
+    match dev2.process(&mut OsRng, &msg1, None) {
+        Err(HandshakeError::InitiationFlood) => (),
+        _ => panic!("expected initiation flood to be detected"),
+    }
+
+    clock.advance(dev2.get_config().initiation_flood_window);
+    let msg1 = dev1.begin(&mut OsRng, &pk2).unwrap();
+    dev2.process(&mut OsRng, &msg1, None)
+        .expect("initiation should be accepted after advancing the clock");
+}
+
+#[test]
+fn handshake_sweep() {
+    let (_pk1, mut dev1, pk2, dev2): (_, Device<usize>, _, _) = setup_devices(&mut OsRng);
+
+    let clock = Arc::new(ManualClock::new());
+    dev1.set_clock(clock.clone());
+
+    // fresh initiations are retained
+
+    let msg1 = dev1.begin(&mut OsRng, &pk2).unwrap();
+    let (_, msg2, _) = dev2.process(&mut OsRng, &msg1, None).unwrap();
+    assert_eq!(dev1.sweep(Duration::from_secs(540)), 0);
+
+    // stale initiations are aborted and the response is refused
+
+    clock.advance(Duration::from_secs(540));
+    assert_eq!(dev1.sweep(Duration::from_secs(540)), 1);
+    assert_eq!(dev1.sweep(Duration::from_secs(540)), 0);
+    for (_, info) in dev1.peers() {
+        assert_eq!(info.state, HandshakeState::Reset);
+    }
+    assert!(dev1.process(&mut OsRng, &msg2.unwrap(), None).is_err());
+}
+
+#[test]
+fn handshake_reserved_bytes() {
+    let (_pk1, mut dev1, pk2, mut dev2): (_, Device<usize>, _, _) = setup_devices(&mut OsRng);
+
+    let config = HandshakeConfig {
+        reserved: ReservedBytes::Custom([0x13, 0x37, 0x42]),
+        ..Default::default()
+    };
+    dev1.set_config(config);
+
+    // a strict responder rejects the initiation
+
+    let msg1 = dev1.begin(&mut OsRng, &pk2).unwrap();
+    assert_eq!(&msg1[1..4], &[0x13, 0x37, 0x42]);
+    match dev2.process(&mut OsRng, &msg1, None) {
+        Err(HandshakeError::InvalidMessageFormat) => (),
+        _ => panic!("expected initiation to be rejected"),
+    }
+
+    // both sides using the same policy complete the handshake
+
+    dev2.set_config(config);
+    let (_, msg2, _) = dev2
+        .process(&mut OsRng, &msg1, None)
+        .expect("failed to process initiation");
+    let msg2 = msg2.unwrap();
+    assert_eq!(&msg2[1..4], &[0x13, 0x37, 0x42]);
+    dev1.process(&mut OsRng, &msg2, None)
+        .expect("failed to process response");
+}
+
+#[test]
+fn handshake_custom_protocol() {
+    const CONSTRUCTION: &[u8] = b"Noise_IKpsk2_25519_ChaChaPoly_BLAKE2s";
+    const IDENTIFIER: &[u8] = b"Private network v1";
+
+    let sk1 = StaticSecret::new(&mut OsRng);
+    let pk1 = PublicKey::from(&sk1);
+    let sk2 = StaticSecret::new(&mut OsRng);
+    let pk2 = PublicKey::from(&sk2);
+
+    let mut dev1: Device<usize> = Device::with_protocol(Protocol::new(CONSTRUCTION, IDENTIFIER));
+    let mut dev2: Device<usize> = Device::new();
+    dev1.set_sk(Some(sk1));
+    dev2.set_sk(Some(StaticSecret::from(sk2.to_bytes())));
+    dev1.add(pk2, 0).unwrap();
+    dev2.add(pk1, 0).unwrap();
+
+    // a standard device cannot decrypt the initiation
+
+    let msg1 = dev1.begin(&mut OsRng, &pk2).unwrap();
+    match dev2.process(&mut OsRng, &msg1, None) {
+        Err(HandshakeError::DecryptionFailure) => (),
+        _ => panic!("expected initiation to be rejected"),
+    }
+
+    // devices using the same strings complete the handshake
+
+    let mut dev2: Device<usize> = Device::with_protocol(Protocol::new(CONSTRUCTION, IDENTIFIER));
+    dev2.set_sk(Some(sk2));
+    dev2.add(pk1, 0).unwrap();
+
+    let (_, msg2, ks_r) = dev2
+        .process(&mut OsRng, &msg1, None)
+        .expect("failed to process initiation");
+    let (_, _, ks_i) = dev1
+        .process(&mut OsRng, &msg2.unwrap(), None)
+        .expect("failed to process response");
+    assert_eq!(ks_i.unwrap().send, ks_r.unwrap().recv);
+}
+
+#[test]
+fn handshake_clear_psk() {
+    let (pk1, mut dev1, pk2, mut dev2): (_, Device<usize>, _, _) = setup_devices(&mut OsRng);
+
+    // remove the psk on both sides
+
+    dev1.set_psk(pk2, None).unwrap();
+    dev2.set_psk(pk1, None).unwrap();
+    assert_eq!(dev1.get_psk(&pk2).unwrap(), [0u8; 32]);
+    assert_eq!(dev2.get_psk(&pk1).unwrap(), [0u8; 32]);
+
+    let msg1 = dev1.begin(&mut OsRng, &pk2).unwrap();
+    let (_, msg2, _) = dev2
+        .process(&mut OsRng, &msg1, None)
+        .expect("failed to process initiation");
+    dev1.process(&mut OsRng, &msg2.unwrap(), None)
+        .expect("failed to process response");
+}
+
+#[test]
+fn handshake_remove_peer() {
+    let (pk1, mut dev1, pk2, dev2): (_, Device<usize>, _, _) = setup_devices(&mut OsRng);
+
+    // start a handshake, then remove the peer on the initiator
+
+    let msg1 = dev1.begin(&mut OsRng, &pk2).unwrap();
+    let (_, msg2, _) = dev2
+        .process(&mut OsRng, &msg1, None)
+        .expect("failed to process initiation");
+
+    assert_eq!(dev1.remove_peer(&pk2).unwrap(), usize::default());
+    assert!(dev1.remove_peer(&pk2).is_err(), "peer removed twice");
+    assert!(!dev1.contains_key(&pk2));
+
+    // the receiver id of the aborted initiation has been released
+
+    match dev1.process(&mut OsRng, &msg2.unwrap(), None) {
+        Err(HandshakeError::UnknownReceiverId) => (),
+        _ => panic!("response for removed peer should be rejected"),
+    }
+
+    // the remaining device still knows the initiator
+    assert!(dev2.contains_key(&pk1));
+}
+
+#[test]
+fn handshake_peer_info() {
+    let (pk1, mut dev1, pk2, dev2): (_, Device<usize>, _, _) = setup_devices(&mut OsRng);
+
+    let info = |dev: &Device<usize>, pk: &PublicKey| {
+        dev.peers()
+            .find(|(k, _)| k.as_bytes() == pk.as_bytes())
+            .map(|(_, info)| info)
+            .unwrap()
+    };
+
+    // initial state
+
+    assert_eq!(dev1.peers().count(), 1);
+    let info1 = info(&dev1, &pk2);
+    assert_eq!(info1.state, HandshakeState::Reset);
+    assert!(info1.last_handshake.is_none());
+    assert!(info1.has_psk);
+
+    // initiation sent
+
+    let msg1 = dev1.begin(&mut OsRng, &pk2).unwrap();
+    assert_eq!(info(&dev1, &pk2).state, HandshakeState::InitiationSent);
+
+    // handshake completed on both ends
+
+    let (_, msg2, _) = dev2.process(&mut OsRng, &msg1, None).unwrap();
+    assert!(info(&dev2, &pk1).last_handshake.is_some());
+    dev1.process(&mut OsRng, &msg2.unwrap(), None).unwrap();
+
+    let info1 = info(&dev1, &pk2);
+    assert_eq!(info1.state, HandshakeState::Reset);
+    assert!(info1.last_handshake.is_some());
+
+    // psk presence
+
+    dev1.set_psk(pk2, None).unwrap();
+    assert!(!info(&dev1, &pk2).has_psk);
+}
+
+#[test]
+fn handshake_replace_pk() {
+    let (pk1, mut dev1, pk2, _dev2): (_, Device<usize>, _, _) = setup_devices(&mut OsRng);
+
+    // create a third device which replaces the second
+
+    let sk3 = StaticSecret::new(&mut OsRng);
+    let pk3 = PublicKey::from(&sk3);
+    let mut dev3: Device<usize> = Device::new();
+    dev3.set_sk(Some(sk3));
+    dev3.add(pk1, 0).unwrap();
+    dev3.set_psk(pk1, Some(dev1.get_psk(&pk2).unwrap()))
+        .unwrap();
+
+    // an in-flight handshake is aborted by the replacement
+
+    let psk = dev1.get_psk(&pk2).unwrap();
+    dev1.begin(&mut OsRng, &pk2).unwrap();
+    assert!(
+        dev1.replace_pk(&pk2, pk1).is_err(),
+        "replaced with device key"
+    );
+    dev1.replace_pk(&pk2, pk3).unwrap();
+    assert!(!dev1.contains_key(&pk2));
+    assert_eq!(dev1.get_psk(&pk3).unwrap(), psk, "psk not retained");
+    assert!(dev1.replace_pk(&pk2, pk3).is_err(), "replaced unknown key");
+
+    // handshake with the new key
+
+    let msg1 = dev1.begin(&mut OsRng, &pk3).unwrap();
+    let (_, msg2, ks_r) = dev3
+        .process(&mut OsRng, &msg1, None)
+        .expect("failed to process initiation");
+    let (_, _, ks_i) = dev1
+        .process(&mut OsRng, &msg2.unwrap(), None)
+        .expect("failed to process response");
+    assert_eq!(ks_i.unwrap().send, ks_r.unwrap().recv);
+}
+
+#[test]
+fn handshake_provisioning() {
+    let (pk1, dev1, pk2, mut dev2): (_, Device<usize>, _, _) = setup_devices(&mut OsRng);
+
+    // remove the initiator from the responder
+
+    let psk = dev2.get_psk(&pk1).unwrap();
+    dev2.remove_peer(&pk1).unwrap();
+
+    // without a provisioner the initiator is unknown
+
+    let msg1 = dev1.begin(&mut OsRng, &pk2).unwrap();
+    match dev2.process(&mut OsRng, &msg1, None) {
+        Err(HandshakeError::UnknownPublicKey) => (),
+        _ => panic!("initiation from unknown peer should be rejected"),
+    }
+
+    // admit the initiator using the provisioner
+
+    dev2.set_provisioner(Some(Box::new(move |pk: &PublicKey| {
+        if pk.as_bytes() == pk1.as_bytes() {
+            Some(PeerConfig {
+                opaque: 42,
+                psk: Some(psk),
+            })
+        } else {
+            None
+        }
+    })));
+
+    match dev2.process(&mut OsRng, &msg1, None) {
+        Err(HandshakeError::PeerProvisioned) => (),
+        _ => panic!("initiator should have been admitted"),
+    }
+
+    let (added, evicted) = dev2.admit_provisioned();
+    assert_eq!(added.len(), 1);
+    assert!(evicted.is_empty());
+    assert_eq!(dev2.get(&pk1), Some(&42));
+
+    // process the initiation again
+
+    let (peer, msg2, _) = dev2
+        .process(&mut OsRng, &msg1, None)
+        .expect("failed to process initiation");
+    assert_eq!(peer, Some(&42));
+    dev1.process(&mut OsRng, &msg2.unwrap(), None)
+        .expect("failed to process response");
+}
+
+#[test]
+fn handshake_max_peers() {
+    let (_pk1, mut dev1, pk2, dev2): (_, Device<usize>, _, _) = setup_devices(&mut OsRng);
+
+    let new_pk = || PublicKey::from(&StaticSecret::new(&mut OsRng));
+
+    // reject peers beyond the limit
+
+    dev1.set_config(HandshakeConfig {
+        max_peers: 2,
+        ..Default::default()
+    });
+    let pk3 = new_pk();
+    assert!(dev1.add(pk3, 3).unwrap().is_none());
+    assert!(dev1.add(new_pk(), 4).is_err(), "added peer beyond limit");
+    assert_eq!(dev1.len(), 2);
+
+    // complete a handshake with the peer (pk2)
+
+    let msg1 = dev1.begin(&mut OsRng, &pk2).unwrap();
+    let (_, msg2, _) = dev2.process(&mut OsRng, &msg1, None).unwrap();
+    dev1.process(&mut OsRng, &msg2.unwrap(), None).unwrap();
+
+    // evict the peer with the oldest activity (pk3 never completed a handshake)
+
+    dev1.set_config(HandshakeConfig {
+        max_peers: 2,
+        eviction: EvictionPolicy::OldestHandshake,
+        ..Default::default()
+    });
+    let pk4 = new_pk();
+    match dev1.add(pk4, 4).unwrap() {
+        Some((pk, opaque)) => {
+            assert_eq!(pk.as_bytes(), pk3.as_bytes());
+            assert_eq!(opaque, 3);
+        }
+        None => panic!("no peer evicted"),
+    }
+    assert_eq!(dev1.len(), 2);
+    assert!(dev1.contains_key(&pk2));
+    assert!(dev1.contains_key(&pk4));
+}
+
+/* The opaque identifier is not required to be Copy or Clone,
+ * enabling embedders to associate arbitrary context with a peer.
+ */
+#[test]
+fn handshake_opaque_not_clone() {
+    #[derive(Default, Debug, PartialEq)]
+    struct Context {
+        name: String,
+    }
+
+    let (_pk1, dev1, pk2, dev2): (_, Device<Context>, _, _) = setup_devices(&mut OsRng);
+
+    let msg1 = dev1.begin(&mut OsRng, &pk2).unwrap();
+    let (peer, msg2, _) = dev2.process(&mut OsRng, &msg1, None).unwrap();
+    assert_eq!(peer, Some(&Context::default()));
+    let (peer, _, _) = dev1.process(&mut OsRng, &msg2.unwrap(), None).unwrap();
+    assert_eq!(peer, Some(&Context::default()));
+}
+
+#[test]
+fn handshake_reset() {
+    let (_pk1, dev1, pk2, dev2): (_, Device<usize>, _, _) = setup_devices(&mut OsRng);
+
+    // abort an in-flight initiation
+
+    let msg1 = dev1.begin(&mut OsRng, &pk2).unwrap();
+    let (_, msg2, _) = dev2.process(&mut OsRng, &msg1, None).unwrap();
+    dev1.reset_handshake(&pk2).unwrap();
+
+    // the response no longer matches any handshake
+
+    match dev1.process(&mut OsRng, &msg2.unwrap(), None) {
+        Err(HandshakeError::UnknownReceiverId) => (),
+        _ => panic!("response to aborted initiation should be rejected"),
+    }
+}
+
+/* The static key of a device may be held by an external backend (e.g. a TPM),
+ * which performs the DH operations without exporting the private key.
+ */
+#[test]
+fn handshake_static_dh() {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    struct Token {
+        sk: StaticSecret,
+        ops: Arc<AtomicUsize>,
+        failed: Arc<AtomicBool>,
+    }
+
+    impl StaticDh for Token {
+        fn pubkey(&self) -> PublicKey {
+            PublicKey::from(&self.sk)
+        }
+
+        fn dh(&self, pk: &PublicKey) -> Option<[u8; 32]> {
+            self.ops.fetch_add(1, Ordering::SeqCst);
+            if self.failed.load(Ordering::SeqCst) {
+                None
+            } else {
+                Some(*self.sk.diffie_hellman(pk).as_bytes())
+            }
+        }
+    }
+
+    let (pk1, dev1, pk2, mut dev2): (_, Device<usize>, _, _) = setup_devices(&mut OsRng);
+    let sk2 = dev2.get_sk().unwrap();
+    let ops = Arc::new(AtomicUsize::new(0));
+    let token = Token {
+        sk: sk2.clone(),
+        ops: ops.clone(),
+        failed: Arc::new(AtomicBool::new(false)),
+    };
+    assert!(dev2.set_static_key(Some(Box::new(token))).is_none());
+
+    // the private key is not exported
+    assert!(dev2.get_sk().is_none());
+
+    // the shared secret with the peer is computed by the backend
+    assert_eq!(ops.load(Ordering::SeqCst), 1);
+
+    let msg1 = dev1.begin(&mut OsRng, &pk2).unwrap();
+    let (_, msg2, ks_r) = dev2.process(&mut OsRng, &msg1, None).unwrap();
+    let (_, _, ks_i) = dev1.process(&mut OsRng, &msg2.unwrap(), None).unwrap();
+    assert_eq!(ks_i.unwrap().send, ks_r.unwrap().recv);
+    assert_eq!(ops.load(Ordering::SeqCst), 2);
+
+    // a failing backend rejects the handshake
+    wait();
+    let failed = Arc::new(AtomicBool::new(true));
+    dev2.set_static_key(Some(Box::new(Token {
+        sk: sk2,
+        ops: Arc::new(AtomicUsize::new(0)),
+        failed: failed.clone(),
+    })));
+    let msg1 = dev1.begin(&mut OsRng, &pk2).unwrap();
+    assert!(dev2.process(&mut OsRng, &msg1, None).is_err());
+    assert!(dev2.begin(&mut OsRng, &pk1).is_err());
+
+    // the shared secret with the peer is computed once the backend recovers
+    failed.store(false, Ordering::SeqCst);
+    wait();
+    let msg1 = dev2.begin(&mut OsRng, &pk1).unwrap();
+    let (_, msg2, ks_r) = dev1.process(&mut OsRng, &msg1, None).unwrap();
+    let (_, _, ks_i) = dev2.process(&mut OsRng, &msg2.unwrap(), None).unwrap();
+    assert_eq!(ks_i.unwrap().send, ks_r.unwrap().recv);
+}
+
+#[test]
+fn handshake_low_order() {
+    let (_pk1, mut dev1, pk2, _dev2): (_, Device<usize>, _, _) = setup_devices(&mut OsRng);
+
+    // the all-zero key (and other low-order points) are rejected as peers
+    let zero = PublicKey::from([0u8; 32]);
+    assert!(is_low_order(&zero));
+    assert!(dev1.add(zero, 0).is_err());
+    assert!(dev1.replace_pk(&pk2, zero).is_err());
+    assert!(dev1.lookup_pk(&pk2).is_ok());
 }
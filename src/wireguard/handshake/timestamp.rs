@@ -1,32 +1,169 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+use spin::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 pub type TAI64N = [u8; 12];
 
 const TAI64_EPOCH: u64 = 0x400000000000000a;
 
+// Granularity of the nanosecond field (~16ms, as in wireguard-go)
+// to avoid leaking the precise system time to peers.
+const WHITENER_MASK: u32 = 0x1000000 - 1;
+
+const NANOS_PER_SEC: u32 = 1_000_000_000;
+
 pub const ZERO: TAI64N = [0u8; 12];
 
+// The largest timestamp issued so far (seconds, nanoseconds),
+// ensures that timestamps are strictly increasing even if the system clock steps backwards.
+static LAST: Mutex<(u64, u32)> = Mutex::new((0, 0));
+
+fn encode(secs: u64, nanos: u32) -> TAI64N {
+    let mut res = [0u8; 12];
+    res[..8].copy_from_slice(&secs.to_be_bytes()[..]);
+    res[8..].copy_from_slice(&nanos.to_be_bytes()[..]);
+    res
+}
+
+fn decode(ts: &TAI64N) -> (u64, u32) {
+    let mut secs = [0u8; 8];
+    let mut nanos = [0u8; 4];
+    secs.copy_from_slice(&ts[..8]);
+    nanos.copy_from_slice(&ts[8..]);
+    (u64::from_be_bytes(secs), u32::from_be_bytes(nanos))
+}
+
 pub fn now() -> TAI64N {
     // get system time as duration
     let sysnow = SystemTime::now();
     let delta = sysnow.duration_since(UNIX_EPOCH).unwrap();
 
-    // convert to tai64n
-    let tai64_secs = delta.as_secs() + TAI64_EPOCH;
-    let tai64_nano = delta.subsec_nanos();
+    // convert to (whitened) tai64n
+    let mut secs = delta.as_secs() + TAI64_EPOCH;
+    let mut nanos = delta.subsec_nanos() & !WHITENER_MASK;
+
+    // ensure strictly larger than any previous timestamp
+    let mut last = LAST.lock();
+    if (secs, nanos) <= *last {
+        secs = last.0;
+        nanos = last.1 + WHITENER_MASK + 1;
+        if nanos >= NANOS_PER_SEC {
+            secs += 1;
+            nanos = 0;
+        }
+    }
+    *last = (secs, nanos);
 
     // serialize
-    let mut res = [0u8; 12];
-    res[..8].copy_from_slice(&tai64_secs.to_be_bytes()[..]);
-    res[8..].copy_from_slice(&tai64_nano.to_be_bytes()[..]);
-    res
+    encode(secs, nanos)
+}
+
+/// Return the largest timestamp issued so far,
+/// intended to be persisted and passed to `restore_counter` after a restart.
+pub fn save_counter() -> TAI64N {
+    let last = LAST.lock();
+    encode(last.0, last.1)
+}
+
+/// Restore a previously saved timestamp counter:
+/// every future timestamp will be strictly larger than the restored value,
+/// even if the system clock has been stepped backwards.
+///
+/// # Arguments
+///
+/// - `ts`: A timestamp obtained from `save_counter`
+pub fn restore_counter(ts: &TAI64N) {
+    let ts = decode(ts);
+    let mut last = LAST.lock();
+    if ts > *last {
+        *last = ts;
+    }
 }
 
 pub fn compare(old: &TAI64N, new: &TAI64N) -> bool {
-    for i in 0..12 {
-        if new[i] > old[i] {
-            return true;
+    // big-endian encoding: lexicographic order is numeric order
+    new[..] > old[..]
+}
+
+/// Check if a new timestamp is larger than an old timestamp minus some tolerance
+///
+/// A timestamp equal to `old` is always rejected (it is a replay),
+/// the tolerance only admits timestamps strictly older than `old`.
+///
+/// # Arguments
+///
+/// - `old`: The newest timestamp seen so far
+/// - `new`: The timestamp to check
+/// - `tolerance`: How much older than `old` the new timestamp may be
+pub fn compare_with_tolerance(old: &TAI64N, new: &TAI64N, tolerance: Duration) -> bool {
+    if tolerance == Duration::from_secs(0) || new[..] == old[..] {
+        return compare(old, new);
+    }
+    let nanos = |ts: &TAI64N| {
+        let (secs, nanos) = decode(ts);
+        u128::from(secs) * u128::from(NANOS_PER_SEC) + u128::from(nanos)
+    };
+    nanos(new) + tolerance.as_nanos() > nanos(old)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timestamp_compare() {
+        let old = encode(TAI64_EPOCH + 10, 500);
+        assert!(compare(&old, &encode(TAI64_EPOCH + 10, 501)));
+        assert!(compare(&old, &encode(TAI64_EPOCH + 11, 0)));
+        assert!(!compare(&old, &old));
+        assert!(!compare(&old, &encode(TAI64_EPOCH + 9, 600)));
+        assert!(!compare(&old, &encode(TAI64_EPOCH + 10, 499)));
+    }
+
+    #[test]
+    fn test_timestamp_compare_with_tolerance() {
+        let old = encode(TAI64_EPOCH + 10, 0);
+        let tolerance = Duration::from_millis(500);
+        assert!(compare_with_tolerance(
+            &old,
+            &encode(TAI64_EPOCH + 9, 600_000_000),
+            tolerance
+        ));
+        assert!(!compare_with_tolerance(
+            &old,
+            &encode(TAI64_EPOCH + 9, 400_000_000),
+            tolerance
+        ));
+        assert!(!compare_with_tolerance(&old, &old, Duration::from_secs(0)));
+        assert!(!compare_with_tolerance(&old, &old, tolerance));
+        assert!(compare_with_tolerance(
+            &old,
+            &encode(TAI64_EPOCH + 10, 1),
+            tolerance
+        ));
+    }
+
+    #[test]
+    fn test_timestamp_monotonic() {
+        let mut prev = now();
+        for _ in 0..1000 {
+            let next = now();
+            assert!(compare(&prev, &next), "timestamps not strictly increasing");
+            assert_eq!(decode(&next).1 & WHITENER_MASK, 0, "timestamp not whitened");
+            prev = next;
         }
     }
-    false
+
+    #[test]
+    fn test_timestamp_restore_counter() {
+        // restore a counter slightly ahead of the system clock
+        let (secs, _) = decode(&now());
+        let ahead = encode(secs + 5, 0);
+        restore_counter(&ahead);
+        assert!(compare(&ahead, &now()));
+        assert!(!compare(&save_counter(), &ahead));
+
+        // restoring an older counter has no effect
+        restore_counter(&ZERO);
+        assert!(compare(&ahead, &save_counter()));
+    }
 }
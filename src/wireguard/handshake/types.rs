@@ -1,7 +1,15 @@
+use super::super::secret::Secret;
 use super::super::types::KeyPair;
+use super::macs::COOKIE_UPDATE_INTERVAL;
+use super::messages::ReservedBytes;
+
+use x25519_dalek::{PublicKey, StaticSecret};
 
 use std::error::Error;
 use std::fmt;
+#[cfg(test)]
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 /* Internal types for the noise IKpsk2 implementation */
 
@@ -32,6 +40,217 @@ impl Error for ConfigError {
     }
 }
 
+// handshake configuration
+
+/// Hard limit on the number of peers (bounded by the receiver id space)
+pub const MAX_PEER_PER_DEVICE: usize = 1 << 20;
+
+/// Policy applied when adding a peer to a device at capacity
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Refuse to add the new peer
+    Reject,
+
+    /// Evict the peer with the oldest handshake
+    /// (peers without handshakes are aged from when they were added)
+    OldestHandshake,
+}
+
+/// Tunable thresholds for replay and flood protection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandshakeConfig {
+    /// Minimum time between two consumed initiations from the same peer
+    pub initiation_flood_window: Duration,
+
+    /// How much older than the newest timestamp seen an initiation may be.
+    /// A non-zero value weakens replay protection
+    /// and should only be used to accommodate reordering on high-latency links:
+    /// only an immediate repeat of the last consumed initiation is detected,
+    /// so an older initiation within the tolerance can be replayed repeatedly
+    /// (at most once per `initiation_flood_window`), resetting the handshake state.
+    pub timestamp_tolerance: Duration,
+
+    /// Maximum number of peers (at most `MAX_PEER_PER_DEVICE`)
+    pub max_peers: usize,
+
+    /// What to do when adding a peer beyond `max_peers`
+    pub eviction: EvictionPolicy,
+
+    /// How the reserved bytes of handshake messages are written and validated
+    pub reserved: ReservedBytes,
+
+    /// Lifetime of cookies, both issued (rotation of the cookie secret) and obtained from peers
+    pub cookie_lifetime: Duration,
+}
+
+impl Default for HandshakeConfig {
+    fn default() -> Self {
+        HandshakeConfig {
+            initiation_flood_window: Duration::from_millis(20),
+            timestamp_tolerance: Duration::from_secs(0),
+            max_peers: MAX_PEER_PER_DEVICE,
+            eviction: EvictionPolicy::Reject,
+            reserved: ReservedBytes::Strict,
+            cookie_lifetime: COOKIE_UPDATE_INTERVAL,
+        }
+    }
+}
+
+// dynamic peer provisioning
+
+/// Configuration of a peer admitted by the provisioning callback
+pub struct PeerConfig<O> {
+    pub opaque: O,        // opaque value to associate with the peer
+    pub psk: Option<Psk>, // optional psk of the peer
+}
+
+/// Callback invoked with the static public key of an unknown initiator,
+/// returns the configuration of the peer to admit it or None to reject the initiation.
+pub type Provisioner<O> = Box<dyn Fn(&PublicKey) -> Option<PeerConfig<O>> + Send + Sync>;
+
+// source of time
+
+/// Source of the current time for the handshake state machine:
+/// used for the birth of key-pairs, flood protection and peer activity.
+///
+/// The router and the timers measure the age of key-pairs against the system clock,
+/// hence the clock is only replaced in tests of the handshake device.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// The monotonic system clock (default)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock which only moves when advanced,
+/// allowing tests to exercise expiry logic without sleeping.
+#[cfg(test)]
+#[derive(Debug)]
+pub struct ManualClock {
+    base: Instant,
+    offset: Mutex<Duration>,
+}
+
+#[cfg(test)]
+impl ManualClock {
+    pub fn new() -> ManualClock {
+        ManualClock {
+            base: Instant::now(),
+            offset: Mutex::new(Duration::from_secs(0)),
+        }
+    }
+
+    /// Move the clock forward
+    pub fn advance(&self, duration: Duration) {
+        *self.offset.lock().unwrap() += duration;
+    }
+}
+
+#[cfg(test)]
+impl Default for ManualClock {
+    fn default() -> Self {
+        ManualClock::new()
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.base + *self.offset.lock().unwrap()
+    }
+}
+
+// static key
+
+/// The static private key of the device, performing the Diffie-Hellman operations of the handshake:
+/// allows the key to be held outside of the process (e.g. in a TPM, PKCS#11 token or secure enclave).
+pub trait StaticDh: Send + Sync {
+    /// The static public key of the device
+    fn pubkey(&self) -> PublicKey;
+
+    /// X25519 of the private key with a (static or ephemeral) public key
+    ///
+    /// # Returns
+    ///
+    /// The shared secret, or None if the backend failed (e.g. is unreachable):
+    /// the handshake is then aborted and the operation attempted again by the next handshake.
+    fn dh(&self, pk: &PublicKey) -> Option<[u8; 32]>;
+
+    /// The private key, if it can be exported (e.g. over the UAPI)
+    fn secret(&self) -> Option<StaticSecret> {
+        None
+    }
+}
+
+/// The private key held in memory (default)
+impl StaticDh for StaticSecret {
+    fn pubkey(&self) -> PublicKey {
+        PublicKey::from(self)
+    }
+
+    fn dh(&self, pk: &PublicKey) -> Option<[u8; 32]> {
+        Some(*self.diffie_hellman(pk).as_bytes())
+    }
+
+    fn secret(&self) -> Option<StaticSecret> {
+        Some(self.clone())
+    }
+}
+
+/// The private key held in memory, in the pages reserved for secrets
+/// (locked and excluded from core dumps, see `lock_secrets`)
+pub struct SecretKey {
+    sk: Secret,
+    pk: PublicKey,
+}
+
+impl From<StaticSecret> for SecretKey {
+    fn from(sk: StaticSecret) -> Self {
+        SecretKey {
+            pk: PublicKey::from(&sk),
+            sk: Secret::new(sk.to_bytes()),
+        }
+    }
+}
+
+impl StaticDh for SecretKey {
+    fn pubkey(&self) -> PublicKey {
+        self.pk
+    }
+
+    fn dh(&self, pk: &PublicKey) -> Option<[u8; 32]> {
+        Some(*StaticSecret::from(*self.sk).diffie_hellman(pk).as_bytes())
+    }
+
+    fn secret(&self) -> Option<StaticSecret> {
+        Some(StaticSecret::from(*self.sk))
+    }
+}
+
+// peer state snapshot
+
+/// The state of the handshake with a peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeState {
+    Reset,
+    InitiationSent,
+}
+
+/// Snapshot of the handshake related state of a peer
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    pub state: HandshakeState,           // state of the handshake
+    pub last_handshake: Option<Instant>, // time of the last completed handshake
+    pub has_psk: bool,                   // is a (non-zero) psk configured
+}
+
 // handshake error
 
 #[derive(Debug)]
@@ -46,6 +265,10 @@ pub enum HandshakeError {
     InvalidMac1,
     RateLimited,
     InitiationFlood,
+    SelfConnection,
+    PeerProvisioned,
+    DuplicateInitiation,
+    StaticKeyUnavailable,
 }
 
 impl fmt::Display for HandshakeError {
@@ -65,6 +288,24 @@ impl fmt::Display for HandshakeError {
             HandshakeError::InitiationFlood => {
                 write!(f, "Message was dropped because of initiation flood")
             }
+            HandshakeError::SelfConnection => {
+                write!(f, "Message was sent using the public key of the device")
+            }
+            HandshakeError::PeerProvisioned => {
+                write!(
+                    f,
+                    "Unknown initiator was admitted, message must be processed again"
+                )
+            }
+            HandshakeError::DuplicateInitiation => {
+                write!(
+                    f,
+                    "Initiation was already consumed (received over another path)"
+                )
+            }
+            HandshakeError::StaticKeyUnavailable => {
+                write!(f, "Diffie-Hellman with the static key failed")
+            }
         }
     }
 }
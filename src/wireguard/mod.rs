@@ -8,10 +8,13 @@
  * e.g. every WireGuard peer consists of a handshake and router peer.
  */
 mod constants;
-mod handshake;
+pub mod handshake;
+mod obfuscation;
 mod peer;
 mod queue;
 mod router;
+mod secret;
+mod tcp;
 mod timers;
 mod types;
 mod wireguard;
@@ -21,10 +24,25 @@ mod workers;
 mod tests;
 
 // represents a WireGuard interface
-pub use wireguard::WireGuard;
+pub use constants::MAX_MTU;
+pub use handshake::{
+    is_low_order, EvictionPolicy, HandshakeConfig, HandshakeState, Protocol, ReservedBytes,
+    StaticDh,
+};
+pub use obfuscation::Obfuscator;
+pub use router::{DropPolicy, ReplayFilter, SourcePolicy, MAX_WINDOW_SIZE, MIN_WINDOW_SIZE};
+pub use secret::lock_secrets;
+pub use secret::Secret;
+pub use tcp::Listener as TcpListener;
+pub use tcp::Proxy as TcpProxy;
+pub use types::{base64, generate_preshared_key, generate_private_key, public_key};
+pub use types::{Key, KeyPair};
+pub use wireguard::{
+    DeviceConfig, KeyConfirmedHandler, MessageStats, PeerExpiryHandler, UnknownMessageHandler,
+    WireGuard,
+};
 
 #[cfg(test)]
 use super::platform::dummy;
 
 use super::platform::{tun, udp, Endpoint};
-use types::KeyPair;
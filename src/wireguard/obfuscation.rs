@@ -0,0 +1,128 @@
+/* Obfuscation of the datagrams on the wire
+ *
+ * An obfuscator sits between the router and the bind:
+ * every message (handshake or transport) is wrapped before it is handed to the UDP / TCP writer
+ * and every received datagram is unwrapped before it reaches the type de-multiplexer.
+ * This allows padding, randomized headers or an external pluggable transport
+ * to be applied without changes to the data path.
+ *
+ * No obfuscator is installed by default, in which case the messages are passed through untouched.
+ * Embedding applications install an obfuscator through the C-ABI (see ffi/mod.rs).
+ */
+
+use std::sync::Arc;
+
+use spin::RwLock;
+
+/// Transformation applied to the raw datagrams sent and received by the device
+pub trait Obfuscator: Send + Sync + 'static {
+    /// Transform an outbound message into the datagram sent on the wire
+    ///
+    /// # Arguments
+    ///
+    /// - `msg`: A WireGuard message (handshake or transport)
+    ///
+    /// # Returns
+    ///
+    /// The datagram to send
+    fn wrap(&self, msg: &[u8]) -> Vec<u8>;
+
+    /// Reverse the transformation of a received datagram
+    ///
+    /// # Arguments
+    ///
+    /// - `datagram`: A datagram received from the wire
+    ///
+    /// # Returns
+    ///
+    /// The WireGuard message, or None if the datagram should be dropped
+    fn unwrap(&self, datagram: Vec<u8>) -> Option<Vec<u8>>;
+}
+
+/// The (replaceable) obfuscator of a device, shared with the outbound writer
+#[derive(Clone, Default)]
+pub struct Obfuscation(Arc<RwLock<Option<Arc<dyn Obfuscator>>>>);
+
+impl Obfuscation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&self, obfuscator: Option<Arc<dyn Obfuscator>>) {
+        *self.0.write() = obfuscator;
+    }
+
+    #[cfg(test)]
+    pub fn enabled(&self) -> bool {
+        self.0.read().is_some()
+    }
+
+    /// Wrap a batch of outbound messages
+    ///
+    /// # Returns
+    ///
+    /// The wrapped datagrams, or None if no obfuscator is installed
+    /// (the messages should be sent as they are, avoiding a copy)
+    pub fn wrap(&self, msgs: &[&[u8]]) -> Option<Vec<Vec<u8>>> {
+        let obfuscator = self.0.read().clone()?;
+        Some(msgs.iter().map(|msg| obfuscator.wrap(msg)).collect())
+    }
+
+    /// Unwrap a received datagram
+    ///
+    /// # Returns
+    ///
+    /// The WireGuard message, or None if the obfuscator rejected the datagram
+    pub fn unwrap(&self, datagram: Vec<u8>) -> Option<Vec<u8>> {
+        let obfuscator = self.0.read().clone();
+        match obfuscator {
+            Some(obfuscator) => obfuscator.unwrap(datagram),
+            None => Some(datagram),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // prefixes a marker byte and XORs the payload with a key
+    struct XorHeader(u8);
+
+    impl Obfuscator for XorHeader {
+        fn wrap(&self, msg: &[u8]) -> Vec<u8> {
+            let mut datagram = vec![0xaa];
+            datagram.extend(msg.iter().map(|b| b ^ self.0));
+            datagram
+        }
+
+        fn unwrap(&self, datagram: Vec<u8>) -> Option<Vec<u8>> {
+            if datagram.first() != Some(&0xaa) {
+                return None;
+            }
+            Some(datagram[1..].iter().map(|b| b ^ self.0).collect())
+        }
+    }
+
+    #[test]
+    fn test_obfuscation() {
+        let obf = Obfuscation::new();
+
+        // pass-through by default
+        assert!(!obf.enabled());
+        assert!(obf.wrap(&[&[1, 2, 3]]).is_none());
+        assert_eq!(obf.unwrap(vec![1, 2, 3]), Some(vec![1, 2, 3]));
+
+        // installed on a clone (e.g. the outbound writer)
+        obf.clone().set(Some(Arc::new(XorHeader(0x0f))));
+        assert!(obf.enabled());
+        let wrapped = obf.wrap(&[&[1, 2, 3], &[]]).unwrap();
+        assert_eq!(wrapped, vec![vec![0xaa, 0x0e, 0x0d, 0x0c], vec![0xaa]]);
+        assert_eq!(obf.unwrap(wrapped[0].clone()), Some(vec![1, 2, 3]));
+        assert_eq!(obf.unwrap(vec![0x00, 0x01]), None);
+
+        // removed again
+        obf.set(None);
+        assert!(obf.wrap(&[&[1]]).is_none());
+    }
+}
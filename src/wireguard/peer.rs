@@ -2,12 +2,11 @@ use super::timers::Timers;
 
 use super::tun::Tun;
 use super::udp::UDP;
-
-use super::constants::REKEY_TIMEOUT;
 use super::wireguard::WireGuard;
 use super::workers::HandshakeJob;
 
 use std::fmt;
+use std::net::SocketAddr;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::time::{Instant, SystemTime};
 
@@ -23,7 +22,7 @@ pub struct PeerInner<T: Tun, B: UDP> {
     pub wg: WireGuard<T, B>,
 
     // TODO: eliminate
-    pub pk: PublicKey,
+    pub pk: Mutex<PublicKey>,
 
     // handshake state
     pub walltime_last_handshake: Mutex<Option<SystemTime>>, /* walltime for last handshake (for UAPI status) */
@@ -31,8 +30,18 @@ pub struct PeerInner<T: Tun, B: UDP> {
     pub handshake_queued: AtomicBool,                       // is a handshake job currently queued?
 
     // stats and configuration
-    pub rx_bytes: AtomicU64, // received bytes
-    pub tx_bytes: AtomicU64, // transmitted bytes
+    pub rx_bytes: AtomicU64,            // received bytes
+    pub tx_bytes: AtomicU64,            // transmitted bytes
+    pub expiry: Mutex<Option<Instant>>, // deadline after which the peer is removed
+
+    // hostname of the endpoint ("host:port") and time of the last resolution
+    pub endpoint_name: Mutex<Option<(String, Instant)>>,
+    pub resolve_endpoint: AtomicBool, // re-resolve the hostname (after failed handshakes)
+
+    // ordered candidate endpoints, rotated when handshakes fail to complete
+    pub endpoints: Mutex<Vec<SocketAddr>>,
+    pub preferred_endpoint: Mutex<Option<SocketAddr>>, // endpoint which answered a raced handshake
+    pub tcp_fallback: AtomicBool, // fall back to TCP when handshakes over UDP fail to complete
 
     // timer model
     pub timers: RwLock<Timers>,
@@ -50,7 +59,7 @@ impl<T: Tun, B: UDP> PeerInner<T, B> {
         // the function is rate limited
         {
             let mut lhs = self.last_handshake_sent.lock();
-            if lhs.elapsed() < REKEY_TIMEOUT {
+            if lhs.elapsed() < self.wg.config.rekey_timeout {
                 log::trace!("{} : packet_send_handshake_initiation, rate-limited!", self);
                 return;
             }
@@ -60,7 +69,7 @@ impl<T: Tun, B: UDP> PeerInner<T, B> {
         // create a new handshake job for the peer
         if !self.handshake_queued.swap(true, Ordering::SeqCst) {
             self.wg.pending.fetch_add(1, Ordering::SeqCst);
-            self.wg.queue.send(HandshakeJob::New(self.pk));
+            self.wg.queue.send(HandshakeJob::New(*self.pk.lock()));
             log::trace!(
                 "{} : packet_send_handshake_initiation, handshake queued",
                 self
@@ -73,6 +82,61 @@ impl<T: Tun, B: UDP> PeerInner<T, B> {
         }
     }
 
+    /// Returns the candidate endpoint following the current endpoint
+    ///
+    /// # Arguments
+    ///
+    /// - `current`: The currently active endpoint
+    ///
+    /// # Returns
+    ///
+    /// The next candidate (wrapping around), the first candidate if the current endpoint
+    /// is not a candidate (e.g. after roaming), or None if there is nothing to fail over to.
+    pub fn next_endpoint(&self, current: Option<SocketAddr>) -> Option<SocketAddr> {
+        let endpoints = self.endpoints.lock();
+        if endpoints.len() < 2 {
+            return None;
+        }
+        let next = current
+            .and_then(|current| endpoints.iter().position(|addr| *addr == current))
+            .map(|i| (i + 1) % endpoints.len())
+            .unwrap_or(0);
+        Some(endpoints[next])
+    }
+
+    /// Returns the candidate endpoint to which initiations are additionally sent
+    /// when racing handshakes over IPv4 and IPv6 (happy eyeballs)
+    ///
+    /// # Arguments
+    ///
+    /// - `current`: The currently active endpoint
+    ///
+    /// # Returns
+    ///
+    /// The first candidate of the other address family,
+    /// or None if no such candidate exists or a preferred endpoint has been recorded.
+    pub fn race_endpoint(&self, current: Option<SocketAddr>) -> Option<SocketAddr> {
+        if self.preferred_endpoint.lock().is_some() {
+            return None;
+        }
+        let current = current?;
+        self.endpoints
+            .lock()
+            .iter()
+            .find(|addr| addr.is_ipv4() != current.is_ipv4())
+            .copied()
+    }
+
+    /// Notify the embedder that a new key-pair with the peer has been confirmed
+    pub fn key_confirmed(&self) {
+        // release the locks before calling the handler (which may reconfigure the device)
+        let pk = *self.pk.lock();
+        let handler = self.wg.confirm_handler.read().clone();
+        if let Some(handler) = handler {
+            handler(&pk);
+        }
+    }
+
     #[inline(always)]
     pub fn timers(&self) -> RwLockReadGuard<Timers> {
         self.timers.read()
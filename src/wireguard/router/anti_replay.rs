@@ -17,48 +17,80 @@ const REDUNDANT_BIT_SHIFTS: usize = 5;
 
 const SIZE_OF_WORD: usize = mem::size_of::<Word>() * 8;
 
-const BITMAP_BITLEN: usize = 2048;
-const BITMAP_LEN: usize = BITMAP_BITLEN / SIZE_OF_WORD;
-const BITMAP_INDEX_MASK: u64 = BITMAP_LEN as u64 - 1;
 const BITMAP_LOC_MASK: u64 = (SIZE_OF_WORD - 1) as u64;
-const WINDOW_SIZE: u64 = (BITMAP_BITLEN - SIZE_OF_WORD) as u64;
 
-pub struct AntiReplay {
-    bitmap: [Word; BITMAP_LEN],
+/// Default window size (in packets), compatible with the other implementations
+pub const WINDOW_SIZE: u64 = (2048 - SIZE_OF_WORD) as u64;
+
+/// Smallest configurable window size (in packets)
+pub const MIN_WINDOW_SIZE: u64 = 128;
+
+/// Largest configurable window size (in packets), filling a bitmap of 8192 bits
+pub const MAX_WINDOW_SIZE: u64 = (8192 - SIZE_OF_WORD) as u64;
+
+/// Sliding window filter rejecting duplicate and too old nonce counters,
+/// one filter is attached to every receive key.
+pub struct ReplayFilter {
+    bitmap: Box<[Word]>,
+    index_mask: u64,
+    window: u64,
     last: u64,
 }
 
-impl Default for AntiReplay {
+impl Default for ReplayFilter {
     fn default() -> Self {
-        AntiReplay::new()
+        ReplayFilter::new()
     }
 }
 
-impl AntiReplay {
+impl ReplayFilter {
     pub fn new() -> Self {
+        ReplayFilter::with_window(WINDOW_SIZE)
+    }
+
+    /// Create a filter accepting counters at least `size` behind the largest seen
+    ///
+    /// # Arguments
+    ///
+    /// - size: The requested window size, clamped to [MIN_WINDOW_SIZE, MAX_WINDOW_SIZE]
+    ///   and rounded up to fill a power-of-two bitmap
+    pub fn with_window(size: u64) -> Self {
         debug_assert_eq!(1 << REDUNDANT_BIT_SHIFTS, SIZE_OF_WORD);
-        debug_assert_eq!(BITMAP_BITLEN % SIZE_OF_WORD, 0);
-        AntiReplay {
+        let size = size.clamp(MIN_WINDOW_SIZE, MAX_WINDOW_SIZE);
+        let bitlen = (size as usize + SIZE_OF_WORD).next_power_of_two();
+        let len = bitlen / SIZE_OF_WORD;
+        ReplayFilter {
             last: 0,
-            bitmap: [0; BITMAP_LEN],
+            bitmap: vec![0; len].into_boxed_slice(),
+            index_mask: len as u64 - 1,
+            window: (bitlen - SIZE_OF_WORD) as u64,
         }
     }
 
-    // Returns true if check is passed, i.e., not a replay or too old.
-    //
-    // Unlike RFC 6479, zero is allowed.
-    fn check(&self, seq: u64) -> bool {
+    /// Returns the window size (in packets)
+    pub fn window(&self) -> u64 {
+        self.window
+    }
+
+    /// Checks a sequence number without marking it
+    ///
+    /// # Returns
+    ///
+    /// True if the check is passed, i.e., not a replay or too old.
+    ///
+    /// Unlike RFC 6479, zero is allowed.
+    pub fn check(&self, seq: u64) -> bool {
         // Larger is always good.
         if seq > self.last {
             return true;
         }
 
-        if self.last - seq > WINDOW_SIZE {
+        if self.last - seq > self.window {
             return false;
         }
 
         let bit_location = seq & BITMAP_LOC_MASK;
-        let index = (seq >> REDUNDANT_BIT_SHIFTS) & BITMAP_INDEX_MASK;
+        let index = (seq >> REDUNDANT_BIT_SHIFTS) & self.index_mask;
 
         self.bitmap[index as usize] & (1 << bit_location) == 0
     }
@@ -73,11 +105,13 @@ impl AntiReplay {
             let index_cur = self.last >> REDUNDANT_BIT_SHIFTS;
             let diff = index - index_cur;
 
-            if diff >= BITMAP_LEN as u64 {
-                self.bitmap = [0; BITMAP_LEN];
+            if diff >= self.bitmap.len() as u64 {
+                for word in self.bitmap.iter_mut() {
+                    *word = 0;
+                }
             } else {
                 for i in 0..diff {
-                    let real_index = (index_cur + i + 1) & BITMAP_INDEX_MASK;
+                    let real_index = (index_cur + i + 1) & self.index_mask;
                     self.bitmap[real_index as usize] = 0;
                 }
             }
@@ -85,7 +119,7 @@ impl AntiReplay {
             self.last = seq;
         }
 
-        let index = index & BITMAP_INDEX_MASK;
+        let index = index & self.index_mask;
         let bit_location = seq & BITMAP_LOC_MASK;
         self.bitmap[index as usize] |= 1 << bit_location;
     }
@@ -98,8 +132,8 @@ impl AntiReplay {
     ///
     /// # Returns
     ///
-    /// Ok(()) if sequence number is valid (not marked and not behind the moving window).
-    /// Err if the sequence number is invalid (already marked or "too old").
+    /// True if sequence number is valid (not marked and not behind the moving window).
+    /// False if the sequence number is invalid (already marked or "too old").
     pub fn update(&mut self, seq: u64) -> bool {
         if self.check(seq) {
             self.update_store(seq);
@@ -114,9 +148,63 @@ impl AntiReplay {
 mod tests {
     use super::*;
 
+    #[test]
+    fn replay_filter_reorder() {
+        let mut ar = ReplayFilter::new();
+
+        // out-of-order delivery within the window
+        assert!(ar.update(10));
+        assert!(ar.update(8));
+        assert!(ar.update(9));
+        assert!(ar.update(0));
+
+        // duplicates are rejected, check does not mark
+        assert!(!ar.update(8));
+        assert!(ar.check(7));
+        assert!(ar.check(7));
+        assert!(ar.update(7));
+        assert!(!ar.check(7));
+
+        // the edge of the window
+        assert!(ar.update(10 + WINDOW_SIZE));
+        assert!(!ar.check(9));
+        assert!(ar.update(11));
+        assert!(!ar.update(11));
+    }
+
+    #[test]
+    fn replay_filter_window() {
+        assert_eq!(ReplayFilter::new().window(), WINDOW_SIZE);
+        assert_eq!(
+            ReplayFilter::with_window(0).window(),
+            256 - SIZE_OF_WORD as u64
+        );
+        assert_eq!(ReplayFilter::with_window(1 << 20).window(), MAX_WINDOW_SIZE);
+        assert_eq!(ReplayFilter::with_window(5000).window(), MAX_WINDOW_SIZE);
+
+        for size in [MIN_WINDOW_SIZE, 1000, WINDOW_SIZE, MAX_WINDOW_SIZE] {
+            let mut ar = ReplayFilter::with_window(size);
+            let window = ar.window();
+            assert!(window >= size);
+
+            // accept counters exactly at the edge of the window, not beyond
+            assert!(ar.update(100_000));
+            assert!(ar.update(100_000 - window));
+            assert!(!ar.check(100_000 - window - 1));
+
+            // every counter within the window is accepted once
+            for i in (100_000 - window + 1)..100_000 {
+                assert!(ar.update(i));
+            }
+            for i in (100_000 - window)..=100_000 {
+                assert!(!ar.check(i));
+            }
+        }
+    }
+
     #[test]
     fn anti_replay() {
-        let mut ar = AntiReplay::new();
+        let mut ar = ReplayFilter::new();
 
         for i in 0..20000 {
             assert!(ar.update(i));
@@ -1,9 +1,20 @@
+use std::time::Duration;
+
 // WireGuard semantics constants
 
 pub const MAX_QUEUED_PACKETS: usize = 1024;
 
+// packets staged (per peer) while awaiting a key-pair, the oldest are dropped first
+pub const MAX_STAGED_PACKETS: usize = 128;
+
+// path MTU to an endpoint (learned from ICMP errors) is forgotten after this duration
+pub const PATH_MTU_EXPIRY: Duration = Duration::from_secs(600);
+
 // performance constants
 
 pub const PARALLEL_QUEUE_SIZE: usize = 4 * MAX_QUEUED_PACKETS;
 
 pub const INORDER_QUEUE_SIZE: usize = MAX_QUEUED_PACKETS;
+
+// ready in-order jobs handled together (e.g. transport messages sent in a single system call)
+pub const MAX_SEQUENTIAL_BATCH: usize = 32;
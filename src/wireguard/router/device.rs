@@ -1,24 +1,28 @@
 use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::ops::Deref;
-use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant};
 
 use spin::{Mutex, RwLock};
-use zerocopy::LayoutVerified;
 
-use super::anti_replay::AntiReplay;
+use super::anti_replay::ReplayFilter;
 
-use super::constants::PARALLEL_QUEUE_SIZE;
-use super::messages::{TransportHeader, TYPE_TRANSPORT};
+use super::constants::{PARALLEL_QUEUE_SIZE, PATH_MTU_EXPIRY};
+use super::ip::{inner_length, packet_too_big};
+use super::messages::TransportHeader;
 use super::peer::{new_peer, Peer, PeerHandle};
-use super::types::{Callbacks, RouterError};
+use super::types::{Callbacks, RouterError, SourcePolicy};
 use super::SIZE_MESSAGE_PREFIX;
 
 use super::receive::ReceiveJob;
 use super::route::RoutingTable;
 use super::worker::{worker, JobUnion};
 
+use super::super::constants::REJECT_AFTER_TIME;
+use super::super::handshake::ReservedBytes;
 use super::super::{tun, udp, Endpoint, KeyPair};
 use super::ParallelQueue;
 
@@ -33,10 +37,35 @@ pub struct DeviceInner<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer
     pub(super) recv: RwLock<HashMap<u32, Arc<DecryptionState<E, C, T, B>>>>, /* receiver id -> decryption state */
     pub(super) table: RoutingTable<Peer<E, C, T, B>>,
 
+    // inbound source address checking
+    pub(super) permissive: AtomicBool, // deliver packets violating the cryptokey routing
+    pub(super) source_violations: AtomicU64, // number of packets violating the cryptokey routing
+
+    // size of the replay window for new key-pairs
+    pub(super) replay_window: AtomicU64,
+
+    // path MTU to endpoints (and time of the update)
+    pub(super) path_mtu: RwLock<HashMap<SocketAddr, (usize, Instant)>>,
+
+    // fixed DSCP of outer packets (otherwise copied from the inner packet)
+    pub(super) dscp: RwLock<Option<u8>>,
+
+    // policy for the reserved bytes following the type of transport messages
+    pub(super) reserved: RwLock<ReservedBytes>,
+
     // work queue
     pub(super) work: ParallelQueue<JobUnion<E, C, T, B>>,
 }
 
+impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> DeviceInner<E, C, T, B> {
+    pub(super) fn get_path_mtu(&self, addr: &SocketAddr) -> Option<usize> {
+        match self.path_mtu.read().get(addr) {
+            Some(&(mtu, updated)) if updated.elapsed() < PATH_MTU_EXPIRY => Some(mtu),
+            _ => None,
+        }
+    }
+}
+
 pub struct EncryptionState {
     pub(super) keypair: Arc<KeyPair>, // keypair
     pub(super) nonce: u64,            // next available nonce
@@ -45,7 +74,7 @@ pub struct EncryptionState {
 pub struct DecryptionState<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> {
     pub(super) keypair: Arc<KeyPair>,
     pub(super) confirmed: AtomicBool,
-    pub(super) protector: Mutex<AntiReplay>,
+    pub(super) protector: Mutex<ReplayFilter>,
     pub(super) peer: Peer<E, C, T, B>,
 }
 
@@ -79,7 +108,7 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> Deref for Dev
 }
 
 pub struct DeviceHandle<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> {
-    state: Device<E, C, T, B>,            // reference to device state
+    pub(super) state: Device<E, C, T, B>, // reference to device state
     handles: Vec<thread::JoinHandle<()>>, // join handles for workers
 }
 
@@ -111,6 +140,12 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> DeviceHandle<
                 outbound: RwLock::new((true, None)),
                 recv: RwLock::new(HashMap::new()),
                 table: RoutingTable::new(),
+                permissive: AtomicBool::new(false),
+                source_violations: AtomicU64::new(0),
+                replay_window: AtomicU64::new(ReplayFilter::new().window()),
+                path_mtu: RwLock::new(HashMap::new()),
+                dscp: RwLock::new(None),
+                reserved: RwLock::new(ReservedBytes::Strict),
             }),
         };
 
@@ -156,11 +191,71 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> DeviceHandle<
         self.state.outbound.write().0 = true;
     }
 
-    /// A new secret key has been set for the device.
-    /// According to WireGuard semantics, this should cause all "sending" keys to be discarded.
-    pub fn clear_sending_keys(&self) {
-        log::debug!("Clear sending keys");
-        // TODO: Implement. Consider: The device does not have an explicit list of peers
+    /// Sets the policy for inbound packets with an inner source address
+    /// outside the allowed IPs of the sending peer (strict by default)
+    pub fn set_source_policy(&self, policy: SourcePolicy) {
+        self.state
+            .permissive
+            .store(policy == SourcePolicy::Permissive, Ordering::Relaxed);
+    }
+
+    pub fn get_source_policy(&self) -> SourcePolicy {
+        if self.state.permissive.load(Ordering::Relaxed) {
+            SourcePolicy::Permissive
+        } else {
+            SourcePolicy::Strict
+        }
+    }
+
+    /// Returns the number of authenticated inbound packets
+    /// with an inner source address outside the allowed IPs of the sending peer
+    pub fn source_violations(&self) -> u64 {
+        self.state.source_violations.load(Ordering::Relaxed)
+    }
+
+    /// Sets the size of the anti-replay window (in packets),
+    /// larger windows tolerate more reordering (e.g. on multi-path links).
+    ///
+    /// # Arguments
+    ///
+    /// - size: Window size, clamped to the range supported by `ReplayFilter`
+    ///
+    /// # Note
+    ///
+    /// The window applies to key-pairs added after the call.
+    pub fn set_replay_window(&self, size: u64) {
+        self.state
+            .replay_window
+            .store(ReplayFilter::with_window(size).window(), Ordering::Relaxed);
+    }
+
+    pub fn get_replay_window(&self) -> u64 {
+        self.state.replay_window.load(Ordering::Relaxed)
+    }
+
+    /// Wipe every key-pair older than the given age from the key-wheels of the peers,
+    /// also drops the expired path MTU entries (see `set_path_mtu`)
+    ///
+    /// # Returns
+    ///
+    /// The number of key-pairs wiped
+    pub fn sweep_keys(&self, max_age: Duration) -> usize {
+        let expired: Vec<_> = self
+            .state
+            .recv
+            .read()
+            .values()
+            .filter(|dec| dec.keypair.birth.elapsed() >= max_age)
+            .cloned()
+            .collect();
+        for dec in expired.iter() {
+            dec.peer.expire_keypair(&dec.keypair);
+        }
+        self.state
+            .path_mtu
+            .write()
+            .retain(|_, &mut (_, updated)| updated.elapsed() < PATH_MTU_EXPIRY);
+        expired.len()
     }
 
     /// Adds a new peer to the device
@@ -194,11 +289,68 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> DeviceHandle<
             .get_route(packet)
             .ok_or(RouterError::NoCryptoKeyRoute)?;
 
+        // answer packets exceeding the path MTU to the peer
+        if let Some(mtu) = peer.inner_mtu() {
+            if inner_length(packet).unwrap_or(packet.len()) > mtu {
+                if let Some(icmp) = packet_too_big(packet, mtu) {
+                    log::debug!("send, packet exceeds path MTU (inner MTU = {})", mtu);
+                    let _ = self.state.inbound.write(&icmp[..]);
+                    return Err(RouterError::PacketTooBig);
+                }
+            }
+        }
+
         // schedule for encryption and transmission to peer
         peer.send(msg, true);
         Ok(())
     }
 
+    /// Record the path MTU to an endpoint, e.g. learned from ICMP errors.
+    /// Packets exceeding the path MTU to the endpoint of a peer
+    /// (including the tunnel overhead) are answered with an ICMP
+    /// "fragmentation needed" / "packet too big" message written to the TUN device.
+    ///
+    /// # Arguments
+    ///
+    /// - `addr`: The address of the endpoint
+    /// - `mtu`: The path MTU (outer packets), expires after PATH_MTU_EXPIRY
+    pub fn set_path_mtu(&self, addr: SocketAddr, mtu: usize) {
+        log::debug!("path MTU to {} is {}", addr, mtu);
+        self.state
+            .path_mtu
+            .write()
+            .insert(addr, (mtu, Instant::now()));
+    }
+
+    /// Set a fixed DSCP value for all outer packets,
+    /// by default the DSCP bits of the inner packet are copied to the outer packet.
+    ///
+    /// # Arguments
+    ///
+    /// - `dscp`: The DSCP value (6 bits), or None to copy the DSCP of the inner packets
+    pub fn set_dscp(&self, dscp: Option<u8>) {
+        *self.state.dscp.write() = dscp.map(|dscp| dscp & 0x3f);
+    }
+
+    pub fn get_dscp(&self) -> Option<u8> {
+        *self.state.dscp.read()
+    }
+
+    /// Set how the reserved bytes following the type of transport messages
+    /// are written and validated (see `ReservedBytes`)
+    pub fn set_reserved(&self, reserved: ReservedBytes) {
+        *self.state.reserved.write() = reserved;
+    }
+
+    pub fn get_reserved(&self) -> ReservedBytes {
+        *self.state.reserved.read()
+    }
+
+    /// Returns the (unexpired) path MTU to an endpoint, if known
+    pub fn get_path_mtu(&self, addr: &SocketAddr) -> Option<usize> {
+        self.state.get_path_mtu(addr)
+    }
+
     /// Receive an encrypted transport message
     ///
     /// # Arguments
@@ -211,19 +363,8 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> DeviceHandle<
         log::trace!("receive, src: {}", src.into_address());
 
         // parse / cast
-        let (header, _) = match LayoutVerified::new_from_prefix(&msg[..]) {
-            Some(v) => v,
-            None => {
-                return Err(RouterError::MalformedTransportMessage);
-            }
-        };
-
-        let header: LayoutVerified<&[u8], TransportHeader> = header;
-
-        debug_assert!(
-            header.f_type.get() == TYPE_TRANSPORT as u32,
-            "this should be checked by the message type multiplexer"
-        );
+        let reserved = *self.state.reserved.read();
+        let (header, _) = TransportHeader::parse(&msg[..], reserved)?;
 
         log::trace!(
             "handle transport message: (receiver = {}, counter = {})",
@@ -232,11 +373,21 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> DeviceHandle<
         );
 
         // lookup peer based on receiver id
-        let dec = self.state.recv.read();
-        let dec = dec
+        let dec = self
+            .state
+            .recv
+            .read()
             .get(&header.f_receiver.get())
+            .cloned()
             .ok_or(RouterError::UnknownReceiverId)?;
 
+        // refuse (and wipe) keys past their lifetime
+        if dec.keypair.birth.elapsed() >= REJECT_AFTER_TIME {
+            log::debug!("decryption key expired");
+            dec.peer.expire_keypair(&dec.keypair);
+            return Err(RouterError::KeyExpired);
+        }
+
         // create inbound job
         let job = ReceiveJob::new(msg, dec.clone(), src);
 
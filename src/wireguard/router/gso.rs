@@ -0,0 +1,483 @@
+/* Segmentation offload for TUN devices exchanging TCP super-packets (see tun::Offload):
+ *
+ * - split: a super-packet read from the TUN device is segmented into packets
+ *   of at most gso_size bytes of payload (completing the checksums),
+ *   which are then routed and encrypted individually.
+ *
+ * - coalesce: consecutive in-order TCP segments of a flow, decrypted in the same batch,
+ *   are merged into a super-packet which is written to the TUN device in a single system call
+ *   (the kernel completes the checksum of every segment).
+ */
+
+use super::super::tun::{Gso, Offload};
+use super::ip::{checksum, fold, VERSION_IP4, VERSION_IP6};
+
+const PROTO_TCP: u8 = 6;
+const PROTO_UDP: u8 = 17;
+
+const SIZE_IP4_HEADER: usize = 20;
+const SIZE_IP6_HEADER: usize = 40;
+const SIZE_TCP_HEADER: usize = 20;
+
+const TCP_CSUM_OFFSET: usize = 16;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+const TCP_CWR: u8 = 0x80;
+
+// largest super-packet (limited by the IPv4 total length)
+const MAX_SUPER_PACKET: usize = 0xffff;
+
+/// A packet to be written to the TUN device
+pub enum Coalesced<'a> {
+    Packet(&'a [u8]),        // single packet (written unmodified)
+    Super(Vec<u8>, Offload), // super-packet of several segments
+}
+
+// Internal function
+//
+// Returns the length of the IP header and of the IP and TCP headers of a TCP packet
+// (IPv4 fragments and IPv6 extension headers are not supported)
+fn parse_tcp(packet: &[u8]) -> Option<(usize, usize)> {
+    let ip_len = match packet.first()? >> 4 {
+        VERSION_IP4 => {
+            let ihl = usize::from(packet[0] & 0xf) * 4;
+            if ihl < SIZE_IP4_HEADER || packet.len() < ihl || packet[9] != PROTO_TCP {
+                return None;
+            }
+            if u16::from_be_bytes([packet[6], packet[7]]) & 0x3fff != 0 {
+                return None;
+            }
+            ihl
+        }
+        VERSION_IP6 => {
+            if packet.len() < SIZE_IP6_HEADER || packet[6] != PROTO_TCP {
+                return None;
+            }
+            SIZE_IP6_HEADER
+        }
+        _ => return None,
+    };
+    let tcp_len = usize::from(packet.get(ip_len + 12)? >> 4) * 4;
+    if tcp_len < SIZE_TCP_HEADER || packet.len() < ip_len + tcp_len {
+        return None;
+    }
+    Some((ip_len, ip_len + tcp_len))
+}
+
+// Internal function
+//
+// Partial checksum of the pseudo-header of a transport segment of the given length
+fn pseudo_header(packet: &[u8], proto: u8, len: usize) -> u32 {
+    if packet[0] >> 4 == VERSION_IP4 {
+        let sum = checksum(0, &packet[12..20]);
+        let sum = checksum(sum, &[0, proto]);
+        checksum(sum, &(len as u16).to_be_bytes())
+    } else {
+        let sum = checksum(0, &packet[8..40]);
+        let sum = checksum(sum, &(len as u32).to_be_bytes());
+        checksum(sum, &[0, 0, 0, proto])
+    }
+}
+
+// Internal function
+//
+// Complete the checksum of a packet of which the checksum field
+// holds the pseudo-header sum (checksum offloading)
+fn complete_checksum(packet: &mut [u8], start: usize, offset: usize) -> Option<()> {
+    let field = start + offset;
+    if field + 2 > packet.len() {
+        return None;
+    }
+    let mut sum = fold(checksum(0, &packet[start..]));
+
+    // zero indicates a missing UDP checksum
+    let proto = match packet[0] >> 4 {
+        VERSION_IP4 => packet.get(9),
+        _ => packet.get(6),
+    };
+    if sum == 0 && proto == Some(&PROTO_UDP) {
+        sum = 0xffff;
+    }
+    packet[field..field + 2].copy_from_slice(&sum.to_be_bytes());
+    Some(())
+}
+
+/// Split a packet read from the TUN device into IP packets
+///
+/// # Arguments
+///
+/// - `packet`: The (super-)packet
+/// - `offload`: The offload metadata of the packet
+/// - `headroom`: Space reserved before every IP packet (for the transport header)
+///
+/// # Returns
+///
+/// The packets (prefixed by headroom bytes) or None if the packet is malformed
+pub fn split(packet: &[u8], offload: &Offload, headroom: usize) -> Option<Vec<Vec<u8>>> {
+    // no segmentation, at most completing the checksum
+    if offload.gso == Gso::None {
+        let mut msg = vec![0u8; headroom + packet.len()];
+        msg[headroom..].copy_from_slice(packet);
+        if offload.needs_csum {
+            complete_checksum(
+                &mut msg[headroom..],
+                offload.csum_start as usize,
+                offload.csum_offset as usize,
+            )?;
+        }
+        return Some(vec![msg]);
+    }
+
+    // sanity check the super-packet
+    let (ip_len, hdr_len) = parse_tcp(packet)?;
+    let version = match offload.gso {
+        Gso::TcpV4 => VERSION_IP4,
+        _ => VERSION_IP6,
+    };
+    let mss = offload.gso_size as usize;
+    let payload = &packet[hdr_len..];
+    if packet[0] >> 4 != version || mss == 0 || payload.is_empty() {
+        return None;
+    }
+
+    let id = u16::from_be_bytes([packet[4], packet[5]]);
+    let seq = u32::from_be_bytes([
+        packet[ip_len + 4],
+        packet[ip_len + 5],
+        packet[ip_len + 6],
+        packet[ip_len + 7],
+    ]);
+    let flags = packet[ip_len + 13];
+    let count = payload.len().div_ceil(mss);
+
+    let mut msgs = Vec::with_capacity(count);
+    for (i, chunk) in payload.chunks(mss).enumerate() {
+        let len = hdr_len + chunk.len();
+        let mut msg = vec![0u8; headroom + len];
+        let segment = &mut msg[headroom..];
+        segment[..hdr_len].copy_from_slice(&packet[..hdr_len]);
+        segment[hdr_len..].copy_from_slice(chunk);
+
+        // IP header: length (and identification) of the segment
+        if version == VERSION_IP4 {
+            segment[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+            segment[4..6].copy_from_slice(&id.wrapping_add(i as u16).to_be_bytes());
+            segment[10..12].copy_from_slice(&[0, 0]);
+            let sum = fold(checksum(0, &segment[..ip_len]));
+            segment[10..12].copy_from_slice(&sum.to_be_bytes());
+        } else {
+            segment[4..6].copy_from_slice(&((len - SIZE_IP6_HEADER) as u16).to_be_bytes());
+        }
+
+        // TCP header: sequence number and flags (FIN / PSH on the last, CWR on the first segment)
+        let tcp = &mut segment[ip_len..];
+        tcp[4..8].copy_from_slice(&seq.wrapping_add((i * mss) as u32).to_be_bytes());
+        let mut segment_flags = flags;
+        if i + 1 < count {
+            segment_flags &= !(TCP_FIN | TCP_PSH);
+        }
+        if i > 0 {
+            segment_flags &= !TCP_CWR;
+        }
+        tcp[13] = segment_flags;
+        tcp[TCP_CSUM_OFFSET..TCP_CSUM_OFFSET + 2].copy_from_slice(&[0, 0]);
+        let sum = pseudo_header(segment, PROTO_TCP, len - ip_len);
+        let sum = fold(checksum(sum, &segment[ip_len..]));
+        segment[ip_len + TCP_CSUM_OFFSET..ip_len + TCP_CSUM_OFFSET + 2]
+            .copy_from_slice(&sum.to_be_bytes());
+        msgs.push(msg);
+    }
+    Some(msgs)
+}
+
+// Internal function
+//
+// Returns the headers of a TCP segment which may be part of a super-packet:
+// carrying payload, without IPv4 options and with a valid checksum
+// (the kernel recomputes the checksum of coalesced segments).
+fn segment_headers(packet: &[u8]) -> Option<(usize, usize)> {
+    let (ip_len, hdr_len) = parse_tcp(packet)?;
+    if packet[0] >> 4 == VERSION_IP4 && ip_len != SIZE_IP4_HEADER {
+        return None;
+    }
+    if packet.len() == hdr_len {
+        return None;
+    }
+    let sum = pseudo_header(packet, PROTO_TCP, packet.len() - ip_len);
+    if fold(checksum(sum, &packet[ip_len..])) != 0 {
+        return None;
+    }
+    Some((ip_len, hdr_len))
+}
+
+// Internal function
+//
+// Checks that two segments belong to the same flow and carry identical headers
+// (except the lengths, identification, sequence number, PSH flag and checksums)
+fn same_flow(a: &[u8], b: &[u8], ip_len: usize, hdr_len: usize) -> bool {
+    let ip = if ip_len == SIZE_IP4_HEADER {
+        // version, TOS, flags, TTL, protocol and addresses
+        a[..2] == b[..2] && a[6..10] == b[6..10] && a[12..20] == b[12..20]
+    } else {
+        // version, traffic class, flow label, next header, hop limit and addresses
+        a[..4] == b[..4] && a[6..40] == b[6..40]
+    };
+    let (ta, tb) = (&a[ip_len..hdr_len], &b[ip_len..hdr_len]);
+
+    // ports, acknowledgement, data offset, window and options
+    ip && ta[..4] == tb[..4]
+        && ta[8..13] == tb[8..13]
+        && ta[14..16] == tb[14..16]
+        && ta[SIZE_TCP_HEADER..] == tb[SIZE_TCP_HEADER..]
+}
+
+fn sequence(packet: &[u8], ip_len: usize) -> u32 {
+    u32::from_be_bytes([
+        packet[ip_len + 4],
+        packet[ip_len + 5],
+        packet[ip_len + 6],
+        packet[ip_len + 7],
+    ])
+}
+
+// Internal function
+//
+// Returns the number of packets (from the start) which can be merged into a super-packet
+fn run_length(packets: &[&[u8]]) -> (usize, usize, usize) {
+    let first = packets[0];
+    let (ip_len, hdr_len) = match segment_headers(first) {
+        Some(v) => v,
+        None => return (1, 0, 0),
+    };
+    if first[ip_len + 13] != TCP_ACK {
+        return (1, ip_len, hdr_len);
+    }
+
+    let mss = first.len() - hdr_len;
+    let mut total = first.len();
+    let mut next = sequence(first, ip_len).wrapping_add(mss as u32);
+    let mut count = 1;
+    for packet in packets[1..].iter() {
+        match segment_headers(packet) {
+            Some(headers) if headers == (ip_len, hdr_len) => (),
+            _ => break,
+        }
+        let flags = packet[ip_len + 13];
+        let payload = packet.len() - hdr_len;
+        if (flags != TCP_ACK && flags != (TCP_ACK | TCP_PSH))
+            || !same_flow(first, packet, ip_len, hdr_len)
+            || sequence(packet, ip_len) != next
+            || payload > mss
+            || total + payload > MAX_SUPER_PACKET
+        {
+            break;
+        }
+        count += 1;
+        total += payload;
+        next = next.wrapping_add(payload as u32);
+
+        // a short segment (or push) ends the super-packet
+        if payload < mss || flags & TCP_PSH != 0 {
+            break;
+        }
+    }
+    (count, ip_len, hdr_len)
+}
+
+// Internal function
+//
+// Merge segments of a flow into a super-packet (headers of the first segment)
+fn merge(packets: &[&[u8]], ip_len: usize, hdr_len: usize) -> Coalesced<'static> {
+    let first = packets[0];
+    let last = packets[packets.len() - 1];
+    let mss = first.len() - hdr_len;
+    let total = packets.iter().map(|p| p.len() - hdr_len).sum::<usize>() + hdr_len;
+
+    let mut msg = Vec::with_capacity(total);
+    msg.extend_from_slice(first);
+    for packet in packets[1..].iter() {
+        msg.extend_from_slice(&packet[hdr_len..]);
+    }
+    msg[ip_len + 13] |= last[ip_len + 13] & TCP_PSH;
+
+    // IP header: total length (and header checksum)
+    let gso = if ip_len == SIZE_IP4_HEADER {
+        msg[2..4].copy_from_slice(&(total as u16).to_be_bytes());
+        msg[10..12].copy_from_slice(&[0, 0]);
+        let sum = fold(checksum(0, &msg[..ip_len]));
+        msg[10..12].copy_from_slice(&sum.to_be_bytes());
+        Gso::TcpV4
+    } else {
+        msg[4..6].copy_from_slice(&((total - SIZE_IP6_HEADER) as u16).to_be_bytes());
+        Gso::TcpV6
+    };
+
+    // the checksum field holds the pseudo-header sum (completed for every segment)
+    let sum = !fold(pseudo_header(&msg, PROTO_TCP, total - ip_len));
+    msg[ip_len + TCP_CSUM_OFFSET..ip_len + TCP_CSUM_OFFSET + 2].copy_from_slice(&sum.to_be_bytes());
+
+    Coalesced::Super(
+        msg,
+        Offload {
+            gso,
+            gso_size: mss as u16,
+            hdr_len: hdr_len as u16,
+            csum_start: ip_len as u16,
+            csum_offset: TCP_CSUM_OFFSET as u16,
+            needs_csum: true,
+        },
+    )
+}
+
+/// Merge consecutive segments of TCP flows into super-packets
+///
+/// # Arguments
+///
+/// - `packets`: The IP packets to be written to the TUN device (in order)
+///
+/// # Returns
+///
+/// The packets and super-packets to be written (in order)
+pub fn coalesce<'a>(packets: &[&'a [u8]]) -> Vec<Coalesced<'a>> {
+    let mut out = Vec::with_capacity(packets.len());
+    let mut start = 0;
+    while start < packets.len() {
+        let (count, ip_len, hdr_len) = run_length(&packets[start..]);
+        if count == 1 {
+            out.push(Coalesced::Packet(packets[start]));
+        } else {
+            out.push(merge(&packets[start..start + count], ip_len, hdr_len));
+        }
+        start += count;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Internal function
+    //
+    // Construct a TCP segment (with valid checksums) of the given flow
+    // (the IPv4 identification is derived from the sequence number)
+    fn segment(v6: bool, seq: u32, flags: u8, payload: &[u8]) -> Vec<u8> {
+        let ip_len = if v6 { SIZE_IP6_HEADER } else { SIZE_IP4_HEADER };
+        let len = ip_len + SIZE_TCP_HEADER + payload.len();
+        let mut packet = vec![0u8; len];
+        if v6 {
+            packet[0] = 0x60;
+            packet[4..6].copy_from_slice(&((len - ip_len) as u16).to_be_bytes());
+            packet[6] = PROTO_TCP;
+            packet[7] = 64;
+            packet[8..24].copy_from_slice(&[0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+            packet[24..40].copy_from_slice(&[0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+        } else {
+            packet[0] = 0x45;
+            packet[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+            packet[4..6].copy_from_slice(&((seq / 100) as u16).to_be_bytes());
+            packet[6] = 0x40; // DF
+            packet[8] = 64;
+            packet[9] = PROTO_TCP;
+            packet[12..16].copy_from_slice(&[10, 0, 0, 1]);
+            packet[16..20].copy_from_slice(&[10, 0, 0, 2]);
+            let sum = fold(checksum(0, &packet[..ip_len]));
+            packet[10..12].copy_from_slice(&sum.to_be_bytes());
+        }
+        let tcp = &mut packet[ip_len..];
+        tcp[0..2].copy_from_slice(&4321u16.to_be_bytes());
+        tcp[2..4].copy_from_slice(&80u16.to_be_bytes());
+        tcp[4..8].copy_from_slice(&seq.to_be_bytes());
+        tcp[8..12].copy_from_slice(&7u32.to_be_bytes());
+        tcp[12] = 5 << 4;
+        tcp[13] = flags;
+        tcp[14..16].copy_from_slice(&1024u16.to_be_bytes());
+        tcp[SIZE_TCP_HEADER..].copy_from_slice(payload);
+        let sum = pseudo_header(&packet, PROTO_TCP, len - ip_len);
+        let sum = fold(checksum(sum, &packet[ip_len..]));
+        packet[ip_len + TCP_CSUM_OFFSET..ip_len + TCP_CSUM_OFFSET + 2]
+            .copy_from_slice(&sum.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn test_coalesce_split() {
+        for &v6 in [false, true].iter() {
+            let payloads: Vec<Vec<u8>> = (0..4u8).map(|i| vec![i; 100]).collect();
+            let mut packets: Vec<Vec<u8>> = payloads
+                .iter()
+                .enumerate()
+                .map(|(i, p)| segment(v6, 1000 + 100 * i as u32, TCP_ACK, p))
+                .collect();
+
+            // the last segment is short and pushed
+            packets.push(segment(v6, 1400, TCP_ACK | TCP_PSH, &[9; 50]));
+
+            // followed by a segment of an unrelated (out-of-order) position
+            packets.push(segment(v6, 5000, TCP_ACK, &[1; 100]));
+
+            let refs: Vec<&[u8]> = packets.iter().map(|p| &p[..]).collect();
+            let out = coalesce(&refs);
+            assert_eq!(out.len(), 2);
+            let (msg, offload) = match &out[0] {
+                Coalesced::Super(msg, offload) => (msg, offload),
+                Coalesced::Packet(_) => panic!("segments not coalesced"),
+            };
+            assert_eq!(offload.gso_size, 100);
+            assert_eq!(msg.len(), refs[0].len() + 350);
+            match out[1] {
+                Coalesced::Packet(packet) => assert_eq!(packet, refs[5]),
+                Coalesced::Super(_, _) => panic!("unrelated segment coalesced"),
+            }
+
+            // splitting the super-packet restores the original segments
+            let segments = split(msg, offload, 4).unwrap();
+            assert_eq!(segments.len(), 5);
+            for (segment, packet) in segments.iter().zip(packets.iter()) {
+                assert_eq!(&segment[..4], &[0, 0, 0, 0]);
+                assert_eq!(&segment[4..], &packet[..]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_complete_checksum() {
+        let packet = segment(false, 1, TCP_ACK | TCP_PSH, &[1, 2, 3]);
+
+        // replace the checksum by the pseudo-header sum
+        let mut partial = packet.clone();
+        let sum = !fold(pseudo_header(&packet, PROTO_TCP, packet.len() - 20));
+        partial[36..38].copy_from_slice(&sum.to_be_bytes());
+
+        let offload = Offload {
+            csum_start: 20,
+            csum_offset: TCP_CSUM_OFFSET as u16,
+            needs_csum: true,
+            ..Default::default()
+        };
+        let msgs = split(&partial, &offload, 0).unwrap();
+        assert_eq!(msgs, vec![packet]);
+    }
+
+    #[test]
+    fn test_coalesce_reject() {
+        // corrupted checksums, SYN flags and non-TCP packets are written unmodified
+        let mut corrupt = segment(false, 100, TCP_ACK, &[1; 10]);
+        corrupt[45] ^= 1;
+        let packets = [
+            segment(false, 0, TCP_ACK, &[1; 100]),
+            corrupt,
+            segment(false, 200, 0x02, &[]),
+            vec![0x45; 28],
+        ];
+        let refs: Vec<&[u8]> = packets.iter().map(|p| &p[..]).collect();
+        let out = coalesce(&refs);
+        assert_eq!(out.len(), 4);
+        assert!(out.iter().all(|c| match c {
+            Coalesced::Packet(_) => true,
+            Coalesced::Super(_, _) => false,
+        }));
+    }
+}
@@ -30,7 +30,7 @@ pub struct IPv6Header {
 
 #[inline(always)]
 pub fn inner_length(packet: &[u8]) -> Option<usize> {
-    match packet.get(0)? >> 4 {
+    match packet.first()? >> 4 {
         VERSION_IP4 => {
             let (header, _): (LayoutVerified<&[u8], IPv4Header>, _) =
                 LayoutVerified::new_from_prefix(packet)?;
@@ -47,3 +47,312 @@ pub fn inner_length(packet: &[u8]) -> Option<usize> {
         _ => None,
     }
 }
+
+// Internal function
+//
+// Internet checksum (ones' complement sum of 16-bit words), starting from the partial sum
+pub fn checksum(sum: u32, data: &[u8]) -> u32 {
+    let mut sum = sum;
+    for chunk in data.chunks(2) {
+        let word = if chunk.len() == 2 {
+            u16::from_be_bytes([chunk[0], chunk[1]])
+        } else {
+            u16::from_be_bytes([chunk[0], 0])
+        };
+        sum += u32::from(word);
+    }
+    sum
+}
+
+pub fn fold(sum: u32) -> u16 {
+    let mut sum = sum;
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/* Construct an ICMP "fragmentation needed" (IPv4) or "packet too big" (IPv6) message
+ * in response to a packet exceeding the MTU of the path to the peer,
+ * which is returned to the sender of the packet (via the TUN device).
+ *
+ * The message appears to originate from the destination of the packet.
+ *
+ * Returns None if the packet must not be answered:
+ * IPv4 packets without the DF flag (may be fragmented), ICMP errors
+ * and IPv6 paths below the minimum IPv6 MTU (1280 bytes).
+ */
+pub fn packet_too_big(packet: &[u8], mtu: usize) -> Option<Vec<u8>> {
+    const TTL: u8 = 64;
+    match packet.first()? >> 4 {
+        VERSION_IP4 => {
+            const PROTO_ICMP: u8 = 1;
+            let ihl = usize::from(packet[0] & 0xf) * 4;
+            if ihl < 20 || packet.len() < ihl || packet[6] & 0x40 == 0 {
+                return None;
+            }
+            if packet[9] == PROTO_ICMP {
+                // only echo messages are answered (never ICMP errors)
+                match packet.get(ihl) {
+                    Some(0) | Some(8) => (),
+                    _ => return None,
+                }
+            }
+
+            // original header and the first 8 bytes of the payload
+            let quote = &packet[..packet.len().min(ihl + 8)];
+            let len = 20 + 8 + quote.len();
+            let mut msg = vec![0u8; len];
+            msg[0] = 0x45;
+            msg[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+            msg[8] = TTL;
+            msg[9] = PROTO_ICMP;
+            msg[12..16].copy_from_slice(&packet[16..20]);
+            msg[16..20].copy_from_slice(&packet[12..16]);
+            let sum = fold(checksum(0, &msg[..20]));
+            msg[10..12].copy_from_slice(&sum.to_be_bytes());
+
+            // ICMP destination unreachable, fragmentation needed (carrying the next-hop MTU)
+            msg[20] = 3;
+            msg[21] = 4;
+            msg[26..28].copy_from_slice(&(mtu.clamp(68, 0xffff) as u16).to_be_bytes());
+            msg[28..].copy_from_slice(quote);
+            let sum = fold(checksum(0, &msg[20..]));
+            msg[22..24].copy_from_slice(&sum.to_be_bytes());
+            Some(msg)
+        }
+        VERSION_IP6 => {
+            const MIN_MTU: usize = 1280;
+            const NEXT_ICMP6: u8 = 58;
+            if mtu < MIN_MTU || packet.len() < mem::size_of::<IPv6Header>() {
+                return None;
+            }
+            if packet[6] == NEXT_ICMP6 && packet.get(40).map(|ty| *ty < 128).unwrap_or(true) {
+                return None;
+            }
+
+            // as much of the original packet as fits in the minimum MTU
+            let quote = &packet[..packet.len().min(MIN_MTU - 40 - 8)];
+            let payload = 8 + quote.len();
+            let mut msg = vec![0u8; 40 + payload];
+            msg[0] = 0x60;
+            msg[4..6].copy_from_slice(&(payload as u16).to_be_bytes());
+            msg[6] = NEXT_ICMP6;
+            msg[7] = TTL;
+            msg[8..24].copy_from_slice(&packet[24..40]);
+            msg[24..40].copy_from_slice(&packet[8..24]);
+
+            // ICMPv6 packet too big (carrying the MTU)
+            msg[40] = 2;
+            msg[44..48].copy_from_slice(&(mtu as u32).to_be_bytes());
+            msg[48..].copy_from_slice(quote);
+
+            // checksum over the pseudo-header and the ICMPv6 message
+            let mut sum = checksum(0, &msg[8..40]);
+            sum = checksum(sum, &(payload as u32).to_be_bytes());
+            sum = checksum(sum, &[0, 0, 0, NEXT_ICMP6]);
+            let sum = fold(checksum(sum, &msg[40..]));
+            msg[42..44].copy_from_slice(&sum.to_be_bytes());
+            Some(msg)
+        }
+        _ => None,
+    }
+}
+
+const ECN_MASK: u8 = 0b11;
+const ECN_NOT_ECT: u8 = 0b00;
+const ECN_ECT_0: u8 = 0b10;
+const ECN_CE: u8 = 0b11;
+
+// Internal function
+//
+// Traffic class (DSCP and ECN bits) of an IP packet
+fn traffic_class(packet: &[u8]) -> Option<u8> {
+    match packet.first()? >> 4 {
+        VERSION_IP4 => packet.get(1).cloned(),
+        VERSION_IP6 => Some(packet[0] << 4 | packet.get(1)? >> 4),
+        _ => None,
+    }
+}
+
+/// Traffic class of the outer packet carrying an inner (plaintext) IP packet:
+/// the DSCP bits are copied from the inner packet (unless overridden)
+/// and the ECN bits are set as in the normal mode of RFC 6040.
+///
+/// # Arguments
+///
+/// - `packet`: The inner packet (empty for keepalives)
+/// - `dscp`: Fixed DSCP value of the outer packets
+pub fn outer_traffic_class(packet: &[u8], dscp: Option<u8>) -> u8 {
+    let inner = traffic_class(packet).unwrap_or(0);
+    let dscp = dscp.map(|dscp| dscp << 2).unwrap_or(inner & !ECN_MASK);
+    let ecn = match inner & ECN_MASK {
+        ECN_CE => ECN_ECT_0,
+        ecn => ecn,
+    };
+    dscp | ecn
+}
+
+/// Propagate congestion experienced (CE) marks of the outer packet
+/// to the decapsulated packet (RFC 6040)
+///
+/// # Arguments
+///
+/// - `outer`: Traffic class of the outer packet
+/// - `packet`: The inner packet
+///
+/// # Returns
+///
+/// False if the packet must be dropped
+/// (a CE mark on a packet from a non-ECN capable transport)
+pub fn ecn_decapsulate(outer: u8, packet: &mut [u8]) -> bool {
+    if outer & ECN_MASK != ECN_CE {
+        return true;
+    }
+    match traffic_class(packet).map(|tc| tc & ECN_MASK) {
+        Some(ECN_NOT_ECT) => false,
+        Some(ECN_CE) | None => true,
+        Some(_) => {
+            if packet[0] >> 4 == VERSION_IP4 {
+                // incremental update of the header checksum (RFC 1624)
+                let old = u16::from_be_bytes([packet[0], packet[1]]);
+                packet[1] |= ECN_CE;
+                let new = u16::from_be_bytes([packet[0], packet[1]]);
+                if packet.len() >= 12 {
+                    let sum = u16::from_be_bytes([packet[10], packet[11]]);
+                    let sum = fold(u32::from(!sum) + u32::from(!old) + u32::from(new));
+                    packet[10..12].copy_from_slice(&sum.to_be_bytes());
+                }
+            } else {
+                packet[1] |= ECN_CE << 4;
+            }
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_traffic_class() {
+        // IPv4 packet, DSCP 46 (EF) and ECT(1)
+        let mut packet = vec![0u8; 20];
+        packet[0] = VERSION_IP4 << 4 | 5;
+        packet[1] = 46 << 2 | 0b01;
+        assert_eq!(outer_traffic_class(&packet, None), 46 << 2 | 0b01);
+        assert_eq!(outer_traffic_class(&packet, Some(8)), 8 << 2 | 0b01);
+
+        // IPv6 packet, DSCP 10 and CE (encapsulated as ECT(0))
+        let mut packet6 = vec![0u8; 40];
+        let tc = 10 << 2 | ECN_CE;
+        packet6[0] = VERSION_IP6 << 4 | tc >> 4;
+        packet6[1] = tc << 4;
+        assert_eq!(outer_traffic_class(&packet6, None), 10 << 2 | ECN_ECT_0);
+
+        // keepalive
+        assert_eq!(outer_traffic_class(&[], None), 0);
+        assert_eq!(outer_traffic_class(&[], Some(1)), 4);
+
+        // no congestion: unchanged
+        let orig = packet.clone();
+        assert!(ecn_decapsulate(ECN_ECT_0, &mut packet));
+        assert_eq!(packet, orig);
+
+        // congestion experienced on ECN capable transport
+        let sum = fold(checksum(0, &packet));
+        packet[10..12].copy_from_slice(&sum.to_be_bytes());
+        assert!(ecn_decapsulate(ECN_CE, &mut packet));
+        assert_eq!(packet[1] & ECN_MASK, ECN_CE);
+        assert_eq!(
+            fold(checksum(0, &packet)),
+            0,
+            "checksum should remain valid"
+        );
+        assert!(ecn_decapsulate(ECN_CE, &mut packet6));
+        assert_eq!(traffic_class(&packet6), Some(10 << 2 | ECN_CE));
+
+        // congestion experienced on non-ECN capable transport
+        packet[1] = 46 << 2 | ECN_NOT_ECT;
+        assert!(!ecn_decapsulate(ECN_CE, &mut packet));
+    }
+
+    #[test]
+    fn test_packet_too_big() {
+        // IPv4 packet (DF set) from 10.0.0.1 to 10.0.0.2
+        let mut packet = vec![0u8; 1000];
+        packet[0] = VERSION_IP4 << 4 | 5;
+        packet[2..4].copy_from_slice(&1000u16.to_be_bytes());
+        packet[6] = 0x40;
+        packet[9] = 17;
+        packet[12..16].copy_from_slice(&[10, 0, 0, 1]);
+        packet[16..20].copy_from_slice(&[10, 0, 0, 2]);
+
+        let msg = packet_too_big(&packet, 900).unwrap();
+        assert_eq!(msg.len(), 20 + 8 + 28);
+        assert_eq!(
+            fold(checksum(0, &msg[..20])),
+            0,
+            "invalid IPv4 header checksum"
+        );
+        assert_eq!(fold(checksum(0, &msg[20..])), 0, "invalid ICMP checksum");
+        assert_eq!(&msg[12..16], &[10, 0, 0, 2]);
+        assert_eq!(&msg[16..20], &[10, 0, 0, 1]);
+        assert_eq!((msg[20], msg[21]), (3, 4));
+        assert_eq!(&msg[26..28], &900u16.to_be_bytes());
+        assert_eq!(&msg[28..], &packet[..28]);
+
+        // may be fragmented
+        packet[6] = 0;
+        assert!(packet_too_big(&packet, 900).is_none());
+
+        // IPv6 packet from fd00::1 to fd00::2
+        let mut packet = vec![0u8; 1500];
+        packet[0] = VERSION_IP6 << 4;
+        packet[4..6].copy_from_slice(&1460u16.to_be_bytes());
+        packet[6] = 17;
+        packet[23] = 1;
+        packet[39] = 2;
+        packet[8] = 0xfd;
+        packet[24] = 0xfd;
+
+        let msg = packet_too_big(&packet, 1400).unwrap();
+        assert_eq!(msg.len(), 1280);
+        assert_eq!(&msg[8..24], &packet[24..40]);
+        assert_eq!(&msg[24..40], &packet[8..24]);
+        assert_eq!(msg[40], 2);
+        assert_eq!(&msg[44..48], &1400u32.to_be_bytes());
+        let mut sum = checksum(0, &msg[8..40]);
+        sum = checksum(sum, &(msg.len() as u32 - 40).to_be_bytes());
+        sum = checksum(sum, &[0, 0, 0, 58]);
+        assert_eq!(
+            fold(checksum(sum, &msg[40..])),
+            0,
+            "invalid ICMPv6 checksum"
+        );
+
+        // below the minimum IPv6 MTU
+        assert!(packet_too_big(&packet, 1200).is_none());
+    }
+
+    #[test]
+    fn test_inner_length_padded() {
+        // IPv4 packet of 21 bytes padded to 32 bytes
+        let mut packet = [0u8; 32];
+        packet[0] = VERSION_IP4 << 4 | 5;
+        packet[3] = 21;
+        assert_eq!(inner_length(&packet[..]), Some(21));
+
+        // IPv6 packet of 40 + 9 bytes padded to 64 bytes
+        let mut packet = [0u8; 64];
+        packet[0] = VERSION_IP6 << 4;
+        packet[5] = 9;
+        assert_eq!(inner_length(&packet[..]), Some(49));
+
+        // empty (keepalive), truncated and unknown packets have no inner length
+        assert_eq!(inner_length(&[]), None);
+        assert_eq!(inner_length(&[VERSION_IP4 << 4; 16]), None);
+        assert_eq!(inner_length(&[0u8; 64]), None);
+    }
+}
@@ -1,6 +1,11 @@
+use super::types::RouterError;
+use super::SIZE_TAG;
+
+use super::super::handshake::ReservedBytes;
+
 use byteorder::LittleEndian;
 use zerocopy::byteorder::{U32, U64};
-use zerocopy::{AsBytes, FromBytes};
+use zerocopy::{AsBytes, ByteSlice, FromBytes, LayoutVerified};
 
 pub const TYPE_TRANSPORT: u32 = 4;
 
@@ -11,3 +16,71 @@ pub struct TransportHeader {
     pub f_receiver: U32<LittleEndian>,
     pub f_counter: U64<LittleEndian>,
 }
+
+/* Zero copy parsing of transport messages */
+
+impl TransportHeader {
+    /// Cast the prefix of a transport message to a header
+    ///
+    /// # Returns
+    ///
+    /// The header and the encrypted body (including the authentication tag).
+    /// Messages with an invalid type (including the reserved bytes following the type,
+    /// validated according to the policy) or too short to hold a tag are rejected.
+    pub fn parse<B: ByteSlice>(
+        bytes: B,
+        reserved: ReservedBytes,
+    ) -> Result<(LayoutVerified<B, Self>, B), RouterError> {
+        let (header, body): (LayoutVerified<B, Self>, B) =
+            LayoutVerified::new_from_prefix(bytes).ok_or(RouterError::MalformedTransportMessage)?;
+
+        if !reserved.check(header.f_type.get(), TYPE_TRANSPORT) || body.len() < SIZE_TAG {
+            return Err(RouterError::MalformedTransportMessage);
+        }
+
+        Ok((header, body))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transport_header_parse() {
+        let mut msg = [0u8; 16 + SIZE_TAG];
+        msg[0] = TYPE_TRANSPORT as u8;
+        msg[4] = 0x2a;
+        msg[8] = 0x01;
+
+        let (header, body) = TransportHeader::parse(&msg[..], ReservedBytes::Strict).unwrap();
+        assert_eq!(header.f_receiver.get(), 0x2a);
+        assert_eq!(header.f_counter.get(), 0x01);
+        assert_eq!(body.len(), SIZE_TAG);
+
+        // too short to contain a tag
+        assert!(TransportHeader::parse(&msg[..msg.len() - 1], ReservedBytes::Strict).is_err());
+
+        // too short to contain a header
+        assert!(TransportHeader::parse(&msg[..8], ReservedBytes::Strict).is_err());
+
+        // invalid type
+        msg[0] = 1;
+        assert!(TransportHeader::parse(&msg[..], ReservedBytes::Strict).is_err());
+    }
+
+    #[test]
+    fn transport_header_reserved_bytes() {
+        let mut msg = [0u8; 16 + SIZE_TAG];
+        msg[..4].copy_from_slice(&[TYPE_TRANSPORT as u8, 0xaa, 0xbb, 0xcc]);
+
+        let custom = ReservedBytes::Custom([0xaa, 0xbb, 0xcc]);
+        assert!(TransportHeader::parse(&msg[..], ReservedBytes::Strict).is_err());
+        assert!(TransportHeader::parse(&msg[..], ReservedBytes::Ignore).is_ok());
+        assert!(TransportHeader::parse(&msg[..], custom).is_ok());
+
+        msg[1] = 0;
+        assert!(TransportHeader::parse(&msg[..], custom).is_err());
+        assert!(TransportHeader::parse(&msg[..], ReservedBytes::Ignore).is_ok());
+    }
+}
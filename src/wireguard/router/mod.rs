@@ -1,3 +1,19 @@
+/* The crypto-key router: the transport data pipeline.
+ *
+ * Outbound IP packets are routed to a peer by destination (allowed IPs),
+ * assigned the next nonce counter of the current key-pair,
+ * encrypted in parallel and transmitted in-order as type-4 transport messages.
+ *
+ * Inbound transport messages are mapped to a key-pair by receiver id,
+ * decrypted in parallel, checked for replay and the inner source address,
+ * stripped of padding (using the inner IP length) and written to the TUN device in-order.
+ *
+ * Events (send, recv, need_key, key_confirmed) are delivered to the timers through `Callbacks`.
+ *
+ * With TUN offloading, outbound super-packets are split into segments before routing
+ * and inbound segments of a batch are coalesced before being written (see `gso`).
+ */
+
 mod anti_replay;
 mod constants;
 mod device;
@@ -5,8 +21,11 @@ mod ip;
 mod messages;
 mod peer;
 mod route;
+mod staged;
 mod types;
 
+pub mod gso;
+
 mod queue;
 mod receive;
 mod send;
@@ -31,7 +50,8 @@ pub const fn message_data_len(payload: usize) -> usize {
     payload + mem::size_of::<TransportHeader>() + SIZE_TAG
 }
 
+pub use anti_replay::{ReplayFilter, MAX_WINDOW_SIZE, MIN_WINDOW_SIZE, WINDOW_SIZE};
 pub use device::DeviceHandle as Device;
 pub use messages::TYPE_TRANSPORT;
 pub use peer::PeerHandle;
-pub use types::Callbacks;
+pub use types::{Callbacks, DropPolicy, SourcePolicy};
@@ -1,31 +1,33 @@
 use super::super::constants::*;
 use super::super::{tun, udp, Endpoint, KeyPair};
 
-use super::anti_replay::AntiReplay;
+use super::anti_replay::ReplayFilter;
 use super::device::DecryptionState;
 use super::device::Device;
 use super::device::EncryptionState;
 
-use super::constants::*;
-use super::types::{Callbacks, RouterError};
-use super::SIZE_MESSAGE_PREFIX;
+use super::types::{Callbacks, DropPolicy, RouterError};
+use super::{SIZE_MESSAGE_PREFIX, SIZE_TAG};
+
+use super::ip::outer_traffic_class;
 
 use super::queue::Queue;
 use super::receive::ReceiveJob;
 use super::send::SendJob;
+use super::staged::StagedPackets;
 use super::worker::JobUnion;
 
 use core::mem;
 use core::ops::Deref;
-use core::sync::atomic::AtomicBool;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 use alloc::sync::Arc;
 
 // TODO: consider no_std alternatives
 use std::fmt;
 use std::net::{IpAddr, SocketAddr};
+use std::time::Instant;
 
-use arraydeque::{ArrayDeque, Wrapping};
 use spin::Mutex;
 
 pub struct KeyWheel {
@@ -40,10 +42,12 @@ pub struct PeerInner<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E
     pub(super) opaque: C::Opaque,
     pub(super) outbound: Queue<SendJob<E, C, T, B>>,
     pub(super) inbound: Queue<ReceiveJob<E, C, T, B>>,
-    pub(super) staged_packets: Mutex<ArrayDeque<[Vec<u8>; MAX_QUEUED_PACKETS], Wrapping>>,
+    pub(super) staged_packets: Mutex<StagedPackets>,
     pub(super) keys: Mutex<KeyWheel>,
     pub(super) enc_key: Mutex<Option<EncryptionState>>,
     pub(super) endpoint: Mutex<Option<E>>,
+    pub(super) last_sent: Mutex<Option<Instant>>, // last transport message sent
+    pub(super) last_received: Mutex<Option<Instant>>, // last authenticated transport message received
 }
 
 /// A Peer dereferences to its opaque type:
@@ -135,7 +139,9 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> DecryptionSta
         DecryptionState {
             confirmed: AtomicBool::new(keypair.initiator),
             keypair: keypair.clone(),
-            protector: spin::Mutex::new(AntiReplay::new()),
+            protector: spin::Mutex::new(ReplayFilter::with_window(
+                peer.device.replay_window.load(Ordering::Relaxed),
+            )),
             peer,
         }
     }
@@ -198,13 +204,15 @@ pub fn new_peer<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>>(
                 outbound: Queue::new(),
                 enc_key: spin::Mutex::new(None),
                 endpoint: spin::Mutex::new(None),
+                last_sent: spin::Mutex::new(None),
+                last_received: spin::Mutex::new(None),
                 keys: spin::Mutex::new(KeyWheel {
                     next: None,
                     current: None,
                     previous: None,
                     retired: vec![],
                 }),
-                staged_packets: spin::Mutex::new(ArrayDeque::new()),
+                staged_packets: spin::Mutex::new(StagedPackets::new()),
             }),
         }
     };
@@ -223,6 +231,34 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> PeerInner<E,
     ///
     /// Unit if packet was sent, or an error indicating why sending failed
     pub fn send_raw(&self, msg: &[u8]) -> Result<(), RouterError> {
+        let tos = outer_traffic_class(&[], *self.device.dscp.read());
+        self.send_raw_batch(&[msg], tos)
+    }
+
+    /// The largest inner packet which can be sent to the endpoint of the peer
+    /// without exceeding the path MTU, if the path MTU to the endpoint is known
+    /// (see `Device::set_path_mtu`)
+    pub fn inner_mtu(&self) -> Option<usize> {
+        let addr = self.endpoint.lock().as_ref()?.into_address();
+        let mtu = self.device.get_path_mtu(&addr)?;
+        let outer = match addr {
+            SocketAddr::V4(_) => 20 + 8,
+            SocketAddr::V6(_) => 40 + 8,
+        };
+        Some(mtu.saturating_sub(outer + SIZE_MESSAGE_PREFIX + SIZE_TAG))
+    }
+
+    /// Send a batch of raw messages to the peer (in a single system call if supported)
+    ///
+    /// # Arguments
+    ///
+    /// - `msgs`: The messages to send
+    /// - `tos`: The traffic class (DSCP and ECN bits) of the outer packets
+    ///
+    /// # Returns
+    ///
+    /// Unit if the packets were sent, or an error indicating why sending failed
+    pub fn send_raw_batch(&self, msgs: &[&[u8]], tos: u8) -> Result<(), RouterError> {
         // send to endpoint (if known)
         match self.endpoint.lock().as_mut() {
             Some(endpoint) => {
@@ -232,7 +268,10 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> PeerInner<E,
                         .1
                         .as_ref()
                         .ok_or(RouterError::SendError)
-                        .and_then(|w| w.write(msg, endpoint).map_err(|_| RouterError::SendError))
+                        .and_then(|w| {
+                            w.write_batch(msgs, endpoint, tos)
+                                .map_err(|_| RouterError::SendError)
+                        })
                 } else {
                     Ok(())
                 }
@@ -251,29 +290,42 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> Peer<E, C, T,
     /// - `stage`: Should the message be staged if no key is available
     pub(super) fn send(&self, msg: Vec<u8>, stage: bool) {
         // check if key available
+        let mut expired = None;
         let (job, need_key) = {
             let mut enc_key = self.enc_key.lock();
             match enc_key.as_mut() {
                 None => {
                     log::debug!("no key encryption key available");
                     if stage {
-                        self.staged_packets.lock().push_back(msg);
+                        self.staged_packets.lock().push(msg);
                     };
                     (None, true)
                 }
                 Some(mut state) => {
-                    // avoid integer overflow in nonce
-                    if state.nonce >= REJECT_AFTER_MESSAGES - 1 {
+                    // avoid integer overflow in nonce and use of keys past their lifetime
+                    if state.nonce >= REJECT_AFTER_MESSAGES - 1
+                        || state.keypair.birth.elapsed() >= REJECT_AFTER_TIME
+                    {
                         log::debug!("encryption key expired");
+                        expired = Some(state.keypair.clone());
                         *enc_key = None;
                         if stage {
-                            self.staged_packets.lock().push_back(msg);
+                            self.staged_packets.lock().push(msg);
                         }
                         (None, true)
                     } else {
                         log::debug!("encryption state available, nonce = {}", state.nonce);
-                        let job =
-                            SendJob::new(msg, state.nonce, state.keypair.clone(), self.clone());
+                        let tos = outer_traffic_class(
+                            &msg[SIZE_MESSAGE_PREFIX..],
+                            *self.device.dscp.read(),
+                        );
+                        let job = SendJob::new(
+                            msg,
+                            state.nonce,
+                            state.keypair.clone(),
+                            self.clone(),
+                            tos,
+                        );
                         if self.outbound.push(job.clone()) {
                             state.nonce += 1;
                             (Some(job), false)
@@ -285,6 +337,10 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> Peer<E, C, T,
             }
         };
 
+        if let Some(keypair) = expired {
+            self.expire_keypair(&keypair);
+        }
+
         if need_key {
             log::debug!("request new key");
             debug_assert!(job.is_none());
@@ -297,13 +353,48 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> Peer<E, C, T,
         }
     }
 
+    /// Wipe a key-pair past its lifetime from the key-wheel and the inbound id map
+    /// (the id is released when the next key-pair is added)
+    ///
+    /// # Arguments
+    ///
+    /// - `keypair`: The expired key-pair
+    pub(super) fn expire_keypair(&self, keypair: &Arc<KeyPair>) {
+        let id = keypair.local_id();
+        let mut keys = self.keys.lock();
+        let keys = &mut *keys;
+
+        // remove from key-wheel (unless already displaced)
+        let mut found = false;
+        for slot in [&mut keys.next, &mut keys.current, &mut keys.previous] {
+            if slot.as_ref().map(|k| Arc::ptr_eq(k, keypair)) == Some(true) {
+                *slot = None;
+                found = true;
+            }
+        }
+        if !found {
+            return;
+        }
+        log::debug!("peer.expire_keypair: wiping key-pair (id = {})", id);
+        keys.retired.push(id);
+
+        // update inbound "recv" map
+        self.device.recv.write().remove(&id);
+
+        // clear encryption state (if using the key-pair)
+        let mut enc_key = self.enc_key.lock();
+        if enc_key.as_ref().map(|s| Arc::ptr_eq(&s.keypair, keypair)) == Some(true) {
+            *enc_key = None;
+        }
+    }
+
     // Transmit all staged packets
     fn send_staged(&self) -> bool {
         log::trace!("peer.send_staged");
         let mut sent = false;
         let mut staged = self.staged_packets.lock();
         loop {
-            match staged.pop_front() {
+            match staged.pop() {
                 Some(msg) => {
                     sent = true;
                     self.send(msg, false);
@@ -337,6 +428,12 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> Peer<E, C, T,
             mem::swap(&mut keys.current, &mut swap);
             mem::swap(&mut keys.previous, &mut swap);
 
+            // retire the displaced previous key (released on the next call to add_keypair)
+            if let Some(k) = swap {
+                self.device.recv.write().remove(&k.local_id());
+                keys.retired.push(k.local_id());
+            }
+
             // tell the world outside the router that a key was confirmed
             C::key_confirmed(&self.opaque);
 
@@ -379,6 +476,18 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> PeerHandle<E,
         self.peer.endpoint.lock().as_ref().map(|e| e.into_address())
     }
 
+    /// Returns the instant at which the last transport message
+    /// (data or keepalive) was transmitted to the peer
+    pub fn last_sent(&self) -> Option<Instant> {
+        *self.peer.last_sent.lock()
+    }
+
+    /// Returns the instant at which the last authenticated transport message
+    /// (data or keepalive) was received from the peer
+    pub fn last_received(&self) -> Option<Instant> {
+        *self.peer.last_received.lock()
+    }
+
     /// Zero all key-material related to the peer
     pub fn zero_keys(&self) {
         log::trace!("peer.zero_keys");
@@ -411,6 +520,26 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> PeerHandle<E,
         *self.peer.enc_key.lock() = None;
     }
 
+    /// Zero all key-material related to the peer
+    ///
+    /// # Returns
+    ///
+    /// The ids of the retired key-pairs, to be released by the caller
+    /// (instead of on the next call to `add_keypair`)
+    pub fn clear_keys(&self) -> Vec<u32> {
+        self.zero_keys();
+        mem::take(&mut self.peer.keys.lock().retired)
+    }
+
+    #[cfg(test)]
+    pub fn has_keys(&self) -> bool {
+        let keys = self.peer.keys.lock();
+        keys.next.is_some()
+            || keys.current.is_some()
+            || keys.previous.is_some()
+            || self.peer.enc_key.lock().is_some()
+    }
+
     pub fn down(&self) {
         self.zero_keys();
     }
@@ -443,16 +572,27 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> PeerHandle<E,
             let mut release = mem::replace(&mut keys.retired, vec![]);
 
             // update key-wheel
+            let mut displaced = Vec::with_capacity(2);
             if new.initiator {
                 // start using key for encryption
                 *self.peer.enc_key.lock() = Some(EncryptionState::new(&new));
 
-                // move current into previous
-                keys.previous = keys.current.as_ref().cloned();
+                // a pending next key is demoted to previous (replacing current),
+                // otherwise current is moved into previous
+                displaced.extend(keys.previous.take());
+                match keys.next.take() {
+                    Some(next) => {
+                        displaced.extend(keys.current.take());
+                        keys.previous = Some(next);
+                    }
+                    None => keys.previous = keys.current.take(),
+                }
                 keys.current = Some(new.clone());
             } else {
-                // store the key and await confirmation
-                keys.previous = keys.next.as_ref().cloned();
+                // store the key and await confirmation,
+                // current is kept (for encryption) until the new key is confirmed
+                displaced.extend(keys.next.take());
+                displaced.extend(keys.previous.take());
                 keys.next = Some(new.clone());
             };
 
@@ -461,8 +601,8 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> PeerHandle<E,
                 log::trace!("peer.add_keypair: updating inbound id map");
                 let mut recv = self.peer.device.recv.write();
 
-                // purge recv map of previous id
-                if let Some(k) = &keys.previous {
+                // purge recv map of displaced ids
+                for k in displaced {
                     recv.remove(&k.local_id());
                     release.push(k.local_id());
                 }
@@ -520,7 +660,7 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> PeerHandle<E,
         self.peer
             .device
             .table
-            .insert(ip, masklen, self.peer.clone())
+            .insert(ip, masklen, self.peer.clone());
     }
 
     /// List subnets mapped to the peer
@@ -539,6 +679,13 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> PeerHandle<E,
         self.peer.device.table.remove(&self.peer)
     }
 
+    /// Replace the subnets mapped to the peer,
+    /// without interrupting the routing of the subnets which remain mapped to the peer.
+    /// Used to apply a new configuration to a running device
+    pub fn set_allowed_ips(&self, ips: &[(IpAddr, u32)]) {
+        self.peer.device.table.replace(&self.peer, ips)
+    }
+
     pub fn clear_src(&self) {
         if let Some(e) = (*self.peer.endpoint.lock()).as_mut() {
             e.clear_src()
@@ -548,4 +695,20 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> PeerHandle<E,
     pub fn purge_staged_packets(&self) {
         self.peer.staged_packets.lock().clear();
     }
+
+    /// Configure the queue of packets awaiting a key-pair
+    ///
+    /// # Arguments
+    ///
+    /// - `depth`: Maximum number of staged packets (default 128)
+    /// - `policy`: Drop the oldest (`Head`, default) or the new (`Tail`) packet when full
+    pub fn set_staged_packets(&self, depth: usize, policy: DropPolicy) {
+        self.peer.staged_packets.lock().configure(depth, policy);
+    }
+
+    /// Returns the depth and drop policy of the queue of packets awaiting a key-pair
+    pub fn get_staged_packets(&self) -> (usize, DropPolicy) {
+        let staged = self.peer.staged_packets.lock();
+        (staged.depth(), staged.policy())
+    }
 }
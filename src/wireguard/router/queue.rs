@@ -4,12 +4,23 @@ use spin::Mutex;
 use core::mem;
 use core::sync::atomic::{AtomicUsize, Ordering};
 
-use super::constants::INORDER_QUEUE_SIZE;
+use super::constants::{INORDER_QUEUE_SIZE, MAX_SEQUENTIAL_BATCH};
 
 pub trait SequentialJob {
     fn is_ready(&self) -> bool;
 
     fn sequential_work(self);
+
+    /// Process a batch of ready jobs (in queue order),
+    /// allows e.g. transmitting several messages in a single system call.
+    fn sequential_batch(jobs: Vec<Self>)
+    where
+        Self: Sized,
+    {
+        for job in jobs {
+            job.sequential_work();
+        }
+    }
 }
 
 pub trait ParallelJob: Sized + SequentialJob {
@@ -63,23 +74,24 @@ impl<J: SequentialJob> Queue<J> {
             loop {
                 let mut queue = self.queue.lock();
 
-                // check if front job is ready
-                match queue.front() {
-                    None => break,
-                    Some(job) => {
-                        if !job.is_ready() {
-                            break;
-                        }
-                    }
-                };
-
-                // take the job out of the queue
-                let job = queue.pop_front().unwrap();
-                debug_assert!(job.is_ready());
+                // take a batch of ready jobs from the front of the queue
+                let mut batch = Vec::new();
+                while batch.len() < MAX_SEQUENTIAL_BATCH {
+                    match queue.front() {
+                        Some(job) if job.is_ready() => (),
+                        _ => break,
+                    };
+                    let job = queue.pop_front().unwrap();
+                    debug_assert!(job.is_ready());
+                    batch.push(job);
+                }
                 mem::drop(queue);
 
-                // process element
-                job.sequential_work();
+                // process elements
+                if batch.is_empty() {
+                    break;
+                }
+                J::sequential_batch(batch);
             }
 
             #[cfg(debug)]
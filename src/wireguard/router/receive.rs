@@ -1,9 +1,10 @@
 use super::device::DecryptionState;
-use super::ip::inner_length;
+use super::gso::{self, Coalesced};
+use super::ip::{ecn_decapsulate, inner_length};
 use super::messages::TransportHeader;
 use super::queue::{ParallelJob, Queue, SequentialJob};
 use super::types::Callbacks;
-use super::{REJECT_AFTER_MESSAGES, SIZE_TAG};
+use super::{REJECT_AFTER_MESSAGES, SIZE_MESSAGE_PREFIX, SIZE_TAG};
 
 use super::super::{tun, udp, Endpoint};
 
@@ -11,6 +12,7 @@ use alloc::sync::Arc;
 use core::sync::atomic::{AtomicBool, Ordering};
 use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
 use spin::Mutex;
+use std::time::Instant;
 use zerocopy::{AsBytes, LayoutVerified};
 
 struct Inner<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> {
@@ -109,7 +111,14 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> ParallelJob
                 }
 
                 // check crypto-key router
-                packet.len() == SIZE_TAG || peer.device.table.check_route(&peer, &packet)
+                if packet.len() == SIZE_TAG || peer.device.table.check_route(peer, packet) {
+                    return true;
+                }
+                peer.device
+                    .source_violations
+                    .fetch_add(1, Ordering::Relaxed);
+                log::debug!("inbound worker: source address not allowed for peer");
+                peer.device.permissive.load(Ordering::Relaxed)
             })();
 
             // remove message in case of failure:
@@ -132,54 +141,107 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> SequentialJob
     }
 
     fn sequential_work(self) {
-        debug_assert_eq!(
-            self.is_ready(),
-            true,
+        Self::sequential_batch(vec![self])
+    }
+
+    fn sequential_batch(jobs: Vec<Self>) {
+        debug_assert!(
+            jobs.iter().all(|job| job.is_ready()),
             "doing sequential work on an incomplete job"
         );
-        log::trace!("processing sequential receive job");
-
-        let job = &self.0;
-        let peer = &job.state.peer;
-        let mut msg = job.buffer.lock();
-        let endpoint = msg.0.take();
-
-        // cast transport header
-        let (header, packet): (LayoutVerified<&[u8], TransportHeader>, &[u8]) =
-            match LayoutVerified::new_from_prefix(&msg.1[..]) {
-                Some(v) => v,
-                None => {
-                    // also covers authentication failure (will fail to parse header)
-                    return;
-                }
-            };
+        log::trace!("processing sequential receive batch ({} jobs)", jobs.len());
 
-        // check for replay
-        if !job.state.protector.lock().update(header.f_counter.get()) {
-            log::debug!("inbound worker: replay detected");
-            return;
-        }
+        // every job in the queue belongs to the same peer
+        let peer = match jobs.first() {
+            Some(job) => &job.0.state.peer,
+            None => return,
+        };
 
-        // check for confirms key
-        if !job.state.confirmed.swap(true, Ordering::SeqCst) {
-            log::debug!("inbound worker: message confirms key");
-            peer.confirm_key(&job.state.keypair);
-        }
+        // check every message (in-order),
+        // recording the length of the inner packet (if any) of accepted messages
+        let mut msgs: Vec<_> = jobs.iter().map(|job| job.0.buffer.lock()).collect();
+        let mut accepted: Vec<Option<(usize, Option<usize>)>> = Vec::with_capacity(jobs.len());
+        for (job, msg) in jobs.iter().zip(msgs.iter_mut()) {
+            let job = &job.0;
+            let endpoint = msg.0.take();
+            let tos = endpoint.as_ref().map(|e| e.traffic_class()).unwrap_or(0);
+            let size = msg.1.len();
+
+            // cast transport header
+            let (header, packet): (LayoutVerified<&mut [u8], TransportHeader>, &mut [u8]) =
+                match LayoutVerified::new_from_prefix(&mut msg.1[..]) {
+                    Some(v) => v,
+                    None => {
+                        // also covers authentication failure (will fail to parse header)
+                        accepted.push(None);
+                        continue;
+                    }
+                };
+
+            // check for replay
+            if !job.state.protector.lock().update(header.f_counter.get()) {
+                log::debug!("inbound worker: replay detected");
+                accepted.push(None);
+                continue;
+            }
 
-        // update endpoint
-        *peer.endpoint.lock() = endpoint;
+            // check for confirms key
+            if !job.state.confirmed.swap(true, Ordering::SeqCst) {
+                log::debug!("inbound worker: message confirms key");
+                peer.confirm_key(&job.state.keypair);
+            }
+
+            // update endpoint and receive time
+            *peer.endpoint.lock() = endpoint;
+            *peer.last_received.lock() = Some(Instant::now());
+
+            // check if should be written to TUN
+            // (keep-alive and malformed packets will have no inner length)
+            let inner = inner_length(packet).filter(|inner| inner + SIZE_TAG <= packet.len());
 
-        // check if should be written to TUN
-        // (keep-alive and malformed packets will have no inner length)
-        if let Some(inner) = inner_length(packet) {
-            if inner + SIZE_TAG <= packet.len() {
-                let _ = peer.device.inbound.write(&packet[..inner]).map_err(|e| {
-                    log::debug!("failed to write inbound packet to TUN: {:?}", e);
-                });
+            // propagate congestion marks of the outer packet
+            let inner = inner.filter(|inner| {
+                let ok = ecn_decapsulate(tos, &mut packet[..*inner]);
+                if !ok {
+                    log::debug!("inbound worker: congestion experienced on non-ECT packet");
+                }
+                ok
+            });
+            accepted.push(Some((size, inner)));
+        }
+
+        // write inner packets to TUN (coalescing segments if supported)
+        let packets: Vec<&[u8]> = msgs
+            .iter()
+            .zip(accepted.iter())
+            .filter_map(|(msg, accepted)| {
+                let inner = (*accepted)?.1?;
+                Some(&msg.1[SIZE_MESSAGE_PREFIX..SIZE_MESSAGE_PREFIX + inner])
+            })
+            .collect();
+        let inbound = &peer.device.inbound;
+        let written: Vec<Result<(), _>> = if inbound.offload() && packets.len() > 1 {
+            gso::coalesce(&packets[..])
+                .into_iter()
+                .map(|packet| match packet {
+                    Coalesced::Packet(packet) => inbound.write(packet),
+                    Coalesced::Super(packet, offload) => inbound.write_offload(&packet, &offload),
+                })
+                .collect()
+        } else {
+            packets.iter().map(|packet| inbound.write(packet)).collect()
+        };
+        for res in written {
+            if let Err(e) = res {
+                log::debug!("failed to write inbound packet to TUN: {:?}", e);
             }
         }
 
-        // trigger callback
-        C::recv(&peer.opaque, msg.1.len(), true, &job.state.keypair);
+        // trigger callbacks
+        for (job, accepted) in jobs.iter().zip(accepted) {
+            if let Some((size, _)) = accepted {
+                C::recv(&peer.opaque, size, true, &job.0.state.keypair);
+            }
+        }
     }
 }
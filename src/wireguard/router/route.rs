@@ -37,11 +37,29 @@ impl<T: Eq + Clone> RoutingTable<T> {
         res
     }
 
-    pub fn insert(&self, ip: IpAddr, cidr: u32, value: T) {
+    /// Map a prefix to a value
+    ///
+    /// # Returns
+    ///
+    /// The value previously mapped to the exact same prefix (if any)
+    pub fn insert(&self, ip: IpAddr, cidr: u32, value: T) -> Option<T> {
         match ip {
             IpAddr::V4(v4) => self.ipv4.write().insert(v4.mask(cidr), cidr, value),
             IpAddr::V6(v6) => self.ipv6.write().insert(v6.mask(cidr), cidr, value),
-        };
+        }
+    }
+
+    /// Remove a single prefix
+    ///
+    /// # Returns
+    ///
+    /// The value mapped to the prefix (if any)
+    #[cfg(test)]
+    pub fn remove_prefix(&self, ip: IpAddr, cidr: u32) -> Option<T> {
+        match ip {
+            IpAddr::V4(v4) => self.ipv4.write().remove(v4.mask(cidr), cidr),
+            IpAddr::V6(v6) => self.ipv6.write().remove(v6.mask(cidr), cidr),
+        }
     }
 
     pub fn list(&self, value: &T) -> Vec<(IpAddr, u32)> {
@@ -71,6 +89,39 @@ impl<T: Eq + Clone> RoutingTable<T> {
         }
     }
 
+    /// Map exactly the given prefixes to the value: prefixes no longer included are removed,
+    /// while the remaining prefixes are never missing from the table (as during a remove and insert).
+    pub fn replace(&self, value: &T, prefixes: &[(IpAddr, u32)]) {
+        let mut v4: Vec<(Ipv4Addr, u32)> = vec![];
+        let mut v6: Vec<(Ipv6Addr, u32)> = vec![];
+        for (ip, cidr) in prefixes.iter() {
+            match ip {
+                IpAddr::V4(ip) => v4.push((ip.mask(*cidr), *cidr)),
+                IpAddr::V6(ip) => v6.push((ip.mask(*cidr), *cidr)),
+            }
+        }
+
+        let mut table = self.ipv4.write();
+        for (ip, cidr) in Self::collect(&*table, value) {
+            if !v4.contains(&(ip, cidr)) {
+                table.remove(ip, cidr);
+            }
+        }
+        for (ip, cidr) in v4 {
+            table.insert(ip, cidr, value.clone());
+        }
+
+        let mut table = self.ipv6.write();
+        for (ip, cidr) in Self::collect(&*table, value) {
+            if !v6.contains(&(ip, cidr)) {
+                table.remove(ip, cidr);
+            }
+        }
+        for (ip, cidr) in v6 {
+            table.insert(ip, cidr, value.clone());
+        }
+    }
+
     #[inline(always)]
     pub fn get_route(&self, packet: &[u8]) -> Option<T> {
         match packet.get(0)? >> 4 {
@@ -123,7 +174,7 @@ impl<T: Eq + Clone> RoutingTable<T> {
                         .longest_match(Ipv4Addr::from(header.f_source))
                         .map(|(_, _, p)| p == peer)
                 })
-                .is_some(),
+                .unwrap_or(false),
 
             Some(VERSION_IP6) => LayoutVerified::new_from_prefix(packet)
                 .and_then(|(header, _): (LayoutVerified<&[u8], IPv6Header>, _)| {
@@ -132,8 +183,128 @@ impl<T: Eq + Clone> RoutingTable<T> {
                         .longest_match(Ipv6Addr::from(header.f_source))
                         .map(|(_, _, p)| p == peer)
                 })
-                .is_some(),
+                .unwrap_or(false),
             _ => false,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ipv4(dst: &str) -> Vec<u8> {
+        let mut packet = vec![0u8; 20];
+        packet[0] = VERSION_IP4 << 4 | 5;
+        let dst: Ipv4Addr = dst.parse().unwrap();
+        packet[16..20].copy_from_slice(&dst.octets());
+        packet
+    }
+
+    fn ipv6(dst: &str) -> Vec<u8> {
+        let mut packet = vec![0u8; 40];
+        packet[0] = VERSION_IP6 << 4;
+        let dst: Ipv6Addr = dst.parse().unwrap();
+        packet[24..40].copy_from_slice(&dst.octets());
+        packet
+    }
+
+    #[test]
+    fn test_longest_prefix_match() {
+        let table: RoutingTable<usize> = RoutingTable::new();
+        assert_eq!(table.insert("0.0.0.0".parse().unwrap(), 0, 1), None);
+        assert_eq!(table.insert("10.0.0.0".parse().unwrap(), 8, 2), None);
+        assert_eq!(table.insert("10.1.0.0".parse().unwrap(), 16, 3), None);
+        assert_eq!(table.insert("2001:db8::".parse().unwrap(), 32, 4), None);
+
+        assert_eq!(table.get_route(&ipv4("192.0.2.1")), Some(1));
+        assert_eq!(table.get_route(&ipv4("10.2.0.1")), Some(2));
+        assert_eq!(table.get_route(&ipv4("10.1.2.3")), Some(3));
+        assert_eq!(table.get_route(&ipv6("2001:db8::1")), Some(4));
+        assert_eq!(table.get_route(&ipv6("2001:db9::1")), None);
+
+        // truncated and non-IP packets are not routed
+        assert_eq!(table.get_route(&ipv4("10.1.2.3")[..19]), None);
+        assert_eq!(table.get_route(&[0u8; 40]), None);
+    }
+
+    #[test]
+    fn test_insert_remove_displaced() {
+        let table: RoutingTable<usize> = RoutingTable::new();
+
+        // host bits are masked, the prefix is displaced from the previous value
+        assert_eq!(table.insert("10.1.0.0".parse().unwrap(), 16, 1), None);
+        assert_eq!(table.insert("10.1.2.3".parse().unwrap(), 16, 2), Some(1));
+        assert_eq!(table.list(&1), vec![]);
+        assert_eq!(table.list(&2), vec![("10.1.0.0".parse().unwrap(), 16)]);
+
+        // remove a single prefix
+        assert_eq!(table.remove_prefix("10.1.0.0".parse().unwrap(), 8), None);
+        assert_eq!(
+            table.remove_prefix("10.1.0.0".parse().unwrap(), 16),
+            Some(2)
+        );
+        assert_eq!(table.get_route(&ipv4("10.1.2.3")), None);
+
+        // remove every prefix of a value
+        table.insert("10.1.0.0".parse().unwrap(), 16, 3);
+        table.insert("2001:db8::".parse().unwrap(), 32, 3);
+        table.remove(&3);
+        assert_eq!(table.list(&3), vec![]);
+    }
+
+    #[test]
+    fn test_replace() {
+        let table: RoutingTable<usize> = RoutingTable::new();
+        table.insert("10.1.0.0".parse().unwrap(), 16, 1);
+        table.insert("10.2.0.0".parse().unwrap(), 16, 1);
+        table.insert("10.3.0.0".parse().unwrap(), 16, 2);
+
+        // the prefixes of other values are retained (unless taken over)
+        table.replace(
+            &1,
+            &[
+                ("10.2.0.0".parse().unwrap(), 16),
+                ("2001:db8::1".parse().unwrap(), 32),
+            ],
+        );
+        let mut list = table.list(&1);
+        list.sort();
+        assert_eq!(
+            list,
+            vec![
+                ("10.2.0.0".parse().unwrap(), 16),
+                ("2001:db8::".parse().unwrap(), 32)
+            ]
+        );
+        assert_eq!(table.get_route(&ipv4("10.1.0.1")), None);
+        assert_eq!(table.get_route(&ipv4("10.3.0.1")), Some(2));
+
+        table.replace(&2, &[]);
+        assert_eq!(table.list(&2), vec![]);
+    }
+
+    #[test]
+    fn test_check_route() {
+        let table: RoutingTable<usize> = RoutingTable::new();
+        table.insert("10.0.0.0".parse().unwrap(), 8, 1);
+        table.insert("10.1.0.0".parse().unwrap(), 16, 2);
+
+        // the source address is checked against the routes of the peer
+        let mut packet = vec![0u8; 20];
+        packet[0] = VERSION_IP4 << 4 | 5;
+        packet[12..16].copy_from_slice(&[10, 2, 0, 1]);
+        assert!(table.check_route(&1, &packet));
+        assert!(!table.check_route(&2, &packet));
+
+        // the longest prefix belongs to another peer
+        packet[12..16].copy_from_slice(&[10, 1, 0, 1]);
+        assert!(!table.check_route(&1, &packet));
+        assert!(table.check_route(&2, &packet));
+
+        // no route and truncated packets
+        packet[12..16].copy_from_slice(&[192, 0, 2, 1]);
+        assert!(!table.check_route(&1, &packet));
+        assert!(!table.check_route(&1, &packet[..16]));
+    }
+}
@@ -9,6 +9,7 @@ use super::super::{tun, udp, Endpoint};
 
 use alloc::sync::Arc;
 use core::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
 
 use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
 use spin::Mutex;
@@ -20,6 +21,7 @@ struct Inner<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> {
     counter: u64,
     keypair: Arc<KeyPair>,
     peer: Peer<E, C, T, B>,
+    tos: u8, // traffic class of the outer packet
 }
 
 pub struct SendJob<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>>(
@@ -38,12 +40,14 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> SendJob<E, C,
         counter: u64,
         keypair: Arc<KeyPair>,
         peer: Peer<E, C, T, B>,
+        tos: u8,
     ) -> SendJob<E, C, T, B> {
         SendJob(Arc::new(Inner {
             buffer: Mutex::new(buffer),
             counter,
             keypair,
             peer,
+            tos,
             ready: AtomicBool::new(false),
         }))
     }
@@ -81,7 +85,8 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> ParallelJob
                 job.counter < REJECT_AFTER_MESSAGES,
                 "should be checked when assigning counters"
             );
-            header.f_type.set(TYPE_TRANSPORT);
+            let reserved = *job.peer.device.reserved.read();
+            header.f_type.set(reserved.encode(TYPE_TRANSPORT));
             header.f_receiver.set(job.keypair.send.id);
             header.f_counter.set(job.counter);
 
@@ -117,20 +122,52 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> SequentialJob
     }
 
     fn sequential_work(self) {
-        debug_assert_eq!(
-            self.is_ready(),
-            true,
-            "doing sequential work 
-            on an incomplete job"
-        );
-        log::trace!("processing sequential send job");
+        Self::sequential_batch(vec![self])
+    }
 
-        // send to peer
-        let job = &self.0;
-        let msg = job.buffer.lock();
-        let xmit = job.peer.send_raw(&msg[..]).is_ok();
+    fn sequential_batch(jobs: Vec<Self>) {
+        debug_assert!(
+            jobs.iter().all(|job| job.is_ready()),
+            "doing sequential work on an incomplete job"
+        );
+        log::trace!("processing sequential send batch ({} jobs)", jobs.len());
+
+        // every job in the queue belongs to the same peer
+        let peer = match jobs.first() {
+            Some(job) => &job.0.peer,
+            None => return,
+        };
+
+        // send to peer (consecutive messages of the same traffic class in a single batch)
+        let msgs: Vec<_> = jobs.iter().map(|job| job.0.buffer.lock()).collect();
+        let mut xmit = vec![false; jobs.len()];
+        let mut start = 0;
+        while start < jobs.len() {
+            let tos = jobs[start].0.tos;
+            let end = start
+                + jobs[start..]
+                    .iter()
+                    .take_while(|job| job.0.tos == tos)
+                    .count();
+            let bufs: Vec<&[u8]> = msgs[start..end].iter().map(|msg| &msg[..]).collect();
+            if peer.send_raw_batch(&bufs[..], tos).is_ok() {
+                *peer.last_sent.lock() = Some(Instant::now());
+                for sent in xmit[start..end].iter_mut() {
+                    *sent = true;
+                }
+            }
+            start = end;
+        }
 
-        // trigger callback (for timers)
-        C::send(&job.peer.opaque, msg.len(), xmit, &job.keypair, job.counter);
+        // trigger callbacks (for timers)
+        for ((job, msg), xmit) in jobs.iter().zip(msgs.iter()).zip(xmit) {
+            C::send(
+                &job.0.peer.opaque,
+                msg.len(),
+                xmit,
+                &job.0.keypair,
+                job.0.counter,
+            );
+        }
     }
 }
@@ -0,0 +1,152 @@
+use std::collections::VecDeque;
+
+use super::constants::{MAX_QUEUED_PACKETS, MAX_STAGED_PACKETS};
+use super::types::DropPolicy;
+
+/* Bounded queue of outbound packets awaiting a key-pair (per peer)
+ *
+ * Packets are transmitted in order once a key-pair becomes available,
+ * when the queue overflows the drop policy decides which packet is discarded.
+ */
+pub struct StagedPackets {
+    queue: VecDeque<Vec<u8>>,
+    depth: usize,
+    policy: DropPolicy,
+}
+
+impl Default for StagedPackets {
+    fn default() -> Self {
+        StagedPackets::new()
+    }
+}
+
+impl StagedPackets {
+    pub fn new() -> Self {
+        StagedPackets {
+            queue: VecDeque::new(),
+            depth: MAX_STAGED_PACKETS,
+            policy: DropPolicy::default(),
+        }
+    }
+
+    /// Change the depth and drop policy of the queue
+    ///
+    /// # Arguments
+    ///
+    /// - `depth`: Maximum number of staged packets, clamped to [1, MAX_QUEUED_PACKETS]
+    /// - `policy`: Which packets to drop when the queue is full
+    pub fn configure(&mut self, depth: usize, policy: DropPolicy) {
+        self.depth = depth.clamp(1, MAX_QUEUED_PACKETS);
+        self.policy = policy;
+        while self.queue.len() > self.depth {
+            self.drop_one();
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn policy(&self) -> DropPolicy {
+        self.policy
+    }
+
+    // Internal function
+    //
+    // Discard a single packet according to the policy
+    fn drop_one(&mut self) {
+        log::debug!("staged packet dropped ({:?})", self.policy);
+        match self.policy {
+            DropPolicy::Head => self.queue.pop_front(),
+            DropPolicy::Tail => self.queue.pop_back(),
+        };
+    }
+
+    /// Stage a packet
+    ///
+    /// # Returns
+    ///
+    /// True if the packet was staged, false if it was dropped (tail-drop)
+    pub fn push(&mut self, msg: Vec<u8>) -> bool {
+        if self.queue.len() < self.depth {
+            self.queue.push_back(msg);
+            return true;
+        }
+        match self.policy {
+            DropPolicy::Head => {
+                self.drop_one();
+                self.queue.push_back(msg);
+                true
+            }
+            DropPolicy::Tail => {
+                log::debug!("staged packet dropped ({:?})", self.policy);
+                false
+            }
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        self.queue.pop_front()
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.queue.clear()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drain(staged: &mut StagedPackets) -> Vec<u8> {
+        let mut res = vec![];
+        while let Some(msg) = staged.pop() {
+            res.push(msg[0]);
+        }
+        res
+    }
+
+    #[test]
+    fn test_staged_drop_policy() {
+        // head-drop: keep the newest packets
+        let mut staged = StagedPackets::new();
+        staged.configure(3, DropPolicy::Head);
+        for i in 0..5 {
+            assert!(staged.push(vec![i]));
+        }
+        assert_eq!(drain(&mut staged), vec![2, 3, 4]);
+
+        // tail-drop: keep the oldest packets
+        staged.configure(3, DropPolicy::Tail);
+        for i in 0..5 {
+            assert_eq!(staged.push(vec![i]), i < 3);
+        }
+        assert_eq!(drain(&mut staged), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_staged_configure() {
+        let mut staged = StagedPackets::new();
+        assert_eq!(staged.depth(), MAX_STAGED_PACKETS);
+        assert_eq!(staged.policy(), DropPolicy::Head);
+
+        // shrinking the queue applies the policy to the staged packets
+        for i in 0..5 {
+            staged.push(vec![i]);
+        }
+        staged.configure(2, DropPolicy::Tail);
+        assert_eq!(staged.len(), 2);
+        assert_eq!(drain(&mut staged), vec![0, 1]);
+
+        // the depth is bounded
+        staged.configure(0, DropPolicy::Head);
+        assert_eq!(staged.depth(), 1);
+        staged.configure(usize::MAX, DropPolicy::Head);
+        assert_eq!(staged.depth(), MAX_QUEUED_PACKETS);
+    }
+}
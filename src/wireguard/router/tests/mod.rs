@@ -1,12 +1,16 @@
 mod bench;
 mod tests;
 
+use super::constants::{MAX_STAGED_PACKETS, PATH_MTU_EXPIRY};
 use super::message_data_len;
+use super::types::RouterError;
 use super::SIZE_MESSAGE_PREFIX;
-use super::{Callbacks, Device};
+use super::{Callbacks, Device, DropPolicy, SourcePolicy};
 use super::{Key, KeyPair};
 
+use super::super::constants::{REJECT_AFTER_MESSAGES, REJECT_AFTER_TIME, REKEY_AFTER_MESSAGES};
 use super::super::dummy;
+use super::super::secret::Secret;
 use super::super::tests::make_packet;
 
 use std::time::Instant;
@@ -21,13 +25,40 @@ fn pad(msg: &[u8]) -> Vec<u8> {
     o
 }
 
+// key-pair of the given generation (distinct keys and ids)
+pub fn generation_keypair(initiator: bool, n: u8) -> KeyPair {
+    let k1 = Key {
+        key: Secret::new([n; 32]),
+        id: 2 * n as u32,
+    };
+    let k2 = Key {
+        key: Secret::new([n ^ 0xff; 32]),
+        id: 2 * n as u32 + 1,
+    };
+    if initiator {
+        KeyPair {
+            birth: Instant::now(),
+            initiator: true,
+            send: k1,
+            recv: k2,
+        }
+    } else {
+        KeyPair {
+            birth: Instant::now(),
+            initiator: false,
+            send: k2,
+            recv: k1,
+        }
+    }
+}
+
 pub fn dummy_keypair(initiator: bool) -> KeyPair {
     let k1 = Key {
-        key: [0x53u8; 32],
+        key: Secret::new([0x53u8; 32]),
         id: 0x646e6573,
     };
     let k2 = Key {
-        key: [0x52u8; 32],
+        key: Secret::new([0x52u8; 32]),
         id: 0x76636572,
     };
     if initiator {
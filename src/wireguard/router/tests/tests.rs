@@ -1,6 +1,6 @@
 use crate::platform::udp::Reader;
 
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::ops::Deref;
 use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
 use std::sync::Arc;
@@ -480,3 +480,662 @@ fn test_bidirectional() {
         }
     }
 }
+
+#[test]
+fn test_keepalive() {
+    init();
+
+    let ((bind_reader1, bind_writer1), (_bind_reader2, bind_writer2)) = dummy::PairBind::pair();
+
+    // create matching devices (storing packets written to the TUN device of router1)
+    let (fake1, _, tun_writer1, _) = dummy::TunTest::create(true);
+    let (_fake, _, tun_writer2, _) = dummy::TunTest::create(false);
+
+    let router1: Device<_, TestCallbacks, _, _> = Device::new(1, tun_writer1);
+    router1.set_outbound_writer(bind_writer1);
+
+    let router2: Device<_, TestCallbacks, _, _> = Device::new(1, tun_writer2);
+    router2.set_outbound_writer(bind_writer2);
+
+    let opaque1 = Opaque::new();
+    let opaque2 = Opaque::new();
+
+    let peer1 = router1.new_peer(opaque1.clone());
+    let peer2 = router2.new_peer(opaque2.clone());
+
+    peer1.add_allowed_ip("192.168.2.0".parse().unwrap(), 24);
+    peer1.add_keypair(dummy_keypair(false));
+    peer2.add_allowed_ip("192.168.1.0".parse().unwrap(), 24);
+    peer2.set_endpoint(dummy::UnitEndpoint::new());
+    peer2.add_keypair(dummy_keypair(true));
+
+    assert_eq!(peer2.last_sent(), None);
+    assert_eq!(peer1.last_received(), None);
+
+    // the key is confirmed by a keepalive, followed by an explicit keepalive
+    for confirm in [true, false] {
+        if !confirm {
+            peer2.send_keepalive();
+        }
+
+        assert_eq!(
+            opaque2.send.wait(TIMEOUT),
+            Some((SIZE_KEEPALIVE, true)),
+            "expected transmission of a keepalive"
+        );
+
+        let mut buf = vec![0u8; SIZE_MSG];
+        let (len, from) = bind_reader1.read(&mut buf).unwrap();
+        buf.truncate(len);
+        assert_eq!(len, SIZE_KEEPALIVE);
+        router1.recv(from, buf).unwrap();
+
+        assert_eq!(
+            opaque1.recv.wait(TIMEOUT),
+            Some((SIZE_KEEPALIVE, true)),
+            "keepalive should be consumed"
+        );
+        if confirm {
+            assert_eq!(
+                opaque1.key_confirmed.wait(TIMEOUT),
+                Some(()),
+                "keepalive should confirm the key"
+            );
+        }
+        no_events!(opaque1);
+        no_events!(opaque2);
+
+        // transmission and reception are recorded for the passive keepalive
+        assert!(peer2.last_sent().unwrap() < peer1.last_received().unwrap());
+        assert_eq!(peer2.last_received(), None);
+        assert_eq!(peer1.last_sent(), None);
+    }
+
+    // keepalives are not written to the TUN device: the first write is the data packet
+    let msg = make_packet(
+        SIZE_MSG,
+        "192.168.2.20".parse().unwrap(), // src
+        "192.168.1.20".parse().unwrap(), // dst
+        0,
+    );
+    router2.send(pad(&msg)).unwrap();
+
+    let mut buf = vec![0u8; SIZE_MSG * 2];
+    let (len, from) = bind_reader1.read(&mut buf).unwrap();
+    buf.truncate(len);
+    router1.recv(from, buf).unwrap();
+    assert_eq!(
+        fake1.read(),
+        msg,
+        "only the data packet should be written to TUN"
+    );
+}
+
+#[test]
+fn test_source_policy() {
+    init();
+
+    let ((bind_reader1, bind_writer1), (_bind_reader2, bind_writer2)) = dummy::PairBind::pair();
+
+    // create matching devices (storing packets written to the TUN device of router1)
+    let (fake1, _, tun_writer1, _) = dummy::TunTest::create(true);
+    let (_fake, _, tun_writer2, _) = dummy::TunTest::create(false);
+
+    let router1: Device<_, TestCallbacks, _, _> = Device::new(1, tun_writer1);
+    router1.set_outbound_writer(bind_writer1);
+
+    let router2: Device<_, TestCallbacks, _, _> = Device::new(1, tun_writer2);
+    router2.set_outbound_writer(bind_writer2);
+
+    let peer1 = router1.new_peer(Opaque::new());
+    let peer2 = router2.new_peer(Opaque::new());
+
+    peer1.add_allowed_ip("192.168.2.0".parse().unwrap(), 24);
+    peer1.add_keypair(dummy_keypair(false));
+    peer2.add_allowed_ip("192.168.1.0".parse().unwrap(), 24);
+    peer2.set_endpoint(dummy::UnitEndpoint::new());
+    peer2.add_keypair(dummy_keypair(true));
+
+    // consume the keepalive confirming the key
+    let mut buf = vec![0u8; SIZE_MSG];
+    let (len, from) = bind_reader1.read(&mut buf).unwrap();
+    buf.truncate(len);
+    router1.recv(from, buf).unwrap();
+
+    let transfer = |src: &str| {
+        let msg = make_packet(
+            SIZE_MSG,
+            src.parse().unwrap(),
+            "192.168.1.20".parse().unwrap(),
+            0,
+        );
+        router2.send(pad(&msg)).unwrap();
+        let mut buf = vec![0u8; SIZE_MSG * 2];
+        let (len, from) = bind_reader1.read(&mut buf).unwrap();
+        buf.truncate(len);
+        router1.recv(from, buf).unwrap();
+        msg
+    };
+
+    // strict: the spoofed packet is dropped (the first write is the valid packet)
+    assert_eq!(router1.get_source_policy(), SourcePolicy::Strict);
+    transfer("10.0.0.1");
+    let msg = transfer("192.168.2.20");
+    assert_eq!(fake1.read(), msg, "spoofed packet should be dropped");
+    assert_eq!(router1.source_violations(), 1);
+
+    // permissive: the spoofed packet is delivered, but counted
+    router1.set_source_policy(SourcePolicy::Permissive);
+    let msg = transfer("10.0.0.1");
+    assert_eq!(fake1.read(), msg, "spoofed packet should be delivered");
+    assert_eq!(router1.source_violations(), 2);
+}
+
+#[test]
+fn test_replay_window() {
+    init();
+
+    // deliver the first transport message after `delay` later messages of a live key-pair
+    // and return if it is accepted
+    let accepts_late = |window: Option<u64>, delay: usize| {
+        let ((bind_reader1, bind_writer1), (_bind_reader2, bind_writer2)) = dummy::PairBind::pair();
+        let (_fake1, _, tun_writer1, _) = dummy::TunTest::create(false);
+        let (_fake2, _, tun_writer2, _) = dummy::TunTest::create(false);
+
+        let router1: Device<_, TestCallbacks, _, _> = Device::new(1, tun_writer1);
+        router1.set_outbound_writer(bind_writer1);
+        let router2: Device<_, TestCallbacks, _, _> = Device::new(1, tun_writer2);
+        router2.set_outbound_writer(bind_writer2);
+        if let Some(window) = window {
+            router1.set_replay_window(window);
+        }
+
+        let opaque1 = Opaque::new();
+        let peer1 = router1.new_peer(opaque1.clone());
+        let peer2 = router2.new_peer(Opaque::new());
+        peer1.add_allowed_ip("192.168.2.0".parse().unwrap(), 24);
+        peer1.add_keypair(dummy_keypair(false));
+        peer2.add_allowed_ip("192.168.1.0".parse().unwrap(), 24);
+        peer2.set_endpoint(dummy::UnitEndpoint::new());
+        peer2.add_keypair(dummy_keypair(true));
+
+        // capture the keepalive confirming the key and the transport messages (in order),
+        // in batches fitting the channel of the bind
+        let msg = make_packet(
+            64,
+            "192.168.2.20".parse().unwrap(),
+            "192.168.1.20".parse().unwrap(),
+            0,
+        );
+        let read = || {
+            let mut buf = vec![0u8; SIZE_MSG];
+            let (len, from) = bind_reader1.read(&mut buf).unwrap();
+            buf.truncate(len);
+            (from, buf)
+        };
+        let mut msgs = vec![read()];
+        while msgs.len() < delay + 2 {
+            let batch = (delay + 2 - msgs.len()).min(64);
+            for _ in 0..batch {
+                router2.send(pad(&msg)).unwrap();
+            }
+            for _ in 0..batch {
+                msgs.push(read());
+            }
+        }
+
+        // the keepalive and the later messages are accepted
+        // (in batches fitting the inbound queue of the peer)
+        let late = msgs.remove(1);
+        for batch in msgs.chunks(64) {
+            for (from, buf) in batch {
+                router1.recv(*from, buf.clone()).unwrap();
+            }
+            for _ in batch {
+                assert!(opaque1.recv.wait(TIMEOUT).is_some());
+            }
+        }
+        router1.recv(late.0, late.1).unwrap();
+        opaque1.recv.wait(TIMEOUT).is_some()
+    };
+
+    // reordering beyond the default window is rejected, unless the window is enlarged
+    assert!(accepts_late(None, 1000));
+    assert!(!accepts_late(None, 3000));
+    assert!(accepts_late(Some(4000), 3000));
+}
+
+#[test]
+fn test_expired_key() {
+    init();
+
+    let (_fake, _reader, tun_writer, _mtu) = dummy::TunTest::create(false);
+    let router: Device<_, TestCallbacks, _, _> = Device::new(1, tun_writer);
+    router.set_outbound_writer(dummy::VoidBind::new());
+
+    let opaque = Opaque::new();
+    let peer = router.new_peer(opaque.clone());
+    peer.add_allowed_ip("192.168.1.0".parse().unwrap(), 24);
+
+    // a key-pair past its lifetime is never used for encryption
+    let mut expired = generation_keypair(true, 1);
+    expired.birth = Instant::now()
+        .checked_sub(REJECT_AFTER_TIME)
+        .expect("monotonic clock should exceed the key-pair age");
+    peer.add_keypair(expired);
+    assert_eq!(
+        opaque.need_key.wait(TIMEOUT),
+        Some(()),
+        "keepalive should request a new key"
+    );
+
+    // data demand stages the packet and requests a new key
+    let msg = make_packet(
+        SIZE_MSG,
+        "127.0.0.1".parse().unwrap(),
+        "192.168.1.20".parse().unwrap(),
+        0,
+    );
+    router.send(pad(&msg)).unwrap();
+    assert_eq!(
+        opaque.need_key.wait(TIMEOUT),
+        Some(()),
+        "should have requested a new key for the expired key-pair"
+    );
+    no_events!(opaque);
+
+    // the staged packet is transmitted once a fresh key-pair is added
+    peer.add_keypair(dummy_keypair(true));
+    assert_eq!(
+        opaque.send.wait(TIMEOUT),
+        Some((SIZE_KEEPALIVE + msg.len(), false)),
+        "staged packet should be encrypted"
+    );
+    no_events!(opaque);
+}
+
+#[test]
+fn test_expired_key_recv() {
+    init();
+
+    let ((bind_reader1, bind_writer1), (_bind_reader2, bind_writer2)) = dummy::PairBind::pair();
+
+    let (_fake1, _, tun_writer1, _) = dummy::TunTest::create(false);
+    let (_fake2, _, tun_writer2, _) = dummy::TunTest::create(false);
+
+    let router1: Device<_, TestCallbacks, _, _> = Device::new(1, tun_writer1);
+    router1.set_outbound_writer(bind_writer1);
+
+    let router2: Device<_, TestCallbacks, _, _> = Device::new(1, tun_writer2);
+    router2.set_outbound_writer(bind_writer2);
+
+    let opaque1 = Opaque::new();
+    let opaque2 = Opaque::new();
+
+    let peer1 = router1.new_peer(opaque1.clone());
+    let peer2 = router2.new_peer(opaque2.clone());
+
+    // the responder holds a key-pair past its lifetime
+    let mut expired = dummy_keypair(false);
+    expired.birth = Instant::now()
+        .checked_sub(REJECT_AFTER_TIME)
+        .expect("monotonic clock should exceed the key-pair age");
+    peer1.add_keypair(expired);
+    peer2.set_endpoint(dummy::UnitEndpoint::new());
+    peer2.add_keypair(dummy_keypair(true));
+
+    assert_eq!(
+        opaque2.send.wait(TIMEOUT),
+        Some((SIZE_KEEPALIVE, true)),
+        "expected transmission of a keepalive"
+    );
+
+    let mut buf = vec![0u8; SIZE_MSG];
+    let (len, from) = bind_reader1.read(&mut buf).unwrap();
+    buf.truncate(len);
+
+    // the message is refused and the key-pair is wiped
+    match router1.recv(from, buf.clone()) {
+        Err(RouterError::KeyExpired) => (),
+        res => panic!("expected expired key, got {:?}", res),
+    }
+    match router1.recv(from, buf) {
+        Err(RouterError::UnknownReceiverId) => (),
+        res => panic!("expected unknown receiver id, got {:?}", res),
+    }
+    no_events!(opaque1);
+
+    // the id of the expired key-pair is released with the next key-pair
+    assert_eq!(
+        peer1.add_keypair(generation_keypair(false, 1)),
+        vec![dummy_keypair(false).local_id()]
+    );
+}
+
+#[test]
+fn test_sweep_keys() {
+    init();
+
+    let (_fake, _reader, tun_writer, _mtu) = dummy::TunTest::create(false);
+    let router: Device<_, TestCallbacks, _, _> = Device::new(1, tun_writer);
+    router.set_outbound_writer(dummy::VoidBind::new());
+
+    let opaque = Opaque::new();
+    let peer = router.new_peer(opaque.clone());
+
+    // an old (unconfirmed) key-pair and a fresh key-pair
+    let mut old = generation_keypair(false, 1);
+    old.birth = Instant::now()
+        .checked_sub(REJECT_AFTER_TIME * 3)
+        .expect("monotonic clock should exceed the key-pair age");
+    peer.add_keypair(old);
+    peer.add_keypair(generation_keypair(true, 2));
+    assert_eq!(
+        opaque.need_key.wait(TIMEOUT),
+        None,
+        "fresh key-pair should be available"
+    );
+
+    // only the old key-pair is wiped (and released with the next key-pair)
+    assert_eq!(router.sweep_keys(REJECT_AFTER_TIME * 3), 1);
+    assert_eq!(router.sweep_keys(REJECT_AFTER_TIME * 3), 0);
+    assert_eq!(
+        peer.add_keypair(generation_keypair(true, 3)),
+        vec![generation_keypair(false, 1).local_id()]
+    );
+}
+
+#[test]
+fn test_staged_packets() {
+    init();
+
+    let (_fake, _reader, tun_writer, _mtu) = dummy::TunTest::create(false);
+    let router: Device<_, TestCallbacks, _, _> = Device::new(1, tun_writer);
+    router.set_outbound_writer(dummy::VoidBind::new());
+
+    let opaque = Opaque::new();
+    let peer = router.new_peer(opaque.clone());
+    peer.add_allowed_ip("192.168.1.0".parse().unwrap(), 24);
+
+    // stage more packets than the queue holds (of distinct sizes)
+    let mut lengths = Vec::new();
+    for i in 0..MAX_STAGED_PACKETS + 8 {
+        let msg = make_packet(
+            64 + i,
+            "127.0.0.1".parse().unwrap(),
+            "192.168.1.20".parse().unwrap(),
+            0,
+        );
+        router.send(pad(&msg)).unwrap();
+        assert_eq!(opaque.need_key.wait(TIMEOUT), Some(()));
+        lengths.push(msg.len());
+    }
+    no_events!(opaque);
+
+    // the newest packets are transmitted in order once a key-pair is added
+    peer.add_keypair(dummy_keypair(true));
+    for len in lengths[lengths.len() - MAX_STAGED_PACKETS..].iter() {
+        assert_eq!(
+            opaque.send.wait(TIMEOUT),
+            Some((SIZE_KEEPALIVE + len, false)),
+            "staged packets should be transmitted in order"
+        );
+    }
+    no_events!(opaque);
+}
+
+#[test]
+fn test_staged_drop_policy() {
+    init();
+
+    let (_fake, _reader, tun_writer, _mtu) = dummy::TunTest::create(false);
+    let router: Device<_, TestCallbacks, _, _> = Device::new(1, tun_writer);
+    router.set_outbound_writer(dummy::VoidBind::new());
+
+    let opaque = Opaque::new();
+    let peer = router.new_peer(opaque.clone());
+    peer.add_allowed_ip("192.168.1.0".parse().unwrap(), 24);
+    assert_eq!(
+        peer.get_staged_packets(),
+        (MAX_STAGED_PACKETS, DropPolicy::Head)
+    );
+
+    // a shallow tail-drop queue keeps the oldest packets (of distinct sizes)
+    peer.set_staged_packets(4, DropPolicy::Tail);
+    assert_eq!(peer.get_staged_packets(), (4, DropPolicy::Tail));
+    let mut lengths = Vec::new();
+    for i in 0..6 {
+        let msg = make_packet(
+            64 + i,
+            "127.0.0.1".parse().unwrap(),
+            "192.168.1.20".parse().unwrap(),
+            0,
+        );
+        router.send(pad(&msg)).unwrap();
+        assert_eq!(opaque.need_key.wait(TIMEOUT), Some(()));
+        lengths.push(msg.len());
+    }
+    no_events!(opaque);
+
+    peer.add_keypair(dummy_keypair(true));
+    for len in lengths[..4].iter() {
+        assert_eq!(
+            opaque.send.wait(TIMEOUT),
+            Some((SIZE_KEEPALIVE + len, false)),
+            "the oldest staged packets should be transmitted in order"
+        );
+    }
+    no_events!(opaque);
+}
+
+#[test]
+fn test_path_mtu() {
+    init();
+
+    // create device (storing packets written to the TUN device)
+    let (fake, _reader, tun_writer, _mtu) = dummy::TunTest::create(true);
+    let router: Device<_, TestCallbacks, _, _> = Device::new(1, tun_writer);
+    router.set_outbound_writer(dummy::VoidBind::new());
+
+    let opaque = Opaque::new();
+    let peer = router.new_peer(opaque.clone());
+    peer.add_allowed_ip("192.168.1.0".parse().unwrap(), 24);
+    peer.set_endpoint(dummy::UnitEndpoint::new());
+    peer.add_keypair(dummy_keypair(true));
+    assert_eq!(opaque.send.wait(TIMEOUT), Some((SIZE_KEEPALIVE, true)));
+
+    // path MTU to the endpoint: 1000 - 20 (IPv4) - 8 (UDP) - 32 (transport overhead)
+    let addr = "127.0.0.1:8080".parse().unwrap();
+    router.set_path_mtu(addr, 1000);
+    assert_eq!(router.get_path_mtu(&addr), Some(1000));
+
+    // oversized packet with DF set is answered with "fragmentation needed"
+    let mut msg = make_packet(
+        1000,
+        "127.0.0.1".parse().unwrap(),
+        "192.168.1.20".parse().unwrap(),
+        0,
+    );
+    msg[0] = 0x45; // header length
+    msg[6] |= 0x40; // don't fragment
+    match router.send(pad(&msg)) {
+        Err(RouterError::PacketTooBig) => (),
+        res => panic!("expected packet too big, got {:?}", res.err()),
+    }
+    let icmp = fake.read();
+    assert_eq!(
+        &icmp[12..16],
+        &msg[16..20],
+        "ICMP should originate at the destination"
+    );
+    assert_eq!(&icmp[16..20], &msg[12..16]);
+    assert_eq!((icmp[20], icmp[21]), (3, 4));
+    assert_eq!(u16::from_be_bytes([icmp[26], icmp[27]]), 940);
+    no_events!(opaque);
+
+    // packets within the path MTU are transmitted
+    let msg = make_packet(
+        900,
+        "127.0.0.1".parse().unwrap(),
+        "192.168.1.20".parse().unwrap(),
+        0,
+    );
+    router.send(pad(&msg)).unwrap();
+    assert_eq!(
+        opaque.send.wait(TIMEOUT),
+        Some((SIZE_KEEPALIVE + msg.len(), true))
+    );
+
+    // expired entries are dropped by the sweep, fresh entries are kept
+    let stale: SocketAddr = "127.0.0.2:8080".parse().unwrap();
+    let updated = Instant::now()
+        .checked_sub(PATH_MTU_EXPIRY)
+        .expect("monotonic clock should exceed the path MTU expiry");
+    router.state.path_mtu.write().insert(stale, (1000, updated));
+    assert_eq!(router.get_path_mtu(&stale), None);
+    router.sweep_keys(REJECT_AFTER_TIME * 3);
+    assert!(!router.state.path_mtu.read().contains_key(&stale));
+    assert_eq!(router.get_path_mtu(&addr), Some(1000));
+}
+
+#[test]
+fn test_key_rotation() {
+    init();
+
+    let ((bind_reader1, bind_writer1), (bind_reader2, bind_writer2)) = dummy::PairBind::pair();
+
+    // create matching devices (storing packets written to the TUN devices)
+    let (fake1, _, tun_writer1, _) = dummy::TunTest::create(true);
+    let (fake2, _, tun_writer2, _) = dummy::TunTest::create(true);
+
+    let router1: Device<_, TestCallbacks, _, _> = Device::new(1, tun_writer1);
+    router1.set_outbound_writer(bind_writer1);
+
+    let router2: Device<_, TestCallbacks, _, _> = Device::new(1, tun_writer2);
+    router2.set_outbound_writer(bind_writer2);
+
+    let opaque2 = Opaque::new();
+
+    let peer1 = router1.new_peer(Opaque::new());
+    let peer2 = router2.new_peer(opaque2.clone());
+
+    peer1.add_allowed_ip("192.168.2.0".parse().unwrap(), 24);
+    peer1.set_endpoint(dummy::UnitEndpoint::new());
+    peer2.add_allowed_ip("192.168.1.0".parse().unwrap(), 24);
+    peer2.set_endpoint(dummy::UnitEndpoint::new());
+
+    // deliver the next transport message written to the bind
+    macro_rules! transfer {
+        ($reader:expr, $router:expr) => {{
+            let mut buf = vec![0u8; SIZE_MSG * 2];
+            let (len, from) = $reader.read(&mut buf).unwrap();
+            buf.truncate(len);
+            let _ = $router.recv(from, buf);
+        }};
+    }
+
+    let msg1 = make_packet(
+        SIZE_MSG,
+        "192.168.1.20".parse().unwrap(),
+        "192.168.2.20".parse().unwrap(),
+        1,
+    );
+    let msg2 = make_packet(
+        SIZE_MSG,
+        "192.168.2.20".parse().unwrap(),
+        "192.168.1.20".parse().unwrap(),
+        2,
+    );
+
+    // generation 1: router1 initiates, the keepalive confirms the next key of router2
+    assert_eq!(
+        peer2.add_keypair(generation_keypair(false, 1)),
+        Vec::<u32>::new()
+    );
+    assert_eq!(
+        peer1.add_keypair(generation_keypair(true, 1)),
+        Vec::<u32>::new()
+    );
+    transfer!(bind_reader2, router2);
+    assert_eq!(opaque2.key_confirmed.wait(TIMEOUT), Some(()));
+
+    // generation 2: router1 rekeys, the current key is kept as previous
+    assert_eq!(
+        peer1.add_keypair(generation_keypair(true, 2)),
+        Vec::<u32>::new()
+    );
+    transfer!(bind_reader2, router2); // keepalive for an unknown key
+
+    // packets encrypted under the previous key are still accepted
+    router2.send(pad(&msg2)).unwrap();
+    transfer!(bind_reader1, router1);
+    assert_eq!(fake1.read(), msg2, "previous key should be accepted");
+
+    // the responder uses the current key until the next key is confirmed
+    assert_eq!(
+        peer2.add_keypair(generation_keypair(false, 2)),
+        Vec::<u32>::new()
+    );
+    router2.send(pad(&msg2)).unwrap();
+    transfer!(bind_reader1, router1);
+    assert_eq!(
+        fake1.read(),
+        msg2,
+        "current key should be used until confirmed"
+    );
+
+    // the next key is promoted on first use
+    router1.send(pad(&msg1)).unwrap();
+    transfer!(bind_reader2, router2);
+    assert_eq!(fake2.read(), msg1);
+    assert_eq!(opaque2.key_confirmed.wait(TIMEOUT), Some(()));
+
+    // generation 3: the previous keys (generation 1) are released
+    assert_eq!(peer2.add_keypair(generation_keypair(false, 3)), vec![2]);
+    assert_eq!(peer1.add_keypair(generation_keypair(true, 3)), vec![3]);
+
+    // an unconfirmed next key is replaced and released
+    assert_eq!(peer2.add_keypair(generation_keypair(false, 4)), vec![6]);
+}
+
+#[test]
+fn test_message_limits() {
+    init();
+
+    let (_fake, _reader, tun_writer, _mtu) = dummy::TunTest::create(false);
+    let router: Device<_, TestCallbacks, _, _> = Device::new(1, tun_writer);
+    router.set_outbound_writer(dummy::VoidBind::new());
+
+    let opaque = Opaque::new();
+    let peer = router.new_peer(opaque.clone());
+    peer.add_allowed_ip("192.168.1.0".parse().unwrap(), 24);
+    peer.add_keypair(dummy_keypair(true));
+    assert_eq!(opaque.send.wait(TIMEOUT), Some((SIZE_KEEPALIVE, false)));
+
+    let msg = make_packet(
+        SIZE_MSG,
+        "127.0.0.1".parse().unwrap(),
+        "192.168.1.20".parse().unwrap(),
+        0,
+    );
+
+    // past the rekey threshold the key is still used (the handshake is triggered by the callback)
+    peer.enc_key.lock().as_mut().unwrap().nonce = REKEY_AFTER_MESSAGES + 1;
+    router.send(pad(&msg)).unwrap();
+    assert_eq!(
+        opaque.send.wait(TIMEOUT),
+        Some((SIZE_KEEPALIVE + msg.len(), false))
+    );
+    no_events!(opaque);
+
+    // at the reject threshold the key is discarded, and the packet staged
+    peer.enc_key.lock().as_mut().unwrap().nonce = REJECT_AFTER_MESSAGES - 1;
+    router.send(pad(&msg)).unwrap();
+    assert_eq!(
+        opaque.need_key.wait(TIMEOUT),
+        Some(()),
+        "should have requested a new key at the reject threshold"
+    );
+    assert!(peer.enc_key.lock().is_none());
+    no_events!(opaque);
+}
@@ -34,6 +34,27 @@ pub trait Callbacks: Send + Sync + 'static {
     fn key_confirmed(opaque: &Self::Opaque);
 }
 
+/// Policy for inbound packets whose inner source address
+/// is not among the allowed IPs of the sending peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SourcePolicy {
+    /// Drop the packet (cryptokey routing)
+    #[default]
+    Strict,
+    /// Deliver the packet (the violation is still counted)
+    Permissive,
+}
+
+/// Packet to drop when the staging queue of a peer is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DropPolicy {
+    /// Drop the oldest staged packet (keep the newest)
+    #[default]
+    Head,
+    /// Drop the new packet
+    Tail,
+}
+
 #[derive(Debug)]
 pub enum RouterError {
     NoCryptoKeyRoute,
@@ -41,6 +62,8 @@ pub enum RouterError {
     UnknownReceiverId,
     NoEndpoint,
     SendError,
+    KeyExpired,
+    PacketTooBig,
 }
 
 impl fmt::Display for RouterError {
@@ -53,6 +76,8 @@ impl fmt::Display for RouterError {
             }
             RouterError::NoEndpoint => write!(f, "No endpoint for peer"),
             RouterError::SendError => write!(f, "Failed to send packet on bind"),
+            RouterError::KeyExpired => write!(f, "Decryption key is past its lifetime"),
+            RouterError::PacketTooBig => write!(f, "Packet exceeds the path MTU to the peer"),
         }
     }
 }
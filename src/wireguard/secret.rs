@@ -0,0 +1,218 @@
+/* Locked memory for the secrets of the device (the static key, PSKs and session keys):
+ *
+ * Secrets are allocated in slots of 32 bytes from a pool of dedicated pages.
+ * Once enabled with `lock_secrets`, the pages are locked in memory (mlock / VirtualLock),
+ * so that the keys are never written to swap, and excluded from core dumps
+ * (MADV_DONTDUMP on Linux, MADV_NOCORE on FreeBSD).
+ *
+ * Locking is bounded by RLIMIT_MEMLOCK (by the working set size on Windows):
+ * a page which cannot be locked is still used (with a warning).
+ * The slot of a dropped secret is cleared and reused, the pages are never released.
+ */
+
+use std::alloc::{self, Layout};
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::ptr::NonNull;
+
+use clear_on_drop::clear::Clear;
+use spin::Mutex;
+
+const SLOT_SIZE: usize = 32;
+
+struct Pool {
+    locked: bool,
+    pages: Vec<NonNull<u8>>,
+    free: Vec<NonNull<[u8; SLOT_SIZE]>>,
+}
+
+// the slots are only accessed through the owning secret (or the pool, while free)
+unsafe impl Send for Pool {}
+
+static POOL: Mutex<Pool> = Mutex::new(Pool {
+    locked: false,
+    pages: Vec::new(),
+    free: Vec::new(),
+});
+
+// Internal function
+//
+// The size of a page of virtual memory
+fn page_size() -> usize {
+    #[cfg(unix)]
+    {
+        let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+        if size > 0 {
+            return size as usize;
+        }
+    }
+    4096
+}
+
+// Internal function
+//
+// Lock a page in memory and exclude it from core dumps
+#[cfg(unix)]
+fn lock_page(page: NonNull<u8>, size: usize) -> io::Result<()> {
+    let addr = page.as_ptr() as *mut libc::c_void;
+    if unsafe { libc::mlock(addr, size) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let advice = Some(libc::MADV_DONTDUMP);
+    #[cfg(target_os = "freebsd")]
+    let advice = Some(libc::MADV_NOCORE);
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+    let advice: Option<libc::c_int> = None;
+    if let Some(advice) = advice {
+        if unsafe { libc::madvise(addr, size, advice) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn lock_page(page: NonNull<u8>, size: usize) -> io::Result<()> {
+    use winapi::um::memoryapi::VirtualLock;
+    if unsafe { VirtualLock(page.as_ptr() as *mut _, size) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(unix, windows)))]
+fn lock_page(_page: NonNull<u8>, _size: usize) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "locking memory is not supported on this platform",
+    ))
+}
+
+impl Pool {
+    fn alloc(&mut self) -> NonNull<[u8; SLOT_SIZE]> {
+        if self.free.is_empty() {
+            // a page is only used for secrets (locked as a whole)
+            let size = page_size();
+            let layout = Layout::from_size_align(size, size).unwrap();
+            let page = NonNull::new(unsafe { alloc::alloc_zeroed(layout) })
+                .unwrap_or_else(|| alloc::handle_alloc_error(layout));
+            if self.locked {
+                if let Err(e) = lock_page(page, size) {
+                    log::warn!("failed to lock memory of secrets: {}", e);
+                }
+            }
+            for i in (0..size / SLOT_SIZE).rev() {
+                let slot = unsafe { page.as_ptr().add(i * SLOT_SIZE) };
+                self.free
+                    .push(NonNull::new(slot as *mut [u8; SLOT_SIZE]).unwrap());
+            }
+            self.pages.push(page);
+        }
+        self.free.pop().unwrap()
+    }
+}
+
+/// Lock the memory of every secret (allocated so far and in the future)
+///
+/// # Returns
+///
+/// An error if the pages allocated so far could not be locked,
+/// e.g. RLIMIT_MEMLOCK is exceeded (the secrets are still usable).
+pub fn lock_secrets() -> io::Result<()> {
+    let mut pool = POOL.lock();
+    pool.locked = true;
+    let size = page_size();
+    for page in pool.pages.iter() {
+        lock_page(*page, size)?;
+    }
+    Ok(())
+}
+
+/// 32 bytes of key material, allocated from the pool of (locked) pages
+/// and cleared when dropped
+pub struct Secret(NonNull<[u8; SLOT_SIZE]>);
+
+// the slot is owned by the secret
+unsafe impl Send for Secret {}
+unsafe impl Sync for Secret {}
+
+impl Secret {
+    pub fn new(value: [u8; SLOT_SIZE]) -> Secret {
+        let slot = POOL.lock().alloc();
+        unsafe { slot.as_ptr().write(value) };
+        Secret(slot)
+    }
+}
+
+impl Deref for Secret {
+    type Target = [u8; SLOT_SIZE];
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { self.0.as_ref() }
+    }
+}
+
+impl DerefMut for Secret {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { self.0.as_mut() }
+    }
+}
+
+impl Clone for Secret {
+    fn clone(&self) -> Secret {
+        Secret::new(**self)
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        (**self).clear();
+        POOL.lock().free.push(self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secret() {
+        let mut secret = Secret::new([1u8; 32]);
+        let copy = secret.clone();
+        secret[0] = 2;
+        assert_eq!(copy[0], 1);
+        assert_eq!(secret[1..], copy[1..]);
+
+        // the slot is cleared when the secret is dropped
+        let slot = copy.0;
+        drop(copy);
+        assert_eq!(unsafe { *slot.as_ptr() }, [0u8; 32]);
+
+        // the slots are carved from page aligned memory
+        let page = page_size();
+        assert!(POOL
+            .lock()
+            .pages
+            .iter()
+            .all(|p| p.as_ptr() as usize & (page - 1) == 0));
+    }
+
+    #[test]
+    fn test_lock_secrets() {
+        let before = Secret::new([3u8; 32]);
+
+        // locking may fail in restricted environments (RLIMIT_MEMLOCK)
+        if let Err(e) = lock_secrets() {
+            eprintln!("failed to lock memory: {}", e);
+        }
+        assert!(POOL.lock().locked);
+
+        // secrets remain usable, including those allocated from new pages
+        let after: Vec<Secret> = (0..2 * page_size() / SLOT_SIZE)
+            .map(|i| Secret::new([i as u8; 32]))
+            .collect();
+        assert_eq!(*before, [3u8; 32]);
+        assert!(after.iter().enumerate().all(|(i, s)| s[31] == i as u8));
+    }
+}
@@ -0,0 +1,817 @@
+/* TCP fallback for the outer transport
+ *
+ * For networks which block UDP entirely, WireGuard messages can be carried over TCP,
+ * every message is framed with a 2-byte (big-endian) length prefix:
+ *
+ * - Connections are opened by peers with TCP fallback enabled, once handshakes over UDP
+ *   fail to complete (see timers.rs), and accepted on the TCP listen port.
+ * - Outbound messages to an address with an open connection are sent over TCP,
+ *   all other messages are sent over UDP (the preferred path).
+ * - Inbound messages are handed to the same de-multiplexer as UDP datagrams,
+ *   with the remote address of the connection as source.
+ * - The accepted connections are bounded, and closed unless they carry an authenticated
+ *   handshake message within a short deadline (afterwards, when idle).
+ */
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use spin::{Mutex, RwLock};
+
+use super::obfuscation::Obfuscation;
+use super::types::base64;
+use super::udp::Writer;
+use super::Endpoint;
+
+// size of the length prefix
+const SIZE_FRAME_HEADER: usize = 2;
+
+/// Largest message which can be framed
+pub const MAX_FRAME_SIZE: usize = u16::MAX as usize;
+
+// Semantics:
+// Time after which a blocked write on a connection is abandoned (and the connection closed)
+const TCP_WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Semantics:
+// Time after which a connection which carried no message is closed
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(180);
+
+// Semantics:
+// Time within which an accepted connection must carry an authenticated handshake message,
+// allows for a cookie reply and the retried initiation (see REKEY_TIMEOUT)
+const TCP_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Bound on the number of accepted connections
+pub const MAX_TCP_CONNECTIONS: usize = 1024;
+
+// Semantics:
+// Bound on the size of the response header of an HTTP proxy
+const MAX_HTTP_HEADER: usize = 8192;
+
+/// Write a framed message to a stream
+#[cfg(test)]
+pub fn write_frame<W: Write>(stream: &mut W, msg: &[u8]) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(SIZE_FRAME_HEADER + msg.len());
+    encode_frame(&mut frame, msg)?;
+    stream.write_all(&frame[..])
+}
+
+/// Read a framed message from a stream
+///
+/// # Returns
+///
+/// The message, or an error if the stream was closed or failed
+pub fn read_frame<R: Read>(stream: &mut R) -> io::Result<Vec<u8>> {
+    let mut header = [0u8; SIZE_FRAME_HEADER];
+    stream.read_exact(&mut header)?;
+    let mut msg = vec![0u8; u16::from_be_bytes(header) as usize];
+    stream.read_exact(&mut msg[..])?;
+    Ok(msg)
+}
+
+// Internal function
+//
+// Append a framed message to a buffer
+fn encode_frame(buf: &mut Vec<u8>, msg: &[u8]) -> io::Result<()> {
+    if msg.len() > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "message too large to frame",
+        ));
+    }
+    buf.extend_from_slice(&(msg.len() as u16).to_be_bytes());
+    buf.extend_from_slice(msg);
+    Ok(())
+}
+
+// Internal function
+//
+// Map IPv4-mapped IPv6 addresses (from dual-stack listeners) to IPv4,
+// such that the address matches the endpoint of the peer
+fn canonical(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().segments() {
+            [0, 0, 0, 0, 0, 0xffff, hi, lo] => {
+                let ip = [(hi >> 8) as u8, hi as u8, (lo >> 8) as u8, lo as u8];
+                SocketAddr::new(IpAddr::from(ip), v6.port())
+            }
+            _ => addr,
+        },
+        SocketAddr::V4(_) => addr,
+    }
+}
+
+struct Connection {
+    id: u64,
+    stream: Mutex<TcpStream>,
+    opened: Instant,
+    inbound: bool,
+    authenticated: AtomicBool,
+}
+
+impl Connection {
+    // Internal function
+    //
+    // An accepted connection which did not carry an authenticated handshake message in time
+    fn expired(&self, timeout: Duration) -> bool {
+        self.inbound
+            && !self.authenticated.load(Ordering::Acquire)
+            && self.opened.elapsed() >= timeout
+    }
+}
+
+/// The open TCP connections, by remote address
+pub struct Connections {
+    next: AtomicU64,
+    max_accepted: usize,
+    handshake_timeout: Duration,
+    conns: RwLock<HashMap<SocketAddr, Arc<Connection>>>,
+}
+
+impl Default for Connections {
+    fn default() -> Self {
+        Connections::new()
+    }
+}
+
+impl Connections {
+    pub fn new() -> Self {
+        Connections::with_limits(MAX_TCP_CONNECTIONS, TCP_HANDSHAKE_TIMEOUT)
+    }
+
+    // Internal function
+    //
+    // Create with a bound on the accepted connections
+    // and the deadline of their first authenticated handshake message
+    fn with_limits(max_accepted: usize, handshake_timeout: Duration) -> Self {
+        Connections {
+            next: AtomicU64::new(0),
+            max_accepted,
+            handshake_timeout,
+            conns: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Register a connected stream (replacing any connection to the same address)
+    ///
+    /// Accepted connections must carry an authenticated handshake message
+    /// within a short deadline (see `authenticate`), otherwise they are closed:
+    /// by the read timeout, or when the next connection is accepted.
+    ///
+    /// # Arguments
+    ///
+    /// - `stream`: The connected stream
+    /// - `inbound`: The stream was accepted on the listen port
+    ///
+    /// # Returns
+    ///
+    /// The remote address, the identifier of the connection (see `remove`)
+    /// and a handle for reading from the stream,
+    /// or an error if the bound on accepted connections is reached
+    pub fn insert(
+        &self,
+        stream: TcpStream,
+        inbound: bool,
+    ) -> io::Result<(SocketAddr, u64, TcpStream)> {
+        let addr = canonical(stream.peer_addr()?);
+        stream.set_nodelay(true)?;
+        stream.set_write_timeout(Some(TCP_WRITE_TIMEOUT))?;
+        stream.set_read_timeout(Some(if inbound {
+            self.handshake_timeout
+        } else {
+            TCP_IDLE_TIMEOUT
+        }))?;
+        let reader = stream.try_clone()?;
+
+        let mut conns = self.conns.write();
+        if inbound {
+            conns.retain(|_, conn| {
+                if conn.expired(self.handshake_timeout) {
+                    let _ = conn.stream.lock().shutdown(Shutdown::Both);
+                    false
+                } else {
+                    true
+                }
+            });
+            let accepted = conns.values().filter(|conn| conn.inbound).count();
+            if accepted >= self.max_accepted && !conns.contains_key(&addr) {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    "too many TCP connections",
+                ));
+            }
+        }
+
+        let id = self.next.fetch_add(1, Ordering::Relaxed);
+        let old = conns.insert(
+            addr,
+            Arc::new(Connection {
+                id,
+                stream: Mutex::new(stream),
+                opened: Instant::now(),
+                inbound,
+                authenticated: AtomicBool::new(!inbound),
+            }),
+        );
+        if let Some(old) = old {
+            let _ = old.stream.lock().shutdown(Shutdown::Both);
+        }
+        Ok((addr, id, reader))
+    }
+
+    /// Mark the connection to an address as authenticated
+    /// (it carried a valid handshake message), such that it is only closed when idle
+    pub fn authenticate(&self, addr: &SocketAddr) {
+        if let Some(conn) = self.conns.read().get(addr) {
+            if !conn.authenticated.swap(true, Ordering::AcqRel) {
+                let _ = conn.stream.lock().set_read_timeout(Some(TCP_IDLE_TIMEOUT));
+            }
+        }
+    }
+
+    /// Close the connection to an address
+    ///
+    /// # Arguments
+    ///
+    /// - `addr`: The remote address
+    /// - `id`: Only close the connection with this identifier (if not replaced since)
+    pub fn remove(&self, addr: &SocketAddr, id: Option<u64>) {
+        let mut conns = self.conns.write();
+        let matches = match (conns.get(addr), id) {
+            (Some(conn), Some(id)) => conn.id == id,
+            (Some(_), None) => true,
+            (None, _) => false,
+        };
+        if matches {
+            if let Some(conn) = conns.remove(addr) {
+                let _ = conn.stream.lock().shutdown(Shutdown::Both);
+            }
+        }
+    }
+
+    /// Close all connections
+    pub fn clear(&self) {
+        for (_, conn) in self.conns.write().drain() {
+            let _ = conn.stream.lock().shutdown(Shutdown::Both);
+        }
+    }
+
+    pub fn contains(&self, addr: &SocketAddr) -> bool {
+        self.conns.read().contains_key(addr)
+    }
+
+    /// Send messages over the connection to an address (in a single write)
+    ///
+    /// # Returns
+    ///
+    /// None if there is no connection to the address,
+    /// otherwise the result of the write (the connection is closed on failure)
+    pub fn send(&self, addr: &SocketAddr, msgs: &[&[u8]]) -> Option<io::Result<()>> {
+        let conn = self.conns.read().get(addr)?.clone();
+        let mut frames = Vec::with_capacity(msgs.iter().map(|m| SIZE_FRAME_HEADER + m.len()).sum());
+        let res = msgs
+            .iter()
+            .try_for_each(|msg| encode_frame(&mut frames, msg))
+            .and_then(|_| conn.stream.lock().write_all(&frames[..]));
+        if let Err(e) = res.as_ref() {
+            log::debug!("TCP connection to {} failed: {}", addr, e);
+            self.remove(addr, Some(conn.id));
+        }
+        Some(res)
+    }
+}
+
+/// Outbound writer of the router:
+/// messages are obfuscated (if enabled, see obfuscation.rs),
+/// then sent over TCP if a connection to the destination is open, otherwise over UDP.
+pub struct Outbound<W> {
+    udp: W,
+    tcp: Arc<Connections>,
+    obfuscation: Obfuscation,
+}
+
+impl<W> Outbound<W> {
+    pub fn new(udp: W, tcp: Arc<Connections>, obfuscation: Obfuscation) -> Self {
+        Outbound {
+            udp,
+            tcp,
+            obfuscation,
+        }
+    }
+}
+
+// Internal function
+//
+// Convert errors of the UDP writer
+fn udp_error<E: std::error::Error>(e: E) -> io::Error {
+    io::Error::other(e.to_string())
+}
+
+impl<E: Endpoint, W: Writer<E>> Writer<E> for Outbound<W> {
+    type Error = io::Error;
+
+    fn write(&self, buf: &[u8], dst: &mut E) -> Result<(), Self::Error> {
+        let wrapped = self.obfuscation.wrap(&[buf]);
+        let buf = wrapped.as_ref().map_or(buf, |wrapped| &wrapped[0][..]);
+        match self.tcp.send(&dst.into_address(), &[buf]) {
+            Some(res) => res,
+            None => self.udp.write(buf, dst).map_err(udp_error),
+        }
+    }
+
+    fn write_batch(&self, bufs: &[&[u8]], dst: &mut E, tos: u8) -> Result<(), Self::Error> {
+        if let Some(wrapped) = self.obfuscation.wrap(bufs) {
+            let bufs: Vec<&[u8]> = wrapped.iter().map(|buf| &buf[..]).collect();
+            return self.send(&bufs[..], dst, tos);
+        }
+        self.send(bufs, dst, tos)
+    }
+}
+
+impl<W> Outbound<W> {
+    // Internal function
+    //
+    // Send (already obfuscated) messages over TCP or UDP
+    fn send<E: Endpoint>(&self, bufs: &[&[u8]], dst: &mut E, tos: u8) -> io::Result<()>
+    where
+        W: Writer<E>,
+    {
+        match self.tcp.send(&dst.into_address(), bufs) {
+            Some(res) => res,
+            None => self.udp.write_batch(bufs, dst, tos).map_err(udp_error),
+        }
+    }
+}
+
+/// Proxy through which outbound TCP connections are established
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Proxy {
+    pub kind: ProxyKind,
+    pub addr: SocketAddr,
+    pub auth: Option<(String, String)>, // username and password
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyKind {
+    Socks5,      // RFC 1928 (with RFC 1929 username / password authentication)
+    HttpConnect, // HTTP CONNECT tunnel (with basic authentication)
+}
+
+impl FromStr for Proxy {
+    type Err = io::Error;
+
+    /// Parse a proxy URL: "socks5://[user:password@]host:port" or "http://[user:password@]host:port"
+    fn from_str(url: &str) -> Result<Self, Self::Err> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidInput, msg);
+        let (kind, rest) = if let Some(rest) = url.strip_prefix("socks5://") {
+            (ProxyKind::Socks5, rest)
+        } else if let Some(rest) = url.strip_prefix("http://") {
+            (ProxyKind::HttpConnect, rest)
+        } else {
+            return Err(invalid("unsupported proxy scheme"));
+        };
+        let rest = rest.trim_end_matches('/');
+        let (auth, host) = match rest.rfind('@') {
+            Some(i) => {
+                let (user, password) = rest[..i].split_at(
+                    rest[..i]
+                        .find(':')
+                        .ok_or_else(|| invalid("missing password"))?,
+                );
+                (
+                    Some((user.to_owned(), password[1..].to_owned())),
+                    &rest[i + 1..],
+                )
+            }
+            None => (None, rest),
+        };
+        let addr = host
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| invalid("proxy address did not resolve"))?;
+        Ok(Proxy { kind, addr, auth })
+    }
+}
+
+/// Open a TCP connection to an address (blocking), optionally through a proxy
+///
+/// # Arguments
+///
+/// - `addr`: The destination
+/// - `proxy`: The proxy to tunnel the connection through
+/// - `timeout`: Bound on establishing the connection (and negotiating with the proxy)
+pub fn connect(
+    addr: SocketAddr,
+    proxy: Option<&Proxy>,
+    timeout: Duration,
+) -> io::Result<TcpStream> {
+    let proxy = match proxy {
+        Some(proxy) => proxy,
+        None => return TcpStream::connect_timeout(&addr, timeout),
+    };
+    let mut stream = TcpStream::connect_timeout(&proxy.addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    match proxy.kind {
+        ProxyKind::Socks5 => socks5_connect(&mut stream, addr, proxy.auth.as_ref())?,
+        ProxyKind::HttpConnect => http_connect(&mut stream, addr, proxy.auth.as_ref())?,
+    }
+    stream.set_read_timeout(None)?;
+    Ok(stream)
+}
+
+// Internal function
+//
+// Negotiate a SOCKS5 (RFC 1928) tunnel to the address
+fn socks5_connect(
+    stream: &mut TcpStream,
+    addr: SocketAddr,
+    auth: Option<&(String, String)>,
+) -> io::Result<()> {
+    let failed = |msg| io::Error::new(io::ErrorKind::ConnectionRefused, msg);
+
+    // method selection
+    let method = if auth.is_some() { 0x02 } else { 0x00 };
+    stream.write_all(&[0x05, 0x01, method])?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply)?;
+    if reply != [0x05, method] {
+        return Err(failed("SOCKS5 proxy rejected the authentication method"));
+    }
+
+    // username / password authentication (RFC 1929)
+    if let Some((user, password)) = auth {
+        if user.len() > 255 || password.len() > 255 {
+            return Err(failed("SOCKS5 credentials too long"));
+        }
+        let mut msg = vec![0x01, user.len() as u8];
+        msg.extend_from_slice(user.as_bytes());
+        msg.push(password.len() as u8);
+        msg.extend_from_slice(password.as_bytes());
+        stream.write_all(&msg[..])?;
+        stream.read_exact(&mut reply)?;
+        if reply[1] != 0x00 {
+            return Err(failed("SOCKS5 authentication failed"));
+        }
+    }
+
+    // connect request
+    let mut msg = vec![0x05, 0x01, 0x00];
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            msg.push(0x01);
+            msg.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            msg.push(0x04);
+            msg.extend_from_slice(&ip.octets());
+        }
+    }
+    msg.extend_from_slice(&addr.port().to_be_bytes());
+    stream.write_all(&msg[..])?;
+
+    // reply (followed by the bound address)
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply)?;
+    if reply[0] != 0x05 || reply[1] != 0x00 {
+        return Err(failed("SOCKS5 proxy refused the connection"));
+    }
+    let len = match reply[3] {
+        0x01 => 4,
+        0x04 => 16,
+        0x03 => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len)?;
+            len[0] as usize
+        }
+        _ => return Err(failed("SOCKS5 reply has invalid address type")),
+    };
+    let mut bound = vec![0u8; len + 2];
+    stream.read_exact(&mut bound[..])
+}
+
+// Internal function
+//
+// Negotiate an HTTP CONNECT tunnel to the address
+fn http_connect(
+    stream: &mut TcpStream,
+    addr: SocketAddr,
+    auth: Option<&(String, String)>,
+) -> io::Result<()> {
+    let mut request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", addr, addr);
+    if let Some((user, password)) = auth {
+        let credentials = format!("{}:{}", user, password);
+        request += &format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            base64(credentials.as_bytes())
+        );
+    }
+    request += "\r\n";
+    stream.write_all(request.as_bytes())?;
+
+    // read the response header (byte-wise, the tunnel starts right after it)
+    let mut header = vec![];
+    while !header.ends_with(b"\r\n\r\n") {
+        if header.len() >= MAX_HTTP_HEADER {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "HTTP proxy response too long",
+            ));
+        }
+        let mut byte = [0u8; 1];
+        stream.read_exact(&mut byte)?;
+        header.push(byte[0]);
+    }
+
+    // check the status code of "HTTP/1.x 200 ..."
+    let status = header.split(|b| *b == b' ').nth(1).unwrap_or(&[]);
+    if header.starts_with(b"HTTP/1.") && status == b"200" {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            "HTTP proxy refused the connection",
+        ))
+    }
+}
+
+/// Listening TCP socket, closed (terminating the accepting thread) when dropped
+pub struct Listener {
+    listener: Arc<TcpListener>,
+}
+
+impl Listener {
+    /// Bind a TCP socket to the port (dual-stack if supported)
+    ///
+    /// # Returns
+    ///
+    /// The listener and a handle for accepting connections
+    pub fn bind(port: u16) -> io::Result<(Listener, Arc<TcpListener>)> {
+        let listener =
+            TcpListener::bind(("::", port)).or_else(|_| TcpListener::bind(("0.0.0.0", port)))?;
+        let listener = Arc::new(listener);
+        Ok((
+            Listener {
+                listener: listener.clone(),
+            },
+            listener,
+        ))
+    }
+
+    pub fn get_port(&self) -> u16 {
+        self.listener
+            .local_addr()
+            .map(|addr| addr.port())
+            .unwrap_or(0)
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        // wake the thread blocked in accept
+        #[cfg(unix)]
+        unsafe {
+            use std::os::unix::io::AsRawFd;
+            libc::shutdown(self.listener.as_raw_fd(), libc::SHUT_RDWR);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+    use std::thread;
+
+    #[test]
+    fn test_frame() {
+        let mut buf = vec![];
+        write_frame(&mut buf, &[1, 2, 3]).unwrap();
+        write_frame(&mut buf, &[]).unwrap();
+        write_frame(&mut buf, &[4; 300]).unwrap();
+        assert_eq!(&buf[..5], &[0, 3, 1, 2, 3]);
+
+        let mut stream = &buf[..];
+        assert_eq!(read_frame(&mut stream).unwrap(), vec![1, 2, 3]);
+        assert_eq!(read_frame(&mut stream).unwrap(), Vec::<u8>::new());
+        assert_eq!(read_frame(&mut stream).unwrap(), vec![4; 300]);
+        assert!(read_frame(&mut stream).is_err());
+
+        // truncated frames and oversized messages
+        assert!(read_frame(&mut &buf[..4]).is_err());
+        assert!(write_frame(&mut vec![], &vec![0; MAX_FRAME_SIZE + 1]).is_err());
+    }
+
+    #[test]
+    fn test_canonical() {
+        let mapped: SocketAddr = "[::ffff:192.0.2.1]:51820".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:51820".parse().unwrap();
+        assert_eq!(canonical(mapped), "192.0.2.1:51820".parse().unwrap());
+        assert_eq!(canonical(v6), v6);
+    }
+
+    #[test]
+    fn test_connections() {
+        let (listener, accept) = Listener::bind(0).unwrap();
+        let port = listener.get_port();
+        let (tx, rx) = channel();
+        let acceptor = thread::spawn(move || {
+            while let Ok((stream, _)) = accept.accept() {
+                tx.send(stream).unwrap();
+            }
+        });
+
+        // messages are framed over the connection
+        let conns = Connections::new();
+        let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let (addr, id, _reader) = conns.insert(stream, false).unwrap();
+        assert_eq!(addr.port(), port);
+        assert!(conns.contains(&addr));
+        assert!(conns.send(&addr, &[&[1, 2], &[3]]).unwrap().is_ok());
+
+        let other: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        assert!(conns.send(&other, &[&[1]]).is_none());
+
+        // stale identifiers do not close newer connections
+        conns.remove(&addr, Some(id + 1));
+        assert!(conns.contains(&addr));
+        conns.remove(&addr, Some(id));
+        assert!(!conns.contains(&addr));
+        assert!(conns.send(&addr, &[&[1]]).is_none());
+
+        let mut accepted = rx.recv().unwrap();
+        assert_eq!(read_frame(&mut accepted).unwrap(), vec![1, 2]);
+        assert_eq!(read_frame(&mut accepted).unwrap(), vec![3]);
+        assert!(read_frame(&mut accepted).is_err());
+
+        // dropping the listener terminates the acceptor
+        drop(listener);
+        acceptor.join().unwrap();
+    }
+
+    #[test]
+    fn test_accepted_connections() {
+        let (listener, accept) = Listener::bind(0).unwrap();
+        let port = listener.get_port();
+        let conns = Connections::with_limits(2, Duration::from_millis(200));
+        let mut clients = vec![];
+        let mut accept_next = || {
+            clients.push(TcpStream::connect(("127.0.0.1", port)).unwrap());
+            accept.accept().unwrap().0
+        };
+
+        // the number of accepted connections is bounded
+        let (first, _, _) = conns.insert(accept_next(), true).unwrap();
+        let (second, _, mut reader) = conns.insert(accept_next(), true).unwrap();
+        assert!(conns.insert(accept_next(), true).is_err());
+
+        // outbound connections are not bounded
+        let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let _outbound = accept.accept().unwrap();
+        let (outbound, _, _) = conns.insert(stream, false).unwrap();
+
+        // unauthenticated connections time out
+        conns.authenticate(&first);
+        assert!(read_frame(&mut reader).is_err());
+        thread::sleep(Duration::from_millis(250));
+        assert!(conns.insert(accept_next(), true).is_ok());
+        assert!(conns.contains(&first));
+        assert!(!conns.contains(&second));
+        assert!(conns.contains(&outbound));
+    }
+
+    // Internal function
+    //
+    // Run a single-connection proxy: for every step, read a request of the given length
+    // and answer with the reply, then echo framed messages
+    fn fake_proxy(steps: Vec<(usize, Vec<u8>)>) -> (SocketAddr, thread::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut requests = vec![];
+            for (len, reply) in steps {
+                let mut request = vec![0u8; len];
+                stream.read_exact(&mut request[..]).unwrap();
+                requests.extend_from_slice(&request[..]);
+                stream.write_all(&reply[..]).unwrap();
+            }
+            if let Ok(msg) = read_frame(&mut stream) {
+                write_frame(&mut stream, &msg[..]).unwrap();
+            }
+            requests
+        });
+        (addr, handle)
+    }
+
+    #[test]
+    fn test_proxy_parse() {
+        let proxy: Proxy = "socks5://127.0.0.1:1080".parse().unwrap();
+        assert_eq!(proxy.kind, ProxyKind::Socks5);
+        assert_eq!(proxy.addr, "127.0.0.1:1080".parse().unwrap());
+        assert_eq!(proxy.auth, None);
+
+        let proxy: Proxy = "http://user:p@ss:w0rd@[::1]:3128/".parse().unwrap();
+        assert_eq!(proxy.kind, ProxyKind::HttpConnect);
+        assert_eq!(proxy.addr, "[::1]:3128".parse().unwrap());
+        assert_eq!(
+            proxy.auth,
+            Some(("user".to_owned(), "p@ss:w0rd".to_owned()))
+        );
+
+        assert!("https://127.0.0.1:3128".parse::<Proxy>().is_err());
+        assert!("socks5://user@127.0.0.1:1080".parse::<Proxy>().is_err());
+        assert!("socks5://127.0.0.1".parse::<Proxy>().is_err());
+    }
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"user:password"), "dXNlcjpwYXNzd29yZA==");
+    }
+
+    #[test]
+    fn test_socks5_connect() {
+        let dst: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        let timeout = Duration::from_secs(5);
+
+        // username / password authentication, then connect to an IPv4 address
+        let (addr, proxy) = fake_proxy(vec![
+            (3, vec![0x05, 0x02]),
+            (7, vec![0x01, 0x00]),
+            (10, vec![0x05, 0x00, 0x00, 0x01, 10, 0, 0, 1, 0x12, 0x34]),
+        ]);
+        let proxy_cfg = Proxy {
+            kind: ProxyKind::Socks5,
+            addr,
+            auth: Some(("ab".to_owned(), "cd".to_owned())),
+        };
+        let mut stream = connect(dst, Some(&proxy_cfg), timeout).unwrap();
+        write_frame(&mut stream, &[1, 2, 3]).unwrap();
+        assert_eq!(read_frame(&mut stream).unwrap(), vec![1, 2, 3]);
+        assert_eq!(
+            proxy.join().unwrap(),
+            vec![
+                0x05, 0x01, 0x02, // methods
+                0x01, 2, b'a', b'b', 2, b'c', b'd', // credentials
+                0x05, 0x01, 0x00, 0x01, 192, 0, 2, 1, 0xca, 0x6c, // connect
+            ]
+        );
+
+        // the proxy refuses the connection
+        let (addr, proxy) = fake_proxy(vec![
+            (3, vec![0x05, 0x00]),
+            (10, vec![0x05, 0x05, 0x00, 0x01, 0, 0, 0, 0, 0, 0]),
+        ]);
+        let proxy_cfg = Proxy {
+            kind: ProxyKind::Socks5,
+            addr,
+            auth: None,
+        };
+        assert!(connect(dst, Some(&proxy_cfg), timeout).is_err());
+        proxy.join().unwrap();
+    }
+
+    #[test]
+    fn test_http_connect() {
+        let dst: SocketAddr = "[2001:db8::1]:51820".parse().unwrap();
+        let timeout = Duration::from_secs(5);
+        let request = "CONNECT [2001:db8::1]:51820 HTTP/1.1\r\nHost: [2001:db8::1]:51820\r\n\r\n";
+
+        let (addr, proxy) = fake_proxy(vec![(
+            request.len(),
+            b"HTTP/1.1 200 Connection established\r\n\r\n".to_vec(),
+        )]);
+        let proxy_cfg = Proxy {
+            kind: ProxyKind::HttpConnect,
+            addr,
+            auth: None,
+        };
+        let mut stream = connect(dst, Some(&proxy_cfg), timeout).unwrap();
+        write_frame(&mut stream, &[4, 5]).unwrap();
+        assert_eq!(read_frame(&mut stream).unwrap(), vec![4, 5]);
+        assert_eq!(proxy.join().unwrap(), request.as_bytes());
+
+        // authentication required
+        let (addr, proxy) = fake_proxy(vec![(
+            request.len(),
+            b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n".to_vec(),
+        )]);
+        let proxy_cfg = Proxy {
+            kind: ProxyKind::HttpConnect,
+            addr,
+            auth: None,
+        };
+        assert!(connect(dst, Some(&proxy_cfg), timeout).is_err());
+        proxy.join().unwrap();
+    }
+}
@@ -1,8 +1,20 @@
+use super::super::platform::memory::{MemoryTun, MemoryTunHost};
+use super::constants::*;
 use super::dummy;
-use super::wireguard::WireGuard;
+use super::handshake::{HandshakeConfig, ReservedBytes};
+use super::tun::{Status, TunEvent};
+use super::udp::{Reader, Writer};
+use super::wireguard::{DeviceConfig, WireGuard};
+use super::Obfuscator;
+
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
+use std::thread;
 
 use std::convert::TryInto;
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant, SystemTime};
 
 use hex;
 use rand_chacha::ChaCha8Rng;
@@ -198,3 +210,773 @@ fn test_pure_wireguard() {
         }
     }
 }
+
+/* Check that the persistent keepalive timer fires:
+ * without a session the keepalive causes a handshake initiation.
+ */
+#[test]
+fn test_persistent_keepalive() {
+    init();
+
+    let (_fake, tun_reader, tun_writer, _) = dummy::TunTest::create(false);
+    let wg: WireGuard<dummy::TunTest, dummy::PairBind> = WireGuard::new(tun_writer);
+    wg.add_tun_reader(tun_reader);
+    wg.up(1500);
+
+    let ((_bind_reader1, bind_writer1), (bind_reader2, _bind_writer2)) = dummy::PairBind::pair();
+    wg.set_writer(bind_writer1);
+
+    let sk = StaticSecret::new(&mut rand::rngs::OsRng);
+    let pk = PublicKey::from(&StaticSecret::new(&mut rand::rngs::OsRng));
+    wg.set_key(Some(sk));
+    wg.add_peer(pk);
+
+    {
+        let peers = wg.peers.read();
+        let peer = peers.get(&pk).unwrap();
+        peer.set_endpoint(dummy::UnitEndpoint::new());
+        peer.opaque().set_persistent_keepalive_interval(1);
+        assert_eq!(peer.opaque().get_keepalive_interval(), 1);
+    }
+
+    let mut buf = vec![0u8; 1500];
+    let (len, _) = bind_reader2.read(&mut buf).unwrap();
+    assert_eq!(len, 148, "expected a handshake initiation");
+    assert_eq!(buf[0], 1);
+}
+
+/* Check that an unanswered initiation is retransmitted with a fresh initiation,
+ * after the rekey timeout (plus jitter) doubled for every retransmission.
+ * A short rekey timeout is configured to keep the test fast.
+ */
+#[test]
+fn test_handshake_retransmission() {
+    init();
+
+    let config = DeviceConfig {
+        rekey_timeout: Duration::from_millis(500),
+        ..Default::default()
+    };
+    let (_fake, tun_reader, tun_writer, _) = dummy::TunTest::create(false);
+    let wg: WireGuard<dummy::TunTest, dummy::PairBind> = WireGuard::with_config(tun_writer, config);
+    wg.add_tun_reader(tun_reader);
+    wg.up(1500);
+
+    let ((_bind_reader1, bind_writer1), (bind_reader2, _bind_writer2)) = dummy::PairBind::pair();
+    wg.set_writer(bind_writer1);
+
+    let sk = StaticSecret::new(&mut rand::rngs::OsRng);
+    let pk = PublicKey::from(&StaticSecret::new(&mut rand::rngs::OsRng));
+    wg.set_key(Some(sk));
+    wg.add_peer(pk);
+
+    {
+        let peers = wg.peers.read();
+        let peer = peers.get(&pk).unwrap();
+        peer.set_endpoint(dummy::UnitEndpoint::new());
+        peer.opaque().packet_send_handshake_initiation();
+    }
+
+    let mut previous = vec![0u8; 1500];
+    let (len, _) = bind_reader2.read(&mut previous).unwrap();
+    previous.truncate(len);
+    assert_eq!(len, 148, "expected a handshake initiation");
+    let mut start = Instant::now();
+
+    for attempts in 0..2 {
+        let mut msg = vec![0u8; 1500];
+        let (len, _) = bind_reader2.read(&mut msg).unwrap();
+        msg.truncate(len);
+        let elapsed = start.elapsed();
+        start = Instant::now();
+        assert_eq!(len, 148, "expected a retransmitted initiation");
+        assert_ne!(previous, msg, "retransmission should be a new initiation");
+        previous = msg;
+
+        let timeout = config.retransmit_timeout(attempts);
+        assert_eq!(timeout, config.rekey_timeout * (1 << attempts));
+        assert!(elapsed + TIMERS_TICK >= timeout);
+        assert!(elapsed <= timeout + REKEY_TIMEOUT_JITTER_MAX + 2 * TIMERS_TICK);
+    }
+}
+
+/* Check that the protocol timing is taken from the device configuration:
+ * initiations are retransmitted after the configured rekey timeout.
+ */
+#[test]
+fn test_device_config() {
+    init();
+
+    // retransmissions after 5, 10, 20, 20 and 20 seconds fit in REKEY_ATTEMPT_TIME
+    let defaults = DeviceConfig::default();
+    assert_eq!(defaults.rekey_timeout, REKEY_TIMEOUT);
+    assert_eq!(defaults.retransmit_timeout(0), REKEY_TIMEOUT);
+    assert_eq!(defaults.retransmit_timeout(2), REKEY_TIMEOUT_MAX);
+    assert_eq!(defaults.retransmit_timeout(usize::MAX), REKEY_TIMEOUT_MAX);
+    assert_eq!(defaults.max_handshake_attempts(), 5);
+
+    let mut config = DeviceConfig {
+        rekey_timeout: Duration::from_secs(1),
+        rekey_attempt_time: Duration::from_secs(3),
+        ..Default::default()
+    };
+    config.handshake.cookie_lifetime = Duration::from_secs(30);
+    assert_eq!(config.max_handshake_attempts(), 2);
+
+    // timeouts longer than REKEY_TIMEOUT_MAX are not shortened
+    let long = DeviceConfig {
+        rekey_timeout: REKEY_TIMEOUT_MAX * 2,
+        ..Default::default()
+    };
+    assert_eq!(long.retransmit_timeout(3), long.rekey_timeout);
+
+    let (_fake, tun_reader, tun_writer, _) = dummy::TunTest::create(false);
+    let wg: WireGuard<dummy::TunTest, dummy::PairBind> = WireGuard::with_config(tun_writer, config);
+    assert_eq!(wg.peers.read().get_config(), config.handshake);
+    wg.add_tun_reader(tun_reader);
+    wg.up(1500);
+
+    let ((_bind_reader1, bind_writer1), (bind_reader2, _bind_writer2)) = dummy::PairBind::pair();
+    wg.set_writer(bind_writer1);
+
+    let sk = StaticSecret::new(&mut rand::rngs::OsRng);
+    let pk = PublicKey::from(&StaticSecret::new(&mut rand::rngs::OsRng));
+    wg.set_key(Some(sk));
+    wg.add_peer(pk);
+
+    {
+        let peers = wg.peers.read();
+        let peer = peers.get(&pk).unwrap();
+        peer.set_endpoint(dummy::UnitEndpoint::new());
+        peer.opaque().packet_send_handshake_initiation();
+    }
+
+    let mut buf = vec![0u8; 1500];
+    let (len, _) = bind_reader2.read(&mut buf).unwrap();
+    assert_eq!(len, 148, "expected a handshake initiation");
+    let start = Instant::now();
+
+    let (len, _) = bind_reader2.read(&mut buf).unwrap();
+    let elapsed = start.elapsed();
+    assert_eq!(len, 148, "expected a retransmitted initiation");
+    assert!(elapsed + TIMERS_TICK >= config.rekey_timeout);
+    assert!(elapsed <= config.rekey_timeout + REKEY_TIMEOUT_JITTER_MAX + 2 * TIMERS_TICK);
+}
+
+/* Check that time-limited peers are removed once expired
+ * and that the expiry event is delivered.
+ */
+// prefixes every datagram with a marker byte
+struct Marker;
+
+impl Obfuscator for Marker {
+    fn wrap(&self, msg: &[u8]) -> Vec<u8> {
+        let mut datagram = vec![0xaa];
+        datagram.extend_from_slice(msg);
+        datagram
+    }
+
+    fn unwrap(&self, datagram: Vec<u8>) -> Option<Vec<u8>> {
+        match datagram.first() {
+            Some(0xaa) => Some(datagram[1..].to_vec()),
+            _ => None,
+        }
+    }
+}
+
+/* Check that the obfuscator is applied between the router and the bind:
+ * outbound messages are wrapped, inbound datagrams unwrapped (or dropped).
+ */
+#[test]
+fn test_obfuscation() {
+    init();
+
+    let (_fake, tun_reader, tun_writer, _) = dummy::TunTest::create(false);
+    let wg: WireGuard<dummy::TunTest, dummy::PairBind> = WireGuard::new(tun_writer);
+    wg.add_tun_reader(tun_reader);
+    wg.up(1500);
+
+    let ((bind_reader1, bind_writer1), (bind_reader2, bind_writer2)) = dummy::PairBind::pair();
+    wg.set_writer(bind_writer1);
+    wg.add_udp_reader(bind_reader1);
+    wg.set_obfuscator(Some(Arc::new(Marker)));
+
+    let (tx, rx) = channel();
+    let tx = Mutex::new(tx);
+    wg.set_unknown_message_handler(Some(Box::new(move |msg: &[u8], _| {
+        tx.lock().unwrap().send(msg.to_vec()).unwrap();
+    })));
+
+    let sk = StaticSecret::new(&mut rand::rngs::OsRng);
+    let pk = PublicKey::from(&StaticSecret::new(&mut rand::rngs::OsRng));
+    wg.set_key(Some(sk));
+    wg.add_peer(pk);
+
+    {
+        let peers = wg.peers.read();
+        let peer = peers.get(&pk).unwrap();
+        peer.set_endpoint(dummy::UnitEndpoint::new());
+        peer.opaque().packet_send_handshake_initiation();
+    }
+
+    // outbound: the initiation is wrapped
+    let mut buf = vec![0u8; 1500];
+    let (len, _) = bind_reader2.read(&mut buf).unwrap();
+    assert_eq!(len, 149, "expected a wrapped handshake initiation");
+    assert_eq!(buf[0], 0xaa);
+    assert_eq!(buf[1], 1);
+
+    // inbound: datagrams without the marker are dropped before the de-multiplexer
+    let mut dst = dummy::UnitEndpoint::new();
+    bind_writer2.write(&[0x55, 0, 0, 0], &mut dst).unwrap();
+    bind_writer2
+        .write(&[0xaa, 0x56, 0, 0, 0], &mut dst)
+        .unwrap();
+    let msg = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(msg, vec![0x56, 0, 0, 0]);
+    assert!(rx.try_recv().is_err());
+    assert_eq!(wg.message_stats().unknown, 1);
+}
+
+#[test]
+fn test_peer_expiry() {
+    init();
+
+    let (_fake, _tun_reader, tun_writer, _) = dummy::TunTest::create(false);
+    let wg: WireGuard<dummy::TunTest, dummy::PairBind> = WireGuard::new(tun_writer);
+
+    let (tx, rx) = channel();
+    let tx = Mutex::new(tx);
+    wg.set_expiry_handler(Some(Box::new(move |pk: &PublicKey| {
+        tx.lock().unwrap().send(*pk.as_bytes()).unwrap();
+    })));
+
+    let new_pk = || PublicKey::from(&StaticSecret::new(&mut rand::rngs::OsRng));
+    let pk1 = new_pk();
+    let pk2 = new_pk();
+    let pk3 = new_pk();
+    for pk in &[pk1, pk2, pk3] {
+        assert!(wg.add_peer(*pk));
+    }
+    assert!(wg.set_peer_expiry(&pk1, Some(Duration::from_secs(0))));
+    assert!(wg.set_peer_expiry(&pk2, Some(Duration::from_secs(3600))));
+    assert!(!wg.set_peer_expiry(&new_pk(), None));
+
+    // only the expired peer is removed
+    let expired = wg.expire_peers();
+    assert_eq!(expired.len(), 1);
+    assert_eq!(expired[0].as_bytes(), pk1.as_bytes());
+    assert_eq!(rx.try_recv().unwrap(), *pk1.as_bytes());
+    assert!(rx.try_recv().is_err());
+
+    {
+        let peers = wg.peers.read();
+        assert!(!peers.contains_key(&pk1));
+        assert!(peers.contains_key(&pk2));
+        assert!(peers.contains_key(&pk3));
+    }
+    assert!(wg.get_peer_expiry(&pk2).unwrap() <= Duration::from_secs(3600));
+    assert!(wg.get_peer_expiry(&pk3).is_none());
+
+    // peers already removed are not reported again
+    assert!(!wg.remove_peer(&pk1));
+    assert!(wg.expire_peers().is_empty());
+    assert!(rx.try_recv().is_err());
+}
+
+#[test]
+fn test_endpoint_name() {
+    init();
+
+    let (_fake, _tun_reader, tun_writer, _) = dummy::TunTest::create(false);
+    let wg: WireGuard<dummy::TunTest, dummy::PairBind> = WireGuard::new(tun_writer);
+
+    let pk = PublicKey::from(&StaticSecret::new(&mut rand::rngs::OsRng));
+    assert!(wg.add_peer(pk));
+
+    // invalid names and unknown peers
+    assert!(wg.set_endpoint_name(&pk, "localhost").is_err());
+    let other = PublicKey::from(&StaticSecret::new(&mut rand::rngs::OsRng));
+    assert!(wg.set_endpoint_name(&other, "localhost:51820").is_err());
+
+    // resolved at configuration time
+    let addr = wg.set_endpoint_name(&pk, "localhost:51820").unwrap();
+    assert!(addr.ip().is_loopback());
+    assert_eq!(addr.port(), 51820);
+    assert!(wg.peers.read().get(&pk).unwrap().get_endpoint().is_some());
+
+    assert!(wg
+        .peers
+        .read()
+        .get(&pk)
+        .unwrap()
+        .endpoints
+        .lock()
+        .contains(&addr));
+
+    // not due for re-resolution
+    assert!(wg.resolve_endpoints().is_empty());
+
+    // not re-resolved periodically while the latest handshake is recent
+    // (the resolution cannot be aged past the uptime of the host)
+    let resolved = match Instant::now().checked_sub(ENDPOINT_REFRESH_INTERVAL) {
+        Some(resolved) => resolved,
+        None => return,
+    };
+    {
+        let peers = wg.peers.read();
+        let peer = peers.get(&pk).unwrap();
+        *peer.walltime_last_handshake.lock() = Some(SystemTime::now());
+        let mut name = peer.endpoint_name.lock();
+        name.as_mut().unwrap().1 = resolved;
+    }
+    assert!(wg.resolve_endpoints().is_empty());
+
+    // re-resolved periodically once the latest handshake is stale
+    *wg.peers
+        .read()
+        .get(&pk)
+        .unwrap()
+        .walltime_last_handshake
+        .lock() = Some(SystemTime::now() - ENDPOINT_STALE_AFTER);
+    assert_eq!(wg.resolve_endpoints().len(), 1);
+    assert!(wg.resolve_endpoints().is_empty());
+
+    // re-resolved after failed handshakes
+    wg.peers
+        .read()
+        .get(&pk)
+        .unwrap()
+        .resolve_endpoint
+        .store(true, Ordering::SeqCst);
+    let resolved = wg.resolve_endpoints();
+    assert_eq!(resolved.len(), 1);
+    assert_eq!(resolved[0].as_bytes(), pk.as_bytes());
+    assert!(wg.resolve_endpoints().is_empty());
+}
+
+#[test]
+fn test_endpoint_failover() {
+    init();
+
+    let (_fake, _tun_reader, tun_writer, _) = dummy::TunTest::create(false);
+    let wg: WireGuard<dummy::TunTest, dummy::PairBind> = WireGuard::new(tun_writer);
+
+    let pk = PublicKey::from(&StaticSecret::new(&mut rand::rngs::OsRng));
+    let other = PublicKey::from(&StaticSecret::new(&mut rand::rngs::OsRng));
+    assert!(wg.add_peer(pk));
+
+    let a: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+    let b: SocketAddr = "[2001:db8::1]:51820".parse().unwrap();
+    let c: SocketAddr = "198.51.100.1:51820".parse().unwrap();
+    let roamed: SocketAddr = "203.0.113.1:51820".parse().unwrap();
+
+    assert!(!wg.set_endpoints(&other, vec![a, b]));
+
+    // a single candidate: nothing to fail over to
+    assert!(wg.set_endpoints(&pk, vec![a]));
+    let peers = wg.peers.read();
+    let peer = peers.get(&pk).unwrap();
+    assert_eq!(peer.next_endpoint(Some(a)), None);
+    drop(peers);
+
+    // candidates are rotated in order
+    assert!(wg.set_endpoints(&pk, vec![a, b, c]));
+    let peers = wg.peers.read();
+    let peer = peers.get(&pk).unwrap();
+    assert!(peer.get_endpoint().is_some());
+    assert_eq!(peer.next_endpoint(Some(a)), Some(b));
+    assert_eq!(peer.next_endpoint(Some(b)), Some(c));
+    assert_eq!(peer.next_endpoint(Some(c)), Some(a));
+
+    // after roaming (or without an endpoint) the first candidate is preferred
+    assert_eq!(peer.next_endpoint(Some(roamed)), Some(a));
+    assert_eq!(peer.next_endpoint(None), Some(a));
+}
+
+#[test]
+fn test_endpoint_race() {
+    init();
+
+    let (_fake, _tun_reader, tun_writer, _) = dummy::TunTest::create(false);
+    let wg: WireGuard<dummy::TunTest, dummy::PairBind> = WireGuard::new(tun_writer);
+
+    let pk = PublicKey::from(&StaticSecret::new(&mut rand::rngs::OsRng));
+    assert!(wg.add_peer(pk));
+
+    let a: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+    let b: SocketAddr = "[2001:db8::1]:51820".parse().unwrap();
+    let c: SocketAddr = "198.51.100.1:51820".parse().unwrap();
+
+    // a single address family: nothing to race
+    assert!(wg.set_endpoints(&pk, vec![a, c]));
+    {
+        let peers = wg.peers.read();
+        let peer = peers.get(&pk).unwrap();
+        assert_eq!(peer.race_endpoint(Some(a)), None);
+    }
+
+    // initiations are raced to the first candidate of the other family
+    assert!(wg.set_endpoints(&pk, vec![a, c, b]));
+    let peers = wg.peers.read();
+    let peer = peers.get(&pk).unwrap();
+    assert_eq!(peer.race_endpoint(Some(a)), Some(b));
+    assert_eq!(peer.race_endpoint(Some(b)), Some(a));
+    assert_eq!(peer.race_endpoint(None), None);
+
+    // until an endpoint answered
+    *peer.preferred_endpoint.lock() = Some(b);
+    assert_eq!(peer.race_endpoint(Some(b)), None);
+    drop(peers);
+
+    // reconfiguring the candidates clears the preference
+    assert!(wg.set_endpoints(&pk, vec![a, b]));
+    let peers = wg.peers.read();
+    assert_eq!(peers.get(&pk).unwrap().race_endpoint(Some(a)), Some(b));
+}
+
+/* Create two devices on in-memory TUN devices (with the given MTU),
+ * connected over a pair bind: the hosts are assigned 10.0.1.0/24 and 10.0.2.0/24.
+ */
+fn memory_pair(
+    mtu: usize,
+) -> (
+    Vec<MemoryTunHost>,
+    Vec<WireGuard<MemoryTun, dummy::PairBind>>,
+) {
+    let mut hosts = vec![];
+    let mut devices = vec![];
+    for _ in 0..2 {
+        let (readers, writer, mut status, host) = MemoryTun::create(1, mtu);
+        let wg: WireGuard<MemoryTun, dummy::PairBind> = WireGuard::new(writer);
+        for reader in readers {
+            wg.add_tun_reader(reader);
+        }
+        match status.event().unwrap() {
+            TunEvent::Up(mtu) => wg.up(mtu),
+            TunEvent::Down => panic!("expected up event"),
+        }
+        hosts.push(host);
+        devices.push(wg);
+    }
+
+    let ((bind_reader1, bind_writer1), (bind_reader2, bind_writer2)) = dummy::PairBind::pair();
+    devices[0].set_writer(bind_writer1);
+    devices[1].set_writer(bind_writer2);
+    devices[0].add_udp_reader(bind_reader1);
+    devices[1].add_udp_reader(bind_reader2);
+
+    let sk1 = StaticSecret::new(&mut rand::rngs::OsRng);
+    let sk2 = StaticSecret::new(&mut rand::rngs::OsRng);
+    let pk1 = PublicKey::from(&sk1);
+    let pk2 = PublicKey::from(&sk2);
+    devices[0].set_key(Some(sk1));
+    devices[1].set_key(Some(sk2));
+    devices[0].add_peer(pk2);
+    devices[1].add_peer(pk1);
+    {
+        let peers = devices[0].peers.read();
+        let peer = peers.get(&pk2).unwrap();
+        peer.add_allowed_ip("10.0.2.0".parse().unwrap(), 24);
+        peer.set_endpoint(dummy::UnitEndpoint::new());
+    }
+    {
+        let peers = devices[1].peers.read();
+        let peer = peers.get(&pk1).unwrap();
+        peer.add_allowed_ip("10.0.1.0".parse().unwrap(), 24);
+    }
+    (hosts, devices)
+}
+
+/* Send a packet from one host and check that it is received by the other
+ *
+ * Packets may be lost while the handshake completes
+ * (e.g. reaching the responder before it has installed the new key): retransmit,
+ * discarding duplicates of earlier packets.
+ */
+fn transmit(from: &MemoryTunHost, to: &MemoryTunHost, packet: &[u8]) -> bool {
+    for _ in 0..10 {
+        from.send(packet.to_owned()).unwrap();
+        while let Some(received) = to.recv_timeout(Duration::from_secs(1)).unwrap() {
+            if received == packet {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+/* Exercise the full device through in-memory TUN devices:
+ * packets sent by one host are delivered to the other (after a handshake).
+ */
+#[test]
+fn test_memory_tun() {
+    init();
+
+    let (hosts, _devices) = memory_pair(1420);
+
+    // the first packet is staged until the handshake completes
+    let src = "10.0.1.1".parse().unwrap();
+    let dst = "10.0.2.1".parse().unwrap();
+    let packet = make_packet(100, src, dst, 0);
+    assert!(transmit(&hosts[0], &hosts[1], &packet));
+
+    // the reply uses the endpoint learned from the handshake
+    let reply = make_packet(200, dst, src, 1);
+    assert!(transmit(&hosts[1], &hosts[0], &reply));
+}
+
+/* Check the notification of confirmed key-pairs:
+ * the initiator confirms the key-pair on the handshake response,
+ * the responder on the first transport message received under the key-pair.
+ */
+#[test]
+fn test_key_confirmed() {
+    init();
+
+    let (hosts, devices) = memory_pair(1420);
+    let (tx, rx) = channel();
+    for (i, wg) in devices.iter().enumerate() {
+        let tx = Mutex::new(tx.clone());
+        wg.set_key_confirmed_handler(Some(Arc::new(move |pk: &PublicKey| {
+            tx.lock().unwrap().send((i, *pk.as_bytes())).unwrap();
+        })));
+    }
+
+    let pk = |wg: &WireGuard<MemoryTun, dummy::PairBind>| {
+        *PublicKey::from(&wg.get_sk().unwrap()).as_bytes()
+    };
+    let src = "10.0.1.1".parse().unwrap();
+    let dst = "10.0.2.1".parse().unwrap();
+    assert!(transmit(
+        &hosts[0],
+        &hosts[1],
+        &make_packet(100, src, dst, 0)
+    ));
+
+    let mut confirmed = vec![
+        rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+        rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+    ];
+    confirmed.sort();
+    assert_eq!(confirmed, vec![(0, pk(&devices[1])), (1, pk(&devices[0]))]);
+    assert!(rx.try_recv().is_err());
+}
+
+/* Check the rotation of the public key of a peer while traffic flows:
+ * the allowed IPs, psk and endpoint of the peer are retained
+ * and a handshake is completed with the new key.
+ */
+#[test]
+fn test_replace_peer_key() {
+    init();
+
+    let (hosts, devices) = memory_pair(1420);
+    let pk1 = PublicKey::from(&devices[0].get_sk().unwrap());
+    let pk2 = PublicKey::from(&devices[1].get_sk().unwrap());
+    let psk = [0x42u8; 32];
+    devices[0].set_psk(pk2, Some(psk));
+    devices[1].set_psk(pk1, Some(psk));
+
+    // the responder learns the endpoint of the initiator from the handshake
+    let src = "10.0.1.1".parse().unwrap();
+    let dst = "10.0.2.1".parse().unwrap();
+    assert!(transmit(
+        &hosts[0],
+        &hosts[1],
+        &make_packet(100, src, dst, 0)
+    ));
+
+    // the first device rotates its key, the second replaces the key of the peer
+    // while sending packets to it
+    let sk = StaticSecret::new(&mut rand::rngs::OsRng);
+    let new = PublicKey::from(&sk);
+    thread::scope(|s| {
+        s.spawn(|| {
+            for id in 1..100 {
+                hosts[1].send(make_packet(100, dst, src, id)).unwrap();
+            }
+        });
+        devices[0].set_key(Some(sk));
+        assert!(devices[1].replace_peer_key(&pk1, new));
+    });
+    assert!(
+        !devices[1].replace_peer_key(&pk1, new),
+        "old key should be gone"
+    );
+    {
+        let peers = devices[1].peers.read();
+        assert!(!peers.contains_key(&pk1));
+        let peer = peers.get(&new).unwrap();
+        assert_eq!(
+            peer.list_allowed_ips(),
+            vec![("10.0.1.0".parse().unwrap(), 24)]
+        );
+        assert!(peer.get_endpoint().is_some());
+    }
+    assert_eq!(devices[1].get_psk(&new), Some(psk));
+
+    // the new key completes a handshake (the sessions of the old key were discarded)
+    assert!(transmit(
+        &hosts[0],
+        &hosts[1],
+        &make_packet(100, src, dst, 100)
+    ));
+    assert!(transmit(
+        &hosts[1],
+        &hosts[0],
+        &make_packet(200, dst, src, 101)
+    ));
+}
+
+/* Check the invalidation of the sessions with a single peer:
+ * the receiver ids of the peer are released and its keys are gone,
+ * while the in-flight handshake of another peer is retained.
+ */
+#[test]
+fn test_clear_session() {
+    init();
+
+    let (hosts, devices) = memory_pair(1420);
+    let pk2 = PublicKey::from(&devices[1].get_sk().unwrap());
+    let pk3 = PublicKey::from(&StaticSecret::new(&mut rand::rngs::OsRng));
+    let src = "10.0.1.1".parse().unwrap();
+    let dst = "10.0.2.1".parse().unwrap();
+    assert!(transmit(
+        &hosts[0],
+        &hosts[1],
+        &make_packet(100, src, dst, 0)
+    ));
+
+    // another peer with an in-flight handshake
+    devices[0].add_peer(pk3);
+    devices[0]
+        .peers
+        .read()
+        .get(&pk3)
+        .unwrap()
+        .set_endpoint(dummy::UnitEndpoint::new());
+    assert!(devices[0].begin_handshake(&pk3));
+    let start = Instant::now();
+    while devices[0].peers.read().allocated_ids(&pk3) == 0 {
+        assert!(start.elapsed() < Duration::from_secs(5), "no initiation");
+        thread::sleep(Duration::from_millis(10));
+    }
+    {
+        let peers = devices[0].peers.read();
+        assert!(peers.allocated_ids(&pk2) > 0);
+        assert!(peers.get(&pk2).unwrap().has_keys());
+        assert_eq!(peers.allocated_ids(&pk3), 1);
+    }
+
+    assert!(devices[0].clear_session(&pk2));
+    {
+        let peers = devices[0].peers.read();
+        assert_eq!(peers.allocated_ids(&pk2), 0);
+        assert!(!peers.get(&pk2).unwrap().has_keys());
+        assert_eq!(peers.allocated_ids(&pk3), 1);
+    }
+    let unknown = PublicKey::from(&StaticSecret::new(&mut rand::rngs::OsRng));
+    assert!(!devices[0].clear_session(&unknown));
+
+    // a new session is established
+    assert!(transmit(
+        &hosts[0],
+        &hosts[1],
+        &make_packet(100, src, dst, 1)
+    ));
+    assert!(devices[0].peers.read().get(&pk2).unwrap().has_keys());
+}
+
+/* Check that the reserved bytes policy applies to all messages:
+ * devices using custom reserved bytes complete a handshake and exchange transport messages.
+ */
+#[test]
+fn test_reserved_bytes() {
+    init();
+
+    let (hosts, devices) = memory_pair(1420);
+    for wg in devices.iter() {
+        wg.set_handshake_config(HandshakeConfig {
+            reserved: ReservedBytes::Custom([0xa1, 0xb2, 0xc3]),
+            ..Default::default()
+        });
+        assert_eq!(
+            wg.router.get_reserved(),
+            ReservedBytes::Custom([0xa1, 0xb2, 0xc3])
+        );
+    }
+
+    let src = "10.0.1.1".parse().unwrap();
+    let dst = "10.0.2.1".parse().unwrap();
+    assert!(transmit(
+        &hosts[0],
+        &hosts[1],
+        &make_packet(100, src, dst, 0)
+    ));
+    assert!(transmit(
+        &hosts[1],
+        &hosts[0],
+        &make_packet(200, dst, src, 1)
+    ));
+}
+
+/* Check jumbo frames:
+ * packets up to an MTU beyond 1500 (read into a reusable buffer) are delivered,
+ * and the MTU is limited to the largest packet which can be transported.
+ */
+#[test]
+fn test_jumbo_frames() {
+    init();
+
+    let mtu = 60000;
+    let (hosts, devices) = memory_pair(mtu);
+
+    let src = "10.0.1.1".parse().unwrap();
+    let dst = "10.0.2.1".parse().unwrap();
+    for (id, size) in [mtu - 20, 100, 9000].iter().enumerate() {
+        let packet = make_packet(*size, src, dst, id as u64);
+        assert!(transmit(&hosts[0], &hosts[1], &packet));
+    }
+    let reply = make_packet(mtu - 20, dst, src, 3);
+    assert!(transmit(&hosts[1], &hosts[0], &reply));
+
+    devices[0].up(1 << 20);
+    assert_eq!(devices[0].mtu.load(Ordering::Relaxed), MAX_MTU);
+}
+
+/* Check the provisioning of unknown initiators:
+ * the responder admits the initiator on the fly and completes the handshake.
+ */
+#[test]
+fn test_provisioning() {
+    init();
+
+    let (hosts, devices) = memory_pair(1420);
+    let pk1 = PublicKey::from(&devices[0].get_sk().unwrap());
+    let pk2 = PublicKey::from(&devices[1].get_sk().unwrap());
+    devices[1].remove_peer(&pk1);
+
+    let (tx, rx) = channel();
+    let tx = Mutex::new(tx);
+    devices[0].set_key_confirmed_handler(Some(Arc::new(move |pk: &PublicKey| {
+        tx.lock().unwrap().send(*pk.as_bytes()).unwrap();
+    })));
+    devices[1].set_provisioner(Some(Box::new(move |pk: &PublicKey| {
+        if pk.as_bytes() == pk1.as_bytes() {
+            Some([0u8; 32])
+        } else {
+            None
+        }
+    })));
+
+    // the packet initiates a handshake, which completes once the initiator is admitted
+    let src = "10.0.1.1".parse().unwrap();
+    let dst = "10.0.2.1".parse().unwrap();
+    hosts[0].send(make_packet(100, src, dst, 0)).unwrap();
+    assert_eq!(
+        rx.recv_timeout(Duration::from_secs(10)).unwrap(),
+        *pk2.as_bytes()
+    );
+    assert!(devices[1].peers.read().contains_key(&pk1));
+}
@@ -1,10 +1,15 @@
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::thread;
 use std::time::{Duration, Instant, SystemTime};
 
 use log::debug;
+use rand::rngs::OsRng;
+use rand::Rng;
 
 use hjul::Timer;
+use spin::Mutex;
 use x25519_dalek::PublicKey;
 
 use super::constants::*;
@@ -13,12 +18,13 @@ use super::router::{message_data_len, Callbacks};
 use super::tun::Tun;
 use super::types::KeyPair;
 use super::udp::UDP;
-use super::WireGuard;
+use super::{DeviceConfig, Endpoint, WireGuard};
 
 pub struct Timers {
     // only updated during configuration
     enabled: bool,
     keepalive_interval: u64,
+    adaptive: Option<AdaptiveKeepalive>,
 
     handshake_attempts: AtomicUsize,
     sent_lastminute_handshake: AtomicBool,
@@ -31,16 +37,144 @@ pub struct Timers {
     new_handshake: Timer,
 }
 
+/* The retransmission timeout of handshake initiations:
+ * the rekey timeout with exponential backoff (see DeviceConfig::retransmit_timeout)
+ * plus random jitter, preventing peers from retransmitting in lockstep.
+ */
+fn rekey_timeout(config: &DeviceConfig, attempts: usize) -> Duration {
+    let jitter = OsRng.gen_range(0, REKEY_TIMEOUT_JITTER_MAX.as_millis() as u64 + 1);
+    config.retransmit_timeout(attempts) + Duration::from_millis(jitter)
+}
+
+/* Adaptive persistent keepalive (NAT binding probing):
+ *
+ * The interval is doubled (from min towards max) after every handshake completed
+ * once the probed interval has been in effect for a full period (the binding survived it),
+ * until a handshake fails after the peer was silent for at least the probed interval
+ * (the NAT binding presumably expired while idle): the interval then settles
+ * on the longest interval known to keep the binding.
+ * Failures while the peer was heard from recently are not caused by the binding and ignored.
+ *
+ * The binding timeout may change (e.g. on another network),
+ * hence probing resumes from the settled interval after ADAPTIVE_KEEPALIVE_REPROBE.
+ * Longer intervals reduce radio wakeups on mobile devices.
+ */
+pub struct AdaptiveKeepalive {
+    min: u64,
+    max: u64,
+    probe: AtomicU64, // interval currently probed (in seconds)
+    safe: AtomicU64,  // longest interval confirmed to keep the binding
+    state: Mutex<ProbeState>,
+}
+
+struct ProbeState {
+    since: Instant,           // the probed interval is in effect since
+    received: Instant,        // last authenticated packet from the peer
+    settled: Option<Instant>, // probing stopped at
+}
+
+impl AdaptiveKeepalive {
+    pub fn new(min: u64, max: u64, now: Instant) -> AdaptiveKeepalive {
+        let min = min.max(1);
+        AdaptiveKeepalive {
+            min,
+            max: max.max(min),
+            probe: AtomicU64::new(min),
+            safe: AtomicU64::new(0),
+            state: Mutex::new(ProbeState {
+                since: now,
+                received: now,
+                settled: None,
+            }),
+        }
+    }
+
+    pub fn range(&self) -> (u64, u64) {
+        (self.min, self.max)
+    }
+
+    pub fn interval(&self) -> u64 {
+        self.probe.load(Ordering::Acquire)
+    }
+
+    pub fn settled(&self) -> bool {
+        self.state.lock().settled.is_some()
+    }
+
+    /* Should be called when an authenticated packet is received from the peer */
+    fn received(&self, now: Instant) {
+        self.state.lock().received = now;
+    }
+
+    /* Should be called when a handshake completes:
+     * if the binding survived a full period of the current interval, probe a longer interval.
+     */
+    fn success(&self, now: Instant) {
+        let mut state = self.state.lock();
+        let probe = self.interval();
+
+        // resume probing periodically
+        if let Some(at) = state.settled {
+            if now.saturating_duration_since(at) >= ADAPTIVE_KEEPALIVE_REPROBE {
+                state.settled = None;
+                state.since = now;
+            }
+            return;
+        }
+
+        if now.saturating_duration_since(state.since) < Duration::from_secs(probe) {
+            return;
+        }
+        self.safe.store(probe, Ordering::Release);
+        if probe >= self.max {
+            state.settled = Some(now);
+        } else {
+            self.probe
+                .store(probe.saturating_mul(2).min(self.max), Ordering::Release);
+            state.since = now;
+        }
+    }
+
+    /* Should be called when a handshake fails:
+     * if the peer was silent for the probed interval (the binding may have expired),
+     * fall back to the longest safe interval and stop probing.
+     */
+    fn failure(&self, now: Instant) {
+        let mut state = self.state.lock();
+        let probe = self.interval();
+        if state.settled.is_some()
+            || now.saturating_duration_since(state.received) < Duration::from_secs(probe)
+        {
+            return;
+        }
+        let safe = self.safe.load(Ordering::Acquire);
+        self.probe.store(safe.max(self.min), Ordering::Release);
+        state.settled = Some(now);
+        state.since = now;
+    }
+}
+
 impl Timers {
     #[inline(always)]
     fn need_another_keepalive(&self) -> bool {
         self.need_another_keepalive.swap(false, Ordering::SeqCst)
     }
+
+    // Internal function
+    //
+    // The effective persistent keepalive interval (in seconds, 0 when disabled)
+    #[inline(always)]
+    fn persistent_interval(&self) -> u64 {
+        match self.adaptive.as_ref() {
+            Some(adaptive) => adaptive.interval(),
+            None => self.keepalive_interval,
+        }
+    }
 }
 
 impl<T: Tun, B: UDP> PeerInner<T, B> {
     pub fn get_keepalive_interval(&self) -> u64 {
-        self.timers().keepalive_interval
+        self.timers().persistent_interval()
     }
 
     pub fn stop_timers(&self) {
@@ -79,7 +213,7 @@ impl<T: Tun, B: UDP> PeerInner<T, B> {
         timers.enabled = true;
 
         // start send_persistent_keepalive
-        if timers.keepalive_interval > 0 {
+        if timers.persistent_interval() > 0 {
             timers
                 .send_persistent_keepalive
                 .start(Duration::from_secs(0));
@@ -90,16 +224,21 @@ impl<T: Tun, B: UDP> PeerInner<T, B> {
     pub fn timers_data_sent(&self) {
         let timers = self.timers();
         if timers.enabled {
+            let config = &self.wg.config;
             timers
                 .new_handshake
-                .start(KEEPALIVE_TIMEOUT + REKEY_TIMEOUT);
+                .start(config.keepalive_timeout + config.rekey_timeout);
         }
     }
 
     /* should be called after an authenticated data packet is received */
     pub fn timers_data_received(&self) {
         let timers = self.timers();
-        if timers.enabled && !timers.send_keepalive.start(KEEPALIVE_TIMEOUT) {
+        if timers.enabled
+            && !timers
+                .send_keepalive
+                .start(self.wg.config.keepalive_timeout)
+        {
             timers.need_another_keepalive.store(true, Ordering::SeqCst)
         }
     }
@@ -127,6 +266,9 @@ impl<T: Tun, B: UDP> PeerInner<T, B> {
         let timers = self.timers();
         if timers.enabled {
             timers.new_handshake.stop();
+            if let Some(adaptive) = timers.adaptive.as_ref() {
+                adaptive.received(Instant::now());
+            }
         }
     }
 
@@ -136,7 +278,10 @@ impl<T: Tun, B: UDP> PeerInner<T, B> {
         let timers = self.timers();
         if timers.enabled {
             timers.send_keepalive.stop();
-            timers.retransmit_handshake.reset(REKEY_TIMEOUT);
+            let attempts = timers.handshake_attempts.load(Ordering::SeqCst);
+            timers
+                .retransmit_handshake
+                .reset(rekey_timeout(&self.wg.config, attempts));
         }
     }
 
@@ -152,6 +297,9 @@ impl<T: Tun, B: UDP> PeerInner<T, B> {
             timers
                 .sent_lastminute_handshake
                 .store(false, Ordering::SeqCst);
+            if let Some(adaptive) = timers.adaptive.as_ref() {
+                adaptive.success(Instant::now());
+            }
             *self.walltime_last_handshake.lock() = Some(SystemTime::now());
         }
     }
@@ -173,11 +321,12 @@ impl<T: Tun, B: UDP> PeerInner<T, B> {
     pub fn timers_any_authenticated_packet_traversal(&self) {
         log::trace!("timers_any_authenticated_packet_traversal");
         let timers = self.timers();
-        if timers.enabled && timers.keepalive_interval > 0 {
+        let interval = timers.persistent_interval();
+        if timers.enabled && interval > 0 {
             // push persistent_keepalive into the future
             timers
                 .send_persistent_keepalive
-                .reset(Duration::from_secs(timers.keepalive_interval));
+                .reset(Duration::from_secs(interval));
         }
     }
 
@@ -185,7 +334,10 @@ impl<T: Tun, B: UDP> PeerInner<T, B> {
         log::trace!("timers_set_retransmit_handshake");
         let timers = self.timers();
         if timers.enabled {
-            timers.retransmit_handshake.reset(REKEY_TIMEOUT);
+            let attempts = timers.handshake_attempts.load(Ordering::SeqCst);
+            timers
+                .retransmit_handshake
+                .reset(rekey_timeout(&self.wg.config, attempts));
         }
     }
 
@@ -205,11 +357,15 @@ impl<T: Tun, B: UDP> PeerInner<T, B> {
         self.timers_any_authenticated_packet_sent();
     }
 
+    /* Update the persistent keepalive interval (in seconds, 0 disables it),
+     * when enabled a keepalive is sent immediately and then every interval without other traffic.
+     */
     pub fn set_persistent_keepalive_interval(&self, secs: u64) {
         let mut timers = self.timers_mut();
 
-        // update the stored keepalive_interval
+        // update the stored keepalive_interval (disables adaptive keepalive)
         timers.keepalive_interval = secs;
+        timers.adaptive = None;
 
         // stop the keepalive timer with the old interval
         timers.send_persistent_keepalive.stop();
@@ -222,6 +378,65 @@ impl<T: Tun, B: UDP> PeerInner<T, B> {
         }
     }
 
+    /* Enable (or disable) the adaptive persistent keepalive,
+     * probing intervals between min and max (in seconds), see AdaptiveKeepalive.
+     * When disabled the fixed persistent keepalive interval applies again.
+     * Setting the same range again retains the probing state.
+     */
+    pub fn set_adaptive_keepalive(&self, range: Option<(u64, u64)>) {
+        if self
+            .get_adaptive_keepalive()
+            .map(|(min, max, _)| (min, max))
+            == range
+        {
+            return;
+        }
+        let mut timers = self.timers_mut();
+        timers.adaptive = range.map(|(min, max)| AdaptiveKeepalive::new(min, max, Instant::now()));
+
+        // restart the persistent keepalive timer with the new interval
+        timers.send_persistent_keepalive.stop();
+        if timers.persistent_interval() > 0 && timers.enabled {
+            timers
+                .send_persistent_keepalive
+                .reset(Duration::from_secs(0));
+        }
+    }
+
+    /// Returns the state of the adaptive keepalive: (min, max, settled),
+    /// the current interval is returned by `get_keepalive_interval`
+    pub fn get_adaptive_keepalive(&self) -> Option<(u64, u64, bool)> {
+        self.timers().adaptive.as_ref().map(|adaptive| {
+            let (min, max) = adaptive.range();
+            (min, max, adaptive.settled())
+        })
+    }
+
+    /* Switch the transport to the endpoint after handshakes failed to complete:
+     *
+     * - over UDP: open a TCP connection (in the background) and initiate a handshake over it.
+     * - over TCP: close the connection, returning to UDP (the preferred path).
+     */
+    fn fallback_tcp(&self, addr: SocketAddr) {
+        if self.wg.tcp.contains(&addr) {
+            debug!("{} : returning to UDP for endpoint {}", self, addr);
+            self.wg.tcp.remove(&addr, None);
+            return;
+        }
+
+        debug!("{} : falling back to TCP for endpoint {}", self, addr);
+        let wg = self.wg.clone();
+        let pk = *self.pk.lock();
+        thread::spawn(move || match wg.connect_tcp(addr) {
+            Ok(()) => {
+                if let Some(peer) = wg.peers.read().get(&pk) {
+                    peer.opaque().packet_send_queued_handshake_initiation(false);
+                }
+            }
+            Err(e) => debug!("failed to connect to {} over TCP: {}", addr, e),
+        });
+    }
+
     fn packet_send_queued_handshake_initiation(&self, is_retry: bool) {
         if !is_retry {
             self.timers().handshake_attempts.store(0, Ordering::SeqCst);
@@ -263,6 +478,7 @@ impl Timers {
         Timers {
             enabled: running,
             keepalive_interval: 0, // disabled
+            adaptive: None,
             need_another_keepalive: AtomicBool::new(false),
             sent_lastminute_handshake: AtomicBool::new(false),
             handshake_attempts: AtomicUsize::new(0),
@@ -275,7 +491,7 @@ impl Timers {
 
                     // check if handshake attempts remaining
                     let attempts = timers.handshake_attempts.fetch_add(1, Ordering::SeqCst);
-                    if attempts > MAX_TIMER_HANDSHAKES {
+                    if attempts > wg.config.max_handshake_attempts() {
                         debug!(
                             "Handshake for peer {} did not complete after {} attempts, giving up",
                             peer,
@@ -284,14 +500,33 @@ impl Timers {
                         timers.send_keepalive.stop();
                         timers.zero_key_material.start(REJECT_AFTER_TIME * 3);
                         peer.purge_staged_packets();
+
+                        // the hostname of the endpoint may resolve to a new address
+                        peer.resolve_endpoint.store(true, Ordering::SeqCst);
+
+                        // fail over to the next candidate endpoint (racing the address families again)
+                        *peer.preferred_endpoint.lock() = None;
+                        if let Some(addr) = peer.next_endpoint(peer.get_endpoint()) {
+                            debug!("{} : failing over to endpoint {}", peer, addr);
+                            peer.set_endpoint(B::Endpoint::from_address(addr));
+                        } else if peer.tcp_fallback.load(Ordering::SeqCst) {
+                            if let Some(addr) = peer.get_endpoint() {
+                                peer.fallback_tcp(addr);
+                            }
+                        }
                     } else {
                         debug!(
                             "Handshake for {} did not complete after {} seconds, retrying (try {})",
                             peer,
-                            REKEY_TIMEOUT.as_secs(),
+                            wg.config.retransmit_timeout(attempts).as_secs(),
                             attempts
                         );
-                        timers.retransmit_handshake.reset(REKEY_TIMEOUT);
+                        timers
+                            .retransmit_handshake
+                            .reset(rekey_timeout(&wg.config, attempts + 1));
+                        if let Some(adaptive) = timers.adaptive.as_ref() {
+                            adaptive.failure(Instant::now());
+                        }
                         peer.clear_src();
                         peer.packet_send_queued_handshake_initiation(true);
                     }
@@ -304,10 +539,18 @@ impl Timers {
                     fetch_peer!(wg, pk, peer);
                     fetch_timers!(peer, timers);
 
+                    // passive keepalive: only when nothing was sent since the last received packet
+                    let idle = match (peer.last_sent(), peer.last_received()) {
+                        (Some(sent), Some(received)) => sent < received,
+                        _ => true,
+                    };
+
                     // send keepalive and schedule next keepalive
-                    peer.send_keepalive();
+                    if idle {
+                        peer.send_keepalive();
+                    }
                     if timers.need_another_keepalive() {
-                        timers.send_keepalive.start(KEEPALIVE_TIMEOUT);
+                        timers.send_keepalive.start(wg.config.keepalive_timeout);
                     }
                 })
             },
@@ -322,7 +565,7 @@ impl Timers {
                     log::debug!(
                         "Retrying handshake with {} because we stopped hearing back after {} seconds",
                         peer,
-                        (KEEPALIVE_TIMEOUT + REKEY_TIMEOUT).as_secs()
+                        (wg.config.keepalive_timeout + wg.config.rekey_timeout).as_secs()
                     );
                     peer.clear_src();
                     peer.packet_send_queued_handshake_initiation(false);
@@ -348,13 +591,14 @@ impl Timers {
                     log::trace!("{} : timer fired (send_persistent_keepalive)", peer);
 
                     // send and schedule persistent keepalive
-                    if timers.keepalive_interval > 0 {
+                    let interval = timers.persistent_interval();
+                    if interval > 0 {
                         timers.send_keepalive.stop();
                         peer.send_keepalive();
                         log::trace!("{} : keepalive queued", peer);
                         timers
                             .send_persistent_keepalive
-                            .start(Duration::from_secs(timers.keepalive_interval));
+                            .start(Duration::from_secs(interval));
                     }
                 })
             },
@@ -409,18 +653,19 @@ impl<T: Tun, B: UDP> Callbacks for PeerInner<T, B> {
         peer.timers_any_authenticated_packet_traversal();
         peer.timers_any_authenticated_packet_received();
         peer.rx_bytes.fetch_add(size as u64, Ordering::Relaxed);
-        if size > 0 && sent {
+        if size > message_data_len(0) && sent {
             peer.timers_data_received();
         }
 
         // keep_key_fresh
 
         #[inline(always)]
-        fn keep_key_fresh(keypair: &Arc<KeyPair>) -> bool {
-            Instant::now() - keypair.birth > REJECT_AFTER_TIME - KEEPALIVE_TIMEOUT - REKEY_TIMEOUT
+        fn keep_key_fresh(config: &DeviceConfig, keypair: &Arc<KeyPair>) -> bool {
+            keypair.birth.elapsed() + config.keepalive_timeout + config.rekey_timeout
+                > REJECT_AFTER_TIME
         }
 
-        if keep_key_fresh(keypair)
+        if keep_key_fresh(&peer.wg.config, keypair)
             && !peer
                 .timers()
                 .sent_lastminute_handshake
@@ -446,5 +691,62 @@ impl<T: Tun, B: UDP> Callbacks for PeerInner<T, B> {
     fn key_confirmed(peer: &Self::Opaque) {
         log::trace!("{} : EVENT(key_confirmed)", peer);
         peer.timers_handshake_complete();
+        peer.key_confirmed();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_adaptive_keepalive() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        // the interval grows once it has been in effect for a full period (bounded by max)
+        let adaptive = AdaptiveKeepalive::new(10, 100, start);
+        assert_eq!(adaptive.interval(), 10);
+        adaptive.success(at(5));
+        assert_eq!(adaptive.interval(), 10);
+        adaptive.success(at(10));
+        assert_eq!(adaptive.interval(), 20);
+        adaptive.success(at(30));
+        assert_eq!(adaptive.interval(), 40);
+
+        // failures while the peer was heard from recently are not evidence of the binding expiring
+        adaptive.received(at(50));
+        adaptive.failure(at(60));
+        assert!(!adaptive.settled());
+        assert_eq!(adaptive.interval(), 40);
+
+        // a failure after silence settles on the longest safe interval
+        adaptive.failure(at(90));
+        assert!(adaptive.settled());
+        assert_eq!(adaptive.interval(), 20);
+        adaptive.success(at(200));
+        adaptive.failure(at(300));
+        assert_eq!(adaptive.interval(), 20);
+
+        // probing resumes periodically
+        let reprobe = 90 + ADAPTIVE_KEEPALIVE_REPROBE.as_secs();
+        adaptive.success(at(reprobe));
+        assert!(!adaptive.settled());
+        adaptive.success(at(reprobe + 20));
+        assert_eq!(adaptive.interval(), 40);
+
+        // probing stops at max
+        let adaptive = AdaptiveKeepalive::new(25, 60, start);
+        for i in 1..4 {
+            adaptive.success(at(60 * i));
+        }
+        assert!(adaptive.settled());
+        assert_eq!(adaptive.interval(), 60);
+        assert_eq!(adaptive.range(), (25, 60));
+
+        // failure before any success falls back to min
+        let adaptive = AdaptiveKeepalive::new(0, 0, start);
+        adaptive.failure(at(1));
+        assert_eq!(adaptive.interval(), 1);
     }
 }
@@ -1,20 +1,17 @@
-use clear_on_drop::clear::Clear;
+use super::secret::Secret;
 use std::fmt;
 use std::time::Instant;
 
+use rand::rngs::OsRng;
+use rand::RngCore;
+use x25519_dalek::{PublicKey, StaticSecret};
+
 #[derive(Clone)]
 pub struct Key {
-    pub key: [u8; 32],
+    pub key: Secret, // zeroed on drop
     pub id: u32,
 }
 
-// zero key on drop
-impl Drop for Key {
-    fn drop(&mut self) {
-        self.key.clear()
-    }
-}
-
 #[cfg(test)]
 impl PartialEq for Key {
     fn eq(&self, other: &Self) -> bool {
@@ -54,3 +51,42 @@ impl KeyPair {
         self.recv.id
     }
 }
+
+/// Generate a new private key (as "wg genkey")
+pub fn generate_private_key() -> StaticSecret {
+    StaticSecret::new(&mut OsRng)
+}
+
+/// Derive the public key of a private key (as "wg pubkey")
+pub fn public_key(sk: &StaticSecret) -> PublicKey {
+    PublicKey::from(sk)
+}
+
+/// Base64 encoding (RFC 4648, with padding), e.g. of keys (as in the output of wg(8))
+pub fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (u32::from(b[0]) << 16) | (u32::from(b[1]) << 8) | u32::from(b[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Generate a new preshared key (as "wg genpsk")
+pub fn generate_preshared_key() -> [u8; 32] {
+    let mut psk = [0u8; 32];
+    OsRng.fill_bytes(&mut psk);
+    psk
+}
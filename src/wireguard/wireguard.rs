@@ -1,7 +1,9 @@
 use super::constants::*;
 use super::handshake;
+use super::obfuscation::{Obfuscation, Obfuscator};
 use super::peer::PeerInner;
 use super::router;
+use super::tcp;
 use super::timers::Timers;
 
 use super::queue::ParallelQueue;
@@ -9,17 +11,21 @@ use super::workers::HandshakeJob;
 
 use super::tun::Tun;
 use super::udp::UDP;
+use super::Endpoint;
 
-use super::workers::{handshake_worker, tun_worker, udp_worker};
+use super::workers::{handshake_worker, tcp_worker, tun_worker, udp_worker};
 
+use std::cmp;
 use std::fmt;
+use std::io;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
 use std::ops::Deref;
 use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::sync::Condvar;
 use std::sync::Mutex as StdMutex;
 use std::thread;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use hjul::Runner;
 use rand::rngs::OsRng;
@@ -28,11 +34,31 @@ use spin::{Mutex, RwLock};
 
 use x25519_dalek::{PublicKey, StaticSecret};
 
+// handle of a peer in the router (the opaque value of the handshake device)
+type PeerHandle<T, B> = router::PeerHandle<
+    <B as UDP>::Endpoint,
+    PeerInner<T, B>,
+    <T as Tun>::Writer,
+    tcp::Outbound<<B as UDP>::Writer>,
+>;
+
+// cryptokey router of the device (writing to the TUN device and the sockets)
+type Router<T, B> = router::Device<
+    <B as UDP>::Endpoint,
+    PeerInner<T, B>,
+    <T as Tun>::Writer,
+    tcp::Outbound<<B as UDP>::Writer>,
+>;
+
 pub struct WireguardInner<T: Tun, B: UDP> {
     // identifier (for logging)
     pub id: u32,
 
-    // timer wheel
+    // protocol timing (fixed at construction)
+    pub config: DeviceConfig,
+
+    // timer wheel: a single thread drives the timers of every peer
+    // (keepalive, retransmission and key expiry), see constants::TIMERS_*
     pub runner: Mutex<Runner>,
 
     // device enabled
@@ -46,22 +72,147 @@ pub struct WireguardInner<T: Tun, B: UDP> {
 
     // peer map
     pub peers: RwLock<
-        handshake::Device<router::PeerHandle<B::Endpoint, PeerInner<T, B>, T::Writer, B::Writer>>,
+        handshake::Device<
+            router::PeerHandle<B::Endpoint, PeerInner<T, B>, T::Writer, tcp::Outbound<B::Writer>>,
+        >,
     >,
 
+    // connections of the TCP fallback transport (by remote address)
+    pub tcp: Arc<tcp::Connections>,
+    pub tcp_proxy: RwLock<Option<tcp::Proxy>>,
+
+    // obfuscation of the datagrams (shared with the outbound writer)
+    pub obfuscation: Obfuscation,
+
     // cryptokey router
-    pub router: router::Device<B::Endpoint, PeerInner<T, B>, T::Writer, B::Writer>,
+    pub router: Router<T, B>,
 
     // handshake related state
     pub last_under_load: Mutex<Instant>,
     pub pending: AtomicUsize, // number of pending handshake packets in queue
     pub queue: ParallelQueue<HandshakeJob<B::Endpoint>>,
+
+    // inbound messages dropped by the type de-multiplexer
+    pub unknown_messages: AtomicU64,
+    pub short_messages: AtomicU64,
+    pub oversized_messages: AtomicU64,
+    pub unknown_handler: RwLock<Option<UnknownMessageHandler<B::Endpoint>>>,
+
+    // invoked when a time-limited peer expires
+    pub expiry_handler: RwLock<Option<PeerExpiryHandler>>,
+
+    // invoked when a new key-pair is confirmed
+    pub confirm_handler: RwLock<Option<KeyConfirmedHandler>>,
+
+    // admits unknown initiators
+    pub provision_handler: RwLock<Option<PeerProvisionHandler>>,
+}
+
+/// Callback invoked with inbound messages of unknown type and their source,
+/// e.g. to monitor scanning activity or handle protocol extensions.
+pub type UnknownMessageHandler<E> = Box<dyn Fn(&[u8], &E) + Send + Sync>;
+
+/// Callback invoked with the public key of a time-limited peer
+/// after it has expired and been removed.
+pub type PeerExpiryHandler = Box<dyn Fn(&PublicKey) + Send + Sync>;
+
+/// Callback invoked with the public key of a peer when a new key-pair is confirmed:
+/// by the handshake response (as initiator),
+/// or by the first valid transport message received under the key-pair (as responder).
+///
+/// The handler is shared, such that it is called without holding the lock of the device.
+pub type KeyConfirmedHandler = Arc<dyn Fn(&PublicKey) + Send + Sync>;
+
+/// Callback invoked with the static public key of an unknown (but authenticated) initiator,
+/// returns None to reject the initiation or the preshared key to admit the peer with
+/// (all zeros for none).
+pub type PeerProvisionHandler = Box<dyn Fn(&PublicKey) -> Option<[u8; 32]> + Send + Sync>;
+
+/// Number of inbound messages dropped by the type de-multiplexer
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MessageStats {
+    pub unknown: u64,   // messages with an unknown type
+    pub short: u64,     // messages too short to contain a type
+    pub oversized: u64, // messages larger than any valid message
+}
+
+/// Tunable protocol timing (and anti-replay window) of the device,
+/// the defaults follow the WireGuard specification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceConfig {
+    pub rekey_timeout: Duration, // interval between handshake retransmissions
+    pub keepalive_timeout: Duration, // delay of the passive keepalive after received data
+    pub rekey_attempt_time: Duration, // duration after which handshake attempts are abandoned
+    pub handshake: handshake::HandshakeConfig, // initial handshake config (see set_handshake_config)
+    pub replay_window: u64, // anti-replay window of new key-pairs in packets (see set_replay_window)
+    pub protocol: handshake::Protocol, // construction and identifier of the handshake (fixed)
+}
+
+impl Default for DeviceConfig {
+    fn default() -> Self {
+        DeviceConfig {
+            rekey_timeout: REKEY_TIMEOUT,
+            keepalive_timeout: KEEPALIVE_TIMEOUT,
+            rekey_attempt_time: REKEY_ATTEMPT_TIME,
+            handshake: handshake::HandshakeConfig::default(),
+            replay_window: router::WINDOW_SIZE,
+            protocol: handshake::Protocol::default(),
+        }
+    }
+}
+
+impl DeviceConfig {
+    /// Delay before retransmitting an unanswered initiation (without jitter):
+    /// the rekey timeout, doubled after every attempt up to `REKEY_TIMEOUT_MAX`.
+    ///
+    /// # Arguments
+    ///
+    /// - `attempts`: The number of retransmissions so far
+    pub fn retransmit_timeout(&self, attempts: usize) -> Duration {
+        let timeout = self.rekey_timeout * (1 << attempts.min(16)) as u32;
+        timeout.min(REKEY_TIMEOUT_MAX.max(self.rekey_timeout))
+    }
+
+    /// Number of handshake retransmissions before giving up,
+    /// such that the attempts span at most `rekey_attempt_time`.
+    pub fn max_handshake_attempts(&self) -> usize {
+        let mut elapsed = Duration::from_secs(0);
+        for attempts in 0.. {
+            elapsed += self
+                .retransmit_timeout(attempts)
+                .max(Duration::from_millis(1));
+            if elapsed > self.rekey_attempt_time {
+                return attempts;
+            }
+        }
+        unreachable!()
+    }
 }
 
 pub struct WireGuard<T: Tun, B: UDP> {
     inner: Arc<WireguardInner<T, B>>,
 }
 
+// Internal function
+//
+// Resolve an endpoint given as "host:port" (blocking),
+// returns every address (e.g. both the AAAA and A records) in the order of the resolver
+fn resolve(name: &str) -> io::Result<Vec<SocketAddr>> {
+    let mut addrs: Vec<SocketAddr> = Vec::new();
+    for addr in name.to_socket_addrs()? {
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+    }
+    if addrs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no addresses found",
+        ));
+    }
+    Ok(addrs)
+}
+
 pub struct WaitCounter(StdMutex<usize>, Condvar);
 
 impl<T: Tun, B: UDP> fmt::Display for WireGuard<T, B> {
@@ -135,6 +286,7 @@ impl<T: Tun, B: UDP> WireGuard<T, B> {
 
         // avoid transmission from router
         self.router.down();
+        self.tcp.clear();
 
         // set all peers down (stops timers)
         for (_, peer) in self.peers.write().iter() {
@@ -147,12 +299,17 @@ impl<T: Tun, B: UDP> WireGuard<T, B> {
 
     /// Brings the WireGuard device up.
     /// Usually called when the associated interface is brought up.
+    ///
+    /// The MTU is limited to MAX_MTU (the largest packet which can be transported).
     pub fn up(&self, mtu: usize) {
         // ensure exclusive access (to avoid race with "up" call)
         let mut enabled = self.enabled.write();
 
         // set mtu
-        self.mtu.store(mtu, Ordering::Relaxed);
+        if mtu > MAX_MTU {
+            log::info!("MTU of {} exceeds the maximum, using {}", mtu, MAX_MTU);
+        }
+        self.mtu.store(cmp::min(mtu, MAX_MTU), Ordering::Relaxed);
 
         // check if already up
         if *enabled {
@@ -175,30 +332,497 @@ impl<T: Tun, B: UDP> WireGuard<T, B> {
         self.peers.write().clear();
     }
 
-    pub fn remove_peer(&self, pk: &PublicKey) {
-        let _ = self.peers.write().remove(pk);
+    /// Remove a peer, stopping its timers and wiping its sessions
+    ///
+    /// # Arguments
+    ///
+    /// - `pk`: The public key of the peer
+    ///
+    /// # Returns
+    ///
+    /// A bool indicating if the peer was removed (false if no such peer exists).
+    pub fn remove_peer(&self, pk: &PublicKey) -> bool {
+        // remove the peer from the handshake device
+        // (the lock is released first: the timers of the peer acquire it)
+        let removed = self.peers.write().remove_peer(pk);
+        match removed {
+            Ok(peer) => {
+                // stop timers and zero the derived key-material
+                peer.stop_timers();
+                peer.zero_keys();
+                true
+            }
+            Err(_) => false,
+        }
     }
 
-    pub fn set_key(&self, sk: Option<StaticSecret>) {
+    /// Replace the public key of a peer,
+    /// retaining the allowed IPs, psk, endpoint and established sessions of the peer.
+    ///
+    /// # Arguments
+    ///
+    /// - `old`: The current public key of the peer
+    /// - `new`: The new public key of the peer
+    ///
+    /// # Returns
+    ///
+    /// A bool indicating if the key was replaced.
+    pub fn replace_peer_key(&self, old: &PublicKey, new: PublicKey) -> bool {
+        // prevent up/down while updating the timers
+        let enabled = self.enabled.read();
+
+        // replace the key in the handshake device,
+        // the exclusive lock is only held for the update of the map (the timers acquire the lock)
         let mut peers = self.peers.write();
-        peers.set_sk(sk);
-        self.router.clear_sending_keys();
+        if peers.replace_pk(old, new).is_err() {
+            return false;
+        }
+        let peers = peers.downgrade();
+
+        // timers lookup the peer by public key: recreate them
+        if let Some(peer) = peers.get(&new) {
+            *peer.pk.lock() = new;
+            let keepalive = peer.get_keepalive_interval();
+            let adaptive = peer.get_adaptive_keepalive();
+            peer.stop_timers();
+            *peer.timers_mut() = Timers::new::<T, B>(self.clone(), new, *enabled);
+            peer.set_persistent_keepalive_interval(keepalive);
+            peer.set_adaptive_keepalive(adaptive.map(|(min, max, _)| (min, max)));
+        }
+        true
+    }
+
+    /// Immediately queue a handshake initiation to the peer,
+    /// bypassing the rate limit on initiations (e.g. to rekey after an endpoint change).
+    ///
+    /// # Arguments
+    ///
+    /// - `pk`: The public key of the peer
+    ///
+    /// # Returns
+    ///
+    /// A bool indicating if the peer exists.
+    pub fn begin_handshake(&self, pk: &PublicKey) -> bool {
+        match self.peers.read().get(pk) {
+            Some(peer) => {
+                *peer.last_handshake_sent.lock() = Instant::now() - TIME_HORIZON;
+                peer.packet_send_handshake_initiation();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// React to a change of network connectivity (e.g. roaming between Wi-Fi and cellular),
+    /// usually after the sockets have been rebound:
+    /// the source addresses of all endpoints are cleared (no longer sticky),
+    /// TCP connections opened over the old network are closed
+    /// and a handshake is immediately initiated with every peer which has an endpoint.
+    ///
+    /// # Returns
+    ///
+    /// The number of peers with which a handshake was initiated
+    pub fn network_changed(&self) -> usize {
+        self.tcp.clear();
+        if !*self.enabled.read() {
+            return 0;
+        }
+
+        let mut initiated = 0;
+        for (_, peer) in self.peers.read().iter() {
+            peer.clear_src();
+            *peer.preferred_endpoint.lock() = None;
+            if peer.get_endpoint().is_none() {
+                continue;
+            }
+            *peer.last_handshake_sent.lock() = Instant::now() - TIME_HORIZON;
+            peer.packet_send_handshake_initiation();
+            initiated += 1;
+        }
+        log::info!(
+            "{} : network changed, initiated {} handshakes",
+            self,
+            initiated
+        );
+        initiated
+    }
+
+    /// Invalidate every session with the peer:
+    /// aborts any in-flight handshake, zeros all derived key-material
+    /// and releases the receiver ids of the handshake and the key-pairs.
+    ///
+    /// # Arguments
+    ///
+    /// - `pk`: The public key of the peer
+    ///
+    /// # Returns
+    ///
+    /// A bool indicating if the peer exists.
+    pub fn clear_session(&self, pk: &PublicKey) -> bool {
+        let peers = self.peers.read();
+        match peers.get(pk) {
+            Some(peer) => {
+                let _ = peers.reset_handshake(pk);
+                for id in peer.clear_keys() {
+                    peers.release(id);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Invalidate the sessions with every peer (see `clear_session`)
+    pub fn clear_sessions(&self) {
+        let peers = self.peers.read();
+        for (pk, peer) in peers.iter() {
+            let _ = peers.reset_handshake(&pk);
+            for id in peer.clear_keys() {
+                peers.release(id);
+            }
+        }
+    }
+
+    /// Erase the sessions and in-flight handshakes older than `REJECT_AFTER_TIME * 3`,
+    /// bounding how long key-material remains in memory.
+    /// Called periodically (every `KEY_SWEEP_INTERVAL`) by the key sweeper.
+    ///
+    /// # Returns
+    ///
+    /// The number of erased sessions and handshakes
+    pub fn sweep_keys(&self) -> usize {
+        let max_age = REJECT_AFTER_TIME * 3;
+        let swept = self.peers.read().sweep(max_age) + self.router.sweep_keys(max_age);
+        if swept > 0 {
+            log::debug!(
+                "{} : key sweeper erased {} sessions/handshakes",
+                self,
+                swept
+            );
+        }
+        swept
+    }
+
+    pub fn set_key(&self, sk: Option<StaticSecret>) {
+        self.set_static_key(
+            sk.map(|sk| Box::new(handshake::SecretKey::from(sk)) as Box<dyn handshake::StaticDh>),
+        )
+    }
+
+    /// Set the static key held by a backend (e.g. a TPM or PKCS#11 token),
+    /// the private key is then not returned by `get_sk`.
+    pub fn set_static_key(&self, sk: Option<Box<dyn handshake::StaticDh>>) {
+        // update the key (aborts in-flight handshakes)
+        self.peers.write().set_static_key(sk);
+
+        // sessions established using the old key are discarded
+        self.clear_sessions();
     }
 
     pub fn get_sk(&self) -> Option<StaticSecret> {
-        self.peers
-            .read()
-            .get_sk()
-            .map(|sk| StaticSecret::from(sk.to_bytes()))
+        self.peers.read().get_sk()
     }
 
-    pub fn set_psk(&self, pk: PublicKey, psk: [u8; 32]) -> bool {
+    pub fn set_psk(&self, pk: PublicKey, psk: Option<[u8; 32]>) -> bool {
         self.peers.write().set_psk(pk, psk).is_ok()
     }
     pub fn get_psk(&self, pk: &PublicKey) -> Option<[u8; 32]> {
         self.peers.read().get_psk(pk).ok()
     }
 
+    /// Set the replay and flood thresholds of the handshake
+    /// (see `handshake::HandshakeConfig`), applying to subsequent initiations.
+    ///
+    /// # Arguments
+    ///
+    /// - `config`: The new handshake configuration
+    pub fn set_handshake_config(&self, config: handshake::HandshakeConfig) {
+        // the reserved bytes policy also applies to transport messages
+        self.router.set_reserved(config.reserved);
+        self.peers.write().set_config(config)
+    }
+
+    pub fn get_handshake_config(&self) -> handshake::HandshakeConfig {
+        self.peers.read().get_config()
+    }
+
+    /// Set the size of the anti-replay window (in packets),
+    /// larger windows tolerate more reordering (e.g. on multi-path links).
+    ///
+    /// # Arguments
+    ///
+    /// - `size`: The window size, clamped to [MIN_WINDOW_SIZE, MAX_WINDOW_SIZE]
+    ///
+    /// Note: the window applies to the key-pairs established after the call
+    pub fn set_replay_window(&self, size: u64) {
+        self.router.set_replay_window(size)
+    }
+
+    pub fn get_replay_window(&self) -> u64 {
+        self.router.get_replay_window()
+    }
+
+    /// Set the policy for inbound packets with an inner source address
+    /// outside the allowed IPs of the sending peer (strict by default)
+    pub fn set_source_policy(&self, policy: router::SourcePolicy) {
+        self.router.set_source_policy(policy)
+    }
+
+    pub fn get_source_policy(&self) -> router::SourcePolicy {
+        self.router.get_source_policy()
+    }
+
+    /// Return the number of authenticated inbound packets
+    /// with an inner source address outside the allowed IPs of the sending peer
+    pub fn source_violations(&self) -> u64 {
+        self.router.source_violations()
+    }
+
+    /// Set the hybrid (post-quantum) secret of the peer,
+    /// mixed into the psk slot of subsequent handshakes
+    ///
+    /// # Arguments
+    ///
+    /// - `pk`: The public key of the peer
+    /// - `secret`: The hybrid secret or None to unset it
+    ///
+    /// # Returns
+    ///
+    /// A bool indicating if the peer exists.
+    pub fn set_hybrid_secret(&self, pk: PublicKey, secret: Option<[u8; 32]>) -> bool {
+        self.peers.write().set_hybrid_secret(pk, secret).is_ok()
+    }
+
+    /// Return the number of inbound messages dropped by the type de-multiplexer
+    pub fn message_stats(&self) -> MessageStats {
+        MessageStats {
+            unknown: self.unknown_messages.load(Ordering::Relaxed),
+            short: self.short_messages.load(Ordering::Relaxed),
+            oversized: self.oversized_messages.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Set (or unset) the callback invoked with messages of unknown type
+    pub fn set_unknown_message_handler(&self, handler: Option<UnknownMessageHandler<B::Endpoint>>) {
+        *self.unknown_handler.write() = handler;
+    }
+
+    /// Set (or unset) the callback invoked when a time-limited peer expires
+    pub fn set_expiry_handler(&self, handler: Option<PeerExpiryHandler>) {
+        *self.expiry_handler.write() = handler;
+    }
+
+    /// Set (or unset) the callback invoked when a new key-pair with a peer is confirmed
+    pub fn set_key_confirmed_handler(&self, handler: Option<KeyConfirmedHandler>) {
+        *self.confirm_handler.write() = handler;
+    }
+
+    /// Set (or unset) the callback used to admit unknown initiators on the fly
+    /// (e.g. for wg-dynamic style provisioning), see `handshake::Device::set_provisioner`.
+    /// Without a callback every unknown initiator is rejected (the default).
+    ///
+    /// The allowed IPs of an admitted peer are empty, until configured.
+    pub fn set_provisioner(&self, handler: Option<PeerProvisionHandler>) {
+        *self.provision_handler.write() = handler;
+    }
+
+    /// Limit the lifetime of a peer: once the deadline has passed the peer is removed
+    /// (and its sessions wiped) by the key sweeper, e.g. for guest access.
+    ///
+    /// # Arguments
+    ///
+    /// - `pk`: The public key of the peer
+    /// - `ttl`: Time (from now) until the peer expires, or None to never expire
+    ///
+    /// # Returns
+    ///
+    /// A bool indicating if the peer exists.
+    pub fn set_peer_expiry(&self, pk: &PublicKey, ttl: Option<Duration>) -> bool {
+        match self.peers.read().get(pk) {
+            Some(peer) => {
+                // a deadline beyond the range of Instant never expires
+                *peer.expiry.lock() = ttl.and_then(|ttl| Instant::now().checked_add(ttl));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remove every peer past its expiry deadline,
+    /// called periodically (every `KEY_SWEEP_INTERVAL`) by the key sweeper.
+    ///
+    /// # Returns
+    ///
+    /// The public keys of the removed peers
+    pub fn expire_peers(&self) -> Vec<PublicKey> {
+        let now = Instant::now();
+        let candidates: Vec<PublicKey> = self
+            .peers
+            .read()
+            .iter()
+            .filter(|(_, peer)| peer.expiry.lock().map(|at| at <= now) == Some(true))
+            .map(|(pk, _)| pk)
+            .collect();
+
+        // skip peers removed (e.g. by the configuration) in the meantime
+        let expired: Vec<PublicKey> = candidates
+            .into_iter()
+            .filter(|pk| self.remove_peer(pk))
+            .collect();
+
+        for pk in expired.iter() {
+            log::info!(
+                "{} : peer {} expired, removed",
+                self,
+                hex::encode(pk.as_bytes())
+            );
+            if let Some(handler) = self.expiry_handler.read().as_ref() {
+                handler(pk);
+            }
+        }
+        expired
+    }
+
+    /// Time until a peer expires (see `set_peer_expiry`)
+    ///
+    /// # Arguments
+    ///
+    /// - `pk`: The public key of the peer
+    ///
+    /// # Returns
+    ///
+    /// The remaining lifetime, or None if the peer does not exist or never expires.
+    pub fn get_peer_expiry(&self, pk: &PublicKey) -> Option<Duration> {
+        let peers = self.peers.read();
+        let at = (*peers.get(pk)?.expiry.lock())?;
+        Some(at.saturating_duration_since(Instant::now()))
+    }
+
+    /// Set the endpoint of a peer to a hostname, which is resolved immediately
+    /// and re-resolved after handshakes fail to complete, or every `ENDPOINT_REFRESH_INTERVAL`
+    /// while the latest handshake is older than `ENDPOINT_STALE_AFTER`,
+    /// such that peers behind dynamic DNS recover automatically.
+    ///
+    /// All the addresses of the name become candidate endpoints (see `set_endpoints`),
+    /// such that handshakes are raced over IPv4 and IPv6.
+    ///
+    /// # Arguments
+    ///
+    /// - `pk`: The public key of the peer
+    /// - `name`: The endpoint as "host:port"
+    ///
+    /// # Returns
+    ///
+    /// The (first) resolved address, or an error if the name could not be resolved
+    pub fn set_endpoint_name(&self, pk: &PublicKey, name: &str) -> io::Result<SocketAddr> {
+        let addrs = resolve(name)?;
+        let addr = addrs[0];
+        match self.peers.read().get(pk) {
+            Some(peer) => {
+                peer.set_endpoint(B::Endpoint::from_address(addr));
+                *peer.endpoint_name.lock() = Some((name.to_owned(), Instant::now()));
+                *peer.endpoints.lock() = addrs;
+                *peer.preferred_endpoint.lock() = None;
+                peer.resolve_endpoint.store(false, Ordering::SeqCst);
+                Ok(addr)
+            }
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "no such peer")),
+        }
+    }
+
+    /// Set an ordered list of candidate endpoints for a peer:
+    /// the first candidate becomes the active endpoint and when handshakes fail to complete
+    /// within `rekey_attempt_time`, the peer fails over to the next candidate.
+    ///
+    /// If the candidates include both IPv4 and IPv6 addresses, initiations are sent to both
+    /// address families until a response is received: the peer then sticks with the endpoint
+    /// which answered first (until handshakes fail to complete again).
+    ///
+    /// # Arguments
+    ///
+    /// - `pk`: The public key of the peer
+    /// - `endpoints`: The candidate endpoints, in order of preference
+    ///
+    /// # Returns
+    ///
+    /// True if the peer exists
+    pub fn set_endpoints(&self, pk: &PublicKey, endpoints: Vec<SocketAddr>) -> bool {
+        match self.peers.read().get(pk) {
+            Some(peer) => {
+                if let Some(addr) = endpoints.first() {
+                    peer.set_endpoint(B::Endpoint::from_address(*addr));
+                }
+                *peer.endpoint_name.lock() = None;
+                *peer.endpoints.lock() = endpoints;
+                *peer.preferred_endpoint.lock() = None;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Re-resolve the hostnames of the endpoints due for resolution (see `set_endpoint_name`),
+    /// called periodically (every `ENDPOINT_RESOLVE_TICK`) by the resolver thread.
+    ///
+    /// The endpoint is only replaced if it is not among the resolved addresses,
+    /// such that endpoints learned by roaming are retained while handshakes complete
+    /// and round-robin DNS does not move the peer between its addresses.
+    ///
+    /// # Returns
+    ///
+    /// The public keys of the peers whose endpoint was resolved
+    pub fn resolve_endpoints(&self) -> Vec<PublicKey> {
+        let due: Vec<(PublicKey, String)> = self
+            .peers
+            .read()
+            .iter()
+            .filter_map(|(pk, peer)| {
+                let name = peer.endpoint_name.lock();
+                let (name, resolved) = name.as_ref()?;
+                let requested = peer.resolve_endpoint.swap(false, Ordering::SeqCst);
+                let stale = match *peer.walltime_last_handshake.lock() {
+                    Some(at) => at
+                        .elapsed()
+                        .map(|age| age >= ENDPOINT_STALE_AFTER)
+                        .unwrap_or(false),
+                    None => true,
+                };
+                if requested || (stale && resolved.elapsed() >= ENDPOINT_REFRESH_INTERVAL) {
+                    Some((pk, name.clone()))
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        let mut resolved = Vec::with_capacity(due.len());
+        for (pk, name) in due {
+            // resolve without holding any locks (may block)
+            let addr = resolve(&name);
+            if let Some(peer) = self.peers.read().get(&pk) {
+                // the name may have been replaced during resolution
+                match peer.endpoint_name.lock().as_mut() {
+                    Some((current, at)) if *current == name => *at = Instant::now(),
+                    _ => continue,
+                }
+                match addr {
+                    Ok(addrs) => {
+                        let current = peer.get_endpoint();
+                        if current.map(|addr| !addrs.contains(&addr)).unwrap_or(true) {
+                            log::info!("{} : endpoint {} resolved to {}", self, name, addrs[0]);
+                            peer.set_endpoint(B::Endpoint::from_address(addrs[0]));
+                            *peer.preferred_endpoint.lock() = None;
+                        }
+                        *peer.endpoints.lock() = addrs;
+                        resolved.push(pk);
+                    }
+                    Err(e) => log::debug!("{} : failed to resolve {}: {}", self, name, e),
+                }
+            }
+        }
+        resolved
+    }
+
     pub fn add_peer(&self, pk: PublicKey) -> bool {
         let mut peers = self.peers.write();
         if peers.contains_key(&pk) {
@@ -207,26 +831,67 @@ impl<T: Tun, B: UDP> WireGuard<T, B> {
 
         // prevent up/down while inserting
         let enabled = self.enabled.read();
+        let peer = self.new_peer(pk, *enabled);
+
+        // finally, add the peer to the handshake device
+        let evicted = match peers.add(pk, peer) {
+            Ok(evicted) => evicted,
+            Err(_) => return false,
+        };
+        drop(enabled);
+        drop(peers);
 
+        // stop any peer evicted to make room
+        if let Some((_, peer)) = evicted {
+            peer.stop_timers();
+            peer.zero_keys();
+        }
+        true
+    }
+
+    /// Add the initiators admitted by the provisioning callback (see `set_provisioner`)
+    ///
+    /// # Returns
+    ///
+    /// The public keys of the added peers
+    pub fn admit_provisioned(&self) -> Vec<PublicKey> {
+        let (added, evicted) = self.peers.write().admit_provisioned();
+
+        // stop any peer evicted to make room
+        for (_, peer) in evicted {
+            log::debug!("{} : {} evicted for a provisioned initiator", self, peer);
+            peer.stop_timers();
+            peer.zero_keys();
+        }
+        added
+    }
+
+    // Internal function
+    //
+    // Create the router peer (and timers) for a public key,
+    // which is not yet added to the handshake device
+    fn new_peer(&self, pk: PublicKey, enabled: bool) -> PeerHandle<T, B> {
         // create timers (lookup by public key)
-        let timers = Timers::new::<T, B>(self.clone(), pk, *enabled);
+        let timers = Timers::new::<T, B>(self.clone(), pk, enabled);
 
         // create new router peer
-        let peer: router::PeerHandle<B::Endpoint, PeerInner<T, B>, T::Writer, B::Writer> =
-            self.router.new_peer(PeerInner {
-                id: OsRng.gen(),
-                pk,
-                wg: self.clone(),
-                walltime_last_handshake: Mutex::new(None),
-                last_handshake_sent: Mutex::new(Instant::now() - TIME_HORIZON),
-                handshake_queued: AtomicBool::new(false),
-                rx_bytes: AtomicU64::new(0),
-                tx_bytes: AtomicU64::new(0),
-                timers: RwLock::new(timers),
-            });
-
-        // finally, add the peer to the handshake device
-        peers.add(pk, peer).is_ok()
+        self.router.new_peer(PeerInner {
+            id: OsRng.gen(),
+            pk: Mutex::new(pk),
+            wg: self.clone(),
+            walltime_last_handshake: Mutex::new(None),
+            last_handshake_sent: Mutex::new(Instant::now() - TIME_HORIZON),
+            handshake_queued: AtomicBool::new(false),
+            rx_bytes: AtomicU64::new(0),
+            tx_bytes: AtomicU64::new(0),
+            expiry: Mutex::new(None),
+            endpoint_name: Mutex::new(None),
+            resolve_endpoint: AtomicBool::new(false),
+            endpoints: Mutex::new(vec![]),
+            preferred_endpoint: Mutex::new(None),
+            tcp_fallback: AtomicBool::new(false),
+            timers: RwLock::new(timers),
+        })
     }
 
     /// Begin consuming messages from the reader.
@@ -242,7 +907,83 @@ impl<T: Tun, B: UDP> WireGuard<T, B> {
     }
 
     pub fn set_writer(&self, writer: B::Writer) {
-        self.router.set_outbound_writer(writer);
+        self.router.set_outbound_writer(tcp::Outbound::new(
+            writer,
+            self.tcp.clone(),
+            self.obfuscation.clone(),
+        ));
+    }
+
+    /// Install an obfuscator applied to every datagram sent and received (see obfuscation.rs)
+    ///
+    /// # Arguments
+    ///
+    /// - `obfuscator`: The obfuscator, or None to send the messages unmodified (the default)
+    ///
+    /// Note: both sides of the tunnel must use the same obfuscation
+    pub fn set_obfuscator(&self, obfuscator: Option<Arc<dyn Obfuscator>>) {
+        self.obfuscation.set(obfuscator);
+    }
+
+    /// Carry the messages to and from the remote address of a connected TCP stream
+    /// over the stream (see tcp.rs), until the stream is closed.
+    ///
+    /// # Arguments
+    ///
+    /// - `stream`: The connected stream
+    /// - `inbound`: The stream was accepted (rather than opened by this device)
+    ///
+    /// # Returns
+    ///
+    /// The remote address of the stream
+    pub fn add_tcp_stream(&self, stream: TcpStream, inbound: bool) -> io::Result<SocketAddr> {
+        let (addr, id, reader) = self.tcp.insert(stream, inbound)?;
+        log::debug!("{} : TCP connection to {} opened", self, addr);
+        let wg = self.clone();
+        thread::spawn(move || {
+            tcp_worker(&wg, reader, addr, id);
+        });
+        Ok(addr)
+    }
+
+    /// Open a TCP connection to an endpoint (blocking),
+    /// subsequent messages to the endpoint are sent over TCP
+    pub fn connect_tcp(&self, addr: SocketAddr) -> io::Result<()> {
+        let proxy = self.tcp_proxy.read().clone();
+        let stream = tcp::connect(addr, proxy.as_ref(), TCP_CONNECT_TIMEOUT)?;
+        self.add_tcp_stream(stream, false).map(|_| ())
+    }
+
+    /// Set the proxy through which TCP connections to peers are opened
+    /// (for networks where direct egress is impossible)
+    ///
+    /// # Arguments
+    ///
+    /// - `proxy`: A SOCKS5 or HTTP CONNECT proxy, or None to connect directly
+    pub fn set_tcp_proxy(&self, proxy: Option<tcp::Proxy>) {
+        *self.tcp_proxy.write() = proxy;
+    }
+
+    /// Accept connections from peers using the TCP fallback transport
+    ///
+    /// # Arguments
+    ///
+    /// - `port`: The TCP port (usually the UDP listen port)
+    ///
+    /// # Returns
+    ///
+    /// The listener, connections are accepted until it is dropped
+    pub fn listen_tcp(&self, port: u16) -> io::Result<tcp::Listener> {
+        let (listener, accept) = tcp::Listener::bind(port)?;
+        let wg = self.clone();
+        thread::spawn(move || {
+            while let Ok((stream, _)) = accept.accept() {
+                if let Err(e) = wg.add_tcp_stream(stream, true) {
+                    log::debug!("{} : failed to accept TCP connection: {}", wg, e);
+                }
+            }
+        });
+        Ok(listener)
     }
 
     pub fn add_tun_reader(&self, reader: T::Reader) {
@@ -263,6 +1004,16 @@ impl<T: Tun, B: UDP> WireGuard<T, B> {
     }
 
     pub fn new(writer: T::Writer) -> WireGuard<T, B> {
+        WireGuard::with_config(writer, DeviceConfig::default())
+    }
+
+    /// Create a device with custom protocol timing
+    ///
+    /// # Arguments
+    ///
+    /// - `writer`: The TUN writer
+    /// - `config`: The timing of the device
+    pub fn with_config(writer: T::Writer, config: DeviceConfig) -> WireGuard<T, B> {
         // workers equal to number of physical cores
         let cpus = num_cpus::get();
 
@@ -270,8 +1021,13 @@ impl<T: Tun, B: UDP> WireGuard<T, B> {
         let (tx, mut rxs) = ParallelQueue::new(cpus, 128);
 
         // create router
-        let router: router::Device<B::Endpoint, PeerInner<T, B>, T::Writer, B::Writer> =
-            router::Device::new(num_cpus::get(), writer);
+        let router: Router<T, B> = router::Device::new(num_cpus::get(), writer);
+        router.set_replay_window(config.replay_window);
+        router.set_reserved(config.handshake.reserved);
+
+        // create handshake device
+        let mut peers = handshake::Device::with_protocol(config.protocol);
+        peers.set_config(config.handshake);
 
         // create arc to state
         let wg = WireGuard {
@@ -282,19 +1038,79 @@ impl<T: Tun, B: UDP> WireGuard<T, B> {
                 mtu: AtomicUsize::new(0),
                 last_under_load: Mutex::new(Instant::now() - TIME_HORIZON),
                 router,
+                tcp: Arc::new(tcp::Connections::new()),
+                tcp_proxy: RwLock::new(None),
+                obfuscation: Obfuscation::new(),
                 pending: AtomicUsize::new(0),
-                peers: RwLock::new(handshake::Device::new()),
+                peers: RwLock::new(peers),
+                config,
                 runner: Mutex::new(Runner::new(TIMERS_TICK, TIMERS_SLOTS, TIMERS_CAPACITY)),
                 queue: tx,
+                unknown_messages: AtomicU64::new(0),
+                short_messages: AtomicU64::new(0),
+                oversized_messages: AtomicU64::new(0),
+                unknown_handler: RwLock::new(None),
+                expiry_handler: RwLock::new(None),
+                confirm_handler: RwLock::new(None),
+                provision_handler: RwLock::new(None),
             }),
         };
 
+        // admit unknown initiators using the provision handler
+        // (the provisioner is owned by the device: do not keep it alive)
+        {
+            let inner = Arc::downgrade(&wg.inner);
+            wg.peers
+                .write()
+                .set_provisioner(Some(Box::new(move |pk: &PublicKey| {
+                    let wg = WireGuard {
+                        inner: inner.upgrade()?,
+                    };
+                    let psk = wg.provision_handler.read().as_ref()?(pk)?;
+                    let enabled = wg.enabled.read();
+                    Some(handshake::PeerConfig {
+                        opaque: wg.new_peer(*pk, *enabled),
+                        psk: Some(psk),
+                    })
+                })));
+        }
+
         // start handshake workers
         while let Some(rx) = rxs.pop() {
             let wg = wg.clone();
             thread::spawn(move || handshake_worker(&wg, rx));
         }
 
+        // start key sweeper
+        {
+            let inner = Arc::downgrade(&wg.inner);
+            thread::spawn(move || loop {
+                thread::sleep(KEY_SWEEP_INTERVAL);
+                match inner.upgrade() {
+                    Some(inner) => {
+                        let wg = WireGuard { inner };
+                        wg.expire_peers();
+                        wg.sweep_keys();
+                    }
+                    None => return,
+                }
+            });
+        }
+
+        // start endpoint resolver
+        {
+            let inner = Arc::downgrade(&wg.inner);
+            thread::spawn(move || loop {
+                thread::sleep(ENDPOINT_RESOLVE_TICK);
+                match inner.upgrade() {
+                    Some(inner) => {
+                        WireGuard { inner }.resolve_endpoints();
+                    }
+                    None => return,
+                }
+            });
+        }
+
         wg
     }
 }
@@ -1,3 +1,5 @@
+use std::mem;
+use std::net::{SocketAddr, TcpStream};
 use std::sync::atomic::Ordering;
 use std::time::Instant;
 
@@ -18,13 +20,14 @@ use super::udp::UDP;
 
 // constants
 use super::constants::{
-    DURATION_UNDER_LOAD, MAX_QUEUED_INCOMING_HANDSHAKES, MESSAGE_PADDING_MULTIPLE,
-    THRESHOLD_UNDER_LOAD,
+    DURATION_UNDER_LOAD, MAX_INPLACE_MTU, MAX_OFFLOAD_PACKET, MAX_QUEUED_INCOMING_HANDSHAKES,
+    MESSAGE_PADDING_MULTIPLE, THRESHOLD_UNDER_LOAD, UDP_READ_BATCH,
 };
-use super::handshake::MAX_HANDSHAKE_MSG_SIZE;
+use super::handshake::{HandshakeError, MAX_HANDSHAKE_MSG_SIZE};
 use super::handshake::{TYPE_COOKIE_REPLY, TYPE_INITIATION, TYPE_RESPONSE};
-use super::router::{CAPACITY_MESSAGE_POSTFIX, SIZE_MESSAGE_PREFIX, TYPE_TRANSPORT};
+use super::router::{self, CAPACITY_MESSAGE_POSTFIX, SIZE_MESSAGE_PREFIX, TYPE_TRANSPORT};
 
+use super::tcp;
 use super::wireguard::WireGuard;
 
 pub enum HandshakeJob<E> {
@@ -54,10 +57,94 @@ const fn padding(size: usize, mtu: usize) -> usize {
     min(mtu, size + (pad - size % pad) % pad)
 }
 
+// Internal function
+//
+// Pad an IP packet (following the prefix) and hand it to the router
+fn route<T: Tun, B: UDP>(wg: &WireGuard<T, B>, mut msg: Vec<u8>, mtu: usize) {
+    let payload = msg.len() - SIZE_MESSAGE_PREFIX;
+
+    // pad (or truncate) to the padded length
+    let padded = padding(payload, mtu);
+    log::trace!(
+        "TUN worker, payload length = {}, padded length = {}",
+        payload,
+        padded
+    );
+    msg.resize(SIZE_MESSAGE_PREFIX + padded, 0);
+    msg.reserve_exact(CAPACITY_MESSAGE_POSTFIX);
+    debug_assert!(padded <= mtu);
+    debug_assert_eq!(
+        if padded < mtu {
+            (msg.len() - SIZE_MESSAGE_PREFIX) % MESSAGE_PADDING_MULTIPLE
+        } else {
+            0
+        },
+        0
+    );
+
+    // crypt-key route
+    let e = wg.router.send(msg);
+    debug!("TUN worker, router returned {:?}", e);
+}
+
 pub fn tun_worker<T: Tun, B: UDP>(wg: &WireGuard<T, B>, reader: T::Reader) {
+    // super-packets are read into a reusable buffer and split into (newly allocated) segments
+    if reader.offload() {
+        let mut buf: Vec<u8> = vec![0; SIZE_MESSAGE_PREFIX + MAX_OFFLOAD_PACKET];
+        loop {
+            let (payload, offload) = match reader.read_offload(&mut buf[..], SIZE_MESSAGE_PREFIX) {
+                Ok(v) => v,
+                Err(e) => {
+                    debug!("TUN worker, failed to read from tun device: {}", e);
+                    break;
+                }
+            };
+            debug!("TUN worker, IP packet of {} bytes ({:?})", payload, offload);
+
+            // check if device is down
+            let mtu = wg.mtu.load(Ordering::Relaxed);
+            if mtu == 0 {
+                continue;
+            }
+
+            let packet = &buf[SIZE_MESSAGE_PREFIX..SIZE_MESSAGE_PREFIX + payload];
+            match router::gso::split(packet, &offload, SIZE_MESSAGE_PREFIX) {
+                Some(msgs) => {
+                    for msg in msgs {
+                        route(wg, msg, mtu);
+                    }
+                }
+                None => debug!("TUN worker, malformed super-packet"),
+            }
+        }
+        return;
+    }
+
+    // jumbo frames are read into a reusable buffer (avoiding a large allocation for every packet)
+    let mut jumbo: Vec<u8> = vec![];
     loop {
-        // create vector big enough for any transport message (based on MTU)
         let mtu = wg.mtu.load(Ordering::Relaxed);
+        if mtu > MAX_INPLACE_MTU {
+            jumbo.resize(mtu + SIZE_MESSAGE_PREFIX + 1, 0);
+            let payload = match reader.read(&mut jumbo[..], SIZE_MESSAGE_PREFIX) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    debug!("TUN worker, failed to read from tun device: {}", e);
+                    break;
+                }
+            };
+            debug!("TUN worker, IP packet of {} bytes (MTU = {})", payload, mtu);
+
+            // copy into a message with capacity for the padding and tag
+            let size = SIZE_MESSAGE_PREFIX + payload;
+            let mut msg =
+                Vec::with_capacity(size + MESSAGE_PADDING_MULTIPLE + CAPACITY_MESSAGE_POSTFIX);
+            msg.extend_from_slice(&jumbo[..size]);
+            route(wg, msg, mtu);
+            continue;
+        }
+
+        // create vector big enough for any transport message (based on MTU)
         let size = mtu + SIZE_MESSAGE_PREFIX + 1;
         let mut msg: Vec<u8> = vec![0; size + CAPACITY_MESSAGE_POSTFIX];
 
@@ -76,71 +163,130 @@ pub fn tun_worker<T: Tun, B: UDP>(wg: &WireGuard<T, B>, reader: T::Reader) {
             continue;
         }
 
-        // truncate padding
-        let padded = padding(payload, mtu);
-        log::trace!(
-            "TUN worker, payload length = {}, padded length = {}",
-            payload,
-            padded
-        );
-        msg.truncate(SIZE_MESSAGE_PREFIX + padded);
-        debug_assert!(padded <= mtu);
-        debug_assert_eq!(
-            if padded < mtu {
-                (msg.len() - SIZE_MESSAGE_PREFIX) % MESSAGE_PADDING_MULTIPLE
-            } else {
-                0
-            },
-            0
-        );
-
-        // crypt-key route
-        let e = wg.router.send(msg);
-        debug!("TUN worker, router returned {:?}", e);
+        // truncate to the packet (the capacity accommodates the padding and tag)
+        msg.truncate(SIZE_MESSAGE_PREFIX + payload);
+        route(wg, msg, mtu);
     }
 }
 
 pub fn udp_worker<T: Tun, B: UDP>(wg: &WireGuard<T, B>, reader: B::Reader) {
+    // buffers are only re-allocated once handed to the handshake device / router
+    let mut bufs: Vec<Vec<u8>> = vec![vec![]; UDP_READ_BATCH];
     loop {
-        // create vector big enough for any message given current MTU
+        // create vectors big enough for any message given current MTU
         let mtu = wg.mtu.load(Ordering::Relaxed);
         let size = mtu + MAX_HANDSHAKE_MSG_SIZE;
-        let mut msg: Vec<u8> = vec![0; size];
+        for buf in bufs.iter_mut() {
+            buf.resize(size, 0);
+        }
+
+        // read a batch of UDP packets into the vectors
+        let batch = {
+            let mut slices: Vec<&mut [u8]> = bufs.iter_mut().map(|buf| &mut buf[..]).collect();
+            match reader.read_batch(&mut slices[..]) {
+                Err(e) => {
+                    debug!("Bind reader closed with {}", e);
+                    return;
+                }
+                Ok(v) => v,
+            }
+        };
+
+        // path MTU learned from ICMP errors
+        for (addr, mtu) in reader.path_mtu_updates() {
+            wg.router.set_path_mtu(addr, mtu);
+        }
+
+        for (buf, (size, src)) in bufs.iter_mut().zip(batch) {
+            // TODO: start device down
+            if mtu == 0 {
+                continue;
+            }
+
+            // a full buffer indicates a (possibly truncated) message larger than any valid message
+            if size == buf.len() {
+                wg.oversized_messages.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            let mut msg = mem::take(buf);
+            msg.truncate(size);
+
+            demultiplex(wg, msg, src);
+        }
+    }
+}
 
-        // read UDP packet into vector
-        let (size, src) = match reader.read(&mut msg) {
+/* Receive framed messages from a TCP connection (see tcp.rs),
+ * the connection is closed when the worker terminates
+ */
+pub fn tcp_worker<T: Tun, B: UDP>(
+    wg: &WireGuard<T, B>,
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    id: u64,
+) {
+    loop {
+        let msg = match tcp::read_frame(&mut stream) {
             Err(e) => {
-                debug!("Bind reader closed with {}", e);
-                return;
+                debug!("{} : TCP connection to {} closed with {}", wg, addr, e);
+                break;
             }
-            Ok(v) => v,
+            Ok(msg) => msg,
         };
-        msg.truncate(size);
 
         // TODO: start device down
+        let mtu = wg.mtu.load(Ordering::Relaxed);
         if mtu == 0 {
             continue;
         }
 
-        // message type de-multiplexer
-        if msg.len() < std::mem::size_of::<u32>() {
+        // a message larger than any valid message
+        if msg.len() >= mtu + MAX_HANDSHAKE_MSG_SIZE {
+            wg.oversized_messages.fetch_add(1, Ordering::Relaxed);
             continue;
         }
-        match LittleEndian::read_u32(&msg[..]) {
-            TYPE_COOKIE_REPLY | TYPE_INITIATION | TYPE_RESPONSE => {
-                debug!("{} : reader, received handshake message", wg);
-                wg.pending.fetch_add(1, Ordering::SeqCst);
-                wg.queue.send(HandshakeJob::Message(msg, src));
-            }
-            TYPE_TRANSPORT => {
-                debug!("{} : reader, received transport message", wg);
 
-                // transport message
-                let _ = wg.router.recv(src, msg).map_err(|e| {
-                    debug!("Failed to handle incoming transport message: {}", e);
-                });
+        demultiplex(wg, msg, B::Endpoint::from_address(addr));
+    }
+    wg.tcp.remove(&addr, Some(id));
+}
+
+// Internal function
+//
+// Hand an inbound message to the handshake device or router, based on the message type
+// (the reserved bytes are validated by the handshake device / router)
+fn demultiplex<T: Tun, B: UDP>(wg: &WireGuard<T, B>, msg: Vec<u8>, src: B::Endpoint) {
+    let msg = match wg.obfuscation.unwrap(msg) {
+        Some(msg) => msg,
+        None => {
+            debug!("{} : reader, datagram rejected by obfuscator", wg);
+            return;
+        }
+    };
+    if msg.len() < std::mem::size_of::<u32>() {
+        wg.short_messages.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    match LittleEndian::read_u32(&msg[..]) & 0xff {
+        TYPE_COOKIE_REPLY | TYPE_INITIATION | TYPE_RESPONSE => {
+            debug!("{} : reader, received handshake message", wg);
+            wg.pending.fetch_add(1, Ordering::SeqCst);
+            wg.queue.send(HandshakeJob::Message(msg, src));
+        }
+        TYPE_TRANSPORT => {
+            debug!("{} : reader, received transport message", wg);
+
+            // transport message
+            let _ = wg.router.recv(src, msg).map_err(|e| {
+                debug!("Failed to handle incoming transport message: {}", e);
+            });
+        }
+        _ => {
+            debug!("{} : reader, received message of unknown type", wg);
+            wg.unknown_messages.fetch_add(1, Ordering::Relaxed);
+            if let Some(handler) = wg.unknown_handler.read().as_ref() {
+                handler(&msg[..], &src);
             }
-            _ => (),
         }
     }
 }
@@ -179,6 +325,7 @@ pub fn handshake_worker<T: Tun, B: UDP>(
         match job {
             HandshakeJob::Message(msg, mut src) => {
                 // process message
+                let mut provisioned = None;
                 let device = wg.peers.read();
                 match device.process(
                     &mut OsRng,
@@ -215,7 +362,9 @@ pub fn handshake_worker<T: Tun, B: UDP>(
                                 .fetch_add(resp_len, Ordering::Relaxed);
 
                             // update endpoint
+                            let addr = src.into_address();
                             peer.set_endpoint(src);
+                            wg.tcp.authenticate(&addr);
 
                             if resp_len > 0 {
                                 // update timers after sending handshake response
@@ -228,6 +377,9 @@ pub fn handshake_worker<T: Tun, B: UDP>(
                                     wg
                                 );
                                 peer.opaque().timers_handshake_complete();
+
+                                // stick with the endpoint which answered (raced handshakes)
+                                *peer.opaque().preferred_endpoint.lock() = Some(addr);
                             }
 
                             // add any new keypair to peer
@@ -238,14 +390,31 @@ pub fn handshake_worker<T: Tun, B: UDP>(
                                 peer.opaque().timers_session_derived();
 
                                 // free any unused ids
+                                let initiator = kp.initiator;
                                 for id in peer.add_keypair(kp) {
                                     device.release(id);
                                 }
+
+                                // the key-pair of the initiator is confirmed by the response,
+                                // that of the responder by the first transport message (router)
+                                if initiator {
+                                    peer.opaque().key_confirmed();
+                                }
                             };
                         }
                     }
+                    Err(HandshakeError::PeerProvisioned) => provisioned = Some(src),
                     Err(e) => debug!("{} : handshake worker, error = {:?}", wg, e),
                 }
+                drop(device);
+
+                // admit the initiator accepted by the provisioner, then process the message again
+                if let Some(src) = provisioned {
+                    debug!("{} : handshake worker, initiator provisioned", wg);
+                    wg.admit_provisioned();
+                    wg.pending.fetch_add(1, Ordering::SeqCst);
+                    wg.queue.send(HandshakeJob::Message(msg, src));
+                }
             }
             HandshakeJob::New(pk) => {
                 if let Some(peer) = wg.peers.read().get(&pk) {
@@ -258,6 +427,16 @@ pub fn handshake_worker<T: Tun, B: UDP>(
                         let _ = peer.send_raw(&msg[..]).map_err(|e| {
                             debug!("{} : handshake worker, failed to send handshake initiation, error = {}", wg, e)
                         });
+
+                        // race the initiation over the other address family (happy eyeballs),
+                        // the responder only answers the first copy to arrive
+                        if let Some(addr) = peer.opaque().race_endpoint(peer.get_endpoint()) {
+                            debug!("{} : handshake worker, racing handshake initiation to {}", wg, addr);
+                            let mut dst = B::Endpoint::from_address(addr);
+                            let _ = wg.router.send_raw(&msg[..], &mut dst).map_err(|e| {
+                                debug!("{} : handshake worker, failed to send handshake initiation, error = {}", wg, e)
+                            });
+                        }
                         peer.opaque().sent_handshake_initiation();
                     });
                     peer.opaque()
@@ -268,3 +447,26 @@ pub fn handshake_worker<T: Tun, B: UDP>(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_padding() {
+        let mtu = 1420;
+
+        // padded to a multiple of 16 bytes
+        assert_eq!(padding(0, mtu), 0);
+        assert_eq!(padding(1, mtu), 16);
+        assert_eq!(padding(16, mtu), 16);
+        assert_eq!(padding(17, mtu), 32);
+        assert_eq!(padding(1400, mtu), 1408);
+
+        // never padded beyond the MTU
+        assert_eq!(padding(1409, mtu), 1420);
+        assert_eq!(padding(1420, mtu), 1420);
+        assert_eq!(padding(1280, 1283), 1280);
+        assert_eq!(padding(1281, 1283), 1283);
+    }
+}
@@ -0,0 +1,56 @@
+/* Conformance testing with the packet crafter:
+ * crafted (valid, malformed and replayed) packets are fed to the implementation under test,
+ * here the handshake device of the library itself.
+ */
+#![cfg(feature = "testing")]
+
+use rand::rngs::OsRng;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use wireguard_rs::wireguard::handshake::testing::Crafter;
+use wireguard_rs::wireguard::handshake::{Device, HandshakeError, TAI64N};
+
+fn setup() -> (Crafter, Device<()>) {
+    let sk1 = StaticSecret::new(&mut OsRng);
+    let sk2 = StaticSecret::new(&mut OsRng);
+    let pk1 = PublicKey::from(&sk1);
+    let pk2 = PublicKey::from(&sk2);
+
+    let crafter = Crafter::new(sk1, pk2, None).unwrap();
+    let mut dev = Device::new();
+    dev.set_sk(Some(sk2));
+    dev.add(pk1, ()).unwrap();
+    (crafter, dev)
+}
+
+#[test]
+fn test_conformance() {
+    let (crafter, dev) = setup();
+
+    // malformed initiations are rejected
+    let init = crafter.initiation(&mut OsRng).unwrap();
+    let malformed = vec![
+        init.clone().corrupt_mac1(),
+        init.clone().truncate(100),
+        init.clone().extend(&[0; 4]),
+        crafter.remac(init.clone().reserved([1, 2, 3])),
+    ];
+    for packet in malformed {
+        assert!(dev.process(&mut OsRng, packet.as_bytes(), None).is_err());
+    }
+
+    // a valid initiation is answered
+    let (_, resp, _) = dev.process(&mut OsRng, init.as_bytes(), None).unwrap();
+    assert!(resp.is_some());
+
+    // replayed timestamps are rejected (fresh device, as initiations are rate limited)
+    let (crafter, dev) = setup();
+    let ts: TAI64N = [0x40, 0, 0, 0, 0x70, 0, 0, 0, 0, 0, 0, 0];
+    let init = crafter.initiation_at(&mut OsRng, &ts).unwrap();
+    dev.process(&mut OsRng, init.as_bytes(), None).unwrap();
+    let replay = crafter.initiation_at(&mut OsRng, &ts).unwrap();
+    match dev.process(&mut OsRng, replay.as_bytes(), None) {
+        Err(HandshakeError::OldTimestamp) => (),
+        _ => panic!("expected old timestamp"),
+    }
+}
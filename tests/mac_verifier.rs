@@ -0,0 +1,85 @@
+/* A UDP front-end verifying the mac fields before forwarding to the tunnel process:
+ * garbage is dropped and, under load, initiators are asked to prove their address with a cookie.
+ */
+
+use std::net::{SocketAddr, UdpSocket};
+use std::time::Duration;
+
+use rand::rngs::OsRng;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use wireguard_rs::wireguard::handshake::cookie::MacVerifier;
+use wireguard_rs::wireguard::handshake::Device;
+
+// Internal function
+//
+// Receive a datagram and forward it to the tunnel process if the mac fields are valid
+//
+// # Returns
+//
+// If the datagram was forwarded
+fn forward(
+    front: &UdpSocket,
+    tunnel: SocketAddr,
+    verifier: &MacVerifier,
+    under_load: bool,
+) -> bool {
+    let mut buf = [0u8; 1500];
+    let (len, src) = front.recv_from(&mut buf).unwrap();
+    let msg = &buf[..len];
+    if verifier.verify_mac1(msg).is_err() {
+        return false;
+    }
+    if under_load && !verifier.verify_mac2(msg, &src).unwrap() {
+        let reply = verifier.cookie_reply(&mut OsRng, msg, &src).unwrap();
+        front.send_to(&reply, src).unwrap();
+        return false;
+    }
+    front.send_to(msg, tunnel).unwrap();
+    true
+}
+
+#[test]
+fn test_mac_verifier_front_end() {
+    let sk_server = StaticSecret::new(&mut OsRng);
+    let pk_server = PublicKey::from(&sk_server);
+
+    let tunnel = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let front = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    for sock in &[&tunnel, &front, &client] {
+        sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    }
+    let verifier = MacVerifier::new(pk_server);
+    let front_addr = front.local_addr().unwrap();
+    let tunnel_addr = tunnel.local_addr().unwrap();
+
+    // the client is a handshake device with the server as peer
+    let mut dev: Device<()> = Device::new();
+    dev.set_sk(Some(StaticSecret::new(&mut OsRng)));
+    dev.add(pk_server, ()).unwrap();
+
+    // garbage is dropped, the initiation is forwarded
+    client.send_to(&[1, 0, 0, 0, 0xff], front_addr).unwrap();
+    assert!(!forward(&front, tunnel_addr, &verifier, false));
+    let init = dev.begin(&mut OsRng, &pk_server).unwrap();
+    client.send_to(&init, front_addr).unwrap();
+    assert!(forward(&front, tunnel_addr, &verifier, false));
+    let mut buf = [0u8; 1500];
+    let (len, _) = tunnel.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..len], &init[..]);
+
+    // under load: the initiation is answered with a cookie reply,
+    // the next initiation carries a valid mac2 and is forwarded
+    let init = dev.begin(&mut OsRng, &pk_server).unwrap();
+    client.send_to(&init, front_addr).unwrap();
+    assert!(!forward(&front, tunnel_addr, &verifier, true));
+    let (len, _) = client.recv_from(&mut buf).unwrap();
+    dev.process(&mut OsRng, &buf[..len], None).unwrap();
+
+    let init = dev.begin(&mut OsRng, &pk_server).unwrap();
+    client.send_to(&init, front_addr).unwrap();
+    assert!(forward(&front, tunnel_addr, &verifier, true));
+    let (len, _) = tunnel.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..len], &init[..]);
+}
@@ -0,0 +1,24 @@
+/* The anti-replay filter used standalone, e.g. by a tool de-duplicating captured transport messages */
+
+use wireguard_rs::wireguard::{ReplayFilter, MAX_WINDOW_SIZE, MIN_WINDOW_SIZE};
+
+#[test]
+fn test_replay_filter_standalone() {
+    let mut filter = ReplayFilter::new();
+    assert!(filter.update(0));
+    assert!(filter.update(2));
+    assert!(filter.update(1));
+    assert!(!filter.update(2));
+    assert!(!filter.check(1));
+    assert!(filter.check(3));
+
+    // counters older than the window are rejected
+    let window = filter.window();
+    assert!(filter.update(window + 10));
+    assert!(!filter.update(5));
+    assert!(filter.update(11));
+
+    // the window is clamped to the configurable range
+    assert!(ReplayFilter::with_window(0).window() >= MIN_WINDOW_SIZE);
+    assert!(ReplayFilter::with_window(u64::MAX).window() <= MAX_WINDOW_SIZE);
+}
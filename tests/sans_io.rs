@@ -0,0 +1,44 @@
+/* Embedding the sans-IO handshake machine:
+ * the handshake is driven over an in-memory channel by the embedder.
+ */
+#![cfg(feature = "sans-io")]
+
+use rand::rngs::OsRng;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use wireguard_rs::wireguard::handshake::{Event, HandshakeMachine};
+
+#[test]
+fn test_handshake_machine() {
+    let sk1 = StaticSecret::new(&mut OsRng);
+    let sk2 = StaticSecret::new(&mut OsRng);
+    let pk1 = PublicKey::from(&sk1);
+    let pk2 = PublicKey::from(&sk2);
+
+    let mut initiator = HandshakeMachine::new(sk1);
+    let mut responder = HandshakeMachine::new(sk2);
+    initiator
+        .add_peer(pk2, "responder", Some([1u8; 32]))
+        .unwrap();
+    responder
+        .add_peer(pk1, "initiator", Some([1u8; 32]))
+        .unwrap();
+
+    let init = initiator.initiate(&mut OsRng, &pk2).unwrap();
+    let (resp, kp_r) = match &responder.receive(&mut OsRng, &init, None).unwrap()[..] {
+        [Event::Send(resp), Event::KeyPair(peer, kp)] => {
+            assert_eq!(**peer, "initiator");
+            (resp.clone(), (*kp.send.key, *kp.recv.key))
+        }
+        _ => panic!("expected a response and a key-pair"),
+    };
+    let kp_i = match &initiator.receive(&mut OsRng, &resp, None).unwrap()[..] {
+        [Event::KeyPair(peer, kp)] => {
+            assert_eq!(**peer, "responder");
+            assert!(kp.initiator);
+            (*kp.send.key, *kp.recv.key)
+        }
+        _ => panic!("expected a key-pair"),
+    };
+    assert_eq!(kp_i, (kp_r.1, kp_r.0));
+}
@@ -0,0 +1,46 @@
+/* An external tool decoding and re-encoding handshake messages with the exported wire format */
+
+use std::convert::TryFrom;
+
+use rand::rngs::OsRng;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use wireguard_rs::wireguard::handshake::{
+    Device, Initiation, Response, TYPE_INITIATION, TYPE_RESPONSE,
+};
+
+#[test]
+fn test_wire_format_external() {
+    let sk1 = StaticSecret::new(&mut OsRng);
+    let pk1 = PublicKey::from(&sk1);
+    let sk2 = StaticSecret::new(&mut OsRng);
+    let pk2 = PublicKey::from(&sk2);
+
+    let mut dev1: Device<()> = Device::new();
+    let mut dev2: Device<()> = Device::new();
+    dev1.set_sk(Some(sk1));
+    dev2.set_sk(Some(sk2));
+    dev1.add(pk2, ()).unwrap();
+    dev2.add(pk1, ()).unwrap();
+
+    // decode the initiation and re-encode it unchanged
+    let init = dev1.begin(&mut OsRng, &pk2).unwrap();
+    let msg = Initiation::from_bytes(&init).unwrap();
+    assert_eq!(msg.noise.f_type.get(), TYPE_INITIATION);
+    assert_eq!(msg.to_bytes(), init);
+
+    // truncated messages and messages of another type are rejected
+    assert!(Initiation::try_from(&init[..init.len() - 1]).is_err());
+    assert!(Response::from_bytes(&init).is_err());
+
+    // the response references the sender of the initiation
+    let (_, resp, _) = dev2.process(&mut OsRng, &init, None).unwrap();
+    let resp = resp.unwrap();
+    let msg = Response::try_from(&resp[..]).unwrap();
+    assert_eq!(msg.noise.f_type.get(), TYPE_RESPONSE);
+    assert_eq!(
+        msg.noise.f_receiver.get(),
+        Initiation::from_bytes(&init).unwrap().noise.f_sender.get()
+    );
+    assert_eq!(msg.to_bytes(), resp);
+}